@@ -0,0 +1,166 @@
+/*
+ * planrt C API.
+ *
+ * Objects are opaque handles: functions creating one write it through an out pointer and
+ * the caller releases it with the matching planrt_*_free. Every fallible function returns
+ * a planrt_status and leaves its out pointers untouched on failure; planrt_last_error then
+ * describes the failure on the calling thread.
+ *
+ * Strings are NUL-terminated UTF-8, positions are in mm in DICOM patient (LPS) coordinates
+ * and doses in Gy. Voxel data is stored with the first index running fastest.
+ */
+
+#ifndef PLANRT_H
+#define PLANRT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PLANRT_ABI_VERSION 1
+
+typedef enum planrt_status {
+    PLANRT_OK = 0,
+    PLANRT_INVALID_ARGUMENT = 1,
+    PLANRT_IO = 2,
+    PLANRT_FORMAT = 3,
+    PLANRT_UNSUPPORTED = 4,
+    PLANRT_CANCELLED = 5,
+    /* A required pointer argument was null. */
+    PLANRT_NULL_POINTER = 6,
+    /* An internal error; the handles passed to the call should not be used again. */
+    PLANRT_PANIC = 7
+} planrt_status;
+
+/* The PLANRT_ABI_VERSION of the library. */
+uint32_t planrt_abi_version(void);
+
+/* Message of the last failed call on this thread, NULL before the first failure. The string
+ * stays valid until the next failing call on the thread. */
+const char *planrt_last_error(void);
+
+/* Called with the completed fraction (0 to 1) of a computation, possibly from several
+ * threads at once; a nonzero return value cancels the computation. */
+typedef int (*planrt_progress_fn)(double fraction, void *user_data);
+
+/* Volumes */
+
+typedef struct planrt_volume planrt_volume;
+
+/* Creates an axis-aligned volume of dims[0] * dims[1] * dims[2] voxels, copying data or
+ * filled with zeros when data is NULL. */
+planrt_status planrt_volume_create(const size_t dims[3], const double origin[3],
+                                   const double spacing[3], const double *data,
+                                   planrt_volume **out);
+
+/* Reads a MetaImage (.mha, .mhd), NIfTI (.nii, .nii.gz) or NRRD (.nrrd, .nhdr) file. */
+planrt_status planrt_volume_load(const char *path, planrt_volume **out);
+
+/* Writes a volume in the format of the extension of path. */
+planrt_status planrt_volume_save(const planrt_volume *volume, const char *path);
+
+/* Releases a volume; NULL is ignored. */
+void planrt_volume_free(planrt_volume *volume);
+
+/* The voxel counts, the position of the first voxel center, the spacing and the unit
+ * vectors of the index axes (axis by axis). */
+planrt_status planrt_volume_geometry(const planrt_volume *volume, size_t dims[3],
+                                     double origin[3], double spacing[3],
+                                     double direction[9]);
+
+/* The voxel values without a copy, valid until the volume is released. */
+planrt_status planrt_volume_data(const planrt_volume *volume, const double **data,
+                                 size_t *len);
+
+/* volume trilinearly interpolated on the grid of target, outside beyond volume. */
+planrt_status planrt_volume_resample(const planrt_volume *volume,
+                                     const planrt_volume *target, double outside,
+                                     planrt_volume **out);
+
+/* Dose-volume histograms */
+
+typedef struct planrt_dvh planrt_dvh;
+
+#define PLANRT_VOLUME_CC 0
+#define PLANRT_VOLUME_PERCENT 1
+
+typedef struct planrt_dvh_summary {
+    /* cm3 */
+    double volume;
+    double min;
+    double max;
+    double mean;
+} planrt_dvh_summary;
+
+/* DVH of dose in bins of bin_width Gy, weighting every voxel by the structure fraction
+ * (0 to 1) of mask on the dose grid. */
+planrt_status planrt_dvh_compute(const planrt_volume *dose, const planrt_volume *mask,
+                                 double bin_width, planrt_dvh **out);
+
+/* Releases a DVH; NULL is ignored. */
+void planrt_dvh_free(planrt_dvh *dvh);
+
+/* Volume and dose statistics. */
+planrt_status planrt_dvh_summarize(const planrt_dvh *dvh, planrt_dvh_summary *out);
+
+/* The highest dose received by at least volume (a PLANRT_VOLUME_* unit), e.g. D95%. */
+planrt_status planrt_dvh_dose_at(const planrt_dvh *dvh, double volume, int volume_unit,
+                                 double *dose);
+
+/* The volume (a PLANRT_VOLUME_* unit) receiving at least dose, e.g. V20Gy. */
+planrt_status planrt_dvh_volume_at(const planrt_dvh *dvh, double dose, int volume_unit,
+                                   double *volume);
+
+/* The points of the cumulative DVH: writes their number to len and, when doses and volumes
+ * are not NULL, the first capacity points to them. Call with NULL arrays to size them. */
+planrt_status planrt_dvh_cumulative(const planrt_dvh *dvh, int volume_unit, double *doses,
+                                    double *volumes, size_t capacity, size_t *len);
+
+/* Gamma analysis */
+
+typedef struct planrt_gamma_options {
+    /* Fraction of the normalization dose, e.g. 0.03. */
+    double dose_difference;
+    /* Distance to agreement (mm). */
+    double distance;
+    /* Nonzero for dose differences relative to the local reference dose. */
+    int local;
+    /* Dose (Gy) of global normalization; the reference maximum when not positive. */
+    double normalization_dose;
+    /* Reference voxels below this fraction of the reference maximum are not evaluated. */
+    double threshold;
+    /* Search lattice steps per evaluated voxel spacing. */
+    size_t interpolation;
+    double max_gamma;
+    /* Worker threads; 0 uses the available parallelism. */
+    size_t threads;
+} planrt_gamma_options;
+
+typedef struct planrt_gamma_summary {
+    size_t evaluated;
+    size_t passed;
+    /* Percentage of the evaluated voxels with gamma <= 1. */
+    double pass_rate;
+    double mean;
+    double max;
+} planrt_gamma_summary;
+
+/* The default criteria, 3 %/3 mm global with a 10 % threshold. */
+planrt_status planrt_gamma_options_default(planrt_gamma_options *options);
+
+/* Gamma of evaluated on the grid of reference: writes the statistics to summary and, when
+ * gamma is not NULL, the gamma volume (NaN where not evaluated) to gamma. callback, when not
+ * NULL, receives the progress with user_data. */
+planrt_status planrt_gamma(const planrt_volume *reference, const planrt_volume *evaluated,
+                           const planrt_gamma_options *options, planrt_progress_fn callback,
+                           void *user_data, planrt_gamma_summary *summary,
+                           planrt_volume **gamma);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PLANRT_H */
@@ -0,0 +1,234 @@
+//! Dose-volume histograms of a dose volume and a structure mask on the same grid.
+
+use crate::volume::Volume;
+use crate::{borrow, guard, slice_mut, write, write_boxed, Outcome, Status};
+use planrt::dvh::{Dvh, VolumeUnit};
+use planrt::error::Error;
+
+/// Volume in cm³.
+pub const VOLUME_CC: i32 = 0;
+/// Volume in percent of the structure.
+pub const VOLUME_PERCENT: i32 = 1;
+
+fn unit(unit: i32) -> Outcome<VolumeUnit> {
+    match unit {
+        VOLUME_CC => Ok(VolumeUnit::Cc),
+        VOLUME_PERCENT => Ok(VolumeUnit::Percent),
+        _ => Err(Error::InvalidArgument(format!("unknown volume unit {}", unit)).into()),
+    }
+}
+
+/// Volume and dose statistics of a DVH.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DvhSummary {
+    /// cm³
+    pub volume: f64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+/// DVH of `dose` in bins of `bin_width` Gy, weighting every voxel by the structure fraction
+/// (0 to 1) of `mask` on the dose grid, e.g. a rasterized structure.
+///
+/// # Safety
+///
+/// `dose` and `mask` are live volume handles and `out` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_dvh_compute(
+    dose: *const Volume,
+    mask: *const Volume,
+    bin_width: f64,
+    out: *mut *mut Dvh,
+) -> Status {
+    guard(|| {
+        let dose = borrow(dose, "dose")?;
+        let mask = borrow(mask, "mask")?;
+        let dvh = Dvh::from_fraction("", &mask.grid, &dose.grid, bin_width)?;
+        write_boxed(out, dvh, "out")
+    })
+}
+
+/// Releases `dvh`; null is ignored.
+///
+/// # Safety
+///
+/// `dvh` is null or a live handle, which must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_dvh_free(dvh: *mut Dvh) {
+    if !dvh.is_null() {
+        drop(Box::from_raw(dvh));
+    }
+}
+
+/// Volume and dose statistics of `dvh`.
+///
+/// # Safety
+///
+/// `dvh` is a live handle and `out` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_dvh_summarize(dvh: *const Dvh, out: *mut DvhSummary) -> Status {
+    guard(|| {
+        let dvh = borrow(dvh, "dvh")?;
+        let summary = DvhSummary {
+            volume: dvh.volume,
+            min: dvh.min,
+            max: dvh.max,
+            mean: dvh.mean,
+        };
+        write(out, summary, "out")
+    })
+}
+
+/// The highest dose received by at least `volume` (in `volume_unit`), e.g. D95%.
+///
+/// # Safety
+///
+/// `dvh` is a live handle and `dose` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_dvh_dose_at(
+    dvh: *const Dvh,
+    volume: f64,
+    volume_unit: i32,
+    dose: *mut f64,
+) -> Status {
+    guard(|| {
+        let dvh = borrow(dvh, "dvh")?;
+        write(dose, dvh.dose_at(volume, unit(volume_unit)?), "dose")
+    })
+}
+
+/// The volume (in `volume_unit`) receiving at least `dose`, e.g. V20Gy.
+///
+/// # Safety
+///
+/// `dvh` is a live handle and `volume` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_dvh_volume_at(
+    dvh: *const Dvh,
+    dose: f64,
+    volume_unit: i32,
+    volume: *mut f64,
+) -> Status {
+    guard(|| {
+        let dvh = borrow(dvh, "dvh")?;
+        write(volume, dvh.volume_at(dose, unit(volume_unit)?), "volume")
+    })
+}
+
+/// The points of the cumulative DVH: writes their number to `len` and, when `doses` and
+/// `volumes` are not null, the first `capacity` points to them. Call with null arrays to
+/// size them.
+///
+/// # Safety
+///
+/// `dvh` is a live handle, `len` is valid for writes and `doses` and `volumes` are null or
+/// valid for writes of `capacity` values.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_dvh_cumulative(
+    dvh: *const Dvh,
+    volume_unit: i32,
+    doses: *mut f64,
+    volumes: *mut f64,
+    capacity: usize,
+    len: *mut usize,
+) -> Status {
+    guard(|| {
+        let dvh = borrow(dvh, "dvh")?;
+        let points = dvh.cumulative(unit(volume_unit)?);
+        if !doses.is_null() || !volumes.is_null() {
+            let n = points.len().min(capacity);
+            let doses = slice_mut(doses, n, "doses")?;
+            let volumes = slice_mut(volumes, n, "volumes")?;
+            for ((d, v), (dose, volume)) in doses.iter_mut().zip(volumes.iter_mut()).zip(&points) {
+                *d = *dose;
+                *v = *volume;
+            }
+        }
+        write(len, points.len(), "len")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dvh::{
+        planrt_dvh_compute, planrt_dvh_cumulative, planrt_dvh_dose_at, planrt_dvh_free,
+        planrt_dvh_summarize, planrt_dvh_volume_at, DvhSummary, VOLUME_CC, VOLUME_PERCENT,
+    };
+    use crate::volume::{planrt_volume_create, planrt_volume_free, Volume};
+    use crate::Status;
+    use planrt::dvh::Dvh;
+    use std::ptr;
+
+    #[test]
+    fn dvh_of_mask() {
+        unsafe {
+            // 10 voxels of 1 cm³ with doses 0 to 9 Gy, the upper half in the structure.
+            let dims = [10usize, 1, 1];
+            let (origin, spacing) = ([0.0; 3], [10.0; 3]);
+            let doses: Vec<f64> = (0..10).map(|v| v as f64).collect();
+            let mask: Vec<f64> = (0..10).map(|v| if v < 5 { 0.0 } else { 1.0 }).collect();
+            let mut volumes: [*mut Volume; 2] = [ptr::null_mut(); 2];
+            for (v, data) in volumes.iter_mut().zip([doses, mask].iter()) {
+                let status = planrt_volume_create(
+                    dims.as_ptr(),
+                    origin.as_ptr(),
+                    spacing.as_ptr(),
+                    data.as_ptr(),
+                    v,
+                );
+                assert_eq!(status, Status::Ok);
+            }
+            let mut dvh: *mut Dvh = ptr::null_mut();
+            let status = planrt_dvh_compute(volumes[0], volumes[1], 0.5, &mut dvh);
+            assert_eq!(status, Status::Ok);
+            let mut summary = DvhSummary::default();
+            assert_eq!(planrt_dvh_summarize(dvh, &mut summary), Status::Ok);
+            assert!((summary.volume - 5.0).abs() < 1e-12);
+            assert_eq!((summary.min, summary.max, summary.mean), (5.0, 9.0, 7.0));
+
+            let mut value = 0.0;
+            assert_eq!(
+                planrt_dvh_volume_at(dvh, 7.0, VOLUME_CC, &mut value),
+                Status::Ok
+            );
+            assert!((value - 3.0).abs() < 1e-9);
+            assert_eq!(
+                planrt_dvh_dose_at(dvh, 100.0, VOLUME_PERCENT, &mut value),
+                Status::Ok
+            );
+            assert!((value - 5.0).abs() < 1e-9);
+            assert_eq!(
+                planrt_dvh_dose_at(dvh, 100.0, 7, &mut value),
+                Status::InvalidArgument
+            );
+
+            let mut len = 0;
+            let status = planrt_dvh_cumulative(
+                dvh,
+                VOLUME_CC,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                0,
+                &mut len,
+            );
+            assert_eq!(status, Status::Ok);
+            let (mut d, mut v) = (vec![0.0; len], vec![0.0; len]);
+            let status = planrt_dvh_cumulative(
+                dvh,
+                VOLUME_CC,
+                d.as_mut_ptr(),
+                v.as_mut_ptr(),
+                len,
+                &mut len,
+            );
+            assert_eq!(status, Status::Ok);
+            assert_eq!((d[0], v[0]), (0.0, 5.0));
+            assert_eq!(v[len - 1], 0.0);
+
+            planrt_dvh_free(dvh);
+            volumes.iter().for_each(|v| planrt_volume_free(*v));
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! 3D gamma analysis of an evaluated against a reference dose volume.
+
+use crate::volume::Volume;
+use crate::{borrow, guard, write, write_boxed, Status};
+use planrt::gamma::{gamma_with_progress, GammaOptions as Options, Normalization};
+use planrt::progress::{CancellationToken, Progress};
+use std::os::raw::c_void;
+
+/// Gamma criteria and search settings, see [`planrt::gamma::GammaOptions`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GammaOptions {
+    /// Fraction of the normalization dose, e.g. 0.03.
+    pub dose_difference: f64,
+    /// Distance to agreement (mm).
+    pub distance: f64,
+    /// Nonzero for dose differences relative to the local reference dose.
+    pub local: i32,
+    /// Dose (Gy) of global normalization; the reference maximum when not positive.
+    pub normalization_dose: f64,
+    /// Reference voxels below this fraction of the reference maximum are not evaluated.
+    pub threshold: f64,
+    /// Search lattice steps per evaluated voxel spacing.
+    pub interpolation: usize,
+    pub max_gamma: f64,
+    /// Worker threads; 0 uses the available parallelism.
+    pub threads: usize,
+}
+
+impl From<&GammaOptions> for Options {
+    fn from(options: &GammaOptions) -> Self {
+        let normalization = if options.local != 0 {
+            Normalization::Local
+        } else if options.normalization_dose > 0.0 {
+            Normalization::Global(Some(options.normalization_dose))
+        } else {
+            Normalization::Global(None)
+        };
+        Options {
+            dose_difference: options.dose_difference,
+            distance: options.distance,
+            normalization,
+            threshold: options.threshold,
+            interpolation: options.interpolation,
+            max_gamma: options.max_gamma,
+            threads: options.threads,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct GammaSummary {
+    pub evaluated: usize,
+    pub passed: usize,
+    /// Percentage of the evaluated voxels with gamma ≤ 1.
+    pub pass_rate: f64,
+    pub mean: f64,
+    pub max: f64,
+}
+
+/// Called with the completed fraction of a computation, possibly from several threads at
+/// once; a nonzero return value cancels the computation.
+pub type ProgressFn = Option<unsafe extern "C" fn(fraction: f64, user_data: *mut c_void) -> i32>;
+
+/// The user data of a [`ProgressFn`], which the caller shares with the worker threads.
+#[derive(Clone, Copy)]
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+unsafe impl Sync for UserData {}
+
+fn progress(callback: ProgressFn, user_data: *mut c_void) -> Progress {
+    let callback = match callback {
+        Some(callback) => callback,
+        None => return Progress::new(),
+    };
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    let user_data = UserData(user_data);
+    Progress::new()
+        .with_token(token)
+        .with_callback(move |fraction| {
+            if unsafe { callback(fraction, user_data.0) } != 0 {
+                cancel.cancel();
+            }
+        })
+}
+
+/// Writes the default criteria, 3 %/3 mm global with a 10 % threshold, to `options`.
+///
+/// # Safety
+///
+/// `options` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_gamma_options_default(options: *mut GammaOptions) -> Status {
+    guard(|| {
+        let d = Options::default();
+        let defaults = GammaOptions {
+            dose_difference: d.dose_difference,
+            distance: d.distance,
+            local: 0,
+            normalization_dose: 0.0,
+            threshold: d.threshold,
+            interpolation: d.interpolation,
+            max_gamma: d.max_gamma,
+            threads: d.threads,
+        };
+        write(options, defaults, "options")
+    })
+}
+
+/// Gamma of `evaluated` on the grid of `reference`: writes the statistics to `summary` and,
+/// when `gamma` is not null, the gamma volume (NaN where not evaluated) to `gamma`.
+/// `callback`, when not null, receives the progress with `user_data`.
+///
+/// # Safety
+///
+/// `reference` and `evaluated` are live volume handles, `options` points to options,
+/// `summary` is valid for writes and `gamma` is null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_gamma(
+    reference: *const Volume,
+    evaluated: *const Volume,
+    options: *const GammaOptions,
+    callback: ProgressFn,
+    user_data: *mut c_void,
+    summary: *mut GammaSummary,
+    gamma: *mut *mut Volume,
+) -> Status {
+    guard(|| {
+        let reference = borrow(reference, "reference")?;
+        let evaluated = borrow(evaluated, "evaluated")?;
+        let options = Options::from(borrow(options, "options")?);
+        borrow(summary, "summary")?;
+        let progress = progress(callback, user_data);
+        let result = gamma_with_progress(&reference.grid, &evaluated.grid, &options, &progress)?;
+        let statistics = GammaSummary {
+            evaluated: result.evaluated,
+            passed: result.passed,
+            pass_rate: result.pass_rate(),
+            mean: result.mean(),
+            max: result.max(),
+        };
+        if !gamma.is_null() {
+            write_boxed(gamma, Volume { grid: result.gamma }, "gamma")?;
+        }
+        write(summary, statistics, "summary")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gamma::{planrt_gamma, planrt_gamma_options_default, GammaOptions, GammaSummary};
+    use crate::volume::{planrt_volume_create, planrt_volume_free, Volume};
+    use crate::Status;
+    use std::os::raw::c_void;
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    unsafe extern "C" fn count(_: f64, user_data: *mut c_void) -> i32 {
+        (*(user_data as *const AtomicUsize)).fetch_add(1, Ordering::Relaxed);
+        0
+    }
+
+    unsafe extern "C" fn cancel(_: f64, _: *mut c_void) -> i32 {
+        1
+    }
+
+    #[test]
+    fn gamma_of_shifted_dose() {
+        unsafe {
+            let dims = [20usize, 5, 5];
+            let (origin, spacing) = ([0.0; 3], [2.0; 3]);
+            let mut doses: [*mut Volume; 2] = [ptr::null_mut(); 2];
+            // A dose gradient of 0.1 Gy/mm along x, evaluated 2 mm shifted.
+            for (v, shift) in doses.iter_mut().zip([0.0, 2.0].iter()) {
+                let data: Vec<f64> = (0..500)
+                    .map(|n| ((n % 20) as f64 * 2.0 + shift) / 10.0)
+                    .collect();
+                let status = planrt_volume_create(
+                    dims.as_ptr(),
+                    origin.as_ptr(),
+                    spacing.as_ptr(),
+                    data.as_ptr(),
+                    v,
+                );
+                assert_eq!(status, Status::Ok);
+            }
+            let mut options = std::mem::zeroed::<GammaOptions>();
+            assert_eq!(planrt_gamma_options_default(&mut options), Status::Ok);
+            assert_eq!((options.distance, options.local), (3.0, 0));
+            options.threads = 2;
+
+            let calls = AtomicUsize::new(0);
+            let mut summary = GammaSummary::default();
+            let mut gamma: *mut Volume = ptr::null_mut();
+            let status = planrt_gamma(
+                doses[0],
+                doses[1],
+                &options,
+                Some(count),
+                &calls as *const AtomicUsize as *mut c_void,
+                &mut summary,
+                &mut gamma,
+            );
+            assert_eq!(status, Status::Ok);
+            assert!(summary.evaluated > 0);
+            assert_eq!(summary.pass_rate, 100.0);
+            assert!(summary.max > 0.6 && summary.max < 0.7);
+            assert!(calls.load(Ordering::Relaxed) > 0);
+            assert_eq!((*gamma).grid.dims(), dims);
+
+            let mut none: *mut Volume = ptr::null_mut();
+            let status = planrt_gamma(
+                doses[0],
+                doses[1],
+                &options,
+                Some(cancel),
+                ptr::null_mut(),
+                &mut summary,
+                &mut none,
+            );
+            assert_eq!(status, Status::Cancelled);
+            assert!(none.is_null());
+            let status = planrt_gamma(
+                doses[0],
+                doses[1],
+                &options,
+                None,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
+            assert_eq!(status, Status::NullPointer);
+            for v in doses.iter().chain([gamma].iter()) {
+                planrt_volume_free(*v);
+            }
+        }
+    }
+}
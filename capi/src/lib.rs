@@ -0,0 +1,231 @@
+//! Stable C ABI of planrt for C and C++ applications, declared in `include/planrt.h`.
+//!
+//! Objects cross the boundary as opaque handles: functions creating one write it through
+//! an out pointer and the caller releases it with the matching `planrt_*_free`. Every
+//! fallible function returns a [`Status`] and leaves its out pointers untouched on failure;
+//! [`planrt_last_error`] then describes the failure on the calling thread. Panics do not
+//! unwind into the caller, they are reported as [`Status::Panic`].
+//!
+//! Strings are NUL-terminated UTF-8, sizes are `size_t` and doses are in Gy.
+
+pub mod dvh;
+pub mod gamma;
+pub mod volume;
+
+use planrt::error::Error;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// Version of the ABI in `planrt.h`, raised with every incompatible change.
+pub const ABI_VERSION: u32 = 1;
+
+/// Result code of every fallible function.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    InvalidArgument = 1,
+    Io = 2,
+    Format = 3,
+    Unsupported = 4,
+    Cancelled = 5,
+    /// A required pointer argument was null.
+    NullPointer = 6,
+    /// An internal error; the handles passed to the call should not be used again.
+    Panic = 7,
+}
+
+/// Why a call failed.
+#[derive(Debug)]
+pub(crate) enum Failure {
+    Error(Error),
+    Null(&'static str),
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Self {
+        Failure::Error(err)
+    }
+}
+
+impl Failure {
+    fn status(&self) -> Status {
+        match self {
+            Failure::Error(Error::InvalidArgument(_)) => Status::InvalidArgument,
+            Failure::Error(Error::Io(_)) => Status::Io,
+            Failure::Error(Error::Format(_)) => Status::Format,
+            Failure::Error(Error::Unsupported(_)) => Status::Unsupported,
+            Failure::Error(Error::Cancelled) => Status::Cancelled,
+            Failure::Null(_) => Status::NullPointer,
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            Failure::Error(err) => err.to_string(),
+            Failure::Null(name) => format!("null pointer: {}", name),
+        }
+    }
+}
+
+pub(crate) type Outcome<T> = std::result::Result<T, Failure>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: &str) {
+    let message = CString::new(message.replace('\0', " ")).expect("no interior NUL");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Runs the body of an exported function, recording a failure or panic for
+/// [`planrt_last_error`].
+pub(crate) fn guard<F: FnOnce() -> Outcome<()>>(f: F) -> Status {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => Status::Ok,
+        Ok(Err(failure)) => {
+            set_last_error(&failure.message());
+            failure.status()
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            set_last_error(&format!("internal error: {}", message));
+            Status::Panic
+        }
+    }
+}
+
+/// The object behind `ptr`.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a live `T`.
+pub(crate) unsafe fn borrow<'a, T>(ptr: *const T, name: &'static str) -> Outcome<&'a T> {
+    ptr.as_ref().ok_or(Failure::Null(name))
+}
+
+/// Writes `value` to the out pointer `ptr`.
+///
+/// # Safety
+///
+/// `ptr` must be null or valid for writes of a `T`.
+pub(crate) unsafe fn write<T>(ptr: *mut T, value: T, name: &'static str) -> Outcome<()> {
+    if ptr.is_null() {
+        return Err(Failure::Null(name));
+    }
+    ptr.write(value);
+    Ok(())
+}
+
+/// Boxes `value` and writes the handle to the out pointer `ptr`, allocating nothing when
+/// `ptr` is null.
+///
+/// # Safety
+///
+/// `ptr` must be null or valid for writes of a `*mut T`.
+pub(crate) unsafe fn write_boxed<T>(ptr: *mut *mut T, value: T, name: &'static str) -> Outcome<()> {
+    if ptr.is_null() {
+        return Err(Failure::Null(name));
+    }
+    ptr.write(Box::into_raw(Box::new(value)));
+    Ok(())
+}
+
+/// The string behind `ptr`.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a NUL-terminated string.
+pub(crate) unsafe fn string<'a>(ptr: *const c_char, name: &'static str) -> Outcome<&'a str> {
+    if ptr.is_null() {
+        return Err(Failure::Null(name));
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|_| Failure::Error(Error::InvalidArgument(format!("{} is not UTF-8", name))))
+}
+
+/// The `len` values behind `ptr`.
+///
+/// # Safety
+///
+/// `ptr` must be null or valid for reads of `len` values.
+pub(crate) unsafe fn slice<'a, T>(
+    ptr: *const T,
+    len: usize,
+    name: &'static str,
+) -> Outcome<&'a [T]> {
+    if ptr.is_null() {
+        return Err(Failure::Null(name));
+    }
+    Ok(std::slice::from_raw_parts(ptr, len))
+}
+
+/// The `len` values behind the out pointer `ptr`.
+///
+/// # Safety
+///
+/// `ptr` must be null or valid for writes of `len` values.
+pub(crate) unsafe fn slice_mut<'a, T>(
+    ptr: *mut T,
+    len: usize,
+    name: &'static str,
+) -> Outcome<&'a mut [T]> {
+    if ptr.is_null() {
+        return Err(Failure::Null(name));
+    }
+    Ok(std::slice::from_raw_parts_mut(ptr, len))
+}
+
+#[no_mangle]
+pub extern "C" fn planrt_abi_version() -> u32 {
+    ABI_VERSION
+}
+
+/// Message of the last failed call on this thread, null before the first failure. The
+/// string stays valid until the next failing call on the thread.
+#[no_mangle]
+pub extern "C" fn planrt_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |m| m.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{guard, planrt_abi_version, planrt_last_error, Failure, Status, ABI_VERSION};
+    use planrt::error::Error;
+    use std::ffi::CStr;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(planrt_last_error()) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn status_and_last_error() {
+        assert_eq!(planrt_abi_version(), ABI_VERSION);
+        assert!(planrt_last_error().is_null());
+        assert_eq!(guard(|| Ok(())), Status::Ok);
+        let status = guard(|| Err(Error::Format("bad header".to_string()).into()));
+        assert_eq!(status, Status::Format);
+        assert_eq!(last_error(), "malformed data: bad header");
+        assert_eq!(guard(|| Err(Failure::Null("out"))), Status::NullPointer);
+        assert_eq!(last_error(), "null pointer: out");
+        assert_eq!(guard(|| panic!("boom")), Status::Panic);
+        assert_eq!(last_error(), "internal error: boom");
+        assert_eq!(guard(|| Ok(())), Status::Ok);
+        assert_eq!(last_error(), "internal error: boom");
+    }
+}
@@ -0,0 +1,276 @@
+//! Volumes: CT, dose and mask grids of `double` values.
+//!
+//! Voxel data is stored with the first index running fastest, see
+//! [`planrt::grid::GridGeometry`]; positions are in mm in DICOM patient (LPS) coordinates.
+
+use crate::{borrow, guard, slice, slice_mut, string, write, write_boxed, Failure, Status};
+use planrt::coords::Vec3;
+use planrt::error::Error;
+use planrt::grid::{Grid3, GridGeometry};
+use planrt::io::{read_volume, write_volume};
+use planrt::resample::resample;
+use std::os::raw::c_char;
+
+/// The opaque `planrt_volume` handle.
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub(crate) grid: Grid3<f64>,
+}
+
+/// Creates an axis-aligned volume of `dims[0] * dims[1] * dims[2]` voxels, copying `data`
+/// or filled with zeros when `data` is null.
+///
+/// # Safety
+///
+/// `dims`, `origin` and `spacing` point to 3 values, `data` is null or points to one value
+/// per voxel and `out` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_volume_create(
+    dims: *const usize,
+    origin: *const f64,
+    spacing: *const f64,
+    data: *const f64,
+    out: *mut *mut Volume,
+) -> Status {
+    guard(|| {
+        let dims = slice(dims, 3, "dims")?;
+        let origin = slice(origin, 3, "origin")?;
+        let spacing = slice(spacing, 3, "spacing")?;
+        if spacing.iter().any(|s| !s.is_finite() || *s <= 0.0) {
+            return Err(Error::InvalidArgument(format!("invalid spacing {:?} mm", spacing)).into());
+        }
+        let len = dims
+            .iter()
+            .try_fold(1usize, |n, d| n.checked_mul(*d))
+            .ok_or_else(|| Error::InvalidArgument(format!("{:?} voxels overflow", dims)))?;
+        let geometry = GridGeometry::new(
+            [dims[0], dims[1], dims[2]],
+            Vec3::from(origin[0], origin[1], origin[2]),
+            Vec3::from(spacing[0], spacing[1], spacing[2]),
+        );
+        let grid = if data.is_null() {
+            Grid3::new(geometry, 0.0)
+        } else {
+            Grid3::from_vec(geometry, slice(data, len, "data")?.to_vec())?
+        };
+        write_boxed(out, Volume { grid }, "out")
+    })
+}
+
+/// Reads a MetaImage (`.mha`, `.mhd`), NIfTI (`.nii`, `.nii.gz`) or NRRD (`.nrrd`, `.nhdr`)
+/// file.
+///
+/// # Safety
+///
+/// `path` is a NUL-terminated string and `out` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_volume_load(path: *const c_char, out: *mut *mut Volume) -> Status {
+    guard(|| {
+        let grid = read_volume(string(path, "path")?)?;
+        write_boxed(out, Volume { grid }, "out")
+    })
+}
+
+/// Writes `volume` in the format of the extension of `path`.
+///
+/// # Safety
+///
+/// `volume` is a live handle and `path` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_volume_save(volume: *const Volume, path: *const c_char) -> Status {
+    guard(|| {
+        let volume = borrow(volume, "volume")?;
+        write_volume(string(path, "path")?, &volume.grid)?;
+        Ok(())
+    })
+}
+
+/// Releases `volume`; null is ignored.
+///
+/// # Safety
+///
+/// `volume` is null or a live handle, which must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_volume_free(volume: *mut Volume) {
+    if !volume.is_null() {
+        drop(Box::from_raw(volume));
+    }
+}
+
+/// Writes the voxel counts (3 values), the position of the first voxel center (3), the
+/// spacing (3) and the unit vectors of the index axes (9, axis by axis) of `volume`.
+///
+/// # Safety
+///
+/// `volume` is a live handle and the arrays are valid for writes of their sizes.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_volume_geometry(
+    volume: *const Volume,
+    dims: *mut usize,
+    origin: *mut f64,
+    spacing: *mut f64,
+    direction: *mut f64,
+) -> Status {
+    guard(|| {
+        let g = borrow(volume, "volume")?.grid.geometry();
+        let dims = slice_mut(dims, 3, "dims")?;
+        let origin = slice_mut(origin, 3, "origin")?;
+        let spacing = slice_mut(spacing, 3, "spacing")?;
+        let direction = slice_mut(direction, 9, "direction")?;
+        dims.copy_from_slice(&g.dims);
+        origin.copy_from_slice(&g.origin.to_array());
+        spacing.copy_from_slice(&g.spacing.to_array());
+        for (a, d) in g.direction.iter().enumerate() {
+            direction[3 * a..3 * a + 3].copy_from_slice(&d.to_array());
+        }
+        Ok(())
+    })
+}
+
+/// The voxel values of `volume` without a copy: `*data` stays valid until the volume is
+/// released, `*len` is the number of voxels.
+///
+/// # Safety
+///
+/// `volume` is a live handle and `data` and `len` are valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_volume_data(
+    volume: *const Volume,
+    data: *mut *const f64,
+    len: *mut usize,
+) -> Status {
+    guard(|| {
+        let grid = &borrow(volume, "volume")?.grid;
+        if data.is_null() {
+            return Err(Failure::Null("data"));
+        }
+        write(len, grid.len(), "len")?;
+        write(data, grid.data().as_ptr(), "data")
+    })
+}
+
+/// `volume` trilinearly interpolated on the grid of `target`, `outside` beyond `volume`.
+///
+/// # Safety
+///
+/// `volume` and `target` are live handles and `out` is valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn planrt_volume_resample(
+    volume: *const Volume,
+    target: *const Volume,
+    outside: f64,
+    out: *mut *mut Volume,
+) -> Status {
+    guard(|| {
+        let volume = borrow(volume, "volume")?;
+        let target = borrow(target, "target")?;
+        let grid = resample(&volume.grid, target.grid.geometry(), outside);
+        write_boxed(out, Volume { grid }, "out")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::volume::{
+        planrt_volume_create, planrt_volume_data, planrt_volume_free, planrt_volume_geometry,
+        planrt_volume_load, planrt_volume_resample, planrt_volume_save, Volume,
+    };
+    use crate::Status;
+    use std::ffi::CString;
+    use std::ptr;
+
+    #[test]
+    fn volume_handles() {
+        unsafe {
+            let dims = [3usize, 2, 2];
+            let data: Vec<f64> = (0..12).map(|v| v as f64).collect();
+            let mut volume: *mut Volume = ptr::null_mut();
+            let status = planrt_volume_create(
+                dims.as_ptr(),
+                [0.0, 0.0, 0.0].as_ptr(),
+                [2.0, 2.0, 2.0].as_ptr(),
+                data.as_ptr(),
+                &mut volume,
+            );
+            assert_eq!(status, Status::Ok);
+            let (mut values, mut len) = (ptr::null(), 0);
+            assert_eq!(
+                planrt_volume_data(volume, &mut values, &mut len),
+                Status::Ok
+            );
+            assert_eq!(std::slice::from_raw_parts(values, len), &data[..]);
+
+            // A grid shifted by half a voxel along x.
+            let mut target: *mut Volume = ptr::null_mut();
+            let status = planrt_volume_create(
+                dims.as_ptr(),
+                [1.0, 0.0, 0.0].as_ptr(),
+                [2.0, 2.0, 2.0].as_ptr(),
+                ptr::null(),
+                &mut target,
+            );
+            assert_eq!(status, Status::Ok);
+            let mut resampled: *mut Volume = ptr::null_mut();
+            let status = planrt_volume_resample(volume, target, 0.0, &mut resampled);
+            assert_eq!(status, Status::Ok);
+            let grid = &(*resampled).grid;
+            assert_eq!(grid[[0, 1, 1]], 9.5);
+            assert_eq!(grid[[1, 0, 0]], 1.5);
+
+            let path = std::env::temp_dir().join(format!("planrt-capi-{}.mha", std::process::id()));
+            let path = CString::new(path.to_str().unwrap()).unwrap();
+            assert_eq!(planrt_volume_save(volume, path.as_ptr()), Status::Ok);
+            let mut loaded: *mut Volume = ptr::null_mut();
+            assert_eq!(planrt_volume_load(path.as_ptr(), &mut loaded), Status::Ok);
+            let mut geometry = ([0usize; 3], [0.0; 3], [0.0; 3], [0.0; 9]);
+            let status = planrt_volume_geometry(
+                loaded,
+                geometry.0.as_mut_ptr(),
+                geometry.1.as_mut_ptr(),
+                geometry.2.as_mut_ptr(),
+                geometry.3.as_mut_ptr(),
+            );
+            assert_eq!(status, Status::Ok);
+            assert_eq!(geometry.0, dims);
+            assert_eq!(geometry.2, [2.0, 2.0, 2.0]);
+            assert_eq!(geometry.3[4], 1.0);
+            std::fs::remove_file(path.to_str().unwrap()).unwrap();
+
+            let missing = CString::new("missing.mha").unwrap();
+            let mut none: *mut Volume = ptr::null_mut();
+            assert_eq!(planrt_volume_load(missing.as_ptr(), &mut none), Status::Io);
+            assert!(none.is_null());
+            assert_eq!(
+                planrt_volume_data(ptr::null(), &mut values, &mut len),
+                Status::NullPointer
+            );
+            let origin = [0.0, 0.0, 0.0];
+            let spacing = [2.0, 2.0, 2.0];
+            assert_eq!(
+                planrt_volume_create(
+                    dims.as_ptr(),
+                    origin.as_ptr(),
+                    spacing.as_ptr(),
+                    ptr::null(),
+                    ptr::null_mut(),
+                ),
+                Status::NullPointer
+            );
+            let huge = [usize::MAX, 2, 1];
+            assert_eq!(
+                planrt_volume_create(
+                    huge.as_ptr(),
+                    origin.as_ptr(),
+                    spacing.as_ptr(),
+                    ptr::null(),
+                    &mut none,
+                ),
+                Status::InvalidArgument
+            );
+            assert!(none.is_null());
+            for v in [volume, target, resampled, loaded].iter() {
+                planrt_volume_free(*v);
+            }
+        }
+    }
+}
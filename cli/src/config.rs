@@ -0,0 +1,89 @@
+//! Command configuration files, in the TOML subset of [`planrt::io::toml`]. Relative paths
+//! in a file are relative to the directory of the file.
+
+use planrt::error::{Error, Result};
+use planrt::io::toml::{Document, Table};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub document: Document,
+    /// Directory of the file, for relative paths.
+    pub dir: PathBuf,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(Config {
+            document: Document::parse(text)?,
+            dir: PathBuf::new(),
+        })
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut config = Self::parse(&std::fs::read_to_string(path)?)?;
+        config.dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(config)
+    }
+
+    pub fn root(&self) -> &Table {
+        self.document.root()
+    }
+
+    /// `path` of the file, relative to its directory unless absolute.
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.dir.join(path)
+    }
+}
+
+/// The string `key` of `table`, which must be present.
+pub fn required<'a>(table: &'a Table, key: &str) -> Result<&'a str> {
+    table
+        .string(key)?
+        .ok_or_else(|| match (table.name.as_str(), table.array) {
+            ("", _) => Error::Format(format!("missing {}", key)),
+            (name, true) => Error::Format(format!("missing [[{}]] {}", name, key)),
+            (name, false) => Error::Format(format!("missing [{}] {}", name, key)),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{required, Config};
+    use std::path::PathBuf;
+
+    #[test]
+    fn parse_config() {
+        let mut config = Config::parse(
+            r#"
+            # DVH export
+            dose = "plan # 1/dose.nii.gz"
+            bin_width = 0.01   # Gy
+
+            [[structure]]
+            name = "PTV"
+            [[structure]]
+            mask = "rectum.mha"
+            "#,
+        )
+        .unwrap();
+        let root = config.root();
+        assert_eq!(required(root, "dose").unwrap(), "plan # 1/dose.nii.gz");
+        assert_eq!(root.number("bin_width").unwrap(), Some(0.01));
+        assert!(required(root, "bin_width").is_err());
+        assert!(required(root, "output").is_err());
+        let structures: Vec<_> = config.document.tables("structure").collect();
+        assert_eq!(required(structures[0], "name").unwrap(), "PTV");
+        let missing = required(structures[1], "name").unwrap_err();
+        assert_eq!(
+            missing.to_string(),
+            "malformed data: missing [[structure]] name"
+        );
+
+        config.dir = PathBuf::from("cases");
+        assert_eq!(config.path("ct.mha"), PathBuf::from("cases/ct.mha"));
+        assert!(Config::parse("dose = unquoted").is_err());
+        assert!(Config::parse("a = 1\na = 2").is_err());
+    }
+}
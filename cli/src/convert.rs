@@ -0,0 +1,26 @@
+//! `planrt convert <input> <output>`: converts a volume between the formats of
+//! [`planrt::io::VolumeFormat`], chosen by the file names.
+
+use planrt::error::{Error, Result};
+use planrt::io::{read_volume, write_volume};
+use std::path::Path;
+
+/// DICOM series are directories or `.dcm` files.
+fn is_dicom(path: &Path) -> bool {
+    path.is_dir()
+        || path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e.eq_ignore_ascii_case("dcm"))
+}
+
+pub fn run<P: AsRef<Path>, Q: AsRef<Path>>(input: P, output: Q) -> Result<()> {
+    let (input, output) = (input.as_ref(), output.as_ref());
+    if let Some(path) = [input, output].iter().find(|p| is_dicom(p)) {
+        return Err(Error::Unsupported(format!(
+            "{} is DICOM, which planrt cannot read or write yet",
+            path.display()
+        )));
+    }
+    write_volume(output, &read_volume(input)?)
+}
@@ -0,0 +1,171 @@
+//! `planrt dvh <config>`: DVHs of structure masks on a dose volume, optionally evaluated
+//! against a clinical protocol.
+//!
+//! ```toml
+//! dose = "dose.nii.gz"
+//! output = "dvh.csv"          # or .json
+//! bin_width = 0.01            # Gy, the default
+//! volume_unit = "percent"     # or "cc", the default
+//! protocol = "prostate.toml"  # optional goals, see planrt::protocol
+//! metrics = "metrics.csv"     # optional goal values as CSV
+//!
+//! [[structure]]
+//! name = "PTV"
+//! mask = "ptv.nii.gz"         # structure fraction (0 to 1) per voxel
+//! ```
+
+use crate::config::{required, Config};
+use crate::report::Format;
+use planrt::dvh::{Dvh, VolumeUnit};
+use planrt::error::{Error, Result};
+use planrt::io::dvh::{
+    write_dvh_csv, write_json, write_metrics_csv, ExportProvenance, MetricRecord,
+};
+use planrt::io::read_volume;
+use planrt::protocol::{GoalStatus, Protocol};
+use planrt::resample::resample;
+use std::path::Path;
+
+fn volume_unit(unit: Option<&str>) -> Result<VolumeUnit> {
+    match unit {
+        None | Some("cc") => Ok(VolumeUnit::Cc),
+        Some("percent") => Ok(VolumeUnit::Percent),
+        Some(unit) => Err(Error::Format(format!(
+            "volume_unit must be \"cc\" or \"percent\", not \"{}\"",
+            unit
+        ))),
+    }
+}
+
+/// Writes the DVHs and goal values; `Ok(false)` when a protocol goal fails.
+pub fn run<P: AsRef<Path>>(config: P) -> Result<bool> {
+    let config = Config::read(config)?;
+    let root = config.root();
+    root.check_keys(&[
+        "dose",
+        "output",
+        "bin_width",
+        "volume_unit",
+        "protocol",
+        "metrics",
+    ])?;
+    config.document.check_tables(&["structure"])?;
+    let output = config.path(required(root, "output")?);
+    let format = Format::from_path(&output)?;
+    let bin_width = root.number("bin_width")?.unwrap_or(0.01);
+    let unit = volume_unit(root.string("volume_unit")?)?;
+
+    let dose = read_volume(config.path(required(root, "dose")?))?;
+    let mut dvhs = Vec::new();
+    for structure in config.document.tables("structure") {
+        structure.check_keys(&["name", "mask"])?;
+        let mut mask = read_volume(config.path(required(structure, "mask")?))?;
+        if mask.geometry() != dose.geometry() {
+            mask = resample(&mask, dose.geometry(), 0.0);
+        }
+        dvhs.push(Dvh::from_fraction(
+            required(structure, "name")?,
+            &mask,
+            &dose,
+            bin_width,
+        )?);
+    }
+
+    let mut passed = true;
+    let mut metrics = Vec::new();
+    if let Some(protocol) = root.string("protocol")? {
+        let report = Protocol::read_toml(config.path(protocol))?.evaluate_dvhs(&dvhs)?;
+        for result in &report.results {
+            let status = match result.status {
+                GoalStatus::Pass => "PASS",
+                GoalStatus::Warn => "WARN",
+                GoalStatus::Fail => "FAIL",
+            };
+            let goal = &result.goal;
+            match result.value {
+                Some(value) => {
+                    println!("{} {} {} = {}", status, goal.structure, goal.metric, value);
+                    metrics.push(MetricRecord {
+                        structure: goal.structure.clone(),
+                        metric: goal.metric,
+                        value,
+                    });
+                }
+                None => println!(
+                    "{} {} {}: no such structure",
+                    status, goal.structure, goal.metric
+                ),
+            }
+        }
+        passed = report.status() != GoalStatus::Fail;
+    }
+
+    let provenance = ExportProvenance {
+        geometry: Some(dose.geometry().clone()),
+        ..Default::default()
+    };
+    match format {
+        Format::Csv => write_dvh_csv(&output, &dvhs, unit, &provenance)?,
+        Format::Json => write_json(&output, &dvhs, unit, &metrics, &provenance)?,
+    }
+    if let Some(path) = root.string("metrics")? {
+        write_metrics_csv(config.path(path), &metrics, &provenance)?;
+    }
+    Ok(passed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dvh::run;
+    use planrt::coords::Vec3;
+    use planrt::grid::{Grid3, GridGeometry};
+    use planrt::io::write_volume;
+
+    #[test]
+    fn dvh_with_protocol() {
+        let dir = std::env::temp_dir().join(format!("planrt-cli-dvh-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // 10 voxels of 1 cm³ with doses 0 to 9 Gy, the upper half in the structure.
+        let geometry = GridGeometry::new(
+            [10, 1, 1],
+            Vec3::from(0.0, 0.0, 0.0),
+            Vec3::from(10.0, 10.0, 10.0),
+        );
+        let dose = Grid3::from_vec(geometry.clone(), (0..10).map(|v| v as f64).collect()).unwrap();
+        let mask = Grid3::from_vec(
+            geometry,
+            (0..10).map(|v| if v < 5 { 0.0 } else { 1.0 }).collect(),
+        )
+        .unwrap();
+        write_volume(dir.join("dose.mha"), &dose).unwrap();
+        write_volume(dir.join("ptv.mha"), &mask).unwrap();
+        let config = dir.join("dvh.toml");
+        let write_config = |goal: &str| {
+            std::fs::write(
+                dir.join("protocol.toml"),
+                format!("name = \"test\"\n[[goal]]\nconstraint = \"{}\"\n", goal),
+            )
+            .unwrap();
+            std::fs::write(
+                &config,
+                "dose = \"dose.mha\"\noutput = \"dvh.json\"\nbin_width = 0.5\n\
+                 protocol = \"protocol.toml\"\nmetrics = \"metrics.csv\"\n\
+                 [[structure]]\nname = \"PTV\"\nmask = \"ptv.mha\"\n",
+            )
+            .unwrap();
+        };
+
+        write_config("PTV Dmean >= 6 Gy");
+        assert!(run(&config).unwrap());
+        let json = std::fs::read_to_string(dir.join("dvh.json")).unwrap();
+        assert!(json.contains("\"structure\": \"PTV\", \"volume_cc\": 5"));
+        let metrics = std::fs::read_to_string(dir.join("metrics.csv")).unwrap();
+        assert!(metrics.contains("PTV,Dmean[Gy],7"));
+
+        write_config("PTV Dmean >= 8 Gy");
+        assert!(!run(&config).unwrap());
+        std::fs::write(&config, "dose = \"dose.mha\"\noutput = \"dvh.txt\"\n").unwrap();
+        assert!(run(&config).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
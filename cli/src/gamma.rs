@@ -0,0 +1,218 @@
+//! `planrt gamma <config>`: 3D gamma analysis of an evaluated against a reference dose
+//! volume, e.g. a measurement or independent calculation against the planned dose.
+//!
+//! ```toml
+//! reference = "plan.nii.gz"
+//! evaluated = "measured.nii.gz"
+//! output = "gamma.json"       # or .csv
+//! dose_difference = 0.03      # criteria, defaults of planrt::gamma::GammaOptions
+//! distance = 3.0              # mm
+//! normalization = "global"    # or "local"
+//! normalization_dose = 2.0    # Gy of global normalization, the reference maximum if absent
+//! threshold = 0.1
+//! interpolation = 3
+//! max_gamma = 2.0
+//! threads = 0
+//! pass_rate = 95.0            # optional action level (%)
+//! gamma_volume = "gamma.mha"  # optional gamma volume
+//! ```
+
+use crate::config::{required, Config};
+use crate::report::Format;
+use planrt::error::{Error, Result};
+use planrt::gamma::{gamma, GammaOptions, Normalization};
+use planrt::io::dvh::{csv_field, json_number, json_string};
+use planrt::io::toml::Table;
+use planrt::io::{read_volume, write_volume};
+use std::path::Path;
+
+fn count(table: &Table, key: &str, default: usize) -> Result<usize> {
+    match table.number(key)? {
+        None => Ok(default),
+        Some(v) if v >= 0.0 && v.fract() == 0.0 => Ok(v as usize),
+        Some(v) => Err(Error::Format(format!(
+            "{} must be a non-negative integer, not {}",
+            key, v
+        ))),
+    }
+}
+
+fn options(table: &Table) -> Result<GammaOptions> {
+    let d = GammaOptions::default();
+    let normalization_dose = table.number("normalization_dose")?;
+    let normalization = match table.string("normalization")? {
+        None | Some("global") => Normalization::Global(normalization_dose),
+        Some("local") if normalization_dose.is_none() => Normalization::Local,
+        Some("local") => {
+            return Err(Error::Format(
+                "normalization_dose needs global normalization".to_string(),
+            ))
+        }
+        Some(n) => {
+            return Err(Error::Format(format!(
+                "normalization must be \"global\" or \"local\", not \"{}\"",
+                n
+            )))
+        }
+    };
+    Ok(GammaOptions {
+        dose_difference: table
+            .number("dose_difference")?
+            .unwrap_or(d.dose_difference),
+        distance: table.number("distance")?.unwrap_or(d.distance),
+        normalization,
+        threshold: table.number("threshold")?.unwrap_or(d.threshold),
+        interpolation: count(table, "interpolation", d.interpolation)?,
+        max_gamma: table.number("max_gamma")?.unwrap_or(d.max_gamma),
+        threads: count(table, "threads", d.threads)?,
+    })
+}
+
+/// Writes the gamma statistics; `Ok(false)` when the pass rate is below the action level.
+pub fn run<P: AsRef<Path>>(config: P) -> Result<bool> {
+    let config = Config::read(config)?;
+    let root = config.root();
+    root.check_keys(&[
+        "reference",
+        "evaluated",
+        "output",
+        "dose_difference",
+        "distance",
+        "normalization",
+        "normalization_dose",
+        "threshold",
+        "interpolation",
+        "max_gamma",
+        "threads",
+        "pass_rate",
+        "gamma_volume",
+    ])?;
+    config.document.check_tables(&[])?;
+    let output = config.path(required(root, "output")?);
+    let format = Format::from_path(&output)?;
+    let options = options(root)?;
+    let action_level = root.number("pass_rate")?;
+
+    let (reference, evaluated) = (required(root, "reference")?, required(root, "evaluated")?);
+    let result = gamma(
+        &read_volume(config.path(reference))?,
+        &read_volume(config.path(evaluated))?,
+        &options,
+    )?;
+    if let Some(path) = root.string("gamma_volume")? {
+        write_volume(config.path(path), &result.gamma)?;
+    }
+
+    let passed = action_level.is_none_or(|level| result.pass_rate() >= level);
+    let (dose_difference, distance) = (100.0 * options.dose_difference, options.distance);
+    println!(
+        "{} {} %/{} mm: {:.2} % of {} voxels pass",
+        if passed { "PASS" } else { "FAIL" },
+        dose_difference,
+        distance,
+        result.pass_rate(),
+        result.evaluated
+    );
+    let local = options.normalization == Normalization::Local;
+    let report = match format {
+        Format::Csv => format!(
+            "reference,evaluated,dose_difference_percent,distance_mm,local,threshold,\
+             evaluated_voxels,passed_voxels,pass_rate_percent,mean_gamma,max_gamma\n\
+             {},{},{},{},{},{},{},{},{},{},{}\n",
+            csv_field(reference),
+            csv_field(evaluated),
+            dose_difference,
+            distance,
+            local,
+            options.threshold,
+            result.evaluated,
+            result.passed,
+            result.pass_rate(),
+            result.mean(),
+            result.max()
+        ),
+        Format::Json => format!(
+            "{{\n  \"reference\": {},\n  \"evaluated\": {},\n  \"criteria\": {{\
+             \"dose_difference_percent\": {}, \"distance_mm\": {}, \"local\": {}, \
+             \"threshold\": {}}},\n  \"evaluated_voxels\": {},\n  \"passed_voxels\": {},\n  \
+             \"pass_rate_percent\": {},\n  \"mean_gamma\": {},\n  \"max_gamma\": {},\n  \
+             \"action_level_percent\": {},\n  \"passed\": {}\n}}\n",
+            json_string(reference),
+            json_string(evaluated),
+            json_number(dose_difference),
+            json_number(distance),
+            local,
+            json_number(options.threshold),
+            result.evaluated,
+            result.passed,
+            json_number(result.pass_rate()),
+            json_number(result.mean()),
+            json_number(result.max()),
+            action_level.map_or("null".to_string(), json_number),
+            passed
+        ),
+    };
+    std::fs::write(&output, report)?;
+    Ok(passed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gamma::run;
+    use planrt::coords::Vec3;
+    use planrt::grid::{Grid3, GridGeometry};
+    use planrt::io::write_volume;
+
+    #[test]
+    fn gamma_of_shifted_dose() {
+        let dir = std::env::temp_dir().join(format!("planrt-cli-gamma-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // A dose gradient of 0.1 Gy/mm along x, evaluated 2 mm shifted.
+        let geometry = GridGeometry::new(
+            [20, 5, 5],
+            Vec3::from(0.0, 0.0, 0.0),
+            Vec3::from(2.0, 2.0, 2.0),
+        );
+        for (name, shift) in [("reference.mha", 0.0), ("evaluated.mha", 2.0)].iter() {
+            let data = (0..500)
+                .map(|n| ((n % 20) as f64 * 2.0 + shift) / 10.0)
+                .collect();
+            let dose = Grid3::from_vec(geometry.clone(), data).unwrap();
+            write_volume(dir.join(name), &dose).unwrap();
+        }
+        let config = dir.join("gamma.toml");
+        let write_config = |distance: f64| {
+            std::fs::write(
+                &config,
+                format!(
+                    "reference = \"reference.mha\"\nevaluated = \"evaluated.mha\"\n\
+                     output = \"gamma.csv\"\ndistance = {}\nthreads = 2\npass_rate = 95\n\
+                     gamma_volume = \"gamma.mha\"\n",
+                    distance
+                ),
+            )
+            .unwrap();
+        };
+
+        write_config(3.0);
+        assert!(run(&config).unwrap());
+        let csv = std::fs::read_to_string(dir.join("gamma.csv")).unwrap();
+        let row: Vec<&str> = csv.lines().nth(1).unwrap().split(',').collect();
+        assert_eq!(
+            row[..5],
+            ["reference.mha", "evaluated.mha", "3", "3", "false"]
+        );
+        assert_eq!(row[8], "100");
+        assert!(dir.join("gamma.mha").exists());
+
+        write_config(0.5);
+        assert!(!run(&config).unwrap());
+        std::fs::write(
+            &config,
+            "reference = \"reference.mha\"\nnormalization = \"relative\"\n",
+        )
+        .unwrap();
+        assert!(run(&config).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+//! `planrt`: batch workflows on the command line, configured by TOML files and reporting in
+//! CSV or JSON.
+//!
+//! The exit status is 0 on success, 1 when a configured check (protocol goals, gamma pass
+//! rate) fails and 2 on errors.
+
+mod config;
+mod convert;
+mod dvh;
+mod gamma;
+mod report;
+
+use planrt::error::{Error, Result};
+use std::process::exit;
+
+const USAGE: &str = "\
+usage: planrt <command> [arguments]
+
+commands:
+  dvh <config>               DVHs and protocol evaluation of structure masks on a dose volume
+  gamma <config>             3D gamma analysis of an evaluated against a reference dose volume
+  convert <input> <output>   converts between MetaImage, NIfTI and NRRD volumes
+
+options:
+  -h, --help                 prints this message
+  -V, --version              prints the version";
+
+/// Fails unless exactly `n` arguments follow the command.
+fn arguments<'a>(command: &str, args: &'a [String], n: usize) -> Result<&'a [String]> {
+    if args.len() == n {
+        Ok(args)
+    } else {
+        Err(Error::InvalidArgument(format!(
+            "{} expects {} argument{}, see planrt --help",
+            command,
+            n,
+            if n == 1 { "" } else { "s" }
+        )))
+    }
+}
+
+/// Runs a command; `Ok(false)` when one of its checks failed.
+fn run(args: &[String]) -> Result<bool> {
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => {
+            return Err(Error::InvalidArgument(
+                "no command, see planrt --help".to_string(),
+            ))
+        }
+    };
+    match command {
+        "-h" | "--help" | "help" => {
+            println!("{}", USAGE);
+            Ok(true)
+        }
+        "-V" | "--version" => {
+            println!("planrt {}", env!("CARGO_PKG_VERSION"));
+            Ok(true)
+        }
+        "dvh" => dvh::run(&arguments(command, args, 1)?[0]),
+        "gamma" => gamma::run(&arguments(command, args, 1)?[0]),
+        "convert" => {
+            let args = arguments(command, args, 2)?;
+            convert::run(&args[0], &args[1])?;
+            Ok(true)
+        }
+        _ => Err(Error::InvalidArgument(format!(
+            "unknown command {}, see planrt --help",
+            command
+        ))),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(err) => {
+            eprintln!("planrt: {}", err);
+            exit(2);
+        }
+    }
+}
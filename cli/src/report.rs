@@ -0,0 +1,23 @@
+//! Report formats, chosen by the extension of the output path.
+
+use planrt::error::{Error, Result};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("csv") => Ok(Format::Csv),
+            Some(e) if e.eq_ignore_ascii_case("json") => Ok(Format::Json),
+            _ => Err(Error::Unsupported(format!(
+                "report {} is neither .csv nor .json",
+                path.display()
+            ))),
+        }
+    }
+}
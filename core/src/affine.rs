@@ -0,0 +1,217 @@
+use crate::coords::Vec3;
+use std::ops::Mul;
+
+/// Affine transform `p' = matrix * p + translation` in 3D (row-major matrix).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Affine3 {
+    pub matrix: [[f64; 3]; 3],
+    pub translation: Vec3<f64>,
+}
+
+impl Default for Affine3 {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+impl Affine3 {
+    pub fn identity() -> Self {
+        Self {
+            matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            translation: Vec3::from(0.0, 0.0, 0.0),
+        }
+    }
+
+    pub fn from(matrix: [[f64; 3]; 3], translation: Vec3<f64>) -> Self {
+        Self {
+            matrix,
+            translation,
+        }
+    }
+
+    /// Builds a transform from a homogeneous 4x4 matrix; the last row is ignored.
+    pub fn from_matrix4(m: [[f64; 4]; 4]) -> Self {
+        let mut matrix = [[0.0; 3]; 3];
+        for (r, row) in matrix.iter_mut().enumerate() {
+            row.copy_from_slice(&m[r][..3]);
+        }
+        Self {
+            matrix,
+            translation: Vec3::from(m[0][3], m[1][3], m[2][3]),
+        }
+    }
+
+    pub fn to_matrix4(&self) -> [[f64; 4]; 4] {
+        let t = self.translation.to_array();
+        let mut m = [[0.0; 4]; 4];
+        for r in 0..3 {
+            m[r][..3].copy_from_slice(&self.matrix[r]);
+            m[r][3] = t[r];
+        }
+        m[3][3] = 1.0;
+        m
+    }
+
+    pub fn translation(t: Vec3<f64>) -> Self {
+        Self {
+            translation: t,
+            ..Self::identity()
+        }
+    }
+
+    pub fn scaling(s: Vec3<f64>) -> Self {
+        Self::from(
+            [[s.x, 0.0, 0.0], [0.0, s.y, 0.0], [0.0, 0.0, s.z]],
+            Vec3::from(0.0, 0.0, 0.0),
+        )
+    }
+
+    /// Rotation (radians) about the x, y and z axis, applied in that order.
+    pub fn rotation(rx: f64, ry: f64, rz: f64) -> Self {
+        let (sx, cx) = rx.sin_cos();
+        let (sy, cy) = ry.sin_cos();
+        let (sz, cz) = rz.sin_cos();
+        let x = Self::from(
+            [[1.0, 0.0, 0.0], [0.0, cx, -sx], [0.0, sx, cx]],
+            Vec3::new(),
+        );
+        let y = Self::from(
+            [[cy, 0.0, sy], [0.0, 1.0, 0.0], [-sy, 0.0, cy]],
+            Vec3::new(),
+        );
+        let z = Self::from(
+            [[cz, -sz, 0.0], [sz, cz, 0.0], [0.0, 0.0, 1.0]],
+            Vec3::new(),
+        );
+        z * y * x
+    }
+
+    /// Rigid transform: rotation about `center` followed by a translation.
+    pub fn rigid(rx: f64, ry: f64, rz: f64, center: Vec3<f64>, t: Vec3<f64>) -> Self {
+        Self::translation(center + t) * Self::rotation(rx, ry, rz) * Self::translation(-center)
+    }
+
+    pub fn transform_point(&self, p: Vec3<f64>) -> Vec3<f64> {
+        self.transform_vector(p) + self.translation
+    }
+
+    pub fn transform_vector(&self, v: Vec3<f64>) -> Vec3<f64> {
+        let m = &self.matrix;
+        Vec3::from(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+
+    pub fn determinant(&self) -> f64 {
+        let m = &self.matrix;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    /// Returns the inverse transform, or `None` if the linear part is singular.
+    pub fn inverse(&self) -> Option<Self> {
+        let det = self.determinant();
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let m = &self.matrix;
+        let mut inv = [[0.0; 3]; 3];
+        for (r, row) in inv.iter_mut().enumerate() {
+            for (c, v) in row.iter_mut().enumerate() {
+                let (r1, r2) = ((c + 1) % 3, (c + 2) % 3);
+                let (c1, c2) = ((r + 1) % 3, (r + 2) % 3);
+                *v = (m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]) / det;
+            }
+        }
+        let linear = Self::from(inv, Vec3::new());
+        let t = -linear.transform_vector(self.translation);
+        Some(Self::from(inv, t))
+    }
+}
+
+/// `a * b` applies `b` first, then `a`.
+impl Mul for Affine3 {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        let mut m = [[0.0; 3]; 3];
+        for (r, row) in m.iter_mut().enumerate() {
+            for (c, v) in row.iter_mut().enumerate() {
+                *v = (0..3).map(|k| self.matrix[r][k] * rhs.matrix[k][c]).sum();
+            }
+        }
+        Self {
+            matrix: m,
+            translation: self.transform_point(rhs.translation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::affine::Affine3;
+    use crate::coords::Vec3;
+    use std::f64::consts::FRAC_PI_2;
+
+    fn assert_close(a: Vec3<f64>, b: Vec3<f64>) {
+        assert!(a.distance(b) < 1e-9, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn affine3_identity() {
+        let p = Vec3::from(1.0, 2.0, 3.0);
+        assert_eq!(Affine3::identity().transform_point(p), p);
+    }
+
+    #[test]
+    fn affine3_rotation() {
+        let r = Affine3::rotation(0.0, 0.0, FRAC_PI_2);
+        assert_close(
+            r.transform_point(Vec3::from(1.0, 0.0, 0.0)),
+            Vec3::from(0.0, 1.0, 0.0),
+        );
+        assert!((r.determinant() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn affine3_mul() {
+        let t = Affine3::translation(Vec3::from(1.0, 0.0, 0.0));
+        let s = Affine3::scaling(Vec3::from(2.0, 2.0, 2.0));
+        let p = Vec3::from(1.0, 1.0, 1.0);
+        assert_close((t * s).transform_point(p), Vec3::from(3.0, 2.0, 2.0));
+        assert_close((s * t).transform_point(p), Vec3::from(4.0, 2.0, 2.0));
+    }
+
+    #[test]
+    fn affine3_inverse() {
+        let a = Affine3::rigid(
+            0.1,
+            -0.2,
+            0.3,
+            Vec3::from(5.0, 6.0, 7.0),
+            Vec3::from(1.0, 2.0, 3.0),
+        );
+        let inv = a.inverse().unwrap();
+        let p = Vec3::from(-4.0, 10.0, 2.5);
+        assert_close(inv.transform_point(a.transform_point(p)), p);
+        assert!(Affine3::scaling(Vec3::from(1.0, 0.0, 1.0))
+            .inverse()
+            .is_none());
+    }
+
+    #[test]
+    fn affine3_rigid_center() {
+        let c = Vec3::from(10.0, 0.0, 0.0);
+        let a = Affine3::rigid(0.0, 0.0, FRAC_PI_2, c, Vec3::new());
+        assert_close(a.transform_point(c), c);
+    }
+
+    #[test]
+    fn affine3_matrix4() {
+        let a = Affine3::rigid(0.1, 0.2, 0.3, Vec3::new(), Vec3::from(1.0, 2.0, 3.0));
+        assert_eq!(Affine3::from_matrix4(a.to_matrix4()), a);
+    }
+}
@@ -0,0 +1,466 @@
+//! MLC apertures.
+//!
+//! An [`Aperture`] is the opening between the leaf pairs of an MLC, optionally bounded by the
+//! jaws, in beam coordinates at the isocenter plane (mm). Each pair has one opening from its
+//! bank A to its bank B leaf; closed pairs have both leaves at the same position. Apertures
+//! are fitted to the beam's eye view of a structure for conformal fields and as a starting
+//! point for forward planning, and their area and perimeter feed the complexity metrics.
+
+use crate::dose::beam::BeamGeometry;
+use crate::dose::delivery::{ControlPoint, Mlc};
+use crate::error::{Error, Result};
+use crate::opt::dao::Segment;
+use crate::structure::Structure;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aperture {
+    /// Ascending `y` (mm) of the leaf pair edges, one more than the pairs.
+    pub boundaries: Vec<f64>,
+    /// Bank A and bank B position (mm) of every leaf pair.
+    pub leaves: Vec<[f64; 2]>,
+    /// `[x1, x2, y1, y2]` (mm); `None` for jaws out of the field.
+    pub jaws: Option<[f64; 4]>,
+}
+
+impl Aperture {
+    pub fn new(
+        boundaries: Vec<f64>,
+        leaves: Vec<[f64; 2]>,
+        jaws: Option<[f64; 4]>,
+    ) -> Result<Self> {
+        if boundaries.len() != leaves.len() + 1 {
+            return Err(Error::InvalidArgument(format!(
+                "{} leaf pairs for {} boundaries",
+                leaves.len(),
+                boundaries.len()
+            )));
+        }
+        if boundaries.windows(2).any(|b| b[1] <= b[0]) {
+            return Err(Error::InvalidArgument(
+                "leaf pair boundaries not ascending".to_string(),
+            ));
+        }
+        if leaves.iter().any(|l| l[1] < l[0]) {
+            return Err(Error::InvalidArgument(
+                "bank A leaf beyond bank B leaf".to_string(),
+            ));
+        }
+        Ok(Aperture {
+            boundaries,
+            leaves,
+            jaws,
+        })
+    }
+
+    /// All pairs of `mlc` closed at the central axis.
+    pub fn closed(mlc: &Mlc) -> Self {
+        Aperture {
+            boundaries: mlc.boundaries.clone(),
+            leaves: vec![[0.0, 0.0]; mlc.pairs()],
+            jaws: None,
+        }
+    }
+
+    /// The aperture of `point` on `mlc`; without leaves, every pair opens to the jaws.
+    pub fn from_control_point(mlc: &Mlc, point: &ControlPoint) -> Result<Self> {
+        let leaves = match (&point.leaves, point.jaws) {
+            (Some(leaves), _) => leaves.clone(),
+            (None, Some(jaws)) => vec![[jaws[0], jaws[1]]; mlc.pairs()],
+            (None, None) => {
+                return Err(Error::InvalidArgument(
+                    "control point without leaves or jaws".to_string(),
+                ))
+            }
+        };
+        Aperture::new(mlc.boundaries.clone(), leaves, point.jaws)
+    }
+
+    /// The aperture of a direct aperture optimization segment on `mlc`, with the jaws on its
+    /// open rows.
+    pub fn from_segment(segment: &Segment, mlc: &Mlc) -> Self {
+        let jaws = segment.jaws();
+        Aperture {
+            boundaries: mlc.boundaries.clone(),
+            leaves: segment.leaves_on(mlc),
+            jaws: if jaws.iter().all(|j| j.is_finite()) {
+                Some(jaws)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Conforms the leaves of `mlc` to the beam's eye view of `structure` from `geometry`,
+    /// expanded by `margin` (mm) in `x` and `y`, with the jaws on the expanded projection. A
+    /// pair opens over the full extent of the projection anywhere across its width, so the
+    /// leaves cover the structure rather than cut into it. The projection is that of the
+    /// outlines of the contours.
+    pub fn fit(
+        structure: &Structure,
+        geometry: &BeamGeometry,
+        mlc: &Mlc,
+        margin: f64,
+    ) -> Result<Self> {
+        if margin.is_nan() || margin < 0.0 {
+            return Err(Error::InvalidArgument(format!(
+                "negative margin {}",
+                margin
+            )));
+        }
+        let edges = project(structure, geometry);
+        let mut jaws = [
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+        for e in &edges {
+            for p in e {
+                jaws = [
+                    jaws[0].min(p[0]),
+                    jaws[1].max(p[0]),
+                    jaws[2].min(p[1]),
+                    jaws[3].max(p[1]),
+                ];
+            }
+        }
+        if !jaws[0].is_finite() {
+            return Err(Error::InvalidArgument(format!(
+                "structure {} does not project into the beam",
+                structure.name
+            )));
+        }
+        let leaves = mlc
+            .boundaries
+            .windows(2)
+            .map(|b| {
+                let (lo, hi) = (b[0] - margin, b[1] + margin);
+                let (mut a, mut z) = (f64::INFINITY, f64::NEG_INFINITY);
+                for x in edges.iter().filter_map(|e| clip(e, lo, hi)) {
+                    a = a.min(x[0]);
+                    z = z.max(x[1]);
+                }
+                if a <= z {
+                    [a - margin, z + margin]
+                } else {
+                    let c = 0.5 * (jaws[0] + jaws[1]);
+                    [c, c]
+                }
+            })
+            .collect();
+        Ok(Aperture {
+            boundaries: mlc.boundaries.clone(),
+            leaves,
+            jaws: Some([
+                jaws[0] - margin,
+                jaws[1] + margin,
+                jaws[2] - margin,
+                jaws[3] + margin,
+            ]),
+        })
+    }
+
+    pub fn pairs(&self) -> usize {
+        self.leaves.len()
+    }
+
+    /// Control point at cumulative meterset weight `weight` delivering the aperture.
+    pub fn control_point(&self, weight: f64) -> ControlPoint {
+        ControlPoint {
+            weight,
+            jaws: self.jaws,
+            leaves: Some(self.leaves.clone()),
+        }
+    }
+
+    /// Open rectangle `[x1, x2, y1, y2]` (mm) of every leaf pair within the jaws; `None` for
+    /// pairs closed by their leaves or the jaws.
+    pub fn openings(&self) -> Vec<Option<[f64; 4]>> {
+        let jaws = self.jaws.unwrap_or([
+            f64::NEG_INFINITY,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::INFINITY,
+        ]);
+        self.leaves
+            .iter()
+            .zip(self.boundaries.windows(2))
+            .map(|(l, b)| {
+                let r = [
+                    l[0].max(jaws[0]),
+                    l[1].min(jaws[1]),
+                    b[0].max(jaws[2]),
+                    b[1].min(jaws[3]),
+                ];
+                if r[1] > r[0] && r[3] > r[2] {
+                    Some(r)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.openings().iter().any(|o| o.is_some())
+    }
+
+    /// mm²
+    pub fn area(&self) -> f64 {
+        self.openings()
+            .iter()
+            .flatten()
+            .map(|r| (r[1] - r[0]) * (r[3] - r[2]))
+            .sum()
+    }
+
+    /// Length (mm) of the outline of the open area: the leaf tips, the jaw edges and the parts
+    /// of the leaf sides not shared with an open neighbouring pair.
+    pub fn perimeter(&self) -> f64 {
+        let openings = self.openings();
+        let mut perimeter = 0.0;
+        let mut previous: Option<[f64; 4]> = None;
+        for o in openings.iter().chain(std::iter::once(&None)) {
+            if let Some(r) = o {
+                perimeter += 2.0 * (r[3] - r[2]);
+            }
+            perimeter += match (previous, o) {
+                (Some(p), Some(r)) if (r[2] - p[3]).abs() < 1e-9 => {
+                    let overlap = (p[1].min(r[1]) - p[0].max(r[0])).max(0.0);
+                    (p[1] - p[0]) + (r[1] - r[0]) - 2.0 * overlap
+                }
+                (p, r) => p.map_or(0.0, |p| p[1] - p[0]) + r.map_or(0.0, |r| r[1] - r[0]),
+            };
+            previous = *o;
+        }
+        perimeter
+    }
+
+    /// Opens every pair with a gap narrower than `min_gap` (mm) symmetrically to `min_gap`;
+    /// closed pairs stay closed. Returns the pairs that were opened.
+    pub fn enforce_min_gap(&mut self, min_gap: f64) -> Vec<usize> {
+        let mut opened = Vec::new();
+        for (n, l) in self.leaves.iter_mut().enumerate() {
+            let gap = l[1] - l[0];
+            if gap > 0.0 && gap < min_gap {
+                let c = 0.5 * (l[0] + l[1]);
+                *l = [c - 0.5 * min_gap, c + 0.5 * min_gap];
+                opened.push(n);
+            }
+        }
+        opened
+    }
+
+    /// The smallest aperture covering both, every pair open over the span of both openings.
+    pub fn union(&self, other: &Aperture) -> Result<Aperture> {
+        self.combine(other, |a, b| match (a, b) {
+            (Some(a), Some(b)) => Some([a[0].min(b[0]), a[1].max(b[1])]),
+            (a, b) => a.or(b),
+        })
+        .map(|mut aperture| {
+            aperture.jaws = match (self.jaws, other.jaws) {
+                (Some(a), Some(b)) => Some([
+                    a[0].min(b[0]),
+                    a[1].max(b[1]),
+                    a[2].min(b[2]),
+                    a[3].max(b[3]),
+                ]),
+                _ => None,
+            };
+            aperture
+        })
+    }
+
+    /// The open area of both.
+    pub fn intersection(&self, other: &Aperture) -> Result<Aperture> {
+        self.combine(other, |a, b| match (a, b) {
+            (Some(a), Some(b)) => Some([a[0].max(b[0]), a[1].min(b[1])]),
+            _ => None,
+        })
+        .map(|mut aperture| {
+            aperture.jaws = match (self.jaws, other.jaws) {
+                (Some(a), Some(b)) => Some([
+                    a[0].max(b[0]),
+                    a[1].min(b[1]),
+                    a[2].max(b[2]),
+                    a[3].min(b[3]),
+                ]),
+                (a, b) => a.or(b),
+            };
+            aperture
+        })
+    }
+
+    /// Combines the openings of every pair within the jaws with `f`; pairs left without an
+    /// opening close at the center of the leaves of `self`.
+    fn combine<F>(&self, other: &Aperture, f: F) -> Result<Aperture>
+    where
+        F: Fn(Option<[f64; 2]>, Option<[f64; 2]>) -> Option<[f64; 2]>,
+    {
+        if self.boundaries != other.boundaries {
+            return Err(Error::InvalidArgument(
+                "apertures on different leaf pairs".to_string(),
+            ));
+        }
+        let x = |o: &Option<[f64; 4]>| o.map(|r| [r[0], r[1]]);
+        let leaves = self
+            .openings()
+            .iter()
+            .zip(&other.openings())
+            .zip(&self.leaves)
+            .map(|((a, b), l)| match f(x(a), x(b)) {
+                Some(x) if x[1] > x[0] => x,
+                _ => {
+                    let c = 0.5 * (l[0] + l[1]);
+                    [c, c]
+                }
+            })
+            .collect();
+        Ok(Aperture {
+            boundaries: self.boundaries.clone(),
+            leaves,
+            jaws: None,
+        })
+    }
+}
+
+/// Edges of the contours of `structure` projected onto the isocenter plane of `geometry`.
+fn project(structure: &Structure, geometry: &BeamGeometry) -> Vec<[[f64; 2]; 2]> {
+    let mut edges = Vec::new();
+    for contour in &structure.contours {
+        let points: Vec<Option<[f64; 2]>> = contour
+            .points
+            .iter()
+            .map(|p| geometry.project(*p).map(|(x, y, _)| [x, y]))
+            .collect();
+        let n = points.len();
+        if n == 1 {
+            if let Some(p) = points[0] {
+                edges.push([p, p]);
+            }
+        }
+        let closing = if contour.closed && n > 2 {
+            n
+        } else {
+            n.saturating_sub(1)
+        };
+        for k in 0..closing {
+            if let (Some(p), Some(q)) = (points[k], points[(k + 1) % n]) {
+                edges.push([p, q]);
+            }
+        }
+    }
+    edges
+}
+
+/// `x` extent of `edge` between `y` = `lo` and `hi`.
+fn clip(edge: &[[f64; 2]; 2], lo: f64, hi: f64) -> Option<[f64; 2]> {
+    let [p, q] = edge;
+    let dy = q[1] - p[1];
+    let (t0, t1) = if dy.abs() < 1e-12 {
+        if p[1] < lo || p[1] > hi {
+            return None;
+        }
+        (0.0, 1.0)
+    } else {
+        let (a, b) = ((lo - p[1]) / dy, (hi - p[1]) / dy);
+        (a.min(b).max(0.0), a.max(b).min(1.0))
+    };
+    if t0 > t1 {
+        return None;
+    }
+    let x0 = p[0] + t0 * (q[0] - p[0]);
+    let x1 = p[0] + t1 * (q[0] - p[0]);
+    Some([x0.min(x1), x0.max(x1)])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aperture::Aperture;
+    use crate::coords::Vec3;
+    use crate::dose::beam::BeamGeometry;
+    use crate::dose::commissioning::MlcData;
+    use crate::dose::delivery::Mlc;
+    use crate::structure::{Contour, Structure, StructureType};
+
+    #[test]
+    fn aperture_geometry() {
+        let mut aperture =
+            Aperture::new(vec![-10.0, 0.0, 10.0], vec![[-5.0, 5.0], [0.0, 10.0]], None).unwrap();
+        assert_eq!(aperture.area(), 200.0);
+        assert_eq!(aperture.perimeter(), 70.0);
+        aperture.jaws = Some([-2.0, 20.0, -10.0, 5.0]);
+        assert_eq!(aperture.area(), 120.0);
+        assert_eq!(aperture.perimeter(), 54.0);
+        assert!(Aperture::new(vec![0.0, 1.0], vec![], None).is_err());
+        assert!(Aperture::new(vec![0.0, 1.0], vec![[1.0, 0.0]], None).is_err());
+
+        let a =
+            Aperture::new(vec![-10.0, 0.0, 10.0], vec![[-5.0, 5.0], [0.0, 10.0]], None).unwrap();
+        let b =
+            Aperture::new(vec![-10.0, 0.0, 10.0], vec![[-8.0, -6.0], [2.0, 4.0]], None).unwrap();
+        assert_eq!(a.union(&b).unwrap().leaves, vec![[-8.0, 5.0], [0.0, 10.0]]);
+        let both = a.intersection(&b).unwrap();
+        assert_eq!(both.leaves, vec![[0.0, 0.0], [2.0, 4.0]]);
+        assert_eq!(both.area(), 20.0);
+        let other = Aperture::new(vec![0.0, 10.0], vec![[0.0, 1.0]], None).unwrap();
+        assert!(a.union(&other).is_err());
+
+        let mut narrow =
+            Aperture::new(vec![0.0, 5.0, 10.0], vec![[1.0, 1.2], [0.0, 0.0]], None).unwrap();
+        assert_eq!(narrow.enforce_min_gap(1.0), vec![0]);
+        assert!((narrow.leaves[0][0] - 0.6).abs() < 1e-12);
+        assert!((narrow.leaves[0][1] - 1.6).abs() < 1e-12);
+        assert_eq!(narrow.leaves[1], [0.0, 0.0]);
+    }
+
+    #[test]
+    fn fit_to_target() {
+        // A 20 mm cube at the isocenter seen from anterior: its anterior face, 10 mm closer
+        // to the source, projects largest.
+        let contours = [-10.0, -5.0, 0.0, 5.0, 10.0]
+            .iter()
+            .map(|z| {
+                Contour::new(vec![
+                    Vec3::from(-10.0, -10.0, *z),
+                    Vec3::from(10.0, -10.0, *z),
+                    Vec3::from(10.0, 10.0, *z),
+                    Vec3::from(-10.0, 10.0, *z),
+                ])
+            })
+            .collect();
+        let cube = Structure::new("PTV", StructureType::Ptv).with_contours(contours);
+        let geometry = BeamGeometry::new(Vec3::new(), 0.0, 1000.0);
+        let mlc = Mlc::uniform(
+            8,
+            5.0,
+            MlcData {
+                transmission: 0.0,
+                dlg: 0.0,
+            },
+        );
+        let edge = 10.0 * 1000.0 / 990.0;
+        let fitted = Aperture::fit(&cube, &geometry, &mlc, 0.0).unwrap();
+        assert_eq!(fitted.leaves[0][0], fitted.leaves[0][1]);
+        assert_eq!(fitted.leaves[7][0], fitted.leaves[7][1]);
+        for l in &fitted.leaves[1..7] {
+            assert!((l[0] + edge).abs() < 1e-9 && (l[1] - edge).abs() < 1e-9);
+        }
+        let jaws = fitted.jaws.unwrap();
+        assert!((jaws[0] + edge).abs() < 1e-9 && (jaws[3] - edge).abs() < 1e-9);
+        assert!((fitted.area() - 4.0 * edge * edge).abs() < 1e-6);
+
+        let margin = Aperture::fit(&cube, &geometry, &mlc, 5.0).unwrap();
+        for l in &margin.leaves {
+            assert!((l[0] + edge + 5.0).abs() < 1e-9 && (l[1] - edge - 5.0).abs() < 1e-9);
+        }
+        assert!(margin.area() > fitted.area());
+        let covered = margin.intersection(&fitted).unwrap();
+        assert!((covered.area() - fitted.area()).abs() < 1e-6);
+        assert!((margin.union(&fitted).unwrap().area() - margin.area()).abs() < 1e-6);
+
+        let empty = Structure::new("Empty", StructureType::Oar);
+        assert!(Aperture::fit(&empty, &geometry, &mlc, 0.0).is_err());
+        assert!(Aperture::fit(&cube, &geometry, &mlc, -1.0).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! Mask-based boolean operations on structures.
+//!
+//! Structures are rasterized on a common grid, combined voxel by voxel and contoured again,
+//! so expressions such as `PTV - (OAR + 3 mm)` compose through [`combine_masks`].
+
+use crate::contouring::extract_contours;
+use crate::error::Result;
+use crate::grid::{Grid3, GridGeometry};
+use crate::raster::rasterize;
+use crate::structure::{Structure, StructureType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BooleanOp {
+    Union,
+    Intersection,
+    Subtraction,
+    Xor,
+}
+
+impl BooleanOp {
+    pub fn apply(self, a: bool, b: bool) -> bool {
+        match self {
+            BooleanOp::Union => a || b,
+            BooleanOp::Intersection => a && b,
+            BooleanOp::Subtraction => a && !b,
+            BooleanOp::Xor => a != b,
+        }
+    }
+
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BooleanOp::Union => "+",
+            BooleanOp::Intersection => "&",
+            BooleanOp::Subtraction => "-",
+            BooleanOp::Xor => "^",
+        }
+    }
+}
+
+pub fn combine_masks(a: &Grid3<bool>, b: &Grid3<bool>, op: BooleanOp) -> Result<Grid3<bool>> {
+    a.zip_map(b, |x, y| op.apply(*x, *y))
+}
+
+/// Combines two structures on `geometry` into a control structure named e.g. `PTV-RECTUM`.
+pub fn combine(a: &Structure, b: &Structure, op: BooleanOp, geometry: &GridGeometry) -> Structure {
+    let mask = combine_masks(&rasterize(a, geometry), &rasterize(b, geometry), op)
+        .expect("masks share the same geometry");
+    let name = format!("{}{}{}", a.name, op.symbol(), b.name);
+    Structure::new(&name, StructureType::Control)
+        .with_color(a.color)
+        .with_contours(extract_contours(&mask, 0.0))
+}
+
+pub fn union(a: &Structure, b: &Structure, geometry: &GridGeometry) -> Structure {
+    combine(a, b, BooleanOp::Union, geometry)
+}
+
+pub fn intersection(a: &Structure, b: &Structure, geometry: &GridGeometry) -> Structure {
+    combine(a, b, BooleanOp::Intersection, geometry)
+}
+
+pub fn subtract(a: &Structure, b: &Structure, geometry: &GridGeometry) -> Structure {
+    combine(a, b, BooleanOp::Subtraction, geometry)
+}
+
+pub fn xor(a: &Structure, b: &Structure, geometry: &GridGeometry) -> Structure {
+    combine(a, b, BooleanOp::Xor, geometry)
+}
+
+/// Union of all `structures` as a mask on `geometry`.
+pub fn union_mask(structures: &[&Structure], geometry: &GridGeometry) -> Grid3<bool> {
+    let mut mask = Grid3::new(geometry.clone(), false);
+    for s in structures {
+        for (m, v) in mask
+            .data_mut()
+            .iter_mut()
+            .zip(rasterize(s, geometry).data())
+        {
+            *m |= *v;
+        }
+    }
+    mask
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::boolean::{
+        combine_masks, intersection, subtract, union, union_mask, xor, BooleanOp,
+    };
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::raster::rasterize;
+    use crate::structure::{Contour, Structure, StructureType};
+
+    fn square(name: &str, cx: f64, half: f64) -> Structure {
+        let contours = (0..3)
+            .map(|k| {
+                let z = k as f64;
+                Contour::new(vec![
+                    Vec3::from(cx - half, -half, z),
+                    Vec3::from(cx + half, -half, z),
+                    Vec3::from(cx + half, half, z),
+                    Vec3::from(cx - half, half, z),
+                ])
+            })
+            .collect();
+        Structure::new(name, StructureType::Oar).with_contours(contours)
+    }
+
+    fn geometry() -> GridGeometry {
+        GridGeometry::new(
+            [20, 20, 3],
+            Vec3::from(-9.5, -9.5, 0.0),
+            Vec3::from(1.0, 1.0, 1.0),
+        )
+    }
+
+    fn count(mask: &Grid3<bool>) -> usize {
+        mask.data().iter().filter(|v| **v).count()
+    }
+
+    #[test]
+    fn boolean_op_apply() {
+        let table = [
+            (BooleanOp::Union, [false, true, true, true]),
+            (BooleanOp::Intersection, [false, false, false, true]),
+            (BooleanOp::Subtraction, [false, true, false, false]),
+            (BooleanOp::Xor, [false, true, true, false]),
+        ];
+        for (op, expected) in table.iter() {
+            let got = [
+                op.apply(false, false),
+                op.apply(true, false),
+                op.apply(false, true),
+                op.apply(true, true),
+            ];
+            assert_eq!(&got, expected);
+        }
+    }
+
+    #[test]
+    fn boolean_structures() {
+        let g = geometry();
+        let a = square("PTV", -2.0, 4.0);
+        let b = square("RECTUM", 2.0, 4.0);
+        let voxels = |s: &Structure| count(&rasterize(s, &g));
+        // 8x8 squares shifted by 4 voxels overlap in a 4x8 band on each of 3 slices.
+        assert_eq!(voxels(&union(&a, &b, &g)), 3 * 96);
+        assert_eq!(voxels(&intersection(&a, &b, &g)), 3 * 32);
+        assert_eq!(voxels(&xor(&a, &b, &g)), 3 * 64);
+        let s = subtract(&a, &b, &g);
+        assert_eq!(s.name, "PTV-RECTUM");
+        assert_eq!(s.structure_type, StructureType::Control);
+        assert_eq!(voxels(&s), 3 * 32);
+    }
+
+    #[test]
+    fn boolean_masks() {
+        let g = geometry();
+        let a = square("A", -2.0, 4.0);
+        let b = square("B", 2.0, 4.0);
+        let both = union_mask(&[&a, &b], &g);
+        let inter = combine_masks(
+            &rasterize(&a, &g),
+            &rasterize(&b, &g),
+            BooleanOp::Intersection,
+        )
+        .unwrap();
+        assert_eq!(count(&both), 3 * 96);
+        assert_eq!(count(&inter), 3 * 32);
+        let other = Grid3::new(
+            GridGeometry::new(
+                [2, 2, 2],
+                Vec3::from(0.0, 0.0, 0.0),
+                Vec3::from(1.0, 1.0, 1.0),
+            ),
+            false,
+        );
+        assert!(combine_masks(&both, &other, BooleanOp::Union).is_err());
+    }
+}
@@ -0,0 +1,629 @@
+//! Gantry, couch and patient collision checking.
+//!
+//! The gantry head is a cylinder on the beam axis, its face at a fixed distance from the
+//! isocenter towards the source. The couch top is a box and the patient the points of the
+//! body contours, both in patient coordinates, so that they turn together with the couch while
+//! the head follows the gantry, collimator and couch angles of [`BeamGeometry::axes`]. The
+//! clearance to the patient is less its margin; the clearance to the couch top is sampled over
+//! the faces and rim of the head. Between two control points of an arc the gantry is checked
+//! every [`CollisionModel::arc_step`] degrees, so that a collision halfway between sparse
+//! control points is not missed.
+
+use std::fmt;
+
+use crate::coords::Vec3;
+use crate::dose::beam::BeamGeometry;
+use crate::error::{Error, Result};
+use crate::plan::{Beam, Plan};
+use crate::structure::Structure;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GantryHead {
+    /// mm
+    pub radius: f64,
+    /// From the isocenter to the face of the head (mm).
+    pub distance: f64,
+    /// From the face of the head towards the source (mm).
+    pub depth: f64,
+}
+
+impl Default for GantryHead {
+    fn default() -> Self {
+        GantryHead {
+            radius: 380.0,
+            distance: 400.0,
+            depth: 500.0,
+        }
+    }
+}
+
+/// Couch top in patient coordinates, extending caudally from its head end.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CouchTop {
+    /// Lateral center `x` (mm).
+    pub center: f64,
+    /// `y` (mm) of the surface the patient lies on.
+    pub surface: f64,
+    /// `z` (mm) of the end towards the head of the patient.
+    pub head_end: f64,
+    /// mm
+    pub width: f64,
+    /// mm
+    pub thickness: f64,
+    /// mm
+    pub length: f64,
+}
+
+impl CouchTop {
+    /// A 530 mm wide, 50 mm thick and 2000 mm long couch top under `body`, centered on it,
+    /// with its surface at the posterior extent of the body and its end 100 mm beyond the head.
+    pub fn under(body: &Structure) -> Result<Self> {
+        let points = || body.contours.iter().flat_map(|c| c.points.iter());
+        let surface = points().map(|p| p.y).fold(f64::NEG_INFINITY, f64::max);
+        if !surface.is_finite() {
+            return Err(Error::InvalidArgument(format!(
+                "structure {} has no contour points",
+                body.name
+            )));
+        }
+        let (lo, hi) = points().fold((f64::INFINITY, f64::NEG_INFINITY), |a, p| {
+            (a.0.min(p.x), a.1.max(p.x))
+        });
+        let head = points().map(|p| p.z).fold(f64::NEG_INFINITY, f64::max);
+        Ok(CouchTop {
+            center: 0.5 * (lo + hi),
+            surface,
+            head_end: head + 100.0,
+            width: 530.0,
+            thickness: 50.0,
+            length: 2000.0,
+        })
+    }
+
+    /// Signed distance (mm) from `p`, negative inside.
+    fn distance(&self, p: Vec3<f64>) -> f64 {
+        let lo = [
+            self.center - 0.5 * self.width,
+            self.surface,
+            self.head_end - self.length,
+        ];
+        let hi = [
+            self.center + 0.5 * self.width,
+            self.surface + self.thickness,
+            self.head_end,
+        ];
+        let p = [p.x, p.y, p.z];
+        let q: Vec<f64> = (0..3).map(|i| (lo[i] - p[i]).max(p[i] - hi[i])).collect();
+        let outside = q.iter().map(|d| d.max(0.0).powi(2)).sum::<f64>().sqrt();
+        let inside = q.iter().cloned().fold(f64::NEG_INFINITY, f64::max).min(0.0);
+        outside + inside
+    }
+
+    fn corners(&self) -> Vec<Vec3<f64>> {
+        let mut corners = Vec::with_capacity(8);
+        for x in &[-0.5, 0.5] {
+            for y in &[0.0, 1.0] {
+                for z in &[0.0, 1.0] {
+                    corners.push(Vec3::from(
+                        self.center + x * self.width,
+                        self.surface + y * self.thickness,
+                        self.head_end - z * self.length,
+                    ));
+                }
+            }
+        }
+        corners
+    }
+}
+
+/// Which object comes too close to the gantry head.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Object {
+    Patient,
+    Couch,
+}
+
+/// Clearance (mm) of the gantry head at one position, negative when overlapping; `None` for
+/// objects not in the model.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Clearance {
+    pub patient: Option<f64>,
+    pub couch: Option<f64>,
+}
+
+/// A range of a beam over which the gantry head comes closer to an object than the minimum
+/// clearance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Collision {
+    pub beam: usize,
+    pub object: Object,
+    /// First and last control point of the range; between control points, the one the range
+    /// starts or ends after.
+    pub control_points: [usize; 2],
+    /// Gantry angles (degrees) at which the range starts and ends.
+    pub gantry: [f64; 2],
+    /// Smallest clearance (mm) over the range.
+    pub clearance: f64,
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "beam {}, gantry {} to {} degrees: {} mm from the {}",
+            self.beam,
+            self.gantry[0],
+            self.gantry[1],
+            self.clearance,
+            match self.object {
+                Object::Patient => "patient",
+                Object::Couch => "couch",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollisionModel {
+    pub head: GantryHead,
+    pub couch: Option<CouchTop>,
+    /// Points (mm) on the surface of the patient.
+    pub patient: Vec<Vec3<f64>>,
+    /// Margin (mm) around the patient points.
+    pub margin: f64,
+    /// Clearance (mm) below which a position is flagged.
+    pub min_clearance: f64,
+    /// Largest gantry rotation (degrees) between checked positions of an arc.
+    pub arc_step: f64,
+}
+
+impl Default for CollisionModel {
+    fn default() -> Self {
+        CollisionModel {
+            head: GantryHead::default(),
+            couch: None,
+            patient: Vec::new(),
+            margin: 0.0,
+            min_clearance: 20.0,
+            arc_step: 1.0,
+        }
+    }
+}
+
+impl CollisionModel {
+    pub fn new(head: GantryHead) -> Self {
+        CollisionModel {
+            head,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_couch(mut self, couch: CouchTop) -> Self {
+        self.couch = Some(couch);
+        self
+    }
+
+    /// The patient envelope: the contour points of `body` expanded by `margin` (mm), e.g. for
+    /// arms, immobilization and setup variation. Contours should be sampled finer than the
+    /// clearance that matters.
+    pub fn with_patient(mut self, body: &Structure, margin: f64) -> Self {
+        self.patient = body
+            .contours
+            .iter()
+            .flat_map(|c| c.points.iter().cloned())
+            .collect();
+        self.margin = margin;
+        self
+    }
+
+    pub fn with_min_clearance(mut self, min_clearance: f64) -> Self {
+        self.min_clearance = min_clearance;
+        self
+    }
+
+    /// Clearance of the gantry head at `geometry`.
+    pub fn clearance(&self, geometry: &BeamGeometry) -> Clearance {
+        let head = Cylinder::new(&self.head, geometry);
+        let patient = if self.patient.is_empty() {
+            None
+        } else {
+            Some(
+                self.patient
+                    .iter()
+                    .map(|p| head.distance(*p))
+                    .fold(f64::INFINITY, f64::min)
+                    - self.margin,
+            )
+        };
+        let couch = self.couch.map(|couch| {
+            let sampled = head
+                .samples()
+                .iter()
+                .map(|p| couch.distance(*p))
+                .fold(f64::INFINITY, f64::min);
+            couch
+                .corners()
+                .iter()
+                .map(|p| head.distance(*p))
+                .fold(sampled, f64::min)
+        });
+        Clearance { patient, couch }
+    }
+
+    /// Every range of the beams of `plan` with the gantry head closer to the patient or couch
+    /// than the minimum clearance; beams are indexed as in [`Plan::beams`].
+    pub fn check(&self, plan: &Plan) -> Result<Vec<Collision>> {
+        if self.arc_step.is_nan() || self.arc_step <= 0.0 {
+            return Err(Error::InvalidArgument(format!(
+                "arc step {} degrees",
+                self.arc_step
+            )));
+        }
+        let mut collisions = Vec::new();
+        for (b, beam) in plan.beams.iter().enumerate() {
+            let mut open: [Option<Collision>; 2] = [None, None];
+            for (control_point, geometry) in self.positions(beam) {
+                let clearance = self.clearance(&geometry);
+                let objects = [
+                    (Object::Patient, clearance.patient),
+                    (Object::Couch, clearance.couch),
+                ];
+                for (slot, (object, clearance)) in open.iter_mut().zip(&objects) {
+                    match (clearance, slot.as_mut()) {
+                        (Some(c), Some(collision)) if *c < self.min_clearance => {
+                            collision.control_points[1] = control_point;
+                            collision.gantry[1] = geometry.gantry;
+                            collision.clearance = collision.clearance.min(*c);
+                        }
+                        (Some(c), None) if *c < self.min_clearance => {
+                            *slot = Some(Collision {
+                                beam: b,
+                                object: *object,
+                                control_points: [control_point; 2],
+                                gantry: [geometry.gantry; 2],
+                                clearance: *c,
+                            });
+                        }
+                        _ => collisions.extend(slot.take()),
+                    }
+                }
+            }
+            collisions.extend(open.iter_mut().filter_map(|c| c.take()));
+        }
+        Ok(collisions)
+    }
+
+    /// Geometry at every control point of `beam` and every arc step between them, with the
+    /// control point it is at or after.
+    fn positions(&self, beam: &Beam) -> Vec<(usize, BeamGeometry)> {
+        let mut positions = Vec::new();
+        let mut previous: Option<BeamGeometry> = None;
+        for n in 0..beam.control_points.len() {
+            let geometry = match beam.geometry(n) {
+                Some(g) => g,
+                None => continue,
+            };
+            if let Some(previous) = previous {
+                let turn = signed(previous.gantry, geometry.gantry);
+                let couch = signed(previous.couch, geometry.couch);
+                let steps = (turn.abs() / self.arc_step).ceil() as usize;
+                for k in 1..steps {
+                    let t = k as f64 / steps as f64;
+                    let mut step = geometry;
+                    step.gantry = (previous.gantry + t * turn).rem_euclid(360.0);
+                    step.couch = (previous.couch + t * couch).rem_euclid(360.0);
+                    positions.push((n - 1, step));
+                }
+            }
+            positions.push((n, geometry));
+            previous = Some(geometry);
+        }
+        positions
+    }
+}
+
+/// Shortest signed rotation (degrees) from `a` to `b`.
+fn signed(a: f64, b: f64) -> f64 {
+    (b - a + 180.0).rem_euclid(360.0) - 180.0
+}
+
+/// The gantry head at one beam geometry.
+struct Cylinder {
+    face: Vec3<f64>,
+    axes: [Vec3<f64>; 3],
+    radius: f64,
+    depth: f64,
+}
+
+impl Cylinder {
+    fn new(head: &GantryHead, geometry: &BeamGeometry) -> Self {
+        let axes = geometry.axes();
+        Cylinder {
+            face: geometry.isocenter + axes[2].scale(head.distance),
+            axes,
+            radius: head.radius,
+            depth: head.depth,
+        }
+    }
+
+    /// Signed distance (mm) from `p`, negative inside.
+    fn distance(&self, p: Vec3<f64>) -> f64 {
+        let r = p - self.face;
+        let a = r.dot(self.axes[2]);
+        let radial = (r - self.axes[2].scale(a)).norm();
+        let axial = (-a).max(a - self.depth);
+        let outward = radial - self.radius;
+        if axial <= 0.0 && outward <= 0.0 {
+            axial.max(outward)
+        } else {
+            (axial.max(0.0).powi(2) + outward.max(0.0).powi(2)).sqrt()
+        }
+    }
+
+    /// Points on the face, back and rim.
+    fn samples(&self) -> Vec<Vec3<f64>> {
+        let [x, y, toward] = self.axes;
+        let mut samples = Vec::new();
+        for a in &[0.0, 0.5, 1.0] {
+            let center = self.face + toward.scale(a * self.depth);
+            let rings: &[f64] = if *a == 0.5 {
+                &[1.0]
+            } else {
+                &[0.0, 1.0 / 3.0, 2.0 / 3.0, 1.0]
+            };
+            for ring in rings {
+                for k in 0..36 {
+                    let (s, c) = (k as f64 * 10.0).to_radians().sin_cos();
+                    let r = ring * self.radius;
+                    samples.push(center + x.scale(r * c) + y.scale(r * s));
+                }
+            }
+        }
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::collision::{Clearance, CollisionModel, CouchTop, GantryHead, Object};
+    use crate::coords::Vec3;
+    use crate::dose::beam::BeamGeometry;
+    use crate::dose::delivery;
+    use crate::opt::vmat::ArcControlPoint;
+    use crate::plan::{Beam, Plan};
+    use crate::structure::{Contour, Structure, StructureType};
+
+    /// A cylinder of radius 150 mm along `z` around the origin.
+    fn body() -> Structure {
+        let contours = (-5..=5)
+            .map(|k| {
+                let z = 40.0 * k as f64;
+                Contour::new(
+                    (0..360)
+                        .map(|d| {
+                            let (s, c) = (d as f64).to_radians().sin_cos();
+                            Vec3::from(150.0 * c, 150.0 * s, z)
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+        Structure::new("BODY", StructureType::External).with_contours(contours)
+    }
+
+    fn point(weight: f64) -> delivery::ControlPoint {
+        delivery::ControlPoint {
+            weight,
+            jaws: None,
+            leaves: None,
+        }
+    }
+
+    /// An arc through `gantry` about `isocenter`.
+    fn arc(isocenter: Vec3<f64>, gantry: &[f64]) -> Beam {
+        let last = (gantry.len() - 1) as f64;
+        let points: Vec<ArcControlPoint> = gantry
+            .iter()
+            .enumerate()
+            .map(|(n, g)| ArcControlPoint {
+                gantry: *g,
+                point: point(n as f64 / last),
+                dose_rate: 600.0,
+                gantry_speed: 4.8,
+            })
+            .collect();
+        let geometry = BeamGeometry::new(isocenter, gantry[0], 1000.0);
+        Beam::arc(1, "Arc", "6X", &geometry, 100.0, &points)
+    }
+
+    #[test]
+    fn collision_detection() {
+        let body = body();
+        let couch = CouchTop::under(&body).unwrap();
+        assert_eq!((couch.surface, couch.head_end), (150.0, 300.0));
+        let model = CollisionModel::new(GantryHead::default())
+            .with_couch(couch)
+            .with_patient(&body, 10.0)
+            .with_min_clearance(30.0);
+
+        // Centered: 250 mm to the patient, less the margin, anteriorly and laterally; 200 mm to
+        // the underside of the couch from below.
+        let centered = |gantry| model.clearance(&BeamGeometry::new(Vec3::new(), gantry, 1000.0));
+        let anterior = centered(0.0);
+        assert!((anterior.patient.unwrap() - 240.0).abs() < 0.1);
+        assert!((centered(90.0).patient.unwrap() - 240.0).abs() < 0.1);
+        let posterior = centered(180.0);
+        assert!((posterior.couch.unwrap() - 200.0).abs() < 1e-6);
+
+        // A lateral isocenter 200 mm to the right brings the head within 50 mm of the
+        // patient's left side and into the couch top from gantry 90.
+        let shifted = BeamGeometry::new(Vec3::from(-200.0, 0.0, 0.0), 90.0, 1000.0);
+        let clearance = model.clearance(&shifted);
+        assert!((clearance.patient.unwrap() - 40.0).abs() < 0.1);
+        assert!(clearance.couch.unwrap() < 0.0);
+
+        // An arc from 10 to 170 degrees with only its end control points still hits the couch
+        // in between; a static anterior beam is clear.
+        let arc = Beam::arc(
+            1,
+            "Arc",
+            "6X",
+            &shifted,
+            100.0,
+            &[10.0, 170.0]
+                .iter()
+                .enumerate()
+                .map(|(n, g)| crate::opt::vmat::ArcControlPoint {
+                    gantry: *g,
+                    point: point(n as f64),
+                    dose_rate: 600.0,
+                    gantry_speed: 4.8,
+                })
+                .collect::<Vec<_>>(),
+        );
+        let anterior = BeamGeometry::new(Vec3::from(-200.0, 0.0, 0.0), 0.0, 1000.0);
+        let fixed = Beam::fixed(2, "AP", "6X", &anterior, 100.0, &[point(0.0), point(1.0)]);
+        let plan = Plan::new("Test").with_beam(fixed).with_beam(arc);
+        let collisions = model.check(&plan).unwrap();
+        assert!(!collisions.is_empty());
+        assert!(collisions.iter().all(|c| c.beam == 1));
+        let couch = collisions
+            .iter()
+            .find(|c| c.object == Object::Couch)
+            .unwrap();
+        assert_eq!(couch.control_points, [0, 0]);
+        assert!(couch.gantry[0] > 10.0 && couch.gantry[0] < 90.0);
+        assert!(couch.gantry[1] > 90.0 && couch.gantry[1] < 170.0);
+        assert!(couch.clearance < 0.0);
+        assert!(couch.to_string().starts_with("beam 1, gantry"));
+
+        let mut coarse = model.clone();
+        coarse.arc_step = 0.0;
+        assert!(coarse.check(&plan).is_err());
+    }
+
+    #[test]
+    fn couch_under_body() {
+        let couch = CouchTop::under(&body()).unwrap();
+        assert_eq!(couch.center, 0.0);
+        assert_eq!(couch.width, 530.0);
+        assert_eq!(couch.head_end - couch.length, -1700.0);
+        let empty = Structure::new("BODY", StructureType::External);
+        assert!(CouchTop::under(&empty).is_err());
+        let unsampled = Structure::new("BODY", StructureType::External)
+            .with_contours(vec![Contour::new(vec![])]);
+        assert!(CouchTop::under(&unsampled).is_err());
+    }
+
+    #[test]
+    fn clearance_of_objects_in_the_model() {
+        let body = body();
+        let geometry = BeamGeometry::new(Vec3::new(), 0.0, 1000.0);
+        let empty = CollisionModel::default();
+        assert_eq!(
+            empty.clearance(&geometry),
+            Clearance {
+                patient: None,
+                couch: None
+            }
+        );
+        let patient = CollisionModel::default().with_patient(&body, 0.0);
+        let clearance = patient.clearance(&geometry);
+        assert!((clearance.patient.unwrap() - 250.0).abs() < 0.1);
+        assert_eq!(clearance.couch, None);
+        // The margin comes off the clearance.
+        let margin = CollisionModel::default().with_patient(&body, 25.0);
+        assert!(
+            (margin.clearance(&geometry).patient.unwrap() - clearance.patient.unwrap() + 25.0)
+                .abs()
+                < 1e-9
+        );
+        // A head further out clears more.
+        let far = CollisionModel::new(GantryHead {
+            distance: 500.0,
+            ..GantryHead::default()
+        })
+        .with_patient(&body, 0.0);
+        assert!((far.clearance(&geometry).patient.unwrap() - 350.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn check_arguments() {
+        let plan = Plan::new("Test").with_beam(arc(Vec3::new(), &[0.0, 10.0]));
+        let mut model = CollisionModel::default();
+        assert_eq!(model.check(&plan).unwrap(), vec![]);
+        assert_eq!(model.check(&Plan::new("Empty")).unwrap(), vec![]);
+        for step in [0.0, -1.0, f64::NAN].iter() {
+            model.arc_step = *step;
+            assert!(model.check(&plan).is_err(), "{}", step);
+        }
+    }
+
+    #[test]
+    fn check_static_beams() {
+        let body = body();
+        let model = CollisionModel::default()
+            .with_couch(CouchTop::under(&body).unwrap())
+            .with_patient(&body, 10.0)
+            .with_min_clearance(30.0);
+        // The head 40 mm from the patient and in the couch top at gantry 90 for the whole
+        // beam; clear anteriorly.
+        let shifted = Vec3::from(-200.0, 0.0, 0.0);
+        let lateral = Beam::fixed(
+            1,
+            "LAT",
+            "6X",
+            &BeamGeometry::new(shifted, 90.0, 1000.0),
+            100.0,
+            &[point(0.0), point(0.5), point(1.0)],
+        );
+        let anterior = Beam::fixed(
+            2,
+            "AP",
+            "6X",
+            &BeamGeometry::new(shifted, 0.0, 1000.0),
+            100.0,
+            &[point(0.0), point(1.0)],
+        );
+        let plan = Plan::new("Test").with_beam(anterior).with_beam(lateral);
+        let collisions = model.check(&plan).unwrap();
+        assert_eq!(collisions.len(), 1);
+        let collision = &collisions[0];
+        assert_eq!(collision.beam, 1);
+        assert_eq!(collision.object, Object::Couch);
+        assert_eq!(collision.control_points, [0, 2]);
+        assert_eq!(collision.gantry, [90.0, 90.0]);
+        assert!(collision.clearance < 0.0);
+
+        // Within 50 mm of the patient, the patient collides too.
+        let strict = model.with_min_clearance(50.0);
+        let objects: Vec<Object> = strict
+            .check(&plan)
+            .unwrap()
+            .iter()
+            .map(|c| c.object)
+            .collect();
+        assert_eq!(objects, vec![Object::Patient, Object::Couch]);
+    }
+
+    #[test]
+    fn check_arc_ranges() {
+        let body = body();
+        let model = CollisionModel::default()
+            .with_couch(CouchTop::under(&body).unwrap())
+            .with_min_clearance(30.0);
+        // Into the couch top around gantry 90, across the control point there.
+        let beam = arc(Vec3::from(-200.0, 0.0, 0.0), &[10.0, 90.0, 170.0]);
+        let collisions = model.check(&Plan::new("Test").with_beam(beam)).unwrap();
+        assert_eq!(collisions.len(), 1);
+        let collision = &collisions[0];
+        assert_eq!(collision.control_points, [0, 1]);
+        assert!(collision.gantry[0] < 90.0 && collision.gantry[1] > 90.0);
+        // The arc turns the short way round through 0: no collision anteriorly.
+        let anterior = arc(Vec3::from(-200.0, 0.0, 0.0), &[330.0, 30.0]);
+        assert!(model
+            .check(&Plan::new("Test").with_beam(anterior))
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+//! Overlap and surface-distance metrics between two segmentations on a common grid.
+//!
+//! Surfaces are the set voxels with at least one unset 6-neighbour (the grid border counts
+//! as unset); surface distances run between surface voxel centers. HD95 is the 95th
+//! percentile (nearest rank) of the distances in both directions together, and surface
+//! Dice counts surface voxels within the tolerance of the other surface.
+
+use crate::distance::distance_transform;
+use crate::error::{Error, Result};
+use crate::grid::{Grid3, GridGeometry};
+use crate::raster::rasterize;
+use crate::structure::Structure;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ComparisonMetrics {
+    pub dice: f64,
+    pub jaccard: f64,
+    /// mm³
+    pub volume_a: f64,
+    /// mm³
+    pub volume_b: f64,
+    /// mm
+    pub hausdorff: f64,
+    /// mm
+    pub hd95: f64,
+    /// Mean of the surface distances in both directions (mm).
+    pub mean_surface_distance: f64,
+    pub surface_dice: f64,
+}
+
+pub fn dice(a: &Grid3<bool>, b: &Grid3<bool>) -> Result<f64> {
+    overlap(a, b).map(dice_of)
+}
+
+pub fn jaccard(a: &Grid3<bool>, b: &Grid3<bool>) -> Result<f64> {
+    overlap(a, b).map(jaccard_of)
+}
+
+/// Dice from (intersection, |a|, |b|) voxel counts; 1 for two empty masks.
+fn dice_of((both, na, nb): (usize, usize, usize)) -> f64 {
+    if na + nb == 0 {
+        1.0
+    } else {
+        2.0 * both as f64 / (na + nb) as f64
+    }
+}
+
+fn jaccard_of((both, na, nb): (usize, usize, usize)) -> f64 {
+    let union = na + nb - both;
+    if union == 0 {
+        1.0
+    } else {
+        both as f64 / union as f64
+    }
+}
+
+fn overlap(a: &Grid3<bool>, b: &Grid3<bool>) -> Result<(usize, usize, usize)> {
+    if a.dims() != b.dims() {
+        return Err(Error::InvalidArgument(format!(
+            "masks differ in size: {:?} vs {:?}",
+            a.dims(),
+            b.dims()
+        )));
+    }
+    let mut counts = (0, 0, 0);
+    for (x, y) in a.data().iter().zip(b.data()) {
+        counts.0 += (*x && *y) as usize;
+        counts.1 += *x as usize;
+        counts.2 += *y as usize;
+    }
+    Ok(counts)
+}
+
+/// Set voxels with an unset (or out-of-grid) face neighbour.
+pub fn surface(mask: &Grid3<bool>) -> Grid3<bool> {
+    let [nx, ny, nz] = mask.dims();
+    let mut out = Grid3::new(mask.geometry().clone(), false);
+    for k in 0..nz {
+        for j in 0..ny {
+            for i in 0..nx {
+                if !mask[[i, j, k]] {
+                    continue;
+                }
+                let border =
+                    i == 0 || j == 0 || k == 0 || i + 1 == nx || j + 1 == ny || k + 1 == nz;
+                let edge = border
+                    || !mask[[i - 1, j, k]]
+                    || !mask[[i + 1, j, k]]
+                    || !mask[[i, j - 1, k]]
+                    || !mask[[i, j + 1, k]]
+                    || !mask[[i, j, k - 1]]
+                    || !mask[[i, j, k + 1]];
+                out.set(i, j, k, edge);
+            }
+        }
+    }
+    out
+}
+
+/// Distances from every surface voxel of `from` to the surface of `to`.
+fn directed_distances(from: &Grid3<bool>, to: &Grid3<bool>) -> Vec<f64> {
+    let d = distance_transform(to);
+    from.data()
+        .iter()
+        .zip(d.data())
+        .filter(|(s, _)| **s)
+        .map(|(_, d)| *d)
+        .collect()
+}
+
+pub fn compare(a: &Grid3<bool>, b: &Grid3<bool>, tolerance: f64) -> Result<ComparisonMetrics> {
+    let counts = overlap(a, b)?;
+    let voxel = a.geometry().voxel_volume();
+    let (sa, sb) = (surface(a), surface(b));
+    let ab = directed_distances(&sa, &sb);
+    let ba = directed_distances(&sb, &sa);
+    let mut all: Vec<f64> = ab.iter().chain(ba.iter()).copied().collect();
+    all.sort_by(|x, y| x.partial_cmp(y).unwrap());
+    let (hausdorff, hd95, mean_surface_distance, surface_dice) = if all.is_empty() {
+        (0.0, 0.0, 0.0, 1.0)
+    } else if ab.is_empty() || ba.is_empty() {
+        (f64::INFINITY, f64::INFINITY, f64::INFINITY, 0.0)
+    } else {
+        let rank = ((0.95 * all.len() as f64).ceil() as usize).max(1) - 1;
+        let within = all.iter().filter(|d| **d <= tolerance).count();
+        (
+            all[all.len() - 1],
+            all[rank],
+            all.iter().sum::<f64>() / all.len() as f64,
+            within as f64 / all.len() as f64,
+        )
+    };
+    Ok(ComparisonMetrics {
+        dice: dice_of(counts),
+        jaccard: jaccard_of(counts),
+        volume_a: counts.1 as f64 * voxel,
+        volume_b: counts.2 as f64 * voxel,
+        hausdorff,
+        hd95,
+        mean_surface_distance,
+        surface_dice,
+    })
+}
+
+/// Compares two structures rasterized on `geometry`; `tolerance` (mm) applies to surface Dice.
+pub fn compare_structures(
+    a: &Structure,
+    b: &Structure,
+    geometry: &GridGeometry,
+    tolerance: f64,
+) -> ComparisonMetrics {
+    compare(&rasterize(a, geometry), &rasterize(b, geometry), tolerance)
+        .expect("masks share the same geometry")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::comparison::{compare, dice, jaccard, surface};
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+
+    fn block(lo: [usize; 3], hi: [usize; 3]) -> Grid3<bool> {
+        let geometry = GridGeometry::new(
+            [12, 12, 12],
+            Vec3::from(0.0, 0.0, 0.0),
+            Vec3::from(1.0, 1.0, 2.0),
+        );
+        let mut mask = Grid3::new(geometry, false);
+        for k in lo[2]..hi[2] {
+            for j in lo[1]..hi[1] {
+                for i in lo[0]..hi[0] {
+                    mask.set(i, j, k, true);
+                }
+            }
+        }
+        mask
+    }
+
+    #[test]
+    fn overlap_metrics() {
+        let a = block([2, 2, 2], [6, 6, 6]);
+        let b = block([4, 2, 2], [8, 6, 6]);
+        assert_eq!(dice(&a, &b).unwrap(), 0.5);
+        assert!((jaccard(&a, &b).unwrap() - 1.0 / 3.0).abs() < 1e-12);
+        assert_eq!(dice(&a, &a).unwrap(), 1.0);
+        let other = Grid3::new(
+            GridGeometry::new([2, 2, 2], Vec3::new(), Vec3::from(1.0, 1.0, 1.0)),
+            false,
+        );
+        assert!(dice(&a, &other).is_err());
+    }
+
+    #[test]
+    fn surface_shell() {
+        let s = surface(&block([2, 2, 2], [6, 6, 6]));
+        assert_eq!(s.data().iter().filter(|v| **v).count(), 64 - 8);
+        assert!(!s[[3, 3, 3]]);
+    }
+
+    #[test]
+    fn compare_shifted_blocks() {
+        let a = block([2, 2, 2], [6, 6, 6]);
+        let b = block([3, 2, 2], [7, 6, 6]);
+        let m = compare(&a, &b, 1.0).unwrap();
+        assert_eq!(m.volume_a, 128.0);
+        assert_eq!(m.dice, 0.75);
+        assert_eq!(m.hausdorff, 1.0);
+        assert_eq!(m.hd95, 1.0);
+        assert_eq!(m.surface_dice, 1.0);
+        assert!(m.mean_surface_distance > 0.0 && m.mean_surface_distance < 1.0);
+        let m = compare(&a, &b, 0.5).unwrap();
+        assert!(m.surface_dice < 1.0);
+
+        let same = compare(&a, &a, 0.0).unwrap();
+        assert_eq!(same.hausdorff, 0.0);
+        assert_eq!(same.surface_dice, 1.0);
+        let empty = compare(&a, &block([0, 0, 0], [0, 0, 0]), 1.0).unwrap();
+        assert!(empty.hausdorff.is_infinite());
+        assert_eq!(empty.dice, 0.0);
+    }
+}
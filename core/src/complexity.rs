@@ -0,0 +1,326 @@
+//! Plan complexity metrics.
+//!
+//! Computed from the control points of every beam, each control point interval weighted by
+//! the MU it delivers with the mean of the apertures at its ends:
+//!
+//! | Metric                  | Definition                                                   |
+//! |-------------------------|--------------------------------------------------------------|
+//! | MU/Gy                   | meterset per fraction over the prescribed dose per fraction  |
+//! | MCS                     | modulation complexity score (McNiven et al. 2010): aperture  |
+//! |                         | area variability times leaf sequence variability, 0 to 1     |
+//! | edge metric (1/mm)      | aperture perimeter over area                                 |
+//! | small aperture score    | fraction of open pairs narrower than a threshold             |
+//! | leaf travel (mm)        | distance moved by all leaves over the beam                   |
+//! | average leaf gap (mm)   | mean opening of the open pairs                               |
+//!
+//! Pairs count as open within the jaws only. Plan values are MU-weighted means of the beam
+//! values, except the leaf travel, which is summed, and MU/Gy, which is that of the plan.
+
+use std::fmt::Write;
+
+use crate::aperture::Aperture;
+use crate::dose::delivery::Mlc;
+use crate::error::Result;
+use crate::plan::{Beam, Plan};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ComplexityOptions {
+    /// Gap (mm) below which an open pair counts as small.
+    pub small_aperture: f64,
+}
+
+impl Default for ComplexityOptions {
+    fn default() -> Self {
+        ComplexityOptions {
+            small_aperture: 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Complexity {
+    /// MU per fraction.
+    pub meterset: f64,
+    /// `None` without a prescription.
+    pub mu_per_gy: Option<f64>,
+    pub mcs: f64,
+    /// 1/mm
+    pub edge_metric: f64,
+    pub small_aperture_score: f64,
+    /// mm
+    pub leaf_travel: f64,
+    /// mm
+    pub average_leaf_gap: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamComplexity {
+    pub number: usize,
+    pub name: String,
+    pub complexity: Complexity,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ComplexityReport {
+    pub beams: Vec<BeamComplexity>,
+    pub plan: Complexity,
+}
+
+impl ComplexityReport {
+    /// One row per beam and a last `plan` row, with columns
+    /// `beam,name,meterset_mu,mu_per_gy,mcs,edge_metric_per_mm,small_aperture_score,leaf_travel_mm,average_leaf_gap_mm`.
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from(
+            "beam,name,meterset_mu,mu_per_gy,mcs,edge_metric_per_mm,small_aperture_score,\
+             leaf_travel_mm,average_leaf_gap_mm\n",
+        );
+        let mut row = |beam: &str, name: &str, c: &Complexity| {
+            let name = if name.contains([',', '"', '\n']) {
+                format!("\"{}\"", name.replace('"', "\"\""))
+            } else {
+                name.to_string()
+            };
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{}",
+                beam,
+                name,
+                c.meterset,
+                c.mu_per_gy.map_or(String::new(), |v| v.to_string()),
+                c.mcs,
+                c.edge_metric,
+                c.small_aperture_score,
+                c.leaf_travel,
+                c.average_leaf_gap
+            )
+            .unwrap();
+        };
+        for b in &self.beams {
+            row(&b.number.to_string(), &b.name, &b.complexity);
+        }
+        row("plan", "", &self.plan);
+        out
+    }
+}
+
+/// Complexity of every beam of `plan` with leaves of `mlc`, and of the plan.
+pub fn report(plan: &Plan, mlc: &Mlc, options: &ComplexityOptions) -> Result<ComplexityReport> {
+    let dose = plan.prescriptions.first().map(|p| p.dose_per_fraction());
+    let per_gy = |mu: f64| dose.filter(|d| *d > 0.0).map(|d| mu / d);
+    let mut beams = Vec::with_capacity(plan.beams.len());
+    for beam in &plan.beams {
+        let mut complexity = beam_complexity(beam, mlc, options)?;
+        complexity.mu_per_gy = per_gy(complexity.meterset);
+        beams.push(BeamComplexity {
+            number: beam.number,
+            name: beam.name.clone(),
+            complexity,
+        });
+    }
+    let meterset: f64 = beams.iter().map(|b| b.complexity.meterset).sum();
+    let mean = |f: fn(&Complexity) -> f64| {
+        if meterset > 0.0 {
+            beams
+                .iter()
+                .map(|b| b.complexity.meterset * f(&b.complexity))
+                .sum::<f64>()
+                / meterset
+        } else {
+            0.0
+        }
+    };
+    let plan = Complexity {
+        meterset,
+        mu_per_gy: per_gy(meterset),
+        mcs: mean(|c| c.mcs),
+        edge_metric: mean(|c| c.edge_metric),
+        small_aperture_score: mean(|c| c.small_aperture_score),
+        leaf_travel: beams.iter().map(|b| b.complexity.leaf_travel).sum(),
+        average_leaf_gap: mean(|c| c.average_leaf_gap),
+    };
+    Ok(ComplexityReport { beams, plan })
+}
+
+/// Complexity of `beam` with leaves of `mlc`; without a plan, MU/Gy is `None`.
+pub fn beam_complexity(beam: &Beam, mlc: &Mlc, options: &ComplexityOptions) -> Result<Complexity> {
+    let points = beam.delivery_points();
+    let apertures = points
+        .iter()
+        .map(|p| Aperture::from_control_point(mlc, p))
+        .collect::<Result<Vec<_>>>()?;
+    let leaf_travel = apertures
+        .windows(2)
+        .map(|a| {
+            a[0].leaves
+                .iter()
+                .zip(&a[1].leaves)
+                .map(|(p, q)| (p[0] - q[0]).abs() + (p[1] - q[1]).abs())
+                .sum::<f64>()
+        })
+        .sum();
+
+    // Mean aperture and MU of every interval delivering dose.
+    let mut segments = Vec::new();
+    for n in 1..points.len() {
+        let mu = beam.meterset * (points[n].weight - points[n - 1].weight);
+        if mu <= 0.0 {
+            continue;
+        }
+        let (a, b) = (&apertures[n - 1], &apertures[n]);
+        let mean = |p: f64, q: f64| 0.5 * (p + q);
+        let aperture = Aperture {
+            boundaries: a.boundaries.clone(),
+            leaves: a
+                .leaves
+                .iter()
+                .zip(&b.leaves)
+                .map(|(p, q)| [mean(p[0], q[0]), mean(p[1], q[1])])
+                .collect(),
+            jaws: match (a.jaws, b.jaws) {
+                (Some(p), Some(q)) => Some([
+                    mean(p[0], q[0]),
+                    mean(p[1], q[1]),
+                    mean(p[2], q[2]),
+                    mean(p[3], q[3]),
+                ]),
+                (p, q) => p.or(q),
+            },
+        };
+        let openings = aperture.openings();
+        if openings.iter().any(|o| o.is_some()) {
+            segments.push((mu, aperture, openings));
+        }
+    }
+
+    // Largest extent of every pair over the beam, for the aperture area variability.
+    let mut extent = vec![[f64::INFINITY, f64::NEG_INFINITY]; mlc.pairs()];
+    for (_, _, openings) in &segments {
+        for (e, o) in extent.iter_mut().zip(openings) {
+            if let Some(o) = o {
+                *e = [e[0].min(o[0]), e[1].max(o[1])];
+            }
+        }
+    }
+    let range: f64 = extent
+        .iter()
+        .filter(|e| e[1] > e[0])
+        .map(|e| e[1] - e[0])
+        .sum();
+
+    let total: f64 = segments.iter().map(|s| s.0).sum();
+    let (mut mcs, mut edge, mut small, mut gap) = (0.0, 0.0, 0.0, 0.0);
+    for (mu, aperture, openings) in &segments {
+        let open: Vec<[f64; 4]> = openings.iter().flatten().cloned().collect();
+        let gaps: Vec<f64> = open.iter().map(|o| o[1] - o[0]).collect();
+        let aav = gaps.iter().sum::<f64>() / range;
+        let lsv = variability(&open, 0) * variability(&open, 1);
+        mcs += mu * aav * lsv;
+        edge += mu * aperture.perimeter() / aperture.area();
+        small += mu * gaps.iter().filter(|g| **g < options.small_aperture).count() as f64
+            / gaps.len() as f64;
+        gap += mu * gaps.iter().sum::<f64>() / gaps.len() as f64;
+    }
+    let weighted = |v: f64| if total > 0.0 { v / total } else { 0.0 };
+    Ok(Complexity {
+        meterset: beam.meterset,
+        mu_per_gy: None,
+        mcs: weighted(mcs),
+        edge_metric: weighted(edge),
+        small_aperture_score: weighted(small),
+        leaf_travel,
+        average_leaf_gap: weighted(gap),
+    })
+}
+
+/// Leaf sequence variability of bank A (0) or B (1) over the open pairs: one for a straight
+/// bank, falling as neighbouring leaves differ relative to the spread of the bank.
+fn variability(open: &[[f64; 4]], bank: usize) -> f64 {
+    if open.len() < 2 {
+        return 1.0;
+    }
+    let (lo, hi) = open
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |a, o| {
+            (a.0.min(o[bank]), a.1.max(o[bank]))
+        });
+    let spread = hi - lo;
+    if spread <= 0.0 {
+        return 1.0;
+    }
+    open.windows(2)
+        .map(|w| spread - (w[0][bank] - w[1][bank]).abs())
+        .sum::<f64>()
+        / ((open.len() - 1) as f64 * spread)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::complexity::{report, variability, ComplexityOptions};
+    use crate::coords::Vec3;
+    use crate::dose::beam::BeamGeometry;
+    use crate::dose::commissioning::MlcData;
+    use crate::dose::delivery::{ControlPoint, Mlc};
+    use crate::plan::{Beam, Plan, Prescription};
+
+    #[test]
+    fn complexity_metrics() {
+        let mlc = Mlc::uniform(
+            4,
+            10.0,
+            MlcData {
+                transmission: 0.0,
+                dlg: 0.0,
+            },
+        );
+        let point = |weight, leaves| ControlPoint {
+            weight,
+            jaws: None,
+            leaves: Some(leaves),
+        };
+        let open = vec![[-20.0, 20.0]; 4];
+        let small = vec![[-4.0, 4.0], [-4.0, 4.0], [0.0, 0.0], [0.0, 0.0]];
+        // 60 MU through the open 40 x 40 mm field, then 40 MU through 8 x 20 mm.
+        let points = [
+            point(0.0, open.clone()),
+            point(0.6, open),
+            point(0.6, small.clone()),
+            point(1.0, small),
+        ];
+        let geometry = BeamGeometry::new(Vec3::new(), 0.0, 1000.0);
+        let plan = Plan::new("Test")
+            .with_prescription(Prescription::new("PTV", 60.0, 30))
+            .with_beam(Beam::fixed(1, "A", "6X", &geometry, 100.0, &points))
+            .with_beam(Beam::fixed(2, "B, 2", "6X", &geometry, 100.0, &points));
+        let report = report(&plan, &mlc, &ComplexityOptions::default()).unwrap();
+
+        let beam = &report.beams[0].complexity;
+        assert_eq!(beam.mu_per_gy, Some(50.0));
+        assert!((beam.mcs - 0.64).abs() < 1e-12);
+        assert!((beam.edge_metric - 0.2).abs() < 1e-12);
+        assert!((beam.small_aperture_score - 0.4).abs() < 1e-12);
+        assert!((beam.leaf_travel - 144.0).abs() < 1e-12);
+        assert!((beam.average_leaf_gap - 27.2).abs() < 1e-12);
+
+        assert_eq!(report.plan.meterset, 200.0);
+        assert_eq!(report.plan.mu_per_gy, Some(100.0));
+        assert!((report.plan.mcs - 0.64).abs() < 1e-12);
+        assert!((report.plan.leaf_travel - 288.0).abs() < 1e-12);
+        let csv = report.to_csv();
+        assert_eq!(csv.lines().count(), 4);
+        assert!(csv
+            .lines()
+            .nth(2)
+            .unwrap()
+            .starts_with("2,\"B, 2\",100,50,"));
+        assert!(csv.lines().nth(3).unwrap().starts_with("plan,,200,100,"));
+
+        // A staircase bank: the leaves step by 10 mm over a 20 mm spread.
+        let stairs = [
+            [0.0, 20.0, 0.0, 1.0],
+            [10.0, 20.0, 1.0, 2.0],
+            [20.0, 20.0, 2.0, 3.0],
+        ];
+        assert!((variability(&stairs, 0) - 0.5).abs() < 1e-12);
+        assert_eq!(variability(&stairs, 1), 1.0);
+    }
+}
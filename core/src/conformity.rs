@@ -0,0 +1,145 @@
+//! Conformity, gradient and homogeneity indices of a target.
+//!
+//! With `TV` the target volume, `PIV` the prescription isodose volume, `TV_PIV` the part of
+//! the target inside it and `PIV50` the volume receiving half the prescription:
+//!
+//! * RTOG CI: `PIV / TV`
+//! * Paddick CI: `TV_PIV² / (TV PIV)`
+//! * Gradient index: `PIV50 / PIV`
+//! * ICRU-83 HI: `(D2% - D98%) / D50%` of the target DVH
+//!
+//! Isodose volumes count every voxel whose center dose reaches the level in full.
+
+use crate::dvh::{Dvh, DvhOptions, VolumeUnit};
+use crate::error::{Error, Result};
+use crate::grid::Grid3;
+use crate::raster::rasterize_fractional;
+use crate::structure::Structure;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConformityIndices {
+    /// Gy
+    pub prescription: f64,
+    /// cc
+    pub target_volume: f64,
+    /// cc
+    pub prescription_isodose_volume: f64,
+    /// Target volume (cc) receiving at least the prescription.
+    pub covered_target_volume: f64,
+    /// cc
+    pub half_prescription_isodose_volume: f64,
+    pub rtog: f64,
+    pub paddick: f64,
+    /// `None` without a prescription isodose volume.
+    pub gradient: Option<f64>,
+    pub homogeneity: f64,
+}
+
+/// Volume (cc) of the voxels of `dose` receiving at least `level` (Gy).
+pub fn isodose_volume(dose: &Grid3<f64>, level: f64) -> f64 {
+    let voxel_cc = dose.geometry().voxel_volume() / 1000.0;
+    dose.data().iter().filter(|d| **d >= level).count() as f64 * voxel_cc
+}
+
+impl ConformityIndices {
+    /// Indices of a target given by its voxel `fraction`s on the grid of `dose`; the
+    /// homogeneity index is read from a target DVH with bins of `bin_width` (Gy).
+    pub fn from_fraction(
+        fraction: &Grid3<f64>,
+        dose: &Grid3<f64>,
+        prescription: f64,
+        bin_width: f64,
+    ) -> Result<Self> {
+        if !prescription.is_finite() || prescription <= 0.0 {
+            return Err(Error::InvalidArgument(format!(
+                "prescription must be positive, got {}",
+                prescription
+            )));
+        }
+        let dvh = Dvh::from_fraction("", fraction, dose, bin_width)?;
+        if dvh.volume <= 0.0 {
+            return Err(Error::InvalidArgument(
+                "target does not cover any voxel of the dose grid".to_string(),
+            ));
+        }
+        let voxel_cc = dose.geometry().voxel_volume() / 1000.0;
+        let covered: f64 = fraction
+            .data()
+            .iter()
+            .zip(dose.data())
+            .filter(|(f, d)| **f > 0.0 && **d >= prescription)
+            .map(|(f, _)| f * voxel_cc)
+            .sum();
+        let piv = isodose_volume(dose, prescription);
+        let piv50 = isodose_volume(dose, 0.5 * prescription);
+        let tv = dvh.volume;
+        let d50 = dvh.dose_at(50.0, VolumeUnit::Percent);
+        let homogeneity = if d50 > 0.0 {
+            (dvh.dose_at(2.0, VolumeUnit::Percent) - dvh.dose_at(98.0, VolumeUnit::Percent)) / d50
+        } else {
+            0.0
+        };
+        Ok(ConformityIndices {
+            prescription,
+            target_volume: tv,
+            prescription_isodose_volume: piv,
+            covered_target_volume: covered,
+            half_prescription_isodose_volume: piv50,
+            rtog: piv / tv,
+            paddick: if piv > 0.0 {
+                covered * covered / (tv * piv)
+            } else {
+                0.0
+            },
+            gradient: if piv > 0.0 { Some(piv50 / piv) } else { None },
+            homogeneity,
+        })
+    }
+}
+
+/// Indices of `target` on the grid of `dose` for a `prescription` (Gy).
+pub fn conformity(
+    target: &Structure,
+    dose: &Grid3<f64>,
+    prescription: f64,
+    options: &DvhOptions,
+) -> Result<ConformityIndices> {
+    let fraction = rasterize_fractional(target, dose.geometry(), options.samples);
+    ConformityIndices::from_fraction(&fraction, dose, prescription, options.bin_width)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::conformity::{isodose_volume, ConformityIndices};
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+
+    #[test]
+    fn conformity_indices() {
+        // 10 voxels of 1 cc with doses 0..9 Gy; the target holds voxels 4..8.
+        let geometry = GridGeometry::new([10, 1, 1], Vec3::new(), Vec3::from(10.0, 10.0, 10.0));
+        let dose = Grid3::from_vec(geometry.clone(), (0..10).map(|i| i as f64).collect()).unwrap();
+        let target = Grid3::from_vec(
+            geometry,
+            (0..10).map(|i| (4..8).contains(&i) as u8 as f64).collect(),
+        )
+        .unwrap();
+        assert!((isodose_volume(&dose, 6.0) - 4.0).abs() < 1e-12);
+
+        let c = ConformityIndices::from_fraction(&target, &dose, 6.0, 0.01).unwrap();
+        assert!((c.target_volume - 4.0).abs() < 1e-12);
+        assert!((c.covered_target_volume - 2.0).abs() < 1e-12);
+        assert!((c.rtog - 1.0).abs() < 1e-12);
+        assert!((c.paddick - 0.25).abs() < 1e-12);
+        // 3 Gy and above: 7 voxels.
+        assert!((c.gradient.unwrap() - 7.0 / 4.0).abs() < 1e-12);
+        // D2% 7 Gy (the maximum), D98% 4.0008 Gy and D50% 6 Gy on the 0.01 Gy bins.
+        assert!((c.homogeneity - 2.9992 / 6.0).abs() < 1e-9);
+
+        let cold = ConformityIndices::from_fraction(&target, &dose, 20.0, 0.01).unwrap();
+        assert_eq!((cold.rtog, cold.paddick, cold.gradient), (0.0, 0.0, None));
+        assert!(ConformityIndices::from_fraction(&target, &dose, 0.0, 0.01).is_err());
+        let empty = target.map(|_| 0.0);
+        assert!(ConformityIndices::from_fraction(&empty, &dose, 6.0, 0.01).is_err());
+    }
+}
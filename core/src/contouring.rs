@@ -0,0 +1,288 @@
+//! Extraction of closed contours from binary masks (marching squares per slice).
+//!
+//! Contour vertices lie on the midpoints between an inside and an outside voxel center, so
+//! rasterizing the result on the same grid reproduces the mask. Outer boundaries are
+//! counter-clockwise and holes clockwise. Saddle cells keep diagonal voxels apart.
+
+use std::collections::HashMap;
+
+use crate::coords::Vec3;
+use crate::grid::{Grid3, GridGeometry};
+use crate::structure::{Contour, Structure, StructureType};
+
+/// Closed contours per slice of `mask`, simplified to within `tolerance` mm (0 keeps every
+/// staircase corner).
+pub fn extract_contours(mask: &Grid3<bool>, tolerance: f64) -> Vec<Contour> {
+    let [nx, ny, nz] = mask.dims();
+    let data = mask.data();
+    (0..nz)
+        .flat_map(|k| {
+            let slice = &data[k * nx * ny..(k + 1) * nx * ny];
+            slice_contours(slice, k, mask.geometry(), tolerance)
+        })
+        .collect()
+}
+
+/// Contours of the pixels set in `slice`, the `k`-th slice of `geometry`.
+pub(crate) fn slice_contours(
+    slice: &[bool],
+    k: usize,
+    geometry: &GridGeometry,
+    tolerance: f64,
+) -> Vec<Contour> {
+    let [nx, ny, _] = geometry.dims;
+    let i2w = geometry.index_to_world();
+    let handedness = geometry.direction[0].cross(geometry.direction[1]).z;
+    trace_slice(nx, ny, |i, j| slice[i + nx * j])
+        .into_iter()
+        .map(|ring| {
+            let mut points: Vec<Vec3<f64>> = ring
+                .into_iter()
+                .map(|(x, y)| i2w.transform_point(Vec3::from(x, y, k as f64)))
+                .collect();
+            if handedness < 0.0 {
+                points.reverse();
+            }
+            Contour::new(simplify(&points, tolerance))
+        })
+        .collect()
+}
+
+/// Structure whose contours are extracted from `mask`.
+pub fn mask_to_structure(
+    name: &str,
+    structure_type: StructureType,
+    mask: &Grid3<bool>,
+    tolerance: f64,
+) -> Structure {
+    Structure::new(name, structure_type).with_contours(extract_contours(mask, tolerance))
+}
+
+/// Boundary loops of one `nx` x `ny` slice in continuous pixel coordinates.
+pub(crate) fn trace_slice<F: Fn(usize, usize) -> bool>(
+    nx: usize,
+    ny: usize,
+    inside: F,
+) -> Vec<Vec<(f64, f64)>> {
+    trace_crossings(nx, ny, inside)
+        .iter()
+        .map(|ring| {
+            drop_collinear(ring)
+                .into_iter()
+                .map(|(x, y)| (x as f64 / 2.0, y as f64 / 2.0))
+                .collect()
+        })
+        .collect()
+}
+
+/// Boundary loops of one `nx` x `ny` slice as every crossed pixel edge, in doubled pixel
+/// coordinates of the edge midpoints.
+pub(crate) fn trace_crossings<F: Fn(usize, usize) -> bool>(
+    nx: usize,
+    ny: usize,
+    inside: F,
+) -> Vec<Vec<(i64, i64)>> {
+    let at = |i: i64, j: i64| {
+        i >= 0 && j >= 0 && (i as usize) < nx && (j as usize) < ny && inside(i as usize, j as usize)
+    };
+    // Crossings are keyed by doubled pixel coordinates; each maps to the next one along the
+    // boundary with the inside on the left.
+    let mut next: HashMap<(i64, i64), (i64, i64)> = HashMap::new();
+    let mut order = Vec::new();
+    for j in -1..ny as i64 {
+        for i in -1..nx as i64 {
+            let corners = [at(i, j), at(i + 1, j), at(i + 1, j + 1), at(i, j + 1)];
+            let edges = [
+                (2 * i + 1, 2 * j),
+                (2 * i + 2, 2 * j + 1),
+                (2 * i + 1, 2 * j + 2),
+                (2 * i, 2 * j + 1),
+            ];
+            // Walking the cell counter-clockwise, every in->out crossing connects to the
+            // nearest preceding out->in crossing.
+            for e in 0..4 {
+                if !corners[e] || corners[(e + 1) % 4] {
+                    continue;
+                }
+                let mut p = (e + 3) % 4;
+                while corners[p] || !corners[(p + 1) % 4] {
+                    p = (p + 3) % 4;
+                }
+                next.insert(edges[e], edges[p]);
+                order.push(edges[e]);
+            }
+        }
+    }
+    let mut rings = Vec::new();
+    for start in order {
+        let mut key = match next.remove(&start) {
+            Some(k) => k,
+            None => continue,
+        };
+        let mut ring = vec![start];
+        while key != start {
+            ring.push(key);
+            key = next.remove(&key).expect("open marching squares boundary");
+        }
+        rings.push(ring);
+    }
+    rings
+}
+
+fn drop_collinear(ring: &[(i64, i64)]) -> Vec<(i64, i64)> {
+    let n = ring.len();
+    (0..n)
+        .filter(|&i| {
+            let a = ring[(i + n - 1) % n];
+            let b = ring[i];
+            let c = ring[(i + 1) % n];
+            (b.0 - a.0) * (c.1 - b.1) != (b.1 - a.1) * (c.0 - b.0)
+        })
+        .map(|i| ring[i])
+        .collect()
+}
+
+/// Douglas-Peucker simplification of a closed polygon.
+pub fn simplify(points: &[Vec3<f64>], tolerance: f64) -> Vec<Vec3<f64>> {
+    if tolerance <= 0.0 || points.len() <= 3 {
+        return points.to_vec();
+    }
+    let far = (1..points.len())
+        .max_by(|&a, &b| {
+            let da = points[a].distance(points[0]);
+            let db = points[b].distance(points[0]);
+            da.partial_cmp(&db).unwrap()
+        })
+        .unwrap();
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[far] = true;
+    let mut closed = points.to_vec();
+    closed.push(points[0]);
+    mark(&closed, 0, far, tolerance, &mut keep);
+    let mut tail = vec![false; closed.len()];
+    mark(&closed, far, points.len(), tolerance, &mut tail);
+    let simplified: Vec<Vec3<f64>> = (0..points.len())
+        .filter(|&i| keep[i] || tail[i])
+        .map(|i| points[i])
+        .collect();
+    if simplified.len() < 3 {
+        points.to_vec()
+    } else {
+        simplified
+    }
+}
+
+fn mark(points: &[Vec3<f64>], first: usize, last: usize, tolerance: f64, keep: &mut [bool]) {
+    if last <= first + 1 {
+        return;
+    }
+    let (a, b) = (points[first], points[last]);
+    let (mut worst, mut index) = (0.0, first);
+    for (i, p) in points.iter().enumerate().take(last).skip(first + 1) {
+        let d = segment_distance(*p, a, b);
+        if d > worst {
+            worst = d;
+            index = i;
+        }
+    }
+    if worst > tolerance {
+        keep[index] = true;
+        mark(points, first, index, tolerance, keep);
+        mark(points, index, last, tolerance, keep);
+    }
+}
+
+fn segment_distance(p: Vec3<f64>, a: Vec3<f64>, b: Vec3<f64>) -> f64 {
+    let ab = b - a;
+    let len2 = ab.dot(ab);
+    if len2 == 0.0 {
+        return p.distance(a);
+    }
+    let t = ((p - a).dot(ab) / len2).clamp(0.0, 1.0);
+    p.distance(a + ab.scale(t))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::contouring::{extract_contours, simplify, trace_slice};
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::raster::rasterize_contours;
+
+    #[test]
+    fn trace_slice_square_and_hole() {
+        let rings = trace_slice(7, 7, |i, j| {
+            (1..6).contains(&i) && (1..6).contains(&j) && (i, j) != (3, 3)
+        });
+        assert_eq!(rings.len(), 2);
+        let area = |r: &Vec<(f64, f64)>| {
+            (0..r.len())
+                .map(|n| {
+                    let (a, b) = (r[n], r[(n + 1) % r.len()]);
+                    a.0 * b.1 - b.0 * a.1
+                })
+                .sum::<f64>()
+                / 2.0
+        };
+        let mut areas: Vec<f64> = rings.iter().map(area).collect();
+        areas.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        // Outer boundary cuts the four corners by 1/8 each; the hole is a diamond.
+        assert!((areas[0] + 0.5).abs() < 1e-12);
+        assert!((areas[1] - 24.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn trace_slice_diagonal() {
+        let rings = trace_slice(2, 2, |i, j| i == j);
+        assert_eq!(rings.len(), 2);
+    }
+
+    #[test]
+    fn extract_contours_round_trip() {
+        let mut geometry = GridGeometry::new(
+            [12, 10, 3],
+            Vec3::from(-5.0, 3.0, -2.0),
+            Vec3::from(1.5, 2.0, 2.5),
+        );
+        geometry.direction[1] = Vec3::from(0.0, -1.0, 0.0);
+        let mut mask = Grid3::new(geometry, false);
+        for k in 0..3 {
+            for j in 0..10 {
+                for i in 0..12 {
+                    let r2 = (i as f64 - 5.5).powi(2) + (j as f64 - 4.5).powi(2);
+                    mask.set(i, j, k, r2 < 16.0 && !(k == 1 && r2 < 2.0));
+                }
+            }
+        }
+        let contours = extract_contours(&mask, 0.0);
+        assert_eq!(contours.len(), 4);
+        let back = rasterize_contours(&contours, mask.geometry());
+        assert_eq!(back.data(), mask.data());
+        assert!(contours.iter().filter(|c| c.signed_area() > 0.0).count() == 3);
+    }
+
+    #[test]
+    fn simplify_square() {
+        let mut points = Vec::new();
+        for n in 0..10 {
+            points.push(Vec3::from(n as f64, 0.0, 0.0));
+        }
+        for n in 0..10 {
+            points.push(Vec3::from(10.0, n as f64, 0.0));
+        }
+        for n in 0..10 {
+            points.push(Vec3::from(
+                10.0 - n as f64,
+                10.0 + 0.01 * (n % 2) as f64,
+                0.0,
+            ));
+        }
+        for n in 0..10 {
+            points.push(Vec3::from(0.0, 10.0 - n as f64, 0.0));
+        }
+        let s = simplify(&points, 0.1);
+        assert_eq!(s.len(), 4);
+        assert_eq!(simplify(&points, 0.0).len(), points.len());
+    }
+}
@@ -1,7 +1,262 @@
-use num_traits::{Num, One, Zero};
-use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Sub, SubAssign, Neg};
+use num_traits::{Float, Num, One, Zero};
+use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Vec3<T: Num + Default + PartialEq> {
+    pub x: T,
+    pub y: T,
+    pub z: T,
+}
+
+impl<T> Vec3<T>
+where
+    T: Num + Default + PartialEq,
+{
+    pub fn new() -> Self {
+        Self {
+            x: Default::default(),
+            y: Default::default(),
+            z: Default::default(),
+        }
+    }
+
+    pub fn from(x: T, y: T, z: T) -> Self {
+        Self { x, y, z }
+    }
+}
+
+impl<T> Vec3<T>
+where
+    T: Num + Default + PartialEq + Copy,
+{
+    pub fn scale(self, s: T) -> Self {
+        Self {
+            x: self.x * s,
+            y: self.y * s,
+            z: self.z * s,
+        }
+    }
+
+    pub fn dot(self, rhs: Self) -> T {
+        self.x * rhs.x + self.y * rhs.y + self.z * rhs.z
+    }
+
+    pub fn cross(self, rhs: Self) -> Self {
+        Self {
+            x: self.y * rhs.z - self.z * rhs.y,
+            y: self.z * rhs.x - self.x * rhs.z,
+            z: self.x * rhs.y - self.y * rhs.x,
+        }
+    }
+
+    pub fn to_array(self) -> [T; 3] {
+        [self.x, self.y, self.z]
+    }
+
+    pub fn from_array(a: [T; 3]) -> Self {
+        Self {
+            x: a[0],
+            y: a[1],
+            z: a[2],
+        }
+    }
+}
+
+impl<T> Vec3<T>
+where
+    T: Float + Default,
+{
+    pub fn norm(self) -> T {
+        self.dot(self).sqrt()
+    }
+
+    pub fn distance(self, rhs: Self) -> T {
+        (self - rhs).norm()
+    }
+
+    /// Returns the unit vector in the same direction, or the zero vector if `self` is zero.
+    pub fn normalize(self) -> Self {
+        let n = self.norm();
+        if n.is_zero() {
+            return self;
+        }
+        Self {
+            x: self.x / n,
+            y: self.y / n,
+            z: self.z / n,
+        }
+    }
+}
+
+impl<T> Zero for Vec3<T>
+where
+    T: Num + Default + PartialEq,
+{
+    fn zero() -> Self {
+        Vec3::from(Zero::zero(), Zero::zero(), Zero::zero())
+    }
+
+    fn set_zero(&mut self) {
+        self.x = Zero::zero();
+        self.y = Zero::zero();
+        self.z = Zero::zero();
+    }
+
+    fn is_zero(&self) -> bool {
+        self.x.is_zero() && self.y.is_zero() && self.z.is_zero()
+    }
+}
+
+impl<T> One for Vec3<T>
+where
+    T: Num + Default + PartialEq,
+{
+    fn one() -> Self {
+        Vec3::from(One::one(), One::one(), One::one())
+    }
+
+    fn set_one(&mut self) {
+        self.x = One::one();
+        self.y = One::one();
+        self.z = One::one();
+    }
+
+    fn is_one(&self) -> bool {
+        self.x.is_one() && self.y.is_one() && self.z.is_one()
+    }
+}
+
+impl<T> Mul for Vec3<T>
+where
+    T: Num + Default + PartialEq,
+{
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        Self {
+            x: self.x * rhs.x,
+            y: self.y * rhs.y,
+            z: self.z * rhs.z,
+        }
+    }
+}
+
+impl<T> MulAssign for Vec3<T>
+where
+    T: Num + Default + PartialEq + MulAssign,
+{
+    fn mul_assign(&mut self, rhs: Self) {
+        self.x *= rhs.x;
+        self.y *= rhs.y;
+        self.z *= rhs.z;
+    }
+}
+
+impl<T> Div for Vec3<T>
+where
+    T: Num + Default + PartialEq,
+{
+    type Output = Self;
+
+    fn div(self, rhs: Self) -> Self::Output {
+        Self {
+            x: self.x / rhs.x,
+            y: self.y / rhs.y,
+            z: self.z / rhs.z,
+        }
+    }
+}
+
+impl<T> DivAssign for Vec3<T>
+where
+    T: Num + Default + PartialEq + DivAssign,
+{
+    fn div_assign(&mut self, rhs: Self) {
+        self.x /= rhs.x;
+        self.y /= rhs.y;
+        self.z /= rhs.z;
+    }
+}
+
+impl<T> Add for Vec3<T>
+where
+    T: Num + Default + PartialEq,
+{
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            x: self.x + rhs.x,
+            y: self.y + rhs.y,
+            z: self.z + rhs.z,
+        }
+    }
+}
+
+impl<T> AddAssign for Vec3<T>
+where
+    T: Num + Default + PartialEq + AddAssign,
+{
+    fn add_assign(&mut self, rhs: Self) {
+        self.x += rhs.x;
+        self.y += rhs.y;
+        self.z += rhs.z;
+    }
+}
+
+impl<T> Sub for Vec3<T>
+where
+    T: Num + Default + PartialEq,
+{
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self {
+            x: self.x - rhs.x,
+            y: self.y - rhs.y,
+            z: self.z - rhs.z,
+        }
+    }
+}
+
+impl<T> SubAssign for Vec3<T>
+where
+    T: Num + Default + PartialEq + SubAssign,
+{
+    fn sub_assign(&mut self, rhs: Self) {
+        self.x -= rhs.x;
+        self.y -= rhs.y;
+        self.z -= rhs.z;
+    }
+}
+
+impl<T> Neg for Vec3<T>
+where
+    T: Num + Default + PartialEq + Neg<Output = T>,
+{
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        Self::Output {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+}
+
+impl<T> PartialEq for Vec3<T>
+where
+    T: Num + Default + PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        PartialEq::eq(&self.x, &other.x)
+            && PartialEq::eq(&self.y, &other.y)
+            && PartialEq::eq(&self.z, &other.z)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Vec4<T: Num + Default + PartialEq> {
     pub x: T,
     pub y: T,
@@ -184,7 +439,10 @@ where
     }
 }
 
-impl<T> Neg for Vec4<T> where T: Num + Default + PartialEq + Neg {
+impl<T> Neg for Vec4<T>
+where
+    T: Num + Default + PartialEq + Neg<Output = T>,
+{
     type Output = Self;
 
     fn neg(self) -> Self::Output {
@@ -192,7 +450,7 @@ impl<T> Neg for Vec4<T> where T: Num + Default + PartialEq + Neg {
             x: -self.x,
             y: -self.y,
             z: -self.z,
-            w: -self.w
+            w: -self.w,
         }
     }
 }
@@ -211,7 +469,7 @@ where
 
 #[cfg(test)]
 mod tests {
-    use crate::coords::Vec4;
+    use crate::coords::{Vec3, Vec4};
     use num_traits::One;
     use num_traits::Zero;
 
@@ -236,15 +494,15 @@ mod tests {
     #[test]
     fn vec4_zero() {
         let v: Vec4<f64> = Vec4::zero();
-        assert_eq!(v.is_zero(), true);
-        assert_eq!(v.is_one(), false);
+        assert!(v.is_zero());
+        assert!(!v.is_one());
     }
 
     #[test]
     fn vec4_one() {
         let v: Vec4<f64> = Vec4::one();
-        assert_eq!(v.is_zero(), false);
-        assert_eq!(v.is_one(), true);
+        assert!(!v.is_zero());
+        assert!(v.is_one());
     }
 
     #[test]
@@ -306,4 +564,36 @@ mod tests {
         assert_eq!(v.z, 9.0);
         assert_eq!(v.w, 16.0);
     }
+
+    #[test]
+    fn vec3_from() {
+        let v: Vec3<f64> = Vec3::from(1.0, 2.0, 3.0);
+        assert_eq!(v.x, 1.0);
+        assert_eq!(v.y, 2.0);
+        assert_eq!(v.z, 3.0);
+    }
+
+    #[test]
+    fn vec3_add_sub() {
+        let v: Vec3<f64> = Vec3::from(1.0, 2.0, 3.0);
+        assert_eq!(v + v, Vec3::from(2.0, 4.0, 6.0));
+        assert!((v - v).is_zero());
+        assert_eq!(-v, Vec3::from(-1.0, -2.0, -3.0));
+    }
+
+    #[test]
+    fn vec3_dot_cross() {
+        let x: Vec3<f64> = Vec3::from(1.0, 0.0, 0.0);
+        let y: Vec3<f64> = Vec3::from(0.0, 1.0, 0.0);
+        assert_eq!(x.dot(y), 0.0);
+        assert_eq!(x.cross(y), Vec3::from(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn vec3_norm() {
+        let v: Vec3<f64> = Vec3::from(3.0, 0.0, 4.0);
+        assert_eq!(v.norm(), 5.0);
+        assert_eq!(v.normalize(), Vec3::from(0.6, 0.0, 0.8));
+        assert_eq!(v.distance(Vec3::zero()), 5.0);
+    }
 }
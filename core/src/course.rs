@@ -0,0 +1,329 @@
+//! Treatment courses: phases, fractionation and delivered fractions.
+//!
+//! A [`Course`] is a sequence of [`Phase`]s, e.g. an initial phase and a boost, each with its
+//! own [`Prescription`] and the fractions delivered of it so far. Phase doses are planned
+//! totals over all fractions of the phase, the convention of [`crate::plan_sum`] and
+//! [`crate::radiobiology`]; the course turns them into plan sums and EQD2 of the planned or
+//! delivered fractions, converting every phase at its own dose per fraction. The free
+//! functions convert doses between per fraction and total at the grid and DVH level.
+
+use crate::dvh::Dvh;
+use crate::error::{Error, Result};
+use crate::grid::{Grid3, GridGeometry};
+use crate::plan::Prescription;
+use crate::plan_sum::{plan_sum, Component, PlanSum};
+use crate::radiobiology::{eqd2_grid, Fractionation};
+
+/// A delivered fraction of a phase.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeliveredFraction {
+    /// From 1 within the phase.
+    pub number: u32,
+    /// Treatment date, e.g. `2024-03-18`.
+    pub date: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phase {
+    pub name: String,
+    pub prescription: Prescription,
+    pub delivered: Vec<DeliveredFraction>,
+}
+
+impl Phase {
+    pub fn new(name: &str, prescription: Prescription) -> Self {
+        Phase {
+            name: name.to_string(),
+            prescription,
+            delivered: Vec::new(),
+        }
+    }
+
+    /// Planned fractions.
+    pub fn fractions(&self) -> u32 {
+        self.prescription.fractions as u32
+    }
+
+    pub fn delivered_fractions(&self) -> u32 {
+        self.delivered.len() as u32
+    }
+
+    pub fn remaining(&self) -> u32 {
+        self.fractions().saturating_sub(self.delivered_fractions())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    /// Records the next fraction as delivered on `date` and returns its number.
+    pub fn deliver(&mut self, date: &str) -> Result<u32> {
+        if self.is_complete() {
+            return Err(Error::InvalidArgument(format!(
+                "all {} fractions of phase {} are delivered",
+                self.fractions(),
+                self.name
+            )));
+        }
+        let number = self.delivered_fractions() + 1;
+        self.delivered.push(DeliveredFraction {
+            number,
+            date: date.to_string(),
+        });
+        Ok(number)
+    }
+
+    /// Gy
+    pub fn delivered_dose(&self) -> f64 {
+        self.prescription.dose_per_fraction() * self.delivered_fractions() as f64
+    }
+
+    /// Planned and delivered fractions for the LQ conversions.
+    pub fn fractionation(&self, scope: Scope) -> Fractionation {
+        Fractionation {
+            fractions: self.fractions(),
+            delivered: match scope {
+                Scope::Planned => self.fractions(),
+                Scope::Delivered => self.delivered_fractions(),
+            },
+        }
+    }
+
+    /// The plan sum component of the planned total `dose` of the phase.
+    pub fn component<'a>(&'a self, dose: &'a Grid3<f64>, scope: Scope) -> Component<'a> {
+        let fractionation = self.fractionation(scope);
+        Component {
+            fractions: fractionation.delivered,
+            ..Component::new(&self.name, dose, fractionation.fractions)
+        }
+    }
+}
+
+/// Which fractions of a course to evaluate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Planned,
+    Delivered,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Course {
+    pub id: String,
+    pub phases: Vec<Phase>,
+}
+
+impl Course {
+    pub fn new(id: &str) -> Self {
+        Course {
+            id: id.to_string(),
+            phases: Vec::new(),
+        }
+    }
+
+    pub fn with_phase(mut self, phase: Phase) -> Self {
+        self.phases.push(phase);
+        self
+    }
+
+    pub fn phase(&self, name: &str) -> Option<&Phase> {
+        self.phases.iter().find(|p| p.name == name)
+    }
+
+    pub fn phase_mut(&mut self, name: &str) -> Option<&mut Phase> {
+        self.phases.iter_mut().find(|p| p.name == name)
+    }
+
+    /// The first phase with fractions left, e.g. to record the next treatment.
+    pub fn current(&mut self) -> Option<&mut Phase> {
+        self.phases.iter_mut().find(|p| !p.is_complete())
+    }
+
+    pub fn fractions(&self, scope: Scope) -> u32 {
+        self.phases
+            .iter()
+            .map(|p| p.fractionation(scope).delivered)
+            .sum()
+    }
+
+    /// Prescribed dose (Gy) to `target` over the phases prescribing to it.
+    pub fn dose(&self, target: &str, scope: Scope) -> f64 {
+        self.phases
+            .iter()
+            .filter(|p| p.prescription.target == target)
+            .map(|p| match scope {
+                Scope::Planned => p.prescription.dose,
+                Scope::Delivered => p.delivered_dose(),
+            })
+            .sum()
+    }
+
+    /// Sum on `reference` of the planned total dose of every phase, `doses` in phase order.
+    pub fn plan_sum(
+        &self,
+        doses: &[&Grid3<f64>],
+        reference: &GridGeometry,
+        scope: Scope,
+    ) -> Result<PlanSum> {
+        self.check(doses)?;
+        let components: Vec<Component> = self
+            .phases
+            .iter()
+            .zip(doses)
+            .map(|(p, d)| p.component(d, scope))
+            .collect();
+        plan_sum(&components, reference)
+    }
+
+    /// Summed EQD2 on `reference` of the planned total dose of every phase, `doses` in phase
+    /// order, each converted at the dose per fraction of its phase.
+    pub fn eqd2(
+        &self,
+        doses: &[&Grid3<f64>],
+        alpha_beta: f64,
+        reference: &GridGeometry,
+        scope: Scope,
+    ) -> Result<PlanSum> {
+        self.check(doses)?;
+        let converted = self
+            .phases
+            .iter()
+            .zip(doses)
+            .map(|(p, d)| eqd2_grid(d, p.fractionation(scope), &[], alpha_beta))
+            .collect::<Result<Vec<_>>>()?;
+        let components: Vec<Component> = self
+            .phases
+            .iter()
+            .zip(&converted)
+            .map(|(p, d)| Component::new(&p.name, d, p.fractions()))
+            .collect();
+        plan_sum(&components, reference)
+    }
+
+    fn check(&self, doses: &[&Grid3<f64>]) -> Result<()> {
+        if doses.len() != self.phases.len() {
+            return Err(Error::InvalidArgument(format!(
+                "{} doses for {} phases",
+                doses.len(),
+                self.phases.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn check_fractions(fractions: u32) -> Result<()> {
+    if fractions == 0 {
+        return Err(Error::InvalidArgument(
+            "dose conversion needs at least one fraction".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Dose per fraction of a total `dose` over `fractions`.
+pub fn per_fraction_grid(dose: &Grid3<f64>, fractions: u32) -> Result<Grid3<f64>> {
+    check_fractions(fractions)?;
+    Ok(dose.map(|d| d / fractions as f64))
+}
+
+/// Total dose over `fractions` of a `dose` per fraction.
+pub fn total_grid(dose: &Grid3<f64>, fractions: u32) -> Result<Grid3<f64>> {
+    check_fractions(fractions)?;
+    Ok(dose.map(|d| d * fractions as f64))
+}
+
+/// The DVH with its dose axis scaled by `factor`; the bins keep their volumes.
+fn scale_dvh(dvh: &Dvh, factor: f64) -> Dvh {
+    Dvh {
+        bin_width: dvh.bin_width * factor,
+        min: dvh.min * factor,
+        max: dvh.max * factor,
+        mean: dvh.mean * factor,
+        ..dvh.clone()
+    }
+}
+
+/// DVH per fraction of a DVH of the total dose over `fractions`.
+pub fn per_fraction_dvh(dvh: &Dvh, fractions: u32) -> Result<Dvh> {
+    check_fractions(fractions)?;
+    Ok(scale_dvh(dvh, 1.0 / fractions as f64))
+}
+
+/// DVH of the total dose over `fractions` of a DVH per fraction.
+pub fn total_dvh(dvh: &Dvh, fractions: u32) -> Result<Dvh> {
+    check_fractions(fractions)?;
+    Ok(scale_dvh(dvh, fractions as f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::course::{
+        per_fraction_dvh, per_fraction_grid, total_dvh, total_grid, Course, Phase, Scope,
+    };
+    use crate::dvh::{Dvh, VolumeUnit};
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::plan::Prescription;
+
+    #[test]
+    fn course_model() {
+        let mut course = Course::new("C1")
+            .with_phase(Phase::new("Initial", Prescription::new("PTV", 50.0, 25)))
+            .with_phase(Phase::new("Boost", Prescription::new("PTV", 15.0, 5)));
+        for day in 0..28 {
+            let phase = course.current().unwrap();
+            phase.deliver(&format!("day {}", day + 1)).unwrap();
+        }
+        let initial = course.phase("Initial").unwrap();
+        assert!(initial.is_complete());
+        assert_eq!(initial.delivered[24].number, 25);
+        let boost = course.phase("Boost").unwrap();
+        assert_eq!((boost.delivered_fractions(), boost.remaining()), (3, 2));
+        assert_eq!(course.fractions(Scope::Planned), 30);
+        assert_eq!(course.fractions(Scope::Delivered), 28);
+        assert_eq!(course.dose("PTV", Scope::Planned), 65.0);
+        assert_eq!(course.dose("PTV", Scope::Delivered), 59.0);
+
+        let geometry = GridGeometry::new([2, 2, 2], Vec3::new(), Vec3::from(1.0, 1.0, 1.0));
+        let initial = Grid3::new(geometry.clone(), 50.0);
+        let boost = Grid3::new(geometry.clone(), 15.0);
+        let doses = [&initial, &boost];
+        let sum = course
+            .plan_sum(&doses, &geometry, Scope::Delivered)
+            .unwrap();
+        assert!((sum.dose[[1, 1, 1]] - 59.0).abs() < 1e-9);
+        assert_eq!(sum.inputs[1].fractions, 3);
+        let planned = course.plan_sum(&doses, &geometry, Scope::Planned).unwrap();
+        assert!((planned.dose[[0, 0, 0]] - 65.0).abs() < 1e-9);
+        // 2 Gy fractions count as they are; 3 x 3 Gy at α/β 10 are 9 * 13 / 12 Gy EQD2.
+        let eqd2 = course
+            .eqd2(&doses, 10.0, &geometry, Scope::Delivered)
+            .unwrap();
+        assert!((eqd2.dose[[0, 1, 0]] - (50.0 + 9.0 * 13.0 / 12.0)).abs() < 1e-9);
+        assert!(course
+            .plan_sum(&doses[..1], &geometry, Scope::Planned)
+            .is_err());
+
+        let phase = course.phase_mut("Initial").unwrap();
+        assert!(phase.deliver("day 29").is_err());
+
+        let per_fraction = per_fraction_grid(&initial, 25).unwrap();
+        assert_eq!(per_fraction[[0, 0, 0]], 2.0);
+        assert_eq!(total_grid(&per_fraction, 25).unwrap(), initial);
+        assert!(per_fraction_grid(&initial, 0).is_err());
+
+        let dvh = Dvh {
+            name: "PTV".to_string(),
+            bin_width: 0.5,
+            bins: vec![0.0, 0.0, 1.0, 3.0],
+            volume: 4.0,
+            min: 1.0,
+            max: 2.0,
+            mean: 1.6,
+        };
+        let total = total_dvh(&dvh, 25).unwrap();
+        assert_eq!(total.max, 50.0);
+        assert_eq!(total.volume_at(37.5, VolumeUnit::Cc), 3.0);
+        assert_eq!(per_fraction_dvh(&total, 25).unwrap(), dvh);
+    }
+}
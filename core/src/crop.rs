@@ -0,0 +1,163 @@
+//! Cropping structures to another structure or to the bounds of a grid.
+
+use crate::boolean::{combine_masks, BooleanOp};
+use crate::contouring::extract_contours;
+use crate::coords::Vec3;
+use crate::grid::GridGeometry;
+use crate::raster::{is_axial, rasterize};
+use crate::structure::{Contour, Structure};
+
+/// The part of `structure` inside `other` (e.g. the body), evaluated on `geometry`; name,
+/// type and color are kept.
+pub fn crop_to(structure: &Structure, other: &Structure, geometry: &GridGeometry) -> Structure {
+    let mask = combine_masks(
+        &rasterize(structure, geometry),
+        &rasterize(other, geometry),
+        BooleanOp::Intersection,
+    )
+    .expect("masks share the same geometry");
+    Structure {
+        contours: extract_contours(&mask, 0.0),
+        ..structure.clone()
+    }
+}
+
+/// The part of `structure` inside the voxels of `geometry`.
+///
+/// On axial grids the contours are clipped exactly against the in-plane grid bounds and
+/// slices beyond the outer voxel faces are dropped; other grids go through a mask.
+pub fn crop_to_grid(structure: &Structure, geometry: &GridGeometry) -> Structure {
+    if !is_axial(geometry) {
+        return Structure {
+            contours: extract_contours(&rasterize(structure, geometry), 0.0),
+            ..structure.clone()
+        };
+    }
+    let [nx, ny, nz] = geometry.dims;
+    let w2i = geometry.world_to_index();
+    let i2w = geometry.index_to_world();
+    let (lo, hi) = (-0.5, [nx as f64 - 0.5, ny as f64 - 0.5, nz as f64 - 0.5]);
+    let contours = structure
+        .contours
+        .iter()
+        .filter_map(|c| {
+            let points: Vec<Vec3<f64>> = c.points.iter().map(|p| w2i.transform_point(*p)).collect();
+            let k = points.iter().map(|p| p.z).sum::<f64>() / points.len().max(1) as f64;
+            if points.len() < 3 || k < lo || k > hi[2] {
+                return None;
+            }
+            let mut clipped = points;
+            for (axis, bound, keep_below) in [
+                (0, lo, false),
+                (0, hi[0], true),
+                (1, lo, false),
+                (1, hi[1], true),
+            ] {
+                clipped = clip(&clipped, axis, bound, keep_below);
+            }
+            if clipped.len() < 3 {
+                return None;
+            }
+            let mut contour =
+                Contour::new(clipped.iter().map(|p| i2w.transform_point(*p)).collect());
+            contour.closed = c.closed;
+            Some(contour)
+        })
+        .collect();
+    Structure {
+        contours,
+        ..structure.clone()
+    }
+}
+
+/// Sutherland-Hodgman clipping of a polygon against one axis-aligned half-plane.
+fn clip(points: &[Vec3<f64>], axis: usize, bound: f64, keep_below: bool) -> Vec<Vec3<f64>> {
+    let coord = |p: &Vec3<f64>| if axis == 0 { p.x } else { p.y };
+    let inside = |p: &Vec3<f64>| {
+        if keep_below {
+            coord(p) <= bound
+        } else {
+            coord(p) >= bound
+        }
+    };
+    let mut out = Vec::with_capacity(points.len() + 4);
+    for (n, q) in points.iter().enumerate() {
+        let p = &points[(n + points.len() - 1) % points.len()];
+        if inside(q) != inside(p) {
+            let t = (bound - coord(p)) / (coord(q) - coord(p));
+            out.push(*p + (*q - *p).scale(t));
+        }
+        if inside(q) {
+            out.push(*q);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::affine::Affine3;
+    use crate::coords::Vec3;
+    use crate::crop::{crop_to, crop_to_grid};
+    use crate::grid::GridGeometry;
+    use crate::raster::rasterize;
+    use crate::structure::{Contour, Structure, StructureType};
+
+    fn square(name: &str, cx: f64, half: f64, zs: &[f64]) -> Structure {
+        let contours = zs
+            .iter()
+            .map(|z| {
+                Contour::new(vec![
+                    Vec3::from(cx - half, -half, *z),
+                    Vec3::from(cx + half, -half, *z),
+                    Vec3::from(cx + half, half, *z),
+                    Vec3::from(cx - half, half, *z),
+                ])
+            })
+            .collect();
+        Structure::new(name, StructureType::Ptv).with_contours(contours)
+    }
+
+    fn geometry() -> GridGeometry {
+        GridGeometry::new(
+            [20, 20, 3],
+            Vec3::from(-9.5, -9.5, 0.0),
+            Vec3::from(1.0, 1.0, 1.0),
+        )
+    }
+
+    #[test]
+    fn crop_to_body() {
+        let g = geometry();
+        let ptv = square("PTV", 6.0, 4.0, &[0.0, 1.0, 2.0]);
+        let body = square("BODY", 0.0, 8.0, &[0.0, 1.0, 2.0]);
+        let cropped = crop_to(&ptv, &body, &g);
+        assert_eq!(cropped.name, "PTV");
+        assert_eq!(cropped.structure_type, StructureType::Ptv);
+        let count = rasterize(&cropped, &g)
+            .data()
+            .iter()
+            .filter(|v| **v)
+            .count();
+        // x from 2 to 8 inside the body: 6 columns of 8 voxels on 3 slices.
+        assert_eq!(count, 3 * 6 * 8);
+    }
+
+    #[test]
+    fn crop_to_grid_bounds() {
+        let g = geometry();
+        let ptv = square("PTV", 8.0, 4.0, &[-3.0, 0.0, 1.0, 2.0, 2.4, 2.6]);
+        let cropped = crop_to_grid(&ptv, &g);
+        assert_eq!(cropped.contours.len(), 4);
+        for c in &cropped.contours {
+            assert!((c.area() - 6.0 * 8.0).abs() < 1e-9);
+            assert!(c.points.iter().all(|p| p.x <= 10.0 + 1e-9));
+        }
+        let mut oblique = g.clone();
+        let rot = Affine3::rotation(0.2, 0.0, 0.0);
+        for d in oblique.direction.iter_mut() {
+            *d = rot.transform_vector(*d);
+        }
+        assert!(!crop_to_grid(&ptv, &oblique).contours.is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+//! Exact Euclidean distance transforms of masks.
+//!
+//! Separable lower-envelope algorithm of Felzenszwalb and Huttenlocher, "Distance
+//! Transforms of Sampled Functions" (2012), one pass per grid axis with its spacing.
+//! Distances run between voxel centers in mm and assume orthogonal grid axes.
+
+use crate::grid::Grid3;
+
+/// Distance (mm) from every voxel to the nearest voxel that is set; infinite for an empty
+/// mask.
+pub fn distance_transform(mask: &Grid3<bool>) -> Grid3<f64> {
+    let squared: Vec<f64> = mask
+        .data()
+        .iter()
+        .map(|v| if *v { 0.0 } else { f64::INFINITY })
+        .collect();
+    let geometry = mask.geometry();
+    let spacing = [geometry.spacing.x, geometry.spacing.y, geometry.spacing.z];
+    let squared = squared_transform(squared, geometry.dims, spacing);
+    Grid3::from_vec(geometry.clone(), squared.iter().map(|d| d.sqrt()).collect())
+        .expect("one distance per voxel")
+}
+
+/// Signed distance (mm): minus the distance to the nearest unset voxel inside the mask, the
+/// distance to the nearest set voxel outside it.
+pub fn signed_distance(mask: &Grid3<bool>) -> Grid3<f64> {
+    let outside = distance_transform(mask);
+    let inside = distance_transform(&mask.map(|v| !*v));
+    outside
+        .zip_map(&inside, |o, i| if *o == 0.0 { -*i } else { *o })
+        .expect("same geometry")
+}
+
+/// Squared distances after one lower-envelope pass along every axis.
+pub(crate) fn squared_transform(
+    mut values: Vec<f64>,
+    dims: [usize; 3],
+    spacing: [f64; 3],
+) -> Vec<f64> {
+    let mut line = Vec::new();
+    let mut out = Vec::new();
+    for axis in 0..3 {
+        let n = dims[axis];
+        if n < 2 {
+            continue;
+        }
+        let stride = [1, dims[0], dims[0] * dims[1]][axis];
+        line.resize(n, 0.0);
+        out.resize(n, 0.0);
+        for l in 0..values.len() / n {
+            let start = (l / stride) * stride * n + l % stride;
+            for (p, v) in line.iter_mut().enumerate() {
+                *v = values[start + p * stride];
+            }
+            envelope(&line, spacing[axis], &mut out);
+            for (p, v) in out.iter().enumerate() {
+                values[start + p * stride] = *v;
+            }
+        }
+    }
+    values
+}
+
+/// `out[p] = min_q f[q] + ((p - q) h)^2` over the finite samples of `f`.
+fn envelope(f: &[f64], h: f64, out: &mut [f64]) {
+    let n = f.len();
+    let mut v: Vec<usize> = Vec::with_capacity(n);
+    let mut z: Vec<f64> = Vec::with_capacity(n + 1);
+    let pos = |q: usize| q as f64 * h;
+    for q in 0..n {
+        if !f[q].is_finite() {
+            continue;
+        }
+        loop {
+            match v.last() {
+                None => {
+                    v.push(q);
+                    z.clear();
+                    z.push(f64::NEG_INFINITY);
+                    break;
+                }
+                Some(&r) => {
+                    let s = ((f[q] + pos(q) * pos(q)) - (f[r] + pos(r) * pos(r)))
+                        / (2.0 * (pos(q) - pos(r)));
+                    if s <= *z.last().unwrap() {
+                        v.pop();
+                        z.pop();
+                    } else {
+                        v.push(q);
+                        z.push(s);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    if v.is_empty() {
+        out.iter_mut().for_each(|o| *o = f64::INFINITY);
+        return;
+    }
+    let mut k = 0;
+    for (p, o) in out.iter_mut().enumerate() {
+        let x = pos(p);
+        while k + 1 < v.len() && z[k + 1] < x {
+            k += 1;
+        }
+        let d = x - pos(v[k]);
+        *o = f[v[k]] + d * d;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::distance::{distance_transform, signed_distance};
+    use crate::grid::{Grid3, GridGeometry};
+
+    fn grid() -> Grid3<bool> {
+        let geometry = GridGeometry::new(
+            [7, 6, 5],
+            Vec3::from(0.0, 0.0, 0.0),
+            Vec3::from(1.0, 2.0, 3.0),
+        );
+        Grid3::new(geometry, false)
+    }
+
+    #[test]
+    fn distance_transform_brute_force() {
+        let mut mask = grid();
+        mask.set(1, 1, 1, true);
+        mask.set(5, 4, 3, true);
+        mask.set(6, 0, 4, true);
+        let d = distance_transform(&mask);
+        let g = mask.geometry().clone();
+        for n in 0..mask.len() {
+            let [i, j, k] = g.ijk(n);
+            let p = g.position(i, j, k);
+            let expected = [[1, 1, 1], [5, 4, 3], [6, 0, 4]]
+                .iter()
+                .map(|q| g.position(q[0], q[1], q[2]).distance(p))
+                .fold(f64::INFINITY, f64::min);
+            assert!((d.data()[n] - expected).abs() < 1e-9);
+        }
+        assert!(distance_transform(&grid()).data()[0].is_infinite());
+    }
+
+    #[test]
+    fn signed_distance_slab() {
+        let mut mask = grid();
+        for k in 0..5 {
+            for j in 0..6 {
+                for i in 2..5 {
+                    mask.set(i, j, k, true);
+                }
+            }
+        }
+        let s = signed_distance(&mask);
+        assert_eq!(s[[3, 2, 2]], -2.0);
+        assert_eq!(s[[2, 2, 2]], -1.0);
+        assert_eq!(s[[1, 2, 2]], 1.0);
+        assert_eq!(s[[6, 0, 0]], 2.0);
+    }
+}
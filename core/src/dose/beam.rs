@@ -0,0 +1,276 @@
+//! External beam geometry and fluence maps.
+//!
+//! Angles follow IEC 61217 (degrees) for a head-first supine patient in DICOM patient
+//! coordinates: gantry 0 irradiates from anterior and gantry 90 from the patient's left.
+//! The collimator turns counter-clockwise as seen from the source and the couch
+//! counter-clockwise as seen from above. Beam coordinates `x` and `y` are the collimator
+//! axes: at all angles 0, `x` points to the patient's left and `y` towards the head.
+
+use crate::coords::Vec3;
+use crate::error::{Error, Result};
+use crate::outcome::normal_cdf;
+use crate::raster::fill_polygons;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeamGeometry {
+    /// mm
+    pub isocenter: Vec3<f64>,
+    /// Degrees.
+    pub gantry: f64,
+    /// Degrees.
+    pub collimator: f64,
+    /// Degrees.
+    pub couch: f64,
+    /// Source to axis distance (mm).
+    pub sad: f64,
+}
+
+/// IEC fixed coordinates to DICOM patient coordinates of a head-first supine patient.
+fn to_patient(v: Vec3<f64>) -> Vec3<f64> {
+    Vec3::from(v.x, -v.z, v.y)
+}
+
+impl BeamGeometry {
+    pub fn new(isocenter: Vec3<f64>, gantry: f64, sad: f64) -> Self {
+        BeamGeometry {
+            isocenter,
+            gantry,
+            collimator: 0.0,
+            couch: 0.0,
+            sad,
+        }
+    }
+
+    /// Unit vectors `x`, `y` and towards the source, in patient coordinates.
+    pub fn axes(&self) -> [Vec3<f64>; 3] {
+        let (sg, cg) = self.gantry.to_radians().sin_cos();
+        let (sc, cc) = self.collimator.to_radians().sin_cos();
+        let (st, ct) = self.couch.to_radians().sin_cos();
+        let source = Vec3::from(sg, 0.0, cg);
+        let x = Vec3::from(cg, 0.0, -sg);
+        let y = Vec3::from(0.0, 1.0, 0.0);
+        let (x, y) = (x.scale(cc) + y.scale(sc), y.scale(cc) - x.scale(sc));
+        // The couch turns the patient, so the fixed frame turns the other way relative to it.
+        let couch = |v: Vec3<f64>| Vec3::from(v.x * ct + v.y * st, -v.x * st + v.y * ct, v.z);
+        [
+            to_patient(couch(x)),
+            to_patient(couch(y)),
+            to_patient(couch(source)),
+        ]
+    }
+
+    /// Source position (mm).
+    pub fn source(&self) -> Vec3<f64> {
+        self.isocenter + self.axes()[2].scale(self.sad)
+    }
+
+    /// Unit vector from the source to the isocenter.
+    pub fn direction(&self) -> Vec3<f64> {
+        -self.axes()[2]
+    }
+
+    /// Beam coordinates `(x, y)` (mm) of `p` projected from the source onto the isocenter
+    /// plane, and the distance (mm) of `p` from the source along the central axis; `None`
+    /// at or behind the source.
+    pub fn project(&self, p: Vec3<f64>) -> Option<(f64, f64, f64)> {
+        let [x, y, toward] = self.axes();
+        let r = p - (self.isocenter + toward.scale(self.sad));
+        let z = -r.dot(toward);
+        if z <= 0.0 {
+            return None;
+        }
+        let scale = self.sad / z;
+        Some((r.dot(x) * scale, r.dot(y) * scale, z))
+    }
+}
+
+/// Fluence on a regular raster in the isocenter plane, in beam coordinates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fluence {
+    /// Center of pixel (0, 0) (mm).
+    pub origin: [f64; 2],
+    /// mm
+    pub spacing: [f64; 2],
+    pub dims: [usize; 2],
+    /// Relative fluence, `x` running fastest; 1 is the calibration fluence.
+    pub values: Vec<f64>,
+}
+
+impl Fluence {
+    pub fn new(origin: [f64; 2], spacing: [f64; 2], dims: [usize; 2], value: f64) -> Self {
+        Fluence {
+            origin,
+            spacing,
+            dims,
+            values: vec![value; dims[0] * dims[1]],
+        }
+    }
+
+    /// Uniform `value` over the rectangle `x[0]..x[1]`, `y[0]..y[1]` (mm), on pixels of at
+    /// most `spacing` (mm) that tile it exactly.
+    pub fn rectangle(x: [f64; 2], y: [f64; 2], spacing: f64, value: f64) -> Result<Self> {
+        if !(x[1] > x[0] && y[1] > y[0] && spacing > 0.0) {
+            return Err(Error::InvalidArgument(format!(
+                "empty field {:?} x {:?} or non-positive spacing {}",
+                x, y, spacing
+            )));
+        }
+        let nx = ((x[1] - x[0]) / spacing).ceil() as usize;
+        let ny = ((y[1] - y[0]) / spacing).ceil() as usize;
+        let (sx, sy) = ((x[1] - x[0]) / nx as f64, (y[1] - y[0]) / ny as f64);
+        Ok(Fluence::new(
+            [x[0] + 0.5 * sx, y[0] + 0.5 * sy],
+            [sx, sy],
+            [nx, ny],
+            value,
+        ))
+    }
+
+    /// `value` inside the polygon `points` (mm, even-odd rule), e.g. an electron cutout
+    /// projected to the isocenter plane, and 0 elsewhere, on pixels of `spacing` (mm).
+    pub fn polygon(points: &[(f64, f64)], spacing: f64, value: f64) -> Result<Self> {
+        if points.len() < 3 || spacing <= 0.0 {
+            return Err(Error::InvalidArgument(format!(
+                "a polygon of {} points on pixels of {} mm",
+                points.len(),
+                spacing
+            )));
+        }
+        let (mut lo, mut hi) = ([f64::INFINITY; 2], [f64::NEG_INFINITY; 2]);
+        for (x, y) in points {
+            lo = [lo[0].min(*x), lo[1].min(*y)];
+            hi = [hi[0].max(*x), hi[1].max(*y)];
+        }
+        let mut fluence = Fluence::rectangle([lo[0], hi[0]], [lo[1], hi[1]], spacing, 0.0)?;
+        let pixels: Vec<(f64, f64)> = points
+            .iter()
+            .map(|(x, y)| {
+                (
+                    (x - fluence.origin[0]) / fluence.spacing[0],
+                    (y - fluence.origin[1]) / fluence.spacing[1],
+                )
+            })
+            .collect();
+        let [nx, ny] = fluence.dims;
+        fill_polygons(&[pixels], nx, ny, |i, j| fluence.set(i, j, value));
+        Ok(fluence)
+    }
+
+    pub fn get(&self, i: usize, j: usize) -> f64 {
+        self.values[i + self.dims[0] * j]
+    }
+
+    pub fn set(&mut self, i: usize, j: usize, value: f64) {
+        self.values[i + self.dims[0] * j] = value;
+    }
+
+    /// Value of the pixel holding `(x, y)` (mm); 0 outside the raster.
+    pub fn at(&self, x: f64, y: f64) -> f64 {
+        let i = ((x - self.origin[0]) / self.spacing[0] + 0.5).floor();
+        let j = ((y - self.origin[1]) / self.spacing[1] + 0.5).floor();
+        if i < 0.0 || j < 0.0 || i >= self.dims[0] as f64 || j >= self.dims[1] as f64 {
+            0.0
+        } else {
+            self.get(i as usize, j as usize)
+        }
+    }
+
+    /// Center of pixel `(i, j)` (mm).
+    pub fn position(&self, i: usize, j: usize) -> (f64, f64) {
+        (
+            self.origin[0] + i as f64 * self.spacing[0],
+            self.origin[1] + j as f64 * self.spacing[1],
+        )
+    }
+
+    /// Fluence convolved with a normalized 2D Gaussian of `sigma` (mm) at `(x, y)`, each
+    /// pixel integrated exactly; pixels beyond `cutoff` sigmas are skipped.
+    pub fn convolve_gaussian(&self, x: f64, y: f64, sigma: f64, cutoff: f64) -> f64 {
+        let weights = |c: f64, axis: usize| {
+            let half = 0.5 * self.spacing[axis];
+            let reach = cutoff * sigma + half;
+            let first = ((c - reach - self.origin[axis]) / self.spacing[axis])
+                .ceil()
+                .max(0.0);
+            let last = ((c + reach - self.origin[axis]) / self.spacing[axis])
+                .floor()
+                .min(self.dims[axis] as f64 - 1.0);
+            let mut out = Vec::new();
+            let mut n = first;
+            while n <= last {
+                let center = self.origin[axis] + n * self.spacing[axis];
+                let w = if sigma > 0.0 {
+                    normal_cdf((center + half - c) / sigma)
+                        - normal_cdf((center - half - c) / sigma)
+                } else if (c - center).abs() < half {
+                    1.0
+                } else {
+                    0.0
+                };
+                out.push((n as usize, w));
+                n += 1.0;
+            }
+            out
+        };
+        let (wx, wy) = (weights(x, 0), weights(y, 1));
+        let mut sum = 0.0;
+        for (j, b) in &wy {
+            let row = &self.values[self.dims[0] * j..];
+            for (i, a) in &wx {
+                sum += a * b * row[*i];
+            }
+        }
+        sum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::beam::{BeamGeometry, Fluence};
+
+    #[test]
+    fn beam_geometry_angles() {
+        let iso = Vec3::from(10.0, 20.0, 30.0);
+        let beam = BeamGeometry::new(iso, 0.0, 1000.0);
+        assert!(beam.source().distance(Vec3::from(10.0, -980.0, 30.0)) < 1e-9);
+        let (x, y, z) = beam.project(Vec3::from(15.0, 520.0, 28.0)).unwrap();
+        assert!((x - 10.0 / 3.0).abs() < 1e-9 && (y + 4.0 / 3.0).abs() < 1e-9);
+        assert!((z - 1500.0).abs() < 1e-9);
+
+        let left = BeamGeometry::new(iso, 90.0, 1000.0);
+        assert!(left.source().distance(Vec3::from(1010.0, 20.0, 30.0)) < 1e-9);
+        assert!(left.direction().distance(Vec3::from(-1.0, 0.0, 0.0)) < 1e-12);
+        assert!(left.project(Vec3::from(2000.0, 0.0, 0.0)).is_none());
+
+        // Collimator 90 turns x onto the former y; couch 90 brings the source to the feet.
+        let collimator = BeamGeometry {
+            collimator: 90.0,
+            ..beam
+        };
+        assert!(collimator.axes()[0].distance(Vec3::from(0.0, 0.0, 1.0)) < 1e-12);
+        let couch = BeamGeometry {
+            couch: 90.0,
+            ..left
+        };
+        assert!(couch.source().distance(Vec3::from(10.0, 20.0, -970.0)) < 1e-9);
+    }
+
+    #[test]
+    fn fluence_convolution() {
+        let fluence = Fluence::rectangle([-50.0, 50.0], [-20.0, 30.0], 3.0, 2.0).unwrap();
+        assert_eq!(fluence.dims, [34, 17]);
+        assert!((fluence.position(0, 0).0 + 50.0 - 0.5 * fluence.spacing[0]).abs() < 1e-12);
+        assert!((fluence.convolve_gaussian(0.0, 5.0, 4.0, 5.0) - 2.0).abs() < 1e-6);
+        // Half of the Gaussian falls outside at a field edge.
+        assert!((fluence.convolve_gaussian(50.0, 5.0, 4.0, 5.0) - 1.0).abs() < 1e-6);
+        assert_eq!(fluence.convolve_gaussian(0.0, 100.0, 4.0, 5.0), 0.0);
+        assert_eq!((fluence.at(49.9, 29.9), fluence.at(50.1, 0.0)), (2.0, 0.0));
+        assert!(Fluence::rectangle([0.0, 0.0], [0.0, 1.0], 1.0, 1.0).is_err());
+
+        let triangle = Fluence::polygon(&[(0.0, 0.0), (40.0, 0.0), (0.0, 40.0)], 1.0, 1.0).unwrap();
+        let area: f64 = triangle.values.iter().sum();
+        assert!((area - 800.0).abs() < 40.0, "{}", area);
+        assert_eq!((triangle.at(5.0, 5.0), triangle.at(30.0, 30.0)), (1.0, 0.0));
+    }
+}
@@ -0,0 +1,368 @@
+//! Brachytherapy dose calculation with the AAPM TG-43 line-source formalism.
+//!
+//! `D = S_K Λ G_L(r, θ) / G_L(r0, θ0) g_L(r) F(r, θ) t` with `r0` = 1 cm and `θ0` = 90°,
+//! summed over all dwell positions. Source data are given in the units of the consensus
+//! tables (cm, degrees, cGy h⁻¹ U⁻¹); positions are in patient coordinates (mm). The radial
+//! dose function is interpolated linearly, held below its first radius and extrapolated
+//! log-linearly beyond its last; the anisotropy function is interpolated bilinearly and held
+//! beyond its table. No source data are built in: register the consensus data of the
+//! sources in use.
+
+use std::collections::HashMap;
+
+use crate::coords::Vec3;
+use crate::error::{Error, Result};
+use crate::grid::{per_voxel, Grid3, GridGeometry};
+
+/// 2D anisotropy function `F(r, θ)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnisotropyTable {
+    /// cm, increasing.
+    pub radii: Vec<f64>,
+    /// Degrees from the source's long axis, increasing.
+    pub angles: Vec<f64>,
+    /// One row per angle, one column per radius.
+    pub values: Vec<Vec<f64>>,
+}
+
+/// Linear interpolation index and weight of `x` in `xs`, held beyond the ends.
+fn bracket(xs: &[f64], x: f64) -> (usize, usize, f64) {
+    if xs.len() == 1 || x <= xs[0] {
+        return (0, 0, 0.0);
+    }
+    if x >= xs[xs.len() - 1] {
+        return (xs.len() - 1, xs.len() - 1, 0.0);
+    }
+    let n = xs.partition_point(|v| *v <= x);
+    (n - 1, n, (x - xs[n - 1]) / (xs[n] - xs[n - 1]))
+}
+
+impl AnisotropyTable {
+    pub fn at(&self, r: f64, theta: f64) -> f64 {
+        let (r0, r1, u) = bracket(&self.radii, r);
+        let (a0, a1, v) = bracket(&self.angles, theta);
+        let row = |a: usize| {
+            let values = &self.values[a];
+            values[r0] + u * (values[r1] - values[r0])
+        };
+        row(a0) + v * (row(a1) - row(a0))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tg43Source {
+    pub name: String,
+    /// Λ (cGy h⁻¹ U⁻¹).
+    pub dose_rate_constant: f64,
+    /// Active length L (cm).
+    pub active_length: f64,
+    /// `g_L(r)` as (r in cm, value), increasing in r.
+    pub radial_dose: Vec<(f64, f64)>,
+    pub anisotropy: AnisotropyTable,
+    /// Days; `None` for sources that do not decay noticeably.
+    pub half_life: Option<f64>,
+}
+
+impl Tg43Source {
+    fn validate(&self) -> Result<()> {
+        let a = &self.anisotropy;
+        let valid = self.dose_rate_constant > 0.0
+            && self.active_length >= 0.0
+            && !self.radial_dose.is_empty()
+            && self.radial_dose.windows(2).all(|w| w[1].0 > w[0].0)
+            && !a.radii.is_empty()
+            && !a.angles.is_empty()
+            && a.values.len() == a.angles.len()
+            && a.values.iter().all(|row| row.len() == a.radii.len());
+        if valid {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgument(format!(
+                "incomplete TG-43 data for source {}",
+                self.name
+            )))
+        }
+    }
+
+    /// Line-source geometry function (cm⁻²) at `r` (cm) and `theta` (radians).
+    fn geometry_function(&self, r: f64, theta: f64) -> f64 {
+        let l = self.active_length;
+        if l == 0.0 {
+            return 1.0 / (r * r);
+        }
+        let (z, y) = (r * theta.cos(), r * theta.sin());
+        if y.abs() < 1e-9 {
+            let d = r * r - l * l / 4.0;
+            return if d > 0.0 { 1.0 / d } else { 0.0 };
+        }
+        let beta = ((z + l / 2.0) / y).atan() - ((z - l / 2.0) / y).atan();
+        beta / (l * y)
+    }
+
+    pub fn radial_dose_function(&self, r: f64) -> f64 {
+        let g = &self.radial_dose;
+        if g.len() == 1 || r <= g[0].0 {
+            return g[0].1;
+        }
+        let n = g.len();
+        if r >= g[n - 1].0 {
+            let ((r0, g0), (r1, g1)) = (g[n - 2], g[n - 1]);
+            if g0 > 0.0 && g1 > 0.0 {
+                return g1 * ((g1 / g0).ln() * (r - r1) / (r1 - r0)).exp();
+            }
+            return g1;
+        }
+        let m = g.partition_point(|(x, _)| *x <= r);
+        let ((r0, g0), (r1, g1)) = (g[m - 1], g[m]);
+        g0 + (r - r0) / (r1 - r0) * (g1 - g0)
+    }
+
+    /// Dose rate (cGy h⁻¹ U⁻¹) at `r` (cm) and `theta` (degrees from the long axis).
+    pub fn dose_rate(&self, r: f64, theta: f64) -> f64 {
+        let reference = self.geometry_function(1.0, std::f64::consts::FRAC_PI_2);
+        self.dose_rate_constant * self.geometry_function(r, theta.to_radians()) / reference
+            * self.radial_dose_function(r)
+            * self.anisotropy.at(r, theta)
+    }
+
+    /// Air-kerma strength after `days` of decay.
+    pub fn decayed_strength(&self, strength: f64, days: f64) -> f64 {
+        match self.half_life {
+            Some(t) => strength * (-std::f64::consts::LN_2 * days / t).exp(),
+            None => strength,
+        }
+    }
+}
+
+/// Sources by name (case-insensitive).
+#[derive(Debug, Clone, Default)]
+pub struct SourceRegistry {
+    sources: HashMap<String, Tg43Source>,
+}
+
+impl SourceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, source: Tg43Source) -> Result<()> {
+        source.validate()?;
+        self.sources.insert(source.name.to_lowercase(), source);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Tg43Source> {
+        self.sources
+            .get(&name.to_lowercase())
+            .ok_or_else(|| Error::InvalidArgument(format!("unknown brachytherapy source {}", name)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dwell {
+    /// mm
+    pub position: Vec3<f64>,
+    /// s
+    pub time: f64,
+}
+
+/// One catheter or applicator channel; the source axis at a dwell follows its neighbours.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Channel {
+    pub dwells: Vec<Dwell>,
+}
+
+impl Channel {
+    /// Dwells from RTPLAN-style control points: positions (mm) and cumulative time weights,
+    /// in pairs of equal positions, scaled to `total_time` (s).
+    pub fn from_control_points(
+        positions: &[Vec3<f64>],
+        cumulative_weights: &[f64],
+        total_time: f64,
+    ) -> Result<Self> {
+        if positions.len() != cumulative_weights.len() || positions.len() < 2 {
+            return Err(Error::InvalidArgument(format!(
+                "{} control point positions for {} weights",
+                positions.len(),
+                cumulative_weights.len()
+            )));
+        }
+        let last = cumulative_weights[cumulative_weights.len() - 1];
+        if last <= 0.0 {
+            return Err(Error::InvalidArgument(
+                "the final cumulative time weight must be positive".to_string(),
+            ));
+        }
+        let mut dwells = Vec::new();
+        for n in 1..positions.len() {
+            let weight = cumulative_weights[n] - cumulative_weights[n - 1];
+            if weight > 0.0 && positions[n].distance(positions[n - 1]) < 1e-6 {
+                dwells.push(Dwell {
+                    position: positions[n],
+                    time: total_time * weight / last,
+                });
+            }
+        }
+        Ok(Channel { dwells })
+    }
+
+    /// Unit vector along the channel at dwell `n`; the z axis for a lone dwell.
+    fn axis(&self, n: usize) -> Vec3<f64> {
+        let d = &self.dwells;
+        let (a, b) = (
+            d[n.saturating_sub(1)].position,
+            d[(n + 1).min(d.len() - 1)].position,
+        );
+        let v = b - a;
+        if v.norm() > 0.0 {
+            v.normalize()
+        } else {
+            Vec3::from(0.0, 0.0, 1.0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BrachyOptions {
+    /// Points closer to a dwell (mm) take the dose at this distance.
+    pub min_distance: f64,
+    /// Worker threads; 0 uses the available parallelism.
+    pub threads: usize,
+}
+
+impl Default for BrachyOptions {
+    fn default() -> Self {
+        BrachyOptions {
+            min_distance: 1.0,
+            threads: 0,
+        }
+    }
+}
+
+/// Dose (Gy) on `geometry` of a source of air-kerma `strength` (U) dwelling in `channels`.
+pub fn brachy_dose(
+    source: &Tg43Source,
+    strength: f64,
+    channels: &[Channel],
+    geometry: &GridGeometry,
+    options: &BrachyOptions,
+) -> Result<Grid3<f64>> {
+    source.validate()?;
+    let dwells: Vec<(Vec3<f64>, Vec3<f64>, f64)> = channels
+        .iter()
+        .flat_map(|c| {
+            (0..c.dwells.len()).map(move |n| (c.dwells[n].position, c.axis(n), c.dwells[n].time))
+        })
+        .filter(|(_, _, t)| *t > 0.0)
+        .collect();
+    let values = per_voxel(geometry, options.threads, |n| {
+        let [i, j, k] = geometry.ijk(n);
+        let p = geometry.position(i, j, k);
+        let mut dose = 0.0;
+        for (position, axis, time) in &dwells {
+            let v = p - *position;
+            let r = v.norm();
+            let cos = if r > 0.0 { v.dot(*axis) / r } else { 0.0 };
+            let theta = cos.clamp(-1.0, 1.0).acos().to_degrees();
+            let r = r.max(options.min_distance) / 10.0;
+            // cGy/h to Gy for a dwell time in seconds.
+            dose += strength * source.dose_rate(r, theta) * time / 360_000.0;
+        }
+        dose
+    });
+    Grid3::from_vec(geometry.clone(), values)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::brachy::{
+        brachy_dose, AnisotropyTable, BrachyOptions, Channel, Dwell, SourceRegistry, Tg43Source,
+    };
+    use crate::grid::GridGeometry;
+
+    fn source() -> Tg43Source {
+        Tg43Source {
+            name: "Test Ir-192".to_string(),
+            dose_rate_constant: 1.1,
+            active_length: 0.35,
+            radial_dose: vec![(0.5, 1.0), (1.0, 1.0), (2.0, 0.99), (5.0, 0.95)],
+            anisotropy: AnisotropyTable {
+                radii: vec![1.0, 5.0],
+                angles: vec![0.0, 30.0, 90.0, 150.0, 180.0],
+                values: vec![
+                    vec![0.6, 0.7],
+                    vec![0.9, 0.92],
+                    vec![1.0, 1.0],
+                    vec![0.9, 0.92],
+                    vec![0.6, 0.7],
+                ],
+            },
+            half_life: Some(73.83),
+        }
+    }
+
+    #[test]
+    fn tg43_dose_rate() {
+        let s = source();
+        // Λ at the reference point, by definition.
+        assert!((s.dose_rate(1.0, 90.0) - 1.1).abs() < 1e-12);
+        let g = |r: f64| s.geometry_function(r, std::f64::consts::FRAC_PI_2);
+        let expected = 1.1 * g(2.0) / g(1.0) * 0.99;
+        assert!((s.dose_rate(2.0, 90.0) - expected).abs() < 1e-12);
+        assert!((s.dose_rate(2.0, 90.0) - 1.1 * 0.99 / 4.0).abs() < 0.01);
+        assert!((s.anisotropy.at(3.0, 15.0) - 0.78).abs() < 1e-12);
+        assert!(
+            (s.radial_dose_function(10.0) - 0.95 * (0.95f64 / 0.99).powf(5.0 / 3.0)).abs() < 1e-12
+        );
+        assert!((s.decayed_strength(40000.0, 73.83) - 20000.0).abs() < 1e-9);
+
+        let mut registry = SourceRegistry::new();
+        registry.register(s).unwrap();
+        assert!(registry.get("test ir-192").is_ok());
+        assert!(registry.get("I-125").is_err());
+    }
+
+    #[test]
+    fn tg43_superposition() {
+        let s = source();
+        let geometry = GridGeometry::new(
+            [21, 21, 1],
+            Vec3::from(-20.0, -20.0, 0.0),
+            Vec3::from(2.0, 2.0, 2.0),
+        );
+        let channel = Channel {
+            dwells: vec![
+                Dwell {
+                    position: Vec3::new(),
+                    time: 3600.0,
+                },
+                Dwell {
+                    position: Vec3::from(0.0, 0.0, 5.0),
+                    time: 0.0,
+                },
+            ],
+        };
+        let dose =
+            brachy_dose(&s, 100.0, &[channel], &geometry, &BrachyOptions::default()).unwrap();
+        // 10 mm from the dwell on the transverse plane: 100 U × Λ for an hour.
+        assert!((dose[[15, 10, 0]] - 1.1).abs() < 1e-12);
+        assert!((dose[[10, 15, 0]] - dose[[5, 10, 0]]).abs() < 1e-12);
+        assert!(dose[[10, 10, 0]] > dose[[15, 10, 0]]);
+
+        let positions = [
+            Vec3::new(),
+            Vec3::new(),
+            Vec3::from(0.0, 0.0, 5.0),
+            Vec3::from(0.0, 0.0, 5.0),
+        ];
+        let channel =
+            Channel::from_control_points(&positions, &[0.0, 1.0, 1.0, 4.0], 200.0).unwrap();
+        assert_eq!(channel.dwells.len(), 2);
+        assert_eq!(
+            (channel.dwells[0].time, channel.dwells[1].time),
+            (50.0, 150.0)
+        );
+        assert!(Channel::from_control_points(&positions, &[0.0], 1.0).is_err());
+    }
+}
@@ -0,0 +1,521 @@
+//! Measured beam data and the fitting of engine parameters to it.
+//!
+//! A [`BeamData`] set holds the scans of one machine and energy as measured in a water tank
+//! ([`Measurement`]: depth doses and lateral profiles), the output factors, the MLC
+//! transmission and dosimetric leaf gap, and the absolute calibration. Positions are in mm
+//! in the plane of the measurement and field sizes in mm at the isocenter. The loaders for
+//! the exchange formats are in [`crate::io::beam_data`].
+//!
+//! [`fit_pencil_beam`] derives a [`PencilBeamKernel`]: the primary sigma from the 80-20 %
+//! penumbra of the profiles, the scatter weight from the output factors and the depth dose
+//! from the largest field with the modelled lateral scatter and the inverse square law
+//! removed.
+
+use crate::dose::pencil_beam::{KernelSample, PencilBeamKernel};
+use crate::error::{Error, Result};
+use crate::outcome::normal_cdf;
+
+/// Scan direction of a measurement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    Crossline,
+    Inline,
+    Depth,
+}
+
+/// A scan in water: a depth dose (`depth` is `None`) or a lateral profile at `depth`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub axis: Axis,
+    /// Collimated field size (mm) at the isocenter, crossline and inline.
+    pub field_size: [f64; 2],
+    /// Source to surface distance (mm).
+    pub ssd: f64,
+    /// Depth (mm) of a profile.
+    pub depth: Option<f64>,
+    /// Ascending positions (mm).
+    pub positions: Vec<f64>,
+    /// Relative readings.
+    pub values: Vec<f64>,
+}
+
+impl Measurement {
+    pub fn new(
+        axis: Axis,
+        field_size: [f64; 2],
+        ssd: f64,
+        depth: Option<f64>,
+        points: Vec<(f64, f64)>,
+    ) -> Result<Self> {
+        if points.len() < 2 {
+            return Err(Error::InvalidArgument(format!(
+                "a scan needs at least two points, got {}",
+                points.len()
+            )));
+        }
+        let mut points = points;
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        Ok(Measurement {
+            axis,
+            field_size,
+            ssd,
+            depth,
+            positions: points.iter().map(|p| p.0).collect(),
+            values: points.iter().map(|p| p.1).collect(),
+        })
+    }
+
+    pub fn is_depth_dose(&self) -> bool {
+        self.axis == Axis::Depth
+    }
+
+    pub fn max(&self) -> f64 {
+        self.values.iter().cloned().fold(0.0, f64::max)
+    }
+
+    /// Reading at `position`, interpolated linearly and held beyond the ends.
+    pub fn at(&self, position: f64) -> f64 {
+        interpolate(&self.positions, &self.values, position)
+    }
+
+    /// Readings divided by the maximum.
+    pub fn normalized(&self) -> Vec<f64> {
+        let max = self.max();
+        self.values.iter().map(|v| v / max).collect()
+    }
+
+    /// Positions where the scan crosses `fraction` of its maximum, searching outward from the
+    /// maximum to the lower and the upper end.
+    pub fn crossings(&self, fraction: f64) -> (Option<f64>, Option<f64>) {
+        let level = fraction * self.max();
+        let peak = self
+            .values
+            .iter()
+            .enumerate()
+            .fold(0, |m, (n, v)| if *v > self.values[m] { n } else { m });
+        let cross = |a: usize, b: usize| {
+            let (va, vb) = (self.values[a], self.values[b]);
+            let t = (level - va) / (vb - va);
+            self.positions[a] + t * (self.positions[b] - self.positions[a])
+        };
+        let lower = (1..=peak)
+            .rev()
+            .find(|n| self.values[n - 1] < level)
+            .map(|n| cross(n - 1, n));
+        let upper = (peak + 1..self.values.len())
+            .find(|n| self.values[*n] < level)
+            .map(|n| cross(n - 1, n));
+        (lower, upper)
+    }
+
+    /// Mean distance (mm) between the 80 % and 20 % levels on both edges of a profile.
+    pub fn penumbra(&self) -> Option<f64> {
+        let (l80, u80) = self.crossings(0.8);
+        let (l20, u20) = self.crossings(0.2);
+        match (l80, u80, l20, u20) {
+            (Some(l80), Some(u80), Some(l20), Some(u20)) => Some(0.5 * ((l80 - l20) + (u20 - u80))),
+            _ => None,
+        }
+    }
+
+    /// Distance (mm) between the 50 % levels.
+    pub fn field_width(&self) -> Option<f64> {
+        match self.crossings(0.5) {
+            (Some(lower), Some(upper)) => Some(upper - lower),
+            _ => None,
+        }
+    }
+}
+
+/// Output factors of square fields at one depth, relative to the reference field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputFactors {
+    /// Depth (mm) of the readings.
+    pub depth: f64,
+    /// Ascending side lengths (mm) at the isocenter.
+    pub sides: Vec<f64>,
+    pub values: Vec<f64>,
+}
+
+impl OutputFactors {
+    /// Output factor of a square field of `side` mm, interpolated linearly.
+    pub fn at(&self, side: f64) -> f64 {
+        interpolate(&self.sides, &self.values, side)
+    }
+}
+
+/// MLC leakage parameters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MlcData {
+    /// Dose through closed leaves relative to the open field.
+    pub transmission: f64,
+    /// Dosimetric leaf gap (mm): the rounded leaf ends transmit as if the gap was this much
+    /// wider.
+    pub dlg: f64,
+}
+
+impl MlcData {
+    /// Fits the transmission and the dosimetric leaf gap to sweeping gap readings: `gaps`
+    /// pairs each gap width (mm) with its reading, `open` and `closed` are the readings of the
+    /// open field and with the leaves closed behind the jaws. The readings minus the
+    /// transmission through the leaves grow linearly with the gap and vanish at minus the
+    /// dosimetric leaf gap.
+    pub fn from_sweeping_gaps(gaps: &[(f64, f64)], open: f64, closed: f64) -> Result<Self> {
+        if gaps.len() < 2 || open <= 0.0 {
+            return Err(Error::InvalidArgument(
+                "sweeping gap fit needs two gaps and a positive open reading".to_string(),
+            ));
+        }
+        let transmission = closed / open;
+        let points: Vec<(f64, f64)> = gaps
+            .iter()
+            .map(|(g, r)| (*g, r - transmission * open))
+            .collect();
+        let (slope, intercept) = linear_fit(&points)?;
+        if slope <= 0.0 {
+            return Err(Error::InvalidArgument(
+                "sweeping gap readings do not grow with the gap".to_string(),
+            ));
+        }
+        Ok(MlcData {
+            transmission,
+            dlg: intercept / slope,
+        })
+    }
+}
+
+/// Measured data of one machine and energy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamData {
+    pub machine: String,
+    pub energy: String,
+    /// Gy per MU at the maximum of the depth dose of the largest field.
+    pub calibration: f64,
+    pub measurements: Vec<Measurement>,
+    pub output_factors: Option<OutputFactors>,
+    pub mlc: Option<MlcData>,
+}
+
+impl BeamData {
+    pub fn new(machine: &str, energy: &str, calibration: f64) -> Self {
+        BeamData {
+            machine: machine.to_string(),
+            energy: energy.to_string(),
+            calibration,
+            measurements: Vec::new(),
+            output_factors: None,
+            mlc: None,
+        }
+    }
+
+    pub fn depth_doses(&self) -> impl Iterator<Item = &Measurement> {
+        self.measurements.iter().filter(|m| m.is_depth_dose())
+    }
+
+    pub fn profiles(&self) -> impl Iterator<Item = &Measurement> {
+        self.measurements.iter().filter(|m| !m.is_depth_dose())
+    }
+
+    /// Depth dose of the largest field.
+    pub fn broad_depth_dose(&self) -> Option<&Measurement> {
+        self.depth_doses()
+            .fold(None, |best: Option<&Measurement>, m| match best {
+                Some(b)
+                    if b.field_size[0] * b.field_size[1] >= m.field_size[0] * m.field_size[1] =>
+                {
+                    Some(b)
+                }
+                _ => Some(m),
+            })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PencilBeamFit {
+    /// Source to axis distance (mm).
+    pub sad: f64,
+    /// Depth spacing (mm) of the kernel samples.
+    pub depth_step: f64,
+    /// Width (mm) of the scatter Gaussian.
+    pub sigma_scatter: f64,
+}
+
+impl Default for PencilBeamFit {
+    fn default() -> Self {
+        PencilBeamFit {
+            sad: 1000.0,
+            depth_step: 5.0,
+            sigma_scatter: 30.0,
+        }
+    }
+}
+
+/// Central-axis share of a Gaussian of `sigma` inside a centered `a` by `b` field.
+fn inside(a: f64, b: f64, sigma: f64) -> f64 {
+    if sigma <= 0.0 {
+        return 1.0;
+    }
+    (2.0 * normal_cdf(0.5 * a / sigma) - 1.0) * (2.0 * normal_cdf(0.5 * b / sigma) - 1.0)
+}
+
+/// Fits a pencil-beam kernel to `data`, see the module documentation. Needs the depth dose
+/// of a broad field and at least one profile; without output factors the scatter weight is 0.
+pub fn fit_pencil_beam(data: &BeamData, fit: &PencilBeamFit) -> Result<PencilBeamKernel> {
+    if fit.sad <= 0.0 || fit.depth_step <= 0.0 {
+        return Err(Error::InvalidArgument(format!(
+            "invalid fit settings {:?}",
+            fit
+        )));
+    }
+    let pdd = data
+        .broad_depth_dose()
+        .ok_or_else(|| Error::InvalidArgument("no depth dose to fit".to_string()))?;
+
+    // Primary sigma per profile depth, in the plane of the profile.
+    let mut sigmas: Vec<(f64, f64)> = data
+        .profiles()
+        .filter_map(|m| match (m.depth, m.penumbra()) {
+            (Some(depth), Some(width)) => Some((depth, width / (2.0 * 0.841_621_233_572_914_2))),
+            _ => None,
+        })
+        .collect();
+    if sigmas.is_empty() {
+        return Err(Error::InvalidArgument(
+            "no profile with a measurable penumbra".to_string(),
+        ));
+    }
+    sigmas.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let mut depths: Vec<f64> = Vec::new();
+    let mut values: Vec<f64> = Vec::new();
+    for (d, s) in sigmas {
+        match depths.last() {
+            Some(last) if (d - last).abs() < 1e-6 => {
+                let n = values.len() - 1;
+                values[n] = 0.5 * (values[n] + s);
+            }
+            _ => {
+                depths.push(d);
+                values.push(s);
+            }
+        }
+    }
+    let sigma_primary = |depth: f64| interpolate(&depths, &values, depth);
+
+    // Scatter weight matching the output factors in the plane of their depth.
+    let scatter_weight = match &data.output_factors {
+        Some(of) if of.sides.len() >= 2 => {
+            let z = (pdd.ssd + of.depth) / fit.sad;
+            let sp = sigma_primary(of.depth);
+            let lateral = |w: f64, side: f64| {
+                let s = side * z;
+                (1.0 - w) * inside(s, s, sp) + w * inside(s, s, fit.sigma_scatter)
+            };
+            let reference = 100.0;
+            let error = |w: f64| {
+                of.sides
+                    .iter()
+                    .zip(&of.values)
+                    .map(|(side, v)| {
+                        let r = lateral(w, *side) / lateral(w, reference);
+                        (r - v) * (r - v)
+                    })
+                    .sum::<f64>()
+            };
+            golden_section(error, 0.0, 1.0)
+        }
+        _ => 0.0,
+    };
+
+    let max = pdd.max();
+    if max <= 0.0 {
+        return Err(Error::InvalidArgument(
+            "depth dose without a positive reading".to_string(),
+        ));
+    }
+    let last = pdd.positions[pdd.positions.len() - 1];
+    let first = pdd.positions[0].max(0.0);
+    let steps = ((last - first) / fit.depth_step).floor() as usize;
+    let samples = (0..=steps)
+        .map(|n| {
+            let depth = first + n as f64 * fit.depth_step;
+            let z = (pdd.ssd + depth) / fit.sad;
+            let sp = sigma_primary(depth);
+            let (a, b) = (pdd.field_size[0] * z, pdd.field_size[1] * z);
+            let lateral = (1.0 - scatter_weight) * inside(a, b, sp)
+                + scatter_weight * inside(a, b, fit.sigma_scatter);
+            let dose = data.calibration * pdd.at(depth) / max * z * z / lateral;
+            KernelSample {
+                depth,
+                dose,
+                sigma_primary: sp,
+                sigma_scatter: fit.sigma_scatter,
+                scatter_weight,
+            }
+        })
+        .collect();
+    PencilBeamKernel::new(samples)
+}
+
+/// Linear interpolation in ascending `x`, held beyond the ends.
+fn interpolate(x: &[f64], y: &[f64], at: f64) -> f64 {
+    if x.is_empty() {
+        return 0.0;
+    }
+    if at <= x[0] {
+        return y[0];
+    }
+    if at >= x[x.len() - 1] {
+        return y[y.len() - 1];
+    }
+    let n = x.partition_point(|v| *v <= at);
+    let t = (at - x[n - 1]) / (x[n] - x[n - 1]);
+    y[n - 1] + t * (y[n] - y[n - 1])
+}
+
+/// Least-squares line through `points` as (slope, intercept).
+fn linear_fit(points: &[(f64, f64)]) -> Result<(f64, f64)> {
+    let n = points.len() as f64;
+    let (sx, sy) = points
+        .iter()
+        .fold((0.0, 0.0), |(a, b), (x, y)| (a + x, b + y));
+    let (mx, my) = (sx / n, sy / n);
+    let (sxx, sxy) = points.iter().fold((0.0, 0.0), |(a, b), (x, y)| {
+        (a + (x - mx) * (x - mx), b + (x - mx) * (y - my))
+    });
+    if sxx == 0.0 {
+        return Err(Error::InvalidArgument(
+            "linear fit over a single abscissa".to_string(),
+        ));
+    }
+    let slope = sxy / sxx;
+    Ok((slope, my - slope * mx))
+}
+
+/// Minimum of a unimodal `f` on [`lo`, `hi`].
+fn golden_section<F: Fn(f64) -> f64>(f: F, mut lo: f64, mut hi: f64) -> f64 {
+    let ratio = 0.5 * (5f64.sqrt() - 1.0);
+    let mut a = hi - ratio * (hi - lo);
+    let mut b = lo + ratio * (hi - lo);
+    let (mut fa, mut fb) = (f(a), f(b));
+    for _ in 0..100 {
+        if fa < fb {
+            hi = b;
+            b = a;
+            fb = fa;
+            a = hi - ratio * (hi - lo);
+            fa = f(a);
+        } else {
+            lo = a;
+            a = b;
+            fa = fb;
+            b = lo + ratio * (hi - lo);
+            fb = f(b);
+        }
+    }
+    0.5 * (lo + hi)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dose::commissioning::{
+        fit_pencil_beam, inside, Axis, BeamData, Measurement, MlcData, OutputFactors, PencilBeamFit,
+    };
+    use crate::outcome::normal_cdf;
+
+    fn depth_dose(depth: f64) -> f64 {
+        0.01 * (1.0 - (-0.3 * depth).exp()) * (-0.005 * depth).exp()
+    }
+
+    /// Scans of a model beam: 3 mm primary sigma, 10 % scatter of 30 mm.
+    fn data() -> BeamData {
+        let (sigma, w, scatter) = (3.0, 0.1, 30.0);
+        let mut data = BeamData::new("Linac", "6X", 1.0);
+        let field = [300.0, 300.0];
+        let points = (0..=60)
+            .map(|n| {
+                let depth = 5.0 * n as f64;
+                let z = (900.0 + depth) / 1000.0;
+                let lateral = (1.0 - w) * inside(300.0 * z, 300.0 * z, sigma)
+                    + w * inside(300.0 * z, 300.0 * z, scatter);
+                (depth, depth_dose(depth) * lateral / (z * z))
+            })
+            .collect::<Vec<_>>();
+        let max = points.iter().fold(0.0f64, |m, p| m.max(p.1));
+        data.calibration = max;
+        let pdd = points.iter().map(|(d, v)| (*d, 100.0 * v / max)).collect();
+        data.measurements
+            .push(Measurement::new(Axis::Depth, field, 900.0, None, pdd).unwrap());
+        let profile = (-400..=400)
+            .map(|n| {
+                let x = 0.5 * n as f64;
+                let edge = normal_cdf((x + 50.0) / sigma) - normal_cdf((x - 50.0) / sigma);
+                (x, 100.0 * edge)
+            })
+            .collect();
+        data.measurements.push(
+            Measurement::new(Axis::Crossline, [100.0, 100.0], 900.0, Some(100.0), profile).unwrap(),
+        );
+        let sides = vec![20.0, 50.0, 100.0, 200.0, 300.0];
+        let z = 1.0;
+        let lateral =
+            |s: f64| (1.0 - w) * inside(s * z, s * z, sigma) + w * inside(s * z, s * z, scatter);
+        data.output_factors = Some(OutputFactors {
+            depth: 100.0,
+            values: sides.iter().map(|s| lateral(*s) / lateral(100.0)).collect(),
+            sides,
+        });
+        data
+    }
+
+    #[test]
+    fn scan_analysis() {
+        let data = data();
+        let profile = data.profiles().next().unwrap();
+        assert!((profile.field_width().unwrap() - 100.0).abs() < 1e-3);
+        assert!((profile.penumbra().unwrap() - 2.0 * 0.8416 * 3.0).abs() < 0.01);
+        assert!((profile.at(0.25) - 0.5 * (profile.at(0.0) + profile.at(0.5))).abs() < 1e-12);
+        assert_eq!(data.broad_depth_dose().unwrap().field_size, [300.0, 300.0]);
+        let of = data.output_factors.as_ref().unwrap();
+        assert!((of.at(100.0) - 1.0).abs() < 1e-12);
+        assert!(Measurement::new(Axis::Depth, [1.0, 1.0], 1000.0, None, vec![(0.0, 1.0)]).is_err());
+    }
+
+    #[test]
+    fn pencil_beam_fit() {
+        let kernel = fit_pencil_beam(&data(), &PencilBeamFit::default()).unwrap();
+        for depth in [15.0, 50.0, 100.0, 200.0] {
+            let k = kernel.at(depth);
+            assert!((k.sigma_primary - 3.0).abs() < 0.01);
+            assert!(
+                (k.scatter_weight - 0.1).abs() < 1e-3,
+                "{}",
+                k.scatter_weight
+            );
+            let expected = depth_dose(depth);
+            assert!(
+                (k.dose - expected).abs() / expected < 1e-3,
+                "{} {}",
+                k.dose,
+                expected
+            );
+        }
+        assert!(fit_pencil_beam(
+            &BeamData::new("Linac", "6X", 1.0),
+            &PencilBeamFit::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn sweeping_gap() {
+        let (open, transmission, dlg) = (100.0, 0.015, 1.5);
+        let gaps: Vec<(f64, f64)> = [2.0, 4.0, 6.0, 10.0, 14.0, 20.0]
+            .iter()
+            .map(|g| (*g, transmission * open + 0.9 * (g + dlg)))
+            .collect();
+        let mlc = MlcData::from_sweeping_gaps(&gaps, open, transmission * open).unwrap();
+        assert!((mlc.transmission - transmission).abs() < 1e-12);
+        assert!((mlc.dlg - dlg).abs() < 1e-9);
+        assert!(MlcData::from_sweeping_gaps(&gaps[..1], open, 1.0).is_err());
+    }
+}
@@ -0,0 +1,580 @@
+//! Beamlet dose-influence matrices.
+//!
+//! Every beam is split into beamlets: square bixels of the fluence map for photon engines,
+//! the individual spots of an [`IonBeam`] for protons. The dose of each beamlet at unit
+//! weight is computed on its own and kept only in the voxels of the planning structures,
+//! dropping values below a fraction of the beamlet's maximum. The rows of the matrix are the
+//! union of the structure voxels in grid order and every structure maps to a subset of rows,
+//! so the dose of a structure is read without touching the rest of the matrix. Columns are
+//! stored compressed in a [`CscMatrix`] with `f32` values.
+
+use crate::dose::beam::{BeamGeometry, Fluence};
+use crate::dose::proton::{EnergyLayer, IonBeam, ProtonPencilBeam, Spot};
+use crate::dose::DoseEngine;
+use crate::error::{Error, Result};
+use crate::grid::{Grid3, GridGeometry};
+use crate::sparse::CscMatrix;
+
+/// A beamlet of beam `beam`, centered at (`x`, `y`) mm in the isocenter plane.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Beamlet {
+    pub beam: usize,
+    pub x: f64,
+    pub y: f64,
+    /// Nominal energy (MeV) of a proton spot, `None` for a photon bixel.
+    pub energy: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DijOptions {
+    /// Width (mm) of the square bixels in the isocenter plane; bixel edges lie on multiples of
+    /// the width.
+    pub bixel_size: f64,
+    /// Values below this fraction of a beamlet's maximum are dropped.
+    pub threshold: f64,
+}
+
+impl Default for DijOptions {
+    fn default() -> Self {
+        DijOptions {
+            bixel_size: 5.0,
+            threshold: 1e-3,
+        }
+    }
+}
+
+/// The bixels of beam `beam` covering the nonzero pixels of `field`.
+pub fn bixels(beam: usize, field: &Fluence, size: f64) -> Vec<Beamlet> {
+    let mut cells: Vec<(i64, i64)> = Vec::new();
+    for j in 0..field.dims[1] {
+        for i in 0..field.dims[0] {
+            if field.get(i, j) > 0.0 {
+                let (x, y) = field.position(i, j);
+                cells.push(((x / size).floor() as i64, (y / size).floor() as i64));
+            }
+        }
+    }
+    cells.sort_by_key(|&(m, n)| (n, m));
+    cells.dedup();
+    cells
+        .into_iter()
+        .map(|(m, n)| Beamlet {
+            beam,
+            x: (m as f64 + 0.5) * size,
+            y: (n as f64 + 0.5) * size,
+            energy: None,
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dij {
+    geometry: GridGeometry,
+    bixel_size: f64,
+    beamlets: Vec<Beamlet>,
+    /// Voxel offset in `geometry` of every row.
+    voxels: Vec<usize>,
+    structures: Vec<(String, Vec<u32>)>,
+    matrix: CscMatrix,
+}
+
+impl Dij {
+    /// Photon beamlets: `beams` pairs each beam with a field whose nonzero pixels are split
+    /// into bixels, and `engine` computes every bixel at unit fluence.
+    pub fn compute(
+        engine: &dyn DoseEngine,
+        beams: &[(BeamGeometry, Fluence)],
+        density: &Grid3<f64>,
+        structures: &[(&str, &Grid3<bool>)],
+        options: &DijOptions,
+    ) -> Result<Dij> {
+        if options.bixel_size <= 0.0 {
+            return Err(Error::InvalidArgument(format!(
+                "non-positive bixel size {} mm",
+                options.bixel_size
+            )));
+        }
+        let beamlets: Vec<Beamlet> = beams
+            .iter()
+            .enumerate()
+            .flat_map(|(n, (_, field))| bixels(n, field, options.bixel_size))
+            .collect();
+        let spacing = beams
+            .iter()
+            .map(|(_, field)| field.spacing[0].min(field.spacing[1]))
+            .fold(options.bixel_size, f64::min);
+        let half = 0.5 * options.bixel_size;
+        Dij::assemble(density, structures, options, beamlets, |beamlet| {
+            let fluence = Fluence::rectangle(
+                [beamlet.x - half, beamlet.x + half],
+                [beamlet.y - half, beamlet.y + half],
+                spacing,
+                1.0,
+            )?;
+            engine.calculate(&beams[beamlet.beam].0, &fluence, density)
+        })
+    }
+
+    /// Proton beamlets: every spot of `beams` computed at unit weight.
+    pub fn compute_spots(
+        engine: &ProtonPencilBeam,
+        beams: &[IonBeam],
+        density: &Grid3<f64>,
+        structures: &[(&str, &Grid3<bool>)],
+        options: &DijOptions,
+    ) -> Result<Dij> {
+        let beamlets: Vec<Beamlet> = beams
+            .iter()
+            .enumerate()
+            .flat_map(|(n, beam)| {
+                beam.layers.iter().flat_map(move |layer| {
+                    layer.spots.iter().map(move |spot| Beamlet {
+                        beam: n,
+                        x: spot.x,
+                        y: spot.y,
+                        energy: Some(layer.energy),
+                    })
+                })
+            })
+            .collect();
+        Dij::assemble(density, structures, options, beamlets, |beamlet| {
+            let beam = &beams[beamlet.beam];
+            let spot = IonBeam {
+                geometry: beam.geometry,
+                layers: vec![EnergyLayer {
+                    energy: beamlet.energy.unwrap_or_default(),
+                    spots: vec![Spot {
+                        x: beamlet.x,
+                        y: beamlet.y,
+                        weight: 1.0,
+                    }],
+                }],
+                range_shifter: beam.range_shifter,
+            };
+            engine.calculate(&spot, density)
+        })
+    }
+
+    fn assemble<F>(
+        density: &Grid3<f64>,
+        structures: &[(&str, &Grid3<bool>)],
+        options: &DijOptions,
+        beamlets: Vec<Beamlet>,
+        mut dose: F,
+    ) -> Result<Dij>
+    where
+        F: FnMut(&Beamlet) -> Result<Grid3<f64>>,
+    {
+        let geometry = density.geometry();
+        if let Some((name, _)) = structures
+            .iter()
+            .find(|(_, mask)| mask.geometry().dims != geometry.dims)
+        {
+            return Err(Error::InvalidArgument(format!(
+                "mask of '{}' is not on the dose grid",
+                name
+            )));
+        }
+        let mut row_of = vec![u32::MAX; geometry.len()];
+        let mut voxels = Vec::new();
+        for (n, row) in row_of.iter_mut().enumerate() {
+            if structures.iter().any(|(_, mask)| mask.data()[n]) {
+                *row = voxels.len() as u32;
+                voxels.push(n);
+            }
+        }
+        let structures = structures
+            .iter()
+            .map(|(name, mask)| {
+                let rows = (0..geometry.len())
+                    .filter(|n| mask.data()[*n])
+                    .map(|n| row_of[n])
+                    .collect();
+                (name.to_string(), rows)
+            })
+            .collect();
+
+        let mut columns = vec![0];
+        let mut rows = Vec::new();
+        let mut values = Vec::new();
+        for beamlet in &beamlets {
+            let grid = dose(beamlet)?;
+            let max = voxels.iter().map(|n| grid.data()[*n]).fold(0.0, f64::max);
+            let cut = options.threshold * max;
+            for (row, n) in voxels.iter().enumerate() {
+                let d = grid.data()[*n];
+                if d > 0.0 && d >= cut {
+                    rows.push(row as u32);
+                    values.push(d as f32);
+                }
+            }
+            columns.push(rows.len());
+        }
+        let matrix = CscMatrix::new(voxels.len(), beamlets.len(), columns, rows, values)?;
+        Ok(Dij {
+            geometry: geometry.clone(),
+            bixel_size: options.bixel_size,
+            beamlets,
+            voxels,
+            structures,
+            matrix,
+        })
+    }
+
+    /// A matrix from precomputed beamlet doses: `columns` holds the (row, dose) pairs of every
+    /// beamlet, rows indexing `voxels` (offsets in `geometry`), and `structures` the rows of
+    /// every structure.
+    pub fn from_columns(
+        geometry: GridGeometry,
+        bixel_size: f64,
+        beamlets: Vec<Beamlet>,
+        voxels: Vec<usize>,
+        structures: Vec<(String, Vec<u32>)>,
+        columns: Vec<Vec<(u32, f32)>>,
+    ) -> Result<Dij> {
+        if columns.len() != beamlets.len() {
+            return Err(Error::InvalidArgument(format!(
+                "{} columns for {} beamlets",
+                columns.len(),
+                beamlets.len()
+            )));
+        }
+        let n = voxels.len();
+        let out_of_range = |row: u32| row as usize >= n;
+        if voxels.iter().any(|v| *v >= geometry.len())
+            || structures
+                .iter()
+                .any(|(_, r)| r.iter().any(|r| out_of_range(*r)))
+            || columns.iter().flatten().any(|(r, _)| out_of_range(*r))
+        {
+            return Err(Error::InvalidArgument(
+                "dose-influence matrix entry outside its rows or grid".to_string(),
+            ));
+        }
+        let mut offsets = vec![0];
+        let (mut rows, mut values) = (Vec::new(), Vec::new());
+        for column in columns {
+            for (row, value) in column {
+                rows.push(row);
+                values.push(value);
+            }
+            offsets.push(rows.len());
+        }
+        let matrix = CscMatrix::new(n, beamlets.len(), offsets, rows, values)?;
+        Ok(Dij {
+            geometry,
+            bixel_size,
+            beamlets,
+            voxels,
+            structures,
+            matrix,
+        })
+    }
+
+    pub fn geometry(&self) -> &GridGeometry {
+        &self.geometry
+    }
+
+    /// mm
+    pub fn bixel_size(&self) -> f64 {
+        self.bixel_size
+    }
+
+    pub fn beamlets(&self) -> &[Beamlet] {
+        &self.beamlets
+    }
+
+    pub fn voxels(&self) -> &[usize] {
+        &self.voxels
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.voxels.len()
+    }
+
+    pub fn column_count(&self) -> usize {
+        self.beamlets.len()
+    }
+
+    /// Number of stored values.
+    pub fn nnz(&self) -> usize {
+        self.matrix.nnz()
+    }
+
+    /// Dose per row (Gy) of every beamlet at unit weight.
+    pub fn matrix(&self) -> &CscMatrix {
+        &self.matrix
+    }
+
+    /// Rows of structure `name`.
+    pub fn structure(&self, name: &str) -> Option<&[u32]> {
+        self.structures
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, rows)| rows.as_slice())
+    }
+
+    pub fn structure_names(&self) -> impl Iterator<Item = &str> {
+        self.structures.iter().map(|(n, _)| n.as_str())
+    }
+
+    /// Rows and values of beamlet `column`.
+    pub fn column(&self, column: usize) -> (&[u32], &[f32]) {
+        self.matrix.col(column)
+    }
+
+    fn check_weights(&self, weights: &[f64]) -> Result<()> {
+        if weights.len() != self.column_count() {
+            return Err(Error::InvalidArgument(format!(
+                "{} weights for {} beamlets",
+                weights.len(),
+                self.column_count()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Dose per row for beamlet `weights`.
+    pub fn dose(&self, weights: &[f64]) -> Result<Vec<f64>> {
+        self.check_weights(weights)?;
+        self.matrix.mul_vec(weights, 0)
+    }
+
+    /// The transpose product: per beamlet, the sum over rows of `row_values` times the
+    /// beamlet's dose, which is the gradient of an objective with respect to the weights.
+    pub fn transpose_dose(&self, row_values: &[f64]) -> Result<Vec<f64>> {
+        if row_values.len() != self.row_count() {
+            return Err(Error::InvalidArgument(format!(
+                "{} values for {} rows",
+                row_values.len(),
+                self.row_count()
+            )));
+        }
+        self.matrix.transpose_mul_vec(row_values, 0)
+    }
+
+    /// Dose on the full grid for beamlet `weights`; voxels outside every structure are 0.
+    pub fn dose_grid(&self, weights: &[f64]) -> Result<Grid3<f64>> {
+        let dose = self.dose(weights)?;
+        let mut grid = Grid3::new(self.geometry.clone(), 0.0);
+        for (n, d) in self.voxels.iter().zip(dose) {
+            grid.data_mut()[*n] = d;
+        }
+        Ok(grid)
+    }
+
+    /// The fluence map of photon beam `beam` with one pixel per bixel holding its weight.
+    pub fn fluence(&self, beam: usize, weights: &[f64]) -> Result<Fluence> {
+        self.check_weights(weights)?;
+        let bixels: Vec<(&Beamlet, f64)> = self
+            .beamlets
+            .iter()
+            .zip(weights)
+            .filter(|(b, _)| b.beam == beam && b.energy.is_none())
+            .map(|(b, w)| (b, *w))
+            .collect();
+        if bixels.is_empty() {
+            return Err(Error::InvalidArgument(format!(
+                "no bixels for beam {}",
+                beam
+            )));
+        }
+        let size = self.bixel_size;
+        let cell = |v: f64| (v / size).floor() as i64;
+        let (mut lo, mut hi) = ([i64::MAX; 2], [i64::MIN; 2]);
+        for (b, _) in &bixels {
+            let c = [cell(b.x), cell(b.y)];
+            for a in 0..2 {
+                lo[a] = lo[a].min(c[a]);
+                hi[a] = hi[a].max(c[a]);
+            }
+        }
+        let mut fluence = Fluence::new(
+            [(lo[0] as f64 + 0.5) * size, (lo[1] as f64 + 0.5) * size],
+            [size, size],
+            [(hi[0] - lo[0] + 1) as usize, (hi[1] - lo[1] + 1) as usize],
+            0.0,
+        );
+        for (b, w) in bixels {
+            let i = (cell(b.x) - lo[0]) as usize;
+            let j = (cell(b.y) - lo[1]) as usize;
+            fluence.set(i, j, w);
+        }
+        Ok(fluence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::beam::{BeamGeometry, Fluence};
+    use crate::dose::dij::{bixels, Dij, DijOptions};
+    use crate::dose::pencil_beam::{KernelSample, PencilBeam, PencilBeamKernel};
+    use crate::dose::proton::{
+        EnergyLayer, IonBeam, ProtonBeamData, ProtonEnergyData, ProtonPencilBeam, Spot,
+    };
+    use crate::dose::DoseEngine;
+    use crate::grid::{Grid3, GridGeometry};
+
+    fn engine() -> PencilBeam {
+        PencilBeam::new(
+            PencilBeamKernel::new(
+                (0..=60)
+                    .map(|n| {
+                        let depth = 5.0 * n as f64;
+                        KernelSample {
+                            depth,
+                            dose: 0.01 * (1.0 - (-0.3 * depth).exp()) * (-0.005 * depth).exp(),
+                            sigma_primary: 2.0 + 0.01 * depth,
+                            sigma_scatter: 15.0 + 0.05 * depth,
+                            scatter_weight: 0.1,
+                        }
+                    })
+                    .collect(),
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn dij_photon() {
+        let geometry = GridGeometry::new(
+            [21, 20, 3],
+            Vec3::from(-40.0, -38.0, -4.0),
+            Vec3::from(4.0, 4.0, 4.0),
+        );
+        let density = Grid3::new(geometry.clone(), 1.0);
+        let mut target = Grid3::new(geometry.clone(), false);
+        let mut body = Grid3::new(geometry, false);
+        for k in 0..3 {
+            for j in 0..20 {
+                for i in 0..21 {
+                    body.set(i, j, k, true);
+                    if (8..13).contains(&i) && (8..13).contains(&j) {
+                        target.set(i, j, k, true);
+                    }
+                }
+            }
+        }
+        let beam = BeamGeometry::new(Vec3::new(), 0.0, 1000.0);
+        let field = Fluence::rectangle([-20.0, 20.0], [-20.0, 20.0], 2.0, 1.0).unwrap();
+        assert_eq!(bixels(0, &field, 10.0).len(), 16);
+
+        let options = DijOptions {
+            bixel_size: 10.0,
+            threshold: 0.0,
+        };
+        let structures = [("PTV", &target), ("Body", &body)];
+        let engine = engine();
+        let dij = Dij::compute(
+            &engine,
+            &[(beam, field.clone())],
+            &density,
+            &structures,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(dij.column_count(), 16);
+        assert_eq!(dij.row_count(), 21 * 20 * 3);
+        assert_eq!(dij.structure("PTV").unwrap().len(), 75);
+
+        // Unit weights add up to the open field.
+        let weights = vec![1.0; 16];
+        let open = engine.calculate(&beam, &field, &density).unwrap();
+        let summed = dij.dose_grid(&weights).unwrap();
+        for n in [geometry_offset(10, 10, 1), geometry_offset(3, 15, 1)] {
+            let (a, b) = (summed.data()[n], open.data()[n]);
+            assert!((a - b).abs() < 1e-5 * open.data()[geometry_offset(10, 10, 1)]);
+        }
+        let fluence = dij.fluence(0, &weights).unwrap();
+        assert_eq!(fluence.dims, [4, 4]);
+        assert_eq!(fluence.at(15.0, -15.0), 1.0);
+
+        // The transpose product matches the dose of every single beamlet.
+        let ones = vec![1.0; dij.row_count()];
+        let gradient = dij.transpose_dose(&ones).unwrap();
+        let mut single = vec![0.0; 16];
+        single[5] = 1.0;
+        let total: f64 = dij.dose(&single).unwrap().iter().sum();
+        assert!((gradient[5] - total).abs() < 1e-9 * total);
+
+        let truncated = Dij::compute(
+            &engine,
+            &[(beam, field)],
+            &density,
+            &[("PTV", &target)],
+            &DijOptions {
+                bixel_size: 10.0,
+                threshold: 0.05,
+            },
+        )
+        .unwrap();
+        assert!(truncated.nnz() < 16 * truncated.row_count());
+        assert!(dij.dose(&[1.0]).is_err());
+    }
+
+    #[test]
+    fn dij_spots() {
+        let depth: Vec<f64> = (0..=110).map(|d| d as f64).collect();
+        let data = ProtonBeamData::new(vec![ProtonEnergyData {
+            energy: 120.0,
+            idd: depth.iter().map(|d| 1.0 + 0.01 * d).collect(),
+            sigma: depth.iter().map(|d| 0.02 * d).collect(),
+            depth,
+            spot_sigma: 4.0,
+            halo_weight: 0.0,
+            halo_sigma: 10.0,
+        }])
+        .unwrap();
+        let engine = ProtonPencilBeam::new(data);
+        let geometry = GridGeometry::new(
+            [15, 40, 1],
+            Vec3::from(-14.0, -49.0, 0.0),
+            Vec3::from(2.0, 2.0, 2.0),
+        );
+        let density = Grid3::new(geometry.clone(), 1.0);
+        let all = Grid3::new(geometry, true);
+        let spot = |x: f64, weight: f64| Spot { x, y: 0.0, weight };
+        let beam = IonBeam {
+            geometry: BeamGeometry::new(Vec3::new(), 0.0, 1e6),
+            layers: vec![EnergyLayer {
+                energy: 120.0,
+                spots: vec![spot(-5.0, 1.0), spot(5.0, 1.0)],
+            }],
+            range_shifter: None,
+        };
+        let options = DijOptions {
+            threshold: 0.0,
+            ..DijOptions::default()
+        };
+        let dij = Dij::compute_spots(
+            &engine,
+            std::slice::from_ref(&beam),
+            &density,
+            &[("All", &all)],
+            &options,
+        )
+        .unwrap();
+        assert_eq!(dij.column_count(), 2);
+        assert_eq!(dij.beamlets()[1].energy, Some(120.0));
+
+        let weighted = IonBeam {
+            layers: vec![EnergyLayer {
+                energy: 120.0,
+                spots: vec![spot(-5.0, 2.0), spot(5.0, 0.5)],
+            }],
+            ..beam
+        };
+        let direct = engine.calculate(&weighted, &density).unwrap();
+        let dose = dij.dose_grid(&[2.0, 0.5]).unwrap();
+        for n in [0, 7 + 15 * 20, 3 + 15 * 39] {
+            let d = direct.data()[n];
+            assert!((dose.data()[n] - d).abs() < 1e-6 * d);
+        }
+        assert!(dij.fluence(0, &[2.0, 0.5]).is_err());
+    }
+
+    fn geometry_offset(i: usize, j: usize, k: usize) -> usize {
+        i + 21 * (j + 20 * k)
+    }
+}
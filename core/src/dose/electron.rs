@@ -0,0 +1,191 @@
+//! Electron pencil-beam dose calculation (Hogstrom).
+//!
+//! The fluence map is the aperture of the cutout projected to the isocenter plane, see
+//! [`crate::dose::beam::Fluence::polygon`]. Every point takes the broad-field central-axis
+//! dose at its water-equivalent depth times the aperture convolved with a Gaussian whose
+//! width combines the in-air spread of the applicator with the multiple scattering
+//! commissioned per depth, and the inverse square law from the virtual source. The depth
+//! dose and sigma come from the same [`PencilBeamKernel`] tables as the photon pencil beam,
+//! with only the primary Gaussian used.
+
+use crate::dose::beam::{BeamGeometry, Fluence};
+use crate::dose::pencil_beam::{PencilBeamKernel, PencilBeamOptions};
+use crate::dose::{
+    check_beam, finish, unsupported, BeamInput, CalculationOptions, DoseEngine, DoseResult,
+};
+use crate::error::{Error, Result};
+use crate::grid::{per_voxel_with, Grid3};
+use crate::progress::Progress;
+use crate::raytrace::{wepl, Source};
+use std::time::Instant;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ElectronPencilBeam {
+    /// Measured central-axis depth dose of a broad field (Gy per unit fluence, inverse
+    /// square removed) and the multiple scattering sigma per depth.
+    pub kernel: PencilBeamKernel,
+    /// Distance (mm) from the virtual source to the isocenter.
+    pub virtual_sad: f64,
+    /// Penumbra of the applicator in air at the isocenter (mm, one sigma).
+    pub air_sigma: f64,
+    pub options: PencilBeamOptions,
+}
+
+impl ElectronPencilBeam {
+    pub fn new(kernel: PencilBeamKernel, virtual_sad: f64, air_sigma: f64) -> Self {
+        ElectronPencilBeam {
+            kernel,
+            virtual_sad,
+            air_sigma,
+            options: PencilBeamOptions::default(),
+        }
+    }
+
+    fn dose(
+        &self,
+        beam: &BeamGeometry,
+        fluence: &Fluence,
+        density: &Grid3<f64>,
+        threads: usize,
+        progress: &Progress,
+    ) -> Result<Grid3<f64>> {
+        check_beam(beam, fluence)?;
+        if self.virtual_sad <= 0.0 {
+            return Err(Error::InvalidArgument(format!(
+                "non-positive virtual source distance {} mm",
+                self.virtual_sad
+            )));
+        }
+        let depth = wepl(density, Source::Point(beam.source()), threads);
+        let geometry = density.geometry();
+        let values = per_voxel_with(geometry, threads, progress, |n| {
+            let [i, j, k] = geometry.ijk(n);
+            let (x, y, z) = match beam.project(geometry.position(i, j, k)) {
+                Some(projection) => projection,
+                None => return 0.0,
+            };
+            let distance = z - beam.sad + self.virtual_sad;
+            if distance <= 0.0 {
+                return 0.0;
+            }
+            let kernel = self.kernel.at(depth.data()[n]);
+            // Both spreads in the isocenter plane.
+            let scale = beam.sad / z;
+            let sigma = (kernel.sigma_primary * kernel.sigma_primary * scale * scale
+                + self.air_sigma * self.air_sigma)
+                .sqrt();
+            let lateral = fluence.convolve_gaussian(x, y, sigma, self.options.cutoff);
+            let inverse_square = (self.virtual_sad / distance).powi(2);
+            kernel.dose * lateral * inverse_square
+        })?;
+        Grid3::from_vec(geometry.clone(), values)
+    }
+}
+
+impl DoseEngine for ElectronPencilBeam {
+    fn name(&self) -> &str {
+        "electron_pencil_beam"
+    }
+
+    fn supports(&self, beam: &BeamInput<'_>) -> bool {
+        matches!(beam, BeamInput::Fluence(..))
+    }
+
+    fn compute(
+        &self,
+        beam: BeamInput<'_>,
+        density: &Grid3<f64>,
+        options: &CalculationOptions,
+    ) -> Result<DoseResult> {
+        let start = Instant::now();
+        let (geometry, fluence) = match beam {
+            BeamInput::Fluence(geometry, fluence) => (geometry, fluence),
+            _ => return Err(unsupported(self.name(), &beam)),
+        };
+        let threads = options.threads_or(self.options.threads);
+        let dose = self.dose(geometry, fluence, density, threads, &options.progress)?;
+        Ok(finish(self.name(), start, dose, None, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::beam::{BeamGeometry, Fluence};
+    use crate::dose::electron::ElectronPencilBeam;
+    use crate::dose::pencil_beam::{KernelSample, PencilBeamKernel};
+    use crate::dose::DoseEngine;
+    use crate::grid::{Grid3, GridGeometry};
+
+    /// A 9 MeV-like depth dose with its practical range near 45 mm.
+    fn kernel() -> PencilBeamKernel {
+        PencilBeamKernel::new(
+            (0..=30)
+                .map(|n| {
+                    let depth = 2.0 * n as f64;
+                    let dose = if depth < 25.0 {
+                        0.8 + 0.008 * depth
+                    } else {
+                        (1.0 - (depth - 25.0) / 20.0).max(0.0)
+                    };
+                    KernelSample {
+                        depth,
+                        dose,
+                        sigma_primary: 0.15 * depth,
+                        sigma_scatter: 0.0,
+                        scatter_weight: 0.0,
+                    }
+                })
+                .collect(),
+        )
+        .unwrap()
+    }
+
+    /// Water from the isocenter plane down along +y, 1 mm voxels around the central axis.
+    fn phantom() -> Grid3<f64> {
+        let geometry = GridGeometry::new(
+            [61, 50, 1],
+            Vec3::from(-30.0, 0.5, 0.0),
+            Vec3::from(1.0, 1.0, 1.0),
+        );
+        Grid3::new(geometry, 1.0)
+    }
+
+    fn circle(radius: f64) -> Fluence {
+        let points: Vec<(f64, f64)> = (0..72)
+            .map(|n| {
+                let a = n as f64 * std::f64::consts::PI / 36.0;
+                (radius * a.cos(), radius * a.sin())
+            })
+            .collect();
+        Fluence::polygon(&points, 0.5, 1.0).unwrap()
+    }
+
+    #[test]
+    fn electron_cutout() {
+        let engine = ElectronPencilBeam::new(kernel(), 900.0, 1.0);
+        let beam = BeamGeometry::new(Vec3::new(), 0.0, 1000.0);
+        let density = phantom();
+        let broad = engine.calculate(&beam, &circle(50.0), &density).unwrap();
+        // Broad field: the depth dose with the virtual source inverse square law.
+        let expected = engine.kernel.at(9.5).dose * (900.0 / 909.5f64).powi(2);
+        assert!((broad[[30, 9, 0]] - expected).abs() / expected < 1e-3);
+        assert_eq!(broad[[30, 49, 0]], 0.0);
+
+        // The penumbra widens with depth.
+        let width = |dose: &Grid3<f64>, j: usize| {
+            let center = dose[[30, j, 0]];
+            (0..61)
+                .filter(|i| {
+                    let d = dose[[*i, j, 0]];
+                    d > 0.2 * center && d < 0.8 * center
+                })
+                .count()
+        };
+        let small = engine.calculate(&beam, &circle(15.0), &density).unwrap();
+        assert!(width(&small, 20) > width(&small, 2));
+        // A small cutout loses lateral scatter equilibrium at depth.
+        assert!(small[[30, 25, 0]] < broad[[30, 25, 0]]);
+        assert!((small[[30, 2, 0]] - broad[[30, 2, 0]]).abs() / broad[[30, 2, 0]] < 1e-3);
+    }
+}
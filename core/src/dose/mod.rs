@@ -0,0 +1,332 @@
+//! Dose calculation.
+//!
+//! Doses are computed on the grid of a relative electron density volume (water is 1; the
+//! stopping power ratio for protons), which also serves as the dose grid. Photon beams are
+//! described by [`beam::BeamGeometry`] and a [`beam::Fluence`] map in the isocenter plane,
+//! scanned ion beams by [`proton::IonBeam`]. Every external beam algorithm implements
+//! [`DoseEngine`], so applications pick one by name from an [`EngineRegistry`].
+
+pub mod beam;
+pub mod brachy;
+pub mod collapsed_cone;
+pub mod commissioning;
+pub mod delivery;
+pub mod dij;
+pub mod electron;
+pub mod monte_carlo;
+pub mod mu_check;
+pub mod pencil_beam;
+pub mod portal;
+pub mod proton;
+
+use crate::dose::beam::{BeamGeometry, Fluence};
+use crate::dose::proton::IonBeam;
+use crate::error::{Error, Result};
+use crate::grid::Grid3;
+use crate::progress::Progress;
+use std::time::{Duration, Instant};
+
+/// The beam handed to a [`DoseEngine`].
+#[derive(Debug, Clone, Copy)]
+pub enum BeamInput<'a> {
+    /// A photon or electron beam and its fluence map in the isocenter plane.
+    Fluence(&'a BeamGeometry, &'a Fluence),
+    /// A scanned ion beam.
+    Ion(&'a IonBeam),
+}
+
+impl BeamInput<'_> {
+    fn kind(&self) -> &'static str {
+        match self {
+            BeamInput::Fluence(..) => "fluence beams",
+            BeamInput::Ion(_) => "ion beams",
+        }
+    }
+}
+
+/// Settings shared by all engines; the algorithm settings stay with each engine.
+#[derive(Debug, Clone, Default)]
+pub struct CalculationOptions {
+    /// Worker threads instead of those of the engine; 0 uses the available parallelism.
+    pub threads: Option<usize>,
+    /// Reports the completed fraction and cancels the calculation.
+    pub progress: Progress,
+}
+
+impl CalculationOptions {
+    /// The threads to use for an engine configured with `threads`.
+    pub fn threads_or(&self, threads: usize) -> usize {
+        self.threads.unwrap_or(threads)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoseMetadata {
+    pub engine: String,
+    /// Simulated histories of stochastic engines.
+    pub histories: Option<usize>,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct DoseResult {
+    /// Gy
+    pub dose: Grid3<f64>,
+    /// Standard error of the dose (Gy) of stochastic engines.
+    pub uncertainty: Option<Grid3<f64>>,
+    pub metadata: DoseMetadata,
+}
+
+/// A dose calculation algorithm for external beams.
+pub trait DoseEngine {
+    /// Registry name, e.g. `pencil_beam`.
+    fn name(&self) -> &str;
+
+    fn supports(&self, beam: &BeamInput<'_>) -> bool;
+
+    /// Dose of `beam` on the grid of `density`.
+    fn compute(
+        &self,
+        beam: BeamInput<'_>,
+        density: &Grid3<f64>,
+        options: &CalculationOptions,
+    ) -> Result<DoseResult>;
+
+    /// Dose (Gy) of `fluence` delivered with `beam` on the grid of `density`, with the
+    /// engine's own settings.
+    fn calculate(
+        &self,
+        beam: &BeamGeometry,
+        fluence: &Fluence,
+        density: &Grid3<f64>,
+    ) -> Result<Grid3<f64>> {
+        let input = BeamInput::Fluence(beam, fluence);
+        Ok(self
+            .compute(input, density, &CalculationOptions::default())?
+            .dose)
+    }
+}
+
+/// The error of an engine handed a beam it cannot compute.
+pub(crate) fn unsupported(engine: &str, beam: &BeamInput<'_>) -> Error {
+    Error::Unsupported(format!("{} does not compute {}", engine, beam.kind()))
+}
+
+/// Wraps the output of an engine started at `start`.
+pub(crate) fn finish(
+    engine: &str,
+    start: Instant,
+    dose: Grid3<f64>,
+    uncertainty: Option<Grid3<f64>>,
+    histories: Option<usize>,
+) -> DoseResult {
+    DoseResult {
+        dose,
+        uncertainty,
+        metadata: DoseMetadata {
+            engine: engine.to_string(),
+            histories,
+            elapsed: start.elapsed(),
+        },
+    }
+}
+
+/// Engines by case-insensitive name, in registration order.
+#[derive(Default)]
+pub struct EngineRegistry {
+    engines: Vec<Box<dyn DoseEngine>>,
+}
+
+impl EngineRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `engine`, replacing one of the same name.
+    pub fn register(&mut self, engine: Box<dyn DoseEngine>) {
+        let name = engine.name().to_lowercase();
+        self.engines.retain(|e| e.name().to_lowercase() != name);
+        self.engines.push(engine);
+    }
+
+    pub fn get(&self, name: &str) -> Result<&dyn DoseEngine> {
+        let name = name.to_lowercase();
+        self.engines
+            .iter()
+            .find(|e| e.name().to_lowercase() == name)
+            .map(|e| e.as_ref())
+            .ok_or_else(|| Error::InvalidArgument(format!("unknown dose engine {}", name)))
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.engines.iter().map(|e| e.name())
+    }
+
+    /// The first registered engine that computes `beam`.
+    pub fn for_beam(&self, beam: &BeamInput<'_>) -> Option<&dyn DoseEngine> {
+        self.engines
+            .iter()
+            .find(|e| e.supports(beam))
+            .map(|e| e.as_ref())
+    }
+
+    /// Dose of `beam` with the engine called `name`.
+    pub fn compute(
+        &self,
+        name: &str,
+        beam: BeamInput<'_>,
+        density: &Grid3<f64>,
+        options: &CalculationOptions,
+    ) -> Result<DoseResult> {
+        let engine = self.get(name)?;
+        if !engine.supports(&beam) {
+            return Err(unsupported(engine.name(), &beam));
+        }
+        engine.compute(beam, density, options)
+    }
+}
+
+/// Rejects beams and fluence maps no engine can compute.
+pub(crate) fn check_beam(beam: &BeamGeometry, fluence: &Fluence) -> Result<()> {
+    if fluence.values.len() != fluence.dims[0] * fluence.dims[1] {
+        return Err(Error::InvalidArgument(format!(
+            "fluence holds {} values for {:?} pixels",
+            fluence.values.len(),
+            fluence.dims
+        )));
+    }
+    if beam.sad <= 0.0 {
+        return Err(Error::InvalidArgument(format!(
+            "non-positive source to axis distance {} mm",
+            beam.sad
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::beam::{BeamGeometry, Fluence};
+    use crate::dose::pencil_beam::{KernelSample, PencilBeam, PencilBeamKernel};
+    use crate::dose::proton::{
+        EnergyLayer, IonBeam, ProtonBeamData, ProtonEnergyData, ProtonPencilBeam, Spot,
+    };
+    use crate::dose::{BeamInput, CalculationOptions, EngineRegistry};
+    use crate::error::Error;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::progress::{CancellationToken, Progress};
+
+    fn registry() -> EngineRegistry {
+        let kernel = PencilBeamKernel::new(vec![KernelSample {
+            depth: 0.0,
+            dose: 0.01,
+            sigma_primary: 2.0,
+            sigma_scatter: 10.0,
+            scatter_weight: 0.1,
+        }])
+        .unwrap();
+        let data = ProtonBeamData::new(vec![ProtonEnergyData {
+            energy: 100.0,
+            depth: vec![0.0, 80.0],
+            idd: vec![1.0, 1.0],
+            sigma: vec![0.0, 1.0],
+            spot_sigma: 4.0,
+            halo_weight: 0.0,
+            halo_sigma: 10.0,
+        }])
+        .unwrap();
+        let mut registry = EngineRegistry::new();
+        registry.register(Box::new(PencilBeam::new(kernel)));
+        registry.register(Box::new(ProtonPencilBeam::new(data)));
+        registry
+    }
+
+    #[test]
+    fn engine_registry() {
+        let registry = registry();
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            vec!["pencil_beam", "proton_pencil_beam"]
+        );
+        let density = Grid3::new(
+            GridGeometry::new(
+                [5, 5, 5],
+                Vec3::from(-8.0, -8.0, -8.0),
+                Vec3::from(4.0, 4.0, 4.0),
+            ),
+            1.0,
+        );
+        let beam = BeamGeometry::new(Vec3::new(), 0.0, 1000.0);
+        let fluence = Fluence::rectangle([-20.0, 20.0], [-20.0, 20.0], 2.0, 1.0).unwrap();
+        let photons = BeamInput::Fluence(&beam, &fluence);
+        let options = CalculationOptions {
+            threads: Some(1),
+            ..Default::default()
+        };
+        let result = registry
+            .compute("Pencil_Beam", photons, &density, &options)
+            .unwrap();
+        assert_eq!(result.metadata.engine, "pencil_beam");
+        assert!(result.uncertainty.is_none());
+        let engine = registry.get("pencil_beam").unwrap();
+        let direct = engine.calculate(&beam, &fluence, &density).unwrap();
+        assert_eq!(direct.data(), result.dose.data());
+
+        let ion = IonBeam {
+            geometry: beam,
+            layers: vec![EnergyLayer {
+                energy: 100.0,
+                spots: vec![Spot {
+                    x: 0.0,
+                    y: 0.0,
+                    weight: 1.0,
+                }],
+            }],
+            range_shifter: None,
+        };
+        let protons = BeamInput::Ion(&ion);
+        assert_eq!(
+            registry.for_beam(&protons).unwrap().name(),
+            "proton_pencil_beam"
+        );
+        assert!(registry
+            .compute("pencil_beam", protons, &density, &options)
+            .is_err());
+        assert!(registry.get("collapsed_cone").is_err());
+        let dose = registry
+            .compute("proton_pencil_beam", protons, &density, &options)
+            .unwrap()
+            .dose;
+        assert!(dose[[2, 2, 2]] > 0.0);
+    }
+
+    #[test]
+    fn engine_registry_cancelled() {
+        let density = Grid3::new(
+            GridGeometry::new(
+                [5, 5, 5],
+                Vec3::from(-8.0, -8.0, -8.0),
+                Vec3::from(4.0, 4.0, 4.0),
+            ),
+            1.0,
+        );
+        let beam = BeamGeometry::new(Vec3::new(), 0.0, 1000.0);
+        let fluence = Fluence::rectangle([-20.0, 20.0], [-20.0, 20.0], 2.0, 1.0).unwrap();
+        let token = CancellationToken::new();
+        token.cancel();
+        let options = CalculationOptions {
+            threads: Some(1),
+            progress: Progress::new().with_token(token),
+        };
+        assert!(matches!(
+            registry().compute(
+                "pencil_beam",
+                BeamInput::Fluence(&beam, &fluence),
+                &density,
+                &options
+            ),
+            Err(Error::Cancelled)
+        ));
+    }
+}
@@ -0,0 +1,642 @@
+//! Voxel Monte Carlo photon transport.
+//!
+//! Photons are tracked with Woodcock (delta) tracking through the density volume, which
+//! doubles as mass density (g/cm³), using approximate water cross sections scaled by the
+//! local density: Klein-Nishina Compton scattering, photoelectric absorption and pair
+//! production. Charged particles are not transported (kerma approximation): the energy
+//! given to electrons and positrons is deposited in the voxel of the interaction, and pair
+//! production emits two back-to-back annihilation photons. Photons below the cutoff energy
+//! deposit their energy locally.
+//!
+//! Histories are run in independent batches, each with its own random stream, spread over
+//! threads. The dose is the mean over the batches and its uncertainty the standard error
+//! of that mean, so a run is reproducible for a given seed and batch count.
+
+use std::f64::consts::PI;
+
+use crate::affine::Affine3;
+use crate::coords::Vec3;
+use crate::dose::beam::{BeamGeometry, Fluence};
+use crate::dose::{
+    check_beam, finish, unsupported, BeamInput, CalculationOptions, DoseEngine, DoseResult,
+};
+use crate::error::{Error, Result};
+use crate::grid::{Grid3, GridGeometry};
+use crate::io::iaea::{Particle, ParticleType};
+use crate::progress::Progress;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// Electron rest energy (MeV).
+const ELECTRON_MASS: f64 = 0.510_998_95;
+/// Classical electron radius (cm).
+const ELECTRON_RADIUS: f64 = 2.817_940_326e-13;
+/// Electrons per gram of water.
+const WATER_ELECTRONS: f64 = 3.3428e23;
+/// Gy per MeV/g.
+const MEV_PER_GRAM: f64 = 1.602_176_634e-10;
+
+/// Pair production in water (MeV, cm²/g).
+const PAIR: [(f64, f64); 12] = [
+    (1.022, 0.0),
+    (1.25, 1.8e-5),
+    (1.5, 9.8e-5),
+    (2.0, 3.9e-4),
+    (3.0, 1.1e-3),
+    (4.0, 1.9e-3),
+    (5.0, 2.6e-3),
+    (6.0, 3.1e-3),
+    (8.0, 4.1e-3),
+    (10.0, 4.9e-3),
+    (15.0, 6.4e-3),
+    (20.0, 7.6e-3),
+];
+
+/// Mass attenuation coefficients of water (cm²/g) at `energy` (MeV).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attenuation {
+    pub compton: f64,
+    pub photoelectric: f64,
+    pub pair: f64,
+}
+
+impl Attenuation {
+    pub fn water(energy: f64) -> Self {
+        let k = energy / ELECTRON_MASS;
+        let l = (1.0 + 2.0 * k).ln();
+        let klein_nishina = 2.0
+            * PI
+            * ELECTRON_RADIUS
+            * ELECTRON_RADIUS
+            * ((1.0 + k) / (k * k) * (2.0 * (1.0 + k) / (1.0 + 2.0 * k) - l / k) + l / (2.0 * k)
+                - (1.0 + 3.0 * k) / ((1.0 + 2.0 * k) * (1.0 + 2.0 * k)));
+        let pair = match PAIR.iter().position(|(e, _)| *e > energy) {
+            Some(0) => 0.0,
+            Some(n) => {
+                let ((e0, m0), (e1, m1)) = (PAIR[n - 1], PAIR[n]);
+                m0 + (energy - e0) / (e1 - e0) * (m1 - m0)
+            }
+            None => PAIR[PAIR.len() - 1].1,
+        };
+        Attenuation {
+            compton: klein_nishina * WATER_ELECTRONS,
+            photoelectric: 2.8e-3 * (energy / 0.1).powi(-3),
+            pair,
+        }
+    }
+
+    pub fn total(&self) -> f64 {
+        self.compton + self.photoelectric + self.pair
+    }
+}
+
+/// Where the histories start.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MonteCarloSource {
+    /// Photons from the beam source spread uniformly over the fluence raster in the
+    /// isocenter plane, energies drawn from (MeV, relative weight) pairs.
+    Point { spectrum: Vec<(f64, f64)> },
+    /// Particles cycled from a phase space, positions (cm) and directions in beam
+    /// coordinates with `z` along the beam from the source. Charged particles are skipped.
+    PhaseSpace(Vec<Particle>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MonteCarloOptions {
+    pub histories: usize,
+    /// Independent batches for the uncertainty, at least 2.
+    pub batches: usize,
+    pub seed: u64,
+    /// Photons below this energy (MeV) are absorbed.
+    pub cutoff: f64,
+    /// Worker threads; 0 uses the available parallelism.
+    pub threads: usize,
+}
+
+impl Default for MonteCarloOptions {
+    fn default() -> Self {
+        MonteCarloOptions {
+            histories: 1_000_000,
+            batches: 10,
+            seed: 1,
+            cutoff: 0.01,
+            threads: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MonteCarloDose {
+    /// Gy
+    pub dose: Grid3<f64>,
+    /// Standard error of the dose (Gy).
+    pub uncertainty: Grid3<f64>,
+    pub histories: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MonteCarlo {
+    pub source: MonteCarloSource,
+    /// Source histories per unit fluence: photons per mm² of the isocenter plane for the
+    /// point source, phase-space particles for a phase space.
+    pub calibration: f64,
+    pub options: MonteCarloOptions,
+}
+
+/// xorshift64* seeded through SplitMix64.
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Random((z ^ (z >> 31)).max(1))
+    }
+
+    /// Uniform in (0, 1].
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let bits = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11;
+        (bits + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    fn isotropic(&mut self) -> Vec3<f64> {
+        let cos = 2.0 * self.next() - 1.0;
+        let phi = 2.0 * PI * self.next();
+        let sin = (1.0 - cos * cos).max(0.0).sqrt();
+        Vec3::from(sin * phi.cos(), sin * phi.sin(), cos)
+    }
+}
+
+/// `direction` turned by polar angle `acos(cos)` and azimuth `phi`.
+fn deflect(direction: Vec3<f64>, cos: f64, phi: f64) -> Vec3<f64> {
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let helper = if direction.x.abs() < 0.9 {
+        Vec3::from(1.0, 0.0, 0.0)
+    } else {
+        Vec3::from(0.0, 1.0, 0.0)
+    };
+    let u = direction.cross(helper).normalize();
+    let v = direction.cross(u);
+    (direction.scale(cos) + u.scale(sin * phi.cos()) + v.scale(sin * phi.sin())).normalize()
+}
+
+/// Scattered to incident energy ratio and cosine of a Klein-Nishina Compton scatter.
+fn compton(energy: f64, random: &mut Random) -> (f64, f64) {
+    let k = energy / ELECTRON_MASS;
+    let eps0 = 1.0 / (1.0 + 2.0 * k);
+    let (alpha1, alpha2) = (-eps0.ln(), 0.5 * (1.0 - eps0 * eps0));
+    loop {
+        let eps = if random.next() * (alpha1 + alpha2) < alpha1 {
+            (-alpha1 * random.next()).exp()
+        } else {
+            (eps0 * eps0 + (1.0 - eps0 * eps0) * random.next()).sqrt()
+        };
+        let one_minus_cos = (1.0 - eps) / (eps * k);
+        let sin2 = one_minus_cos * (2.0 - one_minus_cos);
+        if 1.0 - eps * sin2 / (1.0 + eps * eps) >= random.next() {
+            return (eps, 1.0 - one_minus_cos);
+        }
+    }
+}
+
+/// Distance (mm) along `direction` from `p` to where it enters the grid, 0 inside.
+fn entry(geometry: &GridGeometry, p: Vec3<f64>, direction: Vec3<f64>) -> Option<f64> {
+    let a = geometry.world_to_index().transform_point(p).to_array();
+    let d = geometry
+        .world_to_index()
+        .transform_vector(direction)
+        .to_array();
+    let (mut lo, mut hi) = (0.0f64, f64::INFINITY);
+    for axis in 0..3 {
+        let edges = (-0.5, geometry.dims[axis] as f64 - 0.5);
+        if d[axis] == 0.0 {
+            if a[axis] < edges.0 || a[axis] > edges.1 {
+                return None;
+            }
+            continue;
+        }
+        let t0 = (edges.0 - a[axis]) / d[axis];
+        let t1 = (edges.1 - a[axis]) / d[axis];
+        lo = lo.max(t0.min(t1));
+        hi = hi.min(t0.max(t1));
+    }
+    if hi > lo {
+        Some(lo)
+    } else {
+        None
+    }
+}
+
+struct Photon {
+    position: Vec3<f64>,
+    direction: Vec3<f64>,
+    energy: f64,
+    weight: f64,
+}
+
+/// Transports one batch and returns the energy (MeV) deposited per voxel.
+fn run_batch(
+    engine: &MonteCarlo,
+    beam: &BeamGeometry,
+    fluence: &Fluence,
+    density: &Grid3<f64>,
+    histories: usize,
+    random: &mut Random,
+) -> Vec<f64> {
+    let geometry = density.geometry();
+    let w2i = geometry.world_to_index();
+    let dims = geometry.dims;
+    let max_density = density.data().iter().cloned().fold(0.0, f64::max);
+    let [x_axis, y_axis, toward] = beam.axes();
+    let source = beam.source();
+    let spectrum_total = match &engine.source {
+        MonteCarloSource::Point { spectrum } => spectrum.iter().map(|(_, w)| w).sum(),
+        MonteCarloSource::PhaseSpace(_) => 0.0,
+    };
+    let mut energy = vec![0.0; density.len()];
+    let mut stack = Vec::new();
+    let mut next_particle = 0usize;
+    for _ in 0..histories {
+        match &engine.source {
+            MonteCarloSource::Point { spectrum } => {
+                let x = fluence.origin[0]
+                    + (random.next() * fluence.dims[0] as f64 - 0.5) * fluence.spacing[0];
+                let y = fluence.origin[1]
+                    + (random.next() * fluence.dims[1] as f64 - 0.5) * fluence.spacing[1];
+                let weight = fluence.at(x, y);
+                let mut pick = random.next() * spectrum_total;
+                let mut e = spectrum[spectrum.len() - 1].0;
+                for (value, w) in spectrum {
+                    if pick <= *w {
+                        e = *value;
+                        break;
+                    }
+                    pick -= w;
+                }
+                let target = beam.isocenter + x_axis.scale(x) + y_axis.scale(y);
+                stack.push(Photon {
+                    position: source,
+                    direction: (target - source).normalize(),
+                    energy: e,
+                    weight,
+                });
+            }
+            MonteCarloSource::PhaseSpace(particles) => {
+                let particle = &particles[next_particle % particles.len()];
+                next_particle += 1;
+                if particle.particle_type != ParticleType::Photon {
+                    continue;
+                }
+                let p = particle.position.scale(10.0);
+                let position = source + x_axis.scale(p.x) + y_axis.scale(p.y) - toward.scale(p.z);
+                let u = particle.direction;
+                let direction =
+                    (x_axis.scale(u.x) + y_axis.scale(u.y) - toward.scale(u.z)).normalize();
+                let weight = match beam.project(position) {
+                    Some((x, y, _)) => fluence.at(x, y) * particle.weight,
+                    None => 0.0,
+                };
+                stack.push(Photon {
+                    position,
+                    direction,
+                    energy: particle.energy,
+                    weight,
+                });
+            }
+        }
+        while let Some(mut photon) = stack.pop() {
+            if photon.weight == 0.0 || max_density <= 0.0 {
+                continue;
+            }
+            match entry(geometry, photon.position, photon.direction) {
+                Some(t) => photon.position += photon.direction.scale(t),
+                None => continue,
+            }
+            loop {
+                if photon.energy < engine.options.cutoff {
+                    if let Some(n) = voxel(&w2i, dims, photon.position) {
+                        energy[n] += photon.weight * photon.energy;
+                    }
+                    break;
+                }
+                let mu = Attenuation::water(photon.energy);
+                // mm per unit of the majorant.
+                let step = -random.next().ln() * 10.0 / (mu.total() * max_density);
+                photon.position += photon.direction.scale(step);
+                let n = match voxel(&w2i, dims, photon.position) {
+                    Some(n) => n,
+                    None => break,
+                };
+                if random.next() * max_density > density.data()[n] {
+                    continue;
+                }
+                let pick = random.next() * mu.total();
+                if pick < mu.compton {
+                    let (eps, cos) = compton(photon.energy, random);
+                    energy[n] += photon.weight * photon.energy * (1.0 - eps);
+                    photon.energy *= eps;
+                    photon.direction = deflect(photon.direction, cos, 2.0 * PI * random.next());
+                } else if pick < mu.compton + mu.photoelectric {
+                    energy[n] += photon.weight * photon.energy;
+                    break;
+                } else {
+                    energy[n] += photon.weight * (photon.energy - 2.0 * ELECTRON_MASS);
+                    let direction = random.isotropic();
+                    for d in [direction, -direction] {
+                        stack.push(Photon {
+                            position: photon.position,
+                            direction: d,
+                            energy: ELECTRON_MASS,
+                            weight: photon.weight,
+                        });
+                    }
+                    break;
+                }
+            }
+        }
+    }
+    energy
+}
+
+/// Offset of the voxel holding `p`.
+fn voxel(w2i: &Affine3, dims: [usize; 3], p: Vec3<f64>) -> Option<usize> {
+    let q = w2i.transform_point(p).to_array();
+    let mut ijk = [0usize; 3];
+    for a in 0..3 {
+        let v = (q[a] + 0.5).floor();
+        if v < 0.0 || v >= dims[a] as f64 {
+            return None;
+        }
+        ijk[a] = v as usize;
+    }
+    Some(ijk[0] + dims[0] * (ijk[1] + dims[1] * ijk[2]))
+}
+
+impl MonteCarlo {
+    pub fn new(source: MonteCarloSource, calibration: f64) -> Self {
+        MonteCarlo {
+            source,
+            calibration,
+            options: MonteCarloOptions::default(),
+        }
+    }
+
+    /// Dose and its statistical uncertainty on the grid of `density`.
+    pub fn simulate(
+        &self,
+        beam: &BeamGeometry,
+        fluence: &Fluence,
+        density: &Grid3<f64>,
+    ) -> Result<MonteCarloDose> {
+        self.simulate_with(
+            beam,
+            fluence,
+            density,
+            self.options.threads,
+            &Progress::new(),
+        )
+    }
+
+    fn simulate_with(
+        &self,
+        beam: &BeamGeometry,
+        fluence: &Fluence,
+        density: &Grid3<f64>,
+        threads: usize,
+        progress: &Progress,
+    ) -> Result<MonteCarloDose> {
+        check_beam(beam, fluence)?;
+        let options = &self.options;
+        if options.batches < 2 || options.histories < options.batches {
+            return Err(Error::InvalidArgument(format!(
+                "{} histories in {} batches; at least 2 batches of 1 history are needed",
+                options.histories, options.batches
+            )));
+        }
+        match &self.source {
+            MonteCarloSource::Point { spectrum } => {
+                if spectrum.is_empty() || spectrum.iter().any(|(e, w)| *e <= 0.0 || *w < 0.0) {
+                    return Err(Error::InvalidArgument(
+                        "the spectrum needs positive energies and non-negative weights".to_string(),
+                    ));
+                }
+            }
+            MonteCarloSource::PhaseSpace(particles) => {
+                if particles.is_empty() {
+                    return Err(Error::InvalidArgument("empty phase space".to_string()));
+                }
+            }
+        }
+        let batches = options.batches;
+        let threads = match threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        }
+        .min(batches);
+        let per_batch: Vec<usize> = (0..batches)
+            .map(|b| options.histories / batches + usize::from(b < options.histories % batches))
+            .collect();
+        let mut results: Vec<Vec<f64>> = vec![Vec::new(); batches];
+        let done = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|w| {
+                    let (per_batch, done) = (&per_batch, &done);
+                    scope.spawn(move || {
+                        (w..batches)
+                            .step_by(threads)
+                            .take_while(|_| !progress.is_cancelled())
+                            .map(|b| {
+                                let mut random = Random::new(
+                                    options.seed ^ (b as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15),
+                                );
+                                let energy = run_batch(
+                                    self,
+                                    beam,
+                                    fluence,
+                                    density,
+                                    per_batch[b],
+                                    &mut random,
+                                );
+                                let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                                progress.report(done as f64 / batches as f64);
+                                (b, energy)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            for worker in workers {
+                for (b, energy) in worker.join().expect("Monte Carlo worker panicked") {
+                    results[b] = energy;
+                }
+            }
+        });
+        progress.check()?;
+        let area = match self.source {
+            MonteCarloSource::Point { .. } => {
+                fluence.dims[0] as f64
+                    * fluence.spacing[0]
+                    * fluence.dims[1] as f64
+                    * fluence.spacing[1]
+            }
+            MonteCarloSource::PhaseSpace(_) => 1.0,
+        };
+        let grams = density.geometry().voxel_volume() / 1000.0;
+        let mut dose = vec![0.0; density.len()];
+        let mut uncertainty = vec![0.0; density.len()];
+        for (n, rho) in density.data().iter().enumerate() {
+            let mass = rho * grams;
+            if mass <= 0.0 {
+                continue;
+            }
+            let per_history: Vec<f64> = results
+                .iter()
+                .zip(&per_batch)
+                .map(|(e, h)| MEV_PER_GRAM * self.calibration * area * e[n] / (mass * *h as f64))
+                .collect();
+            let mean = per_history.iter().sum::<f64>() / batches as f64;
+            let variance = per_history.iter().map(|d| (d - mean).powi(2)).sum::<f64>()
+                / (batches * (batches - 1)) as f64;
+            dose[n] = mean;
+            uncertainty[n] = variance.sqrt();
+        }
+        let geometry = density.geometry().clone();
+        Ok(MonteCarloDose {
+            dose: Grid3::from_vec(geometry.clone(), dose)?,
+            uncertainty: Grid3::from_vec(geometry, uncertainty)?,
+            histories: options.histories,
+        })
+    }
+}
+
+impl DoseEngine for MonteCarlo {
+    fn name(&self) -> &str {
+        "monte_carlo"
+    }
+
+    fn supports(&self, beam: &BeamInput<'_>) -> bool {
+        matches!(beam, BeamInput::Fluence(..))
+    }
+
+    fn compute(
+        &self,
+        beam: BeamInput<'_>,
+        density: &Grid3<f64>,
+        options: &CalculationOptions,
+    ) -> Result<DoseResult> {
+        let start = Instant::now();
+        let (geometry, fluence) = match beam {
+            BeamInput::Fluence(geometry, fluence) => (geometry, fluence),
+            _ => return Err(unsupported(self.name(), &beam)),
+        };
+        let threads = options.threads_or(self.options.threads);
+        let result = self.simulate_with(geometry, fluence, density, threads, &options.progress)?;
+        Ok(finish(
+            self.name(),
+            start,
+            result.dose,
+            Some(result.uncertainty),
+            Some(result.histories),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::beam::{BeamGeometry, Fluence};
+    use crate::dose::monte_carlo::{Attenuation, MonteCarlo, MonteCarloSource};
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::io::iaea::{Particle, ParticleType};
+
+    #[test]
+    fn water_attenuation() {
+        // NIST XCOM incoherent scattering and pair production in water.
+        assert!((Attenuation::water(1.0).compton - 0.07066).abs() < 0.0005);
+        assert!((Attenuation::water(0.1).compton - 0.1626).abs() < 0.004);
+        assert!((Attenuation::water(10.0).compton - 0.01715).abs() < 0.0002);
+        assert_eq!(Attenuation::water(1.0).pair, 0.0);
+        assert!((Attenuation::water(10.0).pair - 0.0049).abs() < 1e-9);
+    }
+
+    fn phantom() -> Grid3<f64> {
+        let geometry = GridGeometry::new(
+            [9, 20, 9],
+            Vec3::from(-40.0, -95.0, -40.0),
+            Vec3::from(10.0, 10.0, 10.0),
+        );
+        Grid3::new(geometry, 1.0)
+    }
+
+    #[test]
+    fn monte_carlo_point_source() {
+        let density = phantom();
+        let beam = BeamGeometry::new(Vec3::new(), 0.0, 1000.0);
+        let fluence = Fluence::rectangle([-20.0, 20.0], [-20.0, 20.0], 10.0, 1.0).unwrap();
+        let mut engine = MonteCarlo::new(
+            MonteCarloSource::Point {
+                spectrum: vec![(2.0, 1.0)],
+            },
+            1.0,
+        );
+        engine.options.histories = 100_000;
+        engine.options.batches = 4;
+        engine.options.threads = 2;
+        let run = engine.simulate(&beam, &fluence, &density).unwrap();
+        let again = engine.simulate(&beam, &fluence, &density).unwrap();
+        assert_eq!(run.dose, again.dose);
+
+        // Primary attenuation dominates the central axis beyond the surface voxels.
+        let (shallow, deep) = (run.dose[[4, 3, 4]], run.dose[[4, 15, 4]]);
+        assert!(
+            deep < shallow && deep > 0.3 * shallow,
+            "{} {}",
+            shallow,
+            deep
+        );
+        assert!(run.dose[[0, 10, 0]] < 0.2 * run.dose[[4, 10, 4]]);
+        let relative = run.uncertainty[[4, 3, 4]] / shallow;
+        assert!(relative > 0.0 && relative < 0.1, "{}", relative);
+
+        // Deposited energy never exceeds the incident energy.
+        let mass = density.geometry().voxel_volume() / 1000.0;
+        let deposited: f64 = run.dose.data().iter().map(|d| d * mass).sum::<f64>() / 1.602e-10;
+        let incident = 2.0 * 40.0 * 40.0;
+        assert!(deposited < incident && deposited > 0.1 * incident);
+
+        engine.options.batches = 1;
+        assert!(engine.simulate(&beam, &fluence, &density).is_err());
+    }
+
+    #[test]
+    fn monte_carlo_phase_space() {
+        let density = phantom();
+        let beam = BeamGeometry::new(Vec3::new(), 0.0, 1000.0);
+        let fluence = Fluence::rectangle([-50.0, 50.0], [-50.0, 50.0], 10.0, 1.0).unwrap();
+        let photon = Particle {
+            particle_type: ParticleType::Photon,
+            energy: 1.0,
+            position: Vec3::from(0.0, 0.0, 50.0),
+            direction: Vec3::from(0.0, 0.0, 1.0),
+            weight: 1.0,
+            new_history: true,
+            extra_floats: Vec::new(),
+            extra_longs: Vec::new(),
+        };
+        let electron = Particle {
+            particle_type: ParticleType::Electron,
+            ..photon.clone()
+        };
+        let mut engine = MonteCarlo::new(MonteCarloSource::PhaseSpace(vec![photon, electron]), 1.0);
+        engine.options.histories = 4_000;
+        engine.options.batches = 2;
+        let run = engine.simulate(&beam, &fluence, &density).unwrap();
+        // A pencil along the central axis.
+        assert!(run.dose[[4, 5, 4]] > 10.0 * run.dose[[2, 5, 4]]);
+    }
+}
@@ -0,0 +1,343 @@
+//! Independent monitor unit verification in the manner of AAPM TG-114.
+//!
+//! For every beam the dose its plan delivers to one calculation point is converted back to
+//! monitor units with measured factors:
+//!
+//! MU = D / (K · Sc(rc) · Sp(rd) · T · OAR · WF · TF · ISF)
+//!
+//! with the calibration K, the collimator and phantom scatter factors at the collimator field
+//! size rc and the field size rd at the point, T the TPR (isocentric setups) or PDD (SSD
+//! setups, Mayneord corrected from the tabulated SSD), the off-axis ratio, the wedge and tray
+//! factors and the inverse square factor from the calibration distance. With heterogeneity
+//! correction the radiological depth replaces the physical depth. Field sizes are equivalent
+//! squares (mm) at the isocenter, depths and distances in mm.
+
+use crate::error::{Error, Result};
+
+/// A quantity tabulated over depth and field size, interpolated bilinearly and held beyond
+/// the table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepthSizeTable {
+    pub depths: Vec<f64>,
+    pub sizes: Vec<f64>,
+    /// Depth-major: `values[d * sizes.len() + s]`.
+    pub values: Vec<f64>,
+}
+
+fn bracket(x: &[f64], at: f64) -> (usize, usize, f64) {
+    if x.len() == 1 || at <= x[0] {
+        return (0, 0, 0.0);
+    }
+    if at >= x[x.len() - 1] {
+        return (x.len() - 1, x.len() - 1, 0.0);
+    }
+    let n = x.partition_point(|v| *v <= at);
+    (n - 1, n, (at - x[n - 1]) / (x[n] - x[n - 1]))
+}
+
+fn ascending(x: &[f64]) -> bool {
+    !x.is_empty() && x.windows(2).all(|w| w[1] > w[0])
+}
+
+impl DepthSizeTable {
+    pub fn new(depths: Vec<f64>, sizes: Vec<f64>, values: Vec<f64>) -> Result<Self> {
+        if !ascending(&depths) || !ascending(&sizes) || values.len() != depths.len() * sizes.len() {
+            return Err(Error::InvalidArgument(format!(
+                "a table of {} values over {} depths and {} field sizes",
+                values.len(),
+                depths.len(),
+                sizes.len()
+            )));
+        }
+        Ok(DepthSizeTable {
+            depths,
+            sizes,
+            values,
+        })
+    }
+
+    pub fn at(&self, depth: f64, size: f64) -> f64 {
+        let (d0, d1, u) = bracket(&self.depths, depth);
+        let (s0, s1, v) = bracket(&self.sizes, size);
+        let value = |d: usize, s: usize| self.values[d * self.sizes.len() + s];
+        let a = value(d0, s0) + v * (value(d0, s1) - value(d0, s0));
+        let b = value(d1, s0) + v * (value(d1, s1) - value(d1, s0));
+        a + u * (b - a)
+    }
+}
+
+/// A factor tabulated over one variable, interpolated linearly and held beyond the table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FactorTable {
+    pub x: Vec<f64>,
+    pub values: Vec<f64>,
+}
+
+impl FactorTable {
+    pub fn new(x: Vec<f64>, values: Vec<f64>) -> Result<Self> {
+        if !ascending(&x) || values.len() != x.len() {
+            return Err(Error::InvalidArgument(format!(
+                "a factor table of {} values at {} points",
+                values.len(),
+                x.len()
+            )));
+        }
+        Ok(FactorTable { x, values })
+    }
+
+    pub fn at(&self, x: f64) -> f64 {
+        let (a, b, t) = bracket(&self.x, x);
+        self.values[a] + t * (self.values[b] - self.values[a])
+    }
+}
+
+/// Measured data of one machine and energy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MuData {
+    /// Gy per MU at the reference point for the reference field.
+    pub calibration: f64,
+    /// Source to calibration point distance (mm).
+    pub calibration_distance: f64,
+    /// Depth (mm) of the maximum dose, the normalization depth of the PDD.
+    pub d_max: f64,
+    pub sc: FactorTable,
+    pub sp: FactorTable,
+    pub tpr: DepthSizeTable,
+    /// Percent depth dose over depth and the field size at the surface.
+    pub pdd: DepthSizeTable,
+    /// SSD (mm) of the PDD table.
+    pub pdd_ssd: f64,
+    /// Off-axis ratio over the distance (mm) from the central axis at the isocenter plane.
+    pub off_axis: FactorTable,
+    /// Source to axis distance (mm).
+    pub sad: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Setup {
+    Isocentric,
+    /// Source to surface distance (mm).
+    Ssd(f64),
+}
+
+/// One beam of the plan and its calculation point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamCheck {
+    pub name: String,
+    pub setup: Setup,
+    pub planned_mu: f64,
+    /// Dose (Gy) of this beam at the point according to the plan.
+    pub dose: f64,
+    /// Equivalent square of the collimator setting at the isocenter (mm).
+    pub field_size: f64,
+    /// Physical depth (mm) of the point along the ray from the source.
+    pub depth: f64,
+    /// Radiological depth (mm) of the point, used with heterogeneity correction.
+    pub effective_depth: Option<f64>,
+    /// Source to point distance (mm).
+    pub distance: f64,
+    /// Distance (mm) of the point from the central axis, projected to the isocenter plane.
+    pub off_axis: f64,
+    pub wedge_factor: f64,
+    pub tray_factor: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MuCheckOptions {
+    /// Accepted difference (%) between the planned and the calculated MU.
+    pub tolerance: f64,
+    pub heterogeneity: bool,
+}
+
+impl Default for MuCheckOptions {
+    fn default() -> Self {
+        MuCheckOptions {
+            tolerance: 5.0,
+            heterogeneity: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MuCheckResult {
+    pub name: String,
+    pub planned_mu: f64,
+    pub calculated_mu: f64,
+    /// (planned - calculated) / calculated, in %.
+    pub difference: f64,
+    pub passed: bool,
+}
+
+/// Independent MU of `beam`.
+pub fn calculate_mu(data: &MuData, beam: &BeamCheck, options: &MuCheckOptions) -> Result<f64> {
+    if beam.distance <= 0.0 || beam.field_size <= 0.0 || data.sad <= 0.0 {
+        return Err(Error::InvalidArgument(format!(
+            "beam '{}' has a non-positive distance or field size",
+            beam.name
+        )));
+    }
+    let depth = match (options.heterogeneity, beam.effective_depth) {
+        (true, Some(d)) => d,
+        _ => beam.depth,
+    };
+    let rc = beam.field_size;
+    let (depth_factor, rd, isf) = match beam.setup {
+        Setup::Isocentric => {
+            let rd = rc * beam.distance / data.sad;
+            let isf = (data.calibration_distance / beam.distance).powi(2);
+            (data.tpr.at(depth, rd), rd, isf)
+        }
+        Setup::Ssd(ssd) => {
+            let surface = rc * ssd / data.sad;
+            let (f1, f2) = (data.pdd_ssd, ssd);
+            let mayneord =
+                ((f2 + data.d_max) / (f1 + data.d_max) * (f1 + depth) / (f2 + depth)).powi(2);
+            let pdd = data.pdd.at(depth, surface) / 100.0 * mayneord;
+            let rd = rc * (ssd + data.d_max) / data.sad;
+            let isf = (data.calibration_distance / (ssd + data.d_max)).powi(2);
+            (pdd, rd, isf)
+        }
+    };
+    let factors = data.calibration
+        * data.sc.at(rc)
+        * data.sp.at(rd)
+        * depth_factor
+        * data.off_axis.at(beam.off_axis)
+        * beam.wedge_factor
+        * beam.tray_factor
+        * isf;
+    if factors <= 0.0 {
+        return Err(Error::InvalidArgument(format!(
+            "beam '{}' delivers no dose per MU at its point",
+            beam.name
+        )));
+    }
+    Ok(beam.dose / factors)
+}
+
+/// Verifies the MU of every beam against `data`.
+pub fn mu_check(
+    data: &MuData,
+    beams: &[BeamCheck],
+    options: &MuCheckOptions,
+) -> Result<Vec<MuCheckResult>> {
+    beams
+        .iter()
+        .map(|beam| {
+            let calculated_mu = calculate_mu(data, beam, options)?;
+            let difference = 100.0 * (beam.planned_mu - calculated_mu) / calculated_mu;
+            Ok(MuCheckResult {
+                name: beam.name.clone(),
+                planned_mu: beam.planned_mu,
+                calculated_mu,
+                difference,
+                passed: difference.abs() <= options.tolerance,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dose::mu_check::{
+        calculate_mu, mu_check, BeamCheck, DepthSizeTable, FactorTable, MuCheckOptions, MuData,
+        Setup,
+    };
+
+    fn data() -> MuData {
+        let depths = vec![15.0, 50.0, 100.0, 200.0];
+        let sizes = vec![50.0, 100.0, 200.0];
+        let tpr = vec![
+            1.0, 1.0, 1.0, //
+            0.90, 0.91, 0.93, //
+            0.76, 0.78, 0.81, //
+            0.52, 0.55, 0.60,
+        ];
+        let pdd = vec![
+            100.0, 100.0, 100.0, //
+            85.0, 86.0, 88.0, //
+            65.0, 67.0, 70.0, //
+            38.0, 40.0, 44.0,
+        ];
+        MuData {
+            calibration: 0.01,
+            calibration_distance: 1000.0,
+            d_max: 15.0,
+            sc: FactorTable::new(vec![50.0, 100.0, 200.0], vec![0.97, 1.0, 1.02]).unwrap(),
+            sp: FactorTable::new(vec![50.0, 100.0, 200.0], vec![0.96, 1.0, 1.03]).unwrap(),
+            tpr: DepthSizeTable::new(depths.clone(), sizes.clone(), tpr).unwrap(),
+            pdd: DepthSizeTable::new(depths, sizes, pdd).unwrap(),
+            pdd_ssd: 1000.0,
+            off_axis: FactorTable::new(vec![0.0, 100.0], vec![1.0, 1.03]).unwrap(),
+            sad: 1000.0,
+        }
+    }
+
+    fn beam() -> BeamCheck {
+        BeamCheck {
+            name: "AP".to_string(),
+            setup: Setup::Isocentric,
+            planned_mu: 100.0,
+            dose: 1.0,
+            field_size: 100.0,
+            depth: 100.0,
+            effective_depth: None,
+            distance: 1000.0,
+            off_axis: 0.0,
+            wedge_factor: 1.0,
+            tray_factor: 1.0,
+        }
+    }
+
+    #[test]
+    fn isocentric() {
+        let data = data();
+        let options = MuCheckOptions::default();
+        // Reference field at the isocenter: D / (K TPR).
+        let mu = calculate_mu(&data, &beam(), &options).unwrap();
+        assert!((mu - 1.0 / (0.01 * 0.78)).abs() < 1e-9);
+        // A lung path shortens the radiological depth and lowers the MU.
+        let lung = BeamCheck {
+            effective_depth: Some(50.0),
+            ..beam()
+        };
+        let corrected = calculate_mu(&data, &lung, &options).unwrap();
+        assert!((corrected - 1.0 / (0.01 * 0.91)).abs() < 1e-9);
+        let off = MuCheckOptions {
+            heterogeneity: false,
+            ..options
+        };
+        assert_eq!(calculate_mu(&data, &lung, &off).unwrap(), mu);
+
+        let results = mu_check(&data, &[beam()], &options).unwrap();
+        assert!((results[0].difference - 100.0 * (100.0 - mu) / mu).abs() < 1e-9);
+        assert!(!results[0].passed);
+        let planned = BeamCheck {
+            planned_mu: 1.02 * mu,
+            ..beam()
+        };
+        assert!(mu_check(&data, &[planned], &options).unwrap()[0].passed);
+    }
+
+    #[test]
+    fn ssd_setup() {
+        let data = data();
+        let beam = BeamCheck {
+            setup: Setup::Ssd(1000.0),
+            distance: 1100.0,
+            ..beam()
+        };
+        let mu = calculate_mu(&data, &beam, &MuCheckOptions::default()).unwrap();
+        let rd = 100.0 * 1015.0 / 1000.0;
+        let expected = 1.0 / (0.01 * data.sp.at(rd) * 0.67 * (1000.0f64 / 1015.0).powi(2));
+        assert!((mu - expected).abs() < 1e-9);
+        // Extended SSD: the Mayneord factor raises the PDD.
+        let extended = BeamCheck {
+            setup: Setup::Ssd(1200.0),
+            ..beam
+        };
+        let f = calculate_mu(&data, &extended, &MuCheckOptions::default()).unwrap();
+        assert!(f > mu);
+        assert!(DepthSizeTable::new(vec![1.0, 0.0], vec![1.0], vec![1.0, 1.0]).is_err());
+    }
+}
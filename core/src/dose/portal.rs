@@ -0,0 +1,278 @@
+//! Portal dose prediction.
+//!
+//! The transmission image of a beam at the EPID is predicted from its delivered fluence and
+//! the patient: every pixel sees the fluence on its ray from the source, attenuated
+//! exponentially along the radiological path through the relative electron density, plus a
+//! fraction of the attenuated fluence spread by a Gaussian scatter kernel, and the sum is
+//! blurred by a Gaussian detector response. Images are in calibrated units: the open
+//! calibration field without patient reads its fluence (MU) times the inverse square factor
+//! `(SAD / SID)²`. Arcs are integrated over their control point intervals, each at its mean
+//! gantry angle.
+//!
+//! A [`PortalImage`] carries the geometry of an RT image (source to image distance, angles,
+//! pixel spacing and position); the crate has no DICOM writer, so images are exported with
+//! the volume formats as a single slice, see [`PortalImage::to_grid`].
+
+use crate::coords::Vec3;
+use crate::dose::beam::{BeamGeometry, Fluence};
+use crate::dose::delivery::{self, Mlc};
+use crate::error::{Error, Result};
+use crate::grid::{Grid3, GridGeometry};
+use crate::plan::Beam;
+use crate::raytrace::radiological_path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PortalOptions {
+    /// Source to image distance (mm).
+    pub sid: f64,
+    /// Pixels along `x` and `y`.
+    pub dims: [usize; 2],
+    /// mm at the image plane.
+    pub spacing: [f64; 2],
+    /// Shift (mm) of the image center from the beam axis at the image plane.
+    pub offset: [f64; 2],
+    /// Linear attenuation coefficient of water (1/mm).
+    pub attenuation: f64,
+    /// Patient scatter reaching the detector relative to the attenuated fluence.
+    pub scatter_fraction: f64,
+    /// mm at the image plane.
+    pub scatter_sigma: f64,
+    /// mm at the image plane.
+    pub detector_sigma: f64,
+    /// Fluence raster spacing (mm) at the isocenter plane.
+    pub fluence_spacing: f64,
+}
+
+impl Default for PortalOptions {
+    fn default() -> Self {
+        PortalOptions {
+            sid: 1500.0,
+            dims: [300, 300],
+            spacing: [1.0, 1.0],
+            offset: [0.0, 0.0],
+            attenuation: 0.0049,
+            scatter_fraction: 0.05,
+            scatter_sigma: 40.0,
+            detector_sigma: 1.0,
+            fluence_spacing: 1.0,
+        }
+    }
+}
+
+/// Predicted transmission image in beam coordinates at the image plane.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortalImage {
+    /// Source to image distance (mm).
+    pub sid: f64,
+    /// Degrees.
+    pub gantry: f64,
+    /// Degrees.
+    pub collimator: f64,
+    /// Degrees.
+    pub couch: f64,
+    /// Center of pixel (0, 0) (mm).
+    pub origin: [f64; 2],
+    /// mm
+    pub spacing: [f64; 2],
+    pub dims: [usize; 2],
+    /// Calibrated units, `x` running fastest.
+    pub values: Vec<f64>,
+}
+
+impl PortalImage {
+    pub fn get(&self, i: usize, j: usize) -> f64 {
+        self.values[i + self.dims[0] * j]
+    }
+
+    /// Value of the pixel holding `(x, y)` (mm); 0 outside the image.
+    pub fn at(&self, x: f64, y: f64) -> f64 {
+        let i = ((x - self.origin[0]) / self.spacing[0] + 0.5).floor();
+        let j = ((y - self.origin[1]) / self.spacing[1] + 0.5).floor();
+        if i < 0.0 || j < 0.0 || i >= self.dims[0] as f64 || j >= self.dims[1] as f64 {
+            0.0
+        } else {
+            self.get(i as usize, j as usize)
+        }
+    }
+
+    /// The image as a single slice in beam coordinates (mm), for the volume writers of
+    /// [`crate::io`].
+    pub fn to_grid(&self) -> Grid3<f64> {
+        let geometry = GridGeometry::new(
+            [self.dims[0], self.dims[1], 1],
+            Vec3::from(self.origin[0], self.origin[1], 0.0),
+            Vec3::from(self.spacing[0], self.spacing[1], 1.0),
+        );
+        Grid3::from_vec(geometry, self.values.clone()).expect("one value per pixel")
+    }
+}
+
+/// Predicted portal image of `beam` delivered with `mlc` through `density`.
+pub fn predict(
+    beam: &Beam,
+    mlc: &Mlc,
+    density: &Grid3<f64>,
+    options: &PortalOptions,
+) -> Result<PortalImage> {
+    if options.sid <= beam.sad {
+        return Err(Error::InvalidArgument(format!(
+            "image plane at {} mm not beyond the isocenter at {} mm",
+            options.sid, beam.sad
+        )));
+    }
+    if options.dims[0] == 0 || options.dims[1] == 0 {
+        return Err(Error::InvalidArgument("empty portal image".to_string()));
+    }
+    let first = beam.geometry(0).ok_or_else(|| {
+        Error::InvalidArgument(format!("beam {} has no control points", beam.number))
+    })?;
+    let points = beam.delivery_points();
+    let intervals: Vec<(BeamGeometry, &[delivery::ControlPoint])> = if beam.is_arc() {
+        (1..points.len())
+            .map(|n| {
+                let mut geometry = beam.geometry(n - 1).expect("control point");
+                let next = beam.geometry(n).expect("control point");
+                let turn = (next.gantry - geometry.gantry + 180.0).rem_euclid(360.0) - 180.0;
+                geometry.gantry = (geometry.gantry + 0.5 * turn).rem_euclid(360.0);
+                (geometry, &points[n - 1..=n])
+            })
+            .collect()
+    } else {
+        vec![(first, &points[..])]
+    };
+
+    let [nx, ny] = options.dims;
+    let origin = [
+        options.offset[0] - 0.5 * (nx - 1) as f64 * options.spacing[0],
+        options.offset[1] - 0.5 * (ny - 1) as f64 * options.spacing[1],
+    ];
+    let mut primary = Fluence::new(origin, options.spacing, options.dims, 0.0);
+    let mut attenuated = primary.clone();
+    let magnification = options.sid / beam.sad;
+    let inverse_square = magnification.powi(-2);
+    for (geometry, points) in intervals {
+        if points[points.len() - 1].weight <= points[0].weight {
+            continue;
+        }
+        let fluence = delivery::fluence(mlc, points, beam.meterset, options.fluence_spacing)?;
+        let [x, y, toward] = geometry.axes();
+        let source = geometry.source();
+        let plane = geometry.isocenter - toward.scale(options.sid - geometry.sad);
+        for j in 0..ny {
+            for i in 0..nx {
+                let (u, v) = primary.position(i, j);
+                let open = fluence.at(u / magnification, v / magnification);
+                if open <= 0.0 {
+                    continue;
+                }
+                let pixel = plane + x.scale(u) + y.scale(v);
+                let path = radiological_path(density, source, pixel);
+                let transmission = (-options.attenuation * path).exp();
+                let n = i + nx * j;
+                primary.values[n] += inverse_square * open * transmission;
+                attenuated.values[n] += inverse_square * open * (1.0 - transmission);
+            }
+        }
+    }
+
+    let mut signal = primary.clone();
+    if options.scatter_fraction > 0.0 {
+        for j in 0..ny {
+            for i in 0..nx {
+                let (u, v) = signal.position(i, j);
+                let scatter = attenuated.convolve_gaussian(u, v, options.scatter_sigma, 3.0);
+                signal.values[i + nx * j] += options.scatter_fraction * scatter;
+            }
+        }
+    }
+    let values = (0..ny)
+        .flat_map(|j| (0..nx).map(move |i| (i, j)))
+        .map(|(i, j)| {
+            let (u, v) = signal.position(i, j);
+            signal.convolve_gaussian(u, v, options.detector_sigma, 3.0)
+        })
+        .collect();
+    Ok(PortalImage {
+        sid: options.sid,
+        gantry: first.gantry,
+        collimator: first.collimator,
+        couch: first.couch,
+        origin,
+        spacing: options.spacing,
+        dims: options.dims,
+        values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::beam::BeamGeometry;
+    use crate::dose::commissioning::MlcData;
+    use crate::dose::delivery::{ControlPoint, Mlc};
+    use crate::dose::portal::{predict, PortalOptions};
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::plan::Beam;
+
+    #[test]
+    fn portal_prediction() {
+        let mlc = Mlc::uniform(
+            40,
+            5.0,
+            MlcData {
+                transmission: 0.0,
+                dlg: 0.0,
+            },
+        );
+        let point = |weight| ControlPoint {
+            weight,
+            jaws: Some([-50.0, 50.0, -50.0, 50.0]),
+            leaves: None,
+        };
+        let geometry = BeamGeometry::new(Vec3::new(), 0.0, 1000.0);
+        let beam = Beam::fixed(1, "AP", "6X", &geometry, 100.0, &[point(0.0), point(1.0)]);
+        // A 100 mm water slab across the beam, in a 300 mm cube of 10 mm voxels.
+        let grid = GridGeometry::new(
+            [30, 30, 30],
+            Vec3::from(-145.0, -145.0, -145.0),
+            Vec3::from(10.0, 10.0, 10.0),
+        );
+        let mut slab = Grid3::new(grid.clone(), 0.0);
+        for k in 0..30 {
+            for j in 10..20 {
+                for i in 0..30 {
+                    slab.set(i, j, k, 1.0);
+                }
+            }
+        }
+        let options = PortalOptions {
+            dims: [101, 101],
+            spacing: [2.0, 2.0],
+            ..PortalOptions::default()
+        };
+
+        let open = predict(&beam, &mlc, &Grid3::new(grid, 0.0), &options).unwrap();
+        let calibration = 100.0 * (1000.0f64 / 1500.0).powi(2);
+        assert!((open.at(0.0, 0.0) - calibration).abs() < 1e-3);
+        // The field edge projects to 75 mm at the image plane.
+        assert!(open.at(90.0, 0.0).abs() < 1e-9);
+        assert_eq!(open.sid, 1500.0);
+
+        let image = predict(&beam, &mlc, &slab, &options).unwrap();
+        let primary = calibration * (-0.49f64).exp();
+        let center = image.at(0.0, 0.0);
+        assert!(center > primary && center < 1.05 * primary);
+        // Scatter reaches beyond the field edge.
+        assert!(image.at(90.0, 0.0) > 0.0);
+        assert!(image.at(90.0, 0.0) < 0.05 * center);
+        let grid = image.to_grid();
+        assert_eq!(grid.dims(), [101, 101, 1]);
+        assert_eq!(grid.get(50, 50, 0), Some(&center));
+
+        let close = PortalOptions {
+            sid: 900.0,
+            ..options
+        };
+        assert!(predict(&beam, &mlc, &slab, &close).is_err());
+    }
+}
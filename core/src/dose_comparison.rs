@@ -0,0 +1,264 @@
+//! Statistical comparison of two dose grids within structures, e.g. a TPS dose against an
+//! independent re-calculation.
+//!
+//! The evaluated dose is interpolated onto the reference grid. Voxel statistics weight every
+//! voxel by the fraction of it covered by the structure and skip voxels the evaluated grid
+//! does not cover; differences are evaluated minus reference (Gy). Percentiles are weighted
+//! nearest-rank percentiles of the absolute difference and the correlation is the weighted
+//! Pearson coefficient of the two doses. DVH metrics use DVHs of both doses on the reference
+//! grid, with uncovered voxels at 0 Gy.
+
+use crate::dvh::{Dvh, DvhOptions};
+use crate::error::{Error, Result};
+use crate::grid::Grid3;
+use crate::metric::{Metric, MetricValue};
+use crate::raster::rasterize_fractional;
+use crate::resample::resample;
+use crate::structure::Structure;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoseComparisonOptions {
+    /// Percentiles (0 to 100) of the absolute dose difference.
+    pub percentiles: Vec<f64>,
+    pub metrics: Vec<Metric>,
+    pub dvh: DvhOptions,
+    /// Gy; needed by relative dose metrics.
+    pub prescription: Option<f64>,
+}
+
+impl Default for DoseComparisonOptions {
+    fn default() -> Self {
+        DoseComparisonOptions {
+            percentiles: vec![50.0, 90.0, 95.0, 99.0],
+            metrics: Vec::new(),
+            dvh: DvhOptions::default(),
+            prescription: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DifferenceStatistics {
+    /// Covered voxels with a non-zero weight.
+    pub voxels: usize,
+    /// Weight of the voxels the evaluated grid does not cover.
+    pub uncovered: f64,
+    pub mean: f64,
+    pub mean_absolute: f64,
+    pub std_dev: f64,
+    pub min: f64,
+    pub max: f64,
+    /// Largest absolute difference.
+    pub max_absolute: f64,
+    /// (percentile, absolute difference) per requested percentile.
+    pub percentiles: Vec<(f64, f64)>,
+    /// NaN when either dose is constant.
+    pub correlation: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricDelta {
+    pub metric: Metric,
+    pub reference: MetricValue,
+    pub evaluated: MetricValue,
+    /// Evaluated minus reference, in the metric's result unit.
+    pub difference: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructureDoseComparison {
+    pub structure: String,
+    pub statistics: DifferenceStatistics,
+    pub metrics: Vec<MetricDelta>,
+}
+
+/// Weighted statistics of the differences; `None` when no covered voxel has a weight.
+fn statistics(
+    reference: &[f64],
+    evaluated: &[f64],
+    weights: &[f64],
+    percentiles: &[f64],
+) -> Option<DifferenceStatistics> {
+    let mut samples = Vec::new();
+    let mut uncovered = 0.0;
+    for ((r, e), w) in reference.iter().zip(evaluated).zip(weights) {
+        if *w <= 0.0 {
+            continue;
+        }
+        if e.is_nan() {
+            uncovered += w;
+        } else {
+            samples.push((*r, *e, *w));
+        }
+    }
+    if samples.is_empty() {
+        return None;
+    }
+    let total: f64 = samples.iter().map(|s| s.2).sum();
+    let mean_of = |f: &dyn Fn(&(f64, f64, f64)) -> f64| {
+        samples.iter().map(|s| s.2 * f(s)).sum::<f64>() / total
+    };
+    let mean = mean_of(&|(r, e, _)| e - r);
+    let mean_absolute = mean_of(&|(r, e, _)| (e - r).abs());
+    let variance = mean_of(&|(r, e, _)| (e - r - mean).powi(2));
+    let (mean_r, mean_e) = (mean_of(&|s| s.0), mean_of(&|s| s.1));
+    let covariance = mean_of(&|(r, e, _)| (r - mean_r) * (e - mean_e));
+    let var_r = mean_of(&|(r, _, _)| (r - mean_r).powi(2));
+    let var_e = mean_of(&|(_, e, _)| (e - mean_e).powi(2));
+    let correlation = if var_r > 0.0 && var_e > 0.0 {
+        covariance / (var_r * var_e).sqrt()
+    } else {
+        f64::NAN
+    };
+    let mut absolute: Vec<(f64, f64)> = samples
+        .iter()
+        .map(|(r, e, w)| ((e - r).abs(), *w))
+        .collect();
+    absolute.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    let percentile = |p: f64| {
+        let wanted = p / 100.0 * total;
+        let mut cumulative = 0.0;
+        for (d, w) in &absolute {
+            cumulative += w;
+            if cumulative >= wanted - 1e-12 * total {
+                return *d;
+            }
+        }
+        absolute[absolute.len() - 1].0
+    };
+    let differences = samples.iter().map(|(r, e, _)| e - r);
+    Some(DifferenceStatistics {
+        voxels: samples.len(),
+        uncovered,
+        mean,
+        mean_absolute,
+        std_dev: variance.sqrt(),
+        min: differences.clone().fold(f64::INFINITY, f64::min),
+        max: differences.fold(f64::NEG_INFINITY, f64::max),
+        max_absolute: absolute[absolute.len() - 1].0,
+        percentiles: percentiles.iter().map(|p| (*p, percentile(*p))).collect(),
+        correlation,
+    })
+}
+
+/// Compares `evaluated` against `reference` within regions given as (name, voxel fractions
+/// on the reference grid).
+pub fn compare_doses_in(
+    reference: &Grid3<f64>,
+    evaluated: &Grid3<f64>,
+    regions: &[(&str, &Grid3<f64>)],
+    options: &DoseComparisonOptions,
+) -> Result<Vec<StructureDoseComparison>> {
+    let resampled = resample(evaluated, reference.geometry(), f64::NAN);
+    let filled = resampled.map(|d| if d.is_nan() { 0.0 } else { *d });
+    let mut out = Vec::with_capacity(regions.len());
+    for (name, fraction) in regions {
+        if fraction.dims() != reference.dims() {
+            return Err(Error::InvalidArgument(format!(
+                "{} and the reference dose differ in size: {:?} vs {:?}",
+                name,
+                fraction.dims(),
+                reference.dims()
+            )));
+        }
+        let statistics = statistics(
+            reference.data(),
+            resampled.data(),
+            fraction.data(),
+            &options.percentiles,
+        )
+        .ok_or_else(|| {
+            Error::InvalidArgument(format!("{} has no voxels covered by both doses", name))
+        })?;
+        let dvh_reference = Dvh::from_fraction(name, fraction, reference, options.dvh.bin_width)?;
+        let dvh_evaluated = Dvh::from_fraction(name, fraction, &filled, options.dvh.bin_width)?;
+        let mut metrics = Vec::with_capacity(options.metrics.len());
+        for metric in &options.metrics {
+            let r = metric.evaluate(&dvh_reference, options.prescription)?;
+            let e = metric.evaluate(&dvh_evaluated, options.prescription)?;
+            metrics.push(MetricDelta {
+                metric: *metric,
+                reference: r,
+                evaluated: e,
+                difference: e.value() - r.value(),
+            });
+        }
+        out.push(StructureDoseComparison {
+            structure: name.to_string(),
+            statistics,
+            metrics,
+        });
+    }
+    Ok(out)
+}
+
+/// Compares `evaluated` against `reference` within `structures`, rasterized on the
+/// reference grid.
+pub fn compare_doses(
+    reference: &Grid3<f64>,
+    evaluated: &Grid3<f64>,
+    structures: &[&Structure],
+    options: &DoseComparisonOptions,
+) -> Result<Vec<StructureDoseComparison>> {
+    let fractions: Vec<Grid3<f64>> = structures
+        .iter()
+        .map(|s| rasterize_fractional(s, reference.geometry(), options.dvh.samples))
+        .collect();
+    let regions: Vec<(&str, &Grid3<f64>)> = structures
+        .iter()
+        .zip(&fractions)
+        .map(|(s, f)| (s.name.as_str(), f))
+        .collect();
+    compare_doses_in(reference, evaluated, &regions, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose_comparison::{compare_doses_in, DoseComparisonOptions};
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::metric::{DoseUnit, Metric};
+
+    #[test]
+    fn dose_comparison_statistics() {
+        let geometry = GridGeometry::new([4, 1, 1], Vec3::new(), Vec3::from(10.0, 10.0, 10.0));
+        let reference = Grid3::from_vec(geometry.clone(), vec![10.0, 20.0, 30.0, 40.0]).unwrap();
+        let evaluated = Grid3::from_vec(geometry.clone(), vec![11.0, 20.0, 29.0, 42.0]).unwrap();
+        let all = Grid3::new(geometry.clone(), 1.0);
+        let half = Grid3::from_vec(geometry, vec![0.0, 0.0, 1.0, 1.0]).unwrap();
+        let options = DoseComparisonOptions {
+            percentiles: vec![50.0, 100.0],
+            metrics: vec![Metric::Max(DoseUnit::Gy), Metric::Mean(DoseUnit::Gy)],
+            ..Default::default()
+        };
+        let r = compare_doses_in(
+            &reference,
+            &evaluated,
+            &[("All", &all), ("High", &half)],
+            &options,
+        )
+        .unwrap();
+        let s = &r[0].statistics;
+        assert_eq!(s.voxels, 4);
+        assert!((s.mean - 0.5).abs() < 1e-12);
+        assert!((s.mean_absolute - 1.0).abs() < 1e-12);
+        assert_eq!((s.min, s.max, s.max_absolute), (-1.0, 2.0, 2.0));
+        assert_eq!(s.percentiles, vec![(50.0, 1.0), (100.0, 2.0)]);
+        assert!(s.correlation > 0.99 && s.correlation <= 1.0);
+        assert!((r[0].metrics[0].difference - 2.0).abs() < 1e-12);
+        assert!((r[0].metrics[1].difference - 0.5).abs() < 1e-12);
+        assert_eq!(r[1].statistics.voxels, 2);
+        assert!((r[1].statistics.mean - 0.5).abs() < 1e-12);
+
+        // The evaluated grid only covers the first two voxels.
+        let small = GridGeometry::new([2, 1, 1], Vec3::new(), Vec3::from(10.0, 10.0, 10.0));
+        let partial = Grid3::new(small, 15.0);
+        let r = compare_doses_in(&reference, &partial, &[("All", &all)], &options).unwrap();
+        assert_eq!(
+            (r[0].statistics.voxels, r[0].statistics.uncovered),
+            (2, 2.0)
+        );
+        assert!(r[0].statistics.correlation.is_nan());
+        assert!(compare_doses_in(&reference, &partial, &[("High", &half)], &options).is_err());
+    }
+}
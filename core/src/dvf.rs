@@ -0,0 +1,248 @@
+//! Deformation vector fields.
+//!
+//! A [`Dvf`] holds a displacement (mm) per voxel: the position `x` of its grid corresponds
+//! to the position `x + u(x)` of the target anatomy, with the displacement trilinearly
+//! interpolated between voxel centers. Deformable registration returns a field from the fixed
+//! to the moving image and dose accumulation warps fraction doses with fields from the
+//! fraction to the reference anatomy.
+
+use crate::affine::Affine3;
+use crate::coords::Vec3;
+use crate::error::Result;
+use crate::grid::{Grid3, GridGeometry};
+use crate::resample::Interpolator;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dvf {
+    field: Grid3<Vec3<f64>>,
+    /// The displacement components for interpolation.
+    components: [Grid3<f64>; 3],
+}
+
+impl Dvf {
+    pub fn new(field: Grid3<Vec3<f64>>) -> Self {
+        let components = [field.map(|u| u.x), field.map(|u| u.y), field.map(|u| u.z)];
+        Dvf { field, components }
+    }
+
+    pub fn from_vec(geometry: GridGeometry, data: Vec<Vec3<f64>>) -> Result<Self> {
+        Ok(Self::new(Grid3::from_vec(geometry, data)?))
+    }
+
+    /// The field without displacements.
+    pub fn identity(geometry: GridGeometry) -> Self {
+        Self::new(Grid3::new(geometry, Vec3::new()))
+    }
+
+    /// The displacements of `transform` at the voxel centers of `geometry`.
+    pub fn from_affine(geometry: GridGeometry, transform: &Affine3) -> Self {
+        let mut field = Grid3::new(geometry.clone(), Vec3::new());
+        for (n, u) in field.data_mut().iter_mut().enumerate() {
+            let [i, j, k] = geometry.ijk(n);
+            let p = geometry.position(i, j, k);
+            *u = transform.transform_point(p) - p;
+        }
+        Self::new(field)
+    }
+
+    pub fn grid(&self) -> &Grid3<Vec3<f64>> {
+        &self.field
+    }
+
+    pub fn into_grid(self) -> Grid3<Vec3<f64>> {
+        self.field
+    }
+
+    pub fn geometry(&self) -> &GridGeometry {
+        self.field.geometry()
+    }
+
+    /// Displacement at world position `p`, `None` outside the grid.
+    pub fn displacement(&self, p: Vec3<f64>) -> Option<Vec3<f64>> {
+        let [x, y, z] = &self.components;
+        Some(Vec3::from(
+            Interpolator::new(x).at(p)?,
+            Interpolator::new(y).at(p)?,
+            Interpolator::new(z).at(p)?,
+        ))
+    }
+
+    /// The target position of `p`, `None` outside the grid.
+    pub fn warp_point(&self, p: Vec3<f64>) -> Option<Vec3<f64>> {
+        self.displacement(p).map(|u| p + u)
+    }
+
+    /// The position `x` with `x + u(x) = p`, by `iterations` of the fixed point iteration
+    /// `x = p - u(x)`, which converges where the field compresses or stretches by less than a
+    /// factor of two. The iteration stops when it leaves the grid.
+    pub fn invert_point(&self, p: Vec3<f64>, iterations: usize) -> Vec3<f64> {
+        let mut x = p;
+        for _ in 0..iterations {
+            match self.displacement(x) {
+                Some(u) => x = p - u,
+                None => break,
+            }
+        }
+        x
+    }
+
+    /// `image` sampled at the target position of every voxel, `outside` where that leaves
+    /// the image or the field: the image resampled onto the grid of the field.
+    pub fn warp(&self, image: &Grid3<f64>, outside: f64) -> Grid3<f64> {
+        let sampler = Interpolator::new(image);
+        let geometry = self.geometry();
+        let data = self
+            .field
+            .data()
+            .iter()
+            .enumerate()
+            .map(|(n, &u)| {
+                let [i, j, k] = geometry.ijk(n);
+                sampler
+                    .at(geometry.position(i, j, k) + u)
+                    .unwrap_or(outside)
+            })
+            .collect();
+        Grid3::from_vec(geometry.clone(), data).expect("one value per voxel")
+    }
+
+    /// The field applying `self`, then `next`, on the grid of `self`. Target positions
+    /// outside `next` keep the displacement of `self`.
+    pub fn compose(&self, next: &Dvf) -> Dvf {
+        let geometry = self.geometry();
+        let mut field = self.field.clone();
+        for (n, u) in field.data_mut().iter_mut().enumerate() {
+            let [i, j, k] = geometry.ijk(n);
+            let y = geometry.position(i, j, k) + *u;
+            if let Some(v) = next.displacement(y) {
+                *u += v;
+            }
+        }
+        Dvf::new(field)
+    }
+
+    /// The inverse field on `geometry`, mapping target positions back, by `iterations` of
+    /// [`Dvf::invert_point`] per voxel.
+    pub fn inverse(&self, geometry: &GridGeometry, iterations: usize) -> Dvf {
+        let mut field = Grid3::new(geometry.clone(), Vec3::new());
+        for (n, v) in field.data_mut().iter_mut().enumerate() {
+            let [i, j, k] = geometry.ijk(n);
+            let y = geometry.position(i, j, k);
+            *v = self.invert_point(y, iterations) - y;
+        }
+        Dvf::new(field)
+    }
+
+    /// Determinant of the Jacobian `I + ∇u` per voxel: the local volume ratio of the target
+    /// to the source anatomy, above 1 for expansion, below 1 for compression and at most 0
+    /// where the field folds.
+    pub fn jacobian(&self) -> Grid3<f64> {
+        let geometry = self.geometry();
+        let gradients: Vec<Vec<Vec3<f64>>> = self
+            .components
+            .iter()
+            .map(|c| gradient(c.data(), geometry))
+            .collect();
+        let data = (0..self.field.len())
+            .map(|n| {
+                let mut m = [[0.0; 3]; 3];
+                for (r, row) in m.iter_mut().enumerate() {
+                    let g = gradients[r][n].to_array();
+                    for (c, v) in row.iter_mut().enumerate() {
+                        *v = g[c] + if r == c { 1.0 } else { 0.0 };
+                    }
+                }
+                Affine3::from(m, Vec3::new()).determinant()
+            })
+            .collect();
+        Grid3::from_vec(geometry.clone(), data).expect("one value per voxel")
+    }
+}
+
+/// Gradient (per mm) of `values` on `geometry` by central differences, one sided at the
+/// edges.
+pub(crate) fn gradient(values: &[f64], geometry: &GridGeometry) -> Vec<Vec3<f64>> {
+    let dims = geometry.dims;
+    let stride = [1, dims[0], dims[0] * dims[1]];
+    let w2i = geometry.world_to_index().matrix;
+    (0..values.len())
+        .map(|n| {
+            let ijk = geometry.ijk(n);
+            let mut g = [0.0; 3];
+            for a in 0..3 {
+                if dims[a] < 2 {
+                    continue;
+                }
+                let lo = if ijk[a] > 0 { n - stride[a] } else { n };
+                let hi = if ijk[a] + 1 < dims[a] {
+                    n + stride[a]
+                } else {
+                    n
+                };
+                let span = if ijk[a] > 0 && ijk[a] + 1 < dims[a] {
+                    2.0
+                } else {
+                    1.0
+                };
+                g[a] = (values[hi] - values[lo]) / span;
+            }
+            // Index gradient to world gradient through the transposed world to index matrix.
+            Vec3::from_array([0, 1, 2].map(|c| (0..3).map(|r| w2i[r][c] * g[r]).sum()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::affine::Affine3;
+    use crate::coords::Vec3;
+    use crate::dvf::Dvf;
+    use crate::grid::{Grid3, GridGeometry};
+
+    #[test]
+    fn dvf_operations() {
+        let geometry = GridGeometry::new(
+            [11, 11, 11],
+            Vec3::from(-50.0, -50.0, -50.0),
+            Vec3::from(10.0, 10.0, 10.0),
+        );
+        // Scaling by 1.1 about the origin, then a shift.
+        let scale = Affine3::scaling(Vec3::from(1.1, 1.1, 1.1));
+        let dvf = Dvf::from_affine(geometry.clone(), &scale);
+        let p = Vec3::from(15.0, -5.0, 20.0);
+        let q = dvf.warp_point(p).unwrap();
+        assert!((q - p.scale(1.1)).norm() < 1e-9);
+        assert!(dvf.warp_point(Vec3::from(70.0, 0.0, 0.0)).is_none());
+        assert!((dvf.invert_point(q, 50) - p).norm() < 1e-6);
+        let jacobian = dvf.jacobian();
+        assert!((jacobian[[5, 5, 5]] - 1.331).abs() < 1e-9);
+        assert!((jacobian[[0, 10, 0]] - 1.331).abs() < 1e-9);
+
+        let shift = Dvf::from_affine(
+            geometry.clone(),
+            &Affine3::translation(Vec3::from(0.0, 5.0, 0.0)),
+        );
+        let both = dvf.compose(&shift);
+        assert!((both.warp_point(p).unwrap() - (q + Vec3::from(0.0, 5.0, 0.0))).norm() < 1e-9);
+
+        let inverse = dvf.inverse(&geometry, 50);
+        let back = inverse.compose(&dvf);
+        assert!(back.grid()[[3, 6, 4]].norm() < 1e-6);
+        assert!((inverse.jacobian()[[5, 5, 5]] - 1.0 / 1.331).abs() < 1e-6);
+
+        // The image holding x at x, warped, holds the target x at every voxel.
+        let mut image = Grid3::new(geometry.clone(), 0.0);
+        for n in 0..geometry.len() {
+            let [i, j, k] = geometry.ijk(n);
+            image.data_mut()[n] = geometry.position(i, j, k).x;
+        }
+        let warped = dvf.warp(&image, -1.0);
+        assert!((warped[[7, 5, 5]] - 22.0).abs() < 1e-9);
+        let far = Dvf::from_affine(
+            geometry.clone(),
+            &Affine3::translation(Vec3::from(20.0, 0.0, 0.0)),
+        );
+        assert_eq!(far.warp(&image, -1.0)[[10, 5, 5]], -1.0);
+        assert!(Dvf::from_vec(geometry, vec![Vec3::new(); 3]).is_err());
+    }
+}
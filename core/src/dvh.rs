@@ -0,0 +1,313 @@
+//! Dose-volume histograms.
+//!
+//! Every voxel contributes its dose (Gy, at the voxel center) with the volume of the voxel
+//! covered by the structure, so partially covered boundary voxels count fractionally. Bin `n`
+//! holds the volume with a dose in `[n w, (n + 1) w)`; negative doses fall in the first bin.
+
+use crate::error::{Error, Result};
+use crate::grid::Grid3;
+use crate::raster::rasterize_fractional;
+use crate::structure::Structure;
+
+/// Largest number of dose bins of a histogram, 128 MB of volumes.
+pub const MAX_BINS: usize = 1 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeUnit {
+    /// cm³
+    Cc,
+    /// Percentage of the structure volume.
+    Percent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DvhOptions {
+    /// Gy
+    pub bin_width: f64,
+    /// Sub-samples per voxel axis for the boundary voxel fractions.
+    pub samples: usize,
+}
+
+impl Default for DvhOptions {
+    fn default() -> Self {
+        DvhOptions {
+            bin_width: 0.01,
+            samples: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dvh {
+    pub name: String,
+    /// Gy
+    pub bin_width: f64,
+    /// Volume (cc) per dose bin.
+    pub bins: Vec<f64>,
+    /// cc
+    pub volume: f64,
+    /// Gy
+    pub min: f64,
+    /// Gy
+    pub max: f64,
+    /// Gy
+    pub mean: f64,
+}
+
+impl Dvh {
+    /// Histogram of `dose` weighted by the voxel `fraction`s of a structure on the same grid.
+    /// The doses of the covered voxels must be finite and fit in [`MAX_BINS`] bins.
+    pub fn from_fraction(
+        name: &str,
+        fraction: &Grid3<f64>,
+        dose: &Grid3<f64>,
+        bin_width: f64,
+    ) -> Result<Self> {
+        if fraction.dims() != dose.dims() {
+            return Err(Error::InvalidArgument(format!(
+                "fraction and dose grids differ in size: {:?} vs {:?}",
+                fraction.dims(),
+                dose.dims()
+            )));
+        }
+        if !bin_width.is_finite() || bin_width <= 0.0 {
+            return Err(Error::InvalidArgument(format!(
+                "bin width must be positive, got {}",
+                bin_width
+            )));
+        }
+        let covered = || {
+            fraction
+                .data()
+                .iter()
+                .zip(dose.data())
+                .filter(|(f, _)| **f > 0.0)
+        };
+        let mut max_dose = f64::NEG_INFINITY;
+        for (_, d) in covered() {
+            if !d.is_finite() {
+                return Err(Error::InvalidArgument(format!(
+                    "{} has a non-finite dose {}",
+                    name, d
+                )));
+            }
+            max_dose = max_dose.max(*d);
+        }
+        let bins = max_dose.max(0.0) / bin_width;
+        if bins >= MAX_BINS as f64 {
+            return Err(Error::InvalidArgument(format!(
+                "a maximum dose of {} Gy needs more than {} bins of {} Gy",
+                max_dose, MAX_BINS, bin_width
+            )));
+        }
+        let voxel_cc = dose.geometry().voxel_volume() / 1000.0;
+        let mut dvh = Dvh {
+            name: name.to_string(),
+            bin_width,
+            // No bins without covered voxels.
+            bins: match max_dose {
+                m if m == f64::NEG_INFINITY => Vec::new(),
+                _ => vec![0.0; bins as usize + 1],
+            },
+            volume: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            mean: 0.0,
+        };
+        let mut dose_volume = 0.0;
+        for (f, d) in covered() {
+            let v = f * voxel_cc;
+            dvh.bins[(d.max(0.0) / bin_width) as usize] += v;
+            dvh.volume += v;
+            dose_volume += v * d;
+            dvh.min = dvh.min.min(*d);
+            dvh.max = dvh.max.max(*d);
+        }
+        if dvh.volume > 0.0 {
+            dvh.mean = dose_volume / dvh.volume;
+        } else {
+            dvh.min = 0.0;
+            dvh.max = 0.0;
+        }
+        Ok(dvh)
+    }
+
+    fn scale(&self, unit: VolumeUnit) -> f64 {
+        match unit {
+            VolumeUnit::Cc => 1.0,
+            VolumeUnit::Percent if self.volume > 0.0 => 100.0 / self.volume,
+            VolumeUnit::Percent => 0.0,
+        }
+    }
+
+    /// (dose at the bin center, volume in the bin) per bin.
+    pub fn differential(&self, unit: VolumeUnit) -> Vec<(f64, f64)> {
+        let s = self.scale(unit);
+        self.bins
+            .iter()
+            .enumerate()
+            .map(|(n, v)| ((n as f64 + 0.5) * self.bin_width, v * s))
+            .collect()
+    }
+
+    /// (dose, volume receiving at least that dose) at every bin edge, from 0 Gy to the upper
+    /// edge of the last bin (where the volume is 0).
+    pub fn cumulative(&self, unit: VolumeUnit) -> Vec<(f64, f64)> {
+        let s = self.scale(unit);
+        let mut out = Vec::with_capacity(self.bins.len() + 1);
+        let mut remaining: f64 = self.bins.iter().sum();
+        for (n, v) in self.bins.iter().enumerate() {
+            out.push((n as f64 * self.bin_width, remaining.max(0.0) * s));
+            remaining -= v;
+        }
+        out.push((self.bins.len() as f64 * self.bin_width, 0.0));
+        out
+    }
+
+    /// Volume receiving at least `dose` (Gy), interpolated linearly between bin edges.
+    pub fn volume_at(&self, dose: f64, unit: VolumeUnit) -> f64 {
+        let cumulative = self.cumulative(unit);
+        let x = dose / self.bin_width;
+        if x <= 0.0 {
+            return cumulative[0].1;
+        }
+        let n = x.floor() as usize;
+        if n + 1 >= cumulative.len() {
+            return 0.0;
+        }
+        let t = x - n as f64;
+        cumulative[n].1 + t * (cumulative[n + 1].1 - cumulative[n].1)
+    }
+
+    /// The highest dose (Gy) received by at least `volume`, interpolated linearly between
+    /// bin edges; the maximum dose for a zero volume and 0 beyond the structure volume.
+    pub fn dose_at(&self, volume: f64, unit: VolumeUnit) -> f64 {
+        if volume <= 0.0 {
+            return self.max;
+        }
+        let cumulative = self.cumulative(unit);
+        if volume > cumulative[0].1 {
+            return 0.0;
+        }
+        let n = cumulative
+            .iter()
+            .position(|(_, v)| *v < volume)
+            .unwrap_or(cumulative.len() - 1)
+            .max(1);
+        let ((d0, v0), (d1, v1)) = (cumulative[n - 1], cumulative[n]);
+        let dose = if v0 > v1 {
+            d0 + (v0 - volume) / (v0 - v1) * (d1 - d0)
+        } else {
+            d0
+        };
+        dose.min(self.max)
+    }
+}
+
+/// DVH of `structure` on the grid of `dose`.
+pub fn dvh(structure: &Structure, dose: &Grid3<f64>, options: &DvhOptions) -> Result<Dvh> {
+    let fraction = rasterize_fractional(structure, dose.geometry(), options.samples);
+    Dvh::from_fraction(&structure.name, &fraction, dose, options.bin_width)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dvh::{dvh, Dvh, DvhOptions, VolumeUnit};
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::structure::{Contour, Structure, StructureType};
+
+    fn dose() -> Grid3<f64> {
+        let geometry = GridGeometry::new(
+            [10, 10, 5],
+            Vec3::from(0.0, 0.0, 0.0),
+            Vec3::from(2.0, 2.0, 2.5),
+        );
+        let mut dose = Grid3::new(geometry, 0.0);
+        for n in 0..dose.len() {
+            let [i, _, _] = dose.geometry().ijk(n);
+            dose.data_mut()[n] = i as f64;
+        }
+        dose
+    }
+
+    #[test]
+    fn dvh_uniform_gradient() {
+        let dose = dose();
+        let fraction = dose.map(|_| 1.0);
+        let h = Dvh::from_fraction("BOX", &fraction, &dose, 0.5).unwrap();
+        assert!((h.volume - 5.0).abs() < 1e-12);
+        assert_eq!((h.min, h.max), (0.0, 9.0));
+        assert!((h.mean - 4.5).abs() < 1e-12);
+        let cumulative = h.cumulative(VolumeUnit::Percent);
+        assert_eq!(cumulative[0].0, 0.0);
+        assert!((cumulative[0].1 - 100.0).abs() < 1e-9);
+        assert!((cumulative[10].1 - 50.0).abs() < 1e-9);
+        assert!((cumulative[11].1 - 40.0).abs() < 1e-9);
+        assert_eq!(cumulative.last().unwrap().1, 0.0);
+        let differential = h.differential(VolumeUnit::Cc);
+        assert_eq!(differential.len(), 19);
+        assert_eq!(differential[2].0, 1.25);
+        assert!((differential[2].1 - 0.5).abs() < 1e-12);
+        assert_eq!(differential[3].1, 0.0);
+        assert!(Dvh::from_fraction("BOX", &fraction, &dose, 0.0).is_err());
+    }
+
+    #[test]
+    fn dvh_dose_range() {
+        let mut dose = dose();
+        let fraction = dose.map(|v| if *v < 5.0 { 1.0 } else { 0.0 });
+        // Doses outside the structure are not binned.
+        dose.data_mut()[9] = f64::INFINITY;
+        dose.data_mut()[8] = 1e300;
+        let h = Dvh::from_fraction("BOX", &fraction, &dose, 1.0).unwrap();
+        assert_eq!(h.bins.len(), 5);
+        for bad in [f64::INFINITY, f64::NAN, 1e300].iter() {
+            dose.data_mut()[0] = *bad;
+            assert!(Dvh::from_fraction("BOX", &fraction, &dose, 1.0).is_err());
+        }
+        let empty = Dvh::from_fraction("BOX", &dose.map(|_| 0.0), &dose, 1.0).unwrap();
+        assert!(empty.bins.is_empty());
+        assert_eq!((empty.volume, empty.max), (0.0, 0.0));
+    }
+
+    #[test]
+    fn dvh_volume_and_dose_at() {
+        let dose = dose();
+        let h = Dvh::from_fraction("BOX", &dose.map(|_| 1.0), &dose, 0.5).unwrap();
+        assert!((h.volume_at(5.0, VolumeUnit::Percent) - 50.0).abs() < 1e-9);
+        assert!((h.volume_at(5.25, VolumeUnit::Percent) - 45.0).abs() < 1e-9);
+        assert_eq!(h.volume_at(20.0, VolumeUnit::Cc), 0.0);
+        assert!((h.dose_at(45.0, VolumeUnit::Percent) - 5.25).abs() < 1e-9);
+        assert_eq!(h.dose_at(0.0, VolumeUnit::Cc), 9.0);
+        assert_eq!(h.dose_at(6.0, VolumeUnit::Cc), 0.0);
+    }
+
+    #[test]
+    fn dvh_partial_voxels() {
+        let dose = dose();
+        // Covers x from 0 to 6 mm: voxel 0 (x -1..1) half, 1 and 2 fully, 3 (5..7) half.
+        let contours = (0..5)
+            .map(|k| {
+                let z = 2.5 * k as f64;
+                Contour::new(vec![
+                    Vec3::from(0.0, -1.0, z),
+                    Vec3::from(6.0, -1.0, z),
+                    Vec3::from(6.0, 19.0, z),
+                    Vec3::from(0.0, 19.0, z),
+                ])
+            })
+            .collect();
+        let s = Structure::new("PTV", StructureType::Ptv).with_contours(contours);
+        let options = DvhOptions {
+            bin_width: 1.0,
+            samples: 4,
+        };
+        let h = dvh(&s, &dose, &options).unwrap();
+        assert_eq!(h.name, "PTV");
+        assert!((h.volume - 6.0 * 20.0 * 12.5 / 1000.0).abs() < 1e-9);
+        assert!((h.mean - 1.5).abs() < 1e-9);
+        let d = h.differential(VolumeUnit::Percent);
+        assert!((d[0].1 - 100.0 / 6.0).abs() < 1e-9 && (d[1].1 - 100.0 / 3.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,40 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidArgument(String),
+    Io(std::io::Error),
+    Format(String),
+    Unsupported(String),
+    /// A computation stopped by its [`crate::progress::CancellationToken`].
+    Cancelled,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidArgument(msg) => write!(f, "invalid argument: {}", msg),
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Format(msg) => write!(f, "malformed data: {}", msg),
+            Error::Unsupported(msg) => write!(f, "unsupported: {}", msg),
+            Error::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
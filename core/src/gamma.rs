@@ -0,0 +1,444 @@
+//! 3D gamma analysis (Low et al., "A technique for the quantitative evaluation of dose
+//! distributions", 1998).
+//!
+//! For every reference voxel above the low-dose threshold the evaluated dose is sampled
+//! trilinearly on a search lattice around the voxel, with a step of the smallest evaluated
+//! spacing divided by `interpolation`. Lattice points are visited by increasing distance
+//! and the search stops once the distance term alone exceeds the best gamma found, or at
+//! `max_gamma` DTAs; gamma values at or above `max_gamma` are therefore upper bounds.
+//! Voxels without evaluated dose within that radius get an infinite gamma and fail.
+//! Reference slabs are processed on separate threads.
+//!
+//! Dose-difference and distance-to-agreement maps are available separately.
+
+use crate::coords::Vec3;
+use crate::error::{Error, Result};
+use crate::grid::{per_voxel, per_voxel_with, Grid3};
+use crate::progress::Progress;
+use crate::resample::{resample, Interpolator};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    /// Dose difference relative to this dose (Gy); the reference maximum when `None`.
+    Global(Option<f64>),
+    /// Dose difference relative to the local reference dose.
+    Local,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GammaOptions {
+    /// Fraction of the normalization dose, e.g. 0.03.
+    pub dose_difference: f64,
+    /// Distance to agreement (mm).
+    pub distance: f64,
+    pub normalization: Normalization,
+    /// Reference voxels below this fraction of the reference maximum are not evaluated.
+    pub threshold: f64,
+    /// Search lattice steps per evaluated voxel spacing.
+    pub interpolation: usize,
+    pub max_gamma: f64,
+    /// Worker threads; 0 uses the available parallelism.
+    pub threads: usize,
+}
+
+impl Default for GammaOptions {
+    fn default() -> Self {
+        GammaOptions {
+            dose_difference: 0.03,
+            distance: 3.0,
+            normalization: Normalization::Global(None),
+            threshold: 0.1,
+            interpolation: 3,
+            max_gamma: 2.0,
+            threads: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GammaResult {
+    /// Gamma on the reference grid; NaN for voxels that were not evaluated, infinite where
+    /// the evaluated grid does not reach within the search radius.
+    pub gamma: Grid3<f64>,
+    pub evaluated: usize,
+    pub passed: usize,
+}
+
+impl GammaResult {
+    /// Percentage of the evaluated voxels with gamma ≤ 1.
+    pub fn pass_rate(&self) -> f64 {
+        if self.evaluated == 0 {
+            100.0
+        } else {
+            100.0 * self.passed as f64 / self.evaluated as f64
+        }
+    }
+
+    /// Mean gamma of the evaluated voxels within reach of the evaluated dose; voxels with an
+    /// infinite gamma only count as failures in the pass rate.
+    pub fn mean(&self) -> f64 {
+        let (sum, count) = self
+            .gamma
+            .data()
+            .iter()
+            .filter(|g| g.is_finite())
+            .fold((0.0, 0), |(s, n), g| (s + g, n + 1));
+        sum / count.max(1) as f64
+    }
+
+    pub fn max(&self) -> f64 {
+        self.gamma
+            .data()
+            .iter()
+            .filter(|g| !g.is_nan())
+            .fold(0.0, |m, g| m.max(*g))
+    }
+}
+
+/// The global normalization dose (Gy), `max_dose` by default; `None` for local normalization.
+fn global_dose(normalization: Normalization, max_dose: f64) -> Result<Option<f64>> {
+    match normalization {
+        Normalization::Global(Some(d)) if d > 0.0 => Ok(Some(d)),
+        Normalization::Global(Some(d)) => Err(Error::InvalidArgument(format!(
+            "normalization dose must be positive, got {}",
+            d
+        ))),
+        Normalization::Global(None) if max_dose > 0.0 => Ok(Some(max_dose)),
+        Normalization::Global(None) => Err(Error::InvalidArgument(
+            "global normalization needs a positive reference dose".to_string(),
+        )),
+        Normalization::Local => Ok(None),
+    }
+}
+
+/// Gamma of `evaluated` against `reference`; the grids may differ in geometry.
+pub fn gamma(
+    reference: &Grid3<f64>,
+    evaluated: &Grid3<f64>,
+    options: &GammaOptions,
+) -> Result<GammaResult> {
+    gamma_with_progress(reference, evaluated, options, &Progress::new())
+}
+
+/// As [`gamma`], reporting to `progress` and stopping once it is cancelled.
+pub fn gamma_with_progress(
+    reference: &Grid3<f64>,
+    evaluated: &Grid3<f64>,
+    options: &GammaOptions,
+    progress: &Progress,
+) -> Result<GammaResult> {
+    if options.dose_difference <= 0.0 || options.distance <= 0.0 || options.max_gamma <= 0.0 {
+        return Err(Error::InvalidArgument(
+            "gamma criteria must be positive".to_string(),
+        ));
+    }
+    let max_dose = reference.data().iter().fold(0.0_f64, |m, d| m.max(*d));
+    let global = global_dose(options.normalization, max_dose)?;
+    let cutoff = options.threshold * max_dose;
+    let offsets = search_offsets(evaluated, options);
+    let sampler = Interpolator::new(evaluated);
+    let geometry = reference.geometry();
+    let i2w = geometry.index_to_world();
+    let values = per_voxel_with(reference.geometry(), options.threads, progress, |n| {
+        let d = reference.data()[n];
+        if d < cutoff || (global.is_none() && d <= 0.0) {
+            return f64::NAN;
+        }
+        let norm = options.dose_difference * global.unwrap_or(d);
+        let [i, j, k] = geometry.ijk(n);
+        let p = i2w.transform_point(Vec3::from(i as f64, j as f64, k as f64));
+        voxel_gamma(&sampler, &offsets, p, d, norm)
+    })?;
+    let evaluated_count = values.iter().filter(|g| !g.is_nan()).count();
+    let passed = values.iter().filter(|g| **g <= 1.0).count();
+    Ok(GammaResult {
+        gamma: Grid3::from_vec(geometry.clone(), values).expect("one value per voxel"),
+        evaluated: evaluated_count,
+        passed,
+    })
+}
+
+/// Dose difference (evaluated minus reference) on the reference grid, with the evaluated
+/// dose interpolated; in Gy without `normalization`, otherwise in percent of the global or
+/// local reference dose. NaN where the evaluated grid does not cover the reference voxel.
+/// The global normalization dose must be positive, as for [`gamma`].
+pub fn dose_difference(
+    reference: &Grid3<f64>,
+    evaluated: &Grid3<f64>,
+    normalization: Option<Normalization>,
+) -> Result<Grid3<f64>> {
+    let max_dose = reference.data().iter().fold(0.0_f64, |m, d| m.max(*d));
+    let global = match normalization {
+        Some(n) => global_dose(n, max_dose)?,
+        None => None,
+    };
+    let resampled = resample(evaluated, reference.geometry(), f64::NAN);
+    Ok(resampled
+        .zip_map(reference, |e, r| {
+            let diff = e - r;
+            match (normalization, global) {
+                (None, _) => diff,
+                (Some(_), Some(d)) => 100.0 * diff / d,
+                (Some(_), None) if *r != 0.0 => 100.0 * diff / r,
+                (Some(_), None) => f64::NAN,
+            }
+        })
+        .expect("resampled onto the reference geometry"))
+}
+
+/// Distance (mm) from every reference voxel above the threshold to the nearest point where
+/// the evaluated dose equals the reference dose, found on the gamma search lattice up to
+/// `max_gamma * distance`. Infinite when no such point lies within that radius, NaN below
+/// the threshold.
+pub fn distance_to_agreement(
+    reference: &Grid3<f64>,
+    evaluated: &Grid3<f64>,
+    options: &GammaOptions,
+) -> Result<Grid3<f64>> {
+    if options.distance <= 0.0 || options.max_gamma <= 0.0 {
+        return Err(Error::InvalidArgument(
+            "the DTA search radius must be positive".to_string(),
+        ));
+    }
+    let max_dose = reference.data().iter().fold(0.0_f64, |m, d| m.max(*d));
+    let cutoff = options.threshold * max_dose;
+    let offsets = search_offsets(evaluated, options);
+    let sampler = Interpolator::new(evaluated);
+    let geometry = reference.geometry();
+    let i2w = geometry.index_to_world();
+    let values = per_voxel(reference.geometry(), options.threads, |n| {
+        let d = reference.data()[n];
+        if d < cutoff {
+            return f64::NAN;
+        }
+        let [i, j, k] = geometry.ijk(n);
+        let p = i2w.transform_point(Vec3::from(i as f64, j as f64, k as f64));
+        let mut sign = None;
+        for (distance2, v) in &offsets {
+            let e = match sampler.at(p + *v) {
+                Some(e) => e - d,
+                None => continue,
+            };
+            if e == 0.0 {
+                return distance2.sqrt() * options.distance;
+            }
+            match sign {
+                None => sign = Some(e > 0.0),
+                Some(s) if s != (e > 0.0) => return distance2.sqrt() * options.distance,
+                Some(_) => {}
+            }
+        }
+        f64::INFINITY
+    });
+    Ok(Grid3::from_vec(geometry.clone(), values).expect("one value per voxel"))
+}
+
+/// Search lattice offsets (mm) within `max_gamma` DTAs, sorted by distance.
+fn search_offsets(evaluated: &Grid3<f64>, options: &GammaOptions) -> Vec<(f64, Vec3<f64>)> {
+    let s = evaluated.geometry().spacing;
+    let step = s.x.min(s.y).min(s.z) / options.interpolation.max(1) as f64;
+    let radius = options.max_gamma * options.distance;
+    let n = (radius / step).ceil() as i64;
+    let mut offsets = Vec::new();
+    for c in -n..=n {
+        for b in -n..=n {
+            for a in -n..=n {
+                let v = Vec3::from(a as f64, b as f64, c as f64).scale(step);
+                let r2 = v.dot(v);
+                if r2 <= radius * radius {
+                    offsets.push((r2 / (options.distance * options.distance), v));
+                }
+            }
+        }
+    }
+    offsets.sort_by(|x, y| x.0.partial_cmp(&y.0).unwrap());
+    offsets
+}
+
+fn voxel_gamma(
+    sampler: &Interpolator,
+    offsets: &[(f64, Vec3<f64>)],
+    p: Vec3<f64>,
+    dose: f64,
+    norm: f64,
+) -> f64 {
+    let mut best = f64::INFINITY;
+    for (distance2, v) in offsets {
+        if *distance2 >= best {
+            break;
+        }
+        if let Some(e) = sampler.at(p + *v) {
+            let dd = (e - dose) / norm;
+            best = best.min(distance2 + dd * dd);
+        }
+    }
+    best.sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::error::Error;
+    use crate::gamma::{
+        distance_to_agreement, dose_difference, gamma, gamma_with_progress, GammaOptions,
+        Normalization,
+    };
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::progress::{CancellationToken, Progress};
+
+    fn gaussian(shift: f64, scale: f64, spacing: f64, n: usize) -> Grid3<f64> {
+        let half = (n - 1) as f64 * spacing / 2.0;
+        let geometry = GridGeometry::new(
+            [n, n, n],
+            Vec3::from(-half, -half, -half),
+            Vec3::from(spacing, spacing, spacing),
+        );
+        let mut dose = Grid3::new(geometry, 0.0);
+        for m in 0..dose.len() {
+            let [i, j, k] = dose.geometry().ijk(m);
+            let p = dose.geometry().position(i, j, k) - Vec3::from(shift, 0.0, 0.0);
+            dose.data_mut()[m] = scale * 60.0 * (-p.dot(p) / (2.0 * 15.0 * 15.0)).exp();
+        }
+        dose
+    }
+
+    #[test]
+    fn gamma_identical() {
+        let d = gaussian(0.0, 1.0, 3.0, 15);
+        let r = gamma(&d, &d, &GammaOptions::default()).unwrap();
+        assert_eq!(r.pass_rate(), 100.0);
+        assert_eq!(r.max(), 0.0);
+        assert!(r.evaluated > 0 && r.evaluated < d.len());
+        assert!(r.gamma.data().iter().any(|g| g.is_nan()));
+    }
+
+    #[test]
+    fn gamma_progress() {
+        let d = gaussian(0.0, 1.0, 3.0, 15);
+        let (progress, updates) = Progress::channel();
+        gamma_with_progress(&d, &d, &GammaOptions::default(), &progress).unwrap();
+        let reported: Vec<f64> = updates.try_iter().collect();
+        assert_eq!(reported.len(), 15 * 15);
+        assert_eq!(reported.iter().cloned().fold(0.0, f64::max), 1.0);
+    }
+
+    #[test]
+    fn gamma_cancelled() {
+        let d = gaussian(0.0, 1.0, 3.0, 15);
+        let token = CancellationToken::new();
+        token.cancel();
+        let cancelled = gamma_with_progress(
+            &d,
+            &d,
+            &GammaOptions::default(),
+            &Progress::new().with_token(token),
+        );
+        assert!(matches!(cancelled, Err(Error::Cancelled)));
+    }
+
+    #[test]
+    fn gamma_shift_and_scale() {
+        let reference = gaussian(0.0, 1.0, 3.0, 15);
+        // A 2 mm shift on a finer grid passes 3%/3mm everywhere, but fails 1%/1mm.
+        let shifted = gaussian(2.0, 1.0, 2.0, 25);
+        let options = GammaOptions {
+            threads: 3,
+            ..GammaOptions::default()
+        };
+        let r = gamma(&reference, &shifted, &options).unwrap();
+        assert_eq!(r.pass_rate(), 100.0);
+        assert!(r.max() > 0.3 && r.max() < 0.8);
+        let strict = GammaOptions {
+            dose_difference: 0.01,
+            distance: 1.0,
+            ..options
+        };
+        assert!(gamma(&reference, &shifted, &strict).unwrap().pass_rate() < 90.0);
+
+        // 2% higher everywhere: global gamma ~0.67 at the maximum; local gamma is the same
+        // everywhere.
+        let scaled = gaussian(0.0, 1.02, 3.0, 15);
+        let local = GammaOptions {
+            normalization: Normalization::Local,
+            interpolation: 1,
+            ..options
+        };
+        let r = gamma(&reference, &scaled, &local).unwrap();
+        assert_eq!(r.pass_rate(), 100.0);
+        assert!(r.max() <= 2.0 / 3.0 + 1e-9);
+        assert!(gamma(
+            &reference,
+            &scaled,
+            &GammaOptions {
+                distance: 0.0,
+                ..options
+            }
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn gamma_outside_evaluated_grid() {
+        let reference = gaussian(0.0, 1.0, 3.0, 15);
+        // Evaluated dose covering only the central 5x5x5 voxels of the reference.
+        let evaluated = reference.crop([5, 5, 5], [10, 10, 10]);
+        let options = GammaOptions {
+            threshold: 0.0,
+            max_gamma: 0.5,
+            ..GammaOptions::default()
+        };
+        let r = gamma(&reference, &evaluated, &options).unwrap();
+        assert_eq!(r.evaluated, reference.len());
+        assert!(r.gamma[[0, 0, 0]].is_infinite());
+        assert_eq!(r.gamma[[7, 7, 7]], 0.0);
+        assert!(r.passed < r.evaluated);
+        assert!(r.pass_rate() < 50.0);
+        assert!(r.mean().is_finite() && r.mean() <= 0.5);
+
+        let zero = reference.map(|_| 0.0);
+        assert!(matches!(
+            gamma(&zero, &reference, &GammaOptions::default()),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn difference_and_dta_maps() {
+        let reference = gaussian(0.0, 1.0, 3.0, 15);
+        let scaled = gaussian(0.0, 1.02, 2.0, 25);
+        let diff = dose_difference(&reference, &scaled, None).unwrap();
+        let center = [7, 7, 7];
+        assert!((diff[center] - 1.2).abs() < 1e-9);
+        let local = dose_difference(&reference, &scaled, Some(Normalization::Local)).unwrap();
+        assert!((local[[3, 7, 7]] - 2.0).abs() < 0.1);
+        let global = dose_difference(
+            &reference,
+            &scaled,
+            Some(Normalization::Global(Some(120.0))),
+        )
+        .unwrap();
+        assert!((global[center] - 1.0).abs() < 1e-9);
+        for d in [0.0, -1.0, f64::NAN].iter() {
+            let normalization = Some(Normalization::Global(Some(*d)));
+            assert!(matches!(
+                dose_difference(&reference, &scaled, normalization),
+                Err(Error::InvalidArgument(_))
+            ));
+        }
+        let zero = reference.map(|_| 0.0);
+        let normalization = Some(Normalization::Global(None));
+        assert!(dose_difference(&zero, &scaled, normalization).is_err());
+        assert!(dose_difference(&zero, &scaled, None).is_ok());
+
+        let shifted = gaussian(2.0, 1.0, 2.0, 25);
+        let dta = distance_to_agreement(&reference, &shifted, &GammaOptions::default()).unwrap();
+        // On the x axis, away from the maximum, the isodoses are shifted by 2 mm.
+        assert!((dta[[3, 7, 7]] - 2.0).abs() <= 0.5);
+        assert!((dta[[11, 7, 7]] - 2.0).abs() <= 0.5);
+        assert!(dta[[0, 0, 0]].is_nan());
+        assert!(dta
+            .data()
+            .iter()
+            .all(|d| d.is_nan() || *d <= 6.0 || d.is_infinite()));
+    }
+}
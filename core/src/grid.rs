@@ -0,0 +1,437 @@
+use crate::affine::Affine3;
+use crate::coords::Vec3;
+use crate::error::{Error, Result};
+use crate::progress::Progress;
+use std::ops::{Index, IndexMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Geometry of a regular 3D grid in patient (DICOM LPS) coordinates, in mm.
+///
+/// `origin` is the position of the center of voxel (0, 0, 0) and `direction[a]` the unit
+/// vector along index axis `a`. Voxels are stored with the first index running fastest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GridGeometry {
+    pub dims: [usize; 3],
+    pub origin: Vec3<f64>,
+    pub spacing: Vec3<f64>,
+    pub direction: [Vec3<f64>; 3],
+}
+
+impl GridGeometry {
+    /// Axis-aligned geometry with identity direction cosines.
+    pub fn new(dims: [usize; 3], origin: Vec3<f64>, spacing: Vec3<f64>) -> Self {
+        Self {
+            dims,
+            origin,
+            spacing,
+            direction: [
+                Vec3::from(1.0, 0.0, 0.0),
+                Vec3::from(0.0, 1.0, 0.0),
+                Vec3::from(0.0, 0.0, 1.0),
+            ],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.dims[0] * self.dims[1] * self.dims[2]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn voxel_volume(&self) -> f64 {
+        self.spacing.x * self.spacing.y * self.spacing.z
+    }
+
+    pub fn is_axis_aligned(&self) -> bool {
+        let id = GridGeometry::new(self.dims, self.origin, self.spacing).direction;
+        self.direction
+            .iter()
+            .zip(id.iter())
+            .all(|(a, b)| a.distance(*b) < 1e-6)
+    }
+
+    pub fn contains(&self, i: usize, j: usize, k: usize) -> bool {
+        i < self.dims[0] && j < self.dims[1] && k < self.dims[2]
+    }
+
+    pub fn offset(&self, i: usize, j: usize, k: usize) -> usize {
+        i + self.dims[0] * (j + self.dims[1] * k)
+    }
+
+    pub fn ijk(&self, offset: usize) -> [usize; 3] {
+        let i = offset % self.dims[0];
+        let j = (offset / self.dims[0]) % self.dims[1];
+        let k = offset / (self.dims[0] * self.dims[1]);
+        [i, j, k]
+    }
+
+    /// Transform from continuous voxel indices to patient coordinates.
+    pub fn index_to_world(&self) -> Affine3 {
+        let s = self.spacing.to_array();
+        let mut m = [[0.0; 3]; 3];
+        for (a, d) in self.direction.iter().enumerate() {
+            let d = d.to_array();
+            for r in 0..3 {
+                m[r][a] = d[r] * s[a];
+            }
+        }
+        Affine3::from(m, self.origin)
+    }
+
+    /// Fails unless the spacing is positive and the directions span space, i.e. unless
+    /// [`GridGeometry::world_to_index`] exists; for geometries read from file headers.
+    pub fn check(&self) -> Result<()> {
+        let finite = |v: &Vec3<f64>| v.to_array().iter().all(|c| c.is_finite());
+        let spacing = self.spacing.to_array();
+        if !finite(&self.origin)
+            || !spacing.iter().all(|s| s.is_finite() && *s > 0.0)
+            || !self.direction.iter().all(finite)
+            || self.index_to_world().inverse().is_none()
+        {
+            return Err(Error::Format(format!(
+                "degenerate grid geometry: spacing {:?}, direction {:?}",
+                spacing, self.direction
+            )));
+        }
+        Ok(())
+    }
+
+    /// Transform from patient coordinates to continuous voxel indices.
+    ///
+    /// # Panics
+    ///
+    /// On a geometry rejected by [`GridGeometry::check`].
+    pub fn world_to_index(&self) -> Affine3 {
+        self.index_to_world()
+            .inverse()
+            .expect("grid spacing and direction must be non-degenerate")
+    }
+
+    pub fn position(&self, i: usize, j: usize, k: usize) -> Vec3<f64> {
+        self.index_to_world()
+            .transform_point(Vec3::from(i as f64, j as f64, k as f64))
+    }
+
+    /// Builds a geometry from an index-to-world transform, e.g. one read from a file header.
+    pub fn from_affine(dims: [usize; 3], affine: &Affine3) -> Self {
+        let m = affine.matrix;
+        let mut spacing = [0.0; 3];
+        let mut direction = [Vec3::new(); 3];
+        for a in 0..3 {
+            let col = Vec3::from(m[0][a], m[1][a], m[2][a]);
+            spacing[a] = col.norm();
+            direction[a] = col.normalize();
+        }
+        Self {
+            dims,
+            origin: affine.translation,
+            spacing: Vec3::from_array(spacing),
+            direction,
+        }
+    }
+
+    /// Grid with the same sampling resolution but only the voxels in `[lo, hi)`.
+    pub fn sub_geometry(&self, lo: [usize; 3], hi: [usize; 3]) -> Self {
+        let dims = [hi[0] - lo[0], hi[1] - lo[1], hi[2] - lo[2]];
+        let origin = self.index_to_world().transform_point(Vec3::from(
+            lo[0] as f64,
+            lo[1] as f64,
+            lo[2] as f64,
+        ));
+        Self {
+            dims,
+            origin,
+            ..self.clone()
+        }
+    }
+}
+
+/// `f` for every voxel offset of `geometry`, on slabs of voxels in parallel; `threads` 0
+/// uses the available parallelism.
+pub(crate) fn per_voxel<F: Fn(usize) -> f64 + Sync>(
+    geometry: &GridGeometry,
+    threads: usize,
+    f: F,
+) -> Vec<f64> {
+    per_voxel_with(geometry, threads, &Progress::new(), f).expect("never cancelled")
+}
+
+/// As [`per_voxel`], reporting the completed rows of voxels to `progress` and stopping once
+/// it is cancelled.
+pub(crate) fn per_voxel_with<F: Fn(usize) -> f64 + Sync>(
+    geometry: &GridGeometry,
+    threads: usize,
+    progress: &Progress,
+    f: F,
+) -> Result<Vec<f64>> {
+    let [nx, ny, nz] = geometry.dims;
+    let mut values = vec![f64::NAN; geometry.len()];
+    let threads = match threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let slab = (nx * ny * nz.div_ceil(threads)).max(1);
+    let rows = (ny * nz).max(1) as f64;
+    let done = AtomicUsize::new(0);
+    let (f, done) = (&f, &done);
+    std::thread::scope(|scope| {
+        for (chunk, out) in values.chunks_mut(slab).enumerate() {
+            scope.spawn(move || {
+                for (r, row) in out.chunks_mut(nx.max(1)).enumerate() {
+                    if progress.is_cancelled() {
+                        return;
+                    }
+                    let offset = chunk * slab + r * nx;
+                    for (m, v) in row.iter_mut().enumerate() {
+                        *v = f(offset + m);
+                    }
+                    let rows_done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                    progress.report(rows_done as f64 / rows);
+                }
+            });
+        }
+    });
+    progress.check()?;
+    Ok(values)
+}
+
+/// Regular 3D grid of voxel values (CT, dose, masks, ...).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grid3<T> {
+    geometry: GridGeometry,
+    data: Vec<T>,
+}
+
+impl<T> Grid3<T>
+where
+    T: Clone,
+{
+    pub fn new(geometry: GridGeometry, value: T) -> Self {
+        let data = vec![value; geometry.len()];
+        Self { geometry, data }
+    }
+
+    pub fn from_vec(geometry: GridGeometry, data: Vec<T>) -> Result<Self> {
+        if data.len() != geometry.len() {
+            return Err(Error::InvalidArgument(format!(
+                "grid of {:?} voxels needs {} values, got {}",
+                geometry.dims,
+                geometry.len(),
+                data.len()
+            )));
+        }
+        Ok(Self { geometry, data })
+    }
+
+    pub fn geometry(&self) -> &GridGeometry {
+        &self.geometry
+    }
+
+    pub fn dims(&self) -> [usize; 3] {
+        self.geometry.dims
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [T] {
+        &mut self.data
+    }
+
+    /// Dimensions in memory order, the slowest index first: the shape of a C-ordered
+    /// (NumPy) array view of [`Grid3::data`], indexed `[k, j, i]`.
+    pub fn shape(&self) -> [usize; 3] {
+        let [nx, ny, nz] = self.geometry.dims;
+        [nz, ny, nx]
+    }
+
+    /// Byte strides of the axes of [`Grid3::shape`], to share the data with other languages
+    /// without a copy.
+    pub fn strides(&self) -> [usize; 3] {
+        let [nx, ny, _] = self.geometry.dims;
+        let size = std::mem::size_of::<T>();
+        [nx * ny * size, nx * size, size]
+    }
+
+    pub fn into_vec(self) -> Vec<T> {
+        self.data
+    }
+
+    pub fn get(&self, i: usize, j: usize, k: usize) -> Option<&T> {
+        if self.geometry.contains(i, j, k) {
+            Some(&self.data[self.geometry.offset(i, j, k)])
+        } else {
+            None
+        }
+    }
+
+    pub fn set(&mut self, i: usize, j: usize, k: usize, value: T) {
+        let offset = self.geometry.offset(i, j, k);
+        self.data[offset] = value;
+    }
+
+    pub fn map<U, F>(&self, f: F) -> Grid3<U>
+    where
+        F: FnMut(&T) -> U,
+    {
+        Grid3 {
+            geometry: self.geometry.clone(),
+            data: self.data.iter().map(f).collect(),
+        }
+    }
+
+    /// Combines two grids with identical geometry voxel by voxel.
+    pub fn zip_map<U, V, F>(&self, other: &Grid3<U>, mut f: F) -> Result<Grid3<V>>
+    where
+        F: FnMut(&T, &U) -> V,
+    {
+        if self.geometry != other.geometry {
+            return Err(Error::InvalidArgument(format!(
+                "grid geometries differ: {:?} vs {:?}",
+                self.geometry, other.geometry
+            )));
+        }
+        Ok(Grid3 {
+            geometry: self.geometry.clone(),
+            data: self
+                .data
+                .iter()
+                .zip(other.data.iter())
+                .map(|(a, b)| f(a, b))
+                .collect(),
+        })
+    }
+
+    /// Copies the voxels in `[lo, hi)` into a new grid.
+    pub fn crop(&self, lo: [usize; 3], hi: [usize; 3]) -> Self {
+        let geometry = self.geometry.sub_geometry(lo, hi);
+        let mut data = Vec::with_capacity(geometry.len());
+        for k in lo[2]..hi[2] {
+            for j in lo[1]..hi[1] {
+                let start = self.geometry.offset(lo[0], j, k);
+                data.extend_from_slice(&self.data[start..start + geometry.dims[0]]);
+            }
+        }
+        Self { geometry, data }
+    }
+}
+
+impl<T> Index<[usize; 3]> for Grid3<T> {
+    type Output = T;
+
+    fn index(&self, ijk: [usize; 3]) -> &Self::Output {
+        &self.data[self.geometry.offset(ijk[0], ijk[1], ijk[2])]
+    }
+}
+
+impl<T> IndexMut<[usize; 3]> for Grid3<T> {
+    fn index_mut(&mut self, ijk: [usize; 3]) -> &mut Self::Output {
+        let offset = self.geometry.offset(ijk[0], ijk[1], ijk[2]);
+        &mut self.data[offset]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+
+    fn geometry() -> GridGeometry {
+        GridGeometry::new(
+            [4, 3, 2],
+            Vec3::from(-10.0, 5.0, 0.0),
+            Vec3::from(2.0, 1.0, 3.0),
+        )
+    }
+
+    #[test]
+    fn grid_geometry_offset() {
+        let g = geometry();
+        assert_eq!(g.len(), 24);
+        assert_eq!(g.offset(1, 2, 1), 1 + 4 * (2 + 3));
+        assert_eq!(g.ijk(g.offset(3, 1, 1)), [3, 1, 1]);
+    }
+
+    #[test]
+    fn grid_geometry_position() {
+        let g = geometry();
+        assert_eq!(g.position(1, 2, 1), Vec3::from(-8.0, 7.0, 3.0));
+        let idx = g
+            .world_to_index()
+            .transform_point(Vec3::from(-8.0, 7.0, 3.0));
+        assert!(idx.distance(Vec3::from(1.0, 2.0, 1.0)) < 1e-12);
+        assert!(g.is_axis_aligned());
+    }
+
+    #[test]
+    fn grid_geometry_from_affine() {
+        let g = geometry();
+        assert_eq!(GridGeometry::from_affine(g.dims, &g.index_to_world()), g);
+    }
+
+    #[test]
+    fn grid_geometry_check() {
+        assert!(geometry().check().is_ok());
+        let mut flat = geometry();
+        flat.direction[2] = flat.direction[0];
+        assert!(flat.check().is_err());
+        let mut empty = geometry();
+        empty.spacing.y = 0.0;
+        assert!(empty.check().is_err());
+        empty.spacing.y = f64::NAN;
+        assert!(empty.check().is_err());
+    }
+
+    #[test]
+    fn grid3_from_vec() {
+        assert!(Grid3::from_vec(geometry(), vec![0.0; 23]).is_err());
+        let grid = Grid3::from_vec(geometry(), (0..24).map(|v| v as f64).collect()).unwrap();
+        assert_eq!(grid[[1, 0, 0]], 1.0);
+        assert_eq!(grid.get(3, 2, 1), Some(&23.0));
+        assert_eq!(grid.get(4, 0, 0), None);
+    }
+
+    #[test]
+    fn grid3_shape_and_strides() {
+        let grid = Grid3::new(geometry(), 0.0);
+        assert_eq!(grid.shape(), [2, 3, 4]);
+        assert_eq!(grid.strides(), [96, 32, 8]);
+        let [sk, sj, si] = grid.strides();
+        let offset = (sk + 2 * sj + si) / 8;
+        assert_eq!(offset, grid.geometry().offset(1, 2, 1));
+    }
+
+    #[test]
+    fn grid3_map() {
+        let grid = Grid3::new(geometry(), 2.0);
+        let mask = grid.map(|v| *v > 1.0);
+        assert!(mask.data().iter().all(|v| *v));
+        let sum = grid.zip_map(&grid, |a, b| a + b).unwrap();
+        assert_eq!(sum[[0, 0, 0]], 4.0);
+        let mut geometry = geometry();
+        geometry.origin.x += 1.0;
+        assert!(grid
+            .zip_map(&Grid3::new(geometry, 1.0), |a, b| a + b)
+            .is_err());
+    }
+
+    #[test]
+    fn grid3_crop() {
+        let grid = Grid3::from_vec(geometry(), (0..24).collect()).unwrap();
+        let sub = grid.crop([1, 1, 1], [3, 3, 2]);
+        assert_eq!(sub.dims(), [2, 2, 1]);
+        assert_eq!(sub.data(), &[17, 18, 21, 22]);
+        assert_eq!(sub.geometry().origin, Vec3::from(-8.0, 6.0, 3.0));
+    }
+}
@@ -0,0 +1,149 @@
+//! Shape-based interpolation of contours on slices that were skipped while contouring.
+//!
+//! For every grid slice strictly between two contoured slices, the in-plane signed
+//! distance maps of both neighbours are blended linearly in z and the zero level is
+//! contoured again. Concentric shapes thus morph smoothly, and topology may change.
+
+use crate::contouring::slice_contours;
+use crate::distance::signed_distance;
+use crate::error::{Error, Result};
+use crate::grid::{Grid3, GridGeometry};
+use crate::raster::{fill_slice, group_slices, is_axial, SLICE_TOLERANCE};
+use crate::structure::{Contour, Structure};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InterpolatedStructure {
+    /// The original contours followed by the interpolated ones.
+    pub structure: Structure,
+    /// Slice positions (z, mm) that received interpolated contours.
+    pub interpolated: Vec<f64>,
+}
+
+impl InterpolatedStructure {
+    pub fn is_interpolated(&self, z: f64) -> bool {
+        self.interpolated
+            .iter()
+            .any(|s| (s - z).abs() <= SLICE_TOLERANCE)
+    }
+}
+
+/// Fills the slices of `geometry` that lie between contoured slices at most `max_gap` mm
+/// apart, so that separate parts of a structure are not bridged.
+pub fn interpolate_slices(
+    structure: &Structure,
+    geometry: &GridGeometry,
+    max_gap: f64,
+) -> Result<InterpolatedStructure> {
+    if !is_axial(geometry) {
+        return Err(Error::Unsupported(
+            "slice interpolation needs an axial grid".to_string(),
+        ));
+    }
+    let [nx, ny, nz] = geometry.dims;
+    let plane = geometry.sub_geometry([0, 0, 0], [nx, ny, 1]);
+    let distance = |contours: &[&Contour]| {
+        let mut mask = Grid3::new(plane.clone(), false);
+        fill_slice(contours, &plane, mask.data_mut());
+        signed_distance(&mask)
+    };
+    let slices = group_slices(&structure.contours);
+    let mut added = Vec::new();
+    let mut interpolated = Vec::new();
+    for pair in slices.windows(2) {
+        let ((za, a), (zb, b)) = (&pair[0], &pair[1]);
+        if zb - za > max_gap + SLICE_TOLERANCE {
+            continue;
+        }
+        let targets: Vec<usize> = (0..nz)
+            .filter(|k| {
+                let z = geometry.position(0, 0, *k).z;
+                z > za + SLICE_TOLERANCE && z < zb - SLICE_TOLERANCE
+            })
+            .collect();
+        if targets.is_empty() {
+            continue;
+        }
+        let (da, db) = (distance(a), distance(b));
+        for k in targets {
+            let z = geometry.position(0, 0, k).z;
+            let t = (z - za) / (zb - za);
+            let inside: Vec<bool> = da
+                .data()
+                .iter()
+                .zip(db.data())
+                .map(|(a, b)| (1.0 - t) * a + t * b < 0.0)
+                .collect();
+            added.extend(slice_contours(&inside, k, geometry, 0.0));
+            interpolated.push(z);
+        }
+    }
+    let mut structure = structure.clone();
+    structure.contours.extend(added);
+    Ok(InterpolatedStructure {
+        structure,
+        interpolated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use crate::affine::Affine3;
+    use crate::coords::Vec3;
+    use crate::grid::GridGeometry;
+    use crate::interpolate::interpolate_slices;
+    use crate::raster::SLICE_TOLERANCE;
+    use crate::structure::{Contour, Structure, StructureType};
+
+    fn circle(r: f64, z: f64) -> Contour {
+        Contour::new(
+            (0..90)
+                .map(|n| {
+                    let a = n as f64 * PI / 45.0;
+                    Vec3::from(r * a.cos(), r * a.sin(), z)
+                })
+                .collect(),
+        )
+    }
+
+    fn geometry() -> GridGeometry {
+        GridGeometry::new(
+            [60, 60, 15],
+            Vec3::from(-14.75, -14.75, 0.0),
+            Vec3::from(0.5, 0.5, 1.0),
+        )
+    }
+
+    #[test]
+    fn interpolate_slices_circles() {
+        let s = Structure::new("CTV", StructureType::Ctv).with_contours(vec![
+            circle(10.0, 0.0),
+            circle(6.0, 4.0),
+            circle(6.0, 14.0),
+        ]);
+        let result = interpolate_slices(&s, &geometry(), 5.0).unwrap();
+        assert_eq!(result.interpolated, vec![1.0, 2.0, 3.0]);
+        assert!(result.is_interpolated(2.0));
+        assert!(!result.is_interpolated(4.0));
+        assert_eq!(result.structure.contours.len(), 6);
+        for (n, r) in [9.0, 8.0, 7.0].iter().enumerate() {
+            let c = result
+                .structure
+                .contours_at(1.0 + n as f64, SLICE_TOLERANCE)
+                .next()
+                .unwrap();
+            let mean = c.points.iter().map(|p| p.x.hypot(p.y)).sum::<f64>() / c.len() as f64;
+            assert!((mean - r).abs() < 0.5, "radius {} at slice {}", mean, n);
+        }
+    }
+
+    #[test]
+    fn interpolate_slices_oblique() {
+        let mut g = geometry();
+        let rot = Affine3::rotation(0.3, 0.0, 0.0);
+        g.direction[2] = rot.transform_vector(g.direction[2]);
+        let s = Structure::new("CTV", StructureType::Ctv).with_contours(vec![circle(1.0, 0.0)]);
+        assert!(interpolate_slices(&s, &g, 5.0).is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! Loaders for measured beam data: w2CAD-style ASCII scan files and plain CSV tables.
+//!
+//! A w2CAD file holds blocks between `$STOM` and `$ENOM`, each with `%KEY value` headers and
+//! one `<x y z value>` point per line. Depth doses have `%TYPE OPD`, profiles `%TYPE OPP`
+//! with `%AXIS X` (crossline) or `Y` (inline). Lengths are in mm and `%FLSZ` reads `a*b`.
+//! CSV tables hold one point per line, separated by commas, semicolons, tabs or spaces;
+//! lines starting with `#` and a leading header line are skipped.
+
+use crate::dose::commissioning::{Axis, Measurement, OutputFactors};
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+fn numbers(line: &str) -> Option<Vec<f64>> {
+    line.split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<f64>().ok())
+        .collect()
+}
+
+/// Numeric rows of `text`, with at least `columns` columns each.
+fn rows(text: &str, columns: usize) -> Result<Vec<Vec<f64>>> {
+    let mut rows = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match numbers(line) {
+            Some(values) if values.len() >= columns => rows.push(values),
+            _ if rows.is_empty() => continue,
+            _ => {
+                return Err(Error::Format(format!(
+                    "line {}: expected {} numbers in '{}'",
+                    n + 1,
+                    columns,
+                    line
+                )))
+            }
+        }
+    }
+    Ok(rows)
+}
+
+/// One scan from a two-column CSV table of position and reading.
+pub fn parse_csv(
+    text: &str,
+    axis: Axis,
+    field_size: [f64; 2],
+    ssd: f64,
+    depth: Option<f64>,
+) -> Result<Measurement> {
+    let points = rows(text, 2)?.iter().map(|r| (r[0], r[1])).collect();
+    Measurement::new(axis, field_size, ssd, depth, points)
+}
+
+/// Output factors at `depth` from a two-column CSV table of square field side and factor.
+pub fn parse_output_factors(text: &str, depth: f64) -> Result<OutputFactors> {
+    let mut points: Vec<(f64, f64)> = rows(text, 2)?.iter().map(|r| (r[0], r[1])).collect();
+    if points.is_empty() {
+        return Err(Error::Format("no output factors".to_string()));
+    }
+    points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    Ok(OutputFactors {
+        depth,
+        sides: points.iter().map(|p| p.0).collect(),
+        values: points.iter().map(|p| p.1).collect(),
+    })
+}
+
+/// `%KEY value` headers and `<x y z value>` points of a w2CAD scan.
+type W2cadBlock = (HashMap<String, String>, Vec<[f64; 4]>);
+
+fn w2cad_block(headers: &HashMap<String, String>, points: &[[f64; 4]]) -> Result<Measurement> {
+    let header = |key: &str| {
+        headers
+            .get(key)
+            .ok_or_else(|| Error::Format(format!("w2CAD scan without %{}", key)))
+    };
+    let number = |key: &str| -> Result<f64> {
+        header(key)?
+            .parse::<f64>()
+            .map_err(|_| Error::Format(format!("w2CAD %{} is not a number", key)))
+    };
+    let size: Vec<f64> = header("FLSZ")?
+        .split('*')
+        .map(|s| s.trim().parse::<f64>())
+        .collect::<std::result::Result<_, _>>()
+        .map_err(|_| Error::Format("w2CAD %FLSZ is not 'a*b'".to_string()))?;
+    if size.len() != 2 {
+        return Err(Error::Format("w2CAD %FLSZ is not 'a*b'".to_string()));
+    }
+    let ssd = number("SSD")?;
+    let (axis, depth) = match (header("TYPE")?.as_str(), header("AXIS")?.as_str()) {
+        ("OPD", _) => (Axis::Depth, None),
+        ("OPP", "X") => (Axis::Crossline, Some(number("DPTH")?)),
+        ("OPP", "Y") => (Axis::Inline, Some(number("DPTH")?)),
+        (kind, axis) => {
+            return Err(Error::Unsupported(format!(
+                "w2CAD scan %TYPE {} along %AXIS {}",
+                kind, axis
+            )))
+        }
+    };
+    let coordinate = match axis {
+        Axis::Crossline => 0,
+        Axis::Inline => 1,
+        Axis::Depth => 2,
+    };
+    let points = points.iter().map(|p| (p[coordinate], p[3])).collect();
+    Measurement::new(axis, [size[0], size[1]], ssd, depth, points)
+}
+
+/// All scans of a w2CAD file.
+pub fn parse_w2cad(text: &str) -> Result<Vec<Measurement>> {
+    let mut scans = Vec::new();
+    let mut block: Option<W2cadBlock> = None;
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with("$STOM") {
+            block = Some((HashMap::new(), Vec::new()));
+        } else if line.starts_with("$ENOM") {
+            match block.take() {
+                Some((headers, points)) => scans.push(w2cad_block(&headers, &points)?),
+                None => {
+                    return Err(Error::Format(format!(
+                        "line {}: $ENOM outside a scan",
+                        n + 1
+                    )))
+                }
+            }
+        } else if let Some((headers, points)) = block.as_mut() {
+            if let Some(rest) = line.strip_prefix('%') {
+                let mut parts = rest.splitn(2, char::is_whitespace);
+                let key = parts.next().unwrap_or_default().to_ascii_uppercase();
+                let value = parts.next().unwrap_or_default().trim().to_string();
+                headers.insert(key, value);
+            } else if line.starts_with('<') && line.ends_with('>') {
+                match numbers(&line[1..line.len() - 1]) {
+                    Some(v) if v.len() == 4 => points.push([v[0], v[1], v[2], v[3]]),
+                    _ => {
+                        return Err(Error::Format(format!(
+                            "line {}: malformed w2CAD point '{}'",
+                            n + 1,
+                            line
+                        )))
+                    }
+                }
+            }
+        }
+    }
+    if block.is_some() {
+        return Err(Error::Format("w2CAD scan without $ENOM".to_string()));
+    }
+    Ok(scans)
+}
+
+pub fn read_w2cad<P: AsRef<Path>>(path: P) -> Result<Vec<Measurement>> {
+    parse_w2cad(&std::fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dose::commissioning::Axis;
+    use crate::io::beam_data::{parse_csv, parse_output_factors, parse_w2cad};
+
+    const W2CAD: &str = "$NUMS 002
+$STOM
+%VERSION 02
+%BMTY PHO
+%FLSZ 100*100
+%TYPE OPD
+%AXIS Z
+%SSD 900
+<+000.0 +000.0 +000.0 +050.0>
+<+000.0 +000.0 +015.0 +100.0>
+<+000.0 +000.0 +100.0 +067.0>
+$ENOM
+$STOM
+%FLSZ 100*200
+%TYPE OPP
+%AXIS Y
+%SSD 900
+%DPTH 100
+<+000.0 -060.0 +100.0 +005.0>
+<+000.0 +000.0 +100.0 +100.0>
+<+000.0 +060.0 +100.0 +004.0>
+$ENOM
+$ENOD
+";
+
+    #[test]
+    fn w2cad() {
+        let scans = parse_w2cad(W2CAD).unwrap();
+        assert_eq!(scans.len(), 2);
+        assert_eq!(scans[0].axis, Axis::Depth);
+        assert_eq!(scans[0].positions, vec![0.0, 15.0, 100.0]);
+        assert_eq!(scans[0].ssd, 900.0);
+        assert_eq!(scans[1].axis, Axis::Inline);
+        assert_eq!(scans[1].field_size, [100.0, 200.0]);
+        assert_eq!(scans[1].depth, Some(100.0));
+        assert_eq!(scans[1].values, vec![5.0, 100.0, 4.0]);
+        assert!(parse_w2cad("$STOM\n%FLSZ 10*10\n").is_err());
+        assert!(parse_w2cad(&W2CAD.replace("OPP", "ABS")).is_err());
+    }
+
+    #[test]
+    fn csv() {
+        let pdd = parse_csv(
+            "depth (mm),dose\n# water tank\n0,50\n15;100\n100\t67\n",
+            Axis::Depth,
+            [100.0, 100.0],
+            1000.0,
+            None,
+        )
+        .unwrap();
+        assert_eq!(pdd.values, vec![50.0, 100.0, 67.0]);
+        assert!(parse_csv("0,1\n1,x\n", Axis::Depth, [1.0, 1.0], 1.0, None).is_err());
+        let of = parse_output_factors("side,of\n100 1.0\n30 0.88\n", 100.0).unwrap();
+        assert_eq!(of.sides, vec![30.0, 100.0]);
+        assert!(parse_output_factors("", 100.0).is_err());
+    }
+}
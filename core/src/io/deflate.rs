@@ -0,0 +1,555 @@
+//! DEFLATE (RFC 1951) with zlib (RFC 1950) and gzip (RFC 1952) framing.
+//!
+//! Decompression supports all block types. Compression emits a single fixed-Huffman block
+//! with greedy LZ77 matching, which is simple and compresses masks and dose grids well.
+
+use crate::error::{Error, Result};
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn corrupt(msg: &str) -> Error {
+    Error::Format(format!("deflate stream: {}", msg))
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+    nbits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            bit: 0,
+            nbits: 0,
+        }
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32> {
+        while self.nbits < n {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| corrupt("unexpected end"))?;
+            self.pos += 1;
+            self.bit |= (byte as u32) << self.nbits;
+            self.nbits += 8;
+        }
+        let v = self.bit & ((1u32 << n) - 1);
+        self.bit >>= n;
+        self.nbits -= n;
+        Ok(v)
+    }
+
+    fn align(&mut self) {
+        self.bit = 0;
+        self.nbits = 0;
+    }
+}
+
+/// Canonical Huffman decoding table (counts per length and symbols ordered by code).
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut counts = [0u16; 16];
+        for &l in lengths {
+            counts[l as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for l in 1..15 {
+            offsets[l + 1] = offsets[l] + counts[l];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (s, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                symbols[offsets[l as usize] as usize] = s as u16;
+                offsets[l as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, r: &mut BitReader) -> Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= r.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err(corrupt("invalid Huffman code"))
+    }
+}
+
+fn fixed_tables() -> Result<(Huffman, Huffman)> {
+    let mut lengths = [0u8; 288];
+    for (s, l) in lengths.iter_mut().enumerate() {
+        *l = match s {
+            0..=143 => 8,
+            144..=255 => 9,
+            256..=279 => 7,
+            _ => 8,
+        };
+    }
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5u8; 30])?))
+}
+
+fn dynamic_tables(r: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let hlit = r.bits(5)? as usize + 257;
+    let hdist = r.bits(5)? as usize + 1;
+    let hclen = r.bits(4)? as usize + 4;
+    let mut cl_lengths = [0u8; 19];
+    for &idx in CODE_LENGTH_ORDER.iter().take(hclen) {
+        cl_lengths[idx] = r.bits(3)? as u8;
+    }
+    let cl = Huffman::new(&cl_lengths)?;
+    let mut lengths = Vec::with_capacity(hlit + hdist);
+    while lengths.len() < hlit + hdist {
+        let sym = cl.decode(r)?;
+        match sym {
+            0..=15 => lengths.push(sym as u8),
+            16 => {
+                let prev = *lengths
+                    .last()
+                    .ok_or_else(|| corrupt("repeat without length"))?;
+                let n = 3 + r.bits(2)? as usize;
+                lengths.extend(std::iter::repeat_n(prev, n));
+            }
+            17 => {
+                let n = 3 + r.bits(3)? as usize;
+                lengths.extend(std::iter::repeat_n(0, n));
+            }
+            _ => {
+                let n = 11 + r.bits(7)? as usize;
+                lengths.extend(std::iter::repeat_n(0, n));
+            }
+        }
+    }
+    if lengths.len() > hlit + hdist {
+        return Err(corrupt("code lengths overflow"));
+    }
+    Ok((
+        Huffman::new(&lengths[..hlit])?,
+        Huffman::new(&lengths[hlit..])?,
+    ))
+}
+
+/// Decompresses a raw DEFLATE stream; returns the data and the number of input bytes used.
+pub fn inflate(data: &[u8]) -> Result<(Vec<u8>, usize)> {
+    let mut r = BitReader::new(data);
+    let mut out = Vec::with_capacity(data.len() * 4);
+    loop {
+        let last = r.bits(1)? == 1;
+        match r.bits(2)? {
+            0 => {
+                r.align();
+                let header = data
+                    .get(r.pos..r.pos + 4)
+                    .ok_or_else(|| corrupt("truncated stored block"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+                let nlen = u16::from_le_bytes([header[2], header[3]]) as usize;
+                if len != !nlen & 0xffff {
+                    return Err(corrupt("stored block length mismatch"));
+                }
+                r.pos += 4;
+                let block = data
+                    .get(r.pos..r.pos + len)
+                    .ok_or_else(|| corrupt("truncated stored block"))?;
+                out.extend_from_slice(block);
+                r.pos += len;
+            }
+            btype @ 1..=2 => {
+                let (lit, dist) = if btype == 1 {
+                    fixed_tables()?
+                } else {
+                    dynamic_tables(&mut r)?
+                };
+                loop {
+                    let sym = lit.decode(&mut r)? as usize;
+                    if sym < 256 {
+                        out.push(sym as u8);
+                    } else if sym == 256 {
+                        break;
+                    } else {
+                        let i = sym - 257;
+                        if i >= LENGTH_BASE.len() {
+                            return Err(corrupt("invalid length symbol"));
+                        }
+                        let len =
+                            LENGTH_BASE[i] as usize + r.bits(LENGTH_EXTRA[i] as u32)? as usize;
+                        let d = dist.decode(&mut r)? as usize;
+                        if d >= DIST_BASE.len() {
+                            return Err(corrupt("invalid distance symbol"));
+                        }
+                        let d = DIST_BASE[d] as usize + r.bits(DIST_EXTRA[d] as u32)? as usize;
+                        if d > out.len() {
+                            return Err(corrupt("distance too far back"));
+                        }
+                        let start = out.len() - d;
+                        for n in 0..len {
+                            let b = out[start + n];
+                            out.push(b);
+                        }
+                    }
+                }
+            }
+            _ => return Err(corrupt("invalid block type")),
+        }
+        if last {
+            break;
+        }
+    }
+    Ok((out, r.pos))
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    bit: u32,
+    nbits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, n: u32) {
+        self.bit |= value << self.nbits;
+        self.nbits += n;
+        while self.nbits >= 8 {
+            self.out.push(self.bit as u8);
+            self.bit >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    /// Huffman codes are stored most significant bit first.
+    fn write_code(&mut self, code: u32, n: u32) {
+        let reversed = code.reverse_bits() >> (32 - n);
+        self.write(reversed, n);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.nbits > 0 {
+            self.out.push(self.bit as u8);
+        }
+        self.out
+    }
+
+    fn literal(&mut self, sym: u32) {
+        match sym {
+            0..=143 => self.write_code(0x30 + sym, 8),
+            144..=255 => self.write_code(0x190 + sym - 144, 9),
+            256..=279 => self.write_code(sym - 256, 7),
+            _ => self.write_code(0xc0 + sym - 280, 8),
+        }
+    }
+
+    fn length(&mut self, len: usize) {
+        let i = LENGTH_BASE
+            .iter()
+            .rposition(|&b| b as usize <= len)
+            .unwrap();
+        self.literal(257 + i as u32);
+        self.write(
+            (len - LENGTH_BASE[i] as usize) as u32,
+            LENGTH_EXTRA[i] as u32,
+        );
+    }
+
+    fn distance(&mut self, d: usize) {
+        let i = DIST_BASE.iter().rposition(|&b| b as usize <= d).unwrap();
+        self.write_code(i as u32, 5);
+        self.write((d - DIST_BASE[i] as usize) as u32, DIST_EXTRA[i] as u32);
+    }
+}
+
+const WINDOW: usize = 32768;
+const HASH_BITS: u32 = 15;
+const MAX_CHAIN: usize = 64;
+
+fn hash3(data: &[u8], i: usize) -> usize {
+    let v = (data[i] as u32) << 16 | (data[i + 1] as u32) << 8 | data[i + 2] as u32;
+    (v.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Compresses `data` into a raw DEFLATE stream.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter {
+        out: Vec::with_capacity(data.len() / 4 + 16),
+        bit: 0,
+        nbits: 0,
+    };
+    w.write(1, 1);
+    w.write(1, 2);
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW];
+    let insert = |head: &mut Vec<usize>, prev: &mut Vec<usize>, i: usize| {
+        if i + 2 < data.len() {
+            let h = hash3(data, i);
+            prev[i % WINDOW] = head[h];
+            head[h] = i;
+        }
+    };
+    let mut i = 0;
+    while i < data.len() {
+        let (mut best_len, mut best_dist) = (0, 0);
+        if i + 2 < data.len() {
+            let mut cand = head[hash3(data, i)];
+            let max_len = (data.len() - i).min(258);
+            let mut chain = 0;
+            while cand != usize::MAX && i - cand <= WINDOW && chain < MAX_CHAIN {
+                let len = data[cand..]
+                    .iter()
+                    .zip(data[i..i + max_len].iter())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    best_len = len;
+                    best_dist = i - cand;
+                    if len == max_len {
+                        break;
+                    }
+                }
+                let next = prev[cand % WINDOW];
+                if next == usize::MAX || next >= cand {
+                    break;
+                }
+                cand = next;
+                chain += 1;
+            }
+        }
+        if best_len >= 3 {
+            w.length(best_len);
+            w.distance(best_dist);
+            for n in 0..best_len {
+                insert(&mut head, &mut prev, i + n);
+            }
+            i += best_len;
+        } else {
+            w.literal(data[i] as u32);
+            insert(&mut head, &mut prev, i);
+            i += 1;
+        }
+    }
+    w.literal(256);
+    w.finish()
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+        }
+        *entry = c;
+    }
+    !data.iter().fold(!0u32, |c, &b| {
+        table[((c ^ b as u32) & 0xff) as usize] ^ (c >> 8)
+    })
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.len() >= 2 && data[0] == 0x1f && data[1] == 0x8b
+}
+
+pub fn gzip_compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    out.extend(deflate(data));
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Decompresses a gzip file, including files made of several concatenated members.
+pub fn gzip_decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let member = &data[pos..];
+        if member.len() < 18 || !is_gzip(member) || member[2] != 8 {
+            return Err(Error::Format("not a gzip (deflate) stream".to_string()));
+        }
+        let flags = member[3];
+        let mut p = 10;
+        if flags & 4 != 0 {
+            let xlen = u16::from_le_bytes([member[p], member[p + 1]]) as usize;
+            p += 2 + xlen;
+        }
+        for flag in [8u8, 16].iter() {
+            if flags & flag != 0 {
+                let end = member[p.min(member.len())..]
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or_else(|| Error::Format("truncated gzip header".to_string()))?;
+                p += end + 1;
+            }
+        }
+        if flags & 2 != 0 {
+            p += 2;
+        }
+        let (block, used) = inflate(member.get(p..).unwrap_or(&[]))?;
+        p += used;
+        let trailer = member
+            .get(p..p + 8)
+            .ok_or_else(|| Error::Format("truncated gzip trailer".to_string()))?;
+        let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        if crc != crc32(&block) {
+            return Err(Error::Format("gzip CRC mismatch".to_string()));
+        }
+        out.extend(block);
+        pos += p + 8;
+        while pos < data.len() && data[pos] == 0 {
+            pos += 1;
+        }
+    }
+    Ok(out)
+}
+
+pub fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    out.extend(deflate(data));
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+pub fn zlib_decompress(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < 6
+        || data[0] & 0x0f != 8
+        || (u16::from(data[0]) << 8 | u16::from(data[1])) % 31 != 0
+    {
+        return Err(Error::Format("not a zlib stream".to_string()));
+    }
+    if data[1] & 0x20 != 0 {
+        return Err(Error::Unsupported("zlib preset dictionary".to_string()));
+    }
+    let (out, used) = inflate(&data[2..])?;
+    let trailer = data
+        .get(2 + used..2 + used + 4)
+        .ok_or_else(|| Error::Format("truncated zlib trailer".to_string()))?;
+    if u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]) != adler32(&out) {
+        return Err(Error::Format("zlib checksum mismatch".to_string()));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::deflate::{
+        adler32, crc32, deflate, gzip_compress, gzip_decompress, inflate, zlib_compress,
+        zlib_decompress,
+    };
+
+    fn sample() -> Vec<u8> {
+        let mut data: Vec<u8> = b"the quick brown fox jumps over the lazy dog ".repeat(50);
+        data.extend((0..5000u32).map(|v| (v * v % 251) as u8));
+        data.extend(vec![0u8; 70000]);
+        data
+    }
+
+    #[test]
+    fn deflate_roundtrip() {
+        let data = sample();
+        let compressed = deflate(&data);
+        assert!(compressed.len() < data.len() / 4);
+        let (out, used) = inflate(&compressed).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(used, compressed.len());
+        assert_eq!(inflate(&deflate(&[])).unwrap().0, Vec::<u8>::new());
+    }
+
+    #[test]
+    fn inflate_stored_and_dynamic() {
+        // Stored block containing "abc".
+        let stored = [1u8, 3, 0, 0xfc, 0xff, b'a', b'b', b'c'];
+        assert_eq!(inflate(&stored).unwrap().0, b"abc");
+        // zlib (level 9) output for 200 bytes of skewed text, using a dynamic Huffman block.
+        let dynamic = [
+            0x78, 0xda, 0x2d, 0x8e, 0xd1, 0x15, 0x00, 0x20, 0x08, 0x02, 0x67, 0xf5, 0x60, 0xff,
+            0x19, 0x02, 0xad, 0x0f, 0xe4, 0x01, 0xa1, 0x83, 0xa4, 0xc9, 0x0b, 0x30, 0xcb, 0x5c,
+            0x1a, 0x91, 0x8e, 0xa0, 0x2b, 0xd2, 0x59, 0xe5, 0x52, 0xcc, 0x8f, 0xc7, 0x94, 0x71,
+            0x1d, 0x56, 0x37, 0x90, 0xa4, 0xbc, 0x11, 0x8f, 0x68, 0xcb, 0x56, 0xed, 0x7f, 0x23,
+            0xb8, 0x42, 0x7d, 0x67, 0xfb, 0xa3, 0x72, 0x0b, 0xaa, 0x5b, 0x4b, 0x8b, 0x29, 0xab,
+            0x35, 0xb9, 0xd3, 0xdd, 0x81, 0xb8, 0x7b, 0x2f, 0xaf, 0xbb, 0xee, 0x01, 0x02, 0x80,
+            0x4c, 0x6c,
+        ];
+        let expected =
+            "abcccaaaacaabacaaaadcaabccabaabcabadaaaabbadabaababacaabaaabacaadaacdbdbaab\
+                        bcaabadbbbdabcdbaaabdacbabcaaabcaabaabdbcbbaaabbacabcaaabaaaabcbbbabaabaac\
+                        abdcbaabacbaadabbbbaacccdbbcabcbaaaacabbabaacaaaabb";
+        assert_eq!(zlib_decompress(&dynamic).unwrap(), expected.as_bytes());
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn gzip_roundtrip() {
+        let data = sample();
+        let gz = gzip_compress(&data);
+        assert_eq!(gzip_decompress(&gz).unwrap(), data);
+        let mut two = gz.clone();
+        two.extend(gzip_compress(b"tail"));
+        let mut expected = data;
+        expected.extend_from_slice(b"tail");
+        assert_eq!(gzip_decompress(&two).unwrap(), expected);
+        assert!(gzip_decompress(b"plain").is_err());
+    }
+
+    #[test]
+    fn zlib_roundtrip() {
+        let data = sample();
+        assert_eq!(zlib_decompress(&zlib_compress(&data)).unwrap(), data);
+        let mut corrupt = zlib_compress(&data);
+        let n = corrupt.len();
+        corrupt[n - 1] ^= 1;
+        assert!(zlib_decompress(&corrupt).is_err());
+    }
+}
@@ -0,0 +1,321 @@
+//! CSV and JSON export of cumulative DVHs and dose metric tables.
+//!
+//! CSV files are in long format (one row per DVH point or metric) and start with `#`
+//! comment lines holding the provenance. The JSON document holds the provenance, the units,
+//! every DVH curve with its summary statistics and the metric table.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::dvh::{Dvh, VolumeUnit};
+use crate::error::Result;
+use crate::grid::GridGeometry;
+use crate::metric::{DoseUnit, Metric, MetricValue};
+
+/// Where the exported values come from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExportProvenance {
+    pub plan_uid: Option<String>,
+    pub dose_uid: Option<String>,
+    /// Grid the DVHs were computed on.
+    pub geometry: Option<GridGeometry>,
+}
+
+/// One row of a metric table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricRecord {
+    pub structure: String,
+    pub metric: Metric,
+    pub value: MetricValue,
+}
+
+fn dose_unit(unit: DoseUnit) -> &'static str {
+    match unit {
+        DoseUnit::Gy => "Gy",
+        DoseUnit::Percent => "%",
+    }
+}
+
+fn volume_unit(unit: VolumeUnit) -> &'static str {
+    match unit {
+        VolumeUnit::Cc => "cc",
+        VolumeUnit::Percent => "%",
+    }
+}
+
+fn value_unit(value: &MetricValue) -> &'static str {
+    match value {
+        MetricValue::Dose { unit, .. } => dose_unit(*unit),
+        MetricValue::Volume { unit, .. } => volume_unit(*unit),
+    }
+}
+
+/// `s` as a CSV field, quoted when it holds a comma, quote or line break.
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn csv_provenance(out: &mut String, provenance: &ExportProvenance) {
+    if let Some(uid) = &provenance.plan_uid {
+        writeln!(out, "# plan_uid: {}", uid).unwrap();
+    }
+    if let Some(uid) = &provenance.dose_uid {
+        writeln!(out, "# dose_uid: {}", uid).unwrap();
+    }
+    if let Some(g) = &provenance.geometry {
+        writeln!(
+            out,
+            "# grid: dims {} {} {}, origin {} {} {} mm, spacing {} {} {} mm",
+            g.dims[0],
+            g.dims[1],
+            g.dims[2],
+            g.origin.x,
+            g.origin.y,
+            g.origin.z,
+            g.spacing.x,
+            g.spacing.y,
+            g.spacing.z
+        )
+        .unwrap();
+    }
+}
+
+/// Cumulative DVHs with columns `structure,dose_gy,volume_<unit>`.
+pub fn dvh_csv(dvhs: &[Dvh], unit: VolumeUnit, provenance: &ExportProvenance) -> String {
+    let mut out = String::new();
+    csv_provenance(&mut out, provenance);
+    let column = match unit {
+        VolumeUnit::Cc => "cc",
+        VolumeUnit::Percent => "percent",
+    };
+    writeln!(out, "structure,dose_gy,volume_{}", column).unwrap();
+    for dvh in dvhs {
+        let name = csv_field(&dvh.name);
+        for (d, v) in dvh.cumulative(unit) {
+            writeln!(out, "{},{},{}", name, d, v).unwrap();
+        }
+    }
+    out
+}
+
+/// Metric table with columns `structure,metric,value,unit`.
+pub fn metrics_csv(metrics: &[MetricRecord], provenance: &ExportProvenance) -> String {
+    let mut out = String::new();
+    csv_provenance(&mut out, provenance);
+    out.push_str("structure,metric,value,unit\n");
+    for m in metrics {
+        writeln!(
+            out,
+            "{},{},{},{}",
+            csv_field(&m.structure),
+            csv_field(&m.metric.to_string()),
+            m.value.value(),
+            value_unit(&m.value)
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// `s` as a quoted JSON string.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Non-finite numbers have no JSON representation and become `null`.
+pub fn json_number(v: f64) -> String {
+    if v.is_finite() {
+        v.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+fn json_numbers<I: IntoIterator<Item = f64>>(values: I) -> String {
+    let items: Vec<String> = values.into_iter().map(json_number).collect();
+    format!("[{}]", items.join(","))
+}
+
+fn json_option(s: &Option<String>) -> String {
+    s.as_deref().map_or("null".to_string(), json_string)
+}
+
+/// Provenance, units, DVH curves (volumes in `unit`) and metrics as one JSON document.
+pub fn json(
+    dvhs: &[Dvh],
+    unit: VolumeUnit,
+    metrics: &[MetricRecord],
+    provenance: &ExportProvenance,
+) -> String {
+    let mut out = String::new();
+    out.push_str("{\n  \"provenance\": {");
+    write!(
+        out,
+        "\"plan_uid\": {}, \"dose_uid\": {}, \"grid\": ",
+        json_option(&provenance.plan_uid),
+        json_option(&provenance.dose_uid)
+    )
+    .unwrap();
+    match &provenance.geometry {
+        Some(g) => write!(
+            out,
+            "{{\"dims\": [{},{},{}], \"origin_mm\": {}, \"spacing_mm\": {}, \"direction\": [{}]}}",
+            g.dims[0],
+            g.dims[1],
+            g.dims[2],
+            json_numbers(g.origin.to_array().iter().cloned()),
+            json_numbers(g.spacing.to_array().iter().cloned()),
+            g.direction
+                .iter()
+                .map(|d| json_numbers(d.to_array().iter().cloned()))
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+        .unwrap(),
+        None => out.push_str("null"),
+    }
+    write!(
+        out,
+        "}},\n  \"units\": {{\"dose\": \"Gy\", \"volume\": {}}},\n  \"dvhs\": [",
+        json_string(volume_unit(unit))
+    )
+    .unwrap();
+    for (n, dvh) in dvhs.iter().enumerate() {
+        let curve = dvh.cumulative(unit);
+        write!(
+            out,
+            "{}\n    {{\"structure\": {}, \"volume_cc\": {}, \"min_gy\": {}, \"max_gy\": {}, \
+             \"mean_gy\": {}, \"bin_width_gy\": {}, \"dose\": {}, \"volume\": {}}}",
+            if n == 0 { "" } else { "," },
+            json_string(&dvh.name),
+            json_number(dvh.volume),
+            json_number(dvh.min),
+            json_number(dvh.max),
+            json_number(dvh.mean),
+            json_number(dvh.bin_width),
+            json_numbers(curve.iter().map(|(d, _)| *d)),
+            json_numbers(curve.iter().map(|(_, v)| *v))
+        )
+        .unwrap();
+    }
+    out.push_str(if dvhs.is_empty() { "],\n" } else { "\n  ],\n" });
+    out.push_str("  \"metrics\": [");
+    for (n, m) in metrics.iter().enumerate() {
+        write!(
+            out,
+            "{}\n    {{\"structure\": {}, \"metric\": {}, \"value\": {}, \"unit\": {}}}",
+            if n == 0 { "" } else { "," },
+            json_string(&m.structure),
+            json_string(&m.metric.to_string()),
+            json_number(m.value.value()),
+            json_string(value_unit(&m.value))
+        )
+        .unwrap();
+    }
+    out.push_str(if metrics.is_empty() {
+        "]\n}\n"
+    } else {
+        "\n  ]\n}\n"
+    });
+    out
+}
+
+pub fn write_dvh_csv<P: AsRef<Path>>(
+    path: P,
+    dvhs: &[Dvh],
+    unit: VolumeUnit,
+    provenance: &ExportProvenance,
+) -> Result<()> {
+    std::fs::write(path, dvh_csv(dvhs, unit, provenance))?;
+    Ok(())
+}
+
+pub fn write_metrics_csv<P: AsRef<Path>>(
+    path: P,
+    metrics: &[MetricRecord],
+    provenance: &ExportProvenance,
+) -> Result<()> {
+    std::fs::write(path, metrics_csv(metrics, provenance))?;
+    Ok(())
+}
+
+pub fn write_json<P: AsRef<Path>>(
+    path: P,
+    dvhs: &[Dvh],
+    unit: VolumeUnit,
+    metrics: &[MetricRecord],
+    provenance: &ExportProvenance,
+) -> Result<()> {
+    std::fs::write(path, json(dvhs, unit, metrics, provenance))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dvh::{Dvh, VolumeUnit};
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::io::dvh::{dvh_csv, json, metrics_csv, ExportProvenance, MetricRecord};
+    use crate::metric::{DoseUnit, Metric, MetricValue};
+
+    #[test]
+    fn dvh_export() {
+        let geometry = GridGeometry::new([2, 1, 1], Vec3::new(), Vec3::from(10.0, 10.0, 10.0));
+        let dose = Grid3::from_vec(geometry.clone(), vec![0.5, 1.5]).unwrap();
+        let dvh = Dvh::from_fraction("PTV, boost", &dose.map(|_| 1.0), &dose, 1.0).unwrap();
+        let provenance = ExportProvenance {
+            plan_uid: Some("1.2.3".to_string()),
+            dose_uid: None,
+            geometry: Some(geometry),
+        };
+        assert_eq!(
+            dvh_csv(std::slice::from_ref(&dvh), VolumeUnit::Cc, &provenance),
+            "# plan_uid: 1.2.3\n\
+             # grid: dims 2 1 1, origin 0 0 0 mm, spacing 10 10 10 mm\n\
+             structure,dose_gy,volume_cc\n\
+             \"PTV, boost\",0,2\n\"PTV, boost\",1,1\n\"PTV, boost\",2,0\n"
+        );
+        let metrics = [MetricRecord {
+            structure: "PTV".to_string(),
+            metric: Metric::Mean(DoseUnit::Gy),
+            value: MetricValue::Dose {
+                value: 1.0,
+                unit: DoseUnit::Gy,
+            },
+        }];
+        assert_eq!(
+            metrics_csv(&metrics, &ExportProvenance::default()),
+            "structure,metric,value,unit\nPTV,Dmean[Gy],1,Gy\n"
+        );
+        let doc = json(&[dvh], VolumeUnit::Percent, &metrics, &provenance);
+        assert!(doc.contains("\"plan_uid\": \"1.2.3\", \"dose_uid\": null"));
+        assert!(doc.contains("\"dims\": [2,1,1], \"origin_mm\": [0,0,0]"));
+        assert!(doc.contains("\"units\": {\"dose\": \"Gy\", \"volume\": \"%\"}"));
+        assert!(doc.contains("\"dose\": [0,1,2], \"volume\": [100,50,0]"));
+        assert!(doc.contains("\"metric\": \"Dmean[Gy]\", \"value\": 1, \"unit\": \"Gy\""));
+        assert_eq!(
+            json(&[], VolumeUnit::Cc, &[], &ExportProvenance::default()),
+            "{\n  \"provenance\": {\"plan_uid\": null, \"dose_uid\": null, \"grid\": null},\n  \
+             \"units\": {\"dose\": \"Gy\", \"volume\": \"cc\"},\n  \"dvhs\": [],\n  \
+             \"metrics\": []\n}\n"
+        );
+    }
+}
@@ -0,0 +1,400 @@
+//! IAEA phase-space files: a text `.IAEAheader` describing the record layout and a binary
+//! `.IAEAphsp` file with one fixed-length record per particle.
+//!
+//! Units follow the format: energies in MeV, positions in cm.
+
+use crate::coords::Vec3;
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParticleType {
+    Photon,
+    Electron,
+    Positron,
+    Neutron,
+    Proton,
+}
+
+impl ParticleType {
+    fn from_code(code: i8) -> Result<Self> {
+        Ok(match code.unsigned_abs() {
+            1 => ParticleType::Photon,
+            2 => ParticleType::Electron,
+            3 => ParticleType::Positron,
+            4 => ParticleType::Neutron,
+            5 => ParticleType::Proton,
+            _ => return Err(Error::Format(format!("IAEA particle type {}", code))),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Particle {
+    pub particle_type: ParticleType,
+    /// Kinetic energy in MeV.
+    pub energy: f64,
+    /// Position in cm.
+    pub position: Vec3<f64>,
+    /// Unit direction (u, v, w).
+    pub direction: Vec3<f64>,
+    pub weight: f64,
+    /// The particle starts a new primary history.
+    pub new_history: bool,
+    pub extra_floats: Vec<f32>,
+    pub extra_longs: Vec<i32>,
+}
+
+/// Index of each record field in `PhaseSpaceHeader::stored` and `constants`.
+const FIELDS: [&str; 7] = ["X", "Y", "Z", "U", "V", "W", "Weight"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhaseSpaceHeader {
+    pub title: String,
+    pub big_endian: bool,
+    pub record_length: usize,
+    /// Whether x, y, z, u, v, w and weight are stored per record.
+    pub stored: [bool; 7],
+    /// Values of the fields that are not stored (x, y, z, u, v, w, weight).
+    pub constants: [f64; 7],
+    pub extra_floats: usize,
+    pub extra_longs: usize,
+    pub particles: Option<u64>,
+    pub original_histories: Option<u64>,
+    /// All `$SECTION:` entries of the header, verbatim.
+    pub sections: HashMap<String, String>,
+}
+
+fn leading_number(line: &str) -> Option<&str> {
+    line.split_whitespace()
+        .next()
+        .filter(|s| s.parse::<f64>().is_ok())
+}
+
+impl PhaseSpaceHeader {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut sections = HashMap::new();
+        let mut current: Option<(String, String)> = None;
+        for line in text.lines() {
+            let trimmed = line.trim();
+            if trimmed.starts_with('$') && trimmed.ends_with(':') {
+                if let Some((k, v)) = current.take() {
+                    sections.insert(k, v);
+                }
+                let name = trimmed[1..trimmed.len() - 1].trim().to_string();
+                current = Some((name, String::new()));
+            } else if let Some((_, v)) = current.as_mut() {
+                if !trimmed.is_empty() {
+                    v.push_str(trimmed);
+                    v.push('\n');
+                }
+            }
+        }
+        if let Some((k, v)) = current.take() {
+            sections.insert(k, v);
+        }
+        let section = |name: &str| -> Result<&String> {
+            sections
+                .get(name)
+                .ok_or_else(|| Error::Format(format!("IAEA header without ${}", name)))
+        };
+        let number = |name: &str| -> Option<u64> {
+            sections
+                .get(name)
+                .and_then(|s| s.lines().next())
+                .and_then(leading_number)
+                .and_then(|s| s.parse::<u64>().ok())
+        };
+
+        let contents: Vec<u64> = section("RECORD_CONTENTS")?
+            .lines()
+            .filter_map(leading_number)
+            .map(|s| s.parse::<u64>().unwrap_or(0))
+            .collect();
+        if contents.len() < 9 {
+            return Err(Error::Format(
+                "IAEA $RECORD_CONTENTS is incomplete".to_string(),
+            ));
+        }
+        let mut stored = [false; 7];
+        for (n, s) in stored.iter_mut().enumerate() {
+            *s = contents[n] != 0;
+        }
+        let extra_floats = contents[7] as usize;
+        let extra_longs = contents[8] as usize;
+
+        let mut constants = [0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0];
+        let values: Vec<f64> = sections
+            .get("RECORD_CONSTANT")
+            .map(|s| {
+                s.lines()
+                    .filter_map(leading_number)
+                    .filter_map(|v| v.parse().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut values = values.into_iter();
+        for (n, c) in constants.iter_mut().enumerate() {
+            if !stored[n] {
+                *c = values.next().ok_or_else(|| {
+                    Error::Format(format!("IAEA header has no constant for {}", FIELDS[n]))
+                })?;
+            }
+        }
+
+        let big_endian = match number("BYTE_ORDER") {
+            Some(1234) | None => false,
+            Some(4321) => true,
+            Some(order) => return Err(Error::Unsupported(format!("IAEA byte order {}", order))),
+        };
+        let computed = 1
+            + 4
+            + 4 * stored[..5].iter().filter(|s| **s).count()
+            + 4 * stored[6] as usize
+            + 4 * (extra_floats + extra_longs);
+        let record_length = match number("RECORD_LENGTH") {
+            Some(l) if l as usize != computed => {
+                return Err(Error::Format(format!(
+                    "IAEA record length {} does not match the record contents ({})",
+                    l, computed
+                )))
+            }
+            _ => computed,
+        };
+        Ok(Self {
+            title: sections
+                .get("TITLE")
+                .map(|s| s.trim().to_string())
+                .unwrap_or_default(),
+            big_endian,
+            record_length,
+            stored,
+            constants,
+            extra_floats,
+            extra_longs,
+            particles: number("PARTICLES"),
+            original_histories: number("ORIG_HISTORIES"),
+            sections,
+        })
+    }
+
+    /// Decodes one binary record.
+    pub fn decode(&self, record: &[u8]) -> Result<Particle> {
+        if record.len() != self.record_length {
+            return Err(Error::Format(
+                "IAEA record has the wrong length".to_string(),
+            ));
+        }
+        let mut pos = 1;
+        let mut next = || {
+            let mut b = [
+                record[pos],
+                record[pos + 1],
+                record[pos + 2],
+                record[pos + 3],
+            ];
+            if self.big_endian {
+                b.reverse();
+            }
+            pos += 4;
+            b
+        };
+        let code = record[0] as i8;
+        let particle_type = ParticleType::from_code(code)?;
+        let energy = f32::from_le_bytes(next()) as f64;
+        let mut v = self.constants;
+        for n in [0usize, 1, 2, 3, 4, 6].iter() {
+            if self.stored[*n] {
+                v[*n] = f32::from_le_bytes(next()) as f64;
+            }
+        }
+        // w is not stored; its magnitude follows from u and v and its sign from the type.
+        if self.stored[5] {
+            let w = (1.0 - v[3] * v[3] - v[4] * v[4]).max(0.0).sqrt();
+            v[5] = if code < 0 { -w } else { w };
+        }
+        let extra_floats = (0..self.extra_floats)
+            .map(|_| f32::from_le_bytes(next()))
+            .collect();
+        let extra_longs = (0..self.extra_longs)
+            .map(|_| i32::from_le_bytes(next()))
+            .collect();
+        Ok(Particle {
+            particle_type,
+            energy: energy.abs(),
+            position: Vec3::from(v[0], v[1], v[2]),
+            direction: Vec3::from(v[3], v[4], v[5]),
+            weight: v[6],
+            new_history: energy < 0.0,
+            extra_floats,
+            extra_longs,
+        })
+    }
+}
+
+/// Iterator over the particles of a phase-space file.
+pub struct PhaseSpaceReader<R: Read> {
+    header: PhaseSpaceHeader,
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: Read> PhaseSpaceReader<R> {
+    pub fn new(header: PhaseSpaceHeader, reader: R) -> Self {
+        let buffer = vec![0u8; header.record_length];
+        Self {
+            header,
+            reader,
+            buffer,
+        }
+    }
+
+    pub fn header(&self) -> &PhaseSpaceHeader {
+        &self.header
+    }
+}
+
+impl<R: Read> Iterator for PhaseSpaceReader<R> {
+    type Item = Result<Particle>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut filled = 0;
+        while filled < self.buffer.len() {
+            match self.reader.read(&mut self.buffer[filled..]) {
+                Ok(0) if filled == 0 => return None,
+                Ok(0) => {
+                    return Some(Err(Error::Format(
+                        "IAEA phase-space file ends in a partial record".to_string(),
+                    )))
+                }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+        Some(self.header.decode(&self.buffer))
+    }
+}
+
+fn with_extension(path: &Path, ext: &str) -> PathBuf {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("IAEAheader") | Some("IAEAphsp") => path.with_extension(ext),
+        _ => {
+            let mut p = path.as_os_str().to_owned();
+            p.push(".");
+            p.push(ext);
+            PathBuf::from(p)
+        }
+    }
+}
+
+/// Opens a phase space given its base name or the path of either of its two files.
+pub fn open<P: AsRef<Path>>(path: P) -> Result<PhaseSpaceReader<BufReader<File>>> {
+    let path = path.as_ref();
+    let text = std::fs::read_to_string(with_extension(path, "IAEAheader"))?;
+    let header = PhaseSpaceHeader::parse(&text)?;
+    let file = File::open(with_extension(path, "IAEAphsp"))?;
+    Ok(PhaseSpaceReader::new(header, BufReader::new(file)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::io::iaea::{open, ParticleType, PhaseSpaceHeader, PhaseSpaceReader};
+
+    const HEADER: &str = "$IAEA_INDEX:\n1000\n\n$TITLE:\nTest linac 6 MV\n\n\
+        $FILE_TYPE:\n0\n\n$RECORD_CONTENTS:\n\
+        1 // X is stored ?\n1 // Y is stored ?\n0 // Z is stored ?\n\
+        1 // U is stored ?\n1 // V is stored ?\n1 // W is stored ?\n\
+        1 // Weight is stored ?\n0 // Extra floats stored ?\n1 // Extra longs stored ?\n\
+        2 // Incremental history number stored in the extralong array [ 0]\n\n\
+        $RECORD_CONSTANT:\n100.0 // Constant Z\n\n\
+        $RECORD_LENGTH:\n29\n\n$BYTE_ORDER:\n1234\n\n\
+        $ORIG_HISTORIES:\n5000\n\n$PARTICLES:\n2\n";
+
+    /// Record with energy, x, y, u, v and weight followed by one extra long.
+    fn record(code: i8, values: [f32; 6], n: i32) -> Vec<u8> {
+        let mut r = vec![code as u8];
+        for f in values.iter() {
+            r.extend_from_slice(&f.to_le_bytes());
+        }
+        r.extend_from_slice(&n.to_le_bytes());
+        r
+    }
+
+    #[test]
+    fn iaea_header_parse() {
+        let h = PhaseSpaceHeader::parse(HEADER).unwrap();
+        assert_eq!(h.title, "Test linac 6 MV");
+        assert_eq!(h.record_length, 29);
+        assert_eq!(h.stored, [true, true, false, true, true, true, true]);
+        assert_eq!(h.constants[2], 100.0);
+        assert_eq!(h.extra_longs, 1);
+        assert_eq!(h.particles, Some(2));
+        assert_eq!(h.original_histories, Some(5000));
+        assert!(!h.big_endian);
+    }
+
+    #[test]
+    fn iaea_header_errors() {
+        assert!(PhaseSpaceHeader::parse("$TITLE:\nno contents\n").is_err());
+        let wrong_length = HEADER.replace("$RECORD_LENGTH:\n29", "$RECORD_LENGTH:\n33");
+        assert!(PhaseSpaceHeader::parse(&wrong_length).is_err());
+        let no_constant = HEADER.replace("100.0 // Constant Z", "");
+        assert!(PhaseSpaceHeader::parse(&no_constant).is_err());
+    }
+
+    #[test]
+    fn iaea_reader() {
+        let h = PhaseSpaceHeader::parse(HEADER).unwrap();
+        let mut data = record(1, [-6.0, 1.0, -2.0, 0.6, 0.0, 0.5], 1);
+        data.extend(record(-2, [2.5, 0.0, 0.0, 0.0, 0.8, 1.0], 0));
+        let particles: Vec<_> = PhaseSpaceReader::new(h, &data[..])
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(particles.len(), 2);
+        let p = &particles[0];
+        assert_eq!(p.particle_type, ParticleType::Photon);
+        assert_eq!(p.energy, 6.0);
+        assert!(p.new_history);
+        assert_eq!(p.position, Vec3::from(1.0, -2.0, 100.0));
+        assert!((p.direction.z - 0.8).abs() < 1e-6);
+        assert_eq!(p.weight, 0.5);
+        assert_eq!(p.extra_longs, vec![1]);
+        let e = &particles[1];
+        assert_eq!(e.particle_type, ParticleType::Electron);
+        assert!(!e.new_history);
+        assert!((e.direction.z + 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn iaea_reader_partial_record() {
+        let h = PhaseSpaceHeader::parse(HEADER).unwrap();
+        let data = record(1, [6.0, 0.0, 0.0, 0.0, 0.0, 1.0], 0);
+        let mut reader = PhaseSpaceReader::new(h, &data[..20]);
+        assert!(reader.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn iaea_open() {
+        let dir = std::env::temp_dir().join(format!("planrt-iaea-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("linac.IAEAheader"), HEADER).unwrap();
+        std::fs::write(
+            dir.join("linac.IAEAphsp"),
+            record(3, [1.0, 0.0, 0.0, 0.0, 0.0, 1.0], 0),
+        )
+        .unwrap();
+        let particles: Vec<_> = open(dir.join("linac")).unwrap().collect();
+        assert_eq!(particles.len(), 1);
+        assert_eq!(
+            particles[0].as_ref().unwrap().particle_type,
+            ParticleType::Positron
+        );
+        assert!(open(dir.join("linac.IAEAphsp")).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
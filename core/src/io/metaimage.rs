@@ -0,0 +1,367 @@
+//! MetaImage volumes: single-file `.mha` and header/data pairs (`.mhd` + `.raw`/`.zraw`).
+//!
+//! MetaImage (ITK) world coordinates are LPS, so no axis flip is needed.
+
+use crate::coords::Vec3;
+use crate::error::{Error, Result};
+use crate::grid::{Grid3, GridGeometry};
+use crate::io::deflate::{zlib_compress, zlib_decompress};
+use crate::io::{ScalarType, Voxel};
+use std::path::Path;
+
+fn element_type(name: &str) -> Result<ScalarType> {
+    Ok(match name {
+        "MET_UCHAR" => ScalarType::U8,
+        "MET_CHAR" => ScalarType::I8,
+        "MET_USHORT" => ScalarType::U16,
+        "MET_SHORT" => ScalarType::I16,
+        "MET_UINT" | "MET_ULONG" => ScalarType::U32,
+        "MET_INT" | "MET_LONG" => ScalarType::I32,
+        "MET_ULONG_LONG" => ScalarType::U64,
+        "MET_LONG_LONG" => ScalarType::I64,
+        "MET_FLOAT" => ScalarType::F32,
+        "MET_DOUBLE" => ScalarType::F64,
+        _ => {
+            return Err(Error::Unsupported(format!(
+                "MetaImage element type {}",
+                name
+            )))
+        }
+    })
+}
+
+fn element_name(t: ScalarType) -> &'static str {
+    match t {
+        ScalarType::U8 => "MET_UCHAR",
+        ScalarType::I8 => "MET_CHAR",
+        ScalarType::U16 => "MET_USHORT",
+        ScalarType::I16 => "MET_SHORT",
+        ScalarType::U32 => "MET_UINT",
+        ScalarType::I32 => "MET_INT",
+        ScalarType::U64 => "MET_ULONG_LONG",
+        ScalarType::I64 => "MET_LONG_LONG",
+        ScalarType::F32 => "MET_FLOAT",
+        ScalarType::F64 => "MET_DOUBLE",
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Header {
+    geometry: GridGeometry,
+    element_type: ScalarType,
+    big_endian: bool,
+    compressed: bool,
+    data_file: String,
+}
+
+fn numbers(key: &str, value: &str, n: usize) -> Result<Vec<f64>> {
+    let v = value
+        .split_whitespace()
+        .map(|s| s.parse::<f64>())
+        .collect::<std::result::Result<Vec<f64>, _>>()
+        .map_err(|_| Error::Format(format!("MetaImage {}: invalid number in '{}'", key, value)))?;
+    if v.len() != n {
+        return Err(Error::Format(format!(
+            "MetaImage {} needs {} values, got {}",
+            key,
+            n,
+            v.len()
+        )));
+    }
+    Ok(v)
+}
+
+fn boolean(value: &str) -> bool {
+    value.eq_ignore_ascii_case("true") || value == "1"
+}
+
+/// Parses the header; returns it with the offset of the first byte after the header.
+fn parse_header(bytes: &[u8]) -> Result<(Header, usize)> {
+    let mut dims = None;
+    let mut origin = Vec3::new();
+    let mut spacing = Vec3::from(1.0, 1.0, 1.0);
+    let mut direction = GridGeometry::new([0; 3], origin, spacing).direction;
+    let mut element = None;
+    let mut big_endian = false;
+    let mut compressed = false;
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let end = bytes[pos..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(bytes.len(), |e| pos + e);
+        let line = std::str::from_utf8(&bytes[pos..end])
+            .map_err(|_| Error::Format("MetaImage header is not valid text".to_string()))?
+            .trim();
+        pos = (end + 1).min(bytes.len());
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => return Err(Error::Format(format!("MetaImage header line '{}'", line))),
+        };
+        match key {
+            "ObjectType" if value != "Image" => {
+                return Err(Error::Unsupported(format!(
+                    "MetaImage object type {}",
+                    value
+                )))
+            }
+            "NDims" if value != "3" => {
+                return Err(Error::Unsupported(format!(
+                    "{}-dimensional MetaImage",
+                    value
+                )))
+            }
+            "ElementNumberOfChannels" if value != "1" => {
+                return Err(Error::Unsupported("multi-channel MetaImage".to_string()))
+            }
+            "DimSize" => {
+                let d = numbers(key, value, 3)?;
+                dims = Some([d[0] as usize, d[1] as usize, d[2] as usize]);
+            }
+            "ElementSpacing" => {
+                let s = numbers(key, value, 3)?;
+                spacing = Vec3::from(s[0], s[1], s[2]);
+            }
+            "Offset" | "Position" | "Origin" => {
+                let o = numbers(key, value, 3)?;
+                origin = Vec3::from(o[0], o[1], o[2]);
+            }
+            "TransformMatrix" | "Rotation" | "Orientation" => {
+                let m = numbers(key, value, 9)?;
+                for (a, d) in direction.iter_mut().enumerate() {
+                    *d = Vec3::from(m[3 * a], m[3 * a + 1], m[3 * a + 2]);
+                }
+            }
+            "ElementType" => element = Some(element_type(value)?),
+            "BinaryDataByteOrderMSB" | "ElementByteOrderMSB" => big_endian = boolean(value),
+            "CompressedData" => compressed = boolean(value),
+            "BinaryData" if !boolean(value) => {
+                return Err(Error::Unsupported("ASCII MetaImage data".to_string()))
+            }
+            "ElementDataFile" => {
+                let dims =
+                    dims.ok_or_else(|| Error::Format("MetaImage without DimSize".to_string()))?;
+                let element_type = element
+                    .ok_or_else(|| Error::Format("MetaImage without ElementType".to_string()))?;
+                let geometry = GridGeometry {
+                    dims,
+                    origin,
+                    spacing,
+                    direction,
+                };
+                let header = Header {
+                    geometry,
+                    element_type,
+                    big_endian,
+                    compressed,
+                    data_file: value.to_string(),
+                };
+                return Ok((header, pos));
+            }
+            _ => {}
+        }
+    }
+    Err(Error::Format(
+        "MetaImage header without ElementDataFile".to_string(),
+    ))
+}
+
+fn decode(header: Header, raw: &[u8]) -> Result<Grid3<f64>> {
+    let unzipped;
+    let raw = if header.compressed {
+        unzipped = zlib_decompress(raw)?;
+        &unzipped[..]
+    } else {
+        raw
+    };
+    header.geometry.check()?;
+    let n = header.element_type.data_size(header.geometry.dims)?;
+    let data = raw
+        .get(..n)
+        .ok_or_else(|| Error::Format("MetaImage voxel data is truncated".to_string()))?;
+    let values = header.element_type.decode(data, header.big_endian)?;
+    Grid3::from_vec(header.geometry, values)
+}
+
+/// Reads a `.mha` or `.mhd` file; detached data files are resolved relative to the header.
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Grid3<f64>> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path)?;
+    let (header, offset) = parse_header(&bytes)?;
+    if header.data_file == "LOCAL" {
+        return decode(header, &bytes[offset..]);
+    }
+    if header.data_file.starts_with("LIST") || header.data_file.contains('%') {
+        return Err(Error::Unsupported(
+            "MetaImage data split over multiple files".to_string(),
+        ));
+    }
+    let data_path = path
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(&header.data_file);
+    let raw = std::fs::read(data_path)?;
+    decode(header, &raw)
+}
+
+/// Reads a single-file MetaImage held in memory.
+pub fn read_bytes(bytes: &[u8]) -> Result<Grid3<f64>> {
+    let (header, offset) = parse_header(bytes)?;
+    if header.data_file != "LOCAL" {
+        return Err(Error::InvalidArgument(format!(
+            "MetaImage data is stored in '{}', use read() with the header path",
+            header.data_file
+        )));
+    }
+    decode(header, &bytes[offset..])
+}
+
+fn header_text<T: Voxel>(
+    grid: &Grid3<T>,
+    compressed: bool,
+    data_size: usize,
+    data_file: &str,
+) -> String {
+    let g = grid.geometry();
+    let d = &g.direction;
+    let mut h = String::new();
+    h.push_str("ObjectType = Image\nNDims = 3\nBinaryData = True\n");
+    h.push_str("BinaryDataByteOrderMSB = False\n");
+    h.push_str(&format!(
+        "CompressedData = {}\n",
+        if compressed { "True" } else { "False" }
+    ));
+    if compressed {
+        h.push_str(&format!("CompressedDataSize = {}\n", data_size));
+    }
+    h.push_str(&format!(
+        "TransformMatrix = {} {} {} {} {} {} {} {} {}\n",
+        d[0].x, d[0].y, d[0].z, d[1].x, d[1].y, d[1].z, d[2].x, d[2].y, d[2].z
+    ));
+    h.push_str(&format!(
+        "Offset = {} {} {}\n",
+        g.origin.x, g.origin.y, g.origin.z
+    ));
+    h.push_str("CenterOfRotation = 0 0 0\n");
+    h.push_str(&format!(
+        "ElementSpacing = {} {} {}\n",
+        g.spacing.x, g.spacing.y, g.spacing.z
+    ));
+    h.push_str(&format!(
+        "DimSize = {} {} {}\n",
+        g.dims[0], g.dims[1], g.dims[2]
+    ));
+    h.push_str(&format!("ElementType = {}\n", element_name(T::SCALAR_TYPE)));
+    h.push_str(&format!("ElementDataFile = {}\n", data_file));
+    h
+}
+
+fn encode<T: Voxel>(grid: &Grid3<T>, compressed: bool) -> Vec<u8> {
+    let raw = T::SCALAR_TYPE.encode(grid.data().iter().map(|v| v.to_f64()), false);
+    if compressed {
+        zlib_compress(&raw)
+    } else {
+        raw
+    }
+}
+
+/// Serializes a grid as a single-file MetaImage (`.mha`).
+pub fn write_bytes<T: Voxel>(grid: &Grid3<T>, compressed: bool) -> Vec<u8> {
+    let data = encode(grid, compressed);
+    let mut out = header_text(grid, compressed, data.len(), "LOCAL").into_bytes();
+    out.extend(data);
+    out
+}
+
+/// Writes a `.mha` file, or for a `.mhd` path a header plus a `.raw` (`.zraw`) data file.
+pub fn write<T: Voxel, P: AsRef<Path>>(path: P, grid: &Grid3<T>, compressed: bool) -> Result<()> {
+    let path = path.as_ref();
+    let detached = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("mhd"));
+    if !detached {
+        std::fs::write(path, write_bytes(grid, compressed))?;
+        return Ok(());
+    }
+    let data = encode(grid, compressed);
+    let data_path = path.with_extension(if compressed { "zraw" } else { "raw" });
+    let data_name = data_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| Error::InvalidArgument(format!("invalid path {}", path.display())))?
+        .to_string();
+    std::fs::write(&data_path, &data)?;
+    std::fs::write(path, header_text(grid, compressed, data.len(), &data_name))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::affine::Affine3;
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::io::metaimage::{read, read_bytes, write, write_bytes};
+
+    fn grid() -> Grid3<i16> {
+        let mut geometry = GridGeometry::new(
+            [4, 3, 2],
+            Vec3::from(-250.0, -200.5, 12.0),
+            Vec3::from(0.9, 0.9, 2.5),
+        );
+        let rot = Affine3::rotation(0.1, 0.0, 0.0);
+        for d in geometry.direction.iter_mut() {
+            *d = rot.transform_vector(*d);
+        }
+        Grid3::from_vec(geometry, (0..24).map(|v| v * 100 - 1000).collect()).unwrap()
+    }
+
+    #[test]
+    fn metaimage_roundtrip() {
+        let g = grid();
+        for &compressed in [false, true].iter() {
+            let back = read_bytes(&write_bytes(&g, compressed)).unwrap();
+            assert_eq!(back.geometry(), g.geometry());
+            assert_eq!(back.data()[0], -1000.0);
+            assert_eq!(back.data()[23], 1300.0);
+        }
+    }
+
+    #[test]
+    fn metaimage_parse() {
+        let mut text = b"ObjectType = Image\r\nNDims = 3\r\nDimSize = 2 1 1\r\n\
+            ElementSpacing = 2 2 3\r\nOffset = 1 2 3\r\nBinaryDataByteOrderMSB = True\r\n\
+            ElementType = MET_USHORT\r\nElementDataFile = LOCAL\r\n"
+            .to_vec();
+        text.extend_from_slice(&[0x01, 0x00, 0x00, 0x02]);
+        let g = read_bytes(&text).unwrap();
+        assert_eq!(g.data(), &[256.0, 2.0]);
+        assert_eq!(g.geometry().origin, Vec3::from(1.0, 2.0, 3.0));
+        assert_eq!(g.geometry().spacing, Vec3::from(2.0, 2.0, 3.0));
+    }
+
+    #[test]
+    fn metaimage_detached() {
+        let dir = std::env::temp_dir().join(format!("planrt-mhd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("volume.mhd");
+        let g = grid();
+        write(&path, &g, true).unwrap();
+        assert!(dir.join("volume.zraw").exists());
+        let back = read(&path).unwrap();
+        assert_eq!(back.geometry(), g.geometry());
+        assert_eq!(back.data()[7], -300.0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn metaimage_errors() {
+        assert!(read_bytes(b"NDims = 2\nElementDataFile = LOCAL\n").is_err());
+        assert!(read_bytes(b"NDims = 3\nDimSize = 1 1 1\nElementDataFile = LOCAL\n").is_err());
+        let text =
+            b"NDims = 3\nDimSize = 1 1 1\nElementType = MET_FLOAT\nElementDataFile = x.raw\n";
+        assert!(read_bytes(text).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+pub mod beam_data;
+pub mod deflate;
+pub mod dvh;
+pub mod iaea;
+pub mod metaimage;
+pub mod nifti;
+pub mod nrrd;
+pub mod ply;
+pub mod stl;
+pub mod toml;
+
+use crate::error::{Error, Result};
+use crate::grid::Grid3;
+use std::path::Path;
+
+/// Voxel element types found in volume file formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalarType {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    U64,
+    I64,
+    F32,
+    F64,
+}
+
+impl ScalarType {
+    pub fn size(self) -> usize {
+        match self {
+            ScalarType::U8 | ScalarType::I8 => 1,
+            ScalarType::U16 | ScalarType::I16 => 2,
+            ScalarType::U32 | ScalarType::I32 | ScalarType::F32 => 4,
+            ScalarType::U64 | ScalarType::I64 | ScalarType::F64 => 8,
+        }
+    }
+
+    /// Bytes of `dims` voxels, failing when a file header asks for more than memory can hold.
+    pub fn data_size(self, dims: [usize; 3]) -> Result<usize> {
+        dims.iter()
+            .try_fold(self.size(), |n, d| n.checked_mul(*d))
+            .ok_or_else(|| Error::Format(format!("{:?} voxels of {:?} overflow", dims, self)))
+    }
+
+    /// Decodes `bytes` (a whole number of elements) into `f64` values.
+    pub fn decode(self, bytes: &[u8], big_endian: bool) -> Result<Vec<f64>> {
+        let size = self.size();
+        if !bytes.len().is_multiple_of(size) {
+            return Err(Error::Format(format!(
+                "{} bytes is not a multiple of the {:?} element size",
+                bytes.len(),
+                self
+            )));
+        }
+        Ok(bytes
+            .chunks_exact(size)
+            .map(|c| {
+                let mut b = [0u8; 8];
+                b[..size].copy_from_slice(c);
+                if big_endian {
+                    b[..size].reverse();
+                }
+                match self {
+                    ScalarType::U8 => b[0] as f64,
+                    ScalarType::I8 => b[0] as i8 as f64,
+                    ScalarType::U16 => u16::from_le_bytes([b[0], b[1]]) as f64,
+                    ScalarType::I16 => i16::from_le_bytes([b[0], b[1]]) as f64,
+                    ScalarType::U32 => u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    ScalarType::I32 => i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    ScalarType::F32 => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+                    ScalarType::U64 => u64::from_le_bytes(b) as f64,
+                    ScalarType::I64 => i64::from_le_bytes(b) as f64,
+                    ScalarType::F64 => f64::from_le_bytes(b),
+                }
+            })
+            .collect())
+    }
+
+    /// Encodes `values`, rounding and saturating for integer types.
+    pub fn encode(self, values: impl Iterator<Item = f64>, big_endian: bool) -> Vec<u8> {
+        let mut out = Vec::new();
+        for v in values {
+            let r = v.round();
+            let mut b: Vec<u8> = match self {
+                ScalarType::U8 => vec![r as u8],
+                ScalarType::I8 => vec![r as i8 as u8],
+                ScalarType::U16 => (r as u16).to_le_bytes().to_vec(),
+                ScalarType::I16 => (r as i16).to_le_bytes().to_vec(),
+                ScalarType::U32 => (r as u32).to_le_bytes().to_vec(),
+                ScalarType::I32 => (r as i32).to_le_bytes().to_vec(),
+                ScalarType::U64 => (r as u64).to_le_bytes().to_vec(),
+                ScalarType::I64 => (r as i64).to_le_bytes().to_vec(),
+                ScalarType::F32 => (v as f32).to_le_bytes().to_vec(),
+                ScalarType::F64 => v.to_le_bytes().to_vec(),
+            };
+            if big_endian {
+                b.reverse();
+            }
+            out.extend(b);
+        }
+        out
+    }
+}
+
+/// Voxel value types that can be written to (and converted from) volume files.
+pub trait Voxel: Copy {
+    const SCALAR_TYPE: ScalarType;
+
+    fn to_f64(self) -> f64;
+
+    fn from_f64(v: f64) -> Self;
+}
+
+macro_rules! impl_voxel {
+    ($t:ty, $s:expr) => {
+        impl Voxel for $t {
+            const SCALAR_TYPE: ScalarType = $s;
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            fn from_f64(v: f64) -> Self {
+                v as $t
+            }
+        }
+    };
+}
+
+impl_voxel!(u8, ScalarType::U8);
+impl_voxel!(i8, ScalarType::I8);
+impl_voxel!(u16, ScalarType::U16);
+impl_voxel!(i16, ScalarType::I16);
+impl_voxel!(u32, ScalarType::U32);
+impl_voxel!(i32, ScalarType::I32);
+impl_voxel!(f32, ScalarType::F32);
+impl_voxel!(f64, ScalarType::F64);
+
+/// Masks are stored as unsigned bytes (0 or 1).
+impl Voxel for bool {
+    const SCALAR_TYPE: ScalarType = ScalarType::U8;
+
+    fn to_f64(self) -> f64 {
+        if self {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn from_f64(v: f64) -> Self {
+        v != 0.0
+    }
+}
+
+/// Volume file formats, told apart by the file name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VolumeFormat {
+    /// `.mha`, or `.mhd` with a detached data file.
+    MetaImage,
+    /// `.nii` or `.nii.gz`.
+    Nifti,
+    /// `.nrrd`, or `.nhdr` with a detached data file.
+    Nrrd,
+}
+
+impl VolumeFormat {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if name.ends_with(".mha") || name.ends_with(".mhd") {
+            Ok(VolumeFormat::MetaImage)
+        } else if name.ends_with(".nii") || name.ends_with(".nii.gz") {
+            Ok(VolumeFormat::Nifti)
+        } else if name.ends_with(".nrrd") || name.ends_with(".nhdr") {
+            Ok(VolumeFormat::Nrrd)
+        } else {
+            Err(Error::Unsupported(format!(
+                "unknown volume format of {}",
+                path.display()
+            )))
+        }
+    }
+}
+
+/// Reads a volume in the format of its file name.
+pub fn read_volume<P: AsRef<Path>>(path: P) -> Result<Grid3<f64>> {
+    let path = path.as_ref();
+    match VolumeFormat::from_path(path)? {
+        VolumeFormat::MetaImage => metaimage::read(path),
+        VolumeFormat::Nifti => nifti::read(path),
+        VolumeFormat::Nrrd => nrrd::read(path),
+    }
+}
+
+/// Writes a volume in the format of its file name, uncompressed unless a `.nii.gz` is asked for.
+pub fn write_volume<T: Voxel, P: AsRef<Path>>(path: P, grid: &Grid3<T>) -> Result<()> {
+    let path = path.as_ref();
+    match VolumeFormat::from_path(path)? {
+        VolumeFormat::MetaImage => metaimage::write(path, grid, false),
+        VolumeFormat::Nifti => nifti::write(path, grid),
+        VolumeFormat::Nrrd => nrrd::write(path, grid, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::io::{read_volume, write_volume, ScalarType, VolumeFormat};
+    use std::path::Path;
+
+    #[test]
+    fn scalar_type_roundtrip() {
+        let values = [0.0, 1.0, -2.0, 300.0];
+        for &t in [
+            ScalarType::I16,
+            ScalarType::I32,
+            ScalarType::F32,
+            ScalarType::F64,
+        ]
+        .iter()
+        {
+            for &be in [false, true].iter() {
+                let bytes = t.encode(values.iter().cloned(), be);
+                assert_eq!(bytes.len(), values.len() * t.size());
+                assert_eq!(t.decode(&bytes, be).unwrap(), values.to_vec());
+            }
+        }
+    }
+
+    #[test]
+    fn scalar_type_decode() {
+        assert_eq!(ScalarType::U16.decode(&[1, 2], true).unwrap(), vec![258.0]);
+        assert_eq!(ScalarType::I8.decode(&[255], false).unwrap(), vec![-1.0]);
+        assert!(ScalarType::I32.decode(&[0, 0, 0], false).is_err());
+    }
+
+    #[test]
+    fn volume_formats() {
+        assert_eq!(
+            VolumeFormat::from_path(Path::new("ct/CT.NII.GZ")).unwrap(),
+            VolumeFormat::Nifti
+        );
+        assert_eq!(
+            VolumeFormat::from_path(Path::new("dose.nhdr")).unwrap(),
+            VolumeFormat::Nrrd
+        );
+        assert!(VolumeFormat::from_path(Path::new("CT.1.dcm")).is_err());
+
+        let dir = std::env::temp_dir().join(format!("planrt-volume-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let geometry = GridGeometry::new(
+            [3, 2, 2],
+            Vec3::from(-1.0, 4.0, 2.5),
+            Vec3::from(1.5, 2.0, 3.0),
+        );
+        let grid = Grid3::from_vec(geometry, (0..12).map(|v| v as f64 * 0.5).collect()).unwrap();
+        for name in ["dose.mha", "dose.nii.gz", "dose.nrrd"].iter() {
+            let path = dir.join(name);
+            write_volume(&path, &grid).unwrap();
+            let read = read_volume(&path).unwrap();
+            assert_eq!(read.data(), grid.data());
+            assert!((read.geometry().origin - grid.geometry().origin).norm() < 1e-6);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,608 @@
+//! NIfTI-1 and NIfTI-2 single-file volumes (`.nii`, `.nii.gz`).
+//!
+//! NIfTI world coordinates are RAS while planrt grids use DICOM LPS; the x and y axes are
+//! flipped when reading and writing so the voxel-to-patient mapping is preserved.
+
+use crate::affine::Affine3;
+use crate::coords::Vec3;
+use crate::error::{Error, Result};
+use crate::grid::{Grid3, GridGeometry};
+use crate::io::deflate::{gzip_compress, gzip_decompress, is_gzip};
+use crate::io::{ScalarType, Voxel};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Nifti1,
+    Nifti2,
+}
+
+const NIFTI1_HEADER: usize = 348;
+const NIFTI2_HEADER: usize = 540;
+const XFORM_SCANNER: i32 = 1;
+const UNITS_MM: u8 = 2;
+
+fn datatype_code(t: ScalarType) -> i16 {
+    match t {
+        ScalarType::U8 => 2,
+        ScalarType::I16 => 4,
+        ScalarType::I32 => 8,
+        ScalarType::F32 => 16,
+        ScalarType::F64 => 64,
+        ScalarType::I8 => 256,
+        ScalarType::U16 => 512,
+        ScalarType::U32 => 768,
+        ScalarType::I64 => 1024,
+        ScalarType::U64 => 1280,
+    }
+}
+
+fn scalar_type(code: i16) -> Result<ScalarType> {
+    Ok(match code {
+        2 => ScalarType::U8,
+        4 => ScalarType::I16,
+        8 => ScalarType::I32,
+        16 => ScalarType::F32,
+        64 => ScalarType::F64,
+        256 => ScalarType::I8,
+        512 => ScalarType::U16,
+        768 => ScalarType::U32,
+        1024 => ScalarType::I64,
+        1280 => ScalarType::U64,
+        _ => return Err(Error::Unsupported(format!("NIfTI datatype {}", code))),
+    })
+}
+
+/// The header fields planrt uses, independent of the NIfTI version.
+#[derive(Debug, Clone, PartialEq)]
+struct Header {
+    dims: [usize; 3],
+    datatype: i16,
+    pixdim: [f64; 4],
+    vox_offset: usize,
+    scl_slope: f64,
+    scl_inter: f64,
+    qform_code: i32,
+    sform_code: i32,
+    quatern: [f64; 3],
+    qoffset: [f64; 3],
+    srow: [[f64; 4]; 3],
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn raw<const N: usize>(&self, at: usize) -> [u8; N] {
+        let mut b = [0u8; N];
+        b.copy_from_slice(&self.bytes[at..at + N]);
+        if self.big_endian {
+            b.reverse();
+        }
+        b
+    }
+
+    fn i16(&self, at: usize) -> i16 {
+        i16::from_le_bytes(self.raw(at))
+    }
+
+    fn i32(&self, at: usize) -> i32 {
+        i32::from_le_bytes(self.raw(at))
+    }
+
+    fn i64(&self, at: usize) -> i64 {
+        i64::from_le_bytes(self.raw(at))
+    }
+
+    fn f32(&self, at: usize) -> f64 {
+        f32::from_le_bytes(self.raw(at)) as f64
+    }
+
+    fn f64(&self, at: usize) -> f64 {
+        f64::from_le_bytes(self.raw(at))
+    }
+}
+
+fn volume_dims(dim: &[i64; 8]) -> Result<[usize; 3]> {
+    if dim[0] < 1 || dim[0] > 7 {
+        return Err(Error::Format(format!("invalid NIfTI dim[0] = {}", dim[0])));
+    }
+    let mut dims = [1usize; 3];
+    for a in 0..3 {
+        if (a as i64) < dim[0] {
+            if dim[a + 1] < 1 {
+                return Err(Error::Format(format!(
+                    "invalid NIfTI dimension {}",
+                    dim[a + 1]
+                )));
+            }
+            dims[a] = dim[a + 1] as usize;
+        }
+    }
+    if (4..=dim[0] as usize).any(|a| dim[a] > 1) {
+        return Err(Error::Unsupported(
+            "NIfTI volumes with more than 3 dimensions".to_string(),
+        ));
+    }
+    Ok(dims)
+}
+
+fn parse_header(bytes: &[u8]) -> Result<(Header, bool)> {
+    if bytes.len() < 4 {
+        return Err(Error::Format(
+            "file too short for a NIfTI header".to_string(),
+        ));
+    }
+    let le = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let be = i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let (size, big_endian) = match (le, be) {
+        (348, _) | (540, _) => (le as usize, false),
+        (_, 348) | (_, 540) => (be as usize, true),
+        _ => return Err(Error::Format("not a NIfTI file".to_string())),
+    };
+    if bytes.len() < size {
+        return Err(Error::Format("truncated NIfTI header".to_string()));
+    }
+    let r = Reader { bytes, big_endian };
+    let header = if size == NIFTI1_HEADER {
+        if &bytes[344..347] != b"n+1" {
+            return Err(Error::Unsupported(
+                "NIfTI-1 header/image pairs (.hdr/.img)".to_string(),
+            ));
+        }
+        let mut dim = [0i64; 8];
+        for (n, d) in dim.iter_mut().enumerate() {
+            *d = r.i16(40 + 2 * n) as i64;
+        }
+        let pixdim = [r.f32(76), r.f32(80), r.f32(84), r.f32(88)];
+        let mut srow = [[0.0; 4]; 3];
+        for (row, s) in srow.iter_mut().enumerate() {
+            for (c, v) in s.iter_mut().enumerate() {
+                *v = r.f32(280 + 16 * row + 4 * c);
+            }
+        }
+        Header {
+            dims: volume_dims(&dim)?,
+            datatype: r.i16(70),
+            pixdim,
+            vox_offset: r.f32(108) as usize,
+            scl_slope: r.f32(112),
+            scl_inter: r.f32(116),
+            qform_code: r.i16(252) as i32,
+            sform_code: r.i16(254) as i32,
+            quatern: [r.f32(256), r.f32(260), r.f32(264)],
+            qoffset: [r.f32(268), r.f32(272), r.f32(276)],
+            srow,
+        }
+    } else {
+        if &bytes[4..7] != b"n+2" {
+            return Err(Error::Unsupported(
+                "NIfTI-2 header/image pairs (.hdr/.img)".to_string(),
+            ));
+        }
+        let mut dim = [0i64; 8];
+        for (n, d) in dim.iter_mut().enumerate() {
+            *d = r.i64(16 + 8 * n);
+        }
+        let pixdim = [r.f64(104), r.f64(112), r.f64(120), r.f64(128)];
+        let mut srow = [[0.0; 4]; 3];
+        for (row, s) in srow.iter_mut().enumerate() {
+            for (c, v) in s.iter_mut().enumerate() {
+                *v = r.f64(400 + 32 * row + 8 * c);
+            }
+        }
+        Header {
+            dims: volume_dims(&dim)?,
+            datatype: r.i16(12),
+            pixdim,
+            vox_offset: r.i64(168).max(0) as usize,
+            scl_slope: r.f64(176),
+            scl_inter: r.f64(184),
+            qform_code: r.i32(344),
+            sform_code: r.i32(348),
+            quatern: [r.f64(352), r.f64(360), r.f64(368)],
+            qoffset: [r.f64(376), r.f64(384), r.f64(392)],
+            srow,
+        }
+    };
+    Ok((header, big_endian))
+}
+
+/// Index-to-RAS transform from the quaternion representation (NIfTI method 2).
+fn qform_affine(h: &Header) -> Affine3 {
+    let [b, c, d] = h.quatern;
+    let a = (1.0 - (b * b + c * c + d * d)).max(0.0).sqrt();
+    let r = [
+        [
+            a * a + b * b - c * c - d * d,
+            2.0 * (b * c - a * d),
+            2.0 * (b * d + a * c),
+        ],
+        [
+            2.0 * (b * c + a * d),
+            a * a + c * c - b * b - d * d,
+            2.0 * (c * d - a * b),
+        ],
+        [
+            2.0 * (b * d - a * c),
+            2.0 * (c * d + a * b),
+            a * a + d * d - b * b - c * c,
+        ],
+    ];
+    let qfac = if h.pixdim[0] < 0.0 { -1.0 } else { 1.0 };
+    let scale = [h.pixdim[1], h.pixdim[2], h.pixdim[3] * qfac];
+    let mut m = [[0.0; 3]; 3];
+    for row in 0..3 {
+        for col in 0..3 {
+            m[row][col] = r[row][col] * scale[col];
+        }
+    }
+    Affine3::from(m, Vec3::from_array(h.qoffset))
+}
+
+/// Quaternion (b, c, d) and qfac of the rotation part of `direction` (columns).
+fn quaternion(direction: &[Vec3<f64>; 3]) -> ([f64; 3], f64) {
+    let mut r = [[0.0; 3]; 3];
+    for (col, d) in direction.iter().enumerate() {
+        let d = d.to_array();
+        for row in 0..3 {
+            r[row][col] = d[row];
+        }
+    }
+    let det = Affine3::from(r, Vec3::new()).determinant();
+    let qfac = if det < 0.0 { -1.0 } else { 1.0 };
+    for row in r.iter_mut() {
+        row[2] *= qfac;
+    }
+    let trace = r[0][0] + r[1][1] + r[2][2] + 1.0;
+    if trace > 0.5 {
+        let a = 0.5 * trace.sqrt();
+        let b = 0.25 * (r[2][1] - r[1][2]) / a;
+        let c = 0.25 * (r[0][2] - r[2][0]) / a;
+        let d = 0.25 * (r[1][0] - r[0][1]) / a;
+        return ([b, c, d], qfac);
+    }
+    let xd = 1.0 + r[0][0] - (r[1][1] + r[2][2]);
+    let yd = 1.0 + r[1][1] - (r[0][0] + r[2][2]);
+    let zd = 1.0 + r[2][2] - (r[0][0] + r[1][1]);
+    let (a, b, c, d) = if xd > 1.0 {
+        let b = 0.5 * xd.sqrt();
+        let a = 0.25 * (r[2][1] - r[1][2]) / b;
+        (
+            a,
+            b,
+            0.25 * (r[0][1] + r[1][0]) / b,
+            0.25 * (r[0][2] + r[2][0]) / b,
+        )
+    } else if yd > 1.0 {
+        let c = 0.5 * yd.sqrt();
+        let a = 0.25 * (r[0][2] - r[2][0]) / c;
+        (
+            a,
+            0.25 * (r[0][1] + r[1][0]) / c,
+            c,
+            0.25 * (r[1][2] + r[2][1]) / c,
+        )
+    } else {
+        let d = 0.5 * zd.sqrt();
+        let a = 0.25 * (r[1][0] - r[0][1]) / d;
+        (
+            a,
+            0.25 * (r[0][2] + r[2][0]) / d,
+            0.25 * (r[1][2] + r[2][1]) / d,
+            d,
+        )
+    };
+    // The quaternion is stored with a non-negative scalar part.
+    if a < 0.0 {
+        ([-b, -c, -d], qfac)
+    } else {
+        ([b, c, d], qfac)
+    }
+}
+
+fn ras_lps() -> Affine3 {
+    Affine3::scaling(Vec3::from(-1.0, -1.0, 1.0))
+}
+
+fn geometry(h: &Header) -> GridGeometry {
+    let ras = if h.sform_code > 0 {
+        let s = h.srow;
+        Affine3::from_matrix4([s[0], s[1], s[2], [0.0, 0.0, 0.0, 1.0]])
+    } else if h.qform_code > 0 {
+        qform_affine(h)
+    } else {
+        // Method 1: no orientation information, only voxel sizes.
+        return GridGeometry::new(
+            h.dims,
+            Vec3::new(),
+            Vec3::from(h.pixdim[1], h.pixdim[2], h.pixdim[3]),
+        );
+    };
+    GridGeometry::from_affine(h.dims, &(ras_lps() * ras))
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Grid3<f64>> {
+    read_bytes(&std::fs::read(path)?)
+}
+
+/// Reads an in-memory `.nii` or gzip compressed `.nii.gz` file, applying `scl_slope`/`scl_inter`.
+pub fn read_bytes(bytes: &[u8]) -> Result<Grid3<f64>> {
+    let unzipped;
+    let bytes = if is_gzip(bytes) {
+        unzipped = gzip_decompress(bytes)?;
+        &unzipped[..]
+    } else {
+        bytes
+    };
+    let (h, big_endian) = parse_header(bytes)?;
+    let t = scalar_type(h.datatype)?;
+    let truncated = || Error::Format("NIfTI voxel data is truncated".to_string());
+    let end = h
+        .vox_offset
+        .checked_add(t.data_size(h.dims)?)
+        .ok_or_else(truncated)?;
+    let data = bytes.get(h.vox_offset..end).ok_or_else(truncated)?;
+    let mut values = t.decode(data, big_endian)?;
+    if h.scl_slope != 0.0 && h.scl_slope.is_finite() && (h.scl_slope != 1.0 || h.scl_inter != 0.0) {
+        for v in values.iter_mut() {
+            *v = *v * h.scl_slope + h.scl_inter;
+        }
+    }
+    let geometry = geometry(&h);
+    geometry.check()?;
+    Grid3::from_vec(geometry, values)
+}
+
+/// Writes a NIfTI-1 file, or NIfTI-2 when a dimension exceeds the 16-bit NIfTI-1 fields,
+/// gzip compressed when the path ends with `.gz`.
+pub fn write<T: Voxel, P: AsRef<Path>>(path: P, grid: &Grid3<T>) -> Result<()> {
+    let path = path.as_ref();
+    let version = match grid.dims().iter().all(|d| *d <= i16::MAX as usize) {
+        true => Version::Nifti1,
+        false => Version::Nifti2,
+    };
+    let bytes = write_bytes(grid, version)?;
+    let gz = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("gz"));
+    std::fs::write(path, if gz { gzip_compress(&bytes) } else { bytes })?;
+    Ok(())
+}
+
+/// Serializes a grid as an uncompressed single-file NIfTI image with qform and sform set.
+/// NIfTI-1 holds dimensions up to 32767.
+pub fn write_bytes<T: Voxel>(grid: &Grid3<T>, version: Version) -> Result<Vec<u8>> {
+    let g = grid.geometry();
+    if version == Version::Nifti1 && g.dims.iter().any(|d| *d > i16::MAX as usize) {
+        return Err(Error::Unsupported(format!(
+            "NIfTI-1 dimensions {:?} exceed {}",
+            g.dims,
+            i16::MAX
+        )));
+    }
+    let ras = ras_lps() * g.index_to_world();
+    let mut ras_dir = [Vec3::new(); 3];
+    for (a, d) in ras_dir.iter_mut().enumerate() {
+        *d = ras_lps().transform_vector(g.direction[a]);
+    }
+    let (quatern, qfac) = quaternion(&ras_dir);
+    let m4 = ras.to_matrix4();
+    let t = T::SCALAR_TYPE;
+    let dims = [
+        3,
+        g.dims[0] as i64,
+        g.dims[1] as i64,
+        g.dims[2] as i64,
+        1,
+        1,
+        1,
+        1,
+    ];
+    let pixdim = [
+        qfac,
+        g.spacing.x,
+        g.spacing.y,
+        g.spacing.z,
+        0.0,
+        0.0,
+        0.0,
+        0.0,
+    ];
+    let mut h = match version {
+        Version::Nifti1 => {
+            let mut h = vec![0u8; NIFTI1_HEADER + 4];
+            h[0..4].copy_from_slice(&(NIFTI1_HEADER as i32).to_le_bytes());
+            h[38] = b'r';
+            for (n, d) in dims.iter().enumerate() {
+                h[40 + 2 * n..42 + 2 * n].copy_from_slice(&(*d as i16).to_le_bytes());
+            }
+            h[70..72].copy_from_slice(&datatype_code(t).to_le_bytes());
+            h[72..74].copy_from_slice(&(8 * t.size() as i16).to_le_bytes());
+            for (n, p) in pixdim.iter().enumerate() {
+                h[76 + 4 * n..80 + 4 * n].copy_from_slice(&(*p as f32).to_le_bytes());
+            }
+            h[108..112].copy_from_slice(&((NIFTI1_HEADER + 4) as f32).to_le_bytes());
+            h[112..116].copy_from_slice(&1f32.to_le_bytes());
+            h[123] = UNITS_MM;
+            h[252..254].copy_from_slice(&(XFORM_SCANNER as i16).to_le_bytes());
+            h[254..256].copy_from_slice(&(XFORM_SCANNER as i16).to_le_bytes());
+            let q = [
+                quatern[0], quatern[1], quatern[2], m4[0][3], m4[1][3], m4[2][3],
+            ];
+            for (n, v) in q.iter().enumerate() {
+                h[256 + 4 * n..260 + 4 * n].copy_from_slice(&(*v as f32).to_le_bytes());
+            }
+            for (row, s) in m4.iter().take(3).enumerate() {
+                for (c, v) in s.iter().enumerate() {
+                    let at = 280 + 16 * row + 4 * c;
+                    h[at..at + 4].copy_from_slice(&(*v as f32).to_le_bytes());
+                }
+            }
+            h[344..348].copy_from_slice(b"n+1\0");
+            h
+        }
+        Version::Nifti2 => {
+            let mut h = vec![0u8; NIFTI2_HEADER + 4];
+            h[0..4].copy_from_slice(&(NIFTI2_HEADER as i32).to_le_bytes());
+            h[4..12].copy_from_slice(b"n+2\0\r\n\x1a\n");
+            h[12..14].copy_from_slice(&datatype_code(t).to_le_bytes());
+            h[14..16].copy_from_slice(&(8 * t.size() as i16).to_le_bytes());
+            for (n, d) in dims.iter().enumerate() {
+                h[16 + 8 * n..24 + 8 * n].copy_from_slice(&d.to_le_bytes());
+            }
+            for (n, p) in pixdim.iter().enumerate() {
+                h[104 + 8 * n..112 + 8 * n].copy_from_slice(&p.to_le_bytes());
+            }
+            h[168..176].copy_from_slice(&((NIFTI2_HEADER + 4) as i64).to_le_bytes());
+            h[176..184].copy_from_slice(&1f64.to_le_bytes());
+            h[344..348].copy_from_slice(&XFORM_SCANNER.to_le_bytes());
+            h[348..352].copy_from_slice(&XFORM_SCANNER.to_le_bytes());
+            let q = [
+                quatern[0], quatern[1], quatern[2], m4[0][3], m4[1][3], m4[2][3],
+            ];
+            for (n, v) in q.iter().enumerate() {
+                h[352 + 8 * n..360 + 8 * n].copy_from_slice(&v.to_le_bytes());
+            }
+            for (row, s) in m4.iter().take(3).enumerate() {
+                for (c, v) in s.iter().enumerate() {
+                    let at = 400 + 32 * row + 8 * c;
+                    h[at..at + 8].copy_from_slice(&v.to_le_bytes());
+                }
+            }
+            h[500..504].copy_from_slice(&(UNITS_MM as i32).to_le_bytes());
+            h
+        }
+    };
+    h.extend(t.encode(grid.data().iter().map(|v| v.to_f64()), false));
+    Ok(h)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::affine::Affine3;
+    use crate::coords::Vec3;
+    use crate::error::Error;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::io::deflate::gzip_compress;
+    use crate::io::nifti::{parse_header, qform_affine, read_bytes, write_bytes, Version};
+
+    fn grid() -> Grid3<f32> {
+        let mut geometry = GridGeometry::new(
+            [3, 4, 2],
+            Vec3::from(-20.0, 10.0, 5.0),
+            Vec3::from(1.5, 2.0, 3.0),
+        );
+        let rot = Affine3::rotation(0.0, 0.0, 0.3);
+        for d in geometry.direction.iter_mut() {
+            *d = rot.transform_vector(*d);
+        }
+        Grid3::from_vec(geometry, (0..24).map(|v| v as f32 * 0.5).collect()).unwrap()
+    }
+
+    fn assert_same_geometry(a: &GridGeometry, b: &GridGeometry) {
+        assert_eq!(a.dims, b.dims);
+        assert!(a.origin.distance(b.origin) < 1e-4);
+        assert!(a.spacing.distance(b.spacing) < 1e-5);
+        for n in 0..3 {
+            assert!(a.direction[n].distance(b.direction[n]) < 1e-5);
+        }
+    }
+
+    #[test]
+    fn nifti1_roundtrip() {
+        let g = grid();
+        let bytes = write_bytes(&g, Version::Nifti1).unwrap();
+        assert_eq!(bytes.len(), 352 + 24 * 4);
+        let back = read_bytes(&bytes).unwrap();
+        assert_same_geometry(back.geometry(), g.geometry());
+        assert_eq!(back.data()[5], 2.5);
+        let gz = read_bytes(&gzip_compress(&bytes)).unwrap();
+        assert_eq!(gz.data(), back.data());
+    }
+
+    #[test]
+    fn nifti1_dimension_limit() {
+        let geometry = GridGeometry::new([40000, 1, 1], Vec3::new(), Vec3::from(1.0, 1.0, 1.0));
+        let long = Grid3::new(geometry, 1.0f32);
+        assert!(matches!(
+            write_bytes(&long, Version::Nifti1),
+            Err(Error::Unsupported(_))
+        ));
+        let back = read_bytes(&write_bytes(&long, Version::Nifti2).unwrap()).unwrap();
+        assert_eq!(back.dims(), [40000, 1, 1]);
+    }
+
+    #[test]
+    fn nifti2_roundtrip() {
+        let g = grid();
+        let back = read_bytes(&write_bytes(&g, Version::Nifti2).unwrap()).unwrap();
+        assert_same_geometry(back.geometry(), g.geometry());
+        assert_eq!(back.data()[23], 11.5);
+    }
+
+    #[test]
+    fn nifti_ras_to_lps() {
+        let g = grid();
+        let bytes = write_bytes(&g, Version::Nifti1).unwrap();
+        let (h, _) = parse_header(&bytes).unwrap();
+        // The first voxel's RAS x/y are the negated LPS coordinates.
+        assert!((h.srow[0][3] - 20.0).abs() < 1e-4);
+        assert!((h.srow[1][3] + 10.0).abs() < 1e-4);
+        assert!((h.srow[2][3] - 5.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn nifti_qform_only() {
+        let g = grid();
+        let mut bytes = write_bytes(&g, Version::Nifti1).unwrap();
+        bytes[254] = 0;
+        let (h, _) = parse_header(&bytes).unwrap();
+        assert_eq!(h.sform_code, 0);
+        let q = qform_affine(&h);
+        let s = h.srow;
+        let sform = Affine3::from_matrix4([s[0], s[1], s[2], [0.0, 0.0, 0.0, 1.0]]);
+        let p = Vec3::from(2.0, 3.0, 1.0);
+        assert!(q.transform_point(p).distance(sform.transform_point(p)) < 1e-4);
+        assert_same_geometry(read_bytes(&bytes).unwrap().geometry(), g.geometry());
+    }
+
+    #[test]
+    fn nifti_mask_and_scaling() {
+        let mask = grid().map(|v| *v > 5.0);
+        let mut bytes = write_bytes(&mask, Version::Nifti1).unwrap();
+        bytes[112..116].copy_from_slice(&2f32.to_le_bytes());
+        bytes[116..120].copy_from_slice(&1f32.to_le_bytes());
+        let back = read_bytes(&bytes).unwrap();
+        assert_eq!(back.data()[0], 1.0);
+        assert_eq!(back.data()[23], 3.0);
+    }
+
+    #[test]
+    fn nifti_rejects_4d() {
+        let mut bytes = write_bytes(&grid(), Version::Nifti1).unwrap();
+        bytes[40..42].copy_from_slice(&4i16.to_le_bytes());
+        bytes[48..50].copy_from_slice(&2i16.to_le_bytes());
+        assert!(read_bytes(&bytes).is_err());
+        assert!(read_bytes(b"not a nifti file at all").is_err());
+    }
+
+    #[test]
+    fn nifti_rejects_malformed_header() {
+        // Dimensions whose byte size overflows.
+        let mut bytes = write_bytes(&grid(), Version::Nifti2).unwrap();
+        for a in 1..4 {
+            bytes[16 + 8 * a..24 + 8 * a].copy_from_slice(&(1i64 << 40).to_le_bytes());
+        }
+        assert!(matches!(read_bytes(&bytes), Err(Error::Format(_))));
+        // An sform without extent along the first axis.
+        let mut bytes = write_bytes(&grid(), Version::Nifti1).unwrap();
+        for row in 0..3 {
+            let at = 280 + 16 * row;
+            bytes[at..at + 4].copy_from_slice(&0f32.to_le_bytes());
+        }
+        assert!(matches!(read_bytes(&bytes), Err(Error::Format(_))));
+    }
+}
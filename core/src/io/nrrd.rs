@@ -0,0 +1,427 @@
+//! NRRD volumes with attached (`.nrrd`) or detached (`.nhdr`) headers.
+//!
+//! Volumes in a right-anterior-superior space are converted to LPS on reading; files are
+//! always written in `left-posterior-superior` space.
+
+use crate::coords::Vec3;
+use crate::error::{Error, Result};
+use crate::grid::{Grid3, GridGeometry};
+use crate::io::deflate::{gzip_compress, gzip_decompress};
+use crate::io::{ScalarType, Voxel};
+use std::path::Path;
+
+fn scalar_type(name: &str) -> Result<ScalarType> {
+    Ok(match name {
+        "signed char" | "int8" | "int8_t" => ScalarType::I8,
+        "uchar" | "unsigned char" | "uint8" | "uint8_t" => ScalarType::U8,
+        "short" | "short int" | "signed short" | "signed short int" | "int16" | "int16_t" => {
+            ScalarType::I16
+        }
+        "ushort" | "unsigned short" | "unsigned short int" | "uint16" | "uint16_t" => {
+            ScalarType::U16
+        }
+        "int" | "signed int" | "int32" | "int32_t" => ScalarType::I32,
+        "uint" | "unsigned int" | "uint32" | "uint32_t" => ScalarType::U32,
+        "longlong"
+        | "long long"
+        | "long long int"
+        | "signed long long"
+        | "signed long long int"
+        | "int64"
+        | "int64_t" => ScalarType::I64,
+        "ulonglong" | "unsigned long long" | "unsigned long long int" | "uint64" | "uint64_t" => {
+            ScalarType::U64
+        }
+        "float" => ScalarType::F32,
+        "double" => ScalarType::F64,
+        _ => return Err(Error::Unsupported(format!("NRRD type {}", name))),
+    })
+}
+
+fn type_name(t: ScalarType) -> &'static str {
+    match t {
+        ScalarType::I8 => "int8",
+        ScalarType::U8 => "uint8",
+        ScalarType::I16 => "int16",
+        ScalarType::U16 => "uint16",
+        ScalarType::I32 => "int32",
+        ScalarType::U32 => "uint32",
+        ScalarType::I64 => "int64",
+        ScalarType::U64 => "uint64",
+        ScalarType::F32 => "float",
+        ScalarType::F64 => "double",
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Header {
+    geometry: GridGeometry,
+    scalar_type: ScalarType,
+    big_endian: bool,
+    gzip: bool,
+    data_file: Option<String>,
+    byte_skip: usize,
+}
+
+/// Parses a `(x,y,z)` vector.
+fn vector(s: &str) -> Result<Vec3<f64>> {
+    let inner = s
+        .trim()
+        .strip_prefix('(')
+        .and_then(|s| s.strip_suffix(')'))
+        .ok_or_else(|| Error::Format(format!("NRRD vector '{}'", s)))?;
+    let v = inner
+        .split(',')
+        .map(|c| c.trim().parse::<f64>())
+        .collect::<std::result::Result<Vec<f64>, _>>()
+        .map_err(|_| Error::Format(format!("NRRD vector '{}'", s)))?;
+    if v.len() != 3 {
+        return Err(Error::Unsupported(format!(
+            "NRRD {}-D space vector",
+            v.len()
+        )));
+    }
+    Ok(Vec3::from(v[0], v[1], v[2]))
+}
+
+/// Parses the header; returns it with the offset of the first byte after the blank line.
+fn parse_header(bytes: &[u8]) -> Result<(Header, usize)> {
+    if !bytes.starts_with(b"NRRD000") {
+        return Err(Error::Format("not a NRRD file".to_string()));
+    }
+    let mut dims = None;
+    let mut spacings = None;
+    let mut directions = None;
+    let mut origin = Vec3::new();
+    let mut flip = Vec3::from(1.0, 1.0, 1.0);
+    let mut scalar = None;
+    let mut big_endian = false;
+    let mut gzip = false;
+    let mut data_file = None;
+    let mut byte_skip = 0;
+    let mut pos = 0;
+    let mut first = true;
+    while pos < bytes.len() {
+        let end = bytes[pos..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(bytes.len(), |e| pos + e);
+        let line = std::str::from_utf8(&bytes[pos..end])
+            .map_err(|_| Error::Format("NRRD header is not valid text".to_string()))?
+            .trim_end_matches('\r');
+        pos = (end + 1).min(bytes.len());
+        if first {
+            first = false;
+            continue;
+        }
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with('#') || line.contains(":=") {
+            continue;
+        }
+        let (key, value) = match line.find(": ") {
+            Some(i) => (line[..i].trim(), line[i + 2..].trim()),
+            None => return Err(Error::Format(format!("NRRD header line '{}'", line))),
+        };
+        match key {
+            "type" => scalar = Some(scalar_type(value)?),
+            "dimension" if value != "3" => {
+                return Err(Error::Unsupported(format!("{}-dimensional NRRD", value)))
+            }
+            "sizes" => {
+                let s = value
+                    .split_whitespace()
+                    .map(|v| v.parse::<usize>())
+                    .collect::<std::result::Result<Vec<usize>, _>>()
+                    .map_err(|_| Error::Format(format!("NRRD sizes '{}'", value)))?;
+                if s.len() != 3 {
+                    return Err(Error::Format(format!("NRRD sizes '{}'", value)));
+                }
+                dims = Some([s[0], s[1], s[2]]);
+            }
+            "spacings" => {
+                let s = value
+                    .split_whitespace()
+                    .map(|v| v.parse::<f64>())
+                    .collect::<std::result::Result<Vec<f64>, _>>()
+                    .map_err(|_| Error::Format(format!("NRRD spacings '{}'", value)))?;
+                if s.len() != 3 {
+                    return Err(Error::Format(format!("NRRD spacings '{}'", value)));
+                }
+                spacings = Some(Vec3::from(s[0], s[1], s[2]));
+            }
+            "space" => {
+                flip = match value {
+                    "left-posterior-superior" | "LPS" => Vec3::from(1.0, 1.0, 1.0),
+                    "right-anterior-superior" | "RAS" => Vec3::from(-1.0, -1.0, 1.0),
+                    "left-anterior-superior" | "LAS" => Vec3::from(1.0, -1.0, 1.0),
+                    _ => return Err(Error::Unsupported(format!("NRRD space '{}'", value))),
+                }
+            }
+            "space dimension" if value != "3" => {
+                return Err(Error::Unsupported(format!(
+                    "NRRD space dimension {}",
+                    value
+                )))
+            }
+            "space directions" => {
+                let mut d = [Vec3::new(); 3];
+                let parts: Vec<&str> = value.split_whitespace().collect();
+                if parts.len() != 3 {
+                    return Err(Error::Format(format!("NRRD space directions '{}'", value)));
+                }
+                for (a, p) in parts.iter().enumerate() {
+                    d[a] = vector(p)?;
+                }
+                directions = Some(d);
+            }
+            "space origin" => origin = vector(value)?,
+            "endian" => big_endian = value == "big",
+            "encoding" => {
+                gzip = match value {
+                    "raw" => false,
+                    "gzip" | "gz" => true,
+                    _ => return Err(Error::Unsupported(format!("NRRD encoding '{}'", value))),
+                }
+            }
+            "data file" | "datafile" => {
+                if value.starts_with("LIST") || value.contains(' ') {
+                    return Err(Error::Unsupported(
+                        "NRRD data split over multiple files".to_string(),
+                    ));
+                }
+                data_file = Some(value.to_string());
+            }
+            "byte skip" | "byteskip" => {
+                byte_skip = value
+                    .parse::<usize>()
+                    .map_err(|_| Error::Unsupported(format!("NRRD byte skip {}", value)))?
+            }
+            _ => {}
+        }
+    }
+    let dims = dims.ok_or_else(|| Error::Format("NRRD header without sizes".to_string()))?;
+    let scalar_type =
+        scalar.ok_or_else(|| Error::Format("NRRD header without type".to_string()))?;
+    let geometry = match directions {
+        Some(d) => {
+            let mut g = GridGeometry::new(dims, origin * flip, Vec3::new());
+            let mut spacing = [0.0; 3];
+            for a in 0..3 {
+                spacing[a] = d[a].norm();
+                g.direction[a] = (d[a] * flip).normalize();
+            }
+            g.spacing = Vec3::from_array(spacing);
+            g
+        }
+        None => GridGeometry::new(
+            dims,
+            origin * flip,
+            spacings.unwrap_or(Vec3::from(1.0, 1.0, 1.0)),
+        ),
+    };
+    let header = Header {
+        geometry,
+        scalar_type,
+        big_endian,
+        gzip,
+        data_file,
+        byte_skip,
+    };
+    Ok((header, pos))
+}
+
+fn decode(header: Header, raw: &[u8]) -> Result<Grid3<f64>> {
+    let unzipped;
+    let raw = if header.gzip {
+        unzipped = gzip_decompress(raw)?;
+        &unzipped[..]
+    } else {
+        raw
+    };
+    header.geometry.check()?;
+    let n = header.scalar_type.data_size(header.geometry.dims)?;
+    let data = raw
+        .get(header.byte_skip..header.byte_skip.saturating_add(n))
+        .ok_or_else(|| Error::Format("NRRD voxel data is truncated".to_string()))?;
+    let values = header.scalar_type.decode(data, header.big_endian)?;
+    Grid3::from_vec(header.geometry, values)
+}
+
+/// Reads a `.nrrd` or `.nhdr` file; detached data files are resolved relative to the header.
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Grid3<f64>> {
+    let path = path.as_ref();
+    let bytes = std::fs::read(path)?;
+    let (header, offset) = parse_header(&bytes)?;
+    match header.data_file.clone() {
+        None => decode(header, &bytes[offset..]),
+        Some(file) => {
+            let data_path = path.parent().unwrap_or_else(|| Path::new("")).join(file);
+            let raw = std::fs::read(data_path)?;
+            decode(header, &raw)
+        }
+    }
+}
+
+/// Reads a NRRD file with an attached header held in memory.
+pub fn read_bytes(bytes: &[u8]) -> Result<Grid3<f64>> {
+    let (header, offset) = parse_header(bytes)?;
+    if let Some(file) = &header.data_file {
+        return Err(Error::InvalidArgument(format!(
+            "NRRD data is stored in '{}', use read() with the header path",
+            file
+        )));
+    }
+    decode(header, &bytes[offset..])
+}
+
+fn header_text<T: Voxel>(grid: &Grid3<T>, gzip: bool, data_file: Option<&str>) -> String {
+    let g = grid.geometry();
+    let mut h = String::from("NRRD0004\n# Written by planrt\n");
+    h.push_str(&format!("type: {}\n", type_name(T::SCALAR_TYPE)));
+    h.push_str("dimension: 3\nspace: left-posterior-superior\n");
+    h.push_str(&format!(
+        "sizes: {} {} {}\n",
+        g.dims[0], g.dims[1], g.dims[2]
+    ));
+    let s = g.spacing.to_array();
+    let d: Vec<String> = (0..3)
+        .map(|a| {
+            let v = g.direction[a].scale(s[a]);
+            format!("({},{},{})", v.x, v.y, v.z)
+        })
+        .collect();
+    h.push_str(&format!("space directions: {}\n", d.join(" ")));
+    h.push_str("kinds: domain domain domain\nendian: little\n");
+    h.push_str(&format!(
+        "encoding: {}\n",
+        if gzip { "gzip" } else { "raw" }
+    ));
+    h.push_str(&format!(
+        "space origin: ({},{},{})\n",
+        g.origin.x, g.origin.y, g.origin.z
+    ));
+    if let Some(file) = data_file {
+        h.push_str(&format!("data file: {}\n", file));
+    }
+    h.push('\n');
+    h
+}
+
+fn encode<T: Voxel>(grid: &Grid3<T>, gzip: bool) -> Vec<u8> {
+    let raw = T::SCALAR_TYPE.encode(grid.data().iter().map(|v| v.to_f64()), false);
+    if gzip {
+        gzip_compress(&raw)
+    } else {
+        raw
+    }
+}
+
+/// Serializes a grid as a NRRD file with an attached header.
+pub fn write_bytes<T: Voxel>(grid: &Grid3<T>, gzip: bool) -> Vec<u8> {
+    let mut out = header_text(grid, gzip, None).into_bytes();
+    out.extend(encode(grid, gzip));
+    out
+}
+
+/// Writes a `.nrrd` file, or for a `.nhdr` path a detached header plus a `.raw`(`.gz`) file.
+pub fn write<T: Voxel, P: AsRef<Path>>(path: P, grid: &Grid3<T>, gzip: bool) -> Result<()> {
+    let path = path.as_ref();
+    let detached = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("nhdr"));
+    if !detached {
+        std::fs::write(path, write_bytes(grid, gzip))?;
+        return Ok(());
+    }
+    let data_path = path.with_extension(if gzip { "raw.gz" } else { "raw" });
+    let data_name = data_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| Error::InvalidArgument(format!("invalid path {}", path.display())))?
+        .to_string();
+    std::fs::write(&data_path, encode(grid, gzip))?;
+    std::fs::write(path, header_text(grid, gzip, Some(&data_name)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::affine::Affine3;
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::io::nrrd::{read, read_bytes, write, write_bytes};
+
+    fn grid() -> Grid3<f32> {
+        let mut geometry = GridGeometry::new(
+            [3, 2, 2],
+            Vec3::from(10.0, -20.0, 30.0),
+            Vec3::from(1.0, 2.0, 3.0),
+        );
+        let rot = Affine3::rotation(0.0, 0.2, 0.0);
+        for d in geometry.direction.iter_mut() {
+            *d = rot.transform_vector(*d);
+        }
+        Grid3::from_vec(geometry, (0..12).map(|v| v as f32 / 4.0).collect()).unwrap()
+    }
+
+    fn assert_same_geometry(a: &GridGeometry, b: &GridGeometry) {
+        assert_eq!(a.dims, b.dims);
+        assert!(a.origin.distance(b.origin) < 1e-9);
+        assert!(a.spacing.distance(b.spacing) < 1e-9);
+        for n in 0..3 {
+            assert!(a.direction[n].distance(b.direction[n]) < 1e-9);
+        }
+    }
+
+    #[test]
+    fn nrrd_roundtrip() {
+        let g = grid();
+        for &gzip in [false, true].iter() {
+            let back = read_bytes(&write_bytes(&g, gzip)).unwrap();
+            assert_same_geometry(back.geometry(), g.geometry());
+            assert_eq!(back.data()[11], 2.75);
+        }
+    }
+
+    #[test]
+    fn nrrd_ras_space() {
+        let mut text = b"NRRD0005\n# comment\ntype: short\ndimension: 3\n\
+            space: right-anterior-superior\nsizes: 2 1 1\n\
+            space directions: (-2,0,0) (0,-2,0) (0,0,3)\nendian: big\nencoding: raw\n\
+            space origin: (100,50,-10)\nmeta key:=value\n\n"
+            .to_vec();
+        text.extend_from_slice(&[0xff, 0xfe, 0x00, 0x05]);
+        let g = read_bytes(&text).unwrap();
+        assert_eq!(g.data(), &[-2.0, 5.0]);
+        let geometry = g.geometry();
+        assert_eq!(geometry.origin, Vec3::from(-100.0, -50.0, -10.0));
+        assert_eq!(geometry.spacing, Vec3::from(2.0, 2.0, 3.0));
+        assert_eq!(geometry.direction[0], Vec3::from(1.0, 0.0, 0.0));
+        assert_eq!(geometry.direction[1], Vec3::from(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn nrrd_detached() {
+        let dir = std::env::temp_dir().join(format!("planrt-nhdr-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dose.nhdr");
+        let g = grid().map(|v| *v > 1.0);
+        write(&path, &g, true).unwrap();
+        assert!(dir.join("dose.raw.gz").exists());
+        let back = read(&path).unwrap();
+        assert_same_geometry(back.geometry(), g.geometry());
+        assert_eq!(back.data()[4], 0.0);
+        assert_eq!(back.data()[5], 1.0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nrrd_errors() {
+        assert!(read_bytes(b"P5\n").is_err());
+        assert!(read_bytes(b"NRRD0004\ntype: float\ndimension: 4\n\n").is_err());
+        assert!(read_bytes(b"NRRD0004\ntype: float\nsizes: 1 1 1\ndata file: x.raw\n\n").is_err());
+        assert!(read_bytes(b"NRRD0004\ntype: float\nsizes: 1 1 1\nencoding: bzip2\n\n").is_err());
+    }
+}
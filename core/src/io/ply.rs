@@ -0,0 +1,62 @@
+//! ASCII PLY export of triangle meshes, optionally with a uniform vertex color.
+
+use std::fmt::Write;
+use std::path::Path;
+
+use crate::error::Result;
+use crate::mesh::TriangleMesh;
+
+pub fn write_string(mesh: &TriangleMesh, color: Option<[u8; 3]>) -> String {
+    let mut out = String::new();
+    out.push_str("ply\nformat ascii 1.0\ncomment planrt\n");
+    writeln!(out, "element vertex {}", mesh.vertices.len()).unwrap();
+    out.push_str("property float x\nproperty float y\nproperty float z\n");
+    if color.is_some() {
+        out.push_str("property uchar red\nproperty uchar green\nproperty uchar blue\n");
+    }
+    writeln!(out, "element face {}", mesh.triangles.len()).unwrap();
+    out.push_str("property list uchar int vertex_indices\nend_header\n");
+    for v in &mesh.vertices {
+        write!(out, "{} {} {}", v.x, v.y, v.z).unwrap();
+        if let Some([r, g, b]) = color {
+            write!(out, " {} {} {}", r, g, b).unwrap();
+        }
+        out.push('\n');
+    }
+    for [a, b, c] in &mesh.triangles {
+        writeln!(out, "3 {} {} {}", a, b, c).unwrap();
+    }
+    out
+}
+
+pub fn write<P: AsRef<Path>>(path: P, mesh: &TriangleMesh, color: Option<[u8; 3]>) -> Result<()> {
+    std::fs::write(path, write_string(mesh, color))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::io::ply::write_string;
+    use crate::mesh::TriangleMesh;
+
+    #[test]
+    fn ply_write() {
+        let mesh = TriangleMesh {
+            vertices: vec![
+                Vec3::from(0.0, 0.0, 0.0),
+                Vec3::from(1.5, 0.0, 0.0),
+                Vec3::from(0.0, 1.0, -2.0),
+            ],
+            triangles: vec![[0, 1, 2]],
+        };
+        let text = write_string(&mesh, Some([255, 0, 0]));
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines.contains(&"element vertex 3"));
+        assert!(lines.contains(&"element face 1"));
+        assert!(lines.contains(&"property uchar red"));
+        assert_eq!(lines[lines.len() - 3], "1.5 0 0 255 0 0");
+        assert_eq!(lines[lines.len() - 1], "3 0 1 2");
+        assert!(!write_string(&mesh, None).contains("red"));
+    }
+}
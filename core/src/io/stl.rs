@@ -0,0 +1,103 @@
+//! Binary STL export and import of triangle meshes.
+//!
+//! STL stores unshared triangles in single precision; [`read_bytes`] merges identical
+//! vertices again.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::coords::Vec3;
+use crate::error::{Error, Result};
+use crate::mesh::TriangleMesh;
+
+pub fn write_bytes(mesh: &TriangleMesh, name: &str) -> Vec<u8> {
+    let mut out = vec![0u8; 80];
+    let name = name.as_bytes();
+    let n = name.len().min(80);
+    out[..n].copy_from_slice(&name[..n]);
+    out.extend_from_slice(&(mesh.triangles.len() as u32).to_le_bytes());
+    for t in 0..mesh.triangles.len() {
+        let n = mesh.normal(t);
+        let [a, b, c] = mesh.corners(t);
+        for v in [n, a, b, c].iter() {
+            for x in v.to_array().iter() {
+                out.extend_from_slice(&(*x as f32).to_le_bytes());
+            }
+        }
+        out.extend_from_slice(&[0, 0]);
+    }
+    out
+}
+
+pub fn write<P: AsRef<Path>>(path: P, mesh: &TriangleMesh, name: &str) -> Result<()> {
+    std::fs::write(path, write_bytes(mesh, name))?;
+    Ok(())
+}
+
+pub fn read_bytes(bytes: &[u8]) -> Result<TriangleMesh> {
+    if bytes.len() < 84 {
+        return Err(Error::Format(
+            "STL file shorter than its header".to_string(),
+        ));
+    }
+    let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+    if bytes.len() < 84 + 50 * count {
+        return Err(Error::Format(format!(
+            "STL file too short for {} triangles",
+            count
+        )));
+    }
+    let float = |o: usize| f32::from_le_bytes([bytes[o], bytes[o + 1], bytes[o + 2], bytes[o + 3]]);
+    let mut mesh = TriangleMesh::new();
+    let mut index: HashMap<[u32; 3], usize> = HashMap::new();
+    for t in 0..count {
+        let record = 84 + 50 * t;
+        let mut triangle = [0; 3];
+        for (c, id) in triangle.iter_mut().enumerate() {
+            let o = record + 12 + 12 * c;
+            let p = [float(o), float(o + 4), float(o + 8)];
+            let key = [p[0].to_bits(), p[1].to_bits(), p[2].to_bits()];
+            *id = *index.entry(key).or_insert_with(|| {
+                mesh.vertices
+                    .push(Vec3::from(p[0] as f64, p[1] as f64, p[2] as f64));
+                mesh.vertices.len() - 1
+            });
+        }
+        mesh.triangles.push(triangle);
+    }
+    Ok(mesh)
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> Result<TriangleMesh> {
+    read_bytes(&std::fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::io::stl::{read_bytes, write_bytes};
+    use crate::mesh::TriangleMesh;
+
+    #[test]
+    fn stl_round_trip() {
+        let mesh = TriangleMesh {
+            vertices: vec![
+                Vec3::from(0.0, 0.0, 0.0),
+                Vec3::from(1.0, 0.0, 0.0),
+                Vec3::from(0.0, 1.0, 0.0),
+                Vec3::from(0.0, 0.0, 1.0),
+            ],
+            triangles: vec![[0, 2, 1], [0, 1, 3], [0, 3, 2], [1, 2, 3]],
+        };
+        let bytes = write_bytes(&mesh, "BOLUS");
+        assert_eq!(bytes.len(), 84 + 4 * 50);
+        assert_eq!(&bytes[..5], b"BOLUS");
+        let back = read_bytes(&bytes).unwrap();
+        assert_eq!(back.vertices.len(), 4);
+        for t in 0..4 {
+            assert_eq!(back.corners(t), mesh.corners(t));
+        }
+        assert!((back.volume() - 1.0 / 6.0).abs() < 1e-7);
+        assert!(read_bytes(&bytes[..100]).is_err());
+    }
+}
@@ -0,0 +1,357 @@
+//! The TOML subset of the configuration files of this crate: `key = value` pairs under
+//! `[table]` headers or `[[table]]` arrays of tables, with strings, numbers, booleans and
+//! single-line arrays of those as values, and `#` comments. Strings have no escapes.
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Number(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    key: String,
+    value: Value,
+    /// 1-based line of the entry.
+    line: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Table {
+    /// Name of the table, empty for the top level.
+    pub name: String,
+    /// Whether the table is an element of a `[[name]]` array.
+    pub array: bool,
+    entries: Vec<Entry>,
+}
+
+impl Table {
+    fn header(&self) -> String {
+        match (self.name.is_empty(), self.array) {
+            (true, _) => String::new(),
+            (false, true) => format!("[[{}]] ", self.name),
+            (false, false) => format!("[{}] ", self.name),
+        }
+    }
+
+    fn invalid(&self, entry: &Entry, expected: &str) -> Error {
+        Error::Format(format!(
+            "line {}: {}{} must be {}",
+            entry.line,
+            self.header(),
+            entry.key,
+            expected
+        ))
+    }
+
+    fn entry(&self, key: &str) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.key == key)
+    }
+
+    pub fn string(&self, key: &str) -> Result<Option<&str>> {
+        match self.entry(key) {
+            None => Ok(None),
+            Some(Entry {
+                value: Value::String(s),
+                ..
+            }) => Ok(Some(s)),
+            Some(e) => Err(self.invalid(e, "a string")),
+        }
+    }
+
+    pub fn number(&self, key: &str) -> Result<Option<f64>> {
+        match self.entry(key) {
+            None => Ok(None),
+            Some(Entry {
+                value: Value::Number(v),
+                ..
+            }) => Ok(Some(*v)),
+            Some(e) => Err(self.invalid(e, "a number")),
+        }
+    }
+
+    pub fn boolean(&self, key: &str) -> Result<Option<bool>> {
+        match self.entry(key) {
+            None => Ok(None),
+            Some(Entry {
+                value: Value::Boolean(b),
+                ..
+            }) => Ok(Some(*b)),
+            Some(e) => Err(self.invalid(e, "true or false")),
+        }
+    }
+
+    pub fn strings(&self, key: &str) -> Result<Option<Vec<&str>>> {
+        let entry = match self.entry(key) {
+            None => return Ok(None),
+            Some(e) => e,
+        };
+        match &entry.value {
+            Value::Array(items) => items
+                .iter()
+                .map(|i| match i {
+                    Value::String(s) => Ok(s.as_str()),
+                    _ => Err(self.invalid(entry, "an array of strings")),
+                })
+                .collect::<Result<_>>()
+                .map(Some),
+            _ => Err(self.invalid(entry, "an array of strings")),
+        }
+    }
+
+    pub fn numbers(&self, key: &str) -> Result<Option<Vec<f64>>> {
+        let entry = match self.entry(key) {
+            None => return Ok(None),
+            Some(e) => e,
+        };
+        match &entry.value {
+            Value::Array(items) => items
+                .iter()
+                .map(|i| match i {
+                    Value::Number(v) => Ok(*v),
+                    _ => Err(self.invalid(entry, "an array of numbers")),
+                })
+                .collect::<Result<_>>()
+                .map(Some),
+            _ => Err(self.invalid(entry, "an array of numbers")),
+        }
+    }
+
+    /// A `[lo, hi]` array with `lo <= hi`.
+    pub fn range(&self, key: &str) -> Result<Option<[f64; 2]>> {
+        match self.numbers(key)?.as_deref() {
+            None => Ok(None),
+            Some([lo, hi]) if lo <= hi => Ok(Some([*lo, *hi])),
+            Some(_) => Err(self.invalid(self.entry(key).unwrap(), "an ascending [lo, hi] pair")),
+        }
+    }
+
+    /// Fails on keys other than `known`, which are most likely misspelled.
+    pub fn check_keys(&self, known: &[&str]) -> Result<()> {
+        match self
+            .entries
+            .iter()
+            .find(|e| !known.contains(&e.key.as_str()))
+        {
+            Some(e) => Err(Error::Format(format!(
+                "line {}: unknown key {}{}",
+                e.line,
+                self.header(),
+                e.key
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A parsed file: the top-level table followed by the other tables in file order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    tables: Vec<Table>,
+}
+
+/// `line` up to a `#` outside a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// `text` split at the commas outside strings.
+fn split_items(text: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let (mut quoted, mut start) = (false, 0);
+    for (i, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                items.push(&text[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&text[start..]);
+    items
+}
+
+fn parse_value(text: &str) -> Option<Value> {
+    let text = text.trim();
+    if let Some(s) = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        return match s.contains('"') {
+            true => None,
+            false => Some(Value::String(s.to_string())),
+        };
+    }
+    if let Some(items) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+        let mut items = split_items(items);
+        // A trailing comma is allowed.
+        if items.last().is_some_and(|i| i.trim().is_empty()) {
+            items.pop();
+        }
+        return items
+            .iter()
+            .map(|i| match parse_value(i)? {
+                Value::Array(_) => None,
+                v => Some(v),
+            })
+            .collect::<Option<_>>()
+            .map(Value::Array);
+    }
+    match text {
+        "true" => Some(Value::Boolean(true)),
+        "false" => Some(Value::Boolean(false)),
+        _ => text.parse().ok().map(Value::Number),
+    }
+}
+
+impl Document {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut tables = vec![Table::default()];
+        for (n, raw) in text.lines().enumerate() {
+            let invalid = || Error::Format(format!("line {}: {}", n + 1, raw.trim()));
+            let line = strip_comment(raw).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                let (name, array) = match line.strip_prefix("[[").and_then(|l| l.strip_suffix("]]"))
+                {
+                    Some(name) => (name, true),
+                    None => (
+                        line.strip_prefix('[')
+                            .and_then(|l| l.strip_suffix(']'))
+                            .ok_or_else(invalid)?,
+                        false,
+                    ),
+                };
+                let name = name.trim();
+                let clash = tables.iter().any(|t| t.name == name && !(array && t.array));
+                if name.is_empty() || clash {
+                    return Err(invalid());
+                }
+                tables.push(Table {
+                    name: name.to_string(),
+                    array,
+                    entries: Vec::new(),
+                });
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(invalid)?;
+            let key = key.trim();
+            let value = parse_value(value).ok_or_else(invalid)?;
+            let table = tables.last_mut().expect("the top-level table");
+            if key.is_empty() || table.entry(key).is_some() {
+                return Err(invalid());
+            }
+            table.entries.push(Entry {
+                key: key.to_string(),
+                value,
+                line: n + 1,
+            });
+        }
+        Ok(Document { tables })
+    }
+
+    pub fn root(&self) -> &Table {
+        &self.tables[0]
+    }
+
+    /// The `[name]` table, or the `[[name]]` tables, in file order.
+    pub fn tables<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Table> {
+        self.tables[1..].iter().filter(move |t| t.name == name)
+    }
+
+    /// Fails on tables other than `known`.
+    pub fn check_tables(&self, known: &[&str]) -> Result<()> {
+        match self.tables[1..]
+            .iter()
+            .find(|t| !known.contains(&t.name.as_str()))
+        {
+            Some(t) => Err(Error::Format(format!(
+                "unknown table {}",
+                t.header().trim()
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::toml::Document;
+
+    #[test]
+    fn toml_subset() {
+        let doc = Document::parse(
+            r#"
+            # Linac
+            name = "Linac # 1"
+            energies = ["6X", "10X, FFF",]   # trailing comma
+            max_dose_rate = 600
+
+            [mlc]
+            widths = [10.0, 5.0]
+            interdigitation = false
+
+            [[goal]]
+            constraint = "PTV V95% >= 98%"
+            [[goal]]
+            constraint = "Cord Dmax < 45 Gy"
+            "#,
+        )
+        .unwrap();
+        let root = doc.root();
+        assert_eq!(root.string("name").unwrap(), Some("Linac # 1"));
+        assert_eq!(
+            root.strings("energies").unwrap(),
+            Some(vec!["6X", "10X, FFF"])
+        );
+        assert_eq!(root.number("max_dose_rate").unwrap(), Some(600.0));
+        assert_eq!(root.number("missing").unwrap(), None);
+        assert!(root.string("max_dose_rate").is_err());
+        assert!(root.numbers("energies").is_err());
+        assert!(root
+            .check_keys(&["name", "energies", "max_dose_rate"])
+            .is_ok());
+        assert!(root.check_keys(&["name"]).is_err());
+
+        let mlc = doc.tables("mlc").next().unwrap();
+        assert_eq!(mlc.numbers("widths").unwrap(), Some(vec![10.0, 5.0]));
+        assert_eq!(mlc.boolean("interdigitation").unwrap(), Some(false));
+        assert!(mlc.range("widths").is_err());
+        assert_eq!(
+            doc.tables("goal")
+                .map(|t| t.string("constraint").unwrap().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["PTV V95% >= 98%", "Cord Dmax < 45 Gy"]
+        );
+        assert!(doc.check_tables(&["mlc", "goal"]).is_ok());
+        assert!(doc.check_tables(&["mlc"]).is_err());
+
+        for bad in [
+            "name = unquoted",
+            "name",
+            "a = 1\na = 2",
+            "[mlc]\n[mlc]",
+            "[[goal]]\n[goal]",
+            "[]",
+            "a = [[1], 2]",
+            "a = \"x\"y\"",
+        ]
+        .iter()
+        {
+            assert!(Document::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Isodose lines per slice and isodose surfaces.
+//!
+//! Voxels at or above a level are inside. Lines and surfaces are traced with the marching
+//! squares of [`crate::contouring`] and the marching tetrahedra of [`crate::mesh`], with
+//! every vertex placed by linear interpolation of the dose along its voxel edge (halfway to
+//! a voxel outside the grid). Lines follow the orientation of structure contours.
+
+use crate::contouring::trace_crossings;
+use crate::coords::Vec3;
+use crate::error::{Error, Result};
+use crate::grid::Grid3;
+use crate::mesh::{march_tetrahedra, TriangleMesh};
+use crate::metric::DoseUnit;
+use crate::structure::Contour;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IsodoseLines {
+    /// Gy
+    pub level: f64,
+    pub contours: Vec<Contour>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IsodoseSurface {
+    /// Gy
+    pub level: f64,
+    pub mesh: TriangleMesh,
+}
+
+/// `levels` in Gy; percentages are of the `prescription` (Gy).
+fn absolute_levels(levels: &[f64], unit: DoseUnit, prescription: Option<f64>) -> Result<Vec<f64>> {
+    match unit {
+        DoseUnit::Gy => Ok(levels.to_vec()),
+        DoseUnit::Percent => match prescription {
+            Some(p) if p > 0.0 => Ok(levels.iter().map(|l| l * p / 100.0).collect()),
+            _ => Err(Error::InvalidArgument(
+                "relative isodose levels need a positive prescription".to_string(),
+            )),
+        },
+    }
+}
+
+/// Fraction of the way from dose `a` (inside) to dose `b` (outside) where `level` lies.
+fn crossing(a: f64, b: Option<f64>, level: f64) -> f64 {
+    match b {
+        Some(b) if a != b => ((a - level) / (a - b)).clamp(0.0, 1.0),
+        _ => 0.5,
+    }
+}
+
+/// Isodose lines of every slice of `dose` for each of `levels`.
+pub fn isodose_lines(
+    dose: &Grid3<f64>,
+    levels: &[f64],
+    unit: DoseUnit,
+    prescription: Option<f64>,
+) -> Result<Vec<IsodoseLines>> {
+    let geometry = dose.geometry();
+    let [nx, ny, nz] = geometry.dims;
+    let i2w = geometry.index_to_world();
+    let handedness = geometry.direction[0].cross(geometry.direction[1]).z;
+    let levels = absolute_levels(levels, unit, prescription)?;
+    Ok(levels
+        .iter()
+        .map(|level| {
+            let mut contours = Vec::new();
+            for k in 0..nz {
+                let at = |i: i64, j: i64| {
+                    if i < 0 || j < 0 || i as usize >= nx || j as usize >= ny {
+                        None
+                    } else {
+                        Some(dose[[i as usize, j as usize, k]])
+                    }
+                };
+                for ring in trace_crossings(nx, ny, |i, j| dose[[i, j, k]] >= *level) {
+                    let mut points: Vec<Vec3<f64>> = ring
+                        .iter()
+                        .map(|(x, y)| {
+                            // The edge runs between the pixels at the floor and ceiling of
+                            // the halved coordinates.
+                            let (a, b) = (
+                                (x.div_euclid(2), y.div_euclid(2)),
+                                ((x + 1).div_euclid(2), (y + 1).div_euclid(2)),
+                            );
+                            let (da, db) = (at(a.0, a.1), at(b.0, b.1));
+                            let (inside, outside, from, to) = match (da, db) {
+                                (Some(d), _) if d >= *level => (d, db, a, b),
+                                _ => (db.unwrap_or(0.0), da, b, a),
+                            };
+                            let t = crossing(inside, outside, *level);
+                            let px = from.0 as f64 + t * (to.0 - from.0) as f64;
+                            let py = from.1 as f64 + t * (to.1 - from.1) as f64;
+                            i2w.transform_point(Vec3::from(px, py, k as f64))
+                        })
+                        .collect();
+                    if handedness < 0.0 {
+                        points.reverse();
+                    }
+                    contours.push(Contour::new(points));
+                }
+            }
+            IsodoseLines {
+                level: *level,
+                contours,
+            }
+        })
+        .collect())
+}
+
+/// Isodose surface meshes of `dose` for each of `levels`.
+pub fn isodose_surfaces(
+    dose: &Grid3<f64>,
+    levels: &[f64],
+    unit: DoseUnit,
+    prescription: Option<f64>,
+) -> Result<Vec<IsodoseSurface>> {
+    let [nx, ny, nz] = dose.dims();
+    let value = |p: [i64; 3]| {
+        if p.iter().any(|c| *c < 0)
+            || p[0] as usize >= nx
+            || p[1] as usize >= ny
+            || p[2] as usize >= nz
+        {
+            None
+        } else {
+            Some(dose[[p[0] as usize, p[1] as usize, p[2] as usize]])
+        }
+    };
+    let levels = absolute_levels(levels, unit, prescription)?;
+    Ok(levels
+        .iter()
+        .map(|level| {
+            let inside = |i: i64, j: i64, k: i64| value([i, j, k]).is_some_and(|d| d >= *level);
+            let mesh = march_tetrahedra(dose.geometry(), inside, |a, b| {
+                let inside = value(a).unwrap_or(0.0);
+                let t = crossing(inside, value(b), *level);
+                Vec3::from(
+                    a[0] as f64 + t * (b[0] - a[0]) as f64,
+                    a[1] as f64 + t * (b[1] - a[1]) as f64,
+                    a[2] as f64 + t * (b[2] - a[2]) as f64,
+                )
+            });
+            IsodoseSurface {
+                level: *level,
+                mesh,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::isodose::{isodose_lines, isodose_surfaces};
+    use crate::metric::DoseUnit;
+
+    /// Dose falling off with the distance (mm) from the center of a 21³ grid of 1 mm voxels.
+    fn spherical() -> Grid3<f64> {
+        let geometry = GridGeometry::new([21, 21, 21], Vec3::new(), Vec3::from(1.0, 1.0, 1.0));
+        let mut dose = Grid3::new(geometry, 0.0);
+        let center = Vec3::from(10.0, 10.0, 10.0);
+        for n in 0..dose.len() {
+            let [i, j, k] = dose.geometry().ijk(n);
+            let r = dose.geometry().position(i, j, k).distance(center);
+            dose.data_mut()[n] = 60.0 - 6.0 * r;
+        }
+        dose
+    }
+
+    #[test]
+    fn isodose_lines_circle() {
+        let dose = spherical();
+        // 50 % of 60 Gy is 30 Gy, a radius of 5 mm.
+        let lines = isodose_lines(&dose, &[50.0, 200.0], DoseUnit::Percent, Some(60.0)).unwrap();
+        assert_eq!(lines[0].level, 30.0);
+        let slice: Vec<_> = lines[0]
+            .contours
+            .iter()
+            .filter(|c| (c.z() - 10.0).abs() < 1e-9)
+            .collect();
+        assert_eq!(slice.len(), 1);
+        for p in &slice[0].points {
+            let r = p.distance(Vec3::from(10.0, 10.0, 10.0));
+            assert!((r - 5.0).abs() < 0.1, "{}", r);
+        }
+        assert!(slice[0].signed_area() > 0.0);
+        assert!((slice[0].area() - std::f64::consts::PI * 25.0).abs() < 1.5);
+        assert!(lines[1].contours.is_empty());
+        assert!(isodose_lines(&dose, &[50.0], DoseUnit::Percent, None).is_err());
+    }
+
+    #[test]
+    fn isodose_surface_sphere() {
+        let dose = spherical();
+        let surfaces = isodose_surfaces(&dose, &[30.0], DoseUnit::Gy, None).unwrap();
+        let mesh = &surfaces[0].mesh;
+        for v in &mesh.vertices {
+            assert!((v.distance(Vec3::from(10.0, 10.0, 10.0)) - 5.0).abs() < 0.1);
+        }
+        let sphere = 4.0 / 3.0 * std::f64::consts::PI * 125.0;
+        assert!(
+            (mesh.volume() - sphere).abs() / sphere < 0.05,
+            "{}",
+            mesh.volume()
+        );
+    }
+}
@@ -1,2 +1,51 @@
-#[allow(dead_code)]
-mod coords;
+pub mod accumulation;
+pub mod affine;
+pub mod aperture;
+pub mod boolean;
+pub mod collision;
+pub mod comparison;
+pub mod complexity;
+pub mod conformity;
+pub mod contouring;
+pub mod coords;
+pub mod course;
+pub mod crop;
+pub mod distance;
+pub mod dose;
+pub mod dose_comparison;
+pub mod dvf;
+pub mod dvh;
+pub mod error;
+pub mod gamma;
+pub mod grid;
+pub mod interpolate;
+pub mod io;
+pub mod isodose;
+pub mod machine;
+pub mod margin;
+pub mod mesh;
+pub mod metric;
+pub mod motion;
+pub mod nomenclature;
+pub mod normalization;
+pub mod opt;
+pub mod outcome;
+pub mod plan;
+pub mod plan_sum;
+pub mod probe;
+pub mod progress;
+pub mod protocol;
+pub mod radiobiology;
+pub mod raster;
+pub mod raytrace;
+pub mod registration;
+pub mod resample;
+pub mod respiratory;
+pub mod ring;
+pub mod robustness;
+pub mod shape;
+pub mod sparse;
+pub mod structure;
+pub mod uid;
+
+pub use crate::error::{Error, Result};
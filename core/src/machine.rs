@@ -0,0 +1,1078 @@
+//! Treatment machine model and deliverability checking.
+//!
+//! A [`Machine`] describes what a linac can deliver: its beam energies, the geometry of its
+//! MLC and the rules its leaves obey, the travel of its jaws and the top speeds of gantry,
+//! leaves and dose rate. [`Machine::validate`] reports every violation in the control points
+//! of a set of beams rather than stopping at the first. Speeds are only checked where the beam
+//! sets the dose rate of its control point intervals and delivers MU in them; otherwise the
+//! machine slows down as needed, moving at its own pace with the beam off, and the delivery
+//! time is what [`Machine::delivery_time`] returns.
+//!
+//! Machines are built in code or loaded from a TOML subset:
+//!
+//! ```toml
+//! name = "Linac 1"
+//! energies = ["6X", "10X", "6FFF"]
+//! max_gantry_speed = 6.0   # degrees/s
+//! max_dose_rate = 600.0    # MU/min
+//! couch_range = [-90.0, 90.0]   # degrees, clockwise from the first to the second angle
+//!
+//! [mlc]
+//! widths = [10.0, 10.0, 5.0, 5.0, 10.0, 10.0]   # or ascending `boundaries`
+//! max_span = 150.0
+//! interdigitation = true
+//! min_gap = 0.5
+//! leaf_range = [-200.0, 200.0]
+//! max_leaf_speed = 25.0    # mm/s
+//!
+//! [jaws]
+//! x1 = [-200.0, 20.0]
+//! x2 = [-20.0, 200.0]
+//! y1 = [-200.0, 20.0]
+//! y2 = [-20.0, 200.0]
+//! ```
+//!
+//! Leaf `widths` stack symmetrically about the central axis. Gantry, collimator and couch
+//! ranges default to a full rotation.
+
+use std::fmt;
+use std::path::Path;
+
+use crate::dose::delivery::ControlPoint;
+use crate::error::{Error, Result};
+use crate::io::toml::{Document, Table};
+
+/// The rules MLC leaves obey, which the optimizers and sequencers take from the machine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LeafLimits {
+    /// Largest distance (mm) between two leaves of a bank.
+    pub max_span: f64,
+    /// Whether a leaf may travel past the opposite leaf of a neighbouring pair.
+    pub interdigitation: bool,
+    /// Smallest opening (mm) of an open leaf pair.
+    pub min_gap: f64,
+    /// Reach (mm) of every leaf along `x`.
+    pub leaf_range: [f64; 2],
+    /// mm/s
+    pub max_leaf_speed: f64,
+}
+
+impl Default for LeafLimits {
+    fn default() -> Self {
+        LeafLimits {
+            max_span: 150.0,
+            interdigitation: true,
+            min_gap: 0.5,
+            leaf_range: [-200.0, 200.0],
+            max_leaf_speed: 25.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MlcGeometry {
+    /// Ascending `y` (mm) of the leaf pair edges, one more than the pairs.
+    pub boundaries: Vec<f64>,
+    pub leaves: LeafLimits,
+}
+
+impl Default for MlcGeometry {
+    /// 60 pairs over 400 mm: 40 central pairs of 5 mm between 10 pairs of 10 mm on each side.
+    fn default() -> Self {
+        let widths: Vec<f64> = (0..60)
+            .map(|n| if (10..50).contains(&n) { 5.0 } else { 10.0 })
+            .collect();
+        MlcGeometry {
+            boundaries: boundaries(&widths),
+            leaves: LeafLimits::default(),
+        }
+    }
+}
+
+/// Pair edges of leaves of `widths` stacked symmetrically about zero.
+fn boundaries(widths: &[f64]) -> Vec<f64> {
+    let half = 0.5 * widths.iter().sum::<f64>();
+    let mut edges = vec![-half];
+    for w in widths {
+        edges.push(edges[edges.len() - 1] + w);
+    }
+    edges
+}
+
+/// Travel (mm) of every jaw.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JawLimits {
+    pub x1: [f64; 2],
+    pub x2: [f64; 2],
+    pub y1: [f64; 2],
+    pub y2: [f64; 2],
+}
+
+impl Default for JawLimits {
+    fn default() -> Self {
+        JawLimits {
+            x1: [-200.0, 20.0],
+            x2: [-20.0, 200.0],
+            y1: [-200.0, 20.0],
+            y2: [-20.0, 200.0],
+        }
+    }
+}
+
+/// Speed and leaf limits of arc sequencing, see [`crate::opt::vmat`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArcLimits {
+    /// Degrees per second.
+    pub max_gantry_speed: f64,
+    /// MU/min
+    pub max_dose_rate: f64,
+    /// Leaf rules of the machine, see [`MlcGeometry`]: the leaf speed, the minimum gap and
+    /// interdigitation.
+    pub leaves: LeafLimits,
+}
+
+impl Default for ArcLimits {
+    fn default() -> Self {
+        ArcLimits {
+            max_gantry_speed: 4.8,
+            max_dose_rate: 600.0,
+            leaves: LeafLimits {
+                min_gap: 0.0,
+                ..LeafLimits::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Machine {
+    pub name: String,
+    /// Nominal beam energies, e.g. `6X` or `6FFF`.
+    pub energies: Vec<String>,
+    pub mlc: MlcGeometry,
+    pub jaws: JawLimits,
+    /// Degrees per second.
+    pub max_gantry_speed: f64,
+    /// MU/min
+    pub max_dose_rate: f64,
+    /// Reach (degrees) of the gantry, clockwise from the first to the second angle.
+    pub gantry_range: [f64; 2],
+    /// Reach (degrees) of the collimator, as `gantry_range`.
+    pub collimator_range: [f64; 2],
+    /// Reach (degrees) of the couch, as `gantry_range`.
+    pub couch_range: [f64; 2],
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine {
+            name: "Generic".to_string(),
+            energies: vec!["6X".to_string()],
+            mlc: MlcGeometry::default(),
+            jaws: JawLimits::default(),
+            max_gantry_speed: 6.0,
+            max_dose_rate: 600.0,
+            gantry_range: [0.0, 360.0],
+            collimator_range: [0.0, 360.0],
+            couch_range: [0.0, 360.0],
+        }
+    }
+}
+
+/// A beam as the machine delivers it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeliveredBeam {
+    pub name: String,
+    pub energy: String,
+    /// MU
+    pub meterset: f64,
+    /// Gantry angle (degrees) of every control point.
+    pub gantry: Vec<f64>,
+    /// Collimator angle (degrees) of every control point, or one for all.
+    pub collimator: Vec<f64>,
+    /// Couch angle (degrees) of every control point, or one for all.
+    pub couch: Vec<f64>,
+    pub control_points: Vec<ControlPoint>,
+    /// Dose rate (MU/min) of every control point interval; empty when the machine chooses.
+    pub dose_rates: Vec<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ViolationKind {
+    Energy(String),
+    /// Meterset (MU) not positive or not finite.
+    Meterset(f64),
+    /// Control point count against the angles or dose rates given for them.
+    ControlPoints {
+        expected: usize,
+        found: usize,
+    },
+    /// Cumulative meterset weight decreasing or outside `[0, 1]`.
+    Weight(f64),
+    LeafPairs {
+        expected: usize,
+        found: usize,
+    },
+    /// Leaf position (mm) beyond the leaf range.
+    LeafRange {
+        pair: usize,
+        position: f64,
+    },
+    /// Bank A leaf past the bank B leaf of its pair.
+    LeafOrder {
+        pair: usize,
+    },
+    /// Opening (mm) of an open pair below the minimum gap.
+    MinGap {
+        pair: usize,
+        gap: f64,
+    },
+    /// Leaf of `pair` past the opposite leaf of `pair + 1`.
+    Interdigitation {
+        pair: usize,
+    },
+    /// Distance (mm) between two leaves of bank A (0) or B (1) beyond the maximum span.
+    LeafSpan {
+        bank: usize,
+        span: f64,
+    },
+    /// Jaw (0 to 3 for x1, x2, y1, y2) position (mm) beyond its travel.
+    JawRange {
+        jaw: usize,
+        position: f64,
+    },
+    /// Gantry (0), collimator (1) or couch (2) angle (degrees) beyond its range.
+    AngleRange {
+        axis: usize,
+        angle: f64,
+    },
+    /// Degrees per second.
+    GantrySpeed(f64),
+    /// mm/s
+    LeafSpeed {
+        pair: usize,
+        speed: f64,
+    },
+    /// MU/min
+    DoseRate(f64),
+}
+
+/// A deliverability violation in a beam, at a control point (or the interval ending at it)
+/// when it is not a property of the beam as a whole.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    pub beam: usize,
+    pub control_point: Option<usize>,
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for ViolationKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ViolationKind::Energy(e) => write!(f, "energy {} not available", e),
+            ViolationKind::Meterset(m) => write!(f, "meterset {} MU", m),
+            ViolationKind::ControlPoints { expected, found } => {
+                write!(f, "{} values for {} control points", found, expected)
+            }
+            ViolationKind::Weight(w) => write!(f, "cumulative meterset weight {}", w),
+            ViolationKind::LeafPairs { expected, found } => {
+                write!(f, "{} leaf pairs for an MLC of {}", found, expected)
+            }
+            ViolationKind::LeafRange { pair, position } => {
+                write!(f, "leaf of pair {} at {} mm out of range", pair, position)
+            }
+            ViolationKind::LeafOrder { pair } => write!(f, "leaves of pair {} crossed", pair),
+            ViolationKind::MinGap { pair, gap } => {
+                write!(f, "pair {} open by {} mm, below the minimum gap", pair, gap)
+            }
+            ViolationKind::Interdigitation { pair } => {
+                write!(f, "pairs {} and {} interdigitate", pair, pair + 1)
+            }
+            ViolationKind::LeafSpan { bank, span } => write!(
+                f,
+                "bank {} spans {} mm",
+                if *bank == 0 { "A" } else { "B" },
+                span
+            ),
+            ViolationKind::JawRange { jaw, position } => write!(
+                f,
+                "jaw {} at {} mm out of range",
+                ["X1", "X2", "Y1", "Y2"][*jaw],
+                position
+            ),
+            ViolationKind::AngleRange { axis, angle } => write!(
+                f,
+                "{} at {} degrees out of range",
+                ["gantry", "collimator", "couch"][*axis],
+                angle
+            ),
+            ViolationKind::GantrySpeed(s) => write!(f, "gantry speed {} deg/s", s),
+            ViolationKind::LeafSpeed { pair, speed } => {
+                write!(f, "leaf of pair {} moving {} mm/s", pair, speed)
+            }
+            ViolationKind::DoseRate(r) => write!(f, "dose rate {} MU/min", r),
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.control_point {
+            Some(n) => write!(f, "beam {}, control point {}: {}", self.beam, n, self.kind),
+            None => write!(f, "beam {}: {}", self.beam, self.kind),
+        }
+    }
+}
+
+/// Whether `angle` (degrees) lies on the clockwise rotation from `range[0]` to `range[1]`.
+fn within(range: [f64; 2], angle: f64) -> bool {
+    let tolerance = 1e-6;
+    (angle - range[0] + tolerance).rem_euclid(360.0) <= range[1] - range[0] + 2.0 * tolerance
+}
+
+/// Shortest gantry rotation (degrees) between two angles.
+fn rotation(a: f64, b: f64) -> f64 {
+    let d = (b - a).rem_euclid(360.0);
+    d.min(360.0 - d)
+}
+
+impl Machine {
+    /// Speed and leaf limits of arc sequencing.
+    pub fn arc_limits(&self) -> ArcLimits {
+        ArcLimits {
+            max_gantry_speed: self.max_gantry_speed,
+            max_dose_rate: self.max_dose_rate,
+            leaves: self.mlc.leaves,
+        }
+    }
+
+    /// Largest leaf travel (mm) and gantry rotation (degrees) between control points `a` and
+    /// `b` of `beam`.
+    fn travel(beam: &DeliveredBeam, a: usize, b: usize) -> (Vec<f64>, f64) {
+        let leaves = match (
+            &beam.control_points[a].leaves,
+            &beam.control_points[b].leaves,
+        ) {
+            (Some(p), Some(q)) => p
+                .iter()
+                .zip(q)
+                .map(|(p, q)| (p[0] - q[0]).abs().max((p[1] - q[1]).abs()))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let gantry = match (beam.gantry.get(a), beam.gantry.get(b)) {
+            (Some(p), Some(q)) => rotation(*p, *q),
+            _ => 0.0,
+        };
+        (leaves, gantry)
+    }
+
+    /// Shortest time (s) to deliver `beam` within the speed limits, the beam on while the
+    /// gantry rotates or the leaves move between control points with different weights; zero
+    /// meterset intervals of a step-and-shoot beam are not counted.
+    pub fn delivery_time(&self, beam: &DeliveredBeam) -> f64 {
+        self.interval_times(beam).iter().sum()
+    }
+
+    /// Shortest time (s) of every control point interval of `beam`, as in
+    /// [`Machine::delivery_time`].
+    pub fn interval_times(&self, beam: &DeliveredBeam) -> Vec<f64> {
+        (1..beam.control_points.len())
+            .map(|n| {
+                let mu = beam.meterset
+                    * (beam.control_points[n].weight - beam.control_points[n - 1].weight);
+                if mu <= 0.0 {
+                    return 0.0;
+                }
+                let (leaves, gantry) = Self::travel(beam, n - 1, n);
+                let leaf = leaves.iter().cloned().fold(0.0, f64::max);
+                (60.0 * mu / self.max_dose_rate)
+                    .max(gantry / self.max_gantry_speed)
+                    .max(leaf / self.mlc.leaves.max_leaf_speed)
+            })
+            .collect()
+    }
+
+    /// Every deliverability violation in `beams`.
+    pub fn validate(&self, beams: &[DeliveredBeam]) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        for (b, beam) in beams.iter().enumerate() {
+            let mut report = |control_point: Option<usize>, kind: ViolationKind| {
+                violations.push(Violation {
+                    beam: b,
+                    control_point,
+                    kind,
+                })
+            };
+            if !self
+                .energies
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(&beam.energy))
+            {
+                report(None, ViolationKind::Energy(beam.energy.clone()));
+            }
+            if !(beam.meterset.is_finite() && beam.meterset > 0.0) {
+                report(None, ViolationKind::Meterset(beam.meterset));
+            }
+            let points = beam.control_points.len();
+            let axes = [
+                (&beam.gantry, self.gantry_range),
+                (&beam.collimator, self.collimator_range),
+                (&beam.couch, self.couch_range),
+            ];
+            for (axis, (angles, range)) in axes.iter().enumerate() {
+                if angles.len() != points && angles.len() != 1 {
+                    report(
+                        None,
+                        ViolationKind::ControlPoints {
+                            expected: points,
+                            found: angles.len(),
+                        },
+                    );
+                }
+                for (n, angle) in angles.iter().enumerate() {
+                    if !within(*range, *angle) {
+                        let control_point = if angles.len() == 1 { None } else { Some(n) };
+                        report(
+                            control_point,
+                            ViolationKind::AngleRange {
+                                axis,
+                                angle: *angle,
+                            },
+                        );
+                    }
+                }
+            }
+            let intervals = points.saturating_sub(1);
+            if !beam.dose_rates.is_empty() && beam.dose_rates.len() != intervals {
+                report(
+                    None,
+                    ViolationKind::ControlPoints {
+                        expected: intervals,
+                        found: beam.dose_rates.len(),
+                    },
+                );
+            }
+            let mut previous = 0.0;
+            for (n, point) in beam.control_points.iter().enumerate() {
+                if !(point.weight >= previous && point.weight <= 1.0) {
+                    report(Some(n), ViolationKind::Weight(point.weight));
+                }
+                previous = point.weight.max(previous);
+                if let Some(jaws) = point.jaws {
+                    let limits = [self.jaws.x1, self.jaws.x2, self.jaws.y1, self.jaws.y2];
+                    for (jaw, (p, l)) in jaws.iter().zip(&limits).enumerate() {
+                        if *p < l[0] || *p > l[1] {
+                            report(Some(n), ViolationKind::JawRange { jaw, position: *p });
+                        }
+                    }
+                }
+                if let Some(leaves) = &point.leaves {
+                    for kind in self.check_leaves(leaves) {
+                        report(Some(n), kind);
+                    }
+                }
+            }
+            if beam.dose_rates.len() == intervals {
+                for n in 1..points {
+                    let rate = beam.dose_rates[n - 1];
+                    if !(rate > 0.0 && rate <= self.max_dose_rate * (1.0 + 1e-9)) {
+                        report(Some(n), ViolationKind::DoseRate(rate));
+                        continue;
+                    }
+                    let mu = beam.meterset
+                        * (beam.control_points[n].weight - beam.control_points[n - 1].weight);
+                    // Without MU the leaves and gantry move with the beam off.
+                    if mu <= 0.0 {
+                        continue;
+                    }
+                    let seconds = 60.0 * mu / rate;
+                    let (leaves, gantry) = Self::travel(beam, n - 1, n);
+                    let limit = 1.0 + 1e-9;
+                    if gantry > self.max_gantry_speed * seconds * limit {
+                        report(Some(n), ViolationKind::GantrySpeed(gantry / seconds));
+                    }
+                    for (pair, d) in leaves.iter().enumerate() {
+                        if *d > self.mlc.leaves.max_leaf_speed * seconds * limit {
+                            report(
+                                Some(n),
+                                ViolationKind::LeafSpeed {
+                                    pair,
+                                    speed: d / seconds,
+                                },
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        violations
+    }
+
+    /// Violations of the MLC rules by the bank A and bank B position of every pair.
+    fn check_leaves(&self, leaves: &[[f64; 2]]) -> Vec<ViolationKind> {
+        let mlc = &self.mlc;
+        let limits = &mlc.leaves;
+        let expected = mlc.boundaries.len().saturating_sub(1);
+        if leaves.len() != expected {
+            return vec![ViolationKind::LeafPairs {
+                expected,
+                found: leaves.len(),
+            }];
+        }
+        let tolerance = 1e-6;
+        let mut violations = Vec::new();
+        for (pair, l) in leaves.iter().enumerate() {
+            for position in l {
+                if *position < limits.leaf_range[0] - tolerance
+                    || *position > limits.leaf_range[1] + tolerance
+                {
+                    violations.push(ViolationKind::LeafRange {
+                        pair,
+                        position: *position,
+                    });
+                }
+            }
+            let gap = l[1] - l[0];
+            if gap < -tolerance {
+                violations.push(ViolationKind::LeafOrder { pair });
+            } else if gap > tolerance && gap < limits.min_gap - tolerance {
+                violations.push(ViolationKind::MinGap { pair, gap });
+            }
+        }
+        if !limits.interdigitation {
+            for (pair, w) in leaves.windows(2).enumerate() {
+                if w[0][0] > w[1][1] + tolerance || w[1][0] > w[0][1] + tolerance {
+                    violations.push(ViolationKind::Interdigitation { pair });
+                }
+            }
+        }
+        for bank in 0..2 {
+            let (lo, hi) = leaves
+                .iter()
+                .fold((f64::INFINITY, f64::NEG_INFINITY), |a, l| {
+                    (a.0.min(l[bank]), a.1.max(l[bank]))
+                });
+            if hi - lo > limits.max_span + tolerance {
+                violations.push(ViolationKind::LeafSpan {
+                    bank,
+                    span: hi - lo,
+                });
+            }
+        }
+        violations
+    }
+
+    /// Parses the TOML subset in the module documentation; missing keys keep the values of
+    /// [`Machine::default`].
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let doc = Document::parse(text)?;
+        doc.check_tables(&["mlc", "jaws"])?;
+        let mut machine = Machine::default();
+        let root = doc.root();
+        root.check_keys(&[
+            "name",
+            "energies",
+            "max_gantry_speed",
+            "max_dose_rate",
+            "gantry_range",
+            "collimator_range",
+            "couch_range",
+        ])?;
+        let number = |table: &Table, key: &str, value: &mut f64| {
+            *value = table.number(key)?.unwrap_or(*value);
+            Ok::<_, Error>(())
+        };
+        let range = |table: &Table, key: &str, value: &mut [f64; 2]| {
+            *value = table.range(key)?.unwrap_or(*value);
+            Ok::<_, Error>(())
+        };
+        if let Some(name) = root.string("name")? {
+            machine.name = name.to_string();
+        }
+        if let Some(energies) = root.strings("energies")? {
+            machine.energies = energies.iter().map(|e| e.to_string()).collect();
+        }
+        number(root, "max_gantry_speed", &mut machine.max_gantry_speed)?;
+        number(root, "max_dose_rate", &mut machine.max_dose_rate)?;
+        range(root, "gantry_range", &mut machine.gantry_range)?;
+        range(root, "collimator_range", &mut machine.collimator_range)?;
+        range(root, "couch_range", &mut machine.couch_range)?;
+        let mut widths = None;
+        for mlc in doc.tables("mlc") {
+            mlc.check_keys(&[
+                "boundaries",
+                "widths",
+                "max_span",
+                "interdigitation",
+                "min_gap",
+                "leaf_range",
+                "max_leaf_speed",
+            ])?;
+            if let Some(b) = mlc.numbers("boundaries")? {
+                machine.mlc.boundaries = b;
+            }
+            widths = mlc.numbers("widths")?;
+            number(mlc, "max_span", &mut machine.mlc.leaves.max_span)?;
+            if let Some(i) = mlc.boolean("interdigitation")? {
+                machine.mlc.leaves.interdigitation = i;
+            }
+            number(mlc, "min_gap", &mut machine.mlc.leaves.min_gap)?;
+            range(mlc, "leaf_range", &mut machine.mlc.leaves.leaf_range)?;
+            number(
+                mlc,
+                "max_leaf_speed",
+                &mut machine.mlc.leaves.max_leaf_speed,
+            )?;
+        }
+        for jaws in doc.tables("jaws") {
+            jaws.check_keys(&["x1", "x2", "y1", "y2"])?;
+            range(jaws, "x1", &mut machine.jaws.x1)?;
+            range(jaws, "x2", &mut machine.jaws.x2)?;
+            range(jaws, "y1", &mut machine.jaws.y1)?;
+            range(jaws, "y2", &mut machine.jaws.y2)?;
+        }
+        if let Some(w) = widths {
+            if w.iter().any(|w| w.is_nan() || *w <= 0.0) {
+                return Err(Error::Format(format!("invalid leaf widths {:?}", w)));
+            }
+            machine.mlc.boundaries = boundaries(&w);
+        }
+        if machine.mlc.boundaries.len() < 2
+            || machine.mlc.boundaries.windows(2).any(|b| b[1] <= b[0])
+        {
+            return Err(Error::Format(format!(
+                "leaf boundaries not ascending: {:?}",
+                machine.mlc.boundaries
+            )));
+        }
+        if !(machine.max_gantry_speed > 0.0
+            && machine.max_dose_rate > 0.0
+            && machine.mlc.leaves.max_leaf_speed > 0.0)
+        {
+            return Err(Error::Format("machine speeds must be positive".to_string()));
+        }
+        let ranges = [
+            machine.gantry_range,
+            machine.collimator_range,
+            machine.couch_range,
+        ];
+        if ranges.iter().any(|r| r[1] - r[0] > 360.0) {
+            return Err(Error::Format(
+                "angle ranges must not exceed a full rotation".to_string(),
+            ));
+        }
+        Ok(machine)
+    }
+
+    pub fn read_toml<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dose::commissioning::MlcData;
+    use crate::dose::delivery::{ControlPoint, Mlc};
+    use crate::machine::{DeliveredBeam, Machine, Violation, ViolationKind};
+
+    fn machine() -> Machine {
+        Machine::from_toml(
+            r#"
+            name = "Test"   # four pairs
+            energies = ["6X", "10X"]
+            max_gantry_speed = 6.0
+            max_dose_rate = 600.0
+
+            [mlc]
+            widths = [10.0, 5.0, 5.0, 10.0]
+            max_span = 50.0
+            interdigitation = false
+            min_gap = 1.0
+            leaf_range = [-100.0, 100.0]
+            max_leaf_speed = 20.0
+
+            [jaws]
+            x1 = [-100.0, 10.0]
+            x2 = [-10.0, 100.0]
+            y1 = [-100.0, 10.0]
+            y2 = [-10.0, 100.0]
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn point(weight: f64, leaves: Vec<[f64; 2]>) -> ControlPoint {
+        ControlPoint {
+            weight,
+            jaws: Some([-20.0, 20.0, -15.0, 15.0]),
+            leaves: Some(leaves),
+        }
+    }
+
+    fn arc() -> DeliveredBeam {
+        let open = vec![[-10.0, 10.0]; 4];
+        let shifted = vec![[0.0, 20.0]; 4];
+        DeliveredBeam {
+            name: "Arc".to_string(),
+            energy: "6x".to_string(),
+            meterset: 100.0,
+            gantry: vec![180.0, 190.0],
+            collimator: vec![0.0],
+            couch: vec![0.0],
+            control_points: vec![point(0.0, open), point(1.0, shifted)],
+            dose_rates: vec![600.0],
+        }
+    }
+
+    fn kinds(violations: Vec<Violation>) -> Vec<ViolationKind> {
+        violations.into_iter().map(|v| v.kind).collect()
+    }
+
+    #[test]
+    fn machine_model() {
+        let machine = machine();
+        assert_eq!(machine.name, "Test");
+        assert_eq!(machine.mlc.boundaries, vec![-15.0, -5.0, 0.0, 5.0, 15.0]);
+        assert!(!machine.mlc.leaves.interdigitation);
+        assert_eq!(machine.jaws.y2, [-10.0, 100.0]);
+        assert_eq!(machine.arc_limits().leaves.max_leaf_speed, 20.0);
+        assert_eq!(
+            Mlc::from_geometry(
+                &machine.mlc,
+                MlcData {
+                    transmission: 0.0,
+                    dlg: 0.0
+                }
+            )
+            .pairs(),
+            4
+        );
+        assert_eq!(Machine::default().mlc.boundaries.len(), 61);
+        assert_eq!(Machine::default().mlc.boundaries[0], -200.0);
+        assert!(Machine::from_toml("[couch]").is_err());
+        assert!(Machine::from_toml("[mlc]\nboundaries = [1.0, 0.0]").is_err());
+        assert!(Machine::from_toml("max_dose_rate = fast").is_err());
+
+        // 100 MU at 600 MU/min take 10 s for 10 degrees and 10 mm of leaf travel.
+        let beam = arc();
+        assert!(machine.validate(std::slice::from_ref(&beam)).is_empty());
+        assert!((machine.delivery_time(&beam) - 10.0).abs() < 1e-9);
+
+        let mut fast = beam.clone();
+        fast.meterset = 10.0;
+        fast.energy = "18X".to_string();
+        let kinds: Vec<ViolationKind> = machine
+            .validate(&[beam.clone(), fast.clone()])
+            .into_iter()
+            .map(|v| {
+                assert_eq!(v.beam, 1);
+                v.kind
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ViolationKind::Energy("18X".to_string()),
+                ViolationKind::GantrySpeed(10.0),
+            ]
+        );
+        // Without a dose rate the machine slows down instead.
+        fast.dose_rates.clear();
+        fast.energy = "10X".to_string();
+        assert!(machine.validate(std::slice::from_ref(&fast)).is_empty());
+        assert!((machine.delivery_time(&fast) - 10.0 / 6.0).abs() < 1e-9);
+
+        let mut bad = beam;
+        bad.control_points[1] = point(
+            0.5,
+            vec![[-60.0, -59.5], [-10.0, 10.0], [12.0, 11.0], [20.0, 110.0]],
+        );
+        bad.control_points[0].jaws = Some([-20.0, 20.0, -15.0, 150.0]);
+        bad.control_points.push(point(0.4, vec![[0.0, 0.0]; 3]));
+        bad.gantry.push(200.0);
+        bad.dose_rates = vec![600.0, 900.0];
+        let violations = machine.validate(&[bad]);
+        let messages: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+        let kinds: Vec<&ViolationKind> = violations.iter().map(|v| &v.kind).collect();
+        assert!(kinds.contains(&&ViolationKind::JawRange {
+            jaw: 3,
+            position: 150.0
+        }));
+        assert!(kinds.contains(&&ViolationKind::MinGap { pair: 0, gap: 0.5 }));
+        assert!(kinds.contains(&&ViolationKind::LeafOrder { pair: 2 }));
+        assert!(kinds.contains(&&ViolationKind::LeafRange {
+            pair: 3,
+            position: 110.0
+        }));
+        assert!(kinds.contains(&&ViolationKind::Interdigitation { pair: 0 }));
+        assert!(kinds.contains(&&ViolationKind::LeafSpan {
+            bank: 0,
+            span: 80.0
+        }));
+        assert!(kinds.contains(&&ViolationKind::Weight(0.4)));
+        assert!(kinds.contains(&&ViolationKind::LeafPairs {
+            expected: 4,
+            found: 3
+        }));
+        assert!(kinds.contains(&&ViolationKind::DoseRate(900.0)));
+        assert!(messages.contains(&"beam 0, control point 2: dose rate 900 MU/min".to_string()));
+    }
+
+    #[test]
+    fn machine_energy_meterset_and_weights() {
+        let machine = machine();
+        let mut beam = arc();
+        beam.dose_rates.clear();
+        beam.energy = "10x".to_string();
+        assert!(machine.validate(std::slice::from_ref(&beam)).is_empty());
+        beam.energy = "6FFF".to_string();
+        beam.meterset = 0.0;
+        assert_eq!(
+            kinds(machine.validate(std::slice::from_ref(&beam))),
+            vec![
+                ViolationKind::Energy("6FFF".to_string()),
+                ViolationKind::Meterset(0.0)
+            ]
+        );
+        beam.energy = "6X".to_string();
+        beam.meterset = f64::INFINITY;
+        assert_eq!(
+            kinds(machine.validate(std::slice::from_ref(&beam))),
+            vec![ViolationKind::Meterset(f64::INFINITY)]
+        );
+
+        let mut beam = arc();
+        beam.dose_rates.clear();
+        beam.control_points[0].weight = 0.5;
+        beam.control_points[1].weight = 0.25;
+        beam.control_points.push(point(1.5, vec![[0.0, 20.0]; 4]));
+        beam.gantry.push(200.0);
+        let violations = machine.validate(&[beam]);
+        assert_eq!(violations.len(), 2);
+        assert_eq!(violations[0].control_point, Some(1));
+        assert_eq!(violations[0].kind, ViolationKind::Weight(0.25));
+        assert_eq!(violations[1].control_point, Some(2));
+        assert_eq!(violations[1].kind, ViolationKind::Weight(1.5));
+    }
+
+    #[test]
+    fn machine_angle_ranges() {
+        let mut machine = machine();
+        machine.gantry_range = [-175.0, 175.0];
+        machine.collimator_range = [-90.0, 90.0];
+        machine.couch_range = [-90.0, 90.0];
+        let mut beam = arc();
+        beam.gantry = vec![170.0, 180.0];
+        beam.collimator = vec![270.0];
+        beam.couch = vec![90.0, 120.0];
+        let violations = machine.validate(&[beam.clone()]);
+        assert_eq!(
+            violations
+                .iter()
+                .map(|v| (v.control_point, &v.kind))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    Some(1),
+                    &ViolationKind::AngleRange {
+                        axis: 0,
+                        angle: 180.0
+                    }
+                ),
+                (
+                    Some(1),
+                    &ViolationKind::AngleRange {
+                        axis: 2,
+                        angle: 120.0
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            violations[1].to_string(),
+            "beam 0, control point 1: couch at 120 degrees out of range"
+        );
+
+        beam.gantry = vec![-175.0];
+        beam.collimator = vec![95.0];
+        beam.couch = vec![0.0, 10.0, 20.0];
+        assert_eq!(
+            kinds(machine.validate(&[beam])),
+            vec![
+                ViolationKind::AngleRange {
+                    axis: 1,
+                    angle: 95.0
+                },
+                ViolationKind::ControlPoints {
+                    expected: 2,
+                    found: 3
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn machine_jaw_range() {
+        let machine = machine();
+        let mut beam = arc();
+        beam.control_points[0].jaws = Some([-150.0, 20.0, -15.0, 15.0]);
+        beam.control_points[1].jaws = None;
+        let violations = machine.validate(&[beam]);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].control_point, Some(0));
+        assert_eq!(
+            violations[0].kind,
+            ViolationKind::JawRange {
+                jaw: 0,
+                position: -150.0
+            }
+        );
+    }
+
+    #[test]
+    fn machine_leaf_range_and_order() {
+        let mut machine = machine();
+        machine.mlc.leaves.interdigitation = true;
+        machine.mlc.leaves.max_span = 250.0;
+        let mut beam = arc();
+        beam.control_points[1].leaves = Some(vec![
+            [-100.0, 100.0 + 1e-9],
+            [-101.0, -90.0],
+            [5.0, 4.0],
+            [-10.0, 10.0],
+        ]);
+        beam.dose_rates.clear();
+        assert_eq!(
+            kinds(machine.validate(&[beam])),
+            vec![
+                ViolationKind::LeafRange {
+                    pair: 1,
+                    position: -101.0
+                },
+                ViolationKind::LeafOrder { pair: 2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn machine_min_gap_and_interdigitation() {
+        let mut machine = machine();
+        machine.mlc.leaves.max_span = 200.0;
+        let mut beam = arc();
+        beam.dose_rates.clear();
+        // Closed pairs are exempt from the minimum gap.
+        beam.control_points[1].leaves =
+            Some(vec![[-30.0, -30.0], [-10.0, -9.5], [-5.0, 5.0], [6.0, 7.0]]);
+        let violations = kinds(machine.validate(std::slice::from_ref(&beam)));
+        assert_eq!(
+            violations,
+            vec![
+                ViolationKind::MinGap { pair: 1, gap: 0.5 },
+                ViolationKind::Interdigitation { pair: 0 },
+                ViolationKind::Interdigitation { pair: 1 },
+                ViolationKind::Interdigitation { pair: 2 },
+            ]
+        );
+        machine.mlc.leaves.interdigitation = true;
+        machine.mlc.leaves.min_gap = 0.5;
+        assert!(machine.validate(&[beam]).is_empty());
+    }
+
+    #[test]
+    fn machine_leaf_speed_and_dose_rate() {
+        let machine = machine();
+        // 10 MU at 600 MU/min take 1 s for 30 mm of leaf travel.
+        let mut beam = arc();
+        beam.meterset = 10.0;
+        beam.gantry = vec![180.0];
+        beam.control_points[1] = point(1.0, vec![[20.0, 40.0]; 4]);
+        let violations = machine.validate(std::slice::from_ref(&beam));
+        assert_eq!(violations.len(), 4);
+        assert_eq!(
+            violations[3].kind,
+            ViolationKind::LeafSpeed {
+                pair: 3,
+                speed: 30.0
+            }
+        );
+        assert!((machine.interval_times(&beam)[0] - 1.5).abs() < 1e-9);
+
+        beam.meterset = 15.0;
+        assert!(machine.validate(std::slice::from_ref(&beam)).is_empty());
+        for rate in [0.0, 601.0, f64::NAN].iter() {
+            beam.dose_rates = vec![*rate];
+            let violations = machine.validate(std::slice::from_ref(&beam));
+            assert_eq!(violations.len(), 1);
+            assert_eq!(violations[0].control_point, Some(1));
+            assert!(matches!(violations[0].kind, ViolationKind::DoseRate(_)));
+        }
+        // Leaves and gantry move freely in an interval without MU.
+        beam.dose_rates = vec![600.0];
+        beam.gantry = vec![180.0, 270.0];
+        beam.control_points[0].weight = 1.0;
+        assert!(machine.validate(std::slice::from_ref(&beam)).is_empty());
+        beam.dose_rates = vec![600.0, 600.0];
+        assert_eq!(
+            kinds(machine.validate(&[beam])),
+            vec![ViolationKind::ControlPoints {
+                expected: 1,
+                found: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn machine_from_toml_errors() {
+        assert_eq!(Machine::from_toml("").unwrap(), Machine::default());
+        let machine = Machine::from_toml(
+            "couch_range = [-90.0, 90.0]
+[mlc]
+boundaries = [-5.0, 0.0, 5.0]",
+        )
+        .unwrap();
+        assert_eq!(machine.couch_range, [-90.0, 90.0]);
+        assert_eq!(machine.mlc.boundaries, vec![-5.0, 0.0, 5.0]);
+        assert_eq!(
+            machine.mlc.leaves.max_span,
+            Machine::default().mlc.leaves.max_span
+        );
+
+        for bad in [
+            "speed = 6.0",
+            "[mlc]
+width = [10.0]",
+            "[couch]",
+            "[mlc]
+[mlc]",
+            "name = Linac",
+            "energies = \"6X\"",
+            "energies = [6]",
+            "max_dose_rate = \"600\"",
+            "max_gantry_speed = 0.0",
+            "couch_range = [0.0, 400.0]",
+            "[mlc]
+boundaries = 5.0",
+            "[mlc]
+boundaries = [0.0]",
+            "[mlc]
+boundaries = [0.0, 5.0, 5.0]",
+            "[mlc]
+widths = [10.0, -5.0]",
+            "[mlc]
+widths = [10.0, \"5\"]",
+            "[mlc]
+interdigitation = 1",
+            "[mlc]
+leaf_range = [-100.0]",
+            "[mlc]
+leaf_range = [100.0, -100.0]",
+            "[jaws]
+x1 = [-200.0, 20.0, 0.0]",
+        ]
+        .iter()
+        {
+            assert!(Machine::from_toml(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,293 @@
+//! 3D margin expansion and contraction.
+//!
+//! A voxel belongs to the expanded mask when its displacement `d` from some inside voxel
+//! satisfies `sum((d_a / m_a)^2) <= 1`, with `m_a` the margin on the side `d_a` points to.
+//! The cost is separable, so it is evaluated with one windowed distance pass per grid axis
+//! instead of slice-wise dilation. Contraction expands the complement by the mirrored
+//! margins; everything outside the grid counts as outside the structure.
+
+use crate::contouring::extract_contours;
+use crate::grid::{Grid3, GridGeometry};
+use crate::raster::rasterize;
+use crate::structure::Structure;
+
+/// Margins in mm per patient direction (LPS: +x left, +y posterior, +z superior).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Margins {
+    pub left: f64,
+    pub right: f64,
+    pub anterior: f64,
+    pub posterior: f64,
+    pub superior: f64,
+    pub inferior: f64,
+}
+
+impl Margins {
+    pub fn new(
+        left: f64,
+        right: f64,
+        anterior: f64,
+        posterior: f64,
+        superior: f64,
+        inferior: f64,
+    ) -> Self {
+        Self {
+            left,
+            right,
+            anterior,
+            posterior,
+            superior,
+            inferior,
+        }
+    }
+
+    pub fn uniform(margin: f64) -> Self {
+        Self::new(margin, margin, margin, margin, margin, margin)
+    }
+
+    /// Margins for the (positive, negative) direction along patient axis `axis`.
+    pub fn along(&self, axis: usize) -> (f64, f64) {
+        match axis {
+            0 => (self.left, self.right),
+            1 => (self.posterior, self.anterior),
+            _ => (self.superior, self.inferior),
+        }
+    }
+
+    /// The margins with every direction swapped for its opposite.
+    pub fn mirrored(&self) -> Self {
+        Self::new(
+            self.right,
+            self.left,
+            self.posterior,
+            self.anterior,
+            self.inferior,
+            self.superior,
+        )
+    }
+}
+
+pub fn expand_mask(mask: &Grid3<bool>, margins: &Margins) -> Grid3<bool> {
+    grow(mask, margins, true, false)
+}
+
+pub fn contract_mask(mask: &Grid3<bool>, margins: &Margins) -> Grid3<bool> {
+    grow(mask, &margins.mirrored(), false, true).map(|v| !*v)
+}
+
+/// `structure` grown by `margins` on `geometry`; name, type and color are kept.
+pub fn expand(structure: &Structure, margins: &Margins, geometry: &GridGeometry) -> Structure {
+    let mask = expand_mask(&rasterize(structure, geometry), margins);
+    with_mask(structure, &mask)
+}
+
+/// `structure` shrunk by `margins` on `geometry`; name, type and color are kept.
+pub fn contract(structure: &Structure, margins: &Margins, geometry: &GridGeometry) -> Structure {
+    let mask = contract_mask(&rasterize(structure, geometry), margins);
+    with_mask(structure, &mask)
+}
+
+fn with_mask(structure: &Structure, mask: &Grid3<bool>) -> Structure {
+    Structure {
+        contours: extract_contours(mask, 0.0),
+        ..structure.clone()
+    }
+}
+
+/// Expands the voxels equal to `source` and returns the grown set; voxels beyond the grid
+/// are sources when `outside_source` is set.
+fn grow(mask: &Grid3<bool>, margins: &Margins, source: bool, outside_source: bool) -> Grid3<bool> {
+    let geometry = mask.geometry();
+    let mut cost: Vec<f64> = mask
+        .data()
+        .iter()
+        .map(|v| if *v == source { 0.0 } else { f64::INFINITY })
+        .collect();
+    for axis in 0..3 {
+        let d = geometry.direction[axis];
+        let components = [d.x, d.y, d.z];
+        let patient = (0..3)
+            .max_by(|a, b| {
+                components[*a]
+                    .abs()
+                    .partial_cmp(&components[*b].abs())
+                    .unwrap()
+            })
+            .unwrap();
+        let (pos, neg) = margins.along(patient);
+        // Margins for a positive and a negative index displacement.
+        let (plus, minus) = if components[patient] > 0.0 {
+            (pos, neg)
+        } else {
+            (neg, pos)
+        };
+        let h = [geometry.spacing.x, geometry.spacing.y, geometry.spacing.z][axis];
+        pass(
+            &mut cost,
+            geometry.dims,
+            axis,
+            h,
+            plus,
+            minus,
+            outside_source,
+        );
+    }
+    Grid3::from_vec(
+        geometry.clone(),
+        cost.iter().map(|c| *c <= 1.0 + 1e-9).collect(),
+    )
+    .expect("cost has one value per voxel")
+}
+
+/// One windowed pass of the separable cost along `axis`.
+fn pass(
+    cost: &mut [f64],
+    dims: [usize; 3],
+    axis: usize,
+    h: f64,
+    plus: f64,
+    minus: f64,
+    outside_source: bool,
+) {
+    let window = |m: f64| {
+        if m > 0.0 {
+            (m / h + 1e-9).floor() as i64
+        } else {
+            0
+        }
+    };
+    let (w_plus, w_minus) = (window(plus), window(minus));
+    if w_plus == 0 && w_minus == 0 {
+        return;
+    }
+    let n = dims[axis];
+    let stride = [1, dims[0], dims[0] * dims[1]][axis];
+    let outside = if outside_source { 0.0 } else { f64::INFINITY };
+    let penalty = |t: i64, m: f64| (t as f64 * h / m).powi(2);
+    let mut line = vec![0.0; n];
+    for l in 0..cost.len() / n {
+        let start = (l / stride) * stride * n + l % stride;
+        for (p, v) in line.iter_mut().enumerate() {
+            *v = cost[start + p * stride];
+        }
+        let at = |q: i64| {
+            if q < 0 || q >= n as i64 {
+                outside
+            } else {
+                line[q as usize]
+            }
+        };
+        for p in 0..n as i64 {
+            let mut best = at(p);
+            for t in 1..=w_plus {
+                best = best.min(at(p - t) + penalty(t, plus));
+            }
+            for t in 1..=w_minus {
+                best = best.min(at(p + t) + penalty(t, minus));
+            }
+            cost[start + p as usize * stride] = best;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::margin::{contract_mask, expand, expand_mask, Margins};
+    use crate::raster::rasterize;
+    use crate::structure::{Contour, Structure, StructureType};
+
+    fn grid(n: usize) -> Grid3<bool> {
+        let geometry = GridGeometry::new(
+            [n, n, n],
+            Vec3::from(0.0, 0.0, 0.0),
+            Vec3::from(1.0, 1.0, 1.0),
+        );
+        Grid3::new(geometry, false)
+    }
+
+    fn count(mask: &Grid3<bool>) -> usize {
+        mask.data().iter().filter(|v| **v).count()
+    }
+
+    #[test]
+    fn expand_mask_isotropic() {
+        let mut mask = grid(9);
+        mask.set(4, 4, 4, true);
+        let grown = expand_mask(&mask, &Margins::uniform(2.0));
+        // Integer points within a ball of radius 2.
+        assert_eq!(count(&grown), 33);
+        assert!(grown[[4, 4, 6]]);
+        assert!(!grown[[5, 5, 6]]);
+    }
+
+    #[test]
+    fn expand_mask_anisotropic() {
+        let mut mask = grid(9);
+        mask.set(4, 4, 4, true);
+        let margins = Margins {
+            left: 3.0,
+            inferior: 1.0,
+            ..Margins::default()
+        };
+        let grown = expand_mask(&mask, &margins);
+        assert_eq!(count(&grown), 5);
+        assert!(grown[[7, 4, 4]]);
+        assert!(!grown[[1, 4, 4]]);
+        assert!(grown[[4, 4, 3]]);
+
+        // A flipped x axis points to the patient's right.
+        let mut flipped = mask.geometry().clone();
+        flipped.direction[0] = Vec3::from(-1.0, 0.0, 0.0);
+        let mask = Grid3::from_vec(flipped, mask.into_vec()).unwrap();
+        let grown = expand_mask(&mask, &margins);
+        assert!(grown[[1, 4, 4]]);
+        assert!(!grown[[7, 4, 4]]);
+    }
+
+    #[test]
+    fn contract_mask_cube() {
+        let mut mask = grid(9);
+        for k in 1..8 {
+            for j in 1..8 {
+                for i in 1..8 {
+                    mask.set(i, j, k, true);
+                }
+            }
+        }
+        assert_eq!(count(&contract_mask(&mask, &Margins::uniform(1.0))), 125);
+        let full = grid(5).map(|_| true);
+        assert_eq!(count(&contract_mask(&full, &Margins::uniform(1.0))), 27);
+        let margins = Margins {
+            superior: 2.0,
+            ..Margins::default()
+        };
+        let shrunk = contract_mask(&mask, &margins);
+        assert_eq!(count(&shrunk), 7 * 7 * 5);
+        assert!(shrunk[[4, 4, 5]]);
+        assert!(!shrunk[[4, 4, 6]]);
+    }
+
+    #[test]
+    fn expand_structure() {
+        let geometry = GridGeometry::new(
+            [20, 20, 9],
+            Vec3::from(-9.5, -9.5, -4.0),
+            Vec3::from(1.0, 1.0, 1.0),
+        );
+        let contour = Contour::new(vec![
+            Vec3::from(-2.0, -2.0, 0.0),
+            Vec3::from(2.0, -2.0, 0.0),
+            Vec3::from(2.0, 2.0, 0.0),
+            Vec3::from(-2.0, 2.0, 0.0),
+        ]);
+        let ctv = Structure::new("CTV", StructureType::Ctv).with_contours(vec![contour]);
+        let margins = Margins::new(2.0, 2.0, 2.0, 2.0, 0.0, 0.0);
+        let ptv = expand(&ctv, &margins, &geometry);
+        assert_eq!(ptv.name, "CTV");
+        let mask = rasterize(&ptv, &geometry);
+        assert_eq!(count(&mask), 8 * 8 - 4 * 3);
+        assert!(mask[[8, 6, 4]]);
+    }
+}
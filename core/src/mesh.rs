@@ -0,0 +1,267 @@
+//! Triangle surface meshes of binary masks (marching tetrahedra).
+//!
+//! Every cell of the zero-padded mask is split into six tetrahedra around its main
+//! diagonal, which is consistent between neighbouring cells, so the surface is closed and
+//! free of the ambiguous cases of marching cubes. Triangles are wound counter-clockwise
+//! seen from outside.
+
+use std::collections::HashMap;
+
+use crate::coords::Vec3;
+use crate::grid::{Grid3, GridGeometry};
+use crate::raster::rasterize;
+use crate::structure::Structure;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TriangleMesh {
+    pub vertices: Vec<Vec3<f64>>,
+    pub triangles: Vec<[usize; 3]>,
+}
+
+impl TriangleMesh {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.triangles.is_empty()
+    }
+
+    /// Unit normal of triangle `t` (zero for a degenerate triangle).
+    pub fn normal(&self, t: usize) -> Vec3<f64> {
+        let [a, b, c] = self.corners(t);
+        (b - a).cross(c - a).normalize()
+    }
+
+    pub fn corners(&self, t: usize) -> [Vec3<f64>; 3] {
+        let [a, b, c] = self.triangles[t];
+        [self.vertices[a], self.vertices[b], self.vertices[c]]
+    }
+
+    pub fn area(&self) -> f64 {
+        (0..self.triangles.len())
+            .map(|t| {
+                let [a, b, c] = self.corners(t);
+                0.5 * (b - a).cross(c - a).norm()
+            })
+            .sum()
+    }
+
+    /// Enclosed volume (divergence theorem); positive for outward-facing triangles.
+    pub fn volume(&self) -> f64 {
+        (0..self.triangles.len())
+            .map(|t| {
+                let [a, b, c] = self.corners(t);
+                a.dot(b.cross(c)) / 6.0
+            })
+            .sum()
+    }
+}
+
+/// Kuhn decomposition of the unit cell: paths from corner 0 to corner 7 (bits x, y, z).
+const TETRAHEDRA: [[usize; 4]; 6] = [
+    [0, 1, 3, 7],
+    [0, 1, 5, 7],
+    [0, 2, 3, 7],
+    [0, 2, 6, 7],
+    [0, 4, 5, 7],
+    [0, 4, 6, 7],
+];
+
+/// Surface of the voxel centers in `mask`, placed halfway between inside and outside voxels.
+pub fn mask_mesh(mask: &Grid3<bool>) -> TriangleMesh {
+    let [nx, ny, nz] = mask.dims();
+    let at = |i: i64, j: i64, k: i64| {
+        i >= 0
+            && j >= 0
+            && k >= 0
+            && (i as usize) < nx
+            && (j as usize) < ny
+            && (k as usize) < nz
+            && mask[[i as usize, j as usize, k as usize]]
+    };
+    march_tetrahedra(mask.geometry(), at, |a, b| {
+        Vec3::from(
+            (a[0] + b[0]) as f64,
+            (a[1] + b[1]) as f64,
+            (a[2] + b[2]) as f64,
+        )
+        .scale(0.5)
+    })
+}
+
+/// Surface between the `inside` and outside voxels of `geometry` (and the voxels around
+/// it), with the vertex on the edge from inside voxel `a` to outside voxel `b` at the index
+/// position `place(a, b)`.
+pub(crate) fn march_tetrahedra<I, P>(geometry: &GridGeometry, at: I, place: P) -> TriangleMesh
+where
+    I: Fn(i64, i64, i64) -> bool,
+    P: Fn([i64; 3], [i64; 3]) -> Vec3<f64>,
+{
+    let [nx, ny, nz] = geometry.dims;
+    let i2w = geometry.index_to_world();
+    let mut mesh = TriangleMesh::new();
+    // Vertices are keyed by the doubled index coordinates of their edge midpoint.
+    let mut index: HashMap<[i64; 3], usize> = HashMap::new();
+    let mut vertex = |a: [i64; 3], b: [i64; 3], mesh: &mut TriangleMesh| {
+        let key = [a[0] + b[0], a[1] + b[1], a[2] + b[2]];
+        *index.entry(key).or_insert_with(|| {
+            mesh.vertices.push(i2w.transform_point(place(a, b)));
+            mesh.vertices.len() - 1
+        })
+    };
+    let flip = geometry.index_to_world().determinant() < 0.0;
+    for k in -1..nz as i64 {
+        for j in -1..ny as i64 {
+            for i in -1..nx as i64 {
+                let corner = |c: usize| {
+                    [
+                        i + (c & 1) as i64,
+                        j + ((c >> 1) & 1) as i64,
+                        k + ((c >> 2) & 1) as i64,
+                    ]
+                };
+                let inside: Vec<bool> = (0..8)
+                    .map(|c| {
+                        let p = corner(c);
+                        at(p[0], p[1], p[2])
+                    })
+                    .collect();
+                if inside.iter().all(|v| *v) || inside.iter().all(|v| !*v) {
+                    continue;
+                }
+                for tet in TETRAHEDRA.iter() {
+                    let (ins, outs): (Vec<usize>, Vec<usize>) =
+                        tet.iter().partition(|c| inside[**c]);
+                    let mut polygon = Vec::new();
+                    match ins.len() {
+                        1 => {
+                            for o in &outs {
+                                polygon.push((ins[0], *o));
+                            }
+                        }
+                        2 => {
+                            let (a, b, c, d) = (ins[0], ins[1], outs[0], outs[1]);
+                            polygon.extend_from_slice(&[(a, c), (a, d), (b, d), (b, c)]);
+                        }
+                        3 => {
+                            for n in &ins {
+                                polygon.push((*n, outs[0]));
+                            }
+                        }
+                        _ => continue,
+                    }
+                    let ids: Vec<usize> = polygon
+                        .iter()
+                        .map(|(a, b)| vertex(corner(*a), corner(*b), &mut mesh))
+                        .collect();
+                    // Outward is from the inside corners towards the outside corners.
+                    let centroid = |cs: &[usize]| {
+                        cs.iter()
+                            .fold(Vec3::from(0.0, 0.0, 0.0), |s, c| {
+                                let p = corner(*c);
+                                s + Vec3::from(p[0] as f64, p[1] as f64, p[2] as f64)
+                            })
+                            .scale(1.0 / cs.len() as f64)
+                    };
+                    let outward = centroid(&outs) - centroid(&ins);
+                    let point = |n: usize| {
+                        let (a, b) = polygon[n];
+                        let (a, b) = (corner(a), corner(b));
+                        Vec3::from(
+                            (a[0] + b[0]) as f64,
+                            (a[1] + b[1]) as f64,
+                            (a[2] + b[2]) as f64,
+                        )
+                    };
+                    let normal = (point(1) - point(0)).cross(point(2) - point(0));
+                    let reverse = (normal.dot(outward) < 0.0) != flip;
+                    for n in 1..ids.len() - 1 {
+                        let t = [ids[0], ids[n], ids[n + 1]];
+                        mesh.triangles
+                            .push(if reverse { [t[0], t[2], t[1]] } else { t });
+                    }
+                }
+            }
+        }
+    }
+    mesh
+}
+
+/// Surface mesh of `structure` rasterized on `geometry`.
+pub fn structure_mesh(structure: &Structure, geometry: &GridGeometry) -> TriangleMesh {
+    mask_mesh(&rasterize(structure, geometry))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::mesh::{mask_mesh, TriangleMesh};
+
+    /// Every directed edge must appear once, and its reverse once.
+    fn is_closed(mesh: &TriangleMesh) -> bool {
+        let mut edges = HashMap::new();
+        for t in &mesh.triangles {
+            for n in 0..3 {
+                *edges.entry((t[n], t[(n + 1) % 3])).or_insert(0) += 1;
+            }
+        }
+        edges
+            .iter()
+            .all(|((a, b), count)| *count == 1 && edges.get(&(*b, *a)) == Some(&1))
+    }
+
+    fn cube(n: usize, spacing: f64) -> Grid3<bool> {
+        let geometry = GridGeometry::new(
+            [n + 2, n + 2, n + 2],
+            Vec3::from(0.0, 0.0, 0.0),
+            Vec3::from(spacing, spacing, spacing),
+        );
+        let mut mask = Grid3::new(geometry, false);
+        for k in 1..=n {
+            for j in 1..=n {
+                for i in 1..=n {
+                    mask.set(i, j, k, true);
+                }
+            }
+        }
+        mask
+    }
+
+    #[test]
+    fn mask_mesh_closed() {
+        let mut mask = cube(1, 1.0);
+        let mesh = mask_mesh(&mask);
+        assert!(is_closed(&mesh));
+        assert!(mesh.volume() > 0.0);
+        mask.set(0, 0, 0, true);
+        mask.set(2, 2, 2, true);
+        let mesh = mask_mesh(&mask);
+        assert!(is_closed(&mesh));
+        assert!(mesh.volume() > 0.0);
+    }
+
+    #[test]
+    fn mask_mesh_cube() {
+        let mesh = mask_mesh(&cube(4, 2.0));
+        assert!(is_closed(&mesh));
+        // The surface runs through the face midpoints of a 4x4x4 block of 2 mm voxels, with
+        // edges and corners bevelled.
+        let v = mesh.volume();
+        assert!(v > 0.8 * 512.0 && v < 512.0, "volume {}", v);
+        let a = mesh.area();
+        assert!(a > 0.8 * 384.0 && a < 384.0, "area {}", a);
+        let n = mesh.normal(0);
+        assert!((n.norm() - 1.0).abs() < 1e-12);
+
+        // A mirrored grid still produces outward-facing triangles.
+        let mut mask = cube(4, 2.0);
+        let mut geometry = mask.geometry().clone();
+        geometry.direction[2] = Vec3::from(0.0, 0.0, -1.0);
+        mask = Grid3::from_vec(geometry, mask.into_vec()).unwrap();
+        assert!((mask_mesh(&mask).volume() - v).abs() < 1e-9);
+    }
+}
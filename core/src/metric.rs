@@ -0,0 +1,302 @@
+//! Dose metrics evaluated on DVHs, and their common string syntax.
+//!
+//! | Syntax         | Meaning                                          | Result |
+//! |----------------|--------------------------------------------------|--------|
+//! | `Dmean`        | mean dose (also `Dmax`, `Dmin`)                  | Gy     |
+//! | `D95%`         | dose to the hottest 95 % of the volume           | Gy     |
+//! | `D0.035cc`     | dose to the hottest 0.035 cc                     | Gy     |
+//! | `V20Gy`        | volume receiving at least 20 Gy (also `cGy`)     | %      |
+//! | `V95%`         | volume receiving at least 95 % of prescription   | %      |
+//!
+//! A `[Gy]`, `[%]` or `[cc]` suffix selects the result unit, e.g. `V20Gy[cc]` or
+//! `D2cc[%]` (percentage of the prescription dose).
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::dvh::{Dvh, VolumeUnit};
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoseUnit {
+    Gy,
+    /// Percentage of the prescription dose.
+    Percent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Metric {
+    Mean(DoseUnit),
+    Max(DoseUnit),
+    Min(DoseUnit),
+    /// Dose received by at least `volume`.
+    Dose {
+        volume: f64,
+        volume_unit: VolumeUnit,
+        unit: DoseUnit,
+    },
+    /// Volume receiving at least `dose`.
+    Volume {
+        dose: f64,
+        dose_unit: DoseUnit,
+        unit: VolumeUnit,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricValue {
+    Dose { value: f64, unit: DoseUnit },
+    Volume { value: f64, unit: VolumeUnit },
+}
+
+impl MetricValue {
+    pub fn value(&self) -> f64 {
+        match self {
+            MetricValue::Dose { value, .. } | MetricValue::Volume { value, .. } => *value,
+        }
+    }
+}
+
+impl Metric {
+    /// Evaluates the metric on `dvh`; `prescription` (Gy) is needed for relative doses.
+    pub fn evaluate(&self, dvh: &Dvh, prescription: Option<f64>) -> Result<MetricValue> {
+        let relative = |unit: DoseUnit| -> Result<f64> {
+            match unit {
+                DoseUnit::Gy => Ok(1.0),
+                DoseUnit::Percent => match prescription {
+                    Some(p) if p > 0.0 => Ok(p / 100.0),
+                    _ => Err(Error::InvalidArgument(format!(
+                        "{} needs a positive prescription dose",
+                        self
+                    ))),
+                },
+            }
+        };
+        let dose = |value: f64, unit: DoseUnit| -> Result<MetricValue> {
+            Ok(MetricValue::Dose {
+                value: value / relative(unit)?,
+                unit,
+            })
+        };
+        match *self {
+            Metric::Mean(unit) => dose(dvh.mean, unit),
+            Metric::Max(unit) => dose(dvh.max, unit),
+            Metric::Min(unit) => dose(dvh.min, unit),
+            Metric::Dose {
+                volume,
+                volume_unit,
+                unit,
+            } => dose(dvh.dose_at(volume, volume_unit), unit),
+            Metric::Volume {
+                dose,
+                dose_unit,
+                unit,
+            } => Ok(MetricValue::Volume {
+                value: dvh.volume_at(dose * relative(dose_unit)?, unit),
+                unit,
+            }),
+        }
+    }
+}
+
+fn dose_suffix(unit: DoseUnit) -> &'static str {
+    match unit {
+        DoseUnit::Gy => "Gy",
+        DoseUnit::Percent => "%",
+    }
+}
+
+fn volume_suffix(unit: VolumeUnit) -> &'static str {
+    match unit {
+        VolumeUnit::Cc => "cc",
+        VolumeUnit::Percent => "%",
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Metric::Mean(unit) => write!(f, "Dmean[{}]", dose_suffix(unit)),
+            Metric::Max(unit) => write!(f, "Dmax[{}]", dose_suffix(unit)),
+            Metric::Min(unit) => write!(f, "Dmin[{}]", dose_suffix(unit)),
+            Metric::Dose {
+                volume,
+                volume_unit,
+                unit,
+            } => write!(
+                f,
+                "D{}{}[{}]",
+                volume,
+                volume_suffix(volume_unit),
+                dose_suffix(unit)
+            ),
+            Metric::Volume {
+                dose,
+                dose_unit,
+                unit,
+            } => write!(
+                f,
+                "V{}{}[{}]",
+                dose,
+                dose_suffix(dose_unit),
+                volume_suffix(unit)
+            ),
+        }
+    }
+}
+
+impl fmt::Display for MetricValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            MetricValue::Dose { value, unit } => write!(f, "{} {}", value, dose_suffix(unit)),
+            MetricValue::Volume { value, unit } => write!(f, "{} {}", value, volume_suffix(unit)),
+        }
+    }
+}
+
+impl FromStr for Metric {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Format(format!("invalid dose metric: {}", s));
+        let text = s.trim();
+        let (body, result) = match text.strip_suffix(']') {
+            Some(t) => {
+                let open = t.rfind('[').ok_or_else(invalid)?;
+                (&t[..open], Some(t[open + 1..].trim()))
+            }
+            None => (text, None),
+        };
+        let dose_unit = |u: Option<&str>| match u {
+            None => Ok(DoseUnit::Gy),
+            Some(u) if u.eq_ignore_ascii_case("gy") => Ok(DoseUnit::Gy),
+            Some("%") => Ok(DoseUnit::Percent),
+            Some(_) => Err(invalid()),
+        };
+        let volume_unit = |u: Option<&str>| match u {
+            None | Some("%") => Ok(VolumeUnit::Percent),
+            Some(u) if u.eq_ignore_ascii_case("cc") => Ok(VolumeUnit::Cc),
+            Some(_) => Err(invalid()),
+        };
+        let mut chars = body.chars();
+        let kind = chars.next().ok_or_else(invalid)?.to_ascii_uppercase();
+        let rest = chars.as_str();
+        let lower = rest.to_ascii_lowercase();
+        if kind == 'D' {
+            match lower.as_str() {
+                "mean" => return Ok(Metric::Mean(dose_unit(result)?)),
+                "max" => return Ok(Metric::Max(dose_unit(result)?)),
+                "min" => return Ok(Metric::Min(dose_unit(result)?)),
+                _ => {}
+            }
+        }
+        let split = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .ok_or_else(invalid)?;
+        let value: f64 = rest[..split].parse().map_err(|_| invalid())?;
+        let suffix = &lower[split..];
+        match (kind, suffix) {
+            ('D', "%") | ('D', "cc") => Ok(Metric::Dose {
+                volume: value,
+                volume_unit: volume_unit(Some(suffix))?,
+                unit: dose_unit(result)?,
+            }),
+            ('V', "gy") | ('V', "cgy") | ('V', "%") => Ok(Metric::Volume {
+                dose: if suffix == "cgy" {
+                    value / 100.0
+                } else {
+                    value
+                },
+                dose_unit: if suffix == "%" {
+                    DoseUnit::Percent
+                } else {
+                    DoseUnit::Gy
+                },
+                unit: volume_unit(result)?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dvh::{Dvh, VolumeUnit};
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::metric::{DoseUnit, Metric, MetricValue};
+
+    #[test]
+    fn metric_parse() {
+        let m: Metric = "D95%".parse().unwrap();
+        assert_eq!(
+            m,
+            Metric::Dose {
+                volume: 95.0,
+                volume_unit: VolumeUnit::Percent,
+                unit: DoseUnit::Gy
+            }
+        );
+        assert_eq!(
+            "V20Gy[%]".parse::<Metric>().unwrap(),
+            Metric::Volume {
+                dose: 20.0,
+                dose_unit: DoseUnit::Gy,
+                unit: VolumeUnit::Percent
+            }
+        );
+        assert_eq!(
+            "v2000cGy[cc]".parse::<Metric>().unwrap(),
+            Metric::Volume {
+                dose: 20.0,
+                dose_unit: DoseUnit::Gy,
+                unit: VolumeUnit::Cc
+            }
+        );
+        assert_eq!(
+            "D0.035cc".parse::<Metric>().unwrap().to_string(),
+            "D0.035cc[Gy]"
+        );
+        assert_eq!(
+            " Dmean[%] ".parse::<Metric>().unwrap(),
+            Metric::Mean(DoseUnit::Percent)
+        );
+        for bad in [
+            "",
+            "D",
+            "X5%",
+            "D95",
+            "V20Gy[Gy]",
+            "D95%[cc]",
+            "Dmedian",
+            "V2.0.1Gy",
+        ]
+        .iter()
+        {
+            assert!(bad.parse::<Metric>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn metric_evaluate() {
+        let geometry = GridGeometry::new([10, 1, 1], Vec3::new(), Vec3::from(10.0, 10.0, 10.0));
+        let dose = Grid3::from_vec(geometry, (1..=10).map(|d| d as f64 * 6.0).collect()).unwrap();
+        let dvh = Dvh::from_fraction("PTV", &dose.map(|_| 1.0), &dose, 0.5).unwrap();
+        let eval = |s: &str, rx| s.parse::<Metric>().unwrap().evaluate(&dvh, rx);
+        assert_eq!(
+            eval("Dmax", None).unwrap(),
+            MetricValue::Dose {
+                value: 60.0,
+                unit: DoseUnit::Gy
+            }
+        );
+        assert!((eval("Dmean[%]", Some(60.0)).unwrap().value() - 55.0).abs() < 1e-9);
+        assert!(eval("Dmean[%]", None).is_err());
+        assert!((eval("V30Gy", None).unwrap().value() - 60.0).abs() < 1e-9);
+        assert!((eval("V50%[cc]", Some(60.0)).unwrap().value() - 6.0).abs() < 1e-9);
+        assert!((eval("D1cc", None).unwrap().value() - 60.0).abs() < 1e-9);
+        let d95 = eval("D95%", None).unwrap().value();
+        assert!((d95 - 6.25).abs() < 1e-9);
+        assert_eq!(eval("V36Gy", None).unwrap().to_string(), "50 %");
+    }
+}
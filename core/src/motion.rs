@@ -0,0 +1,305 @@
+//! Dose under respiratory motion.
+//!
+//! Displacements are rigid shifts (mm) of the anatomy from its planning position. Anatomy
+//! displaced by `s` sees the static dose at `x + s`, so the motion-averaged dose is the
+//! static dose convolved with the probability density of the displacements, a
+//! [`MotionPdf`]: measured from a breathing trace or parametric. Positions shifted outside
+//! the dose grid receive no dose.
+//!
+//! The convolution assumes many breathing cycles per beam. Interplay between the motion and
+//! a modulated delivery is estimated from the dose of every control point interval instead,
+//! each shifted by the displacement of the breathing trace at the time it is delivered.
+
+use crate::coords::Vec3;
+use crate::error::{Error, Result};
+use crate::grid::Grid3;
+use crate::machine::Machine;
+use crate::plan::Beam;
+use crate::resample::Interpolator;
+
+/// Discrete probability density of displacements.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotionPdf {
+    /// Displacements (mm) and their probabilities, summing to 1.
+    pub displacements: Vec<(Vec3<f64>, f64)>,
+}
+
+impl MotionPdf {
+    /// Density of `weights` of displacements, normalized.
+    pub fn new(weights: Vec<(Vec3<f64>, f64)>) -> Result<Self> {
+        let total: f64 = weights.iter().map(|(_, w)| w).sum();
+        if weights.iter().any(|(_, w)| w.is_nan() || *w < 0.0) || total <= 0.0 {
+            return Err(Error::InvalidArgument(
+                "motion weights must be non-negative with a positive sum".to_string(),
+            ));
+        }
+        Ok(MotionPdf {
+            displacements: weights.into_iter().map(|(s, w)| (s, w / total)).collect(),
+        })
+    }
+
+    /// Gaussian with standard deviations `sigma` (mm) along x, y and z, sampled every `step`
+    /// mm to three sigma; a zero sigma gives motion along the other axes only.
+    pub fn gaussian(sigma: Vec3<f64>, step: f64) -> Result<Self> {
+        if step.is_nan() || step <= 0.0 {
+            return Err(Error::InvalidArgument(format!("motion step {} mm", step)));
+        }
+        let axes: Vec<Vec<f64>> = sigma
+            .to_array()
+            .iter()
+            .map(|&s| {
+                let n = (3.0 * s.max(0.0) / step).floor() as i64;
+                (-n..=n).map(|i| i as f64 * step).collect()
+            })
+            .collect();
+        let weight = |d: f64, s: f64| {
+            if s > 0.0 {
+                (-0.5 * (d / s).powi(2)).exp()
+            } else {
+                1.0
+            }
+        };
+        let mut weights = Vec::new();
+        for &z in &axes[2] {
+            for &y in &axes[1] {
+                for &x in &axes[0] {
+                    let w = weight(x, sigma.x) * weight(y, sigma.y) * weight(z, sigma.z);
+                    weights.push((Vec3::from(x, y, z), w));
+                }
+            }
+        }
+        Self::new(weights)
+    }
+
+    /// Density of the positions of `trace` over its duration, binned to `bin` mm.
+    pub fn from_trace(trace: &BreathingTrace, bin: f64) -> Result<Self> {
+        if bin.is_nan() || bin <= 0.0 {
+            return Err(Error::InvalidArgument(format!("motion bin {} mm", bin)));
+        }
+        let mut bins: Vec<([i64; 3], f64)> = Vec::new();
+        let times = &trace.times;
+        for n in 0..times.len() {
+            // Every sample stands for half of each neighbouring interval.
+            let lo = if n > 0 { times[n - 1] } else { times[n] };
+            let hi = if n + 1 < times.len() {
+                times[n + 1]
+            } else {
+                times[n]
+            };
+            let w = if times.len() > 1 {
+                0.5 * (hi - lo)
+            } else {
+                1.0
+            };
+            let key = trace.positions[n]
+                .to_array()
+                .map(|c| (c / bin).round() as i64);
+            match bins.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, total)) => *total += w,
+                None => bins.push((key, w)),
+            }
+        }
+        Self::new(
+            bins.into_iter()
+                .map(|(k, w)| (Vec3::from_array(k.map(|c| c as f64 * bin)), w))
+                .collect(),
+        )
+    }
+
+    /// Probability-weighted mean displacement.
+    pub fn mean(&self) -> Vec3<f64> {
+        self.displacements
+            .iter()
+            .fold(Vec3::new(), |m, (s, w)| m + s.scale(*w))
+    }
+}
+
+/// Displacements (mm) of the anatomy over time (s), repeating after the last sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BreathingTrace {
+    pub times: Vec<f64>,
+    pub positions: Vec<Vec3<f64>>,
+}
+
+impl BreathingTrace {
+    /// Trace of `positions` at ascending `times`.
+    pub fn new(times: Vec<f64>, positions: Vec<Vec3<f64>>) -> Result<Self> {
+        if times.is_empty() || times.len() != positions.len() {
+            return Err(Error::InvalidArgument(format!(
+                "{} times for {} breathing positions",
+                times.len(),
+                positions.len()
+            )));
+        }
+        if times.windows(2).any(|t| t[1].is_nan() || t[1] <= t[0]) {
+            return Err(Error::InvalidArgument(
+                "breathing trace times must ascend".to_string(),
+            ));
+        }
+        Ok(BreathingTrace { times, positions })
+    }
+
+    /// One cycle of the Lujan model `amplitude cos^2n(π t / period)` sampled at `samples`
+    /// times: the full `amplitude` (mm) at end inhalation and none at end exhalation, where
+    /// larger `n` lingers longer.
+    pub fn lujan(amplitude: Vec3<f64>, period: f64, n: i32, samples: usize) -> Result<Self> {
+        if period.is_nan() || period <= 0.0 || n < 1 || samples < 2 {
+            return Err(Error::InvalidArgument(format!(
+                "invalid breathing model: period {} s, power {}, {} samples",
+                period, n, samples
+            )));
+        }
+        let times: Vec<f64> = (0..samples)
+            .map(|i| period * i as f64 / samples as f64)
+            .collect();
+        let positions = times
+            .iter()
+            .map(|t| amplitude.scale((std::f64::consts::PI * t / period).cos().powi(2 * n)))
+            .collect();
+        Self::new(times, positions)
+    }
+
+    /// Duration (s) of one repetition: the span of the samples plus the mean sample interval.
+    pub fn period(&self) -> f64 {
+        let n = self.times.len();
+        if n < 2 {
+            return 0.0;
+        }
+        let span = self.times[n - 1] - self.times[0];
+        span * n as f64 / (n - 1) as f64
+    }
+
+    /// Displacement at time `t` (s), linearly interpolated and repeating the trace.
+    pub fn at(&self, t: f64) -> Vec3<f64> {
+        let n = self.times.len();
+        let period = self.period();
+        if n < 2 || period <= 0.0 {
+            return self.positions[0];
+        }
+        let t = self.times[0] + (t - self.times[0]).rem_euclid(period);
+        let i = self.times.partition_point(|&s| s <= t);
+        // Past the last sample the trace returns to the first.
+        let (a, b, tb) = if i >= n {
+            (n - 1, 0, self.times[0] + period)
+        } else {
+            (i - 1, i, self.times[i])
+        };
+        let f = (t - self.times[a]) / (tb - self.times[a]);
+        self.positions[a].scale(1.0 - f) + self.positions[b].scale(f)
+    }
+}
+
+/// `dose` at the displaced position `x + s` of every voxel, 0 outside the grid.
+fn shifted(sampler: &Interpolator, s: Vec3<f64>, w: f64, out: &mut Grid3<f64>) {
+    let geometry = out.geometry().clone();
+    for (n, d) in out.data_mut().iter_mut().enumerate() {
+        let [i, j, k] = geometry.ijk(n);
+        *d += w * sampler.at(geometry.position(i, j, k) + s).unwrap_or(0.0);
+    }
+}
+
+/// The motion-averaged dose of a static `dose` under `pdf`.
+pub fn convolve(dose: &Grid3<f64>, pdf: &MotionPdf) -> Grid3<f64> {
+    let sampler = Interpolator::new(dose);
+    let mut out = Grid3::new(dose.geometry().clone(), 0.0);
+    for (s, w) in &pdf.displacements {
+        shifted(&sampler, *s, *w, &mut out);
+    }
+    out
+}
+
+/// Start time (s) of every control point interval of `beam` delivered by `machine` as fast
+/// as its limits allow, see [`Machine::interval_times`].
+pub fn interval_starts(beam: &Beam, machine: &Machine) -> Vec<f64> {
+    let mut t = 0.0;
+    machine
+        .interval_times(&beam.delivered())
+        .iter()
+        .map(|d| {
+            let start = t;
+            t += d;
+            start
+        })
+        .collect()
+}
+
+/// Dose of the control point intervals `doses`, delivered at `times` (s) after the start of
+/// the beam at time `start` of the breathing `trace`, each shifted by the displacement of
+/// the trace at its delivery time.
+pub fn interplay(
+    doses: &[&Grid3<f64>],
+    times: &[f64],
+    trace: &BreathingTrace,
+    start: f64,
+) -> Result<Grid3<f64>> {
+    let first = doses
+        .first()
+        .ok_or_else(|| Error::InvalidArgument("interplay of no doses".to_string()))?;
+    if doses.len() != times.len() {
+        return Err(Error::InvalidArgument(format!(
+            "{} times for {} control point doses",
+            times.len(),
+            doses.len()
+        )));
+    }
+    if doses.iter().any(|d| d.geometry() != first.geometry()) {
+        return Err(Error::InvalidArgument(
+            "control point doses must share one grid".to_string(),
+        ));
+    }
+    let mut out = Grid3::new(first.geometry().clone(), 0.0);
+    for (dose, t) in doses.iter().zip(times) {
+        shifted(&Interpolator::new(dose), trace.at(start + t), 1.0, &mut out);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::motion::{convolve, interplay, BreathingTrace, MotionPdf};
+
+    #[test]
+    fn motion_blurring_and_interplay() {
+        let geometry = GridGeometry::new([21, 1, 1], Vec3::new(), Vec3::from(1.0, 1.0, 1.0));
+        let field = Grid3::from_vec(
+            geometry.clone(),
+            (0..21).map(|i| if i >= 10 { 2.0 } else { 0.0 }).collect(),
+        )
+        .unwrap();
+
+        let pdf = MotionPdf::gaussian(Vec3::from(1.5, 0.0, 0.0), 1.0).unwrap();
+        assert_eq!(pdf.displacements.len(), 9);
+        let total: f64 = pdf.displacements.iter().map(|(_, w)| w).sum();
+        assert!((total - 1.0).abs() < 1e-12);
+        let blurred = convolve(&field, &pdf);
+        // The edge spreads out symmetrically; the plateau and the dose outside recover.
+        assert!((blurred[[9, 0, 0]] + blurred[[10, 0, 0]] - 2.0).abs() < 1e-9);
+        assert!(blurred[[10, 0, 0]] > 1.0 && blurred[[10, 0, 0]] < 2.0);
+        assert!(blurred[[2, 0, 0]] < 1e-9);
+        assert!((blurred[[16, 0, 0]] - 2.0).abs() < 1e-9);
+
+        // cos² breathing spends on average half its amplitude.
+        let trace = BreathingTrace::lujan(Vec3::from(4.0, 0.0, 0.0), 4.0, 1, 40).unwrap();
+        assert_eq!(trace.period(), 4.0);
+        assert!((trace.at(2.0) - Vec3::new()).norm() < 1e-12);
+        assert!((trace.at(4.0).x - 4.0).abs() < 1e-12);
+        assert!((trace.at(1.0).x - 2.0).abs() < 1e-12);
+        let measured = MotionPdf::from_trace(&trace, 1.0).unwrap();
+        assert!((measured.mean().x - 2.0).abs() < 0.1);
+        assert!(MotionPdf::new(vec![(Vec3::new(), 0.0)]).is_err());
+
+        // Two halves of the dose, delivered at end inhalation and end exhalation.
+        let half = field.map(|d| 0.5 * d);
+        let doses = [&half, &half];
+        let dose = interplay(&doses, &[0.0, 2.0], &trace, 0.0).unwrap();
+        assert_eq!(dose[[6, 0, 0]], 1.0);
+        assert_eq!(dose[[8, 0, 0]], 1.0);
+        assert_eq!(dose[[12, 0, 0]], 2.0);
+        let shifted = interplay(&doses, &[0.0, 2.0], &trace, 2.0).unwrap();
+        assert_eq!(shifted, dose);
+        assert!(interplay(&doses, &[0.0], &trace, 0.0).is_err());
+        assert!(BreathingTrace::new(vec![0.0, 0.0], vec![Vec3::new(); 2]).is_err());
+    }
+}
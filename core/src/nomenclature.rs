@@ -0,0 +1,363 @@
+//! Mapping of free-text ROI names to AAPM TG-263 standard names.
+//!
+//! Names are split into lowercase tokens (on separators, case changes and digits) and
+//! laterality tokens (`l`, `lt`, `left`, ...) are taken out. The longest leading run of the
+//! remaining tokens that matches a standard name or a synonym gives the base name, and the
+//! laterality is appended as `_L`/`_R` when the standard list has that variant. Trailing
+//! tokens that do not match (e.g. the `x` in `Parotid_L_x`) are ignored. Target names keep
+//! their suffix: `ptv 70` becomes `PTV_70`.
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::error::{Error, Result};
+
+/// TG-263 standard names for common organs at risk.
+pub const TG263_NAMES: &[&str] = &[
+    "Bladder",
+    "Bone_Mandible",
+    "BrachialPlex_L",
+    "BrachialPlex_R",
+    "Brain",
+    "Brainstem",
+    "Breast_L",
+    "Breast_R",
+    "Bowel_Large",
+    "Bowel_Small",
+    "CaudaEquina",
+    "Cavity_Oral",
+    "Cochlea_L",
+    "Cochlea_R",
+    "Colon",
+    "Duodenum",
+    "Esophagus",
+    "External",
+    "Eye_L",
+    "Eye_R",
+    "Femur_Head_L",
+    "Femur_Head_R",
+    "Glnd_Lacrimal_L",
+    "Glnd_Lacrimal_R",
+    "Glnd_Submand_L",
+    "Glnd_Submand_R",
+    "Glnd_Thyroid",
+    "Heart",
+    "Hippocampus_L",
+    "Hippocampus_R",
+    "Kidney_L",
+    "Kidney_R",
+    "Kidneys",
+    "Larynx",
+    "Lens_L",
+    "Lens_R",
+    "Lips",
+    "Liver",
+    "Lung_L",
+    "Lung_R",
+    "Lungs",
+    "OpticChiasm",
+    "OpticNrv_L",
+    "OpticNrv_R",
+    "Pancreas",
+    "Parotid_L",
+    "Parotid_R",
+    "PenileBulb",
+    "Pituitary",
+    "Prostate",
+    "Rectum",
+    "SeminalVes",
+    "SpinalCord",
+    "Spleen",
+    "Stomach",
+];
+
+/// Common aliases (`alias = standard base name`).
+const TG263_SYNONYMS: &str = "
+body = External
+skin = External
+outline = External
+cord = SpinalCord
+brain stem = Brainstem
+optic nerve = OpticNrv
+chiasm = OpticChiasm
+optic chiasma = OpticChiasm
+femoral head = Femur_Head
+femur = Femur_Head
+small bowel = Bowel_Small
+large bowel = Bowel_Large
+bowel small = Bowel_Small
+oral cavity = Cavity_Oral
+mandible = Bone_Mandible
+seminal vesicles = SeminalVes
+seminal vesicle = SeminalVes
+penile bulb = PenileBulb
+lung total = Lungs
+total lung = Lungs
+both lungs = Lungs
+parotid gland = Parotid
+submandibular = Glnd_Submand
+submandibular gland = Glnd_Submand
+submand = Glnd_Submand
+lacrimal = Glnd_Lacrimal
+lacrimal gland = Glnd_Lacrimal
+thyroid = Glnd_Thyroid
+eye ball = Eye
+globe = Eye
+brachial plexus = BrachialPlex
+oesophagus = Esophagus
+urinary bladder = Bladder
+";
+
+const TARGETS: &[&str] = &["PTV", "CTV", "GTV", "ITV"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Left,
+    Right,
+}
+
+/// Names that were mapped (`original`, `standard`) and names without a match.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MappingReport {
+    pub mapped: Vec<(String, String)>,
+    pub unmapped: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NameMapper {
+    standard: BTreeSet<String>,
+    /// Token key of a standard base name or synonym -> standard base name.
+    keys: HashMap<String, String>,
+}
+
+impl NameMapper {
+    /// A mapper without any names.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The built-in TG-263 names and synonyms.
+    pub fn tg263() -> Self {
+        let mut mapper = Self::new();
+        for name in TG263_NAMES {
+            mapper.add_standard(name);
+        }
+        mapper
+            .load_synonyms(TG263_SYNONYMS)
+            .expect("built-in synonyms refer to standard names");
+        mapper
+    }
+
+    pub fn add_standard(&mut self, name: &str) {
+        self.standard.insert(name.to_string());
+        let base = strip_side(name);
+        self.keys.insert(key(&tokens(base)), base.to_string());
+    }
+
+    /// Maps `alias` to the standard name or base name `standard` (`Parotid` for
+    /// `Parotid_L`/`Parotid_R`).
+    pub fn add_synonym(&mut self, alias: &str, standard: &str) -> Result<()> {
+        let known = self.standard.contains(standard)
+            || self.standard.iter().any(|s| strip_side(s) == standard);
+        if !known {
+            return Err(Error::InvalidArgument(format!(
+                "{} is not a standard name",
+                standard
+            )));
+        }
+        self.keys.insert(key(&tokens(alias)), standard.to_string());
+        Ok(())
+    }
+
+    /// Adds `alias = standard` lines; blank lines and `#` comments are skipped.
+    pub fn load_synonyms(&mut self, text: &str) -> Result<()> {
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (alias, standard) = line
+                .split_once('=')
+                .ok_or_else(|| Error::Format(format!("synonym line without '=': {}", line)))?;
+            self.add_synonym(alias.trim(), standard.trim())?;
+        }
+        Ok(())
+    }
+
+    pub fn standard_names(&self) -> impl Iterator<Item = &str> {
+        self.standard.iter().map(|s| s.as_str())
+    }
+
+    /// The standard name for `name`, if one matches.
+    pub fn map(&self, name: &str) -> Option<String> {
+        if let Some(s) = self
+            .standard
+            .iter()
+            .find(|s| s.eq_ignore_ascii_case(name.trim()))
+        {
+            return Some(s.clone());
+        }
+        if let Some(t) = target(name) {
+            return Some(t);
+        }
+        let mut side = None;
+        let rest: Vec<String> = tokens(name)
+            .into_iter()
+            .filter(|t| match t.as_str() {
+                "l" | "lt" | "left" | "lft" => {
+                    side = Some(Side::Left);
+                    false
+                }
+                "r" | "rt" | "right" | "rgt" => {
+                    side = Some(Side::Right);
+                    false
+                }
+                _ => true,
+            })
+            .collect();
+        for n in (1..=rest.len()).rev() {
+            let base = match self.keys.get(&key(&rest[..n])) {
+                Some(b) => b,
+                None => continue,
+            };
+            let candidate = match side {
+                Some(Side::Left) => format!("{}_L", base),
+                Some(Side::Right) => format!("{}_R", base),
+                None => base.clone(),
+            };
+            if self.standard.contains(&candidate) {
+                return Some(candidate);
+            }
+            if self.standard.contains(base) {
+                return Some(base.clone());
+            }
+        }
+        None
+    }
+
+    pub fn map_all<'a, I: IntoIterator<Item = &'a str>>(&self, names: I) -> MappingReport {
+        let mut report = MappingReport::default();
+        for name in names {
+            match self.map(name) {
+                Some(s) => report.mapped.push((name.to_string(), s)),
+                None => report.unmapped.push(name.to_string()),
+            }
+        }
+        report
+    }
+}
+
+fn strip_side(name: &str) -> &str {
+    name.strip_suffix("_L")
+        .or_else(|| name.strip_suffix("_R"))
+        .unwrap_or(name)
+}
+
+/// `PTV`, `CTV`, `GTV` or `ITV` with the rest of the name joined by underscores.
+fn target(name: &str) -> Option<String> {
+    let name = name.trim();
+    let upper = name.to_ascii_uppercase();
+    let prefix = TARGETS.iter().find(|t| upper.starts_with(*t))?;
+    let remainder = &name[prefix.len()..];
+    // "PTVx" or "CTVn" are not target names with a suffix.
+    if remainder.starts_with(|c: char| c.is_alphabetic()) {
+        return None;
+    }
+    let mut out = prefix.to_string();
+    for r in remainder
+        .split(|c: char| !c.is_ascii_alphanumeric() && c != '.')
+        .filter(|s| !s.is_empty())
+    {
+        out.push('_');
+        out.push_str(r);
+    }
+    Some(out)
+}
+
+fn key(tokens: &[String]) -> String {
+    tokens.concat()
+}
+
+/// Lowercase tokens split on separators, lower-to-upper case changes and letter/digit
+/// boundaries.
+fn tokens(name: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut current = String::new();
+    let mut previous: Option<char> = None;
+    for c in name.chars() {
+        if !c.is_alphanumeric() {
+            if !current.is_empty() {
+                out.push(std::mem::take(&mut current));
+            }
+            previous = None;
+            continue;
+        }
+        if let Some(p) = previous {
+            let case_change = p.is_lowercase() && c.is_uppercase();
+            let digit_change = p.is_ascii_digit() != c.is_ascii_digit();
+            if (case_change || digit_change) && !current.is_empty() {
+                out.push(std::mem::take(&mut current));
+            }
+        }
+        current.extend(c.to_lowercase());
+        previous = Some(c);
+    }
+    if !current.is_empty() {
+        out.push(current);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nomenclature::{tokens, NameMapper};
+
+    #[test]
+    fn nomenclature_tokens() {
+        assert_eq!(tokens("OpticNrv_L"), vec!["optic", "nrv", "l"]);
+        assert_eq!(tokens("lt  parotid-2"), vec!["lt", "parotid", "2"]);
+        assert_eq!(tokens("SPINAL CORD"), vec!["spinal", "cord"]);
+    }
+
+    #[test]
+    fn nomenclature_map() {
+        let m = NameMapper::tg263();
+        let cases = [
+            ("lt parotid", "Parotid_L"),
+            ("Parotid_L_x", "Parotid_L"),
+            ("Right Parotid Gland", "Parotid_R"),
+            ("parotid_r", "Parotid_R"),
+            ("Spinal Cord", "SpinalCord"),
+            ("cord", "SpinalCord"),
+            ("BrainStem", "Brainstem"),
+            ("Optic Nerve L", "OpticNrv_L"),
+            ("Femoral Head Rt", "Femur_Head_R"),
+            ("BODY", "External"),
+            ("Small Bowel", "Bowel_Small"),
+            ("Lungs", "Lungs"),
+            ("lung left", "Lung_L"),
+            ("ptv 70", "PTV_70"),
+            ("PTV_High", "PTV_High"),
+            ("CTV-54.0", "CTV_54.0"),
+        ];
+        for (name, expected) in cases.iter() {
+            assert_eq!(m.map(name).as_deref(), Some(*expected), "{}", name);
+        }
+        assert_eq!(m.map("Couch"), None);
+        assert_eq!(m.map("Ptvx"), None);
+    }
+
+    #[test]
+    fn nomenclature_custom_synonyms() {
+        let mut m = NameMapper::tg263();
+        assert_eq!(m.map("Speicheldruese links"), None);
+        assert!(m.load_synonyms("links").is_err());
+        m.load_synonyms("# German\nspeicheldruese links = Parotid_L\n")
+            .unwrap();
+        assert_eq!(m.map("Speicheldruese links").as_deref(), Some("Parotid_L"));
+        assert!(m.add_synonym("couch", "Couch").is_err());
+        let report = m.map_all(vec!["lt parotid", "Couch", "Heart"]);
+        assert_eq!(report.mapped.len(), 2);
+        assert_eq!(report.unmapped, vec!["Couch".to_string()]);
+        assert!(m.standard_names().any(|s| s == "Heart"));
+    }
+}
@@ -0,0 +1,185 @@
+//! Dose renormalization.
+//!
+//! The dose grid is scaled by one factor so that a criterion is met. Dose metrics scale with
+//! the dose, so `D95% = 60 Gy` gives `60 / D95%`; a coverage criterion `V57Gy = 98 %` is
+//! the same as `D98% = 57 Gy`. Monitor units scale with the same factor.
+
+use crate::coords::Vec3;
+use crate::dvh::Dvh;
+use crate::error::{Error, Result};
+use crate::grid::Grid3;
+use crate::metric::{DoseUnit, Metric};
+use crate::resample::Interpolator;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Criterion<'a> {
+    /// A metric of `dvh` (of the dose being normalized) reaches `value` in the metric's
+    /// result unit: a dose for dose metrics, a volume for volume metrics.
+    Metric {
+        dvh: &'a Dvh,
+        metric: Metric,
+        value: f64,
+    },
+    /// The interpolated dose at `point` (mm), e.g. the isocenter.
+    Point {
+        point: Vec3<f64>,
+        dose: f64,
+        unit: DoseUnit,
+    },
+    /// The maximum dose of the grid.
+    Max { dose: f64, unit: DoseUnit },
+}
+
+#[derive(Debug, Clone)]
+pub struct Normalization {
+    pub dose: Grid3<f64>,
+    /// Factor applied to the dose.
+    pub scale: f64,
+}
+
+impl Normalization {
+    /// Scales beam or segment monitor units along with the dose.
+    pub fn scale_monitor_units(&self, monitor_units: &mut [f64]) {
+        for mu in monitor_units {
+            *mu *= self.scale;
+        }
+    }
+}
+
+fn to_gy(value: f64, unit: DoseUnit, prescription: Option<f64>) -> Result<f64> {
+    match unit {
+        DoseUnit::Gy => Ok(value),
+        DoseUnit::Percent => match prescription {
+            Some(p) if p > 0.0 => Ok(value * p / 100.0),
+            _ => Err(Error::InvalidArgument(
+                "a relative normalization dose needs a positive prescription".to_string(),
+            )),
+        },
+    }
+}
+
+/// Factor that makes `dose` meet `criterion`; `prescription` (Gy) is needed for relative
+/// doses.
+pub fn normalization_scale(
+    dose: &Grid3<f64>,
+    criterion: &Criterion,
+    prescription: Option<f64>,
+) -> Result<f64> {
+    let (wanted, current) = match *criterion {
+        Criterion::Metric { dvh, metric, value } => match metric {
+            Metric::Volume {
+                dose: level,
+                dose_unit,
+                unit,
+            } => (
+                to_gy(level, dose_unit, prescription)?,
+                dvh.dose_at(value, unit),
+            ),
+            _ => (value, metric.evaluate(dvh, prescription)?.value()),
+        },
+        Criterion::Point {
+            point,
+            dose: d,
+            unit,
+        } => {
+            let current = Interpolator::new(dose).at(point).ok_or_else(|| {
+                Error::InvalidArgument(format!("{:?} lies outside the dose grid", point))
+            })?;
+            (to_gy(d, unit, prescription)?, current)
+        }
+        Criterion::Max { dose: d, unit } => (
+            to_gy(d, unit, prescription)?,
+            dose.data()
+                .iter()
+                .cloned()
+                .fold(f64::NEG_INFINITY, f64::max),
+        ),
+    };
+    if !(current.is_finite() && current > 0.0) {
+        return Err(Error::InvalidArgument(format!(
+            "cannot normalize a dose of {} Gy",
+            current
+        )));
+    }
+    Ok(wanted / current)
+}
+
+/// `dose` scaled to meet `criterion`.
+pub fn normalize(
+    dose: &Grid3<f64>,
+    criterion: &Criterion,
+    prescription: Option<f64>,
+) -> Result<Normalization> {
+    let scale = normalization_scale(dose, criterion, prescription)?;
+    Ok(Normalization {
+        dose: dose.map(|d| d * scale),
+        scale,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dvh::{Dvh, VolumeUnit};
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::metric::{DoseUnit, Metric};
+    use crate::normalization::{normalization_scale, normalize, Criterion};
+
+    #[test]
+    fn normalize_criteria() {
+        let geometry = GridGeometry::new([10, 1, 1], Vec3::new(), Vec3::from(10.0, 10.0, 10.0));
+        let dose = Grid3::from_vec(geometry, (1..=10).map(|d| d as f64 * 6.0).collect()).unwrap();
+        let dvh = Dvh::from_fraction("PTV", &dose.map(|_| 1.0), &dose, 0.5).unwrap();
+
+        let mean = Criterion::Metric {
+            dvh: &dvh,
+            metric: Metric::Mean(DoseUnit::Gy),
+            value: 66.0,
+        };
+        let n = normalize(&dose, &mean, None).unwrap();
+        assert!((n.scale - 2.0).abs() < 1e-12);
+        assert_eq!(n.dose.data()[9], 120.0);
+        let mut mu = [100.0, 50.0];
+        n.scale_monitor_units(&mut mu);
+        assert_eq!(mu, [200.0, 100.0]);
+
+        // V50% = 60 % of a 60 Gy prescription holds already (D60% = 30 Gy).
+        let coverage = Criterion::Metric {
+            dvh: &dvh,
+            metric: "V50%".parse().unwrap(),
+            value: 60.0,
+        };
+        let s = normalization_scale(&dose, &coverage, Some(60.0)).unwrap();
+        assert!((s - 1.0).abs() < 1e-9);
+        assert!((dvh.dose_at(60.0, VolumeUnit::Percent) - 30.0).abs() < 1e-9);
+        // Only 50 % reaching 30 Gy means scaling D50% = 36 Gy down to 30 Gy.
+        let coverage = Criterion::Metric {
+            dvh: &dvh,
+            metric: "V30Gy".parse().unwrap(),
+            value: 50.0,
+        };
+        let s = normalization_scale(&dose, &coverage, None).unwrap();
+        assert!((s - 30.0 / 36.0).abs() < 1e-9);
+
+        let isocenter = Criterion::Point {
+            point: Vec3::from(15.0, 0.0, 0.0),
+            dose: 100.0,
+            unit: DoseUnit::Percent,
+        };
+        assert!((normalization_scale(&dose, &isocenter, Some(45.0)).unwrap() - 3.0).abs() < 1e-9);
+        assert!(normalization_scale(&dose, &isocenter, None).is_err());
+        let outside = Criterion::Point {
+            point: Vec3::from(-50.0, 0.0, 0.0),
+            dose: 60.0,
+            unit: DoseUnit::Gy,
+        };
+        assert!(normalization_scale(&dose, &outside, None).is_err());
+        let max = Criterion::Max {
+            dose: 107.0,
+            unit: DoseUnit::Percent,
+        };
+        assert!((normalization_scale(&dose, &max, Some(60.0)).unwrap() - 1.07).abs() < 1e-12);
+        let zero = dose.map(|_| 0.0);
+        assert!(normalize(&zero, &max, Some(60.0)).is_err());
+    }
+}
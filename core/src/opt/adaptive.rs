@@ -0,0 +1,234 @@
+//! Warm-start re-optimization for adaptive planning.
+//!
+//! An adapted plan keeps the beams of the plan it adapts: its matrix is computed for the same
+//! beams on the anatomy of the day, with the structures contoured on it, and the optimization
+//! starts from the prior plan instead of flat weights. Beamlet weights carry over to the
+//! beamlets at the same beam, energy and position, fluence maps are sampled at the bixel
+//! centers, and segments keep their leaves, rounded to the bixel edges, either frozen with
+//! only their weights re-optimized or as the first apertures of direct aperture optimization.
+//! Starting near the optimum, few iterations are needed, so the default solver stops early:
+//! speed matters more than the last digit of the objective while the patient is on the couch.
+
+use crate::dose::beam::Fluence;
+use crate::dose::dij::{Beamlet, Dij};
+use crate::error::{Error, Result};
+use crate::opt::dao::{self, DaoOptions, DaoResult, Segment};
+use crate::opt::fmo::{self, FmoOptions, FmoResult};
+use crate::opt::objective::PlanObjectives;
+
+/// The plan being adapted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PriorPlan<'a> {
+    /// Beamlet weights of a prior matrix; beamlets without a match start at zero.
+    Beamlets {
+        beamlets: &'a [Beamlet],
+        weights: &'a [f64],
+    },
+    /// Fluence map of every beam, indexed by beam, as in [`FmoResult::fluences`] for photon
+    /// beams; proton spots start at zero.
+    Fluences(&'a [Fluence]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveOptions {
+    /// Whether segments keep their shapes, only their weights being re-optimized.
+    pub freeze_apertures: bool,
+    /// Apertures and solver; the solver also optimizes beamlet weights.
+    pub dao: DaoOptions,
+}
+
+impl Default for AdaptiveOptions {
+    fn default() -> Self {
+        AdaptiveOptions {
+            freeze_apertures: false,
+            dao: DaoOptions {
+                weights: FmoOptions {
+                    max_iterations: 50,
+                    tolerance: 1e-5,
+                    penalty_steps: 2,
+                    ..FmoOptions::default()
+                },
+                ..DaoOptions::default()
+            },
+        }
+    }
+}
+
+/// Starting beamlet weights of `dij` from `prior`.
+pub fn transfer(dij: &Dij, prior: &PriorPlan) -> Result<Vec<f64>> {
+    match prior {
+        PriorPlan::Beamlets { beamlets, weights } => {
+            if beamlets.len() != weights.len() {
+                return Err(Error::InvalidArgument(format!(
+                    "{} weights for {} prior beamlets",
+                    weights.len(),
+                    beamlets.len()
+                )));
+            }
+            let same = |a: &Beamlet, b: &Beamlet| {
+                a.beam == b.beam
+                    && (a.x - b.x).abs() < 1e-6
+                    && (a.y - b.y).abs() < 1e-6
+                    && match (a.energy, b.energy) {
+                        (Some(e), Some(f)) => (e - f).abs() < 1e-6,
+                        (None, None) => true,
+                        _ => false,
+                    }
+            };
+            Ok(dij
+                .beamlets()
+                .iter()
+                .map(|b| {
+                    beamlets
+                        .iter()
+                        .position(|p| same(p, b))
+                        .map_or(0.0, |n| weights[n])
+                })
+                .collect())
+        }
+        PriorPlan::Fluences(fluences) => dij
+            .beamlets()
+            .iter()
+            .map(|b| match (b.energy, fluences.get(b.beam)) {
+                (Some(_), _) => Ok(0.0),
+                (None, Some(f)) => Ok(f.at(b.x, b.y)),
+                (None, None) => Err(Error::InvalidArgument(format!(
+                    "no prior fluence for beam {}",
+                    b.beam
+                ))),
+            })
+            .collect(),
+    }
+}
+
+/// Re-optimizes the beamlet weights of `dij` for `plan` starting from `prior`.
+pub fn reoptimize(
+    dij: &Dij,
+    plan: &PlanObjectives,
+    prior: &PriorPlan,
+    options: &AdaptiveOptions,
+) -> Result<FmoResult> {
+    let weights = transfer(dij, prior)?;
+    fmo::optimize_from(dij, plan, weights, &options.dao.weights)
+}
+
+/// Re-optimizes the prior `segments` on `dij` for `plan`; without frozen apertures, apertures
+/// are added up to the per-beam limit, the prior segments counting towards it.
+pub fn reoptimize_segments(
+    dij: &Dij,
+    plan: &PlanObjectives,
+    segments: &[Segment],
+    options: &AdaptiveOptions,
+) -> Result<DaoResult> {
+    let mut dao_options = options.dao;
+    if options.freeze_apertures {
+        dao_options.max_apertures = 0;
+    }
+    dao::optimize_from(dij, plan, segments, &dao_options)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::dij::{Beamlet, Dij};
+    use crate::grid::GridGeometry;
+    use crate::opt::adaptive::{
+        reoptimize, reoptimize_segments, transfer, AdaptiveOptions, PriorPlan,
+    };
+    use crate::opt::dao::{self, DaoOptions};
+    use crate::opt::fmo::{self, FmoOptions};
+    use crate::opt::objective::{DoseObjective, ObjectiveKind, PlanObjectives};
+
+    /// One beam of two rows of four bixels over a row of nine voxels, bixel column `c` peaking
+    /// in voxel `2 c + 1` with its dose scaled by `scale`; the target covers voxels 2 to 5 and
+    /// an organ at risk voxels 6 to 8.
+    fn dij(scale: f32) -> Dij {
+        let geometry = GridGeometry::new([9, 1, 1], Vec3::new(), Vec3::from(1.0, 1.0, 1.0));
+        let beamlets = (0..8)
+            .map(|n| Beamlet {
+                beam: 0,
+                x: 5.0 * (n % 4) as f64 + 2.5,
+                y: 5.0 * (n / 4) as f64 + 2.5,
+                energy: None,
+            })
+            .collect();
+        let columns = (0..8u32)
+            .map(|n| {
+                let peak = 2 * (n % 4) + 1;
+                let scale = if n < 4 { scale } else { 0.7 * scale };
+                vec![
+                    (peak - 1, 0.4 * scale),
+                    (peak, scale),
+                    (peak + 1, 0.4 * scale),
+                ]
+            })
+            .collect();
+        let structures = vec![
+            ("PTV".to_string(), (2..6).collect()),
+            ("OAR".to_string(), (6..9).collect()),
+        ];
+        Dij::from_columns(
+            geometry,
+            5.0,
+            beamlets,
+            (0..9).collect(),
+            structures,
+            columns,
+        )
+        .unwrap()
+    }
+
+    fn plan() -> PlanObjectives {
+        PlanObjectives::new()
+            .with_objective(DoseObjective::new(
+                "PTV",
+                ObjectiveKind::Uniform(2.0),
+                100.0,
+            ))
+            .with_objective(DoseObjective::new("OAR", ObjectiveKind::Max(0.0), 1.0))
+    }
+
+    #[test]
+    fn warm_start() {
+        let (before, after) = (dij(1.0), dij(0.8));
+        let objectives = plan();
+        let prior = fmo::optimize(&before, &objectives, &FmoOptions::default()).unwrap();
+        let beamlets = PriorPlan::Beamlets {
+            beamlets: before.beamlets(),
+            weights: &prior.weights,
+        };
+        assert_eq!(transfer(&before, &beamlets).unwrap(), prior.weights);
+        let fluences = PriorPlan::Fluences(&prior.fluences);
+        assert_eq!(transfer(&before, &fluences).unwrap(), prior.weights);
+        assert!(transfer(&before, &PriorPlan::Fluences(&[])).is_err());
+
+        // Starting from the prior plan gets further than flat weights in a few iterations, and
+        // as far in the end.
+        let options = AdaptiveOptions::default();
+        let best = |h: &[f64]| h[h.len() - 1];
+        let mut quick = options;
+        quick.dao.weights.max_iterations = 3;
+        let warm = reoptimize(&after, &objectives, &fluences, &quick).unwrap();
+        let cold = fmo::optimize(&after, &objectives, &quick.dao.weights).unwrap();
+        assert!(warm.history[0] < cold.history[0]);
+        assert!(best(&warm.history) < best(&cold.history));
+        let warm = reoptimize(&after, &objectives, &fluences, &options).unwrap();
+        let cold = fmo::optimize(&after, &objectives, &options.dao.weights).unwrap();
+        assert!(best(&warm.history) <= best(&cold.history) * 1.01 + 1e-9);
+
+        let prior = dao::optimize(&before, &objectives, &DaoOptions::default()).unwrap();
+        let frozen = AdaptiveOptions {
+            freeze_apertures: true,
+            ..options
+        };
+        let adapted = reoptimize_segments(&after, &objectives, &prior.segments, &frozen).unwrap();
+        assert!(adapted.segments.len() <= prior.segments.len());
+        for s in &adapted.segments {
+            assert!(prior.segments.iter().any(|p| p.leaves == s.leaves));
+        }
+        let unfrozen = reoptimize_segments(&after, &objectives, &prior.segments, &options).unwrap();
+        assert!(best(&unfrozen.history) <= best(&adapted.history) + 1e-9);
+        // The re-optimized weights do at least as well as the prior ones on the new anatomy.
+        assert!(best(&adapted.history) <= adapted.history[0] + 1e-9);
+    }
+}
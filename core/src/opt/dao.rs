@@ -0,0 +1,642 @@
+//! Direct aperture optimization for step-and-shoot IMRT.
+//!
+//! Instead of optimizing beamlet weights and sequencing them into segments afterwards, the
+//! plan is grown aperture by aperture with column generation (Romeijn et al.): the gradient of
+//! the objectives with respect to the beamlet weights prices every deliverable aperture, the
+//! aperture of lowest price over all beams is added when it is negative, and the weights of all
+//! apertures are then re-optimized. The leaves of a bixel row open a contiguous run of bixels,
+//! so leaf positions fall on bixel edges; without interdigitation the cheapest aperture is found
+//! by dynamic programming over the rows. Segments below the minimum weight are dropped at the
+//! end and the remaining weights re-optimized.
+
+use crate::dose::delivery::{ControlPoint, Mlc};
+use crate::dose::dij::Dij;
+use crate::error::{Error, Result};
+use crate::machine::LeafLimits;
+use crate::opt::fmo::{solve, FmoOptions, FmoProblem};
+use crate::opt::objective::PlanObjectives;
+use crate::progress::Progress;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DaoOptions {
+    /// Apertures per beam.
+    pub max_apertures: usize,
+    /// Leaf rules of the machine, see [`crate::machine::MlcGeometry`]; apertures keep the
+    /// minimum gap and the interdigitation rule.
+    pub leaves: LeafLimits,
+    /// Segments below this fraction of the largest segment weight are dropped.
+    pub min_weight: f64,
+    /// Solver of the segment weights; its bounds apply to every segment weight.
+    pub weights: FmoOptions,
+}
+
+impl Default for DaoOptions {
+    fn default() -> Self {
+        DaoOptions {
+            max_apertures: 5,
+            leaves: LeafLimits::default(),
+            min_weight: 0.02,
+            weights: FmoOptions::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub beam: usize,
+    pub weight: f64,
+    /// `y` extent (mm) of every bixel row of the beam with its bank A and bank B position
+    /// (mm); closed pairs have both leaves at the same position.
+    pub leaves: Vec<([f64; 2], [f64; 2])>,
+}
+
+impl Segment {
+    fn is_open(&self) -> bool {
+        self.leaves.iter().any(|(_, x)| x[1] > x[0])
+    }
+
+    /// Bank A and bank B position of every leaf pair of `mlc`; pairs outside the bixel rows
+    /// are closed at the central axis.
+    pub fn leaves_on(&self, mlc: &Mlc) -> Vec<[f64; 2]> {
+        mlc.boundaries
+            .windows(2)
+            .map(|b| {
+                let y = 0.5 * (b[0] + b[1]);
+                self.leaves
+                    .iter()
+                    .find(|(r, _)| y >= r[0] && y < r[1])
+                    .map_or([0.0, 0.0], |(_, x)| *x)
+            })
+            .collect()
+    }
+
+    /// `[x1, x2, y1, y2]` (mm) around the open rows; empty bounds when all are closed.
+    pub fn jaws(&self) -> [f64; 4] {
+        let mut jaws = [
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ];
+        for (r, x) in self.leaves.iter().filter(|(_, x)| x[1] > x[0]) {
+            jaws = [
+                jaws[0].min(x[0]),
+                jaws[1].max(x[1]),
+                jaws[2].min(r[0]),
+                jaws[3].max(r[1]),
+            ];
+        }
+        jaws
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DaoResult {
+    /// Segments in the order they were added.
+    pub segments: Vec<Segment>,
+    /// Beamlet weights delivered by the segments.
+    pub weights: Vec<f64>,
+    /// Objective value, with the initial constraint penalty, at the starting segments, after
+    /// re-optimizing their weights when there are any, and after every added aperture.
+    pub history: Vec<f64>,
+    /// Whether no aperture could lower the objective further.
+    pub converged: bool,
+}
+
+impl DaoResult {
+    /// Summed segment weight of beam `beam`.
+    pub fn beam_weight(&self, beam: usize) -> f64 {
+        self.segments
+            .iter()
+            .filter(|s| s.beam == beam)
+            .map(|s| s.weight)
+            .sum()
+    }
+
+    /// Step-and-shoot control points of beam `beam` on `mlc`, two per segment with the
+    /// cumulative weight normalized to one and the jaws on the open rows; the meterset of the
+    /// beam is [`DaoResult::beam_weight`]. Pairs outside the bixel rows stay closed.
+    pub fn control_points(&self, beam: usize, mlc: &Mlc) -> Result<Vec<ControlPoint>> {
+        let segments: Vec<&Segment> = self.segments.iter().filter(|s| s.beam == beam).collect();
+        let total = self.beam_weight(beam);
+        if segments.is_empty() || total <= 0.0 {
+            return Err(Error::InvalidArgument(format!(
+                "no segments for beam {}",
+                beam
+            )));
+        }
+        let mut points = Vec::with_capacity(2 * segments.len());
+        let mut cumulative = 0.0;
+        for segment in segments {
+            let leaves = segment.leaves_on(mlc);
+            let jaws = segment.jaws();
+            points.push(ControlPoint {
+                weight: cumulative / total,
+                jaws: Some(jaws),
+                leaves: Some(leaves.clone()),
+            });
+            cumulative += segment.weight;
+            points.push(ControlPoint {
+                weight: cumulative / total,
+                jaws: Some(jaws),
+                leaves: Some(leaves),
+            });
+        }
+        Ok(points)
+    }
+}
+
+/// The bixels of a beam on a grid of rows and columns.
+pub(crate) struct BeamGrid {
+    pub(crate) beam: usize,
+    size: f64,
+    /// Cell of the first column and of every row.
+    x0: i64,
+    rows: Vec<i64>,
+    pub(crate) columns: usize,
+    /// Matrix column of every cell, row by row.
+    index: Vec<Option<usize>>,
+}
+
+impl BeamGrid {
+    /// The grid of every photon beam of `dij` in beam order, each covering its own bixels or,
+    /// when `shared`, all on the grid covering the bixels of every beam.
+    pub(crate) fn all(dij: &Dij, shared: bool) -> Vec<BeamGrid> {
+        let size = dij.bixel_size();
+        let cell = |v: f64| (v / size).floor() as i64;
+        let bixels: Vec<(usize, usize, i64, i64)> = dij
+            .beamlets()
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.energy.is_none())
+            .map(|(n, b)| (b.beam, n, cell(b.x), cell(b.y)))
+            .collect();
+        let extent = |bixels: &[(usize, usize, i64, i64)]| {
+            let x0 = bixels.iter().map(|b| b.2).min().unwrap();
+            let columns = (bixels.iter().map(|b| b.2).max().unwrap() - x0 + 1) as usize;
+            let mut rows: Vec<i64> = bixels.iter().map(|b| b.3).collect();
+            rows.sort_unstable();
+            rows.dedup();
+            (x0, columns, rows)
+        };
+        let common = if shared && !bixels.is_empty() {
+            Some(extent(&bixels))
+        } else {
+            None
+        };
+        let mut beams: Vec<usize> = bixels.iter().map(|b| b.0).collect();
+        beams.sort_unstable();
+        beams.dedup();
+        beams
+            .into_iter()
+            .map(|beam| {
+                let own: Vec<(usize, usize, i64, i64)> =
+                    bixels.iter().filter(|b| b.0 == beam).cloned().collect();
+                let (x0, columns, rows) = common.clone().unwrap_or_else(|| extent(&own));
+                let mut index = vec![None; rows.len() * columns];
+                for (_, n, x, y) in own {
+                    let row = rows.binary_search(&y).unwrap();
+                    index[row * columns + (x - x0) as usize] = Some(n);
+                }
+                BeamGrid {
+                    beam,
+                    size,
+                    x0,
+                    rows,
+                    columns,
+                    index,
+                }
+            })
+            .collect()
+    }
+
+    /// Matrix columns opened by the `[left, right)` column runs of every row.
+    pub(crate) fn bixels(&self, runs: &[(usize, usize)]) -> Vec<usize> {
+        runs.iter()
+            .enumerate()
+            .flat_map(|(row, (l, r))| {
+                (*l..*r).filter_map(move |c| self.index[row * self.columns + c])
+            })
+            .collect()
+    }
+
+    /// Open columns of every row under the leaves of `segment`, rounded to bixel edges.
+    pub(crate) fn runs(&self, segment: &Segment) -> Vec<(usize, usize)> {
+        let column = |x: f64| {
+            ((x / self.size).round() as i64 - self.x0).clamp(0, self.columns as i64) as usize
+        };
+        self.rows
+            .iter()
+            .map(|y| {
+                let y = (*y as f64 + 0.5) * self.size;
+                segment
+                    .leaves
+                    .iter()
+                    .find(|(r, _)| y >= r[0] && y < r[1])
+                    .map_or((0, 0), |(_, x)| {
+                        (column(x[0]), column(x[1]).max(column(x[0])))
+                    })
+            })
+            .collect()
+    }
+
+    pub(crate) fn segment(&self, runs: &[(usize, usize)], weight: f64) -> Segment {
+        let edge = |c: usize| (self.x0 + c as i64) as f64 * self.size;
+        Segment {
+            beam: self.beam,
+            weight,
+            leaves: self
+                .rows
+                .iter()
+                .zip(runs)
+                .map(|(y, (l, r))| {
+                    let y = *y as f64 * self.size;
+                    ([y, y + self.size], [edge(*l), edge(*r)])
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Cheapest aperture of `grid` for the beamlet `gradient`: its price and the `[left, right)`
+/// open columns of every row, closed rows having `left == right`. Open rows span at least
+/// `min_columns`; without interdigitation the runs of neighbouring rows overlap or touch. Only
+/// the runs `feasible` accepts for a row are considered; the price is infinite without any.
+pub(crate) fn price(
+    grid: &BeamGrid,
+    gradient: &[f64],
+    min_columns: usize,
+    interdigitation: bool,
+    feasible: &dyn Fn(usize, (usize, usize)) -> bool,
+) -> (f64, Vec<(usize, usize)>) {
+    let n = grid.columns;
+    let states: Vec<(usize, usize)> = (0..=n)
+        .flat_map(|l| (l..=n).map(move |r| (l, r)))
+        .filter(|(l, r)| l == r || r - l >= min_columns.max(1))
+        .collect();
+    let compatible = |a: (usize, usize), b: (usize, usize)| a.0 <= b.1 && b.0 <= a.1;
+    // Cheapest cost of the rows so far ending in every state, with the state it came from.
+    let mut best: Vec<f64> = Vec::new();
+    let mut from: Vec<Vec<usize>> = Vec::with_capacity(grid.rows.len());
+    for row in 0..grid.rows.len() {
+        let mut prefix = vec![0.0; n + 1];
+        for c in 0..n {
+            let g = grid.index[row * n + c].map_or(0.0, |m| gradient[m]);
+            prefix[c + 1] = prefix[c] + g;
+        }
+        let mut next = Vec::with_capacity(states.len());
+        let mut back = Vec::with_capacity(states.len());
+        for s in &states {
+            let cost = if feasible(row, *s) {
+                prefix[s.1] - prefix[s.0]
+            } else {
+                f64::INFINITY
+            };
+            if row == 0 {
+                next.push(cost);
+                back.push(0);
+                continue;
+            }
+            let mut previous = (f64::INFINITY, 0);
+            for (p, t) in states.iter().enumerate() {
+                if (interdigitation || compatible(*s, *t)) && best[p] < previous.0 {
+                    previous = (best[p], p);
+                }
+            }
+            next.push(cost + previous.0);
+            back.push(previous.1);
+        }
+        best = next;
+        from.push(back);
+    }
+    let (mut state, value) =
+        best.iter().enumerate().fold(
+            (0, f64::INFINITY),
+            |a, (s, v)| if *v < a.1 { (s, *v) } else { a },
+        );
+    let mut runs = vec![(0, 0); grid.rows.len()];
+    for row in (0..grid.rows.len()).rev() {
+        runs[row] = states[state];
+        state = from[row][state];
+    }
+    (value, runs)
+}
+
+/// Beamlet weights of the apertures, given as matrix columns, at `weights`.
+pub(crate) fn expand(apertures: &[Vec<usize>], weights: &[f64], columns: usize) -> Vec<f64> {
+    let mut bixels = vec![0.0; columns];
+    for (aperture, w) in apertures.iter().zip(weights) {
+        for c in aperture {
+            bixels[*c] += w;
+        }
+    }
+    bixels
+}
+
+/// Optimal weights of the apertures, given as matrix columns, from `weights`, with the
+/// objective value at the initial constraint penalty.
+pub(crate) fn optimize_weights(
+    problem: &FmoProblem,
+    apertures: &[Vec<usize>],
+    weights: Vec<f64>,
+    columns: usize,
+    options: &FmoOptions,
+) -> Result<(Vec<f64>, f64)> {
+    let (weights, _, _) = solve(
+        problem,
+        |w| expand(apertures, w, columns),
+        |gradient| {
+            apertures
+                .iter()
+                .map(|a| a.iter().map(|c| gradient[*c]).sum())
+                .collect()
+        },
+        weights,
+        options,
+        &Progress::new(),
+    )?;
+    let value = problem
+        .evaluate(&expand(apertures, &weights, columns), options.penalty)?
+        .0;
+    Ok((weights, value))
+}
+
+/// Optimizes the apertures and segment weights of every photon beam of `dij` for `plan`,
+/// see the module documentation.
+pub fn optimize(dij: &Dij, plan: &PlanObjectives, options: &DaoOptions) -> Result<DaoResult> {
+    optimize_from(dij, plan, &[], options)
+}
+
+/// As [`optimize`], starting from `segments` of the same beams: their leaves are rounded to the
+/// bixel edges of `dij` and their weights re-optimized before apertures are added.
+pub fn optimize_from(
+    dij: &Dij,
+    plan: &PlanObjectives,
+    segments: &[Segment],
+    options: &DaoOptions,
+) -> Result<DaoResult> {
+    if options.weights.lower < 0.0 || options.weights.lower > options.weights.upper {
+        return Err(Error::InvalidArgument(format!(
+            "invalid segment weight bounds [{}, {}]",
+            options.weights.lower, options.weights.upper
+        )));
+    }
+    if options.leaves.min_gap < 0.0 {
+        return Err(Error::InvalidArgument(format!(
+            "negative minimum leaf gap {}",
+            options.leaves.min_gap
+        )));
+    }
+    let problem = FmoProblem::new(dij, plan)?;
+    let grids = BeamGrid::all(dij, false);
+    if grids.is_empty() {
+        return Err(Error::InvalidArgument(
+            "no photon bixels to form apertures from".to_string(),
+        ));
+    }
+    let columns = dij.column_count();
+    let min_columns = (options.leaves.min_gap / dij.bixel_size() - 1e-9).ceil() as usize;
+    // Grid and open runs of every aperture with its matrix columns.
+    let mut shapes: Vec<(usize, Vec<(usize, usize)>)> = Vec::new();
+    let mut apertures: Vec<Vec<usize>> = Vec::new();
+    let mut weights: Vec<f64> = Vec::new();
+    for segment in segments {
+        let g = grids
+            .iter()
+            .position(|g| g.beam == segment.beam)
+            .ok_or_else(|| {
+                Error::InvalidArgument(format!(
+                    "segment of beam {} without photon bixels",
+                    segment.beam
+                ))
+            })?;
+        let runs = grids[g].runs(segment);
+        apertures.push(grids[g].bixels(&runs));
+        shapes.push((g, runs));
+        weights.push(segment.weight);
+    }
+    let mut history = vec![
+        problem
+            .evaluate(
+                &expand(&apertures, &weights, columns),
+                options.weights.penalty,
+            )?
+            .0,
+    ];
+    if !apertures.is_empty() {
+        let (w, v) = optimize_weights(&problem, &apertures, weights, columns, &options.weights)?;
+        weights = w;
+        history.push(v);
+    }
+    let mut converged = false;
+    loop {
+        let (value, gradient) = problem.evaluate(
+            &expand(&apertures, &weights, columns),
+            options.weights.penalty,
+        )?;
+        let candidate = grids
+            .iter()
+            .enumerate()
+            .filter(|(g, _)| shapes.iter().filter(|s| s.0 == *g).count() < options.max_apertures)
+            .map(|(g, grid)| {
+                let (p, runs) = price(
+                    grid,
+                    &gradient,
+                    min_columns,
+                    options.leaves.interdigitation,
+                    &|_, _| true,
+                );
+                (p, g, runs)
+            })
+            .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        let (p, g, runs) = match candidate {
+            Some(c) => c,
+            None => break,
+        };
+        if p >= -1e-12 * value.abs().max(1e-300) {
+            converged = true;
+            break;
+        }
+        apertures.push(grids[g].bixels(&runs));
+        shapes.push((g, runs));
+        weights.push(options.weights.lower);
+        let (w, v) = optimize_weights(&problem, &apertures, weights, columns, &options.weights)?;
+        weights = w;
+        history.push(v);
+    }
+    let largest = weights.iter().cloned().fold(0.0, f64::max);
+    let keep: Vec<bool> = weights
+        .iter()
+        .map(|w| *w > 0.0 && *w >= options.min_weight * largest)
+        .collect();
+    if keep.iter().any(|k| !k) {
+        shapes = shapes
+            .into_iter()
+            .zip(&keep)
+            .filter(|s| *s.1)
+            .map(|s| s.0)
+            .collect();
+        apertures = apertures
+            .into_iter()
+            .zip(&keep)
+            .filter(|a| *a.1)
+            .map(|a| a.0)
+            .collect();
+        weights = weights
+            .into_iter()
+            .zip(&keep)
+            .filter(|w| *w.1)
+            .map(|w| w.0)
+            .collect();
+        if !weights.is_empty() {
+            let (w, v) =
+                optimize_weights(&problem, &apertures, weights, columns, &options.weights)?;
+            weights = w;
+            history.push(v);
+        }
+    }
+    let segments = shapes
+        .iter()
+        .zip(&weights)
+        .map(|((g, runs), w)| grids[*g].segment(runs, *w))
+        .filter(Segment::is_open)
+        .collect();
+    Ok(DaoResult {
+        segments,
+        weights: expand(&apertures, &weights, columns),
+        history,
+        converged,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::beam::Fluence;
+    use crate::dose::commissioning::MlcData;
+    use crate::dose::delivery::{accumulate, fluence, Mlc};
+    use crate::dose::dij::{Beamlet, Dij};
+    use crate::grid::GridGeometry;
+    use crate::machine::LeafLimits;
+    use crate::opt::dao::{optimize, price, BeamGrid, DaoOptions};
+    use crate::opt::fmo::{self, FmoOptions};
+    use crate::opt::objective::{DoseObjective, ObjectiveKind, PlanObjectives};
+
+    /// One beam of two rows of three bixels over a row of six voxels: a target on the left
+    /// and an organ at risk on the right that only the right column reaches.
+    fn dij() -> Dij {
+        let geometry = GridGeometry::new([6, 1, 1], Vec3::new(), Vec3::from(1.0, 1.0, 1.0));
+        let beamlets = (0..6)
+            .map(|n| Beamlet {
+                beam: 0,
+                x: 5.0 * (n % 3) as f64 + 2.5,
+                y: 5.0 * (n / 3) as f64 + 2.5,
+                energy: None,
+            })
+            .collect();
+        let columns = vec![
+            vec![(0, 1.0), (1, 0.3)],
+            vec![(1, 0.8), (2, 0.4)],
+            vec![(3, 0.6), (4, 0.6), (5, 0.6)],
+            vec![(1, 0.5), (2, 0.9)],
+            vec![(0, 0.3), (2, 0.5), (3, 0.7)],
+            vec![(3, 0.2), (4, 0.8), (5, 0.4)],
+        ];
+        let structures = vec![
+            ("PTV".to_string(), vec![0, 1, 2, 3]),
+            ("OAR".to_string(), vec![4, 5]),
+        ];
+        Dij::from_columns(
+            geometry,
+            5.0,
+            beamlets,
+            (0..6).collect(),
+            structures,
+            columns,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn aperture_pricing() {
+        let dij = dij();
+        let grid = &BeamGrid::all(&dij, false)[0];
+        // The bottom row wants its left bixel, the top row its right one.
+        let gradient = [-1.0, 0.5, 0.5, 0.5, 0.5, -1.0];
+        let (p, runs) = price(grid, &gradient, 0, true, &|_, _| true);
+        assert_eq!(p, -2.0);
+        assert_eq!(runs, vec![(0, 1), (2, 3)]);
+        // Without interdigitation the runs must touch: the top row stretches to the left.
+        let (p, runs) = price(grid, &gradient, 0, false, &|_, _| true);
+        assert_eq!(p, -1.5);
+        assert_eq!(runs, vec![(0, 1), (1, 3)]);
+        // A 10 mm minimum gap opens two bixels per open row.
+        let (p, runs) = price(grid, &gradient, 2, true, &|_, _| true);
+        assert_eq!(p, -1.0);
+        assert!(runs.iter().all(|(l, r)| l == r || r - l >= 2));
+    }
+
+    #[test]
+    fn direct_aperture_optimization() {
+        let dij = dij();
+        let plan = PlanObjectives::new()
+            .with_objective(DoseObjective::new(
+                "PTV",
+                ObjectiveKind::Uniform(2.0),
+                100.0,
+            ))
+            .with_objective(DoseObjective::new("OAR", ObjectiveKind::Max(0.0), 1.0));
+        let options = DaoOptions {
+            max_apertures: 4,
+            ..DaoOptions::default()
+        };
+        let result = optimize(&dij, &plan, &options).unwrap();
+        assert!(!result.segments.is_empty() && result.segments.len() <= 4);
+        assert!(result.history.windows(2).all(|h| h[1] <= h[0] + 1e-9));
+        let fmo = fmo::optimize(&dij, &plan, &FmoOptions::default()).unwrap();
+        let best = fmo.history[fmo.history.len() - 1];
+        let reached = result.history[result.history.len() - 1];
+        assert!(reached < 0.05 * result.history[0]);
+        assert!(reached >= best - 1e-9);
+        let largest = result.segments.iter().map(|s| s.weight).fold(0.0, f64::max);
+        assert!(result.segments.iter().all(|s| s.weight >= 0.02 * largest));
+
+        // The control points deliver the segment fluence on the bixels.
+        let mlc = Mlc::uniform(
+            4,
+            5.0,
+            MlcData {
+                transmission: 0.0,
+                dlg: 0.0,
+            },
+        );
+        let points = result.control_points(0, &mlc).unwrap();
+        assert_eq!(points.len(), 2 * result.segments.len());
+        assert_eq!(points[points.len() - 1].weight, 1.0);
+        let mut delivered = Fluence::new([2.5, 2.5], [5.0, 5.0], [3, 2], 0.0);
+        accumulate(&mut delivered, &mlc, &points, result.beam_weight(0)).unwrap();
+        for (n, w) in result.weights.iter().enumerate() {
+            assert!((delivered.get(n % 3, n / 3) - w).abs() < 1e-9, "{}", n);
+        }
+        assert!(fluence(&mlc, &points, 1.0, 1.0).is_ok());
+        assert!(result.control_points(1, &mlc).is_err());
+
+        let constrained = DaoOptions {
+            leaves: LeafLimits {
+                min_gap: 10.0,
+                interdigitation: false,
+                ..LeafLimits::default()
+            },
+            ..options
+        };
+        let result = optimize(&dij, &plan, &constrained).unwrap();
+        for s in &result.segments {
+            for (_, x) in &s.leaves {
+                assert!(x[1] == x[0] || x[1] - x[0] >= 10.0 - 1e-9);
+            }
+            for pair in s.leaves.windows(2) {
+                assert!(pair[0].1[0] <= pair[1].1[1] && pair[1].1[0] <= pair[0].1[1]);
+            }
+        }
+    }
+}
@@ -0,0 +1,621 @@
+//! Fluence map optimization for IMRT.
+//!
+//! The beamlet weights of a [`Dij`] matrix minimize the sum of the objectives of a
+//! [`PlanObjectives`] within bounds. The solver is a projected L-BFGS: the quasi-Newton
+//! direction is computed on the weights away from their bounds, projected back into the box and
+//! accepted along an Armijo backtracking line search; a direction that does not descend is
+//! replaced by the negative gradient. The curvature memory is only updated for pairs with
+//! positive curvature. Constraints enter as a quadratic penalty on their violation whose weight
+//! grows tenfold, restarting from the last solution, until they hold.
+
+use crate::dose::beam::Fluence;
+use crate::dose::dij::Dij;
+use crate::error::{Error, Result};
+use crate::opt::objective::{rows, PlanObjectives};
+use crate::progress::Progress;
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FmoOptions {
+    pub max_iterations: usize,
+    /// Converged when the objective changes less than this, relative.
+    pub tolerance: f64,
+    /// Curvature pairs kept by L-BFGS.
+    pub memory: usize,
+    /// Bounds of every beamlet weight.
+    pub lower: f64,
+    pub upper: f64,
+    /// Starting weight of every beamlet.
+    pub initial: f64,
+    /// Weight of the squared constraint violations in the first solve.
+    pub penalty: f64,
+    /// Solves with a tenfold penalty after the first while a constraint is violated.
+    pub penalty_steps: usize,
+    /// Largest constraint value taken as met.
+    pub constraint_tolerance: f64,
+}
+
+impl Default for FmoOptions {
+    fn default() -> Self {
+        FmoOptions {
+            max_iterations: 200,
+            tolerance: 1e-7,
+            memory: 8,
+            lower: 0.0,
+            upper: f64::INFINITY,
+            initial: 1.0,
+            penalty: 1e3,
+            penalty_steps: 4,
+            constraint_tolerance: 1e-3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FmoResult {
+    pub weights: Vec<f64>,
+    /// Optimized fluence map of every beam with photon bixels, in beam order.
+    pub fluences: Vec<Fluence>,
+    /// Penalized objective value before the first and after every iteration of every solve.
+    pub history: Vec<f64>,
+    pub converged: bool,
+}
+
+/// The objectives and constraints of a plan over the beamlet weights of a matrix.
+pub struct FmoProblem<'a> {
+    dij: &'a Dij,
+    plan: &'a PlanObjectives,
+    objective_rows: Vec<&'a [u32]>,
+    constraint_rows: Vec<&'a [u32]>,
+}
+
+impl<'a> FmoProblem<'a> {
+    pub fn new(dij: &'a Dij, plan: &'a PlanObjectives) -> Result<Self> {
+        let objective_rows = plan
+            .objectives
+            .iter()
+            .map(|o| rows(dij, o.structure()))
+            .collect::<Result<Vec<_>>>()?;
+        let constraint_rows = plan
+            .constraints
+            .iter()
+            .map(|c| rows(dij, c.structure()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(FmoProblem {
+            dij,
+            plan,
+            objective_rows,
+            constraint_rows,
+        })
+    }
+
+    /// Objective value plus `penalty` times the squared constraint violations, and its
+    /// gradient with respect to the beamlet `weights`.
+    pub fn evaluate(&self, weights: &[f64], penalty: f64) -> Result<(f64, Vec<f64>)> {
+        self.evaluate_scaled(weights, None, penalty)
+    }
+
+    /// As [`FmoProblem::evaluate`], with every objective multiplied by its `factors` entry.
+    pub(crate) fn evaluate_scaled(
+        &self,
+        weights: &[f64],
+        factors: Option<&[f64]>,
+        penalty: f64,
+    ) -> Result<(f64, Vec<f64>)> {
+        let dose = self.dij.dose(weights)?;
+        let mut gradient = vec![0.0; dose.len()];
+        let mut value = 0.0;
+        let mut own = vec![0.0; dose.len()];
+        for (n, (o, rows)) in self
+            .plan
+            .objectives
+            .iter()
+            .zip(&self.objective_rows)
+            .enumerate()
+        {
+            match factors {
+                Some(f) => {
+                    own.iter_mut().for_each(|g| *g = 0.0);
+                    value += f[n] * o.evaluate(rows, &dose, &mut own);
+                    for (g, v) in gradient.iter_mut().zip(&own) {
+                        *g += f[n] * v;
+                    }
+                }
+                None => value += o.evaluate(rows, &dose, &mut gradient),
+            }
+        }
+        for (c, rows) in self.plan.constraints.iter().zip(&self.constraint_rows) {
+            let mut own = vec![0.0; dose.len()];
+            let violation = c.evaluate(rows, &dose, &mut own);
+            if violation > 0.0 {
+                value += penalty * violation * violation;
+                for (g, o) in gradient.iter_mut().zip(&own) {
+                    *g += 2.0 * penalty * violation * o;
+                }
+            }
+        }
+        Ok((value, self.dij.transpose_dose(&gradient)?))
+    }
+
+    /// Value of every objective at the beamlet `weights`.
+    pub fn objectives(&self, weights: &[f64]) -> Result<Vec<f64>> {
+        let dose = self.dij.dose(weights)?;
+        let mut scratch = vec![0.0; dose.len()];
+        Ok(self
+            .plan
+            .objectives
+            .iter()
+            .zip(&self.objective_rows)
+            .map(|(o, rows)| o.evaluate(rows, &dose, &mut scratch))
+            .collect())
+    }
+
+    /// Value of every constraint at the beamlet `weights`.
+    pub fn constraints(&self, weights: &[f64]) -> Result<Vec<f64>> {
+        let dose = self.dij.dose(weights)?;
+        let mut scratch = vec![0.0; dose.len()];
+        Ok(self
+            .plan
+            .constraints
+            .iter()
+            .zip(&self.constraint_rows)
+            .map(|(c, rows)| c.evaluate(rows, &dose, &mut scratch))
+            .collect())
+    }
+}
+
+/// A penalized objective over beamlet weights, as minimized by [`solve`].
+pub(crate) trait Penalized {
+    /// Value with `penalty` times the squared constraint violations, and its gradient.
+    fn evaluate(&self, weights: &[f64], penalty: f64) -> Result<(f64, Vec<f64>)>;
+    /// Value of every constraint.
+    fn constraints(&self, weights: &[f64]) -> Result<Vec<f64>>;
+}
+
+impl<'a> Penalized for FmoProblem<'a> {
+    fn evaluate(&self, weights: &[f64], penalty: f64) -> Result<(f64, Vec<f64>)> {
+        FmoProblem::evaluate(self, weights, penalty)
+    }
+
+    fn constraints(&self, weights: &[f64]) -> Result<Vec<f64>> {
+        FmoProblem::constraints(self, weights)
+    }
+}
+
+fn project(x: &mut [f64], lower: f64, upper: f64) {
+    for v in x {
+        *v = v.clamp(lower, upper);
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// L-BFGS two-loop recursion: minus the inverse Hessian estimate times `gradient`.
+fn direction(gradient: &[f64], memory: &VecDeque<(Vec<f64>, Vec<f64>)>) -> Vec<f64> {
+    let mut q = gradient.to_vec();
+    let mut alphas = Vec::with_capacity(memory.len());
+    for (s, y) in memory.iter().rev() {
+        let alpha = dot(s, &q) / dot(y, s);
+        for (qi, yi) in q.iter_mut().zip(y) {
+            *qi -= alpha * yi;
+        }
+        alphas.push(alpha);
+    }
+    if let Some((s, y)) = memory.back() {
+        let gamma = dot(s, y) / dot(y, y);
+        for qi in q.iter_mut() {
+            *qi *= gamma;
+        }
+    }
+    for ((s, y), alpha) in memory.iter().zip(alphas.iter().rev()) {
+        let beta = dot(y, &q) / dot(y, s);
+        for (qi, si) in q.iter_mut().zip(s) {
+            *qi += (alpha - beta) * si;
+        }
+    }
+    q.iter().map(|v| -v).collect()
+}
+
+/// Minimizes `f`, returning its value and gradient, over the box of `options` from `x`;
+/// returns the minimizer, the objective history and whether it converged.
+fn minimize<F>(
+    f: F,
+    x: Vec<f64>,
+    options: &FmoOptions,
+    progress: &Progress,
+) -> Result<(Vec<f64>, Vec<f64>, bool)>
+where
+    F: Fn(&[f64]) -> Result<(f64, Vec<f64>)>,
+{
+    let (lower, upper) = (options.lower, options.upper);
+    let mut x = x;
+    project(&mut x, lower, upper);
+    let (mut value, mut gradient) = f(&x)?;
+    let mut history = vec![value];
+    let mut memory: VecDeque<(Vec<f64>, Vec<f64>)> = VecDeque::new();
+    let mut converged = false;
+    for iteration in 0..options.max_iterations {
+        progress.update(iteration as f64 / options.max_iterations as f64)?;
+        // Weights at a bound the gradient pushes against stay put.
+        let free: Vec<bool> = x
+            .iter()
+            .zip(&gradient)
+            .map(|(v, g)| !((*v <= lower && *g > 0.0) || (*v >= upper && *g < 0.0)))
+            .collect();
+        let masked: Vec<f64> = gradient
+            .iter()
+            .zip(&free)
+            .map(|(g, f)| if *f { *g } else { 0.0 })
+            .collect();
+        if masked.iter().all(|g| *g == 0.0) {
+            converged = true;
+            break;
+        }
+        let mut d = direction(&masked, &memory);
+        for (di, f) in d.iter_mut().zip(&free) {
+            if !f {
+                *di = 0.0;
+            }
+        }
+        if dot(&d, &masked) >= 0.0 {
+            d = masked.iter().map(|g| -g).collect();
+            memory.clear();
+        }
+        let mut step = 1.0;
+        let mut accepted = None;
+        for _ in 0..40 {
+            let mut candidate: Vec<f64> = x.iter().zip(&d).map(|(v, di)| v + step * di).collect();
+            project(&mut candidate, lower, upper);
+            let moved: Vec<f64> = candidate.iter().zip(&x).map(|(c, v)| c - v).collect();
+            let (v, g) = f(&candidate)?;
+            if v <= value + 1e-4 * dot(&gradient, &moved) {
+                accepted = Some((candidate, moved, v, g));
+                break;
+            }
+            step *= 0.5;
+        }
+        let (candidate, s, v, g) = match accepted {
+            Some(a) => a,
+            None => {
+                converged = true;
+                break;
+            }
+        };
+        let y: Vec<f64> = g.iter().zip(&gradient).map(|(a, b)| a - b).collect();
+        if dot(&s, &y) > 1e-12 * dot(&y, &y).sqrt() * dot(&s, &s).sqrt() {
+            memory.push_back((s, y));
+            if memory.len() > options.memory {
+                memory.pop_front();
+            }
+        }
+        let change = (value - v).abs();
+        x = candidate;
+        value = v;
+        gradient = g;
+        history.push(value);
+        if change <= options.tolerance * value.abs().max(1e-300) {
+            converged = true;
+            break;
+        }
+    }
+    Ok((x, history, converged))
+}
+
+/// Minimizes `problem` over the variables `x` of beamlet weights `beamlets(x)`, whose
+/// gradient `chain` maps back from the beamlets to the variables, raising the penalty while a
+/// constraint is violated; returns the minimizer, the objective history and whether the last
+/// solve converged. Every penalty step reports to its share of `progress`.
+pub(crate) fn solve<B, C>(
+    problem: &dyn Penalized,
+    beamlets: B,
+    chain: C,
+    x: Vec<f64>,
+    options: &FmoOptions,
+    progress: &Progress,
+) -> Result<(Vec<f64>, Vec<f64>, bool)>
+where
+    B: Fn(&[f64]) -> Vec<f64>,
+    C: Fn(&[f64]) -> Vec<f64>,
+{
+    let mut penalty = options.penalty;
+    let mut x = x;
+    let mut history = Vec::new();
+    let mut converged = false;
+    let steps = (options.penalty_steps + 1) as f64;
+    for step in 0..=options.penalty_steps {
+        let stage = progress.stage(step as f64 / steps, (step + 1) as f64 / steps);
+        let (next, values, done) = minimize(
+            |x| {
+                let (value, gradient) = problem.evaluate(&beamlets(x), penalty)?;
+                Ok((value, chain(&gradient)))
+            },
+            x,
+            options,
+            &stage,
+        )?;
+        x = next;
+        history.extend(values);
+        converged = done;
+        if step == options.penalty_steps
+            || problem
+                .constraints(&beamlets(&x))?
+                .iter()
+                .all(|c| *c <= options.constraint_tolerance)
+        {
+            break;
+        }
+        penalty *= 10.0;
+    }
+    progress.report(1.0);
+    Ok((x, history, converged))
+}
+
+/// Minimizes `plan` over the beamlet weights of `dij`, see the module documentation.
+pub fn optimize(dij: &Dij, plan: &PlanObjectives, options: &FmoOptions) -> Result<FmoResult> {
+    let initial = vec![options.initial; dij.column_count()];
+    optimize_from(dij, plan, initial, options)
+}
+
+/// As [`optimize`], starting from `weights`.
+pub fn optimize_from(
+    dij: &Dij,
+    plan: &PlanObjectives,
+    weights: Vec<f64>,
+    options: &FmoOptions,
+) -> Result<FmoResult> {
+    optimize_with_progress(dij, plan, weights, options, &Progress::new())
+}
+
+/// As [`optimize_from`], reporting its progress per iteration to `progress`.
+pub fn optimize_with_progress(
+    dij: &Dij,
+    plan: &PlanObjectives,
+    weights: Vec<f64>,
+    options: &FmoOptions,
+    progress: &Progress,
+) -> Result<FmoResult> {
+    check(options)?;
+    let problem = FmoProblem::new(dij, plan)?;
+    let (x, history, converged) = solve(
+        &problem,
+        |w| w.to_vec(),
+        |g| g.to_vec(),
+        weights,
+        options,
+        progress,
+    )?;
+    let fluences = fluences(dij, &x)?;
+    Ok(FmoResult {
+        weights: x,
+        fluences,
+        history,
+        converged,
+    })
+}
+
+pub(crate) fn check(options: &FmoOptions) -> Result<()> {
+    if options.lower > options.upper {
+        return Err(Error::InvalidArgument(format!(
+            "empty weight bounds [{}, {}]",
+            options.lower, options.upper
+        )));
+    }
+    Ok(())
+}
+
+/// Fluence map of every beam of `dij` with photon bixels at the beamlet `weights`.
+pub(crate) fn fluences(dij: &Dij, weights: &[f64]) -> Result<Vec<Fluence>> {
+    let mut beams: Vec<usize> = dij
+        .beamlets()
+        .iter()
+        .filter(|b| b.energy.is_none())
+        .map(|b| b.beam)
+        .collect();
+    beams.dedup();
+    beams.iter().map(|b| dij.fluence(*b, weights)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::dij::{Beamlet, Dij};
+    use crate::error::Error;
+    use crate::grid::GridGeometry;
+    use crate::opt::fmo::{
+        optimize, optimize_from, optimize_with_progress, FmoOptions, FmoProblem,
+    };
+    use crate::opt::objective::{DoseObjective, Limit, MeanDose, ObjectiveKind, PlanObjectives};
+    use crate::progress::{CancellationToken, Progress};
+
+    /// Two beams of three bixels over a row of six voxels: a target in the middle and an
+    /// organ at risk on the right that the left bixel of beam 1 crosses.
+    fn dij() -> Dij {
+        let geometry = GridGeometry::new([6, 1, 1], Vec3::new(), Vec3::from(1.0, 1.0, 1.0));
+        let beamlets = (0..6)
+            .map(|n| Beamlet {
+                beam: n / 3,
+                x: 5.0 * (n % 3) as f64,
+                y: 0.0,
+                energy: None,
+            })
+            .collect();
+        let columns = vec![
+            vec![(0, 1.0), (1, 0.2)],
+            vec![(1, 0.5), (2, 0.5), (3, 0.1)],
+            vec![(3, 0.5), (4, 0.2)],
+            vec![(2, 0.5), (5, 0.8)],
+            vec![(1, 0.4), (2, 0.3), (3, 0.4)],
+            vec![(0, 0.6), (1, 0.2)],
+        ];
+        let structures = vec![
+            ("PTV".to_string(), vec![1, 2, 3]),
+            ("OAR".to_string(), vec![4, 5]),
+        ];
+        Dij::from_columns(
+            geometry,
+            5.0,
+            beamlets,
+            (0..6).collect(),
+            structures,
+            columns,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn fluence_map_optimization() {
+        let dij = dij();
+        let target = DoseObjective::new("PTV", ObjectiveKind::Uniform(2.0), 100.0);
+        let plan = PlanObjectives::new()
+            .with_objective(target.clone())
+            .with_objective(DoseObjective::new("OAR", ObjectiveKind::Max(0.0), 1.0));
+        let result = optimize(&dij, &plan, &FmoOptions::default()).unwrap();
+        assert!(result.converged);
+        assert!(result.history.windows(2).all(|h| h[1] <= h[0] + 1e-12));
+        assert!(result.history.last().unwrap() < &(0.01 * result.history[0]));
+        assert!(result.weights.iter().all(|w| *w >= 0.0));
+        let dose = dij.dose(&result.weights).unwrap();
+        for d in &dose[1..4] {
+            assert!((d - 2.0).abs() < 0.1, "{:?}", dose);
+        }
+        // Without the organ at risk objective the bixel through it is used more.
+        let target_only = PlanObjectives::new().with_objective(target.clone());
+        let target_only = optimize(&dij, &target_only, &FmoOptions::default()).unwrap();
+        assert!(result.weights[3] < 0.5 * target_only.weights[3]);
+        assert_eq!(result.fluences.len(), 2);
+        assert_eq!(result.fluences[1].dims, [3, 1]);
+        assert_eq!(result.fluences[1].values[0], result.weights[3]);
+
+        // A mean dose constraint holds where the objective only trades off.
+        let constrained = PlanObjectives::new()
+            .with_objective(target)
+            .with_constraint(MeanDose::new("OAR", Limit::Upper(0.1), 1.0));
+        let options = FmoOptions::default();
+        let result = optimize(&dij, &constrained, &options).unwrap();
+        let dose = dij.dose(&result.weights).unwrap();
+        assert!(0.5 * (dose[4] + dose[5]) <= 0.1 + options.constraint_tolerance);
+        let unconstrained = dij.dose(&target_only.weights).unwrap();
+        assert!(0.5 * (unconstrained[4] + unconstrained[5]) > 0.2);
+
+        let bounded = FmoOptions {
+            upper: 1.0,
+            ..FmoOptions::default()
+        };
+        let capped = optimize(&dij, &plan, &bounded).unwrap();
+        assert!(capped.weights.iter().all(|w| *w <= 1.0));
+        let missing = PlanObjectives::new().with_constraint(DoseObjective::new(
+            "Lung",
+            ObjectiveKind::Max(1.0),
+            1.0,
+        ));
+        assert!(optimize(&dij, &missing, &FmoOptions::default()).is_err());
+    }
+
+    fn plan() -> PlanObjectives {
+        PlanObjectives::new()
+            .with_objective(DoseObjective::new(
+                "PTV",
+                ObjectiveKind::Uniform(2.0),
+                100.0,
+            ))
+            .with_objective(DoseObjective::new("OAR", ObjectiveKind::Max(0.0), 1.0))
+            .with_constraint(MeanDose::new("OAR", Limit::Upper(0.1), 1.0))
+    }
+
+    #[test]
+    fn fmo_problem_evaluation() {
+        let (dij, plan) = (dij(), plan());
+        let problem = FmoProblem::new(&dij, &plan).unwrap();
+        let weights = [0.5, 1.0, 0.2, 0.8, 0.3, 0.6];
+        let objectives = problem.objectives(&weights).unwrap();
+        let constraints = problem.constraints(&weights).unwrap();
+        assert_eq!((objectives.len(), constraints.len()), (2, 1));
+        assert!(constraints[0] > 0.0);
+        // Without a penalty the value is the sum of the objectives.
+        let (value, _) = problem.evaluate(&weights, 0.0).unwrap();
+        assert!((value - objectives.iter().sum::<f64>()).abs() < 1e-12);
+        let (penalized, gradient) = problem.evaluate(&weights, 10.0).unwrap();
+        assert!((penalized - value - 10.0 * constraints[0].powi(2)).abs() < 1e-9);
+        // The gradient matches central differences.
+        let h = 1e-6;
+        for (n, g) in gradient.iter().enumerate() {
+            let (mut up, mut down) = (weights, weights);
+            up[n] += h;
+            down[n] -= h;
+            let numeric = (problem.evaluate(&up, 10.0).unwrap().0
+                - problem.evaluate(&down, 10.0).unwrap().0)
+                / (2.0 * h);
+            assert!((numeric - g).abs() < 1e-4 * numeric.abs().max(1.0), "{}", n);
+        }
+        assert!(problem.evaluate(&weights[..5], 0.0).is_err());
+        assert!(problem.objectives(&[]).is_err());
+        assert!(problem.constraints(&[1.0; 7]).is_err());
+    }
+
+    #[test]
+    fn fmo_arguments() {
+        let (dij, plan) = (dij(), plan());
+        let empty = FmoOptions {
+            lower: 1.0,
+            upper: 0.5,
+            ..FmoOptions::default()
+        };
+        assert!(optimize(&dij, &plan, &empty).is_err());
+        assert!(optimize_from(&dij, &plan, vec![1.0; 5], &FmoOptions::default()).is_err());
+        let unknown = PlanObjectives::new().with_objective(DoseObjective::new(
+            "Lung",
+            ObjectiveKind::Max(1.0),
+            1.0,
+        ));
+        assert!(FmoProblem::new(&dij, &unknown).is_err());
+        assert!(optimize(&dij, &unknown, &FmoOptions::default()).is_err());
+    }
+
+    #[test]
+    fn fmo_from_weights() {
+        let (dij, plan) = (dij(), plan());
+        let options = FmoOptions::default();
+        let first = optimize(&dij, &plan, &options).unwrap();
+        // Restarting from the optimum changes next to nothing.
+        let restarted = optimize_from(&dij, &plan, first.weights.clone(), &options).unwrap();
+        let problem = FmoProblem::new(&dij, &plan).unwrap();
+        let total = |w: &[f64]| problem.objectives(w).unwrap().iter().sum::<f64>();
+        let optimum = total(&first.weights);
+        assert!((total(&restarted.weights) - optimum).abs() <= 1e-3 * optimum.max(1e-9));
+        // Starting weights outside the bounds are projected into them.
+        let bounded = FmoOptions {
+            upper: 0.5,
+            max_iterations: 0,
+            penalty_steps: 0,
+            ..options
+        };
+        let result = optimize_from(&dij, &plan, vec![2.0; 6], &bounded).unwrap();
+        assert_eq!(result.weights, vec![0.5; 6]);
+        assert_eq!(result.history.len(), 1);
+        assert!(!result.converged);
+    }
+
+    #[test]
+    fn fmo_progress() {
+        let (dij, plan) = (dij(), plan());
+        let (progress, updates) = Progress::channel();
+        optimize_with_progress(&dij, &plan, vec![1.0; 6], &FmoOptions::default(), &progress)
+            .unwrap();
+        let reported: Vec<f64> = updates.try_iter().collect();
+        assert!(reported.len() > 1);
+        assert_eq!(reported[reported.len() - 1], 1.0);
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let cancelled = optimize_with_progress(
+            &dij,
+            &plan,
+            vec![1.0; 6],
+            &FmoOptions::default(),
+            &Progress::new().with_token(token),
+        );
+        assert!(matches!(cancelled, Err(Error::Cancelled)));
+    }
+}
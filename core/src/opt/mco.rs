@@ -0,0 +1,614 @@
+//! Multi-criteria optimization by Pareto surface navigation.
+//!
+//! Every objective of a [`PlanObjectives`] is a criterion; the constraints hold in every plan.
+//! Pareto-optimal plans minimize weighted sums of the criteria with [`crate::opt::fmo`]. The
+//! anchor plan of a criterion weights the others by a small fraction only, so that it is
+//! Pareto-optimal rather than weakly so. The anchors bound the criteria between the ideal
+//! point, every criterion at its anchor, and the nadir point, the worst value of every
+//! criterion over the anchors; the balanced plans weight the criteria by the inverse of that
+//! range, all of them equally and, optionally, every pair of them.
+//!
+//! Navigation interpolates the beamlet weights of the database plans with convex
+//! coefficients. The dose is linear in the weights, so the interpolated dose is the same
+//! combination of the plan doses, and for convex criteria the combination of the plan values is
+//! an upper bound on the interpolated value. Sliding a criterion moves the coefficients towards
+//! the plan with the best (or worst) value of that criterion until the bound reaches the
+//! requested value.
+//!
+//! A database is stored in a binary file: the magic `PLTMCODB`, the number of criteria,
+//! plans and beamlets as `u64`, then the factors, criterion values and beamlet weights of every
+//! plan as `f64`, all little-endian.
+
+use crate::dose::beam::Fluence;
+use crate::dose::dij::Dij;
+use crate::error::{Error, Result};
+use crate::opt::fmo::{check, fluences, solve, FmoOptions, FmoProblem, Penalized};
+use crate::opt::objective::PlanObjectives;
+use crate::progress::Progress;
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"PLTMCODB";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct McoOptions {
+    /// Weight of the other criteria in an anchor plan.
+    pub anchor_weight: f64,
+    /// Whether to add a balanced plan for every pair of criteria.
+    pub pairs: bool,
+    pub weights: FmoOptions,
+}
+
+impl Default for McoOptions {
+    fn default() -> Self {
+        McoOptions {
+            anchor_weight: 1e-3,
+            pairs: true,
+            weights: FmoOptions::default(),
+        }
+    }
+}
+
+/// A Pareto-optimal plan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParetoPlan {
+    /// Factor of every criterion in the weighted sum the plan minimizes.
+    pub factors: Vec<f64>,
+    /// Value of every criterion.
+    pub values: Vec<f64>,
+    /// Beamlet weights.
+    pub weights: Vec<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParetoDatabase {
+    /// The anchor plans in criterion order, then the balanced plans.
+    pub plans: Vec<ParetoPlan>,
+}
+
+struct Weighted<'a> {
+    problem: &'a FmoProblem<'a>,
+    factors: &'a [f64],
+}
+
+impl<'a> Penalized for Weighted<'a> {
+    fn evaluate(&self, weights: &[f64], penalty: f64) -> Result<(f64, Vec<f64>)> {
+        self.problem
+            .evaluate_scaled(weights, Some(self.factors), penalty)
+    }
+
+    fn constraints(&self, weights: &[f64]) -> Result<Vec<f64>> {
+        self.problem.constraints(weights)
+    }
+}
+
+fn pareto_plan(
+    problem: &FmoProblem,
+    beamlets: usize,
+    factors: Vec<f64>,
+    options: &FmoOptions,
+) -> Result<ParetoPlan> {
+    let weighted = Weighted {
+        problem,
+        factors: &factors,
+    };
+    let initial = vec![options.initial; beamlets];
+    let (weights, _, _) = solve(
+        &weighted,
+        |w| w.to_vec(),
+        |g| g.to_vec(),
+        initial,
+        options,
+        &Progress::new(),
+    )?;
+    Ok(ParetoPlan {
+        values: problem.objectives(&weights)?,
+        factors,
+        weights,
+    })
+}
+
+/// Anchor and balanced plans of the objectives of `plan`, see the module documentation.
+pub fn generate(dij: &Dij, plan: &PlanObjectives, options: &McoOptions) -> Result<ParetoDatabase> {
+    check(&options.weights)?;
+    let n = plan.objectives.len();
+    if n < 2 {
+        return Err(Error::InvalidArgument(format!(
+            "multi-criteria optimization needs two criteria or more, got {}",
+            n
+        )));
+    }
+    if !(options.anchor_weight > 0.0 && options.anchor_weight < 1.0) {
+        return Err(Error::InvalidArgument(format!(
+            "anchor weight {} outside (0, 1)",
+            options.anchor_weight
+        )));
+    }
+    let problem = FmoProblem::new(dij, plan)?;
+    let plans = (0..n)
+        .map(|i| {
+            let factors = (0..n)
+                .map(|j| if i == j { 1.0 } else { options.anchor_weight })
+                .collect();
+            pareto_plan(&problem, dij.column_count(), factors, &options.weights)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let anchors = ParetoDatabase { plans };
+    let (ideal, nadir) = (anchors.ideal(), anchors.nadir());
+    let scale: Vec<f64> = ideal
+        .iter()
+        .zip(&nadir)
+        .map(|(i, n)| 1.0 / (n - i).max(1e-12 * n.abs().max(1.0)))
+        .collect();
+    let mut balanced = vec![vec![true; n]];
+    if options.pairs && n > 2 {
+        for i in 0..n {
+            for j in i + 1..n {
+                balanced.push((0..n).map(|k| k == i || k == j).collect());
+            }
+        }
+    }
+    let mut plans = anchors.plans;
+    for chosen in balanced {
+        let factors = chosen
+            .iter()
+            .zip(&scale)
+            .map(|(c, s)| if *c { *s } else { options.anchor_weight * s })
+            .collect();
+        plans.push(pareto_plan(
+            &problem,
+            dij.column_count(),
+            factors,
+            &options.weights,
+        )?);
+    }
+    Ok(ParetoDatabase { plans })
+}
+
+impl ParetoDatabase {
+    pub fn criteria(&self) -> usize {
+        self.plans.first().map_or(0, |p| p.values.len())
+    }
+
+    /// Best value of every criterion over the plans.
+    pub fn ideal(&self) -> Vec<f64> {
+        (0..self.criteria())
+            .map(|c| {
+                self.plans
+                    .iter()
+                    .map(|p| p.values[c])
+                    .fold(f64::INFINITY, f64::min)
+            })
+            .collect()
+    }
+
+    /// Worst value of every criterion over the anchor plans.
+    pub fn nadir(&self) -> Vec<f64> {
+        let n = self.criteria();
+        (0..n)
+            .map(|c| {
+                self.plans[..n.min(self.plans.len())]
+                    .iter()
+                    .map(|p| p.values[c])
+                    .fold(f64::NEG_INFINITY, f64::max)
+            })
+            .collect()
+    }
+
+    /// A navigator at the balanced combination of all plans.
+    pub fn navigator(&self) -> Navigator<'_> {
+        let n = self.plans.len();
+        Navigator {
+            database: self,
+            coefficients: vec![1.0 / n as f64; n],
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let criteria = self.criteria();
+        let beamlets = self.plans.first().map_or(0, |p| p.weights.len());
+        let mut bytes = Vec::with_capacity(32 + 8 * self.plans.len() * (2 * criteria + beamlets));
+        bytes.extend_from_slice(MAGIC);
+        for n in [criteria, self.plans.len(), beamlets] {
+            bytes.extend_from_slice(&(n as u64).to_le_bytes());
+        }
+        for p in &self.plans {
+            for v in p.factors.iter().chain(&p.values).chain(&p.weights) {
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        bytes
+    }
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Result<ParetoDatabase> {
+    if bytes.len() < 32 || &bytes[..8] != MAGIC {
+        return Err(Error::Format("not a Pareto plan database".to_string()));
+    }
+    let word = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap()) as usize;
+    let (criteria, plans, beamlets) = (word(8), word(16), word(24));
+    let stride = criteria
+        .checked_mul(2)
+        .and_then(|n| n.checked_add(beamlets))
+        .and_then(|n| n.checked_mul(8));
+    let len = stride.and_then(|s| s.checked_mul(plans));
+    let (stride, len) = match (stride, len) {
+        (Some(s), Some(l)) if bytes.len() - 32 == l => (s, l),
+        _ => return Err(Error::Format("truncated Pareto plan database".to_string())),
+    };
+    let plans = bytes[32..32 + len]
+        .chunks_exact(stride.max(1))
+        .take(plans)
+        .map(|plan| {
+            let values: Vec<f64> = plan
+                .chunks_exact(8)
+                .map(|c| f64::from_le_bytes(c.try_into().unwrap()))
+                .collect();
+            ParetoPlan {
+                factors: values[..criteria].to_vec(),
+                values: values[criteria..2 * criteria].to_vec(),
+                weights: values[2 * criteria..].to_vec(),
+            }
+        })
+        .collect();
+    Ok(ParetoDatabase { plans })
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> Result<ParetoDatabase> {
+    from_bytes(&fs::read(path)?)
+}
+
+pub fn write<P: AsRef<Path>>(path: P, database: &ParetoDatabase) -> Result<()> {
+    fs::write(path, database.to_bytes())?;
+    Ok(())
+}
+
+/// A convex combination of the plans of a database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Navigator<'a> {
+    database: &'a ParetoDatabase,
+    coefficients: Vec<f64>,
+}
+
+impl<'a> Navigator<'a> {
+    pub fn coefficients(&self) -> &[f64] {
+        &self.coefficients
+    }
+
+    /// Sets the coefficient of every plan, normalized to sum to one.
+    pub fn set_coefficients(&mut self, coefficients: &[f64]) -> Result<()> {
+        let total: f64 = coefficients.iter().sum();
+        if coefficients.len() != self.database.plans.len()
+            || coefficients.iter().any(|c| c.is_nan() || *c < 0.0)
+            || total <= 0.0
+        {
+            return Err(Error::InvalidArgument(format!(
+                "invalid coefficients {:?} for {} plans",
+                coefficients,
+                self.database.plans.len()
+            )));
+        }
+        self.coefficients = coefficients.iter().map(|c| c / total).collect();
+        Ok(())
+    }
+
+    /// Combination of the criterion values of the plans; an upper bound on the values of the
+    /// interpolated plan for convex criteria.
+    pub fn values(&self) -> Vec<f64> {
+        (0..self.database.criteria())
+            .map(|c| {
+                self.database
+                    .plans
+                    .iter()
+                    .zip(&self.coefficients)
+                    .map(|(p, l)| l * p.values[c])
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Moves the coefficients towards the plan with the best value of `criterion`, or the
+    /// worst to raise it, until its bound is `value` or that plan is reached; returns the
+    /// bound.
+    pub fn slide(&mut self, criterion: usize, value: f64) -> Result<f64> {
+        if criterion >= self.database.criteria() {
+            return Err(Error::InvalidArgument(format!(
+                "criterion {} of {}",
+                criterion,
+                self.database.criteria()
+            )));
+        }
+        let current = self.values()[criterion];
+        let plans = &self.database.plans;
+        let order =
+            |a: &&ParetoPlan, b: &&ParetoPlan| a.values[criterion].total_cmp(&b.values[criterion]);
+        let toward = if value < current {
+            plans.iter().enumerate().min_by(|a, b| order(&a.1, &b.1))
+        } else {
+            plans.iter().enumerate().max_by(|a, b| order(&a.1, &b.1))
+        };
+        let (k, plan) = match toward {
+            Some(t) => t,
+            None => return Ok(current),
+        };
+        let reach = plan.values[criterion] - current;
+        let s = if reach != 0.0 {
+            ((value - current) / reach).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        for (n, c) in self.coefficients.iter_mut().enumerate() {
+            *c = (1.0 - s) * *c + if n == k { s } else { 0.0 };
+        }
+        Ok(self.values()[criterion])
+    }
+
+    /// Beamlet weights of the interpolated plan.
+    pub fn weights(&self) -> Vec<f64> {
+        let beamlets = self.database.plans.first().map_or(0, |p| p.weights.len());
+        let mut weights = vec![0.0; beamlets];
+        for (p, l) in self.database.plans.iter().zip(&self.coefficients) {
+            for (w, v) in weights.iter_mut().zip(&p.weights) {
+                *w += l * v;
+            }
+        }
+        weights
+    }
+
+    /// Dose of the interpolated plan in every row of `dij`.
+    pub fn dose(&self, dij: &Dij) -> Result<Vec<f64>> {
+        dij.dose(&self.weights())
+    }
+
+    /// Fluence maps of the interpolated plan, see [`crate::opt::fmo::FmoResult`].
+    pub fn fluences(&self, dij: &Dij) -> Result<Vec<Fluence>> {
+        fluences(dij, &self.weights())
+    }
+
+    /// Exact criterion values of the interpolated plan.
+    pub fn evaluate(&self, dij: &Dij, plan: &PlanObjectives) -> Result<Vec<f64>> {
+        FmoProblem::new(dij, plan)?.objectives(&self.weights())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::dij::{Beamlet, Dij};
+    use crate::grid::GridGeometry;
+    use crate::opt::fmo::FmoOptions;
+    use crate::opt::mco::{
+        from_bytes, generate, read, write, McoOptions, ParetoDatabase, ParetoPlan,
+    };
+    use crate::opt::objective::{DoseObjective, ObjectiveKind, PlanObjectives};
+
+    /// Four beamlets over a row of five voxels: the target in voxels 1 to 3 and an organ at
+    /// risk in voxel 4 that the beamlets covering the right of the target cross.
+    fn dij() -> Dij {
+        let geometry = GridGeometry::new([5, 1, 1], Vec3::new(), Vec3::from(1.0, 1.0, 1.0));
+        let beamlets = (0..4)
+            .map(|n| Beamlet {
+                beam: 0,
+                x: 5.0 * n as f64,
+                y: 0.0,
+                energy: None,
+            })
+            .collect();
+        let columns = vec![
+            vec![(0, 0.5), (1, 1.0)],
+            vec![(1, 0.3), (2, 1.0)],
+            vec![(2, 0.3), (3, 1.0), (4, 0.6)],
+            vec![(3, 0.8), (4, 0.8)],
+        ];
+        let structures = vec![
+            ("PTV".to_string(), vec![1, 2, 3]),
+            ("OAR".to_string(), vec![4]),
+        ];
+        Dij::from_columns(
+            geometry,
+            5.0,
+            beamlets,
+            (0..5).collect(),
+            structures,
+            columns,
+        )
+        .unwrap()
+    }
+
+    fn criteria() -> PlanObjectives {
+        PlanObjectives::new()
+            .with_objective(DoseObjective::new("PTV", ObjectiveKind::Uniform(2.0), 1.0))
+            .with_objective(DoseObjective::new("OAR", ObjectiveKind::Max(0.0), 1.0))
+            .with_objective(DoseObjective::new("PTV", ObjectiveKind::Max(1.9), 1.0))
+    }
+
+    #[test]
+    fn pareto_navigation() {
+        let dij = dij();
+        let plan = criteria();
+        let database = generate(&dij, &plan, &McoOptions::default()).unwrap();
+        // Three anchors, the balanced plan and three pairs.
+        assert_eq!(database.plans.len(), 7);
+        let ideal = database.ideal();
+        for (c, anchor) in database.plans[..3].iter().enumerate() {
+            assert!(anchor.values[c] <= ideal[c] + 1e-6);
+        }
+        let nadir = database.nadir();
+        assert!(nadir[1] > ideal[1] + 0.1, "{:?} {:?}", ideal, nadir);
+        // No plan dominates another.
+        for a in &database.plans {
+            for b in &database.plans {
+                let better = a.values.iter().zip(&b.values).all(|(x, y)| *x < *y - 1e-3);
+                assert!(!better, "{:?} {:?}", a.values, b.values);
+            }
+        }
+
+        let mut navigator = database.navigator();
+        let exact = navigator.evaluate(&dij, &plan).unwrap();
+        let bound = navigator.values();
+        for (e, b) in exact.iter().zip(&bound) {
+            assert!(*e <= b + 1e-9);
+        }
+        // Lowering the organ at risk criterion raises the target ones.
+        let target = 0.5 * (bound[1] + ideal[1]);
+        assert!((navigator.slide(1, target).unwrap() - target).abs() < 1e-9);
+        let slid = navigator.evaluate(&dij, &plan).unwrap();
+        assert!(slid[1] <= target + 1e-9);
+        assert!(navigator.values()[0] > bound[0]);
+        // Sliding beyond the database stops at its best plan.
+        let best = navigator.slide(1, -1.0).unwrap();
+        assert!((best - ideal[1]).abs() < 1e-9);
+        assert_eq!(navigator.coefficients()[1], 1.0);
+        let dose = navigator.dose(&dij).unwrap();
+        assert_eq!(dose, dij.dose(&database.plans[1].weights).unwrap());
+        assert_eq!(navigator.fluences(&dij).unwrap()[0].values.len(), 4);
+
+        navigator
+            .set_coefficients(&[1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0])
+            .unwrap();
+        assert_eq!(navigator.coefficients()[0], 0.5);
+        assert!(navigator.set_coefficients(&[1.0]).is_err());
+        assert!(navigator.slide(3, 0.0).is_err());
+
+        let bytes = database.to_bytes();
+        assert_eq!(from_bytes(&bytes).unwrap(), database);
+        assert!(from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let single = PlanObjectives::new().with_objective(DoseObjective::new(
+            "PTV",
+            ObjectiveKind::Uniform(2.0),
+            1.0,
+        ));
+        assert!(generate(&dij, &single, &McoOptions::default()).is_err());
+    }
+
+    /// Three plans of two criteria and two beamlets, built by hand.
+    fn database() -> ParetoDatabase {
+        let plan = |values: [f64; 2], weights: [f64; 2]| ParetoPlan {
+            factors: vec![1.0, 1.0],
+            values: values.to_vec(),
+            weights: weights.to_vec(),
+        };
+        ParetoDatabase {
+            plans: vec![
+                plan([1.0, 4.0], [2.0, 0.0]),
+                plan([3.0, 2.0], [0.0, 2.0]),
+                plan([2.0, 3.0], [1.0, 1.0]),
+            ],
+        }
+    }
+
+    #[test]
+    fn generate_arguments() {
+        let (dij, plan) = (dij(), criteria());
+        for anchor_weight in [0.0, 1.0, f64::NAN].iter() {
+            let options = McoOptions {
+                anchor_weight: *anchor_weight,
+                ..McoOptions::default()
+            };
+            assert!(generate(&dij, &plan, &options).is_err());
+        }
+        let options = McoOptions {
+            weights: FmoOptions {
+                lower: 1.0,
+                upper: 0.0,
+                ..FmoOptions::default()
+            },
+            ..McoOptions::default()
+        };
+        assert!(generate(&dij, &plan, &options).is_err());
+        let unknown = PlanObjectives::new()
+            .with_objective(DoseObjective::new("PTV", ObjectiveKind::Max(2.0), 1.0))
+            .with_objective(DoseObjective::new("Lung", ObjectiveKind::Max(0.0), 1.0));
+        assert!(generate(&dij, &unknown, &McoOptions::default()).is_err());
+    }
+
+    #[test]
+    fn generate_without_pairs() {
+        let (dij, plan) = (dij(), criteria());
+        let options = McoOptions {
+            pairs: false,
+            ..McoOptions::default()
+        };
+        let database = generate(&dij, &plan, &options).unwrap();
+        // Three anchors and the balanced plan.
+        assert_eq!(database.plans.len(), 4);
+        assert_eq!(database.criteria(), 3);
+        let anchor = &database.plans[1].factors;
+        assert_eq!(anchor, &vec![1e-3, 1.0, 1e-3]);
+        assert!(database.plans[3].factors.iter().all(|f| *f > 1e-3));
+    }
+
+    #[test]
+    fn database_bounds_and_files() {
+        let database = database();
+        assert_eq!(database.criteria(), 2);
+        assert_eq!(database.ideal(), vec![1.0, 2.0]);
+        // The nadir is over the two anchor plans only.
+        assert_eq!(database.nadir(), vec![3.0, 4.0]);
+        assert_eq!(ParetoDatabase { plans: vec![] }.criteria(), 0);
+
+        let path = std::env::temp_dir().join(format!("planrt-mco-{}.db", std::process::id()));
+        write(&path, &database).unwrap();
+        assert_eq!(read(&path).unwrap(), database);
+        std::fs::remove_file(&path).unwrap();
+        assert!(read(&path).is_err());
+
+        let bytes = database.to_bytes();
+        assert_eq!(bytes.len(), 32 + 3 * 8 * 6);
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        assert!(from_bytes(&magic).is_err());
+        assert!(from_bytes(&bytes[..16]).is_err());
+        let mut longer = bytes.clone();
+        longer.extend_from_slice(&[0; 8]);
+        assert!(from_bytes(&longer).is_err());
+        let mut huge = bytes;
+        huge[24..32].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(from_bytes(&huge).is_err());
+    }
+
+    #[test]
+    fn navigator_coefficients() {
+        let database = database();
+        let mut navigator = database.navigator();
+        assert_eq!(navigator.coefficients(), &[1.0 / 3.0; 3]);
+        assert_eq!(navigator.values(), vec![2.0, 3.0]);
+        assert_eq!(navigator.weights(), vec![1.0, 1.0]);
+        navigator.set_coefficients(&[3.0, 1.0, 0.0]).unwrap();
+        assert_eq!(navigator.coefficients(), &[0.75, 0.25, 0.0]);
+        assert_eq!(navigator.weights(), vec![1.5, 0.5]);
+        for bad in [
+            vec![1.0, 1.0],
+            vec![1.0, -1.0, 1.0],
+            vec![1.0, f64::NAN, 1.0],
+            vec![0.0, 0.0, 0.0],
+        ]
+        .iter()
+        {
+            assert!(navigator.set_coefficients(bad).is_err(), "{:?}", bad);
+        }
+        assert_eq!(navigator.coefficients(), &[0.75, 0.25, 0.0]);
+    }
+
+    #[test]
+    fn navigator_slide() {
+        let database = database();
+        let mut navigator = database.navigator();
+        // Halfway towards the plan with the best first criterion.
+        assert!((navigator.slide(0, 1.5).unwrap() - 1.5).abs() < 1e-12);
+        let c = navigator.coefficients();
+        assert!((c[0] - 2.0 / 3.0).abs() < 1e-12 && (c[1] - 1.0 / 6.0).abs() < 1e-12);
+        // Raising a criterion moves towards its worst plan and stops there.
+        assert_eq!(navigator.slide(0, 10.0).unwrap(), 3.0);
+        assert_eq!(navigator.coefficients(), &[0.0, 1.0, 0.0]);
+        // At the requested value already.
+        assert_eq!(navigator.slide(1, 2.0).unwrap(), 2.0);
+        assert_eq!(navigator.coefficients(), &[0.0, 1.0, 0.0]);
+        assert!(navigator.slide(2, 0.0).is_err());
+        assert!(ParetoDatabase { plans: vec![] }
+            .navigator()
+            .slide(0, 0.0)
+            .is_err());
+    }
+}
@@ -0,0 +1,14 @@
+//! Inverse planning.
+//!
+//! Plans are optimized over the beamlet weights of a [`crate::dose::dij::Dij`] matrix: the
+//! dose in every structure voxel is the matrix times the weights and the objectives and
+//! constraints of [`objective`] are evaluated on the rows of their structure.
+
+pub mod adaptive;
+pub mod dao;
+pub mod fmo;
+pub mod mco;
+pub mod objective;
+pub mod robust;
+pub mod sequencing;
+pub mod vmat;
@@ -0,0 +1,515 @@
+//! Objectives and constraints of inverse planning.
+//!
+//! An [`Objective`] is a penalty on the doses of the rows of its structure, a [`Constraint`] a
+//! function of them that a plan must keep at or below zero; both add their gradient with respect
+//! to the row doses, so the optimizers only see the sum and custom objectives plug in alongside
+//! the built-in ones. The built-in penalties are quadratic and scaled by their weight; the
+//! voxel-wise ones are normalized by the number of rows, so objectives of structures of
+//! different size compare. Dose-volume objectives follow Wu and Mohan: only the voxels between
+//! the dose level and the dose at the allowed volume are pulled to the level.
+
+use crate::distance::distance_transform;
+use crate::dose::dij::Dij;
+use crate::error::{Error, Result};
+use crate::grid::Grid3;
+
+pub trait Objective {
+    /// Structure of the rows the objective is evaluated on.
+    fn structure(&self) -> &str;
+
+    /// Value of the objective for the row doses `dose`, adding its gradient with respect to
+    /// the row doses to `gradient`.
+    fn evaluate(&self, rows: &[u32], dose: &[f64], gradient: &mut [f64]) -> f64;
+}
+
+pub trait Constraint {
+    /// Structure of the rows the constraint is evaluated on.
+    fn structure(&self) -> &str;
+
+    /// Value of the constraint, met at or below zero, for the row doses `dose`, adding its
+    /// gradient with respect to the row doses to `gradient`.
+    fn evaluate(&self, rows: &[u32], dose: &[f64], gradient: &mut [f64]) -> f64;
+}
+
+/// The objectives and constraints an optimizer minimizes over.
+#[derive(Default)]
+pub struct PlanObjectives {
+    pub objectives: Vec<Box<dyn Objective>>,
+    pub constraints: Vec<Box<dyn Constraint>>,
+}
+
+impl PlanObjectives {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_objective<O: Objective + 'static>(mut self, objective: O) -> Self {
+        self.objectives.push(Box::new(objective));
+        self
+    }
+
+    pub fn with_constraint<C: Constraint + 'static>(mut self, constraint: C) -> Self {
+        self.constraints.push(Box::new(constraint));
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjectiveKind {
+    /// Every voxel at this dose (Gy).
+    Uniform(f64),
+    /// No voxel below this dose (Gy).
+    Min(f64),
+    /// No voxel above this dose (Gy).
+    Max(f64),
+    /// At most `volume` (fraction) above `dose` (Gy).
+    MaxDvh { dose: f64, volume: f64 },
+    /// At least `volume` (fraction) at `dose` (Gy) or more.
+    MinDvh { dose: f64, volume: f64 },
+}
+
+/// Voxel-wise dose and dose-volume objective; as a constraint its penalty must vanish.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoseObjective {
+    pub structure: String,
+    pub kind: ObjectiveKind,
+    pub weight: f64,
+}
+
+impl DoseObjective {
+    pub fn new(structure: &str, kind: ObjectiveKind, weight: f64) -> Self {
+        DoseObjective {
+            structure: structure.to_string(),
+            kind,
+            weight,
+        }
+    }
+}
+
+impl Objective for DoseObjective {
+    fn structure(&self) -> &str {
+        &self.structure
+    }
+
+    fn evaluate(&self, rows: &[u32], dose: &[f64], gradient: &mut [f64]) -> f64 {
+        if rows.is_empty() {
+            return 0.0;
+        }
+        let scale = self.weight / rows.len() as f64;
+        // The voxels penalized with their reference level.
+        let (lo, hi, level) = match self.kind {
+            ObjectiveKind::Uniform(d) => (f64::NEG_INFINITY, f64::INFINITY, d),
+            ObjectiveKind::Min(d) => (f64::NEG_INFINITY, d, d),
+            ObjectiveKind::Max(d) => (d, f64::INFINITY, d),
+            ObjectiveKind::MaxDvh { dose: d, volume } => {
+                let at_volume = dose_at_volume(rows, dose, volume);
+                (d, at_volume.max(d), d)
+            }
+            ObjectiveKind::MinDvh { dose: d, volume } => {
+                let at_volume = dose_at_volume(rows, dose, volume);
+                (at_volume.min(d), d, d)
+            }
+        };
+        let mut value = 0.0;
+        for row in rows {
+            let d = dose[*row as usize];
+            let penalized = match self.kind {
+                ObjectiveKind::Uniform(_) => true,
+                ObjectiveKind::Min(_) | ObjectiveKind::MinDvh { .. } => d >= lo && d < hi,
+                ObjectiveKind::Max(_) | ObjectiveKind::MaxDvh { .. } => d > lo && d < hi,
+            };
+            if penalized {
+                let diff = d - level;
+                value += scale * diff * diff;
+                gradient[*row as usize] += 2.0 * scale * diff;
+            }
+        }
+        value
+    }
+}
+
+impl Constraint for DoseObjective {
+    fn structure(&self) -> &str {
+        &self.structure
+    }
+
+    fn evaluate(&self, rows: &[u32], dose: &[f64], gradient: &mut [f64]) -> f64 {
+        Objective::evaluate(self, rows, dose, gradient)
+    }
+}
+
+/// Dose (Gy) received by at least the fraction `volume` of `rows`.
+fn dose_at_volume(rows: &[u32], dose: &[f64], volume: f64) -> f64 {
+    let mut doses: Vec<f64> = rows.iter().map(|r| dose[*r as usize]).collect();
+    doses.sort_by(|a, b| b.partial_cmp(a).unwrap());
+    let n = ((volume.clamp(0.0, 1.0) * doses.len() as f64).ceil() as usize).max(1);
+    doses[n.min(doses.len()) - 1]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    /// At most this dose (Gy).
+    Upper(f64),
+    /// At least this dose (Gy).
+    Lower(f64),
+}
+
+impl Limit {
+    /// How far `value` (Gy) is beyond the limit, negative within, and its derivative.
+    fn excess(&self, value: f64) -> (f64, f64) {
+        match *self {
+            Limit::Upper(l) => (value - l, 1.0),
+            Limit::Lower(l) => (l - value, -1.0),
+        }
+    }
+}
+
+/// Adds `scale` times the gradient of a structure quantity, given per row in `derivative`.
+fn add_gradient(rows: &[u32], derivative: &[f64], scale: f64, gradient: &mut [f64]) {
+    for (row, d) in rows.iter().zip(derivative) {
+        gradient[*row as usize] += scale * d;
+    }
+}
+
+/// Squared excess of a structure quantity `(value, derivative per row)` over `limit`.
+fn penalty(
+    rows: &[u32],
+    (value, derivative): (f64, Vec<f64>),
+    limit: Limit,
+    weight: f64,
+    gradient: &mut [f64],
+) -> f64 {
+    let (excess, sign) = limit.excess(value);
+    if excess <= 0.0 {
+        return 0.0;
+    }
+    add_gradient(rows, &derivative, 2.0 * weight * excess * sign, gradient);
+    weight * excess * excess
+}
+
+/// Excess of a structure quantity `(value, derivative per row)` over `limit`.
+fn bound(
+    rows: &[u32],
+    (value, derivative): (f64, Vec<f64>),
+    limit: Limit,
+    gradient: &mut [f64],
+) -> f64 {
+    let (excess, sign) = limit.excess(value);
+    add_gradient(rows, &derivative, sign, gradient);
+    excess
+}
+
+/// Mean dose of a structure.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeanDose {
+    pub structure: String,
+    pub limit: Limit,
+    pub weight: f64,
+}
+
+impl MeanDose {
+    pub fn new(structure: &str, limit: Limit, weight: f64) -> Self {
+        MeanDose {
+            structure: structure.to_string(),
+            limit,
+            weight,
+        }
+    }
+
+    fn mean(rows: &[u32], dose: &[f64]) -> (f64, Vec<f64>) {
+        let n = rows.len().max(1) as f64;
+        let mean = rows.iter().map(|r| dose[*r as usize]).sum::<f64>() / n;
+        (mean, vec![1.0 / n; rows.len()])
+    }
+}
+
+impl Objective for MeanDose {
+    fn structure(&self) -> &str {
+        &self.structure
+    }
+
+    fn evaluate(&self, rows: &[u32], dose: &[f64], gradient: &mut [f64]) -> f64 {
+        penalty(
+            rows,
+            Self::mean(rows, dose),
+            self.limit,
+            self.weight,
+            gradient,
+        )
+    }
+}
+
+impl Constraint for MeanDose {
+    fn structure(&self) -> &str {
+        &self.structure
+    }
+
+    fn evaluate(&self, rows: &[u32], dose: &[f64], gradient: &mut [f64]) -> f64 {
+        bound(rows, Self::mean(rows, dose), self.limit, gradient)
+    }
+}
+
+/// Generalized equivalent uniform dose `(mean of d^a)^(1/a)` of a structure: large `a` for
+/// serial organs, `a = 1` the mean dose and negative `a` for targets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Geud {
+    pub structure: String,
+    pub a: f64,
+    pub limit: Limit,
+    pub weight: f64,
+}
+
+impl Geud {
+    pub fn new(structure: &str, a: f64, limit: Limit, weight: f64) -> Self {
+        Geud {
+            structure: structure.to_string(),
+            a,
+            limit,
+            weight,
+        }
+    }
+
+    fn geud(&self, rows: &[u32], dose: &[f64]) -> (f64, Vec<f64>) {
+        if rows.is_empty() || self.a == 0.0 {
+            return (0.0, vec![0.0; rows.len()]);
+        }
+        let n = rows.len() as f64;
+        // Doses are kept positive so that negative powers stay finite.
+        let doses: Vec<f64> = rows.iter().map(|r| dose[*r as usize].max(1e-9)).collect();
+        let mean = doses.iter().map(|d| d.powf(self.a)).sum::<f64>() / n;
+        let geud = mean.powf(1.0 / self.a);
+        let derivative = doses
+            .iter()
+            .map(|d| geud.powf(1.0 - self.a) * d.powf(self.a - 1.0) / n)
+            .collect();
+        (geud, derivative)
+    }
+}
+
+impl Objective for Geud {
+    fn structure(&self) -> &str {
+        &self.structure
+    }
+
+    fn evaluate(&self, rows: &[u32], dose: &[f64], gradient: &mut [f64]) -> f64 {
+        penalty(
+            rows,
+            self.geud(rows, dose),
+            self.limit,
+            self.weight,
+            gradient,
+        )
+    }
+}
+
+impl Constraint for Geud {
+    fn structure(&self) -> &str {
+        &self.structure
+    }
+
+    fn evaluate(&self, rows: &[u32], dose: &[f64], gradient: &mut [f64]) -> f64 {
+        bound(rows, self.geud(rows, dose), self.limit, gradient)
+    }
+}
+
+/// Dose falling off away from a target: no voxel above a limit going linearly from `high`
+/// (Gy) at the target to `low` (Gy) at `width` (mm) from it and beyond. As a constraint its
+/// penalty must vanish.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Falloff {
+    pub structure: String,
+    /// Distance (mm) to the target of every row of the structure, in row order.
+    pub distances: Vec<f64>,
+    pub high: f64,
+    pub low: f64,
+    pub width: f64,
+    pub weight: f64,
+}
+
+impl Falloff {
+    /// Falloff over `structure` of `dij` away from the voxels set in `target`, a mask on the
+    /// dose grid of `dij`.
+    pub fn new(
+        dij: &Dij,
+        structure: &str,
+        target: &Grid3<bool>,
+        high: f64,
+        low: f64,
+        width: f64,
+        weight: f64,
+    ) -> Result<Self> {
+        if target.geometry() != dij.geometry() {
+            return Err(Error::InvalidArgument(
+                "the falloff target is not on the dose grid of the matrix".to_string(),
+            ));
+        }
+        let distance = distance_transform(target);
+        let distances = rows(dij, structure)?
+            .iter()
+            .map(|r| distance.data()[dij.voxels()[*r as usize]])
+            .collect();
+        Ok(Falloff {
+            structure: structure.to_string(),
+            distances,
+            high,
+            low,
+            width,
+            weight,
+        })
+    }
+
+    /// Dose limit (Gy) at `distance` (mm) from the target.
+    pub fn limit(&self, distance: f64) -> f64 {
+        let t = if self.width > 0.0 {
+            (distance / self.width).clamp(0.0, 1.0)
+        } else {
+            1.0
+        };
+        self.high + t * (self.low - self.high)
+    }
+}
+
+impl Objective for Falloff {
+    fn structure(&self) -> &str {
+        &self.structure
+    }
+
+    fn evaluate(&self, rows: &[u32], dose: &[f64], gradient: &mut [f64]) -> f64 {
+        if rows.is_empty() {
+            return 0.0;
+        }
+        let scale = self.weight / rows.len() as f64;
+        let mut value = 0.0;
+        for (row, distance) in rows.iter().zip(&self.distances) {
+            let diff = dose[*row as usize] - self.limit(*distance);
+            if diff > 0.0 {
+                value += scale * diff * diff;
+                gradient[*row as usize] += 2.0 * scale * diff;
+            }
+        }
+        value
+    }
+}
+
+impl Constraint for Falloff {
+    fn structure(&self) -> &str {
+        &self.structure
+    }
+
+    fn evaluate(&self, rows: &[u32], dose: &[f64], gradient: &mut [f64]) -> f64 {
+        Objective::evaluate(self, rows, dose, gradient)
+    }
+}
+
+/// Rows of `structure` in `dij`.
+pub(crate) fn rows<'a>(dij: &'a Dij, structure: &str) -> Result<&'a [u32]> {
+    dij.structure(structure).ok_or_else(|| {
+        Error::InvalidArgument(format!("no structure '{}' in the matrix", structure))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::opt::objective::{
+        Constraint, DoseObjective, Falloff, Geud, Limit, MeanDose, Objective, ObjectiveKind,
+    };
+
+    const ROWS: [u32; 4] = [0, 1, 2, 3];
+    const DOSE: [f64; 4] = [1.0, 2.0, 3.0, 4.0];
+
+    /// Checks the gradient of `f` against central differences and returns its value.
+    fn checked(f: &dyn Fn(&[f64], &mut [f64]) -> f64) -> f64 {
+        let mut gradient = vec![0.0; 4];
+        let value = f(&DOSE, &mut gradient);
+        for n in 0..4 {
+            let (mut up, mut down) = (DOSE, DOSE);
+            up[n] += 1e-6;
+            down[n] -= 1e-6;
+            let mut scratch = vec![0.0; 4];
+            let numeric = (f(&up, &mut scratch) - f(&down, &mut scratch)) / 2e-6;
+            assert!(
+                (numeric - gradient[n]).abs() < 1e-6,
+                "{} {}",
+                n,
+                gradient[n]
+            );
+        }
+        value
+    }
+
+    fn objective(o: &dyn Objective) -> f64 {
+        checked(&|dose, gradient| o.evaluate(&ROWS, dose, gradient))
+    }
+
+    fn constraint(c: &dyn Constraint) -> f64 {
+        checked(&|dose, gradient| c.evaluate(&ROWS, dose, gradient))
+    }
+
+    #[test]
+    fn objective_gradients() {
+        let check = |kind: ObjectiveKind, expected: f64| {
+            let value = objective(&DoseObjective::new("PTV", kind, 2.0));
+            assert!((value - expected).abs() < 1e-12, "{:?} {}", kind, value);
+        };
+        check(
+            ObjectiveKind::Uniform(2.5),
+            0.5 * (2.25 + 0.25 + 0.25 + 2.25),
+        );
+        check(ObjectiveKind::Min(2.5), 0.5 * (2.25 + 0.25));
+        check(ObjectiveKind::Max(2.5), 0.5 * (0.25 + 2.25));
+        // Half the volume may exceed 1.5 Gy: only the voxel at 2 Gy is pulled down.
+        check(
+            ObjectiveKind::MaxDvh {
+                dose: 1.5,
+                volume: 0.5,
+            },
+            0.5 * 0.25,
+        );
+        // Three quarters must reach 3.5 Gy: the voxels at 2 and 3 Gy are pushed up.
+        check(
+            ObjectiveKind::MinDvh {
+                dose: 3.5,
+                volume: 0.75,
+            },
+            0.5 * (2.25 + 0.25),
+        );
+    }
+
+    #[test]
+    fn builtin_objectives() {
+        // Mean 2.5 Gy.
+        let mean = MeanDose::new("OAR", Limit::Upper(2.0), 2.0);
+        assert!((objective(&mean) - 2.0 * 0.25).abs() < 1e-12);
+        assert!((constraint(&mean) - 0.5).abs() < 1e-12);
+        let mean = MeanDose::new("OAR", Limit::Upper(3.0), 2.0);
+        assert_eq!(objective(&mean), 0.0);
+        assert!((constraint(&mean) + 0.5).abs() < 1e-12);
+        assert!((constraint(&MeanDose::new("PTV", Limit::Lower(3.0), 1.0)) - 0.5).abs() < 1e-12);
+
+        // a = 1 is the mean dose, large a tends to the maximum and negative a to the minimum.
+        let geud = |a: f64| constraint(&Geud::new("OAR", a, Limit::Upper(0.0), 1.0));
+        assert!((geud(1.0) - 2.5).abs() < 1e-12);
+        assert!(geud(40.0) > 3.8 && geud(40.0) < 4.0);
+        assert!(geud(-40.0) > 1.0 && geud(-40.0) < 1.1);
+        let value = objective(&Geud::new("OAR", 8.0, Limit::Upper(3.0), 1.0));
+        let excess = geud(8.0) - 3.0;
+        assert!((value - excess * excess).abs() < 1e-12);
+
+        // The limit falls from 4 Gy at the target to 1 Gy 30 mm away.
+        let falloff = Falloff {
+            structure: "Body".to_string(),
+            distances: vec![0.0, 10.0, 20.0, 30.0],
+            high: 4.0,
+            low: 1.0,
+            width: 30.0,
+            weight: 4.0,
+        };
+        assert_eq!(falloff.limit(10.0), 3.0);
+        assert_eq!(falloff.limit(50.0), 1.0);
+        assert!((objective(&falloff) - (1.0 + 9.0)).abs() < 1e-12);
+        assert_eq!(
+            Constraint::evaluate(&falloff, &ROWS, &[1.0; 4], &mut [0.0; 4]),
+            0.0
+        );
+    }
+}
@@ -0,0 +1,388 @@
+//! Robust fluence map optimization over uncertainty scenarios.
+//!
+//! Every [`Scenario`] of the robustness engine brings its own [`Dij`] matrix over the same
+//! beamlets and structures: computed beforehand, or recomputed for the scenario by moving the
+//! beams, shifting the patient or scaling the stopping power before [`Dij::compute`]. The
+//! objectives of a [`PlanObjectives`] are evaluated on every scenario dose and aggregated
+//! either as their expected value over the scenario probabilities or as their worst case. The
+//! worst case is a smoothed maximum, `T ln Σ exp(f_s / T)`, whose temperature `T` is a fraction
+//! of the largest scenario objective at the starting weights, so that the solver sees a
+//! gradient that mixes the scenarios close to the worst instead of jumping between them.
+//! Constraints have to hold in every scenario, with the penalty of [`crate::opt::fmo`].
+
+use crate::dose::beam::Fluence;
+use crate::dose::dij::Dij;
+use crate::error::{Error, Result};
+use crate::opt::fmo::{check, fluences, solve, FmoOptions, FmoProblem, Penalized};
+use crate::opt::objective::PlanObjectives;
+use crate::progress::Progress;
+use crate::robustness::Scenario;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    /// Probability weighted sum of the scenario objectives.
+    Expected,
+    /// Largest scenario objective.
+    WorstCase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RobustOptions {
+    pub aggregation: Aggregation,
+    /// Temperature of the smoothed worst case relative to the largest scenario objective at
+    /// the starting weights; 0 takes the plain maximum.
+    pub smoothing: f64,
+    pub weights: FmoOptions,
+}
+
+impl Default for RobustOptions {
+    fn default() -> Self {
+        RobustOptions {
+            aggregation: Aggregation::WorstCase,
+            smoothing: 0.01,
+            weights: FmoOptions::default(),
+        }
+    }
+}
+
+/// Uncertainty scenarios with their dose-influence matrices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScenarioSet {
+    pub scenarios: Vec<Scenario>,
+    /// Matrix of every scenario, over the same beamlets and structures.
+    pub matrices: Vec<Dij>,
+    /// Probability of every scenario in the expected objective; equal when empty.
+    pub probabilities: Vec<f64>,
+}
+
+impl ScenarioSet {
+    pub fn new(scenarios: Vec<Scenario>, matrices: Vec<Dij>) -> Self {
+        ScenarioSet {
+            scenarios,
+            matrices,
+            probabilities: Vec::new(),
+        }
+    }
+
+    /// Recomputes the matrix of every scenario with `compute`.
+    pub fn recompute<F>(scenarios: Vec<Scenario>, compute: F) -> Result<Self>
+    where
+        F: Fn(&Scenario) -> Result<Dij>,
+    {
+        let matrices = scenarios.iter().map(compute).collect::<Result<Vec<_>>>()?;
+        Ok(Self::new(scenarios, matrices))
+    }
+
+    pub fn with_probabilities(mut self, probabilities: Vec<f64>) -> Self {
+        self.probabilities = probabilities;
+        self
+    }
+
+    /// Normalized probabilities of the scenarios.
+    fn normalized(&self) -> Result<Vec<f64>> {
+        let n = self.matrices.len();
+        if n == 0 || self.scenarios.len() != n {
+            return Err(Error::InvalidArgument(format!(
+                "{} scenarios with {} matrices",
+                self.scenarios.len(),
+                n
+            )));
+        }
+        let columns = self.matrices[0].column_count();
+        if let Some(m) = self.matrices.iter().find(|m| m.column_count() != columns) {
+            return Err(Error::InvalidArgument(format!(
+                "scenario matrices with {} and {} beamlets",
+                columns,
+                m.column_count()
+            )));
+        }
+        if self.probabilities.is_empty() {
+            return Ok(vec![1.0 / n as f64; n]);
+        }
+        let total: f64 = self.probabilities.iter().sum();
+        if self.probabilities.len() != n
+            || self.probabilities.iter().any(|p| p.is_nan() || *p < 0.0)
+            || total <= 0.0
+        {
+            return Err(Error::InvalidArgument(format!(
+                "invalid scenario probabilities {:?}",
+                self.probabilities
+            )));
+        }
+        Ok(self.probabilities.iter().map(|p| p / total).collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RobustResult {
+    pub weights: Vec<f64>,
+    /// Optimized fluence map of every beam with photon bixels, from the first scenario matrix.
+    pub fluences: Vec<Fluence>,
+    /// Objective value of every scenario at the optimized weights, without constraints.
+    pub scenario_values: Vec<f64>,
+    /// Aggregated penalized objective before the first and after every iteration of every
+    /// solve.
+    pub history: Vec<f64>,
+    pub converged: bool,
+}
+
+struct RobustProblem<'a> {
+    problems: Vec<FmoProblem<'a>>,
+    probabilities: Vec<f64>,
+    aggregation: Aggregation,
+    temperature: f64,
+}
+
+impl<'a> Penalized for RobustProblem<'a> {
+    fn evaluate(&self, weights: &[f64], penalty: f64) -> Result<(f64, Vec<f64>)> {
+        let scenarios = self
+            .problems
+            .iter()
+            .map(|p| p.evaluate(weights, penalty))
+            .collect::<Result<Vec<_>>>()?;
+        let largest = scenarios
+            .iter()
+            .map(|s| s.0)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let (value, mix): (f64, Vec<f64>) = match self.aggregation {
+            Aggregation::Expected => (
+                scenarios
+                    .iter()
+                    .zip(&self.probabilities)
+                    .map(|(s, p)| p * s.0)
+                    .sum(),
+                self.probabilities.clone(),
+            ),
+            Aggregation::WorstCase if self.temperature > 0.0 => {
+                let t = self.temperature;
+                let exp: Vec<f64> = scenarios
+                    .iter()
+                    .map(|s| ((s.0 - largest) / t).exp())
+                    .collect();
+                let sum: f64 = exp.iter().sum();
+                (
+                    largest + t * sum.ln(),
+                    exp.iter().map(|e| e / sum).collect(),
+                )
+            }
+            Aggregation::WorstCase => {
+                let worst = scenarios.iter().position(|s| s.0 == largest).unwrap_or(0);
+                let mut mix = vec![0.0; scenarios.len()];
+                mix[worst] = 1.0;
+                (largest, mix)
+            }
+        };
+        let mut gradient = vec![0.0; weights.len()];
+        for ((_, g), m) in scenarios.iter().zip(&mix) {
+            if *m > 0.0 {
+                for (a, b) in gradient.iter_mut().zip(g) {
+                    *a += m * b;
+                }
+            }
+        }
+        Ok((value, gradient))
+    }
+
+    fn constraints(&self, weights: &[f64]) -> Result<Vec<f64>> {
+        let mut values = Vec::new();
+        for p in &self.problems {
+            values.extend(p.constraints(weights)?);
+        }
+        Ok(values)
+    }
+}
+
+/// Minimizes `plan` over the scenarios of `set`, see the module documentation.
+pub fn optimize(
+    set: &ScenarioSet,
+    plan: &PlanObjectives,
+    options: &RobustOptions,
+) -> Result<RobustResult> {
+    let initial = match set.matrices.first() {
+        Some(m) => vec![options.weights.initial; m.column_count()],
+        None => Vec::new(),
+    };
+    optimize_from(set, plan, initial, options)
+}
+
+/// As [`optimize`], starting from `weights`.
+pub fn optimize_from(
+    set: &ScenarioSet,
+    plan: &PlanObjectives,
+    weights: Vec<f64>,
+    options: &RobustOptions,
+) -> Result<RobustResult> {
+    check(&options.weights)?;
+    if options.smoothing.is_nan() || options.smoothing < 0.0 {
+        return Err(Error::InvalidArgument(format!(
+            "negative worst case smoothing {}",
+            options.smoothing
+        )));
+    }
+    let probabilities = set.normalized()?;
+    let problems = set
+        .matrices
+        .iter()
+        .map(|m| FmoProblem::new(m, plan))
+        .collect::<Result<Vec<_>>>()?;
+    let mut problem = RobustProblem {
+        problems,
+        probabilities,
+        aggregation: options.aggregation,
+        temperature: 0.0,
+    };
+    let values = scenario_values(&problem, &weights)?;
+    problem.temperature = options.smoothing * values.iter().cloned().fold(0.0, f64::max);
+    let (x, history, converged) = solve(
+        &problem,
+        |w| w.to_vec(),
+        |g| g.to_vec(),
+        weights,
+        &options.weights,
+        &Progress::new(),
+    )?;
+    Ok(RobustResult {
+        fluences: fluences(&set.matrices[0], &x)?,
+        scenario_values: scenario_values(&problem, &x)?,
+        weights: x,
+        history,
+        converged,
+    })
+}
+
+fn scenario_values(problem: &RobustProblem, weights: &[f64]) -> Result<Vec<f64>> {
+    problem
+        .problems
+        .iter()
+        .map(|p| Ok(p.evaluate(weights, 0.0)?.0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::dij::{Beamlet, Dij};
+    use crate::grid::GridGeometry;
+    use crate::opt::fmo::{self, FmoOptions, FmoProblem};
+    use crate::opt::objective::{DoseObjective, Limit, MeanDose, ObjectiveKind, PlanObjectives};
+    use crate::opt::robust::{optimize, Aggregation, RobustOptions, ScenarioSet};
+    use crate::robustness::Scenario;
+
+    /// Six beamlets over a row of ten 1 mm voxels, beamlet `n` peaking in voxel `n + 2`,
+    /// with the patient shifted by `shift` voxels: a target in voxels 4 and 5 and an organ at
+    /// risk in voxel 8.
+    fn dij(shift: i64) -> Dij {
+        let geometry = GridGeometry::new([10, 1, 1], Vec3::new(), Vec3::from(1.0, 1.0, 1.0));
+        let beamlets = (0..6)
+            .map(|n| Beamlet {
+                beam: 0,
+                x: n as f64,
+                y: 0.0,
+                energy: None,
+            })
+            .collect();
+        let columns = (0..6)
+            .map(|n| {
+                let peak = n + 2 + shift;
+                [(peak - 1, 0.3), (peak, 1.0), (peak + 1, 0.3)]
+                    .iter()
+                    .filter(|(v, _)| (0..10).contains(v))
+                    .map(|(v, d)| (*v as u32, *d as f32))
+                    .collect()
+            })
+            .collect();
+        let structures = vec![
+            ("PTV".to_string(), vec![4, 5]),
+            ("OAR".to_string(), vec![8]),
+        ];
+        Dij::from_columns(
+            geometry,
+            1.0,
+            beamlets,
+            (0..10).collect(),
+            structures,
+            columns,
+        )
+        .unwrap()
+    }
+
+    fn scenarios() -> ScenarioSet {
+        let shifts = [0, -1, 1];
+        ScenarioSet::recompute(
+            shifts
+                .iter()
+                .map(|s| Scenario::shifted(Vec3::from(*s as f64, 0.0, 0.0)))
+                .collect(),
+            |s| Ok(dij(s.shift.x as i64)),
+        )
+        .unwrap()
+    }
+
+    fn objectives() -> PlanObjectives {
+        PlanObjectives::new()
+            .with_objective(DoseObjective::new("PTV", ObjectiveKind::Min(2.0), 100.0))
+            .with_objective(DoseObjective::new("PTV", ObjectiveKind::Max(2.2), 100.0))
+            .with_objective(DoseObjective::new("OAR", ObjectiveKind::Max(0.0), 1.0))
+    }
+
+    /// Objective of every scenario of `set` at `weights`.
+    fn values(set: &ScenarioSet, plan: &PlanObjectives, weights: &[f64]) -> Vec<f64> {
+        set.matrices
+            .iter()
+            .map(|m| {
+                let problem = FmoProblem::new(m, plan).unwrap();
+                problem.evaluate(weights, 0.0).unwrap().0
+            })
+            .collect()
+    }
+
+    #[test]
+    fn robust_optimization() {
+        let set = scenarios();
+        let plan = objectives();
+        let nominal = fmo::optimize(&set.matrices[0], &plan, &FmoOptions::default()).unwrap();
+        let nominal_values = values(&set, &plan, &nominal.weights);
+        let worst = |v: &[f64]| v.iter().cloned().fold(0.0, f64::max);
+        let mean = |v: &[f64]| v.iter().sum::<f64>() / v.len() as f64;
+
+        let worst_case = optimize(&set, &plan, &RobustOptions::default()).unwrap();
+        assert!(worst_case.history.windows(2).all(|h| h[1] <= h[0] + 1e-9));
+        assert_eq!(
+            worst_case.scenario_values,
+            values(&set, &plan, &worst_case.weights)
+        );
+        assert!(
+            worst(&worst_case.scenario_values) < 0.5 * worst(&nominal_values),
+            "{:?} {:?}",
+            worst_case.scenario_values,
+            nominal_values
+        );
+        // The nominal plan is better in the nominal scenario only.
+        assert!(worst_case.scenario_values[0] >= nominal_values[0] - 1e-6);
+
+        let expected = RobustOptions {
+            aggregation: Aggregation::Expected,
+            ..RobustOptions::default()
+        };
+        let result = optimize(&set, &plan, &expected).unwrap();
+        assert!(mean(&result.scenario_values) < mean(&nominal_values));
+        assert!(mean(&result.scenario_values) <= mean(&worst_case.scenario_values) + 1e-6);
+        // Weighting the nominal scenario alone recovers the nominal optimum.
+        let weighted = scenarios().with_probabilities(vec![1.0, 0.0, 0.0]);
+        let result = optimize(&weighted, &plan, &expected).unwrap();
+        assert!((result.scenario_values[0] - nominal_values[0]).abs() < 1e-3);
+
+        // Constraints hold in every scenario.
+        let constrained =
+            objectives().with_constraint(MeanDose::new("OAR", Limit::Upper(0.5), 1.0));
+        let result = optimize(&set, &constrained, &RobustOptions::default()).unwrap();
+        for m in &set.matrices {
+            let dose = m.dose(&result.weights).unwrap();
+            assert!(dose[8] <= 0.5 + 1e-3, "{:?}", dose);
+        }
+
+        assert!(optimize(&set.clone().with_probabilities(vec![1.0]), &plan, &expected).is_err());
+        let mismatched = ScenarioSet::new(vec![Scenario::nominal()], Vec::new());
+        assert!(optimize(&mismatched, &plan, &expected).is_err());
+    }
+}
@@ -0,0 +1,658 @@
+//! Leaf sequencing of fluence maps into MLC control points.
+//!
+//! Every leaf pair delivers the row of the map under its center, its leaves stepping over the
+//! pixel edges; both methods sweep the leaves unidirectionally from bank A's side.
+//!
+//! Step-and-shoot quantizes the map into intensity levels and decomposes every profile with
+//! the sweep technique of Bortfeld: the k-th rise of the profile opens and its k-th fall
+//! closes the k-th unit segment of the pair. The k-th segments of all pairs form a segment of
+//! the field, split where neighbouring open pairs would interdigitate or a bank would exceed
+//! the leaf span; pairs closed in a segment park where their neighbours allow, and openings
+//! narrower than the minimum gap are widened about their center.
+//!
+//! Sliding window follows Spirou and Chui: the leading leaf (bank B) of a pair opens every edge
+//! and the trailing leaf (bank A) closes it, the meterset between them being the fluence. A
+//! leaf waits on an edge for a step of the fluence and crosses pixels at its top speed. Pairs that would interdigitate,
+//! exceed the span or close below the minimum gap are held back: delaying both leaves of a
+//! pair from an edge on keeps its fluence (Kamath et al.), while holding the trailing leaf for
+//! the gap adds fluence just behind it (the pair still opens and closes on the raster edges).
+//! Control points fall on every arrival at and departure from an edge.
+//!
+//! The control points are delivered back on the map's raster, with the leaf transmission and
+//! dosimetric leaf gap of the MLC, to report the reconstruction error.
+
+use crate::dose::beam::Fluence;
+use crate::dose::delivery::{accumulate, ControlPoint, Mlc};
+use crate::error::{Error, Result};
+use crate::machine::LeafLimits;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SequencingOptions {
+    /// Intensity levels of step-and-shoot between zero and the largest fluence.
+    pub levels: usize,
+    /// Leaf rules of the machine, see [`crate::machine::MlcGeometry`]: the span, the minimum
+    /// gap, interdigitation and, for sliding window, the leaf speed.
+    pub leaves: LeafLimits,
+    /// Sliding window dose rate (MU/min); the leaf speed over it is the leaf travel per MU.
+    pub dose_rate: f64,
+}
+
+impl SequencingOptions {
+    /// Sliding window leaf travel (mm) per MU.
+    fn leaf_travel_per_mu(&self) -> f64 {
+        60.0 * self.leaves.max_leaf_speed / self.dose_rate
+    }
+}
+
+impl Default for SequencingOptions {
+    fn default() -> Self {
+        SequencingOptions {
+            levels: 10,
+            leaves: LeafLimits {
+                min_gap: 0.0,
+                ..LeafLimits::default()
+            },
+            dose_rate: 600.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconstructionError {
+    /// MU
+    pub rms: f64,
+    /// MU
+    pub max: f64,
+    /// Root mean square error relative to the largest fluence.
+    pub relative_rms: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeafSequence {
+    pub control_points: Vec<ControlPoint>,
+    /// MU
+    pub meterset: f64,
+    /// Step-and-shoot segments, or sliding window control point intervals.
+    pub segments: usize,
+    /// Segments in which a closed pair could only park breaking the leaf span.
+    pub span_violations: usize,
+    pub error: ReconstructionError,
+}
+
+/// Pixel edges (mm) along `x` and the profile of every leaf pair of `mlc` in `fluence`.
+fn profiles(fluence: &Fluence, mlc: &Mlc) -> (Vec<f64>, Vec<Vec<f64>>) {
+    let [nx, _] = fluence.dims;
+    let sx = fluence.spacing[0];
+    let edges = (0..=nx)
+        .map(|i| fluence.origin[0] + (i as f64 - 0.5) * sx)
+        .collect();
+    let profiles = mlc
+        .boundaries
+        .windows(2)
+        .map(|b| {
+            let y = 0.5 * (b[0] + b[1]);
+            (0..nx)
+                .map(|i| fluence.at(fluence.position(i, 0).0, y).max(0.0))
+                .collect()
+        })
+        .collect();
+    (edges, profiles)
+}
+
+fn check(fluence: &Fluence, mlc: &Mlc, options: &SequencingOptions) -> Result<()> {
+    if fluence.values.is_empty() || mlc.pairs() == 0 {
+        return Err(Error::InvalidArgument(
+            "sequencing needs a fluence map and leaf pairs".to_string(),
+        ));
+    }
+    if options.levels == 0
+        || options.leaves.max_span <= 0.0
+        || options.leaves.min_gap < 0.0
+        || options.leaves.max_leaf_speed <= 0.0
+        || options.dose_rate <= 0.0
+    {
+        return Err(Error::InvalidArgument(format!(
+            "invalid sequencing options {:?}",
+            options
+        )));
+    }
+    Ok(())
+}
+
+/// Jaws on the raster of `fluence` and the leaf pairs.
+fn jaws(fluence: &Fluence, mlc: &Mlc) -> [f64; 4] {
+    let [sx, sy] = fluence.spacing;
+    let [nx, ny] = fluence.dims;
+    [
+        fluence.origin[0] - 0.5 * sx,
+        fluence.origin[0] + (nx as f64 - 0.5) * sx,
+        (fluence.origin[1] - 0.5 * sy).max(mlc.boundaries[0]),
+        (fluence.origin[1] + (ny as f64 - 0.5) * sy).min(mlc.boundaries[mlc.pairs()]),
+    ]
+}
+
+/// Delivers `points` back on the raster of `fluence` and compares.
+fn reconstruct(
+    fluence: &Fluence,
+    mlc: &Mlc,
+    points: &[ControlPoint],
+    meterset: f64,
+) -> Result<ReconstructionError> {
+    let mut delivered = Fluence::new(fluence.origin, fluence.spacing, fluence.dims, 0.0);
+    accumulate(&mut delivered, mlc, points, meterset)?;
+    let n = fluence.values.len() as f64;
+    let (mut sum, mut max) = (0.0, 0.0f64);
+    for (d, f) in delivered.values.iter().zip(&fluence.values) {
+        let e = d - f.max(0.0);
+        sum += e * e;
+        max = max.max(e.abs());
+    }
+    let rms = (sum / n).sqrt();
+    let peak = fluence.values.iter().cloned().fold(0.0, f64::max);
+    Ok(ReconstructionError {
+        rms,
+        max,
+        relative_rms: if peak > 0.0 { rms / peak } else { 0.0 },
+    })
+}
+
+/// A step-and-shoot segment being filled pair by pair.
+struct Segment {
+    leaves: Vec<Option<[f64; 2]>>,
+    /// Extent of bank A and bank B over the open pairs.
+    bank_a: [f64; 2],
+    bank_b: [f64; 2],
+    last_open: Option<[f64; 2]>,
+}
+
+impl Segment {
+    fn new(pairs: usize) -> Self {
+        Segment {
+            leaves: vec![None; pairs],
+            bank_a: [f64::INFINITY, f64::NEG_INFINITY],
+            bank_b: [f64::INFINITY, f64::NEG_INFINITY],
+            last_open: None,
+        }
+    }
+
+    fn admits(&self, gap: [f64; 2], options: &SequencingOptions) -> bool {
+        let span = |bank: [f64; 2], x: f64| bank[1].max(x) - bank[0].min(x);
+        let apart = match self.last_open {
+            Some(o) => gap[0] > o[1] || o[0] > gap[1],
+            None => false,
+        };
+        (options.leaves.interdigitation || !apart)
+            && span(self.bank_a, gap[0]) <= options.leaves.max_span + 1e-9
+            && span(self.bank_b, gap[1]) <= options.leaves.max_span + 1e-9
+    }
+
+    fn open(&mut self, pair: usize, gap: [f64; 2]) {
+        self.leaves[pair] = Some(gap);
+        self.bank_a = [self.bank_a[0].min(gap[0]), self.bank_a[1].max(gap[0])];
+        self.bank_b = [self.bank_b[0].min(gap[1]), self.bank_b[1].max(gap[1])];
+        self.last_open = Some(gap);
+    }
+
+    /// Leaf positions with the closed pairs parked between their open neighbours, near the
+    /// middle of the field and within the leaf span where possible; the second value tells
+    /// whether the span holds.
+    fn park(&self, options: &SequencingOptions) -> (Vec<[f64; 2]>, bool) {
+        let open: Vec<(usize, [f64; 2])> = self
+            .leaves
+            .iter()
+            .enumerate()
+            .filter_map(|(n, l)| l.map(|g| (n, g)))
+            .collect();
+        let span = options.leaves.max_span;
+        // Parking positions keeping both banks within the span.
+        let allowed = [self.bank_b[1] - span, self.bank_a[0] + span];
+        let target = 0.5 * (self.bank_a[1] + self.bank_b[0]);
+        let mut spans_hold = true;
+        let leaves = self
+            .leaves
+            .iter()
+            .enumerate()
+            .map(|(n, l)| {
+                if let Some(g) = l {
+                    return *g;
+                }
+                let above = open.iter().rev().find(|(m, _)| *m < n).map(|o| o.1);
+                let below = open.iter().find(|(m, _)| *m > n).map(|o| o.1);
+                let mut window = [f64::NEG_INFINITY, f64::INFINITY];
+                if !options.leaves.interdigitation {
+                    for g in above.iter().chain(below.iter()) {
+                        window = [window[0].max(g[0]), window[1].min(g[1])];
+                    }
+                }
+                let lo = window[0].max(allowed[0]);
+                let hi = window[1].min(allowed[1]);
+                let p = if lo <= hi {
+                    target.clamp(lo, hi)
+                } else {
+                    spans_hold = false;
+                    target.clamp(window[0], window[1].max(window[0]))
+                };
+                [p, p]
+            })
+            .collect();
+        (leaves, spans_hold)
+    }
+}
+
+/// `[left, right)` edge indices of the unit segments of an integer profile, in sweep order.
+fn sweep(levels: &[i64]) -> Vec<(usize, usize)> {
+    let (mut lefts, mut rights) = (Vec::new(), Vec::new());
+    let mut previous = 0;
+    for (i, l) in levels.iter().chain(std::iter::once(&0)).enumerate() {
+        let step = l - previous;
+        for _ in 0..step.max(0) {
+            lefts.push(i);
+        }
+        for _ in 0..(-step).max(0) {
+            rights.push(i);
+        }
+        previous = *l;
+    }
+    lefts.into_iter().zip(rights).collect()
+}
+
+/// Step-and-shoot control points delivering `fluence` (MU) with `mlc`.
+pub fn step_and_shoot(
+    fluence: &Fluence,
+    mlc: &Mlc,
+    options: &SequencingOptions,
+) -> Result<LeafSequence> {
+    check(fluence, mlc, options)?;
+    let (edges, profiles) = profiles(fluence, mlc);
+    let peak = profiles.iter().flatten().cloned().fold(0.0, f64::max);
+    if peak <= 0.0 {
+        return Err(Error::InvalidArgument(
+            "nothing to sequence in an empty fluence map".to_string(),
+        ));
+    }
+    let unit = peak / options.levels as f64;
+    let sweeps: Vec<Vec<(usize, usize)>> = profiles
+        .iter()
+        .map(|p| {
+            let levels: Vec<i64> = p.iter().map(|v| (v / unit).round() as i64).collect();
+            sweep(&levels)
+        })
+        .collect();
+    let count = sweeps.iter().map(Vec::len).max().unwrap_or(0);
+    // Leaf positions of every segment with its number of units.
+    let mut shapes: Vec<(Vec<[f64; 2]>, usize)> = Vec::new();
+    let mut span_violations = 0;
+    for k in 0..count {
+        let mut segments: Vec<Segment> = Vec::new();
+        for (pair, sweep) in sweeps.iter().enumerate() {
+            let (l, r) = match sweep.get(k) {
+                Some(s) => *s,
+                None => continue,
+            };
+            let (mut a, mut b) = (edges[l], edges[r]);
+            if b - a < options.leaves.min_gap {
+                let center = 0.5 * (a + b);
+                a = center - 0.5 * options.leaves.min_gap;
+                b = center + 0.5 * options.leaves.min_gap;
+            }
+            let gap = [a, b];
+            let fits = segments.iter().position(|s| s.admits(gap, options));
+            let segment = match fits {
+                Some(n) => &mut segments[n],
+                None => {
+                    segments.push(Segment::new(mlc.pairs()));
+                    segments.last_mut().unwrap()
+                }
+            };
+            segment.open(pair, gap);
+        }
+        for segment in segments {
+            let (leaves, spans_hold) = segment.park(options);
+            if !spans_hold {
+                span_violations += 1;
+            }
+            match shapes.last_mut() {
+                Some((last, units)) if *last == leaves => *units += 1,
+                _ => shapes.push((leaves, 1)),
+            }
+        }
+    }
+    let total: usize = shapes.iter().map(|s| s.1).sum();
+    let jaws = jaws(fluence, mlc);
+    let mut points = Vec::with_capacity(2 * shapes.len());
+    let mut cumulative = 0;
+    for (leaves, units) in &shapes {
+        for end in [false, true] {
+            if end {
+                cumulative += units;
+            }
+            points.push(ControlPoint {
+                weight: cumulative as f64 / total as f64,
+                jaws: Some(jaws),
+                leaves: Some(leaves.clone()),
+            });
+        }
+    }
+    let meterset = total as f64 * unit;
+    let error = reconstruct(fluence, mlc, &points, meterset)?;
+    Ok(LeafSequence {
+        control_points: points,
+        meterset,
+        segments: shapes.len(),
+        span_violations,
+        error,
+    })
+}
+
+/// Meterset (MU) at which the leading and trailing leaf of every pair arrive at and depart
+/// from every edge: knot `2 i` is the arrival at edge `i`, knot `2 i + 1` the departure.
+struct Trajectories {
+    leading: Vec<Vec<f64>>,
+    trailing: Vec<Vec<f64>>,
+}
+
+fn hold(times: &mut [f64], from: usize, delay: f64) {
+    for t in times[from..].iter_mut() {
+        *t += delay;
+    }
+}
+
+impl Trajectories {
+    /// Delays both leaves of `pair` by `delay` from their arrival at the edge of `knot` on,
+    /// which keeps its fluence.
+    fn delay(&mut self, pair: usize, knot: usize, delay: f64) {
+        let from = knot - knot % 2;
+        hold(&mut self.leading[pair], from, delay);
+        hold(&mut self.trailing[pair], from, delay);
+    }
+
+    /// One pass over the rules, fixing every violation; whether any was found.
+    fn enforce(&mut self, options: &SequencingOptions, spacing: f64) -> bool {
+        let pairs = self.leading.len();
+        let knots = self.leading[0].len();
+        let tolerance = 1e-9;
+        let mut found = false;
+        let gap = 2 * (options.leaves.min_gap / spacing - 1e-9).ceil().max(0.0) as usize;
+        let span = 2 * (options.leaves.max_span / spacing + 1e-9).floor() as usize;
+        for a in 0..pairs {
+            // The trailing leaf reaches a knot once the leading leaf is the gap beyond; the
+            // leaves closing on the last edge are exempt.
+            for k in 0..knots.saturating_sub(gap) {
+                let delay = self.leading[a][k + gap] - self.trailing[a][k];
+                if gap > 0 && delay > tolerance {
+                    hold(&mut self.trailing[a], k, delay);
+                    hold(&mut self.leading[a], k + gap + 1, delay);
+                    found = true;
+                }
+            }
+            for b in 0..pairs {
+                if a == b {
+                    continue;
+                }
+                for k in 0..knots {
+                    // The trailing leaf of a reaches a knot after the leading leaf of its
+                    // neighbour b.
+                    if !options.leaves.interdigitation && (a + 1 == b || b + 1 == a) {
+                        let delay = self.leading[b][k] - self.trailing[a][k];
+                        if delay > tolerance {
+                            self.delay(a, k, delay);
+                            found = true;
+                        }
+                    }
+                    // The leaves of b are within the span behind those of a.
+                    if k >= span {
+                        let delay = (self.leading[b][k - span] - self.leading[a][k])
+                            .max(self.trailing[b][k - span] - self.trailing[a][k]);
+                        if delay > tolerance {
+                            self.delay(a, k, delay);
+                            found = true;
+                        }
+                    }
+                }
+            }
+        }
+        found
+    }
+}
+
+/// Position (mm) at meterset `t` of a leaf reaching the knots of `edges` at `times`.
+fn position(edges: &[f64], times: &[f64], t: f64) -> f64 {
+    let n = times.partition_point(|s| *s <= t);
+    if n == 0 {
+        return edges[0];
+    }
+    if n == times.len() {
+        return edges[edges.len() - 1];
+    }
+    let (x0, x1) = (edges[(n - 1) / 2], edges[n / 2]);
+    x0 + (t - times[n - 1]) / (times[n] - times[n - 1]) * (x1 - x0)
+}
+
+/// Sliding window control points delivering `fluence` (MU) with `mlc`.
+pub fn sliding_window(
+    fluence: &Fluence,
+    mlc: &Mlc,
+    options: &SequencingOptions,
+) -> Result<LeafSequence> {
+    check(fluence, mlc, options)?;
+    let (edges, profiles) = profiles(fluence, mlc);
+    if profiles.iter().flatten().all(|v| *v <= 0.0) {
+        return Err(Error::InvalidArgument(
+            "nothing to sequence in an empty fluence map".to_string(),
+        ));
+    }
+    let spacing = fluence.spacing[0];
+    let travel = spacing / options.leaf_travel_per_mu();
+    let mut trajectories = Trajectories {
+        leading: Vec::with_capacity(profiles.len()),
+        trailing: Vec::with_capacity(profiles.len()),
+    };
+    for profile in &profiles {
+        let knots = 2 * edges.len();
+        let (mut leading, mut trailing) = (vec![0.0; knots], vec![0.0; knots]);
+        let mut previous = 0.0;
+        for (i, v) in profile.iter().chain(std::iter::once(&0.0)).enumerate() {
+            // A leaf waits on the edge for a step of the fluence and crosses the next pixel
+            // at its top speed.
+            let step = v - previous;
+            leading[2 * i + 1] = leading[2 * i] + (-step).max(0.0);
+            trailing[2 * i + 1] = trailing[2 * i] + step.max(0.0);
+            if 2 * i + 2 < knots {
+                leading[2 * i + 2] = leading[2 * i + 1] + travel;
+                trailing[2 * i + 2] = trailing[2 * i + 1] + travel;
+            }
+            previous = *v;
+        }
+        trajectories.leading.push(leading);
+        trajectories.trailing.push(trailing);
+    }
+    let limit = 10 * profiles.len() * edges.len() + 100;
+    let mut passes = 0;
+    while trajectories.enforce(options, spacing) {
+        passes += 1;
+        if passes > limit {
+            return Err(Error::InvalidArgument(
+                "the leaf rules cannot be met by holding leaves back".to_string(),
+            ));
+        }
+    }
+    let mut times: Vec<f64> = trajectories
+        .leading
+        .iter()
+        .chain(&trajectories.trailing)
+        .flatten()
+        .cloned()
+        .collect();
+    times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    times.dedup_by(|a, b| (*a - *b).abs() < 1e-9);
+    let meterset = times[times.len() - 1];
+    let jaws = jaws(fluence, mlc);
+    let points: Vec<ControlPoint> = times
+        .iter()
+        .map(|t| ControlPoint {
+            weight: t / meterset,
+            jaws: Some(jaws),
+            leaves: Some(
+                trajectories
+                    .trailing
+                    .iter()
+                    .zip(&trajectories.leading)
+                    .map(|(a, b)| [position(&edges, a, *t), position(&edges, b, *t)])
+                    .collect(),
+            ),
+        })
+        .collect();
+    let error = reconstruct(fluence, mlc, &points, meterset)?;
+    Ok(LeafSequence {
+        segments: points.len() - 1,
+        control_points: points,
+        meterset,
+        span_violations: 0,
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dose::beam::Fluence;
+    use crate::dose::commissioning::MlcData;
+    use crate::dose::delivery::Mlc;
+    use crate::machine::LeafLimits;
+    use crate::opt::sequencing::{sliding_window, step_and_shoot, sweep, SequencingOptions};
+
+    fn mlc() -> Mlc {
+        Mlc::uniform(
+            8,
+            5.0,
+            MlcData {
+                transmission: 0.0,
+                dlg: 0.0,
+            },
+        )
+    }
+
+    /// A 40 x 40 mm map on 5 mm pixels: a ramp in the lower half, a peak in the upper half.
+    fn map() -> Fluence {
+        let mut f = Fluence::new([-17.5, -17.5], [5.0, 5.0], [8, 8], 0.0);
+        for j in 0..8 {
+            for i in 0..8 {
+                let v = if j < 4 {
+                    10.0 * i as f64
+                } else {
+                    [0.0, 10.0, 30.0, 70.0, 70.0, 30.0, 10.0, 0.0][i]
+                };
+                f.set(i, j, v);
+            }
+        }
+        f
+    }
+
+    fn leaves_of(points: &[crate::dose::delivery::ControlPoint]) -> Vec<&Vec<[f64; 2]>> {
+        points.iter().map(|p| p.leaves.as_ref().unwrap()).collect()
+    }
+
+    #[test]
+    fn unit_sweep() {
+        assert_eq!(sweep(&[1, 2, 1]), vec![(0, 2), (1, 3)]);
+        assert_eq!(sweep(&[0, 2, 0, 1]), vec![(1, 2), (1, 2), (3, 4)]);
+        assert!(sweep(&[0, 0]).is_empty());
+    }
+
+    #[test]
+    fn step_and_shoot_sequencing() {
+        let options = SequencingOptions {
+            levels: 7,
+            ..SequencingOptions::default()
+        };
+        let sequence = step_and_shoot(&map(), &mlc(), &options).unwrap();
+        // The levels hit the map exactly.
+        assert!(sequence.error.max < 1e-9, "{:?}", sequence.error);
+        assert!((sequence.meterset - 70.0).abs() < 1e-9);
+        assert_eq!(sequence.control_points.len(), 2 * sequence.segments);
+
+        let strict = SequencingOptions {
+            leaves: LeafLimits {
+                interdigitation: false,
+                min_gap: 10.0,
+                max_span: 20.0,
+                ..options.leaves
+            },
+            ..options
+        };
+        let sequence = step_and_shoot(&map(), &mlc(), &strict).unwrap();
+        assert_eq!(sequence.span_violations, 0);
+        for leaves in leaves_of(&sequence.control_points) {
+            for pair in leaves.windows(2) {
+                assert!(pair[0][0] <= pair[1][1] && pair[1][0] <= pair[0][1]);
+            }
+            for l in leaves {
+                assert!(l[1] == l[0] || l[1] - l[0] >= 10.0 - 1e-9);
+            }
+            for bank in 0..2 {
+                let (lo, hi) = leaves
+                    .iter()
+                    .fold((f64::INFINITY, f64::NEG_INFINITY), |a, l| {
+                        (a.0.min(l[bank]), a.1.max(l[bank]))
+                    });
+                assert!(hi - lo <= 20.0 + 1e-9);
+            }
+        }
+        // Widened gaps cost accuracy.
+        assert!(sequence.error.max > 0.0);
+        assert!(sequence.error.relative_rms < 0.25, "{:?}", sequence.error);
+        assert!(step_and_shoot(
+            &Fluence::new([0.0, 0.0], [5.0, 5.0], [2, 2], 0.0),
+            &mlc(),
+            &options
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn sliding_window_sequencing() {
+        let options = SequencingOptions::default();
+        let sequence = sliding_window(&map(), &mlc(), &options).unwrap();
+        assert!(sequence.error.relative_rms < 1e-6, "{:?}", sequence.error);
+        let points = &sequence.control_points;
+        assert!(points.windows(2).all(|p| p[1].weight > p[0].weight));
+        // Leaves only move forward and no faster than allowed.
+        for (a, b) in leaves_of(points)
+            .windows(2)
+            .map(|w| (w[0], w[1]))
+            .zip(points.windows(2))
+        {
+            let mu = (b[1].weight - b[0].weight) * sequence.meterset;
+            for (p, q) in a.0.iter().zip(a.1) {
+                for bank in 0..2 {
+                    assert!(q[bank] >= p[bank] - 1e-9);
+                    assert!(q[bank] - p[bank] <= 2.5 * mu + 1e-6);
+                }
+            }
+        }
+
+        let strict = SequencingOptions {
+            leaves: LeafLimits {
+                interdigitation: false,
+                max_span: 20.0,
+                ..options.leaves
+            },
+            ..options
+        };
+        let sequence = sliding_window(&map(), &mlc(), &strict).unwrap();
+        assert!(sequence.error.relative_rms < 1e-6, "{:?}", sequence.error);
+        for leaves in leaves_of(&sequence.control_points) {
+            for pair in leaves.windows(2) {
+                assert!(pair[0][0] <= pair[1][1] + 1e-9 && pair[1][0] <= pair[0][1] + 1e-9);
+            }
+        }
+        let gapped = SequencingOptions {
+            leaves: LeafLimits {
+                min_gap: 5.0,
+                ..options.leaves
+            },
+            ..options
+        };
+        let sequence = sliding_window(&map(), &mlc(), &gapped).unwrap();
+        assert!(sequence.error.max > 0.0);
+    }
+}
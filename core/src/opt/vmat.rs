@@ -0,0 +1,696 @@
+//! VMAT arc sequencing by progressive resolution.
+//!
+//! The arc is sampled at the gantry angles of the photon beams of a [`Dij`] matrix, one
+//! aperture per control point, following Otto's progressive sampling: apertures are first
+//! placed by column generation on a coarse subset of the angles, then every level halves the
+//! spacing, inserting the apertures in between and splitting the weights of the sectors. Leaves
+//! can only travel as far between neighbouring control points as the leaf speed allows while
+//! the gantry turns at full speed, so every aperture is priced (see [`crate::opt::dao`]) among
+//! the leaf positions within reach of its placed neighbours; after every level the apertures
+//! are re-priced one by one within reach and kept when the re-optimized weights lower the
+//! objective. The gantry slows down where the dose rate or the leaves would otherwise exceed
+//! their limits, which sets the dose rate and gantry speed of the control point sequence.
+
+use crate::dose::delivery::{ControlPoint, Mlc};
+use crate::dose::dij::Dij;
+use crate::error::{Error, Result};
+use crate::machine::ArcLimits;
+use crate::opt::dao::{expand, optimize_weights, price, BeamGrid, Segment};
+use crate::opt::fmo::{FmoOptions, FmoProblem};
+use crate::opt::objective::PlanObjectives;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct VmatOptions {
+    pub limits: ArcLimits,
+    /// Halvings of the control point spacing after the coarse sampling.
+    pub levels: usize,
+    /// Solver of the control point weights; its bounds apply to every weight.
+    pub weights: FmoOptions,
+}
+
+impl Default for VmatOptions {
+    fn default() -> Self {
+        VmatOptions {
+            limits: ArcLimits::default(),
+            levels: 3,
+            weights: FmoOptions::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArcControlPoint {
+    /// Degrees.
+    pub gantry: f64,
+    pub point: ControlPoint,
+    /// MU/min from this control point to the next; zero at the last.
+    pub dose_rate: f64,
+    /// Degrees per second from this control point to the next; zero at the last.
+    pub gantry_speed: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmatResult {
+    /// Gantry angle (degrees) of every control point.
+    pub gantry: Vec<f64>,
+    /// Aperture and meterset (MU) of every control point, in arc order.
+    pub segments: Vec<Segment>,
+    /// Beamlet weights delivered by the segments.
+    pub weights: Vec<f64>,
+    /// Objective value, with the initial constraint penalty, before the first and after every
+    /// placed aperture and level.
+    pub history: Vec<f64>,
+}
+
+impl VmatResult {
+    /// MU of the arc.
+    pub fn meterset(&self) -> f64 {
+        self.segments.iter().map(|s| s.weight).sum()
+    }
+
+    /// MU delivered between every control point and the next: half the weight of either,
+    /// the first and last sectors also taking the outer half of the end points.
+    fn sectors(&self) -> Vec<f64> {
+        let n = self.segments.len();
+        (0..n - 1)
+            .map(|k| {
+                let mut mu = 0.5 * (self.segments[k].weight + self.segments[k + 1].weight);
+                if k == 0 {
+                    mu += 0.5 * self.segments[0].weight;
+                }
+                if k == n - 2 {
+                    mu += 0.5 * self.segments[n - 1].weight;
+                }
+                mu
+            })
+            .collect()
+    }
+
+    /// Seconds between every control point and the next: the gantry at full speed unless
+    /// the dose rate or a leaf would be too fast.
+    fn durations(&self, limits: &ArcLimits) -> Vec<f64> {
+        self.sectors()
+            .iter()
+            .enumerate()
+            .map(|(k, mu)| {
+                let turn = (self.gantry[k + 1] - self.gantry[k]).abs();
+                let travel = self.segments[k]
+                    .leaves
+                    .iter()
+                    .zip(&self.segments[k + 1].leaves)
+                    .map(|((_, a), (_, b))| (a[0] - b[0]).abs().max((a[1] - b[1]).abs()))
+                    .fold(0.0, f64::max);
+                (turn / limits.max_gantry_speed)
+                    .max(60.0 * mu / limits.max_dose_rate)
+                    .max(travel / limits.leaves.max_leaf_speed)
+            })
+            .collect()
+    }
+
+    /// Seconds to deliver the arc within `limits`.
+    pub fn delivery_time(&self, limits: &ArcLimits) -> f64 {
+        self.durations(limits).iter().sum()
+    }
+
+    /// The control point sequence on `mlc` with the cumulative meterset weight normalized
+    /// to one, the jaws around every open aperture of the arc, and the dose rate and gantry
+    /// speed of every sector within `limits`.
+    pub fn control_points(&self, mlc: &Mlc, limits: &ArcLimits) -> Result<Vec<ArcControlPoint>> {
+        let total = self.meterset();
+        let jaws = self.segments.iter().map(Segment::jaws).fold(
+            [
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+            ],
+            |a, j| {
+                [
+                    a[0].min(j[0]),
+                    a[1].max(j[1]),
+                    a[2].min(j[2]),
+                    a[3].max(j[3]),
+                ]
+            },
+        );
+        if total <= 0.0 || jaws[0] >= jaws[1] {
+            return Err(Error::InvalidArgument(
+                "the arc delivers no dose".to_string(),
+            ));
+        }
+        let sectors = self.sectors();
+        let durations = self.durations(limits);
+        let mut cumulative = 0.0;
+        let mut points = Vec::with_capacity(self.segments.len());
+        for (k, segment) in self.segments.iter().enumerate() {
+            let (dose_rate, gantry_speed) = match durations.get(k) {
+                Some(t) => (
+                    60.0 * sectors[k] / t,
+                    (self.gantry[k + 1] - self.gantry[k]).abs() / t,
+                ),
+                None => (0.0, 0.0),
+            };
+            points.push(ArcControlPoint {
+                gantry: self.gantry[k],
+                point: ControlPoint {
+                    weight: cumulative / total,
+                    jaws: Some(jaws),
+                    leaves: Some(segment.leaves_on(mlc)),
+                },
+                dose_rate,
+                gantry_speed,
+            });
+            if k < sectors.len() {
+                cumulative += sectors[k];
+            }
+        }
+        Ok(points)
+    }
+}
+
+/// The apertures placed so far at every control point.
+struct Arc<'a> {
+    problem: FmoProblem<'a>,
+    grids: Vec<BeamGrid>,
+    columns: usize,
+    /// Leaf columns a leaf can travel per control point.
+    reach: usize,
+    min_columns: usize,
+    options: &'a VmatOptions,
+    runs: Vec<Option<Vec<(usize, usize)>>>,
+    apertures: Vec<Vec<usize>>,
+    weights: Vec<f64>,
+}
+
+impl<'a> Arc<'a> {
+    fn evaluate(&self) -> Result<(f64, Vec<f64>)> {
+        self.problem.evaluate(
+            &expand(&self.apertures, &self.weights, self.columns),
+            self.options.weights.penalty,
+        )
+    }
+
+    /// Cheapest aperture at control point `k` within reach of its nearest placed neighbours.
+    fn price(&self, k: usize, gradient: &[f64]) -> Result<(f64, Vec<(usize, usize)>)> {
+        let left = (0..k).rev().find(|j| self.runs[*j].is_some());
+        let right = (k + 1..self.runs.len()).find(|j| self.runs[*j].is_some());
+        let neighbours: Vec<(&Vec<(usize, usize)>, usize)> = left
+            .into_iter()
+            .chain(right)
+            .map(|j| {
+                (
+                    self.runs[j].as_ref().unwrap(),
+                    self.reach * (j.max(k) - j.min(k)),
+                )
+            })
+            .collect();
+        let feasible = |row: usize, (l, r): (usize, usize)| {
+            neighbours.iter().all(|(runs, reach)| {
+                let (nl, nr) = runs[row];
+                l.max(nl) - l.min(nl) <= *reach && r.max(nr) - r.min(nr) <= *reach
+            })
+        };
+        let (p, runs) = price(
+            &self.grids[k],
+            gradient,
+            self.min_columns,
+            self.options.limits.leaves.interdigitation,
+            &feasible,
+        );
+        if !p.is_finite() {
+            return Err(Error::InvalidArgument(format!(
+                "no aperture at control point {} within reach of the leaf speed limit",
+                k
+            )));
+        }
+        Ok((p, runs))
+    }
+
+    fn place(&mut self, k: usize, runs: Vec<(usize, usize)>) {
+        self.apertures[k] = self.grids[k].bixels(&runs);
+        self.runs[k] = Some(runs);
+    }
+
+    fn reoptimize(&mut self) -> Result<f64> {
+        let weights = std::mem::take(&mut self.weights);
+        let (weights, value) = optimize_weights(
+            &self.problem,
+            &self.apertures,
+            weights,
+            self.columns,
+            &self.options.weights,
+        )?;
+        self.weights = weights;
+        Ok(value)
+    }
+
+    /// Re-prices every placed aperture within reach, keeping the new shapes when the
+    /// re-optimized weights lower the objective.
+    fn refine(&mut self, value: f64) -> Result<f64> {
+        let (_, gradient) = self.evaluate()?;
+        let saved = (
+            self.runs.clone(),
+            self.apertures.clone(),
+            self.weights.clone(),
+        );
+        let mut changed = false;
+        for k in 0..self.runs.len() {
+            if self.runs[k].is_none() {
+                continue;
+            }
+            let current: f64 = self.apertures[k].iter().map(|c| gradient[*c]).sum();
+            let (p, runs) = self.price(k, &gradient)?;
+            if p < current - 1e-12 * value.abs().max(1e-300) {
+                self.place(k, runs);
+                changed = true;
+            }
+        }
+        if !changed {
+            return Ok(value);
+        }
+        let refined = self.reoptimize()?;
+        if refined < value {
+            return Ok(refined);
+        }
+        self.runs = saved.0;
+        self.apertures = saved.1;
+        self.weights = saved.2;
+        Ok(value)
+    }
+}
+
+/// Optimizes an arc through the photon beams of `dij`, beam `n` at gantry angle `gantry[n]`
+/// (degrees, monotonic), for `plan`; see the module documentation.
+pub fn optimize(
+    dij: &Dij,
+    plan: &PlanObjectives,
+    gantry: &[f64],
+    options: &VmatOptions,
+) -> Result<VmatResult> {
+    let limits = &options.limits;
+    if limits.max_gantry_speed <= 0.0
+        || limits.leaves.max_leaf_speed <= 0.0
+        || limits.max_dose_rate <= 0.0
+    {
+        return Err(Error::InvalidArgument(format!(
+            "arc limits must be positive, got {:?}",
+            limits
+        )));
+    }
+    if options.weights.lower < 0.0 || options.weights.lower > options.weights.upper {
+        return Err(Error::InvalidArgument(format!(
+            "invalid control point weight bounds [{}, {}]",
+            options.weights.lower, options.weights.upper
+        )));
+    }
+    if limits.leaves.min_gap < 0.0 {
+        return Err(Error::InvalidArgument(format!(
+            "negative minimum leaf gap {}",
+            limits.leaves.min_gap
+        )));
+    }
+    let grids = BeamGrid::all(dij, true);
+    if grids.len() != gantry.len() || gantry.len() < 2 {
+        return Err(Error::InvalidArgument(format!(
+            "an arc of {} photon beams needs as many gantry angles, at least two, got {}",
+            grids.len(),
+            gantry.len()
+        )));
+    }
+    let steps: Vec<f64> = gantry.windows(2).map(|g| g[1] - g[0]).collect();
+    if !(steps.iter().all(|s| *s > 0.0) || steps.iter().all(|s| *s < 0.0)) {
+        return Err(Error::InvalidArgument(
+            "gantry angles of an arc must be strictly monotonic".to_string(),
+        ));
+    }
+    let spacing = steps.iter().map(|s| s.abs()).fold(f64::INFINITY, f64::min);
+    let size = dij.bixel_size();
+    let n = gantry.len();
+    let mut arc = Arc {
+        problem: FmoProblem::new(dij, plan)?,
+        columns: dij.column_count(),
+        reach: (limits.leaves.max_leaf_speed * spacing / limits.max_gantry_speed / size + 1e-9)
+            .floor() as usize,
+        min_columns: (limits.leaves.min_gap / size - 1e-9).ceil() as usize,
+        options,
+        runs: vec![None; n],
+        apertures: vec![Vec::new(); n],
+        weights: vec![options.weights.lower; n],
+        grids,
+    };
+    let mut history = vec![arc.evaluate()?.0];
+
+    // Coarse sampling by column generation.
+    let step = 1usize << options.levels.min(usize::BITS as usize - 1);
+    let mut coarse: Vec<usize> = (0..n).step_by(step).collect();
+    if coarse[coarse.len() - 1] != n - 1 {
+        coarse.push(n - 1);
+    }
+    while !coarse.is_empty() {
+        let (_, gradient) = arc.evaluate()?;
+        let priced = coarse
+            .iter()
+            .map(|k| arc.price(*k, &gradient))
+            .collect::<Result<Vec<_>>>()?;
+        let c = (0..priced.len())
+            .min_by(|a, b| priced[*a].0.partial_cmp(&priced[*b].0).unwrap())
+            .unwrap();
+        let k = coarse.remove(c);
+        arc.place(k, priced.into_iter().nth(c).unwrap().1);
+        history.push(arc.reoptimize()?);
+    }
+    let refined = arc.refine(history[history.len() - 1])?;
+    history.push(refined);
+
+    // Progressive refinement of the spacing.
+    for level in (0..options.levels).rev() {
+        let step = 1 << level;
+        let new: Vec<usize> = (0..n)
+            .step_by(step)
+            .filter(|k| arc.runs[*k].is_none())
+            .collect();
+        if new.is_empty() {
+            continue;
+        }
+        for w in arc.weights.iter_mut() {
+            *w *= 0.5;
+        }
+        let (_, gradient) = arc.evaluate()?;
+        for k in new {
+            let (_, runs) = arc.price(k, &gradient)?;
+            arc.place(k, runs);
+            let placed: Vec<f64> = [k.checked_sub(step), Some(k + step)]
+                .iter()
+                .flatten()
+                .copied()
+                .filter(|j| *j < n && arc.runs[*j].is_some())
+                .map(|j| arc.weights[j])
+                .collect();
+            arc.weights[k] = placed.iter().sum::<f64>() / placed.len().max(1) as f64;
+        }
+        let value = arc.reoptimize()?;
+        history.push(arc.refine(value)?);
+    }
+
+    let segments = arc
+        .grids
+        .iter()
+        .zip(&arc.runs)
+        .zip(&arc.weights)
+        .map(|((grid, runs), w)| grid.segment(runs.as_ref().unwrap(), *w))
+        .collect();
+    Ok(VmatResult {
+        gantry: gantry.to_vec(),
+        segments,
+        weights: expand(&arc.apertures, &arc.weights, arc.columns),
+        history,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::commissioning::MlcData;
+    use crate::dose::delivery::Mlc;
+    use crate::dose::dij::{Beamlet, Dij};
+    use crate::grid::GridGeometry;
+    use crate::machine::{ArcLimits, LeafLimits};
+    use crate::opt::dao::Segment;
+    use crate::opt::fmo::FmoOptions;
+    use crate::opt::objective::{DoseObjective, ObjectiveKind, PlanObjectives};
+    use crate::opt::vmat::{optimize, VmatOptions, VmatResult};
+
+    /// Nine beams of two rows of four bixels over a row of eight voxels, the target on the
+    /// first five and an organ at risk on the last three; the bixels reach voxels shifting
+    /// with the beam.
+    fn dij() -> Dij {
+        let geometry = GridGeometry::new([8, 1, 1], Vec3::new(), Vec3::from(1.0, 1.0, 1.0));
+        let mut beamlets = Vec::new();
+        let mut columns = Vec::new();
+        for beam in 0..9 {
+            for n in 0..8 {
+                let (c, r) = (n % 4, n / 4);
+                beamlets.push(Beamlet {
+                    beam,
+                    x: 5.0 * c as f64 - 7.5,
+                    y: 5.0 * r as f64 - 2.5,
+                    energy: None,
+                });
+                let v = (2 * c + r + beam) % 8;
+                columns.push(vec![(v as u32, 1.0), (((v + 1) % 8) as u32, 0.5)]);
+            }
+        }
+        for column in columns.iter_mut() {
+            column.sort_by_key(|e| e.0);
+        }
+        let structures = vec![
+            ("PTV".to_string(), vec![0, 1, 2, 3, 4]),
+            ("OAR".to_string(), vec![5, 6, 7]),
+        ];
+        Dij::from_columns(
+            geometry,
+            5.0,
+            beamlets,
+            (0..8).collect(),
+            structures,
+            columns,
+        )
+        .unwrap()
+    }
+
+    fn plan() -> PlanObjectives {
+        PlanObjectives::new()
+            .with_objective(DoseObjective::new(
+                "PTV",
+                ObjectiveKind::Uniform(2.0),
+                100.0,
+            ))
+            .with_objective(DoseObjective::new("OAR", ObjectiveKind::Max(0.0), 10.0))
+    }
+
+    /// Gantry angles of the nine beams, 10 degrees apart.
+    fn gantry() -> Vec<f64> {
+        (0..9).map(|n| 180.0 + 10.0 * n as f64).collect()
+    }
+
+    fn mlc() -> Mlc {
+        Mlc::uniform(
+            10,
+            5.0,
+            MlcData {
+                transmission: 0.0,
+                dlg: 0.0,
+            },
+        )
+    }
+
+    #[test]
+    fn arc_sequencing() {
+        let (dij, plan, gantry) = (dij(), plan(), gantry());
+        // 10 degrees take 2.08 s at full gantry speed: one 5 mm bixel of leaf travel.
+        let limits = ArcLimits {
+            max_gantry_speed: 4.8,
+            max_dose_rate: 60.0,
+            leaves: LeafLimits {
+                max_leaf_speed: 2.4,
+                ..ArcLimits::default().leaves
+            },
+        };
+        let options = VmatOptions {
+            limits,
+            levels: 2,
+            ..VmatOptions::default()
+        };
+        let result = optimize(&dij, &plan, &gantry, &options).unwrap();
+        assert_eq!(result.segments.len(), 9);
+        let last = result.history[result.history.len() - 1];
+        assert!(last < 0.1 * result.history[0], "{:?}", result.history);
+        for pair in result.segments.windows(2) {
+            for ((_, a), (_, b)) in pair[0].leaves.iter().zip(&pair[1].leaves) {
+                assert!((a[0] - b[0]).abs() <= 5.0 + 1e-9 && (a[1] - b[1]).abs() <= 5.0 + 1e-9);
+            }
+        }
+
+        let points = result.control_points(&mlc(), &limits).unwrap();
+        assert_eq!(points.len(), 9);
+        assert_eq!(points[0].point.weight, 0.0);
+        assert!((points[8].point.weight - 1.0).abs() < 1e-12);
+        assert!(points
+            .windows(2)
+            .all(|p| p[1].point.weight >= p[0].point.weight));
+        for p in &points[..8] {
+            assert!(p.dose_rate <= 60.0 + 1e-9 && p.gantry_speed <= 4.8 + 1e-9);
+        }
+        assert!(result.delivery_time(&limits) >= 80.0 / 4.8 - 1e-9);
+        // At 60 MU/min every MU takes at least a second.
+        assert!(result.delivery_time(&limits) >= result.meterset() - 1e-9);
+
+        assert!(optimize(&dij, &plan, &gantry[..8], &options).is_err());
+        let mut reversed = gantry.clone();
+        reversed.swap(3, 4);
+        assert!(optimize(&dij, &plan, &reversed, &options).is_err());
+    }
+
+    #[test]
+    fn arc_arguments() {
+        let (dij, plan, gantry) = (dij(), plan(), gantry());
+        let with_limits = |limits: ArcLimits| VmatOptions {
+            limits,
+            levels: 1,
+            ..VmatOptions::default()
+        };
+        let d = ArcLimits::default();
+        for limits in [
+            ArcLimits {
+                max_gantry_speed: 0.0,
+                ..d
+            },
+            ArcLimits {
+                max_dose_rate: -60.0,
+                ..d
+            },
+            ArcLimits {
+                leaves: LeafLimits {
+                    max_leaf_speed: 0.0,
+                    ..d.leaves
+                },
+                ..d
+            },
+            ArcLimits {
+                leaves: LeafLimits {
+                    min_gap: -1.0,
+                    ..d.leaves
+                },
+                ..d
+            },
+        ]
+        .iter()
+        {
+            assert!(optimize(&dij, &plan, &gantry, &with_limits(*limits)).is_err());
+        }
+        for (lower, upper) in [(-1.0, 1.0), (2.0, 1.0)].iter() {
+            let options = VmatOptions {
+                weights: FmoOptions {
+                    lower: *lower,
+                    upper: *upper,
+                    ..FmoOptions::default()
+                },
+                ..with_limits(d)
+            };
+            assert!(optimize(&dij, &plan, &gantry, &options).is_err());
+        }
+        let mut repeated = gantry.clone();
+        repeated[4] = repeated[3];
+        assert!(optimize(&dij, &plan, &repeated, &with_limits(d)).is_err());
+        let unknown = PlanObjectives::new().with_objective(DoseObjective::new(
+            "Lung",
+            ObjectiveKind::Max(1.0),
+            1.0,
+        ));
+        assert!(optimize(&dij, &unknown, &gantry, &with_limits(d)).is_err());
+        // A decreasing arc is as valid as an increasing one.
+        let reversed: Vec<f64> = gantry.iter().rev().copied().collect();
+        assert!(optimize(&dij, &plan, &reversed, &with_limits(d)).is_ok());
+    }
+
+    #[test]
+    fn arc_leaf_rules() {
+        let (dij, plan, gantry) = (dij(), plan(), gantry());
+        let options = VmatOptions {
+            limits: ArcLimits {
+                leaves: LeafLimits {
+                    min_gap: 10.0,
+                    interdigitation: false,
+                    ..ArcLimits::default().leaves
+                },
+                ..ArcLimits::default()
+            },
+            levels: 1,
+            ..VmatOptions::default()
+        };
+        let result = optimize(&dij, &plan, &gantry, &options).unwrap();
+        for segment in &result.segments {
+            for (_, x) in segment.leaves.iter().filter(|(_, x)| x[1] > x[0]) {
+                assert!(x[1] - x[0] >= 10.0 - 1e-9, "{:?}", segment.leaves);
+            }
+            // Neighbouring rows overlap or touch without interdigitation.
+            for pair in segment.leaves.windows(2) {
+                let (a, b) = (pair[0].1, pair[1].1);
+                assert!(a[0] <= b[1] && b[0] <= a[1], "{:?}", segment.leaves);
+            }
+        }
+    }
+
+    /// Three control points 10 degrees apart of a single 10 mm row, the leaves moving 5 mm
+    /// in the first sector.
+    fn arc_result(weights: [f64; 3]) -> VmatResult {
+        let leaves = [[-5.0, 5.0], [0.0, 10.0], [0.0, 10.0]];
+        VmatResult {
+            gantry: vec![0.0, 10.0, 20.0],
+            segments: weights
+                .iter()
+                .zip(leaves.iter())
+                .map(|(w, x)| Segment {
+                    beam: 0,
+                    weight: *w,
+                    leaves: vec![([-5.0, 5.0], *x)],
+                })
+                .collect(),
+            weights: Vec::new(),
+            history: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn arc_timing() {
+        let result = arc_result([1.0, 2.0, 1.0]);
+        assert_eq!(result.meterset(), 4.0);
+        // Every sector delivers 2 MU. The first takes 5 s for the leaves at 1 mm/s, the
+        // second 2 s for the gantry at 5 degrees/s and the dose at 60 MU/min alike.
+        let limits = ArcLimits {
+            max_gantry_speed: 5.0,
+            max_dose_rate: 60.0,
+            leaves: LeafLimits {
+                max_leaf_speed: 1.0,
+                ..ArcLimits::default().leaves
+            },
+        };
+        assert!((result.delivery_time(&limits) - 7.0).abs() < 1e-12);
+        let points = result.control_points(&mlc(), &limits).unwrap();
+        let weights: Vec<f64> = points.iter().map(|p| p.point.weight).collect();
+        assert_eq!(weights, vec![0.0, 0.5, 1.0]);
+        assert!((points[0].dose_rate - 24.0).abs() < 1e-12);
+        assert!((points[0].gantry_speed - 2.0).abs() < 1e-12);
+        assert!((points[1].dose_rate - 60.0).abs() < 1e-12);
+        assert!((points[1].gantry_speed - 5.0).abs() < 1e-12);
+        assert_eq!((points[2].dose_rate, points[2].gantry_speed), (0.0, 0.0));
+        for p in &points {
+            assert_eq!(p.point.jaws, Some([-5.0, 10.0, -5.0, 5.0]));
+        }
+        assert_eq!(points[1].point.leaves.as_ref().unwrap()[4], [0.0, 10.0]);
+        assert_eq!(points[1].point.leaves.as_ref().unwrap()[0], [0.0, 0.0]);
+
+        // Faster leaves leave the gantry speed as the limit.
+        let fast = ArcLimits {
+            leaves: LeafLimits {
+                max_leaf_speed: 25.0,
+                ..limits.leaves
+            },
+            ..limits
+        };
+        assert!((result.delivery_time(&fast) - 4.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn arc_without_dose() {
+        let limits = ArcLimits::default();
+        assert!(arc_result([0.0; 3])
+            .control_points(&mlc(), &limits)
+            .is_err());
+        let mut closed = arc_result([1.0; 3]);
+        for segment in closed.segments.iter_mut() {
+            segment.leaves[0].1 = [0.0, 0.0];
+        }
+        assert!(closed.control_points(&mlc(), &limits).is_err());
+    }
+}
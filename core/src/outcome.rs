@@ -0,0 +1,214 @@
+//! NTCP and TCP models evaluated on DVHs.
+//!
+//! * LKB NTCP: `gEUD = (Σ vᵢ Dᵢᵃ / V)^(1/a)` with `a = 1/n`, `t = (gEUD - TD50) / (m TD50)`
+//!   and `NTCP = Φ(t)`.
+//! * LQ Poisson TCP: `TCP = exp(-Σ ρ vᵢ exp(-α Dᵢ (1 + dᵢ / (α/β))))` with `dᵢ = Dᵢ / n`
+//!   per fraction and `ρ` clonogens per cc.
+//!
+//! Doses are the differential DVH bin centers.
+
+use crate::dvh::Dvh;
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LkbParameters {
+    /// Gy
+    pub td50: f64,
+    pub m: f64,
+    /// Volume effect; gEUD uses `a = 1 / n`.
+    pub n: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PoissonTcpParameters {
+    /// Gy⁻¹
+    pub alpha: f64,
+    /// Gy
+    pub alpha_beta: f64,
+    /// Clonogens per cc.
+    pub clonogen_density: f64,
+    pub fractions: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutcomeModel {
+    Lkb(LkbParameters),
+    PoissonTcp(PoissonTcpParameters),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutcomeResult {
+    pub structure: String,
+    pub model: OutcomeModel,
+    pub probability: f64,
+    /// Gy; LKB only.
+    pub geud: Option<f64>,
+}
+
+/// Generalized equivalent uniform dose (Gy) for volume parameter `a`.
+pub fn geud(dvh: &Dvh, a: f64) -> Result<f64> {
+    if dvh.volume <= 0.0 {
+        return Err(Error::InvalidArgument(format!(
+            "{} has an empty DVH",
+            dvh.name
+        )));
+    }
+    if a == 0.0 {
+        return Err(Error::InvalidArgument(
+            "gEUD needs a non-zero volume parameter".to_string(),
+        ));
+    }
+    // Doses are taken relative to the highest occupied bin so large `a` does not overflow.
+    let top = match dvh.bins.iter().rposition(|v| *v > 0.0) {
+        Some(n) => (n as f64 + 0.5) * dvh.bin_width,
+        None => return Ok(0.0),
+    };
+    let sum: f64 = dvh
+        .bins
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| **v > 0.0)
+        .map(|(n, v)| v * ((n as f64 + 0.5) * dvh.bin_width / top).powf(a))
+        .sum();
+    Ok(top * (sum / dvh.volume).powf(1.0 / a))
+}
+
+impl OutcomeModel {
+    pub fn evaluate(&self, dvh: &Dvh) -> Result<OutcomeResult> {
+        let (probability, geud_value) = match self {
+            OutcomeModel::Lkb(p) => {
+                if p.td50 <= 0.0 || p.m <= 0.0 || p.n <= 0.0 {
+                    return Err(Error::InvalidArgument(
+                        "LKB parameters must be positive".to_string(),
+                    ));
+                }
+                let g = geud(dvh, 1.0 / p.n)?;
+                (normal_cdf((g - p.td50) / (p.m * p.td50)), Some(g))
+            }
+            OutcomeModel::PoissonTcp(p) => {
+                if p.fractions == 0 || p.alpha_beta <= 0.0 {
+                    return Err(Error::InvalidArgument(
+                        "TCP needs fractions and a positive α/β".to_string(),
+                    ));
+                }
+                let survivors: f64 = dvh
+                    .bins
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, v)| **v > 0.0)
+                    .map(|(n, v)| {
+                        let dose = (n as f64 + 0.5) * dvh.bin_width;
+                        let d = dose / p.fractions as f64;
+                        p.clonogen_density * v * (-p.alpha * dose * (1.0 + d / p.alpha_beta)).exp()
+                    })
+                    .sum();
+                ((-survivors).exp(), None)
+            }
+        };
+        Ok(OutcomeResult {
+            structure: dvh.name.clone(),
+            model: *self,
+            probability,
+            geud: geud_value,
+        })
+    }
+}
+
+/// Evaluates every (structure name, model) pair on the DVH with that name.
+pub fn evaluate_models(
+    models: &[(&str, OutcomeModel)],
+    dvhs: &[Dvh],
+) -> Result<Vec<OutcomeResult>> {
+    models
+        .iter()
+        .map(|(name, model)| {
+            let dvh = dvhs
+                .iter()
+                .find(|d| d.name == *name)
+                .ok_or_else(|| Error::InvalidArgument(format!("no DVH for {}", name)))?;
+            model.evaluate(dvh)
+        })
+        .collect()
+}
+
+/// Standard normal cumulative distribution.
+pub fn normal_cdf(x: f64) -> f64 {
+    0.5 * erfc(-x / std::f64::consts::SQRT_2)
+}
+
+/// Complementary error function (Chebyshev fit from Numerical Recipes, relative error below
+/// 1.2e-7).
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let poly = -z * z - 1.26551223
+        + t * (1.00002368
+            + t * (0.37409196
+                + t * (0.09678418
+                    + t * (-0.18628806
+                        + t * (0.27886807
+                            + t * (-1.13520398
+                                + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277))))))));
+    let r = t * poly.exp();
+    if x >= 0.0 {
+        r
+    } else {
+        2.0 - r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dvh::Dvh;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::outcome::{
+        evaluate_models, geud, normal_cdf, LkbParameters, OutcomeModel, PoissonTcpParameters,
+    };
+
+    fn dvh(name: &str, doses: &[f64]) -> Dvh {
+        let geometry = GridGeometry::new(
+            [doses.len(), 1, 1],
+            Vec3::new(),
+            Vec3::from(10.0, 10.0, 10.0),
+        );
+        let dose = Grid3::from_vec(geometry, doses.to_vec()).unwrap();
+        Dvh::from_fraction(name, &dose.map(|_| 1.0), &dose, 1.0).unwrap()
+    }
+
+    #[test]
+    fn outcome_normal_cdf() {
+        assert!((normal_cdf(0.0) - 0.5).abs() < 1e-7);
+        assert!((normal_cdf(1.0) - 0.841344746).abs() < 1e-6);
+        assert!((normal_cdf(-1.96) - 0.024997895).abs() < 1e-6);
+    }
+
+    #[test]
+    fn outcome_models() {
+        let lung = dvh("Lungs", &[10.5, 30.5]);
+        assert!((geud(&lung, 1.0).unwrap() - 20.5).abs() < 1e-9);
+        assert!((geud(&lung, 1e3).unwrap() - 30.5).abs() < 0.1);
+        let lkb = OutcomeModel::Lkb(LkbParameters {
+            td50: 20.5,
+            m: 0.35,
+            n: 1.0,
+        });
+        let r = lkb.evaluate(&lung).unwrap();
+        assert!((r.probability - 0.5).abs() < 1e-7);
+        assert_eq!(r.geud, Some(lung.mean));
+        assert_eq!(r.model, lkb);
+
+        let tcp = OutcomeModel::PoissonTcp(PoissonTcpParameters {
+            alpha: 0.3,
+            alpha_beta: 10.0,
+            clonogen_density: 1e7,
+            fractions: 30,
+        });
+        let ptv = dvh("PTV", &[60.5, 60.5]);
+        let expected = (-1e7 * 2.0 * (-0.3_f64 * 60.5 * (1.0 + 60.5 / 30.0 / 10.0)).exp()).exp();
+        let results = evaluate_models(&[("PTV", tcp), ("Lungs", lkb)], &[lung, ptv]).unwrap();
+        assert!((results[0].probability - expected).abs() < 1e-12);
+        assert_eq!(results[1].structure, "Lungs");
+        assert!(evaluate_models(&[("Heart", lkb)], &[]).is_err());
+    }
+}
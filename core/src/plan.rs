@@ -0,0 +1,1159 @@
+//! Treatment plan domain model.
+//!
+//! A [`Plan`] holds its prescriptions, fraction groups and beams independently of any file
+//! format: readers and writers map their attributes into and out of these types, and dose
+//! engines, optimizers and reports work on them. Beams follow the conventions of
+//! [`crate::dose::beam`] and [`crate::dose::delivery`]: angles in degrees (IEC 61217),
+//! positions in mm at the isocenter plane and cumulative meterset weights from 0 to 1 over the
+//! control points of a beam. A fraction group lists the meterset of its beams per fraction;
+//! the meterset of a beam is its own total for one fraction.
+//!
+//! [`Plan::validate`] reports every inconsistency in the control points of the beams and
+//! between beams and fraction groups, like [`crate::machine::Machine::validate`] does for what
+//! a machine can deliver.
+
+use std::fmt;
+
+use crate::coords::Vec3;
+use crate::dose::beam::BeamGeometry;
+use crate::dose::delivery;
+use crate::error::{Error, Result};
+use crate::machine::{DeliveredBeam, Machine, Violation};
+use crate::opt::vmat::ArcControlPoint;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Radiation {
+    Photon,
+    Electron,
+    Proton,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BeamKind {
+    /// Fixed gantry with static segments (conformal or step-and-shoot).
+    Static,
+    /// Leaves or gantry move while the beam is on (sliding window or arc).
+    Dynamic,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prescription {
+    /// Structure the dose is prescribed to.
+    pub target: String,
+    /// Gy over all fractions.
+    pub dose: f64,
+    pub fractions: usize,
+}
+
+impl Prescription {
+    pub fn new(target: &str, dose: f64, fractions: usize) -> Self {
+        Prescription {
+            target: target.to_string(),
+            dose,
+            fractions,
+        }
+    }
+
+    /// Gy
+    pub fn dose_per_fraction(&self) -> f64 {
+        self.dose / self.fractions.max(1) as f64
+    }
+}
+
+/// A beam delivered in every fraction of a group, with its meterset (MU) per fraction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReferencedBeam {
+    /// Number of the beam.
+    pub beam: usize,
+    pub meterset: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FractionGroup {
+    pub number: usize,
+    pub fractions: usize,
+    pub beams: Vec<ReferencedBeam>,
+}
+
+impl FractionGroup {
+    /// MU per fraction.
+    pub fn meterset(&self) -> f64 {
+        self.beams.iter().map(|b| b.meterset).sum()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlPoint {
+    /// Cumulative meterset weight, jaws and leaves.
+    pub point: delivery::ControlPoint,
+    /// Degrees.
+    pub gantry: f64,
+    /// Degrees.
+    pub collimator: f64,
+    /// Degrees.
+    pub couch: f64,
+    /// MU/min from this control point to the next, when the plan sets it.
+    pub dose_rate: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Beam {
+    pub number: usize,
+    pub name: String,
+    /// Name of the treatment machine, see [`crate::machine::Machine`].
+    pub machine: String,
+    pub radiation: Radiation,
+    /// Nominal energy, e.g. `6X` or `6FFF`.
+    pub energy: String,
+    pub kind: BeamKind,
+    /// mm
+    pub isocenter: Vec3<f64>,
+    /// Source to axis distance (mm).
+    pub sad: f64,
+    /// MU per fraction.
+    pub meterset: f64,
+    pub control_points: Vec<ControlPoint>,
+}
+
+impl Beam {
+    /// A photon beam at the fixed `geometry` delivering `points`, e.g. the step-and-shoot
+    /// segments of [`crate::opt::dao::DaoResult::control_points`]; dynamic when the leaves
+    /// move while the meterset increases.
+    pub fn fixed(
+        number: usize,
+        name: &str,
+        energy: &str,
+        geometry: &BeamGeometry,
+        meterset: f64,
+        points: &[delivery::ControlPoint],
+    ) -> Self {
+        let dynamic = points
+            .windows(2)
+            .any(|p| p[1].weight > p[0].weight && p[1].leaves != p[0].leaves);
+        Beam {
+            number,
+            name: name.to_string(),
+            machine: String::new(),
+            radiation: Radiation::Photon,
+            energy: energy.to_string(),
+            kind: if dynamic {
+                BeamKind::Dynamic
+            } else {
+                BeamKind::Static
+            },
+            isocenter: geometry.isocenter,
+            sad: geometry.sad,
+            meterset,
+            control_points: points
+                .iter()
+                .map(|p| ControlPoint {
+                    point: p.clone(),
+                    gantry: geometry.gantry,
+                    collimator: geometry.collimator,
+                    couch: geometry.couch,
+                    dose_rate: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// A photon arc delivering `points`, e.g. from
+    /// [`crate::opt::vmat::VmatResult::control_points`]; `geometry` gives the isocenter,
+    /// collimator, couch and source distance.
+    pub fn arc(
+        number: usize,
+        name: &str,
+        energy: &str,
+        geometry: &BeamGeometry,
+        meterset: f64,
+        points: &[ArcControlPoint],
+    ) -> Self {
+        let n = points.len();
+        Beam {
+            number,
+            name: name.to_string(),
+            machine: String::new(),
+            radiation: Radiation::Photon,
+            energy: energy.to_string(),
+            kind: BeamKind::Dynamic,
+            isocenter: geometry.isocenter,
+            sad: geometry.sad,
+            meterset,
+            control_points: points
+                .iter()
+                .enumerate()
+                .map(|(k, p)| ControlPoint {
+                    point: p.point.clone(),
+                    gantry: p.gantry,
+                    collimator: geometry.collimator,
+                    couch: geometry.couch,
+                    dose_rate: if k + 1 < n { Some(p.dose_rate) } else { None },
+                })
+                .collect(),
+        }
+    }
+
+    pub fn with_machine(mut self, machine: &str) -> Self {
+        self.machine = machine.to_string();
+        self
+    }
+
+    /// Geometry of control point `n` for the dose engines.
+    pub fn geometry(&self, n: usize) -> Option<BeamGeometry> {
+        self.control_points.get(n).map(|p| BeamGeometry {
+            isocenter: self.isocenter,
+            gantry: p.gantry,
+            collimator: p.collimator,
+            couch: p.couch,
+            sad: self.sad,
+        })
+    }
+
+    /// Whether the gantry rotates over the control points.
+    pub fn is_arc(&self) -> bool {
+        self.control_points
+            .windows(2)
+            .any(|p| (p[1].gantry - p[0].gantry).abs() > 1e-6)
+    }
+
+    /// Jaw and leaf positions of every control point for fluence delivery.
+    pub fn delivery_points(&self) -> Vec<delivery::ControlPoint> {
+        self.control_points
+            .iter()
+            .map(|p| p.point.clone())
+            .collect()
+    }
+
+    /// The beam as the machine checks it, see [`crate::machine::Machine::validate`]; dose
+    /// rates are given when every interval sets one.
+    pub fn delivered(&self) -> DeliveredBeam {
+        let n = self.control_points.len();
+        let dose_rates: Option<Vec<f64>> = self.control_points[..n.saturating_sub(1)]
+            .iter()
+            .map(|p| p.dose_rate)
+            .collect();
+        DeliveredBeam {
+            name: self.name.clone(),
+            energy: self.energy.clone(),
+            meterset: self.meterset,
+            gantry: self.control_points.iter().map(|p| p.gantry).collect(),
+            collimator: self.control_points.iter().map(|p| p.collimator).collect(),
+            couch: self.control_points.iter().map(|p| p.couch).collect(),
+            control_points: self.delivery_points(),
+            dose_rates: dose_rates.unwrap_or_default(),
+        }
+    }
+
+    /// The arc with control points every `spacing` degrees at most, evenly spaced from its
+    /// first to its last gantry angle. Cumulative meterset weight, jaws, leaves and delivery
+    /// time are interpolated linearly in gantry angle between the original control points, so
+    /// the MU of every original interval is spread evenly over its rotation; dose rates are
+    /// kept when every interval sets one.
+    pub fn resample(&self, spacing: f64) -> Result<Beam> {
+        if spacing.is_nan() || spacing <= 0.0 {
+            return Err(Error::InvalidArgument(format!(
+                "control point spacing {} degrees",
+                spacing
+            )));
+        }
+        if !self.is_arc() {
+            return Err(Error::InvalidArgument(format!(
+                "beam {} is not an arc",
+                self.number
+            )));
+        }
+        let points = &self.control_points;
+        // Rotation from the first control point, which must not change direction.
+        let mut rotation = vec![0.0];
+        for p in points.windows(2) {
+            let step = turn(p[0].gantry, p[1].gantry);
+            rotation.push(rotation[rotation.len() - 1] + step);
+        }
+        let direction = rotation[rotation.len() - 1].signum();
+        if rotation.windows(2).any(|r| (r[1] - r[0]) * direction < 0.0) {
+            return Err(Error::InvalidArgument(format!(
+                "arc {} changes direction",
+                self.number
+            )));
+        }
+        let rotation: Vec<f64> = rotation.iter().map(|r| r * direction).collect();
+        // Cumulative delivery time (s) when every interval sets its dose rate.
+        let times: Option<Vec<f64>> = points[..points.len() - 1]
+            .iter()
+            .zip(points.windows(2))
+            .map(|(p, w)| {
+                p.dose_rate
+                    .filter(|r| *r > 0.0)
+                    .map(|r| 60.0 * self.meterset * (w[1].point.weight - w[0].point.weight) / r)
+            })
+            .collect::<Option<Vec<f64>>>()
+            .map(|t| {
+                std::iter::once(0.0)
+                    .chain(t.iter().scan(0.0, |s, t| {
+                        *s += t;
+                        Some(*s)
+                    }))
+                    .collect()
+            });
+
+        let total = rotation[rotation.len() - 1];
+        let count = (total / spacing - 1e-9).ceil().max(1.0) as usize;
+        let lerp = |a: f64, b: f64, t: f64| a + t * (b - a);
+        let mut resampled: Vec<(ControlPoint, Option<f64>)> = (0..=count)
+            .map(|n| {
+                let r = total * n as f64 / count as f64;
+                let k = rotation[1..]
+                    .iter()
+                    .position(|q| *q >= r - 1e-9)
+                    .unwrap_or(points.len() - 2);
+                let (a, b) = (&points[k], &points[k + 1]);
+                let width = rotation[k + 1] - rotation[k];
+                let t = if width > 0.0 {
+                    ((r - rotation[k]) / width).clamp(0.0, 1.0)
+                } else {
+                    1.0
+                };
+                let jaws = match (a.point.jaws, b.point.jaws) {
+                    (Some(p), Some(q)) => Some([
+                        lerp(p[0], q[0], t),
+                        lerp(p[1], q[1], t),
+                        lerp(p[2], q[2], t),
+                        lerp(p[3], q[3], t),
+                    ]),
+                    (p, q) => p.or(q),
+                };
+                let leaves = match (&a.point.leaves, &b.point.leaves) {
+                    (Some(p), Some(q)) => Some(
+                        p.iter()
+                            .zip(q)
+                            .map(|(p, q)| [lerp(p[0], q[0], t), lerp(p[1], q[1], t)])
+                            .collect(),
+                    ),
+                    (p, q) => p.clone().or_else(|| q.clone()),
+                };
+                let point = ControlPoint {
+                    point: delivery::ControlPoint {
+                        weight: lerp(a.point.weight, b.point.weight, t),
+                        jaws,
+                        leaves,
+                    },
+                    gantry: (points[0].gantry + direction * r).rem_euclid(360.0),
+                    collimator: lerp(a.collimator, b.collimator, t),
+                    couch: lerp(a.couch, b.couch, t),
+                    dose_rate: None,
+                };
+                (point, times.as_ref().map(|s| lerp(s[k], s[k + 1], t)))
+            })
+            .collect();
+        let last = resampled.len() - 1;
+        resampled[last].0.point.weight = points[points.len() - 1].point.weight;
+        for n in 0..last {
+            if let (Some(s), Some(e)) = (resampled[n].1, resampled[n + 1].1) {
+                let mu =
+                    self.meterset * (resampled[n + 1].0.point.weight - resampled[n].0.point.weight);
+                resampled[n].0.dose_rate = if e > s {
+                    Some(60.0 * mu / (e - s))
+                } else {
+                    None
+                };
+            }
+        }
+        Ok(Beam {
+            control_points: resampled.into_iter().map(|(p, _)| p).collect(),
+            ..self.clone()
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValidationOptions {
+    /// Largest gantry rotation (degrees) between two control points of an arc.
+    pub max_gantry_step: f64,
+    /// Relative difference allowed between the meterset of a beam and that of its fraction
+    /// group.
+    pub meterset_tolerance: f64,
+}
+
+impl Default for ValidationOptions {
+    fn default() -> Self {
+        ValidationOptions {
+            max_gantry_step: 10.0,
+            meterset_tolerance: 1e-3,
+        }
+    }
+}
+
+/// An inconsistency in a plan; beams are identified by number.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlanIssue {
+    /// A beam without at least two control points.
+    ControlPoints {
+        beam: usize,
+        count: usize,
+    },
+    /// Cumulative meterset weight decreasing or outside `[0, 1]`.
+    Weight {
+        beam: usize,
+        control_point: usize,
+        weight: f64,
+    },
+    /// Cumulative meterset weight not starting at 0 or not ending at 1.
+    WeightRange {
+        beam: usize,
+        first: f64,
+        last: f64,
+    },
+    /// Gantry turning the other way than the rest of the arc.
+    GantryReversal {
+        beam: usize,
+        control_point: usize,
+    },
+    /// Gantry rotation (degrees) from the previous control point above the maximum step.
+    GantryStep {
+        beam: usize,
+        control_point: usize,
+        step: f64,
+    },
+    /// Collimator or couch angle changing within a beam.
+    AngleChange {
+        beam: usize,
+        control_point: usize,
+        axis: &'static str,
+    },
+    /// A fraction group referencing a beam the plan does not hold, or twice.
+    UnknownBeam {
+        group: usize,
+        beam: usize,
+    },
+    DuplicateBeam {
+        group: usize,
+        beam: usize,
+    },
+    /// A beam no fraction group delivers.
+    UnreferencedBeam {
+        beam: usize,
+    },
+    /// Meterset (MU) of a beam in a fraction group against that of the beam.
+    Meterset {
+        group: usize,
+        beam: usize,
+        group_meterset: f64,
+        beam_meterset: f64,
+    },
+    /// A fraction group without fractions.
+    Fractions {
+        group: usize,
+    },
+}
+
+impl fmt::Display for PlanIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlanIssue::ControlPoints { beam, count } => {
+                write!(f, "beam {}: {} control points", beam, count)
+            }
+            PlanIssue::Weight {
+                beam,
+                control_point,
+                weight,
+            } => write!(
+                f,
+                "beam {}, control point {}: cumulative meterset weight {}",
+                beam, control_point, weight
+            ),
+            PlanIssue::WeightRange { beam, first, last } => write!(
+                f,
+                "beam {}: cumulative meterset weight from {} to {}",
+                beam, first, last
+            ),
+            PlanIssue::GantryReversal {
+                beam,
+                control_point,
+            } => write!(
+                f,
+                "beam {}, control point {}: gantry reverses",
+                beam, control_point
+            ),
+            PlanIssue::GantryStep {
+                beam,
+                control_point,
+                step,
+            } => write!(
+                f,
+                "beam {}, control point {}: gantry step of {} degrees",
+                beam, control_point, step
+            ),
+            PlanIssue::AngleChange {
+                beam,
+                control_point,
+                axis,
+            } => write!(
+                f,
+                "beam {}, control point {}: {} angle changes",
+                beam, control_point, axis
+            ),
+            PlanIssue::UnknownBeam { group, beam } => {
+                write!(f, "fraction group {}: no beam {}", group, beam)
+            }
+            PlanIssue::DuplicateBeam { group, beam } => {
+                write!(f, "fraction group {}: beam {} listed twice", group, beam)
+            }
+            PlanIssue::UnreferencedBeam { beam } => {
+                write!(f, "beam {} in no fraction group", beam)
+            }
+            PlanIssue::Meterset {
+                group,
+                beam,
+                group_meterset,
+                beam_meterset,
+            } => write!(
+                f,
+                "fraction group {}: beam {} at {} MU, the beam at {} MU",
+                group, beam, group_meterset, beam_meterset
+            ),
+            PlanIssue::Fractions { group } => write!(f, "fraction group {}: no fractions", group),
+        }
+    }
+}
+
+/// Shortest signed gantry rotation (degrees) from `a` to `b`.
+fn turn(a: f64, b: f64) -> f64 {
+    (b - a + 180.0).rem_euclid(360.0) - 180.0
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Plan {
+    /// Short identifier of the plan.
+    pub label: String,
+    pub name: String,
+    pub prescriptions: Vec<Prescription>,
+    pub fraction_groups: Vec<FractionGroup>,
+    pub beams: Vec<Beam>,
+}
+
+impl Plan {
+    pub fn new(label: &str) -> Self {
+        Plan {
+            label: label.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn with_prescription(mut self, prescription: Prescription) -> Self {
+        self.prescriptions.push(prescription);
+        self
+    }
+
+    pub fn with_fraction_group(mut self, group: FractionGroup) -> Self {
+        self.fraction_groups.push(group);
+        self
+    }
+
+    pub fn with_beam(mut self, beam: Beam) -> Self {
+        self.beams.push(beam);
+        self
+    }
+
+    pub fn beam(&self, number: usize) -> Option<&Beam> {
+        self.beams.iter().find(|b| b.number == number)
+    }
+
+    /// A single fraction group of `fractions` with every beam at its own meterset.
+    pub fn fraction_group_of_beams(&self, number: usize, fractions: usize) -> FractionGroup {
+        FractionGroup {
+            number,
+            fractions,
+            beams: self
+                .beams
+                .iter()
+                .map(|b| ReferencedBeam {
+                    beam: b.number,
+                    meterset: b.meterset,
+                })
+                .collect(),
+        }
+    }
+
+    /// Total planned fractions over the fraction groups.
+    pub fn fractions(&self) -> usize {
+        self.fraction_groups.iter().map(|g| g.fractions).sum()
+    }
+
+    /// MU over all fractions of all groups.
+    pub fn total_meterset(&self) -> f64 {
+        self.fraction_groups
+            .iter()
+            .map(|g| g.fractions as f64 * g.meterset())
+            .sum()
+    }
+
+    /// Every violation of the limits of `machine` in the beams, indexed as in
+    /// [`Plan::beams`]; see [`Machine::validate`].
+    pub fn machine_violations(&self, machine: &Machine) -> Vec<Violation> {
+        let beams: Vec<DeliveredBeam> = self.beams.iter().map(|b| b.delivered()).collect();
+        machine.validate(&beams)
+    }
+
+    /// Every inconsistency in the control points of the beams and between the beams and the
+    /// fraction groups.
+    pub fn validate(&self, options: &ValidationOptions) -> Vec<PlanIssue> {
+        let mut issues = Vec::new();
+        for beam in &self.beams {
+            let points = &beam.control_points;
+            let number = beam.number;
+            if points.len() < 2 {
+                issues.push(PlanIssue::ControlPoints {
+                    beam: number,
+                    count: points.len(),
+                });
+                continue;
+            }
+            let mut previous = 0.0;
+            for (n, p) in points.iter().enumerate() {
+                let weight = p.point.weight;
+                if weight.is_nan() || weight < previous || weight > 1.0 {
+                    issues.push(PlanIssue::Weight {
+                        beam: number,
+                        control_point: n,
+                        weight,
+                    });
+                }
+                previous = weight.max(previous);
+            }
+            let (first, last) = (
+                points[0].point.weight,
+                points[points.len() - 1].point.weight,
+            );
+            if first.abs() > 1e-9 || (last - 1.0).abs() > 1e-9 {
+                issues.push(PlanIssue::WeightRange {
+                    beam: number,
+                    first,
+                    last,
+                });
+            }
+            let steps: Vec<f64> = points
+                .windows(2)
+                .map(|p| turn(p[0].gantry, p[1].gantry))
+                .collect();
+            let direction = steps.iter().sum::<f64>().signum();
+            for (n, p) in points.windows(2).enumerate() {
+                let step = steps[n];
+                if step * direction < -1e-9 {
+                    issues.push(PlanIssue::GantryReversal {
+                        beam: number,
+                        control_point: n + 1,
+                    });
+                }
+                if step.abs() > options.max_gantry_step + 1e-9 {
+                    issues.push(PlanIssue::GantryStep {
+                        beam: number,
+                        control_point: n + 1,
+                        step: step.abs(),
+                    });
+                }
+                for (axis, a, b) in &[
+                    ("collimator", p[0].collimator, p[1].collimator),
+                    ("couch", p[0].couch, p[1].couch),
+                ] {
+                    if turn(*a, *b).abs() > 1e-6 {
+                        issues.push(PlanIssue::AngleChange {
+                            beam: number,
+                            control_point: n + 1,
+                            axis,
+                        });
+                    }
+                }
+            }
+        }
+
+        let mut referenced = Vec::new();
+        for group in &self.fraction_groups {
+            if group.fractions == 0 {
+                issues.push(PlanIssue::Fractions {
+                    group: group.number,
+                });
+            }
+            let mut seen = Vec::new();
+            for r in &group.beams {
+                if seen.contains(&r.beam) {
+                    issues.push(PlanIssue::DuplicateBeam {
+                        group: group.number,
+                        beam: r.beam,
+                    });
+                    continue;
+                }
+                seen.push(r.beam);
+                match self.beam(r.beam) {
+                    Some(beam) => {
+                        let scale = beam.meterset.abs().max(r.meterset.abs()).max(1e-9);
+                        if (beam.meterset - r.meterset).abs() > options.meterset_tolerance * scale {
+                            issues.push(PlanIssue::Meterset {
+                                group: group.number,
+                                beam: r.beam,
+                                group_meterset: r.meterset,
+                                beam_meterset: beam.meterset,
+                            });
+                        }
+                    }
+                    None => issues.push(PlanIssue::UnknownBeam {
+                        group: group.number,
+                        beam: r.beam,
+                    }),
+                }
+            }
+            referenced.extend(seen);
+        }
+        for beam in &self.beams {
+            if !referenced.contains(&beam.number) {
+                issues.push(PlanIssue::UnreferencedBeam { beam: beam.number });
+            }
+        }
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dose::beam::BeamGeometry;
+    use crate::dose::delivery;
+    use crate::machine::{Machine, ViolationKind};
+    use crate::opt::vmat::ArcControlPoint;
+    use crate::plan::{
+        Beam, BeamKind, FractionGroup, Plan, PlanIssue, Prescription, ReferencedBeam,
+        ValidationOptions,
+    };
+
+    fn point(weight: f64, leaves: Vec<[f64; 2]>) -> delivery::ControlPoint {
+        delivery::ControlPoint {
+            weight,
+            jaws: Some([-20.0, 20.0, -20.0, 20.0]),
+            leaves: Some(leaves),
+        }
+    }
+
+    fn plan() -> Plan {
+        let geometry = BeamGeometry::new(Vec3::from(0.0, 10.0, 0.0), 90.0, 1000.0);
+        let open = vec![[-10.0, 10.0]; 60];
+        let small = vec![[-5.0, 5.0]; 60];
+        let segments = [
+            point(0.0, open.clone()),
+            point(0.6, open.clone()),
+            point(0.6, small.clone()),
+            point(1.0, small.clone()),
+        ];
+        let arc: Vec<ArcControlPoint> = [(180.0, 0.0, open), (200.0, 1.0, small)]
+            .iter()
+            .map(|(gantry, weight, leaves)| ArcControlPoint {
+                gantry: *gantry,
+                point: point(*weight, leaves.clone()),
+                dose_rate: 600.0,
+                gantry_speed: 4.0,
+            })
+            .collect();
+        let plan = Plan::new("PROST")
+            .with_name("Prostate")
+            .with_prescription(Prescription::new("PTV", 60.0, 20))
+            .with_beam(
+                Beam::fixed(1, "IMRT 90", "6X", &geometry, 120.0, &segments).with_machine("Linac"),
+            )
+            .with_beam(Beam::arc(2, "Arc", "6X", &geometry, 100.0, &arc));
+        let group = plan.fraction_group_of_beams(1, 20);
+        plan.with_fraction_group(group)
+    }
+
+    #[test]
+    fn plan_model() {
+        let plan = plan();
+        assert_eq!(plan.prescriptions[0].dose_per_fraction(), 3.0);
+        assert_eq!(plan.fractions(), 20);
+        assert_eq!(plan.fraction_groups[0].meterset(), 220.0);
+        assert_eq!(plan.total_meterset(), 4400.0);
+
+        let fixed = plan.beam(1).unwrap();
+        assert_eq!(fixed.kind, BeamKind::Static);
+        assert_eq!(fixed.machine, "Linac");
+        assert!(!fixed.is_arc());
+        let geometry = fixed.geometry(3).unwrap();
+        assert_eq!((geometry.gantry, geometry.sad), (90.0, 1000.0));
+        assert_eq!(geometry.isocenter, Vec3::from(0.0, 10.0, 0.0));
+        assert!(fixed.geometry(4).is_none());
+        assert_eq!(fixed.delivery_points()[2].weight, 0.6);
+        assert!(fixed.delivered().dose_rates.is_empty());
+
+        let arc = plan.beam(2).unwrap();
+        assert_eq!(arc.kind, BeamKind::Dynamic);
+        assert!(arc.is_arc());
+        assert_eq!(arc.control_points[1].dose_rate, None);
+        let delivered = arc.delivered();
+        assert_eq!(delivered.gantry, vec![180.0, 200.0]);
+        assert_eq!(delivered.dose_rates, vec![600.0]);
+        assert!(plan.beam(3).is_none());
+
+        // 100 MU at 600 MU/min over 20 degrees turns the gantry at 2 deg/s.
+        let machine = Machine::default();
+        assert!(plan.machine_violations(&machine).is_empty());
+        let slow = Machine {
+            max_gantry_speed: 1.0,
+            ..Machine::default()
+        };
+        let violations = plan.machine_violations(&slow);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].beam, 1);
+        assert_eq!(violations[0].kind, ViolationKind::GantrySpeed(2.0));
+    }
+
+    #[test]
+    fn resample_and_validate() {
+        let geometry = BeamGeometry::new(Vec3::new(), 0.0, 1000.0);
+        let arc: Vec<ArcControlPoint> = [
+            (350.0, 0.0, -10.0, 600.0),
+            (10.0, 0.25, 10.0, 300.0),
+            (30.0, 1.0, 30.0, 0.0),
+        ]
+        .iter()
+        .map(|(gantry, weight, leaf, dose_rate)| ArcControlPoint {
+            gantry: *gantry,
+            point: point(*weight, vec![[*leaf - 5.0, *leaf + 5.0]; 60]),
+            dose_rate: *dose_rate,
+            gantry_speed: 0.0,
+        })
+        .collect();
+        let beam = Beam::arc(1, "Arc", "6X", &geometry, 100.0, &arc);
+        let resampled = beam.resample(5.0).unwrap();
+        let points = &resampled.control_points;
+        assert_eq!(points.len(), 9);
+        assert!((points[1].gantry - 355.0).abs() < 1e-9);
+        assert!(points[2].gantry.abs() < 1e-9);
+        assert!((points[1].point.weight - 0.0625).abs() < 1e-12);
+        assert!((points[4].point.weight - 0.25).abs() < 1e-12);
+        assert!((points[6].point.weight - 0.625).abs() < 1e-12);
+        assert_eq!(points[8].point.weight, 1.0);
+        let leaves = points[6].point.leaves.as_ref().unwrap();
+        assert!((leaves[0][0] - 15.0).abs() < 1e-9);
+        // 25 MU over the first 20 degrees at 600 MU/min and 75 MU over the next at 300.
+        assert!((points[0].dose_rate.unwrap() - 600.0).abs() < 1e-9);
+        assert!((points[7].dose_rate.unwrap() - 300.0).abs() < 1e-9);
+        assert_eq!(points[8].dose_rate, None);
+        assert_eq!(resampled.delivered().dose_rates.len(), 8);
+        assert!(beam.resample(0.0).is_err());
+        let fixed = plan().beams[0].clone();
+        assert!(fixed.resample(5.0).is_err());
+
+        let valid = Plan::new("Arc").with_beam(resampled);
+        let valid = {
+            let group = valid.fraction_group_of_beams(1, 5);
+            valid.with_fraction_group(group)
+        };
+        let options = ValidationOptions::default();
+        assert!(valid.validate(&options).is_empty());
+        assert_eq!(
+            valid
+                .validate(&ValidationOptions {
+                    max_gantry_step: 4.0,
+                    ..options
+                })
+                .len(),
+            8
+        );
+
+        let mut broken = Plan::new("Arc").with_beam(beam.clone());
+        broken.beams[0].control_points[1].point.weight = 1.5;
+        broken.beams[0].control_points[2].gantry = 0.0;
+        broken.beams[0].control_points[2].couch = 10.0;
+        let mut second = beam;
+        second.number = 2;
+        broken = broken.with_beam(second).with_fraction_group(FractionGroup {
+            number: 1,
+            fractions: 0,
+            beams: vec![
+                ReferencedBeam {
+                    beam: 1,
+                    meterset: 90.0,
+                },
+                ReferencedBeam {
+                    beam: 1,
+                    meterset: 100.0,
+                },
+                ReferencedBeam {
+                    beam: 3,
+                    meterset: 100.0,
+                },
+            ],
+        });
+        let issues = broken.validate(&options);
+        let expected = vec![
+            PlanIssue::Weight {
+                beam: 1,
+                control_point: 1,
+                weight: 1.5,
+            },
+            PlanIssue::Weight {
+                beam: 1,
+                control_point: 2,
+                weight: 1.0,
+            },
+            PlanIssue::GantryStep {
+                beam: 1,
+                control_point: 1,
+                step: 20.0,
+            },
+            PlanIssue::GantryReversal {
+                beam: 1,
+                control_point: 2,
+            },
+            PlanIssue::AngleChange {
+                beam: 1,
+                control_point: 2,
+                axis: "couch",
+            },
+            PlanIssue::GantryStep {
+                beam: 2,
+                control_point: 1,
+                step: 20.0,
+            },
+            PlanIssue::GantryStep {
+                beam: 2,
+                control_point: 2,
+                step: 20.0,
+            },
+            PlanIssue::Fractions { group: 1 },
+            PlanIssue::Meterset {
+                group: 1,
+                beam: 1,
+                group_meterset: 90.0,
+                beam_meterset: 100.0,
+            },
+            PlanIssue::DuplicateBeam { group: 1, beam: 1 },
+            PlanIssue::UnknownBeam { group: 1, beam: 3 },
+            PlanIssue::UnreferencedBeam { beam: 2 },
+        ];
+        assert_eq!(issues, expected);
+        assert_eq!(
+            issues[3].to_string(),
+            "beam 1, control point 2: gantry reverses"
+        );
+    }
+
+    /// An arc through `gantry` with the meterset spread evenly, alone in a plan of 5 fractions.
+    fn arc_plan(gantry: &[f64]) -> Plan {
+        let geometry = BeamGeometry::new(Vec3::new(), 0.0, 1000.0);
+        let last = (gantry.len() - 1) as f64;
+        let arc: Vec<ArcControlPoint> = gantry
+            .iter()
+            .enumerate()
+            .map(|(n, g)| ArcControlPoint {
+                gantry: *g,
+                point: point(n as f64 / last, vec![[-5.0, 5.0]; 60]),
+                dose_rate: 600.0,
+                gantry_speed: 4.0,
+            })
+            .collect();
+        let plan = Plan::new("Arc").with_beam(Beam::arc(1, "Arc", "6X", &geometry, 100.0, &arc));
+        let group = plan.fraction_group_of_beams(1, 5);
+        plan.with_fraction_group(group)
+    }
+
+    fn issues(plan: &Plan) -> Vec<PlanIssue> {
+        plan.validate(&ValidationOptions::default())
+    }
+
+    #[test]
+    fn beam_kind_of_fixed_beams() {
+        let geometry = BeamGeometry::new(Vec3::new(), 90.0, 1000.0);
+        let (open, small) = (vec![[-10.0, 10.0]; 60], vec![[-5.0, 5.0]; 60]);
+        let kind = |points: &[delivery::ControlPoint]| {
+            Beam::fixed(1, "IMRT", "6X", &geometry, 100.0, points).kind
+        };
+        // Leaves moving with the beam off between segments.
+        assert_eq!(
+            kind(&[
+                point(0.0, open.clone()),
+                point(0.5, open.clone()),
+                point(0.5, small.clone()),
+                point(1.0, small.clone()),
+            ]),
+            BeamKind::Static
+        );
+        assert_eq!(
+            kind(&[point(0.0, open.clone()), point(1.0, open.clone())]),
+            BeamKind::Static
+        );
+        // Sliding window.
+        assert_eq!(
+            kind(&[point(0.0, open.clone()), point(1.0, small.clone())]),
+            BeamKind::Dynamic
+        );
+        assert_eq!(
+            kind(&[
+                point(0.0, open),
+                point(0.5, small.clone()),
+                point(1.0, small)
+            ]),
+            BeamKind::Dynamic
+        );
+        let beam = Beam::fixed(1, "IMRT", "6X", &geometry, 100.0, &[point(0.0, vec![])]);
+        assert!(beam
+            .control_points
+            .iter()
+            .all(|p| p.gantry == 90.0 && p.dose_rate.is_none()));
+    }
+
+    #[test]
+    fn validate_control_point_count() {
+        let mut plan = arc_plan(&[0.0, 10.0]);
+        plan.beams[0].control_points.truncate(1);
+        assert_eq!(
+            issues(&plan),
+            vec![PlanIssue::ControlPoints { beam: 1, count: 1 }]
+        );
+        plan.beams[0].control_points.clear();
+        assert_eq!(
+            issues(&plan),
+            vec![PlanIssue::ControlPoints { beam: 1, count: 0 }]
+        );
+    }
+
+    #[test]
+    fn validate_weights() {
+        let mut plan = arc_plan(&[0.0, 5.0, 10.0]);
+        assert!(issues(&plan).is_empty());
+        plan.beams[0].control_points[1].point.weight = f64::NAN;
+        let found = issues(&plan);
+        assert_eq!(found.len(), 1);
+        assert!(matches!(
+            found[0],
+            PlanIssue::Weight {
+                beam: 1,
+                control_point: 1,
+                ..
+            }
+        ));
+        plan.beams[0].control_points[1].point.weight = -0.5;
+        assert_eq!(
+            issues(&plan),
+            vec![PlanIssue::Weight {
+                beam: 1,
+                control_point: 1,
+                weight: -0.5
+            }]
+        );
+    }
+
+    #[test]
+    fn validate_weight_range() {
+        let mut plan = arc_plan(&[0.0, 5.0, 10.0]);
+        plan.beams[0].control_points[0].point.weight = 0.1;
+        plan.beams[0].control_points[2].point.weight = 0.9;
+        assert_eq!(
+            issues(&plan),
+            vec![PlanIssue::WeightRange {
+                beam: 1,
+                first: 0.1,
+                last: 0.9
+            }]
+        );
+    }
+
+    #[test]
+    fn validate_gantry_reversal() {
+        // Through 0 degrees is no reversal.
+        assert!(issues(&arc_plan(&[350.0, 355.0, 0.0, 5.0])).is_empty());
+        assert_eq!(
+            issues(&arc_plan(&[350.0, 355.0, 0.0, 355.0])),
+            vec![PlanIssue::GantryReversal {
+                beam: 1,
+                control_point: 3
+            }]
+        );
+    }
+
+    #[test]
+    fn validate_gantry_step() {
+        let plan = arc_plan(&[0.0, 10.0, 25.0]);
+        assert_eq!(
+            issues(&plan),
+            vec![PlanIssue::GantryStep {
+                beam: 1,
+                control_point: 2,
+                step: 15.0
+            }]
+        );
+        let options = ValidationOptions {
+            max_gantry_step: 15.0,
+            ..ValidationOptions::default()
+        };
+        assert!(plan.validate(&options).is_empty());
+    }
+
+    #[test]
+    fn validate_angle_change() {
+        let mut plan = arc_plan(&[0.0, 5.0, 10.0]);
+        // The same angle the other way round is no change.
+        plan.beams[0].control_points[0].collimator = 360.0;
+        assert!(issues(&plan).is_empty());
+        plan.beams[0].control_points[2].collimator = 45.0;
+        let found = issues(&plan);
+        assert_eq!(
+            found,
+            vec![PlanIssue::AngleChange {
+                beam: 1,
+                control_point: 2,
+                axis: "collimator"
+            }]
+        );
+        assert_eq!(
+            found[0].to_string(),
+            "beam 1, control point 2: collimator angle changes"
+        );
+    }
+
+    #[test]
+    fn validate_fraction_groups() {
+        let mut plan = arc_plan(&[0.0, 10.0]);
+        plan.fraction_groups[0].fractions = 0;
+        assert_eq!(issues(&plan), vec![PlanIssue::Fractions { group: 1 }]);
+
+        let mut plan = arc_plan(&[0.0, 10.0]);
+        plan.fraction_groups[0].beams[0].meterset = 100.05;
+        assert!(issues(&plan).is_empty());
+        plan.fraction_groups[0].beams[0].meterset = 101.0;
+        assert_eq!(
+            issues(&plan),
+            vec![PlanIssue::Meterset {
+                group: 1,
+                beam: 1,
+                group_meterset: 101.0,
+                beam_meterset: 100.0
+            }]
+        );
+    }
+
+    #[test]
+    fn validate_beam_references() {
+        let mut plan = arc_plan(&[0.0, 10.0]);
+        let reference = plan.fraction_groups[0].beams[0];
+        plan.fraction_groups[0].beams.push(reference);
+        assert_eq!(
+            issues(&plan),
+            vec![PlanIssue::DuplicateBeam { group: 1, beam: 1 }]
+        );
+
+        let mut plan = arc_plan(&[0.0, 10.0]);
+        plan.fraction_groups[0].beams[0].beam = 2;
+        assert_eq!(
+            issues(&plan),
+            vec![
+                PlanIssue::UnknownBeam { group: 1, beam: 2 },
+                PlanIssue::UnreferencedBeam { beam: 1 }
+            ]
+        );
+        plan.fraction_groups.clear();
+        assert_eq!(issues(&plan), vec![PlanIssue::UnreferencedBeam { beam: 1 }]);
+    }
+}
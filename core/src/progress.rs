@@ -0,0 +1,162 @@
+//! Progress reporting and cancellation of long computations.
+//!
+//! A [`Progress`] handle travels with a computation: dose engines take it in their
+//! [`crate::dose::CalculationOptions`], gamma analysis, registration and fluence map
+//! optimization in their `*_with_progress` variants. The computation reports the completed
+//! fraction of its work, from 0 to 1, to the callback of the handle, possibly from several
+//! worker threads, and stops with [`Error::Cancelled`] soon after the [`CancellationToken`]
+//! of the handle is cancelled. Nested steps report into a [`Progress::stage`] of the overall
+//! range. Without a callback and a token the handle costs nothing.
+
+use crate::error::{Error, Result};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Shared flag to abort a computation, e.g. from a GUI thread; clones share the flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+type Callback = dyn Fn(f64) + Send + Sync;
+
+#[derive(Clone)]
+pub struct Progress {
+    callback: Option<Arc<Callback>>,
+    token: Option<CancellationToken>,
+    /// Overall fractions at the start and the end of the work of this handle.
+    range: [f64; 2],
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress {
+            callback: None,
+            token: None,
+            range: [0.0, 1.0],
+        }
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Progress")
+            .field("callback", &self.callback.is_some())
+            .field("token", &self.token)
+            .field("range", &self.range)
+            .finish()
+    }
+}
+
+impl Progress {
+    /// A handle that reports nowhere and is never cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reports the completed fraction to `callback`.
+    pub fn with_callback<F: Fn(f64) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    pub fn with_token(mut self, token: CancellationToken) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// A handle sending the completed fractions to the returned receiver, e.g. for a server
+    /// thread polling the progress of a job.
+    pub fn channel() -> (Self, Receiver<f64>) {
+        let (sender, receiver) = mpsc::channel();
+        let sender: Mutex<Sender<f64>> = Mutex::new(sender);
+        let progress = Self::new().with_callback(move |fraction| {
+            if let Ok(sender) = sender.lock() {
+                // The receiver may be gone; the computation carries on regardless.
+                let _ = sender.send(fraction);
+            }
+        });
+        (progress, receiver)
+    }
+
+    /// The handle of a step covering the fractions `start` to `end` of the work of this one.
+    pub fn stage(&self, start: f64, end: f64) -> Self {
+        let [lo, hi] = self.range;
+        Progress {
+            range: [lo + start * (hi - lo), lo + end * (hi - lo)],
+            ..self.clone()
+        }
+    }
+
+    /// Reports that `fraction` of the work of this handle is done.
+    pub fn report(&self, fraction: f64) {
+        if let Some(callback) = &self.callback {
+            let [lo, hi] = self.range;
+            callback(lo + fraction.clamp(0.0, 1.0) * (hi - lo));
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.token.as_ref().is_some_and(|t| t.is_cancelled())
+    }
+
+    /// [`Error::Cancelled`] once the token is cancelled.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reports `fraction` and checks for cancellation.
+    pub fn update(&self, fraction: f64) -> Result<()> {
+        self.report(fraction);
+        self.check()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::progress::{CancellationToken, Progress};
+
+    #[test]
+    fn progress_stages_and_cancellation() {
+        let (progress, updates) = Progress::channel();
+        progress.report(0.25);
+        let second = progress.stage(0.5, 1.0);
+        second.report(0.5);
+        second.stage(0.0, 0.5).report(2.0);
+        assert_eq!(
+            updates.try_iter().collect::<Vec<_>>(),
+            vec![0.25, 0.75, 0.75]
+        );
+
+        let token = CancellationToken::new();
+        let progress = progress.with_token(token.clone());
+        assert!(progress.update(0.1).is_ok());
+        token.cancel();
+        assert!(matches!(
+            progress.stage(0.2, 0.4).check(),
+            Err(Error::Cancelled)
+        ));
+        assert!(Progress::new().check().is_ok());
+    }
+}
@@ -0,0 +1,459 @@
+//! Clinical goals and protocols evaluated against a plan.
+//!
+//! A goal reads `<structure> <metric> <comparison> <objective> [unit]`, e.g.
+//! `SpinalCord D0.03cc < 45 Gy` or `PTV V95% >= 98%`; the metric uses the syntax of
+//! [`crate::metric`] and a unit after the objective (`Gy`, `cGy`, `%` or `cc`) sets the
+//! result unit of the metric. A missed objective is a warning while the value still meets
+//! the optional warning limit, and a failure otherwise.
+//!
+//! Protocols are built in code or loaded from a TOML subset; there is no JSON reader. A
+//! `warning` limit is in the unit of the objective of its constraint:
+//!
+//! ```toml
+//! name = "Prostate 60 Gy / 20 fx"
+//! prescription = 60.0
+//!
+//! [[goal]]
+//! constraint = "PTV V95% >= 98%"
+//! warning = 95.0
+//!
+//! [[goal]]
+//! constraint = "Heart Dmean < 2600 cGy"
+//! warning = 2800   # cGy
+//! ```
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::dvh::{dvh, Dvh, DvhOptions, VolumeUnit};
+use crate::error::{Error, Result};
+use crate::grid::Grid3;
+use crate::io::toml::Document;
+use crate::metric::{DoseUnit, Metric, MetricValue};
+use crate::structure::StructureSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    pub fn holds(self, value: f64, limit: f64) -> bool {
+        match self {
+            Comparison::Less => value < limit,
+            Comparison::LessOrEqual => value <= limit,
+            Comparison::Greater => value > limit,
+            Comparison::GreaterOrEqual => value >= limit,
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        })
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "<" => Ok(Comparison::Less),
+            "<=" | "≤" => Ok(Comparison::LessOrEqual),
+            ">" => Ok(Comparison::Greater),
+            ">=" | "≥" => Ok(Comparison::GreaterOrEqual),
+            _ => Err(Error::Format(format!("invalid comparison: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Goal {
+    pub structure: String,
+    pub metric: Metric,
+    pub comparison: Comparison,
+    /// In the result unit of the metric.
+    pub objective: f64,
+    /// Limit a missed objective still has to meet to be a warning.
+    pub warning: Option<f64>,
+}
+
+impl Goal {
+    pub fn new(structure: &str, metric: Metric, comparison: Comparison, objective: f64) -> Self {
+        Goal {
+            structure: structure.to_string(),
+            metric,
+            comparison,
+            objective,
+            warning: None,
+        }
+    }
+
+    pub fn with_warning(mut self, limit: f64) -> Self {
+        self.warning = Some(limit);
+        self
+    }
+
+    /// Status of a metric `value` against the objective and warning limit.
+    pub fn status(&self, value: f64) -> GoalStatus {
+        if self.comparison.holds(value, self.objective) {
+            GoalStatus::Pass
+        } else if self
+            .warning
+            .is_some_and(|w| self.comparison.holds(value, w))
+        {
+            GoalStatus::Warn
+        } else {
+            GoalStatus::Fail
+        }
+    }
+}
+
+/// `metric` with its result unit set by an objective `unit`, and the factor converting the
+/// objective to that unit.
+fn with_unit(metric: Metric, unit: &str) -> Option<(Metric, f64)> {
+    let unit = unit.to_ascii_lowercase();
+    let dose_unit = match unit.as_str() {
+        "gy" | "cgy" => Some(DoseUnit::Gy),
+        "%" => Some(DoseUnit::Percent),
+        _ => None,
+    };
+    let scale = if unit == "cgy" { 0.01 } else { 1.0 };
+    let m = match metric {
+        Metric::Mean(_) => Metric::Mean(dose_unit?),
+        Metric::Max(_) => Metric::Max(dose_unit?),
+        Metric::Min(_) => Metric::Min(dose_unit?),
+        Metric::Dose {
+            volume,
+            volume_unit,
+            ..
+        } => Metric::Dose {
+            volume,
+            volume_unit,
+            unit: dose_unit?,
+        },
+        Metric::Volume {
+            dose, dose_unit, ..
+        } => Metric::Volume {
+            dose,
+            dose_unit,
+            unit: match unit.as_str() {
+                "%" => VolumeUnit::Percent,
+                "cc" => VolumeUnit::Cc,
+                _ => return None,
+            },
+        },
+    };
+    Some((m, scale))
+}
+
+/// The goal `s` and the factor converting its objective unit to the result unit.
+fn parse_goal(s: &str) -> Result<(Goal, f64)> {
+    let invalid = || Error::Format(format!("invalid clinical goal: {}", s));
+    let words: Vec<&str> = s.split_whitespace().collect();
+    let at = words
+        .iter()
+        .position(|w| w.parse::<Comparison>().is_ok())
+        .ok_or_else(invalid)?;
+    if at < 2 {
+        return Err(invalid());
+    }
+    let comparison: Comparison = words[at].parse()?;
+    let metric: Metric = words[at - 1].parse()?;
+    let structure = words[..at - 1].join(" ");
+    let objective = words[at + 1..].concat();
+    let split = objective
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+        .unwrap_or(objective.len());
+    let value: f64 = objective[..split].parse().map_err(|_| invalid())?;
+    let (metric, scale) = match &objective[split..] {
+        "" => (metric, 1.0),
+        unit => with_unit(metric, unit).ok_or_else(invalid)?,
+    };
+    Ok((
+        Goal::new(&structure, metric, comparison, value * scale),
+        scale,
+    ))
+}
+
+impl FromStr for Goal {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        parse_goal(s).map(|(goal, _)| goal)
+    }
+}
+
+impl fmt::Display for Goal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.structure, self.metric, self.comparison, self.objective
+        )
+    }
+}
+
+/// Ordered from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum GoalStatus {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GoalResult {
+    pub goal: Goal,
+    /// `None` when the plan has no structure of that name, which fails the goal.
+    pub value: Option<MetricValue>,
+    pub status: GoalStatus,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtocolReport {
+    pub protocol: String,
+    pub results: Vec<GoalResult>,
+}
+
+impl ProtocolReport {
+    /// The worst status of all goals; `Pass` without goals.
+    pub fn status(&self) -> GoalStatus {
+        self.results
+            .iter()
+            .map(|r| r.status)
+            .max()
+            .unwrap_or(GoalStatus::Pass)
+    }
+
+    pub fn with_status(&self, status: GoalStatus) -> impl Iterator<Item = &GoalResult> {
+        self.results.iter().filter(move |r| r.status == status)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Protocol {
+    pub name: String,
+    /// Gy; needed by goals with relative doses.
+    pub prescription: Option<f64>,
+    pub goals: Vec<Goal>,
+}
+
+impl Protocol {
+    pub fn new(name: &str) -> Self {
+        Protocol {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_prescription(mut self, prescription: f64) -> Self {
+        self.prescription = Some(prescription);
+        self
+    }
+
+    pub fn with_goal(mut self, goal: Goal) -> Self {
+        self.goals.push(goal);
+        self
+    }
+
+    /// Parses the TOML subset in the module documentation: top-level `name` and
+    /// `prescription`, and `[[goal]]` tables with a `constraint` and optional `warning` in
+    /// the unit of the constraint objective.
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let doc = Document::parse(text)?;
+        doc.check_tables(&["goal"])?;
+        let root = doc.root();
+        root.check_keys(&["name", "prescription"])?;
+        let mut protocol = Protocol {
+            name: root.string("name")?.unwrap_or_default().to_string(),
+            prescription: root.number("prescription")?,
+            goals: Vec::new(),
+        };
+        for table in doc.tables("goal") {
+            table.check_keys(&["constraint", "warning"])?;
+            let (goal, scale) = match table.string("constraint")? {
+                Some(c) => parse_goal(c)?,
+                None => {
+                    return Err(Error::Format(
+                        "[[goal]] table without a constraint".to_string(),
+                    ))
+                }
+            };
+            protocol.goals.push(Goal {
+                warning: table.number("warning")?.map(|w| w * scale),
+                ..goal
+            });
+        }
+        Ok(protocol)
+    }
+
+    pub fn read_toml<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    /// Evaluates the goals on DVHs, matched to goal structures by case-insensitive name.
+    pub fn evaluate_dvhs(&self, dvhs: &[Dvh]) -> Result<ProtocolReport> {
+        let mut results = Vec::with_capacity(self.goals.len());
+        for goal in &self.goals {
+            let name = goal.structure.trim();
+            let value = match dvhs
+                .iter()
+                .find(|d| d.name.trim().eq_ignore_ascii_case(name))
+            {
+                Some(d) => Some(goal.metric.evaluate(d, self.prescription)?),
+                None => None,
+            };
+            results.push(GoalResult {
+                goal: goal.clone(),
+                status: value.map_or(GoalStatus::Fail, |v| goal.status(v.value())),
+                value,
+            });
+        }
+        Ok(ProtocolReport {
+            protocol: self.name.clone(),
+            results,
+        })
+    }
+
+    /// Evaluates the goals on the DVHs of `structures` on the grid of `dose`.
+    pub fn evaluate(
+        &self,
+        structures: &StructureSet,
+        dose: &Grid3<f64>,
+        options: &DvhOptions,
+    ) -> Result<ProtocolReport> {
+        let mut dvhs: Vec<Dvh> = Vec::new();
+        for goal in &self.goals {
+            let structure = match structures.find(&goal.structure) {
+                Some(s) => s,
+                None => continue,
+            };
+            if dvhs.iter().all(|d| d.name != structure.name) {
+                dvhs.push(dvh(structure, dose, options)?);
+            }
+        }
+        self.evaluate_dvhs(&dvhs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dvh::{Dvh, VolumeUnit};
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::metric::{DoseUnit, Metric};
+    use crate::protocol::{Comparison, Goal, GoalStatus, Protocol};
+
+    fn dvh(name: &str, doses: &[f64]) -> Dvh {
+        let geometry = GridGeometry::new(
+            [doses.len(), 1, 1],
+            Vec3::new(),
+            Vec3::from(10.0, 10.0, 10.0),
+        );
+        let dose = Grid3::from_vec(geometry, doses.to_vec()).unwrap();
+        Dvh::from_fraction(name, &dose.map(|_| 1.0), &dose, 0.1).unwrap()
+    }
+
+    #[test]
+    fn goal_parse() {
+        let g: Goal = "Spinal Cord D0.03cc < 45 Gy".parse().unwrap();
+        assert_eq!(g.structure, "Spinal Cord");
+        assert_eq!(g.comparison, Comparison::Less);
+        assert_eq!(g.objective, 45.0);
+        let g: Goal = "PTV V95% ≥ 98%".parse().unwrap();
+        assert_eq!(g.comparison, Comparison::GreaterOrEqual);
+        let g: Goal = "Lungs V20Gy <= 200cc".parse().unwrap();
+        assert_eq!(
+            g.metric,
+            Metric::Volume {
+                dose: 20.0,
+                dose_unit: DoseUnit::Gy,
+                unit: VolumeUnit::Cc
+            }
+        );
+        let g: Goal = "Heart Dmean < 2600 cGy".parse().unwrap();
+        assert_eq!((g.metric, g.objective), (Metric::Mean(DoseUnit::Gy), 26.0));
+        for bad in [
+            "PTV V95%",
+            "V95% >= 98",
+            "PTV V95% >= 98 Gy",
+            "PTV D95% >= high",
+        ]
+        .iter()
+        {
+            assert!(bad.parse::<Goal>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn protocol_evaluate() {
+        let protocol = Protocol::from_toml(
+            r#"
+            # Test protocol
+            name = "Test # 1"
+            prescription = 60
+
+            [[goal]]
+            constraint = "PTV V95% >= 98%"
+            warning = 90 # still acceptable
+
+            [[goal]]
+            constraint = "cord Dmax < 45 Gy"
+
+            [[goal]]
+            constraint = "Rectum Dmean < 30 Gy"
+
+            [[goal]]
+            constraint = "Cord Dmax < 4000 cGy"
+            warning = 4500
+            "#,
+        )
+        .unwrap();
+        assert_eq!(protocol.name, "Test # 1");
+        assert_eq!(protocol.goals[0].warning, Some(90.0));
+        assert_eq!(protocol.goals[3].warning, Some(45.0));
+        let dvhs = [
+            dvh(
+                "PTV",
+                &[56.0, 60.0, 60.5, 61.0, 61.0, 61.0, 61.0, 61.0, 61.0, 61.0],
+            ),
+            dvh("Cord", &[44.0]),
+        ];
+        let report = protocol.evaluate_dvhs(&dvhs).unwrap();
+        let statuses: Vec<GoalStatus> = report.results.iter().map(|r| r.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                GoalStatus::Warn,
+                GoalStatus::Pass,
+                GoalStatus::Fail,
+                GoalStatus::Warn
+            ]
+        );
+        assert!((report.results[0].value.unwrap().value() - 90.0).abs() < 1e-9);
+        assert!(report.results[2].value.is_none());
+        assert_eq!(report.status(), GoalStatus::Fail);
+        assert_eq!(report.with_status(GoalStatus::Pass).count(), 1);
+
+        let coded = Protocol::new("Coded").with_goal(Goal::new(
+            "PTV",
+            "Dmean[%]".parse().unwrap(),
+            Comparison::Greater,
+            100.0,
+        ));
+        assert!(coded.evaluate_dvhs(&dvhs).is_err());
+        assert!(Protocol::from_toml("[[goal]]\nwarning = 1").is_err());
+        assert!(Protocol::from_toml("name = unquoted").is_err());
+    }
+}
@@ -0,0 +1,207 @@
+//! Linear-quadratic dose conversions.
+//!
+//! A planned total dose `D` in `n` fractions gives `d = D / n` per fraction; of the `m`
+//! delivered fractions `BED = m d (1 + d / (α/β))` and `EQD2 = BED / (1 + 2 / (α/β))`.
+
+use crate::dvh::Dvh;
+use crate::error::{Error, Result};
+use crate::grid::Grid3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fractionation {
+    /// Fractions the planned dose is prescribed in.
+    pub fractions: u32,
+    /// Fractions delivered so far (or to be evaluated).
+    pub delivered: u32,
+}
+
+impl Fractionation {
+    /// All `fractions` delivered.
+    pub fn new(fractions: u32) -> Self {
+        Fractionation {
+            fractions,
+            delivered: fractions,
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.fractions == 0 {
+            return Err(Error::InvalidArgument(
+                "fractionation needs at least one fraction".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn check_alpha_beta(alpha_beta: f64) -> Result<()> {
+    if alpha_beta <= 0.0 {
+        return Err(Error::InvalidArgument(format!(
+            "α/β must be positive, got {}",
+            alpha_beta
+        )));
+    }
+    Ok(())
+}
+
+/// BED (Gy) of a planned total `dose`.
+pub fn bed(dose: f64, alpha_beta: f64, fractionation: Fractionation) -> Result<f64> {
+    check_alpha_beta(alpha_beta)?;
+    fractionation.check()?;
+    Ok(lq_bed(dose, alpha_beta, fractionation))
+}
+
+/// EQD2 (Gy) of a planned total `dose`.
+pub fn eqd2(dose: f64, alpha_beta: f64, fractionation: Fractionation) -> Result<f64> {
+    check_alpha_beta(alpha_beta)?;
+    fractionation.check()?;
+    Ok(lq_eqd2(dose, alpha_beta, fractionation))
+}
+
+fn lq_bed(dose: f64, alpha_beta: f64, fractionation: Fractionation) -> f64 {
+    let d = dose / fractionation.fractions as f64;
+    fractionation.delivered as f64 * d * (1.0 + d / alpha_beta)
+}
+
+fn lq_eqd2(dose: f64, alpha_beta: f64, fractionation: Fractionation) -> f64 {
+    lq_bed(dose, alpha_beta, fractionation) / (1.0 + 2.0 / alpha_beta)
+}
+
+/// Voxel-wise BED of a planned dose grid.
+pub fn bed_grid(
+    dose: &Grid3<f64>,
+    alpha_beta: f64,
+    fractionation: Fractionation,
+) -> Result<Grid3<f64>> {
+    check_alpha_beta(alpha_beta)?;
+    fractionation.check()?;
+    Ok(dose.map(|d| lq_bed(*d, alpha_beta, fractionation)))
+}
+
+/// Voxel-wise EQD2 with per-structure α/β: a voxel takes the α/β of the first structure mask
+/// in `structures` containing it, `default_alpha_beta` otherwise.
+pub fn eqd2_grid(
+    dose: &Grid3<f64>,
+    fractionation: Fractionation,
+    structures: &[(&Grid3<bool>, f64)],
+    default_alpha_beta: f64,
+) -> Result<Grid3<f64>> {
+    fractionation.check()?;
+    check_alpha_beta(default_alpha_beta)?;
+    for (mask, alpha_beta) in structures {
+        check_alpha_beta(*alpha_beta)?;
+        if mask.dims() != dose.dims() {
+            return Err(Error::InvalidArgument(format!(
+                "mask and dose grids differ in size: {:?} vs {:?}",
+                mask.dims(),
+                dose.dims()
+            )));
+        }
+    }
+    let mut out = dose.clone();
+    for (n, v) in out.data_mut().iter_mut().enumerate() {
+        let alpha_beta = structures
+            .iter()
+            .find(|(mask, _)| mask.data()[n])
+            .map_or(default_alpha_beta, |(_, ab)| *ab);
+        *v = lq_eqd2(*v, alpha_beta, fractionation);
+    }
+    Ok(out)
+}
+
+/// The DVH with its dose axis converted by `f`, rebinned at the same bin width. Every bin
+/// moves with its center dose, which also gives the mean; min and max are converted
+/// exactly.
+fn convert_dvh<F: Fn(f64) -> f64>(dvh: &Dvh, f: F) -> Dvh {
+    let w = dvh.bin_width;
+    let mut out = Dvh {
+        bins: Vec::new(),
+        min: f(dvh.min),
+        max: f(dvh.max),
+        mean: 0.0,
+        ..dvh.clone()
+    };
+    let mut dose_volume = 0.0;
+    for (n, v) in dvh.bins.iter().enumerate() {
+        if *v == 0.0 {
+            continue;
+        }
+        let d = f((n as f64 + 0.5) * w);
+        let bin = (d.max(0.0) / w) as usize;
+        if bin >= out.bins.len() {
+            out.bins.resize(bin + 1, 0.0);
+        }
+        out.bins[bin] += v;
+        dose_volume += v * d;
+    }
+    if dvh.volume > 0.0 {
+        out.mean = dose_volume / dvh.volume;
+    }
+    out
+}
+
+/// BED DVH of a DVH of the planned dose.
+pub fn bed_dvh(dvh: &Dvh, alpha_beta: f64, fractionation: Fractionation) -> Result<Dvh> {
+    check_alpha_beta(alpha_beta)?;
+    fractionation.check()?;
+    Ok(convert_dvh(dvh, |d| lq_bed(d, alpha_beta, fractionation)))
+}
+
+/// EQD2 DVH of a DVH of the planned dose.
+pub fn eqd2_dvh(dvh: &Dvh, alpha_beta: f64, fractionation: Fractionation) -> Result<Dvh> {
+    check_alpha_beta(alpha_beta)?;
+    fractionation.check()?;
+    Ok(convert_dvh(dvh, |d| lq_eqd2(d, alpha_beta, fractionation)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::dvh::{Dvh, VolumeUnit};
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::radiobiology::{bed, eqd2, eqd2_dvh, eqd2_grid, Fractionation};
+
+    #[test]
+    fn lq_conversions() {
+        // 60 Gy in 30 fractions is 60 Gy EQD2 for any α/β.
+        assert!((eqd2(60.0, 3.0, Fractionation::new(30)).unwrap() - 60.0).abs() < 1e-12);
+        // 3 x 18 Gy with α/β 10: BED 151.2 Gy, EQD2 126 Gy.
+        assert!((bed(54.0, 10.0, Fractionation::new(3)).unwrap() - 151.2).abs() < 1e-9);
+        assert!((eqd2(54.0, 10.0, Fractionation::new(3)).unwrap() - 126.0).abs() < 1e-9);
+        let partial = Fractionation {
+            fractions: 3,
+            delivered: 1,
+        };
+        assert!((bed(54.0, 10.0, partial).unwrap() - 50.4).abs() < 1e-9);
+    }
+
+    #[test]
+    fn lq_conversions_reject_invalid_parameters() {
+        let f = Fractionation::new(3);
+        assert!(bed(54.0, 0.0, f).is_err());
+        assert!(eqd2(54.0, -3.0, f).is_err());
+        assert!(bed(54.0, 10.0, Fractionation::new(0)).is_err());
+        assert!(eqd2(54.0, 10.0, Fractionation::new(0)).is_err());
+    }
+
+    #[test]
+    fn eqd2_structure_alpha_beta() {
+        let geometry = GridGeometry::new([2, 1, 1], Vec3::new(), Vec3::from(1.0, 1.0, 1.0));
+        let dose = Grid3::new(geometry.clone(), 50.0);
+        let mut cord = Grid3::new(geometry, false);
+        cord.set(1, 0, 0, true);
+        let f = Fractionation::new(5);
+        let e = eqd2_grid(&dose, f, &[(&cord, 2.0)], 10.0).unwrap();
+        assert!((e.data()[0] - eqd2(50.0, 10.0, f).unwrap()).abs() < 1e-12);
+        assert!((e.data()[1] - 150.0).abs() < 1e-12);
+        assert!(eqd2_grid(&dose, Fractionation::new(0), &[], 10.0).is_err());
+        assert!(eqd2_grid(&dose, f, &[(&cord, 0.0)], 10.0).is_err());
+
+        let h = Dvh::from_fraction("CORD", &cord.map(|v| *v as u8 as f64), &dose, 0.1).unwrap();
+        let converted = eqd2_dvh(&h, 2.0, f).unwrap();
+        assert!((converted.max - 150.0).abs() < 1e-9);
+        assert!((converted.mean - 150.0).abs() < 0.5);
+        assert!((converted.volume_at(149.0, VolumeUnit::Percent) - 100.0).abs() < 1e-9);
+        assert_eq!(converted.volume_at(151.0, VolumeUnit::Percent), 0.0);
+    }
+}
@@ -0,0 +1,434 @@
+//! Scan conversion of axial contours into voxel masks.
+//!
+//! All polygons on a slice are filled together with the even-odd rule, so nested polygons
+//! become holes. Each grid slice takes the contours of the nearest contoured slice when it
+//! lies within half a contour slice thickness of it; the thickness is the median distance
+//! between contoured slices, so gaps in the contouring stay empty. Contours with a
+//! non-finite coordinate are ignored.
+
+use crate::coords::Vec3;
+use crate::grid::{Grid3, GridGeometry};
+use crate::structure::{Contour, Structure};
+
+/// Contours closer than this (mm) in z are treated as lying on the same slice.
+pub const SLICE_TOLERANCE: f64 = 1e-3;
+
+pub fn rasterize(structure: &Structure, geometry: &GridGeometry) -> Grid3<bool> {
+    rasterize_contours(&structure.contours, geometry)
+}
+
+pub fn rasterize_contours(contours: &[Contour], geometry: &GridGeometry) -> Grid3<bool> {
+    let mut mask = Grid3::new(geometry.clone(), false);
+    let slices = group_slices(contours);
+    if slices.is_empty() || geometry.is_empty() {
+        return mask;
+    }
+    let half = 0.5 * thickness_or_grid(&slices, geometry) + SLICE_TOLERANCE;
+    let [nx, ny, nz] = geometry.dims;
+    if is_axial(geometry) {
+        for k in 0..nz {
+            let z = geometry.position(0, 0, k).z;
+            let polygons = match nearest_slice(&slices, z, half) {
+                Some(p) => p,
+                None => continue,
+            };
+            let offset = geometry.offset(0, 0, k);
+            fill_slice(
+                polygons,
+                geometry,
+                &mut mask.data_mut()[offset..offset + nx * ny],
+            );
+        }
+    } else {
+        // Oblique grids: test every voxel center against the slice it falls on.
+        for (n, v) in mask.data_mut().iter_mut().enumerate() {
+            let [i, j, k] = geometry.ijk(n);
+            let p = geometry.position(i, j, k);
+            if let Some(polygons) = nearest_slice(&slices, p.z, half) {
+                *v = polygons.iter().filter(|c| crosses(c, p)).count() % 2 == 1;
+            }
+        }
+    }
+    mask
+}
+
+/// Marks the pixels of one slice of an axial `geometry` inside `contours` (even-odd rule).
+pub(crate) fn fill_slice(contours: &[&Contour], geometry: &GridGeometry, data: &mut [bool]) {
+    let w2i = geometry.world_to_index();
+    let polygons: Vec<Vec<(f64, f64)>> = contours
+        .iter()
+        .map(|c| {
+            c.points
+                .iter()
+                .map(|p| {
+                    let q = w2i.transform_point(*p);
+                    (q.x, q.y)
+                })
+                .collect()
+        })
+        .collect();
+    let [nx, ny, _] = geometry.dims;
+    fill_polygons(&polygons, nx, ny, |i, j| data[i + nx * j] = true);
+}
+
+/// Fraction of every voxel covered by the contours, estimated on `samples`³ sub-voxel points.
+pub fn rasterize_fractional(
+    structure: &Structure,
+    geometry: &GridGeometry,
+    samples: usize,
+) -> Grid3<f64> {
+    let mut fraction = Grid3::new(geometry.clone(), 0.0);
+    let slices = group_slices(&structure.contours);
+    if slices.is_empty() || geometry.is_empty() {
+        return fraction;
+    }
+    let n = samples.max(1);
+    let weight = 1.0 / (n * n * n) as f64;
+    let half = 0.5 * thickness_or_grid(&slices, geometry) + SLICE_TOLERANCE;
+    let [nx, ny, nz] = geometry.dims;
+    let i2w = geometry.index_to_world();
+    let w2i = geometry.world_to_index();
+    // Sub-sample s of a voxel sits at index offset (s + 0.5) / n - 0.5.
+    let sub = |s: usize| (s as f64 + 0.5) / n as f64 - 0.5;
+    let axial = is_axial(geometry);
+    for k in 0..nz {
+        for sz in 0..n {
+            let kz = k as f64 + sub(sz);
+            let z = i2w.transform_point(Vec3::from(0.0, 0.0, kz)).z;
+            let offset = geometry.offset(0, 0, k);
+            let data = &mut fraction.data_mut()[offset..offset + nx * ny];
+            if axial {
+                let polygons = match nearest_slice(&slices, z, half) {
+                    Some(p) => p,
+                    None => continue,
+                };
+                let polygons: Vec<Vec<(f64, f64)>> = polygons
+                    .iter()
+                    .map(|c| {
+                        c.points
+                            .iter()
+                            .map(|p| {
+                                let q = w2i.transform_point(*p);
+                                ((q.x + 0.5) * n as f64 - 0.5, (q.y + 0.5) * n as f64 - 0.5)
+                            })
+                            .collect()
+                    })
+                    .collect();
+                fill_polygons(&polygons, nx * n, ny * n, |i, j| {
+                    data[i / n + nx * (j / n)] += weight
+                });
+                continue;
+            }
+            for j in 0..ny {
+                for i in 0..nx {
+                    for sy in 0..n {
+                        for sx in 0..n {
+                            let p = i2w.transform_point(Vec3::from(
+                                i as f64 + sub(sx),
+                                j as f64 + sub(sy),
+                                kz,
+                            ));
+                            let polygons = match nearest_slice(&slices, p.z, half) {
+                                Some(p) => p,
+                                None => continue,
+                            };
+                            if polygons.iter().filter(|c| crosses(c, p)).count() % 2 == 1 {
+                                data[i + nx * j] += weight;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    fraction
+}
+
+/// Contours grouped per slice, sorted by z, without those of fewer than three points or
+/// with non-finite coordinates.
+pub(crate) fn group_slices(contours: &[Contour]) -> Vec<(f64, Vec<&Contour>)> {
+    let finite = |c: &Contour| {
+        c.points
+            .iter()
+            .all(|p| p.x.is_finite() && p.y.is_finite() && p.z.is_finite())
+    };
+    let mut sorted: Vec<&Contour> = contours
+        .iter()
+        .filter(|c| c.len() >= 3 && finite(c))
+        .collect();
+    sorted.sort_by(|a, b| a.z().total_cmp(&b.z()));
+    let mut slices: Vec<(f64, Vec<&Contour>)> = Vec::new();
+    for c in sorted {
+        match slices.last_mut() {
+            Some((z, group)) if (c.z() - *z).abs() <= SLICE_TOLERANCE => group.push(c),
+            _ => slices.push((c.z(), vec![c])),
+        }
+    }
+    slices
+}
+
+/// Median distance between contoured slices; `None` for fewer than two slices.
+pub(crate) fn slice_thickness(slices: &[(f64, Vec<&Contour>)]) -> Option<f64> {
+    let mut gaps: Vec<f64> = slices.windows(2).map(|w| w[1].0 - w[0].0).collect();
+    if gaps.is_empty() {
+        return None;
+    }
+    gaps.sort_by(|a, b| a.total_cmp(b));
+    Some(gaps[gaps.len() / 2])
+}
+
+/// The contour slice thickness, or the grid slice spacing for a single slice.
+fn thickness_or_grid(slices: &[(f64, Vec<&Contour>)], geometry: &GridGeometry) -> f64 {
+    slice_thickness(slices).unwrap_or(geometry.direction[2].z.abs() * geometry.spacing.z)
+}
+
+fn nearest_slice<'a, 'b>(
+    slices: &'b [(f64, Vec<&'a Contour>)],
+    z: f64,
+    half: f64,
+) -> Option<&'b [&'a Contour]> {
+    let i = slices.partition_point(|(sz, _)| *sz < z);
+    let below = i.checked_sub(1).map(|i| &slices[i]);
+    let above = slices.get(i);
+    let best = match (below, above) {
+        (Some(b), Some(a)) => {
+            if z - b.0 <= a.0 - z {
+                b
+            } else {
+                a
+            }
+        }
+        (Some(b), None) => b,
+        (None, Some(a)) => a,
+        (None, None) => return None,
+    };
+    if (best.0 - z).abs() <= half {
+        Some(&best.1)
+    } else {
+        None
+    }
+}
+
+/// Whether the grid slices are axial planes.
+pub(crate) fn is_axial(geometry: &GridGeometry) -> bool {
+    let d = &geometry.direction;
+    d[0].z.abs() < 1e-6 && d[1].z.abs() < 1e-6 && (d[2].z.abs() - 1.0).abs() < 1e-6
+}
+
+/// Even-odd test of the axial projection of `p` against one polygon.
+pub(crate) fn crosses(contour: &Contour, p: Vec3<f64>) -> bool {
+    let pts = &contour.points;
+    let mut inside = false;
+    let n = pts.len();
+    for e in 0..n {
+        let a = pts[e];
+        let b = pts[(e + 1) % n];
+        if (a.y > p.y) != (b.y > p.y) {
+            let x = a.x + (p.y - a.y) * (b.x - a.x) / (b.y - a.y);
+            if p.x < x {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+/// Scanline fill of polygons given in continuous pixel coordinates (even-odd rule).
+///
+/// `set(i, j)` is called for every pixel center inside the polygons on an `nx` x `ny` raster.
+pub(crate) fn fill_polygons<F: FnMut(usize, usize)>(
+    polygons: &[Vec<(f64, f64)>],
+    nx: usize,
+    ny: usize,
+    mut set: F,
+) {
+    let mut xs = Vec::new();
+    for j in 0..ny {
+        let y = j as f64;
+        xs.clear();
+        for poly in polygons {
+            let n = poly.len();
+            for e in 0..n {
+                let (x0, y0) = poly[e];
+                let (x1, y1) = poly[(e + 1) % n];
+                if (y0 <= y && y < y1) || (y1 <= y && y < y0) {
+                    let x = x0 + (y - y0) * (x1 - x0) / (y1 - y0);
+                    if x.is_finite() {
+                        xs.push(x);
+                    }
+                }
+            }
+        }
+        xs.sort_by(|a, b| a.total_cmp(b));
+        for pair in xs.chunks_exact(2) {
+            let start = pair[0].ceil().max(0.0);
+            let end = pair[1].floor().min(nx as f64 - 1.0);
+            if start > end {
+                continue;
+            }
+            for i in start as usize..=end as usize {
+                set(i, j);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::affine::Affine3;
+    use crate::coords::Vec3;
+    use crate::grid::GridGeometry;
+    use crate::raster::{fill_polygons, rasterize, rasterize_contours, rasterize_fractional};
+    use crate::structure::{Contour, Structure, StructureType};
+
+    fn square(cx: f64, cy: f64, z: f64, half: f64) -> Contour {
+        Contour::new(vec![
+            Vec3::from(cx - half, cy - half, z),
+            Vec3::from(cx + half, cy - half, z),
+            Vec3::from(cx + half, cy + half, z),
+            Vec3::from(cx - half, cy + half, z),
+        ])
+    }
+
+    fn geometry(nz: usize, dz: f64) -> GridGeometry {
+        GridGeometry::new(
+            [20, 20, nz],
+            Vec3::from(-9.5, -9.5, 0.0),
+            Vec3::from(1.0, 1.0, dz),
+        )
+    }
+
+    fn count(mask: &crate::grid::Grid3<bool>) -> usize {
+        mask.data().iter().filter(|v| **v).count()
+    }
+
+    #[test]
+    fn fill_polygons_square() {
+        let mut n = 0;
+        fill_polygons(
+            &[vec![(0.5, 0.5), (3.5, 0.5), (3.5, 2.5), (0.5, 2.5)]],
+            10,
+            10,
+            |i, j| {
+                assert!((1..=3).contains(&i) && (1..=2).contains(&j));
+                n += 1;
+            },
+        );
+        assert_eq!(n, 6);
+    }
+
+    #[test]
+    fn rasterize_square() {
+        let s = Structure::new("PTV", StructureType::Ptv)
+            .with_contours(vec![square(0.0, 0.0, 0.0, 5.0)]);
+        let mask = rasterize(&s, &geometry(1, 1.0));
+        assert_eq!(count(&mask), 100);
+        assert!(mask[[10, 10, 0]]);
+        assert!(!mask[[2, 10, 0]]);
+    }
+
+    #[test]
+    fn rasterize_ignores_non_finite_contours() {
+        let mut broken = square(0.0, 0.0, 1.0, 5.0);
+        broken.points[2].x = f64::NAN;
+        let mut nan_z = square(0.0, 0.0, 0.0, 8.0);
+        nan_z.points[0].z = f64::NAN;
+        let contours = vec![square(0.0, 0.0, 0.0, 5.0), broken, nan_z];
+        let mask = rasterize_contours(&contours, &geometry(1, 1.0));
+        assert_eq!(count(&mask), 100);
+    }
+
+    #[test]
+    fn rasterize_hole_and_islands() {
+        let contours = vec![
+            square(0.0, 0.0, 0.0, 8.0),
+            square(0.0, 0.0, 0.0, 3.0),
+            square(-5.0, 5.0, 0.0, 1.0),
+        ];
+        let mask = rasterize_contours(&contours, &geometry(1, 1.0));
+        // 16x16 outer minus 6x6 hole; the 2x2 island sits inside the outer square so it is
+        // a hole of its own under the even-odd rule.
+        assert_eq!(count(&mask), 256 - 36 - 4);
+        assert!(!mask[[10, 10, 0]]);
+        assert!(mask[[4, 10, 0]]);
+    }
+
+    #[test]
+    fn rasterize_slice_spacing() {
+        // Contours every 3 mm on a 1 mm grid: each contour covers three grid slices.
+        let contours: Vec<Contour> = (0..3)
+            .map(|n| square(0.0, 0.0, 3.0 + 3.0 * n as f64, 2.0))
+            .collect();
+        let mask = rasterize_contours(&contours, &geometry(15, 1.0));
+        for k in 0..15 {
+            let filled = mask[[10, 10, k]];
+            assert_eq!(filled, (2..=10).contains(&k), "slice {}", k);
+        }
+    }
+
+    #[test]
+    fn rasterize_gap() {
+        let contours = vec![
+            square(0.0, 0.0, 0.0, 2.0),
+            square(0.0, 0.0, 2.0, 2.0),
+            square(0.0, 0.0, 10.0, 2.0),
+            square(0.0, 0.0, 12.0, 2.0),
+        ];
+        let mask = rasterize_contours(&contours, &geometry(13, 1.0));
+        assert!(mask[[10, 10, 3]]);
+        assert!(!mask[[10, 10, 6]]);
+        assert!(mask[[10, 10, 9]]);
+    }
+
+    #[test]
+    fn rasterize_flipped_and_oblique() {
+        let contours = vec![square(2.0, 0.0, 0.0, 3.0)];
+        let reference = rasterize_contours(&contours, &geometry(1, 1.0));
+        let mut flipped = geometry(1, 1.0);
+        flipped.direction[0] = Vec3::from(-1.0, 0.0, 0.0);
+        flipped.origin.x = 9.5;
+        let mask = rasterize_contours(&contours, &flipped);
+        assert_eq!(count(&mask), count(&reference));
+        assert!(mask[[19 - 12, 10, 0]]);
+
+        let mut oblique = geometry(1, 1.0);
+        let rot = Affine3::rotation(0.2, 0.0, 0.0);
+        for d in oblique.direction.iter_mut() {
+            *d = rot.transform_vector(*d);
+        }
+        oblique.origin = Vec3::from(-9.5, 0.0, 0.0);
+        let mask = rasterize_contours(&contours, &oblique);
+        // Rows 0..=2 stay within half a slice of z = 0 and cross the square at x in [-1, 5].
+        assert_eq!(count(&mask), 18);
+        assert!(mask[[11, 0, 0]]);
+        assert!(!mask[[11, 5, 0]]);
+    }
+
+    #[test]
+    fn rasterize_fractional_square() {
+        // A 3.5 mm square offset from the voxel centers, on two 2 mm slices.
+        let contours = [0.0, 2.0]
+            .iter()
+            .map(|z| {
+                Contour::new(vec![
+                    Vec3::from(-1.3, -1.6, *z),
+                    Vec3::from(2.2, -1.6, *z),
+                    Vec3::from(2.2, 1.9, *z),
+                    Vec3::from(-1.3, 1.9, *z),
+                ])
+            })
+            .collect();
+        let s = Structure::new("GTV", StructureType::Gtv).with_contours(contours);
+        let g = GridGeometry::new(
+            [10, 10, 5],
+            Vec3::from(-4.5, -4.5, -1.0),
+            Vec3::from(1.0, 1.0, 1.0),
+        );
+        let fraction = rasterize_fractional(&s, &g, 10);
+        let volume: f64 = fraction.data().iter().sum();
+        assert!((volume - 3.5 * 3.5 * 4.0).abs() < 0.05, "volume {}", volume);
+        assert!((fraction[[4, 4, 1]] - 1.0).abs() < 1e-12);
+        assert!((fraction[[3, 4, 1]] - 0.3).abs() < 1e-12);
+        assert!(fraction.data().iter().all(|f| *f <= 1.0 + 1e-12));
+    }
+}
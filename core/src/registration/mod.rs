@@ -0,0 +1,229 @@
+//! Image registration.
+//!
+//! Registrations align a moving volume to a fixed volume. The resulting transforms map
+//! positions of the fixed image to the corresponding positions in the moving image, so the
+//! moving image sampled at `T(x)` matches the fixed image at `x` and resampling the moving
+//! image onto the fixed grid follows the transform directly. Both volumes are registered
+//! coarse to fine on pyramids that halve the resolution per level.
+
+pub mod deformable;
+pub mod rigid;
+
+use crate::affine::Affine3;
+use crate::coords::Vec3;
+use crate::grid::{Grid3, GridGeometry};
+use crate::progress::Progress;
+
+/// Convergence of one pyramid level.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelDiagnostics {
+    /// 0 at full resolution.
+    pub level: usize,
+    pub iterations: usize,
+    /// Similarity at the end of the level, larger is better.
+    pub similarity: f64,
+    /// Whether the level met its stopping criterion within the iterations.
+    pub converged: bool,
+}
+
+/// Image similarity driving a registration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Similarity {
+    /// Mutual information of the joint intensity histogram, for images of different contrast
+    /// or modality.
+    MutualInformation { bins: usize },
+    /// Normalized cross-correlation, for images of the same contrast.
+    Correlation,
+}
+
+impl Default for Similarity {
+    fn default() -> Self {
+        Similarity::MutualInformation { bins: 32 }
+    }
+}
+
+impl Similarity {
+    /// Similarity of paired `(fixed, moving)` samples, larger is better: the mutual information
+    /// (nats) or the correlation coefficient. `ranges` bound the fixed and moving intensities
+    /// of the histogram.
+    pub(crate) fn evaluate(&self, pairs: &[(f64, f64)], ranges: [[f64; 2]; 2]) -> f64 {
+        if pairs.is_empty() {
+            return 0.0;
+        }
+        match *self {
+            Similarity::MutualInformation { bins } => mutual_information(pairs, ranges, bins),
+            Similarity::Correlation => correlation(pairs),
+        }
+    }
+}
+
+/// Continuous bin position of `v` in `range`, clamped to the histogram.
+fn bin(v: f64, range: [f64; 2], bins: usize) -> f64 {
+    let width = range[1] - range[0];
+    if width <= 0.0 {
+        return 0.0;
+    }
+    ((v - range[0]) / width * (bins - 1) as f64)
+        .max(0.0)
+        .min((bins - 1) as f64)
+}
+
+/// Mutual information with the samples splatted linearly into the moving bins, which keeps
+/// the measure smooth in sub-voxel shifts of the moving image.
+fn mutual_information(pairs: &[(f64, f64)], ranges: [[f64; 2]; 2], bins: usize) -> f64 {
+    let mut joint = vec![0.0; bins * bins];
+    for &(f, m) in pairs {
+        let a = bin(f, ranges[0], bins).round() as usize;
+        let b = bin(m, ranges[1], bins);
+        let lo = (b.floor() as usize).min(bins - 1);
+        let t = b - lo as f64;
+        joint[a * bins + lo] += 1.0 - t;
+        if t > 0.0 {
+            joint[a * bins + lo + 1] += t;
+        }
+    }
+    let total = pairs.len() as f64;
+    let mut fixed = vec![0.0; bins];
+    let mut moving = vec![0.0; bins];
+    for a in 0..bins {
+        for b in 0..bins {
+            let p = joint[a * bins + b] / total;
+            fixed[a] += p;
+            moving[b] += p;
+        }
+    }
+    let mut mi = 0.0;
+    for a in 0..bins {
+        for b in 0..bins {
+            let p = joint[a * bins + b] / total;
+            if p > 0.0 {
+                mi += p * (p / (fixed[a] * moving[b])).ln();
+            }
+        }
+    }
+    mi
+}
+
+fn correlation(pairs: &[(f64, f64)]) -> f64 {
+    let n = pairs.len() as f64;
+    let (sf, sm) = pairs
+        .iter()
+        .fold((0.0, 0.0), |(sf, sm), &(f, m)| (sf + f, sm + m));
+    let (mf, mm) = (sf / n, sm / n);
+    let (mut cov, mut vf, mut vm) = (0.0, 0.0, 0.0);
+    for &(f, m) in pairs {
+        cov += (f - mf) * (m - mm);
+        vf += (f - mf) * (f - mf);
+        vm += (m - mm) * (m - mm);
+    }
+    if vf <= 0.0 || vm <= 0.0 {
+        0.0
+    } else {
+        cov / (vf * vm).sqrt()
+    }
+}
+
+/// Smallest and largest value of `grid`.
+pub(crate) fn range(grid: &Grid3<f64>) -> [f64; 2] {
+    grid.data()
+        .iter()
+        .fold([f64::INFINITY, f64::NEG_INFINITY], |[lo, hi], &v| {
+            [lo.min(v), hi.max(v)]
+        })
+}
+
+/// `grid` at half the resolution along every axis with more than one voxel, each voxel the
+/// mean of the block of up to 2 x 2 x 2 voxels it covers.
+pub(crate) fn downsample(grid: &Grid3<f64>) -> Grid3<f64> {
+    let dims = grid.dims();
+    let factor = [0, 1, 2].map(|a| if dims[a] > 1 { 2 } else { 1 });
+    let coarse = [0, 1, 2].map(|a| dims[a].div_ceil(factor[a]));
+    // Block `n` covers fine voxels `2n` and `2n + 1`, centered at `2n + 0.5`.
+    let shift = Vec3::from_array([0, 1, 2].map(|a| 0.5 * (factor[a] - 1) as f64));
+    let scale = Affine3::scaling(Vec3::from_array(factor.map(|f| f as f64)));
+    let i2w = grid.geometry().index_to_world() * Affine3::translation(shift) * scale;
+    let geometry = GridGeometry::from_affine(coarse, &i2w);
+    let mut out = Grid3::new(geometry, 0.0);
+    for k in 0..coarse[2] {
+        for j in 0..coarse[1] {
+            for i in 0..coarse[0] {
+                let (mut sum, mut n) = (0.0, 0.0);
+                for c in 0..factor[2] {
+                    for b in 0..factor[1] {
+                        for a in 0..factor[0] {
+                            let [x, y, z] =
+                                [i * factor[0] + a, j * factor[1] + b, k * factor[2] + c];
+                            if let Some(v) = grid.get(x, y, z) {
+                                sum += v;
+                                n += 1.0;
+                            }
+                        }
+                    }
+                }
+                out.set(i, j, k, sum / n);
+            }
+        }
+    }
+    out
+}
+
+/// Pyramid of `levels` resolutions of `grid`, the full resolution first.
+pub(crate) fn pyramid(grid: &Grid3<f64>, levels: usize) -> Vec<Grid3<f64>> {
+    let mut out = vec![grid.clone()];
+    while out.len() < levels {
+        let next = downsample(out.last().expect("full resolution"));
+        out.push(next);
+    }
+    out
+}
+
+/// Progress stage of every level of `pyramid`, in proportion to its voxels, the coarsest
+/// level first.
+pub(crate) fn stages(pyramid: &[Grid3<f64>], progress: &Progress) -> Vec<Progress> {
+    let total: usize = pyramid.iter().map(|g| g.len()).sum();
+    let mut done = 0;
+    let mut out: Vec<Progress> = pyramid
+        .iter()
+        .rev()
+        .map(|g| {
+            let start = done as f64 / total as f64;
+            done += g.len();
+            progress.stage(start, done as f64 / total as f64)
+        })
+        .collect();
+    out.reverse();
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::registration::{downsample, Similarity};
+
+    #[test]
+    fn similarity_and_pyramid() {
+        let pairs: Vec<(f64, f64)> = (0..100).map(|i| (i as f64, 50.0 - i as f64)).collect();
+        let ranges = [[0.0, 99.0], [-49.0, 50.0]];
+        assert!((Similarity::Correlation.evaluate(&pairs, ranges) + 1.0).abs() < 1e-12);
+        // An inverted but one to one intensity mapping keeps the mutual information.
+        let mi = Similarity::default();
+        let inverted = mi.evaluate(&pairs, ranges);
+        let same: Vec<(f64, f64)> = pairs.iter().map(|&(f, _)| (f, f)).collect();
+        assert!((inverted - mi.evaluate(&same, [[0.0, 99.0]; 2])).abs() < 1e-9);
+        let constant: Vec<(f64, f64)> = pairs.iter().map(|&(f, _)| (f, 1.0)).collect();
+        assert!(mi.evaluate(&constant, [[0.0, 99.0], [1.0, 1.0]]).abs() < 1e-12);
+
+        let geometry = GridGeometry::new([5, 4, 1], Vec3::new(), Vec3::from(1.0, 2.0, 3.0));
+        let data = (0..20).map(|n| n as f64).collect();
+        let grid = Grid3::from_vec(geometry, data).unwrap();
+        let coarse = downsample(&grid);
+        assert_eq!(coarse.dims(), [3, 2, 1]);
+        assert_eq!(coarse.geometry().spacing, Vec3::from(2.0, 4.0, 3.0));
+        assert_eq!(coarse.geometry().origin, Vec3::from(0.5, 1.0, 0.0));
+        // Voxels 0, 1, 5 and 6.
+        assert_eq!(coarse[[0, 0, 0]], 3.0);
+        // The partial block of voxels 14 and 19.
+        assert_eq!(coarse[[2, 1, 0]], 16.5);
+    }
+}
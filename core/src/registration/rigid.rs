@@ -0,0 +1,306 @@
+//! Multi-resolution rigid registration.
+//!
+//! The six rigid parameters, rotations (radians) about the x, y and z axis through the center
+//! of the fixed image and a translation (mm), are optimized by a compass search: every
+//! parameter is stepped in both directions, improvements are kept and the step is halved
+//! when none remains, until it falls below the minimum step. Rotations step by the step
+//! length over the radius of the fixed image, so both move its boundary by about the same
+//! distance. The similarity is evaluated on a regular subset of the fixed voxels; positions
+//! mapped outside the moving image are left out, and transforms leaving less than a quarter
+//! of the samples inside it are rejected.
+
+use crate::affine::Affine3;
+use crate::coords::Vec3;
+use crate::error::{Error, Result};
+use crate::grid::Grid3;
+use crate::progress::Progress;
+use crate::registration::{pyramid, range, stages, LevelDiagnostics, Similarity};
+use crate::resample::Interpolator;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RigidOptions {
+    pub similarity: Similarity,
+    /// Pyramid levels, each at half the resolution of the next.
+    pub levels: usize,
+    /// Search iterations per level.
+    pub max_iterations: usize,
+    /// Initial step (mm) at full resolution, doubled per coarser level.
+    pub step: f64,
+    /// Step (mm) at full resolution at which a level has converged, doubled per coarser level.
+    pub min_step: f64,
+    /// Fixed voxels sampled for the similarity per level.
+    pub samples: usize,
+    /// Starting transform from fixed to moving positions.
+    pub initial: Affine3,
+}
+
+impl Default for RigidOptions {
+    fn default() -> Self {
+        RigidOptions {
+            similarity: Similarity::default(),
+            levels: 3,
+            max_iterations: 200,
+            step: 4.0,
+            min_step: 0.05,
+            samples: 20_000,
+            initial: Affine3::identity(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RigidResult {
+    /// Maps fixed image positions to moving image positions.
+    pub transform: Affine3,
+    /// Rotations (radians) about the x, y and z axis through `center`, then translations (mm)
+    /// along them, applied after the initial transform.
+    pub parameters: [f64; 6],
+    /// Center of the fixed image (mm).
+    pub center: Vec3<f64>,
+    /// Similarity at full resolution.
+    pub similarity: f64,
+    /// Coarsest level first.
+    pub levels: Vec<LevelDiagnostics>,
+}
+
+impl RigidResult {
+    pub fn converged(&self) -> bool {
+        self.levels.iter().all(|l| l.converged)
+    }
+}
+
+/// Similarity of one pyramid level under a rigid transform.
+struct Level<'a> {
+    samples: Vec<(Vec3<f64>, f64)>,
+    moving: Interpolator<'a>,
+    ranges: [[f64; 2]; 2],
+    similarity: Similarity,
+}
+
+impl<'a> Level<'a> {
+    fn new(fixed: &Grid3<f64>, moving: &'a Grid3<f64>, options: &RigidOptions) -> Self {
+        let stride = (fixed.len() as f64 / options.samples as f64)
+            .ceil()
+            .max(1.0) as usize;
+        let geometry = fixed.geometry();
+        let samples = (0..fixed.len())
+            .step_by(stride)
+            .map(|n| {
+                let [i, j, k] = geometry.ijk(n);
+                (geometry.position(i, j, k), fixed.data()[n])
+            })
+            .collect();
+        Level {
+            samples,
+            moving: Interpolator::new(moving),
+            ranges: [range(fixed), range(moving)],
+            similarity: options.similarity,
+        }
+    }
+
+    fn similarity(&self, transform: &Affine3) -> f64 {
+        let pairs: Vec<(f64, f64)> = self
+            .samples
+            .iter()
+            .filter_map(|&(p, f)| self.moving.at(transform.transform_point(p)).map(|m| (f, m)))
+            .collect();
+        if 4 * pairs.len() < self.samples.len() {
+            return f64::NEG_INFINITY;
+        }
+        self.similarity.evaluate(&pairs, self.ranges)
+    }
+}
+
+fn transform(parameters: &[f64; 6], center: Vec3<f64>, initial: &Affine3) -> Affine3 {
+    let [rx, ry, rz, tx, ty, tz] = *parameters;
+    Affine3::rigid(rx, ry, rz, center, Vec3::from(tx, ty, tz)) * *initial
+}
+
+/// Rigid transform mapping `fixed` positions onto the matching `moving` positions.
+pub fn register(
+    fixed: &Grid3<f64>,
+    moving: &Grid3<f64>,
+    options: &RigidOptions,
+) -> Result<RigidResult> {
+    register_with_progress(fixed, moving, options, &Progress::new())
+}
+
+/// [`register`] reporting its progress per search iteration to `progress`.
+pub fn register_with_progress(
+    fixed: &Grid3<f64>,
+    moving: &Grid3<f64>,
+    options: &RigidOptions,
+    progress: &Progress,
+) -> Result<RigidResult> {
+    if fixed.is_empty() || moving.is_empty() {
+        return Err(Error::InvalidArgument(
+            "empty registration image".to_string(),
+        ));
+    }
+    if options.levels == 0 || options.samples == 0 {
+        return Err(Error::InvalidArgument(
+            "registration needs at least one level and sample".to_string(),
+        ));
+    }
+    if let Similarity::MutualInformation { bins } = options.similarity {
+        if bins < 2 {
+            return Err(Error::InvalidArgument(format!(
+                "{} histogram bins for mutual information",
+                bins
+            )));
+        }
+    }
+    if options.min_step.is_nan() || options.min_step <= 0.0 || options.step < options.min_step {
+        return Err(Error::InvalidArgument(format!(
+            "invalid registration steps {} and {} mm",
+            options.step, options.min_step
+        )));
+    }
+
+    let geometry = fixed.geometry();
+    let [nx, ny, nz] = geometry.dims;
+    let far = Vec3::from((nx - 1) as f64, (ny - 1) as f64, (nz - 1) as f64);
+    let i2w = geometry.index_to_world();
+    let center = i2w.transform_point(far.scale(0.5));
+    let radius = (0.5 * i2w.transform_vector(far).norm()).max(1.0);
+
+    let fixed_levels = pyramid(fixed, options.levels);
+    let moving_levels = pyramid(moving, options.levels);
+    let stages = stages(&fixed_levels, progress);
+    let mut parameters = [0.0; 6];
+    let mut levels = Vec::with_capacity(options.levels);
+    let mut similarity = f64::NEG_INFINITY;
+    for level in (0..options.levels).rev() {
+        let evaluator = Level::new(&fixed_levels[level], &moving_levels[level], options);
+        let cost = |p: &[f64; 6]| evaluator.similarity(&transform(p, center, &options.initial));
+        let factor = (1u64 << level) as f64;
+        let mut step = options.step * factor;
+        let min_step = options.min_step * factor;
+        similarity = cost(&parameters);
+        let mut iterations = 0;
+        let mut converged = false;
+        while iterations < options.max_iterations {
+            stages[level].update(iterations as f64 / options.max_iterations as f64)?;
+            iterations += 1;
+            let mut improved = false;
+            for a in 0..6 {
+                let delta = if a < 3 { step / radius } else { step };
+                for &sign in &[1.0, -1.0] {
+                    let mut trial = parameters;
+                    trial[a] += sign * delta;
+                    let s = cost(&trial);
+                    if s > similarity {
+                        parameters = trial;
+                        similarity = s;
+                        improved = true;
+                        break;
+                    }
+                }
+            }
+            if !improved {
+                step *= 0.5;
+                if step < min_step {
+                    converged = true;
+                    break;
+                }
+            }
+        }
+        stages[level].report(1.0);
+        levels.push(LevelDiagnostics {
+            level,
+            iterations,
+            similarity,
+            converged,
+        });
+    }
+    Ok(RigidResult {
+        transform: transform(&parameters, center, &options.initial),
+        parameters,
+        center,
+        similarity,
+        levels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::affine::Affine3;
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::registration::rigid::{register, RigidOptions};
+    use crate::registration::Similarity;
+
+    #[test]
+    fn recover_rigid_transform() {
+        // Three ellipsoidal blobs of different size and intensity on a 50 mm cube.
+        let phantom = |p: Vec3<f64>| {
+            let blob = |c: Vec3<f64>, s: Vec3<f64>, a: f64| {
+                let d = p - c;
+                let r = (d.x / s.x).powi(2) + (d.y / s.y).powi(2) + (d.z / s.z).powi(2);
+                a * (-0.5 * r).exp()
+            };
+            blob(Vec3::from(-6.0, -4.0, 0.0), Vec3::from(8.0, 5.0, 6.0), 1.0)
+                + blob(Vec3::from(9.0, 5.0, 4.0), Vec3::from(4.0, 6.0, 3.0), 0.6)
+                + blob(Vec3::from(0.0, 10.0, -8.0), Vec3::from(3.0, 3.0, 5.0), 0.3)
+        };
+        let geometry = GridGeometry::new(
+            [26, 26, 26],
+            Vec3::from(-25.0, -25.0, -25.0),
+            Vec3::from(2.0, 2.0, 2.0),
+        );
+        let truth = Affine3::rigid(0.05, -0.04, 0.08, Vec3::new(), Vec3::from(3.0, -2.0, 4.0));
+        let inverse = truth.inverse().unwrap();
+        let image = |f: &dyn Fn(Vec3<f64>) -> f64| {
+            let mut grid = Grid3::new(geometry.clone(), 0.0);
+            for n in 0..geometry.len() {
+                let [i, j, k] = geometry.ijk(n);
+                grid.data_mut()[n] = f(geometry.position(i, j, k));
+            }
+            grid
+        };
+        let fixed = image(&phantom);
+        // The moving image holds the fixed anatomy at the transformed positions.
+        let same = image(&|p| phantom(inverse.transform_point(p)));
+        let inverted = image(&|p| 1.0 - phantom(inverse.transform_point(p)));
+
+        let error = |transform: &Affine3| {
+            [-10.0, 10.0]
+                .iter()
+                .flat_map(|&x| {
+                    [-10.0, 10.0]
+                        .iter()
+                        .map(move |&y| Vec3::from(x, y, 0.5 * x))
+                })
+                .map(|p| {
+                    transform
+                        .transform_point(p)
+                        .distance(truth.transform_point(p))
+                })
+                .fold(0.0, f64::max)
+        };
+        let correlation = RigidOptions {
+            similarity: Similarity::Correlation,
+            samples: 5000,
+            ..RigidOptions::default()
+        };
+        let result = register(&fixed, &same, &correlation).unwrap();
+        assert!(error(&result.transform) < 0.5);
+        assert!(result.similarity > 0.99);
+        assert_eq!(result.levels.len(), 3);
+        assert_eq!(result.levels[2].level, 0);
+
+        let mutual = RigidOptions {
+            samples: 5000,
+            ..RigidOptions::default()
+        };
+        let result = register(&fixed, &inverted, &mutual).unwrap();
+        assert!(result.converged());
+        assert!(error(&result.transform) < 1.0);
+
+        let empty = RigidOptions {
+            levels: 0,
+            ..mutual
+        };
+        assert!(register(&fixed, &same, &empty).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! Trilinear interpolation of scalar grids and resampling onto another grid geometry.
+//!
+//! Positions between the outer voxel centers and the outer voxel faces take the value of
+//! the nearest outer voxel; positions beyond the faces are outside the grid.
+
+use crate::affine::Affine3;
+use crate::coords::Vec3;
+use crate::grid::{Grid3, GridGeometry};
+
+/// Trilinear interpolation at world positions (mm).
+#[derive(Debug, Clone)]
+pub struct Interpolator<'a> {
+    grid: &'a Grid3<f64>,
+    w2i: Affine3,
+}
+
+impl<'a> Interpolator<'a> {
+    pub fn new(grid: &'a Grid3<f64>) -> Self {
+        Interpolator {
+            grid,
+            w2i: grid.geometry().world_to_index(),
+        }
+    }
+
+    pub fn grid(&self) -> &Grid3<f64> {
+        self.grid
+    }
+
+    /// Value at world position `p`, `None` outside the grid.
+    pub fn at(&self, p: Vec3<f64>) -> Option<f64> {
+        self.at_index(self.w2i.transform_point(p))
+    }
+
+    /// Value at continuous index position `q`.
+    pub fn at_index(&self, q: Vec3<f64>) -> Option<f64> {
+        let dims = self.grid.dims();
+        let mut lo = [0usize; 3];
+        let mut t = [0.0; 3];
+        for (a, c) in q.to_array().iter().enumerate() {
+            let n = dims[a];
+            if n == 0 || *c < -0.5 || *c > n as f64 - 0.5 {
+                return None;
+            }
+            let c = c.max(0.0).min((n - 1) as f64);
+            let f = (c.floor() as usize).min(n.saturating_sub(2));
+            lo[a] = f;
+            t[a] = c - f as f64;
+        }
+        let data = self.grid.data();
+        let stride = [1, dims[0], dims[0] * dims[1]];
+        let base = lo[0] + stride[1] * lo[1] + stride[2] * lo[2];
+        let mut value = 0.0;
+        for corner in 0..8 {
+            let mut w = 1.0;
+            let mut offset = base;
+            for a in 0..3 {
+                if corner >> a & 1 == 0 {
+                    w *= 1.0 - t[a];
+                } else if dims[a] > 1 {
+                    w *= t[a];
+                    offset += stride[a];
+                } else {
+                    w = 0.0;
+                }
+            }
+            if w != 0.0 {
+                value += w * data[offset];
+            }
+        }
+        Some(value)
+    }
+}
+
+/// `grid` interpolated at the voxel centers of `geometry`; `outside` where they fall
+/// outside `grid`.
+pub fn resample(grid: &Grid3<f64>, geometry: &GridGeometry, outside: f64) -> Grid3<f64> {
+    let sampler = Interpolator::new(grid);
+    let to_source = grid.geometry().world_to_index() * geometry.index_to_world();
+    let [nx, ny, nz] = geometry.dims;
+    let mut out = Vec::with_capacity(geometry.len());
+    for k in 0..nz {
+        for j in 0..ny {
+            for i in 0..nx {
+                let q = to_source.transform_point(Vec3::from(i as f64, j as f64, k as f64));
+                out.push(sampler.at_index(q).unwrap_or(outside));
+            }
+        }
+    }
+    Grid3::from_vec(geometry.clone(), out).expect("one value per voxel")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::resample::{resample, Interpolator};
+
+    fn linear() -> Grid3<f64> {
+        let geometry = GridGeometry::new(
+            [4, 3, 2],
+            Vec3::from(-1.0, 0.0, 10.0),
+            Vec3::from(2.0, 1.0, 3.0),
+        );
+        let mut grid = Grid3::new(geometry, 0.0);
+        for n in 0..grid.len() {
+            let [i, j, k] = grid.geometry().ijk(n);
+            let p = grid.geometry().position(i, j, k);
+            grid.data_mut()[n] = 2.0 * p.x - p.y + 0.5 * p.z;
+        }
+        grid
+    }
+
+    #[test]
+    fn interpolator_linear_field() {
+        let grid = linear();
+        let s = Interpolator::new(&grid);
+        let f = |x: f64, y: f64, z: f64| 2.0 * x - y + 0.5 * z;
+        for p in [(0.3, 0.7, 11.0), (4.9, 2.0, 13.0), (-1.0, 0.0, 10.0)].iter() {
+            let v = s.at(Vec3::from(p.0, p.1, p.2)).unwrap();
+            assert!((v - f(p.0, p.1, p.2)).abs() < 1e-12);
+        }
+        // Beyond the outer centers but within the outer faces: nearest edge value.
+        assert!((s.at(Vec3::from(-1.5, 0.0, 10.0)).unwrap() - f(-1.0, 0.0, 10.0)).abs() < 1e-12);
+        assert_eq!(s.at(Vec3::from(-2.5, 0.0, 10.0)), None);
+        assert_eq!(s.at(Vec3::from(0.0, 0.0, 15.0)), None);
+    }
+
+    #[test]
+    fn resample_onto_finer_grid() {
+        let grid = linear();
+        let target = GridGeometry::new(
+            [7, 5, 1],
+            Vec3::from(-1.0, 0.0, 11.5),
+            Vec3::from(1.0, 0.5, 1.0),
+        );
+        let r = resample(&grid, &target, -1.0);
+        assert!((r[[3, 2, 0]] - (2.0 * 2.0 - 1.0 + 0.5 * 11.5)).abs() < 1e-12);
+        let shifted = GridGeometry::new(
+            [2, 1, 1],
+            Vec3::from(5.0, 0.0, 10.0),
+            Vec3::from(2.0, 1.0, 1.0),
+        );
+        let r = resample(&grid, &shifted, -1.0);
+        assert_eq!(r.data()[1], -1.0);
+    }
+}
@@ -0,0 +1,310 @@
+//! Respiratory-correlated (4D) CT.
+//!
+//! A [`PhaseSet`] holds the phase images of a 4D-CT on one grid, tagged with their phase in
+//! the breathing cycle (0 % at end inhalation, 50 % around end exhalation for phase
+//! binning). The derived maximum, average and minimum intensity projections are computed
+//! voxel by voxel over the phases. An ITV is the union of a target over the phases, either
+//! contoured on every phase or contoured once and propagated with deformation vector fields
+//! from every phase to the reference phase, see [`PhaseSet::register`].
+
+use crate::boolean::union_mask;
+use crate::contouring::extract_contours;
+use crate::dvf::Dvf;
+use crate::error::{Error, Result};
+use crate::grid::{Grid3, GridGeometry};
+use crate::raster::rasterize;
+use crate::registration::deformable::{self, DeformableOptions};
+use crate::structure::Structure;
+
+/// Phase in percent of the breathing cycle from a series description or phase tag such as
+/// `"CT 4D 50%"` or `"T=20 %"`: the last number followed by a percent sign.
+pub fn parse_phase(description: &str) -> Option<f64> {
+    let chars: Vec<char> = description.chars().collect();
+    let percent = chars.iter().rposition(|&c| c == '%')?;
+    let end = chars[..percent]
+        .iter()
+        .rposition(|c| !c.is_whitespace())
+        .map(|e| e + 1)?;
+    let start = chars[..end]
+        .iter()
+        .rposition(|c| !(c.is_ascii_digit() || *c == '.'))
+        .map_or(0, |s| s + 1);
+    let phase: f64 = chars[start..end].iter().collect::<String>().parse().ok()?;
+    if phase < 100.0 {
+        Some(phase)
+    } else {
+        None
+    }
+}
+
+/// One phase image of a 4D-CT.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhaseImage {
+    /// Percent of the breathing cycle.
+    pub phase: f64,
+    pub image: Grid3<f64>,
+}
+
+/// Voxelwise projection over the phases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Projection {
+    /// Maximum intensity projection, e.g. to contour a lung tumor ITV.
+    Maximum,
+    /// Average intensity projection, for dose calculation.
+    Average,
+    /// Minimum intensity projection, e.g. for tumors within the liver.
+    Minimum,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PhaseSet {
+    /// Sorted by phase.
+    pub phases: Vec<PhaseImage>,
+}
+
+impl PhaseSet {
+    pub fn new() -> Self {
+        PhaseSet { phases: Vec::new() }
+    }
+
+    /// Adds the image of `phase` (percent), which must share the grid of the other phases.
+    pub fn add(&mut self, phase: f64, image: Grid3<f64>) -> Result<()> {
+        if phase.is_nan() || !(0.0..100.0).contains(&phase) {
+            return Err(Error::InvalidArgument(format!(
+                "phase {} % outside the breathing cycle",
+                phase
+            )));
+        }
+        if let Some(first) = self.phases.first() {
+            if first.image.geometry() != image.geometry() {
+                return Err(Error::InvalidArgument(format!(
+                    "phase {} % is not on the grid of the 4D-CT",
+                    phase
+                )));
+            }
+        }
+        if self.phase(phase).is_some() {
+            return Err(Error::InvalidArgument(format!(
+                "duplicate phase {} %",
+                phase
+            )));
+        }
+        let at = self.phases.iter().take_while(|p| p.phase < phase).count();
+        self.phases.insert(at, PhaseImage { phase, image });
+        Ok(())
+    }
+
+    pub fn with_phase(mut self, phase: f64, image: Grid3<f64>) -> Result<Self> {
+        self.add(phase, image)?;
+        Ok(self)
+    }
+
+    pub fn len(&self) -> usize {
+        self.phases.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.phases.is_empty()
+    }
+
+    pub fn phase(&self, phase: f64) -> Option<&PhaseImage> {
+        self.phases.iter().find(|p| (p.phase - phase).abs() < 1e-6)
+    }
+
+    pub fn geometry(&self) -> Option<&GridGeometry> {
+        self.phases.first().map(|p| p.image.geometry())
+    }
+
+    fn first(&self) -> Result<&PhaseImage> {
+        self.phases
+            .first()
+            .ok_or_else(|| Error::InvalidArgument("4D-CT without phases".to_string()))
+    }
+
+    pub fn projection(&self, projection: Projection) -> Result<Grid3<f64>> {
+        let mut out = self.first()?.image.clone();
+        for p in &self.phases[1..] {
+            for (o, v) in out.data_mut().iter_mut().zip(p.image.data()) {
+                *o = match projection {
+                    Projection::Maximum => o.max(*v),
+                    Projection::Average => *o + v,
+                    Projection::Minimum => o.min(*v),
+                };
+            }
+        }
+        if projection == Projection::Average {
+            let n = self.len() as f64;
+            out.data_mut().iter_mut().for_each(|o| *o /= n);
+        }
+        Ok(out)
+    }
+
+    pub fn mip(&self) -> Result<Grid3<f64>> {
+        self.projection(Projection::Maximum)
+    }
+
+    pub fn aip(&self) -> Result<Grid3<f64>> {
+        self.projection(Projection::Average)
+    }
+
+    /// Deformation vector fields from every phase, in phase order, to the `reference` phase,
+    /// registering the reference image (moving) to each phase (fixed). The field of the
+    /// reference phase itself is the identity.
+    pub fn register(&self, reference: f64, options: &DeformableOptions) -> Result<Vec<Dvf>> {
+        let target = self
+            .phase(reference)
+            .ok_or_else(|| Error::InvalidArgument(format!("no reference phase {} %", reference)))?;
+        self.phases
+            .iter()
+            .map(|p| {
+                if (p.phase - reference).abs() < 1e-6 {
+                    Ok(Dvf::identity(p.image.geometry().clone()))
+                } else {
+                    deformable::register(&p.image, &target.image, None, options).map(|r| r.dvf)
+                }
+            })
+            .collect()
+    }
+}
+
+/// `mask` of the reference anatomy on the grid of `dvf`, which maps to reference positions:
+/// the voxels whose reference position lies inside the mask.
+pub fn propagate_mask(mask: &Grid3<bool>, dvf: &Dvf) -> Grid3<bool> {
+    let geometry = dvf.geometry();
+    let w2i = mask.geometry().world_to_index();
+    let data = dvf
+        .grid()
+        .data()
+        .iter()
+        .enumerate()
+        .map(|(n, &u)| {
+            let [i, j, k] = geometry.ijk(n);
+            let q = w2i.transform_point(geometry.position(i, j, k) + u);
+            let [x, y, z] = [q.x.round(), q.y.round(), q.z.round()];
+            x >= 0.0
+                && y >= 0.0
+                && z >= 0.0
+                && *mask
+                    .get(x as usize, y as usize, z as usize)
+                    .unwrap_or(&false)
+        })
+        .collect();
+    Grid3::from_vec(geometry.clone(), data).expect("one value per voxel")
+}
+
+/// `structure` of the reference anatomy propagated to the phase of `dvf`, rasterized on
+/// the grid of the field.
+pub fn propagate(structure: &Structure, dvf: &Dvf) -> Structure {
+    let mask = propagate_mask(&rasterize(structure, dvf.geometry()), dvf);
+    Structure {
+        contours: extract_contours(&mask, 0.0),
+        ..structure.clone()
+    }
+}
+
+/// Union of the masks of `structures` on `geometry`, e.g. a GTV contoured on every phase,
+/// with the type and color of the first.
+pub fn itv(name: &str, structures: &[&Structure], geometry: &GridGeometry) -> Result<Structure> {
+    let first = structures
+        .first()
+        .ok_or_else(|| Error::InvalidArgument("ITV of no structures".to_string()))?;
+    let mask = union_mask(structures, geometry);
+    Ok(Structure::new(name, first.structure_type)
+        .with_color(first.color)
+        .with_contours(extract_contours(&mask, 0.0)))
+}
+
+/// ITV of `structure`, contoured on the reference phase, propagated to every phase with
+/// the `dvfs` from the phases to the reference phase, on the grid of the first field.
+pub fn propagated_itv(name: &str, structure: &Structure, dvfs: &[Dvf]) -> Result<Structure> {
+    let geometry = dvfs
+        .first()
+        .ok_or_else(|| Error::InvalidArgument("ITV of no phases".to_string()))?
+        .geometry();
+    let reference = rasterize(structure, geometry);
+    let mut mask = reference.clone();
+    for dvf in dvfs {
+        for (m, v) in mask
+            .data_mut()
+            .iter_mut()
+            .zip(propagate_mask(&reference, dvf).data())
+        {
+            *m |= *v;
+        }
+    }
+    Ok(Structure::new(name, structure.structure_type)
+        .with_color(structure.color)
+        .with_contours(extract_contours(&mask, 0.0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::affine::Affine3;
+    use crate::coords::Vec3;
+    use crate::dvf::Dvf;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::raster::rasterize;
+    use crate::respiratory::{itv, parse_phase, propagate, propagated_itv, PhaseSet};
+    use crate::structure::{Contour, Structure, StructureType};
+
+    fn square(name: &str, x: f64, y: f64, half: f64) -> Structure {
+        let contours = (0..5)
+            .map(|k| {
+                let z = k as f64 * 2.0;
+                Contour::new(vec![
+                    Vec3::from(x - half, y - half, z),
+                    Vec3::from(x + half, y - half, z),
+                    Vec3::from(x + half, y + half, z),
+                    Vec3::from(x - half, y + half, z),
+                ])
+            })
+            .collect();
+        Structure::new(name, StructureType::Gtv).with_contours(contours)
+    }
+
+    #[test]
+    fn phases_and_itv() {
+        assert_eq!(parse_phase("CT 4D 50%"), Some(50.0));
+        assert_eq!(parse_phase("T=20 % (gated)"), Some(20.0));
+        assert_eq!(parse_phase("Average CT"), None);
+
+        let geometry = GridGeometry::new([20, 20, 5], Vec3::new(), Vec3::from(2.0, 2.0, 2.0));
+        let mut set = PhaseSet::new();
+        for (n, phase) in [50.0, 0.0, 20.0].iter().enumerate() {
+            let mut image = Grid3::new(geometry.clone(), -1000.0);
+            image.set(n, 0, 0, 40.0 * n as f64);
+            set.add(*phase, image).unwrap();
+        }
+        assert_eq!(set.phases[0].phase, 0.0);
+        assert!(set.add(20.0, Grid3::new(geometry.clone(), 0.0)).is_err());
+        assert!(set.add(100.0, Grid3::new(geometry.clone(), 0.0)).is_err());
+        let mip = set.mip().unwrap();
+        assert_eq!(mip[[2, 0, 0]], 80.0);
+        assert_eq!(mip[[0, 0, 0]], 0.0);
+        assert!((set.aip().unwrap()[[2, 0, 0]] - (80.0 - 2000.0) / 3.0).abs() < 1e-9);
+        assert!(PhaseSet::new().mip().is_err());
+
+        // The target moves 6 mm along y between the phases.
+        let gtvs = [
+            square("GTV 0", 15.0, 13.0, 4.0),
+            square("GTV 50", 15.0, 19.0, 4.0),
+        ];
+        let contoured = itv("ITV", &[&gtvs[0], &gtvs[1]], &geometry).unwrap();
+        assert_eq!(contoured.structure_type, StructureType::Gtv);
+        let mask = rasterize(&contoured, &geometry);
+        assert_eq!(mask.data().iter().filter(|&&m| m).count(), 4 * 7 * 5);
+
+        // Phase positions map 6 mm on to the reference phase.
+        let shift = Affine3::translation(Vec3::from(0.0, 6.0, 0.0));
+        let dvf = Dvf::from_affine(geometry.clone(), &shift);
+        let propagated = propagate(&gtvs[1], &dvf);
+        assert_eq!(
+            rasterize(&propagated, &geometry),
+            rasterize(&gtvs[0], &geometry)
+        );
+        let identity = Dvf::identity(geometry.clone());
+        let itv = propagated_itv("ITV", &gtvs[1], &[identity, dvf]).unwrap();
+        assert_eq!(rasterize(&itv, &geometry), mask);
+        assert!(set.register(30.0, &Default::default()).is_err());
+    }
+}
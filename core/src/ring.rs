@@ -0,0 +1,176 @@
+//! Ring and shell helper structures for optimization.
+//!
+//! A ring from `inner` to `outer` mm around a target is the target expanded by `outer`
+//! minus the target expanded by `inner`, optionally cropped to a body outline.
+
+use crate::boolean::{combine_masks, BooleanOp};
+use crate::contouring::extract_contours;
+use crate::error::Result;
+use crate::grid::{Grid3, GridGeometry};
+use crate::margin::{contract_mask, expand_mask, Margins};
+use crate::raster::rasterize;
+use crate::structure::{Structure, StructureType};
+
+/// Voxels between `inner` and `outer` mm from `target`, restricted to `body` when given.
+pub fn ring_mask(
+    target: &Grid3<bool>,
+    inner: f64,
+    outer: f64,
+    body: Option<&Grid3<bool>>,
+) -> Result<Grid3<bool>> {
+    let outside = expand_mask(target, &Margins::uniform(outer));
+    let excluded = expand_mask(target, &Margins::uniform(inner));
+    let ring = combine_masks(&outside, &excluded, BooleanOp::Subtraction)?;
+    match body {
+        Some(body) => combine_masks(&ring, body, BooleanOp::Intersection),
+        None => Ok(ring),
+    }
+}
+
+/// Ring structure named e.g. `PTV_Ring5-10`.
+pub fn ring(
+    target: &Structure,
+    inner: f64,
+    outer: f64,
+    geometry: &GridGeometry,
+    body: Option<&Structure>,
+) -> Structure {
+    let body = body.map(|b| rasterize(b, geometry));
+    let mask = ring_mask(&rasterize(target, geometry), inner, outer, body.as_ref())
+        .expect("masks share the same geometry");
+    helper(
+        &format!("{}_Ring{}-{}", target.name, inner, outer),
+        target,
+        &mask,
+    )
+}
+
+/// Consecutive rings between the distances in `bounds`, e.g. `[0, 5, 10]` gives the 0-5 mm
+/// and 5-10 mm rings.
+pub fn rings(
+    target: &Structure,
+    bounds: &[f64],
+    geometry: &GridGeometry,
+    body: Option<&Structure>,
+) -> Vec<Structure> {
+    let target_mask = rasterize(target, geometry);
+    let body = body.map(|b| rasterize(b, geometry));
+    bounds
+        .windows(2)
+        .map(|w| {
+            let mask = ring_mask(&target_mask, w[0], w[1], body.as_ref())
+                .expect("masks share the same geometry");
+            helper(
+                &format!("{}_Ring{}-{}", target.name, w[0], w[1]),
+                target,
+                &mask,
+            )
+        })
+        .collect()
+}
+
+/// The outer `thickness` mm of `structure`, e.g. a skin shell of the body, named
+/// `BODY_Shell3`.
+pub fn inner_shell(structure: &Structure, thickness: f64, geometry: &GridGeometry) -> Structure {
+    let mask = rasterize(structure, geometry);
+    let core = contract_mask(&mask, &Margins::uniform(thickness));
+    let shell = combine_masks(&mask, &core, BooleanOp::Subtraction).expect("same geometry");
+    helper(
+        &format!("{}_Shell{}", structure.name, thickness),
+        structure,
+        &shell,
+    )
+}
+
+fn helper(name: &str, source: &Structure, mask: &Grid3<bool>) -> Structure {
+    Structure::new(name, StructureType::Control)
+        .with_color(source.color)
+        .with_contours(extract_contours(mask, 0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::raster::rasterize;
+    use crate::ring::{inner_shell, ring_mask, rings};
+    use crate::structure::{Contour, Structure, StructureType};
+
+    fn geometry() -> GridGeometry {
+        GridGeometry::new(
+            [30, 30, 5],
+            Vec3::from(-14.5, -14.5, -2.0),
+            Vec3::from(1.0, 1.0, 1.0),
+        )
+    }
+
+    fn square(name: &str, half: f64, structure_type: StructureType) -> Structure {
+        let contours = (-2..=2)
+            .map(|k| {
+                let z = k as f64;
+                Contour::new(vec![
+                    Vec3::from(-half, -half, z),
+                    Vec3::from(half, -half, z),
+                    Vec3::from(half, half, z),
+                    Vec3::from(-half, half, z),
+                ])
+            })
+            .collect();
+        Structure::new(name, structure_type).with_contours(contours)
+    }
+
+    fn count(mask: &Grid3<bool>) -> usize {
+        mask.data().iter().filter(|v| **v).count()
+    }
+
+    #[test]
+    fn ring_mask_single_voxel() {
+        let mut target = Grid3::new(geometry(), false);
+        target.set(15, 15, 2, true);
+        let ring = ring_mask(&target, 1.0, 2.0, None).unwrap();
+        // Ball of radius 2 (33 voxels) minus ball of radius 1 (7 voxels).
+        assert_eq!(count(&ring), 26);
+        let mut body = Grid3::new(geometry(), false);
+        body.set(15, 17, 2, true);
+        body.set(15, 15, 2, true);
+        assert_eq!(
+            count(&ring_mask(&target, 1.0, 2.0, Some(&body)).unwrap()),
+            1
+        );
+    }
+
+    #[test]
+    fn rings_around_target() {
+        let g = geometry();
+        let ptv = square("PTV", 3.0, StructureType::Ptv);
+        let body = square("BODY", 8.0, StructureType::External);
+        let r = rings(&ptv, &[0.0, 2.0, 10.0], &g, Some(&body));
+        assert_eq!(r.len(), 2);
+        assert_eq!(r[0].name, "PTV_Ring0-2");
+        assert_eq!(r[1].structure_type, StructureType::Control);
+        let target = rasterize(&ptv, &g);
+        let inner = rasterize(&r[0], &g);
+        let outer = rasterize(&r[1], &g);
+        for n in 0..target.len() {
+            let [i, j, _] = g.ijk(n);
+            assert!(!(target.data()[n] && inner.data()[n]));
+            assert!(!(inner.data()[n] && outer.data()[n]));
+            // Cropped to the 16x16 body.
+            if outer.data()[n] {
+                assert!((7..23).contains(&i) && (7..23).contains(&j));
+            }
+        }
+        assert!(count(&inner) > 0 && count(&outer) > count(&inner));
+    }
+
+    #[test]
+    fn inner_shell_body() {
+        let g = geometry();
+        let body = square("BODY", 8.0, StructureType::External);
+        let shell = inner_shell(&body, 1.0, &g);
+        assert_eq!(shell.name, "BODY_Shell1");
+        // Voxels beyond the grid count as outside, so the first and last slice belong to the
+        // shell as well and a 14x14x3 core remains.
+        assert_eq!(count(&rasterize(&shell, &g)), 16 * 16 * 5 - 14 * 14 * 3);
+    }
+}
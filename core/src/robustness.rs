@@ -0,0 +1,369 @@
+//! Robustness evaluation over setup and range error scenarios.
+//!
+//! A scenario dose is the nominal dose moved rigidly with respect to the patient: with a
+//! shift `s` and rotation `R` about the isocenter, the patient point `p` receives the
+//! nominal dose at `R (p - iso) + iso + s`. A proton range error scales the depth along the
+//! beam from the range origin, so with a range scale `k` depth `t` receives the nominal dose
+//! of depth `t / k`. Positions outside the nominal grid receive no dose.
+//!
+//! Structures stay in place, so their voxel fractions are computed once on the nominal grid
+//! (see [`crate::raster::rasterize_fractional`]).
+
+use crate::affine::Affine3;
+use crate::coords::Vec3;
+use crate::dvh::{Dvh, VolumeUnit};
+use crate::error::{Error, Result};
+use crate::grid::Grid3;
+use crate::metric::{Metric, MetricValue};
+use crate::resample::Interpolator;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scenario {
+    /// mm
+    pub shift: Vec3<f64>,
+    /// Radians about the x, y and z axis through the isocenter.
+    pub rotation: Vec3<f64>,
+    /// Range relative to the nominal range.
+    pub range_scale: f64,
+}
+
+impl Scenario {
+    pub fn nominal() -> Self {
+        Scenario {
+            shift: Vec3::new(),
+            rotation: Vec3::new(),
+            range_scale: 1.0,
+        }
+    }
+
+    pub fn shifted(shift: Vec3<f64>) -> Self {
+        Scenario {
+            shift,
+            ..Self::nominal()
+        }
+    }
+
+    /// The nominal scenario, shifts of ±`shift` (mm) and rotations of ±`rotation` (rad)
+    /// along every axis, each combined with range scales of 1 and 1 ± `range`. Zero
+    /// magnitudes are left out.
+    pub fn generate(shift: f64, rotation: f64, range: f64) -> Vec<Self> {
+        let mut setups = vec![Self::nominal()];
+        for a in 0..3 {
+            for sign in [1.0, -1.0].iter() {
+                let mut axis = [0.0; 3];
+                axis[a] = *sign;
+                let axis = Vec3::from_array(axis);
+                if shift > 0.0 {
+                    setups.push(Self::shifted(axis.scale(shift)));
+                }
+                if rotation > 0.0 {
+                    setups.push(Scenario {
+                        rotation: axis.scale(rotation),
+                        ..Self::nominal()
+                    });
+                }
+            }
+        }
+        let mut ranges = vec![1.0];
+        if range > 0.0 {
+            ranges.extend([1.0 + range, 1.0 - range].iter());
+        }
+        ranges
+            .iter()
+            .flat_map(|k| {
+                setups.iter().map(move |s| Scenario {
+                    range_scale: *k,
+                    ..*s
+                })
+            })
+            .collect()
+    }
+
+    pub fn is_nominal(&self) -> bool {
+        *self == Self::nominal()
+    }
+}
+
+/// Beam geometry for range scaling.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RangeModel {
+    /// Position (mm) depth is measured from, e.g. the beam entry point.
+    pub origin: Vec3<f64>,
+    /// Beam direction.
+    pub direction: Vec3<f64>,
+}
+
+/// Spread of the cumulative DVH (% volume at every bin edge) over the scenarios.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DvhBand {
+    pub structure: String,
+    /// Gy
+    pub dose: Vec<f64>,
+    pub nominal: Vec<f64>,
+    pub lower: Vec<f64>,
+    pub upper: Vec<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricSpread {
+    pub structure: String,
+    pub metric: Metric,
+    pub nominal: MetricValue,
+    /// Metric value per scenario.
+    pub values: Vec<f64>,
+    pub min: f64,
+    pub max: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RobustnessReport {
+    pub scenarios: Vec<Scenario>,
+    /// Voxel-wise minimum dose over the scenarios.
+    pub min_dose: Grid3<f64>,
+    /// Voxel-wise maximum dose over the scenarios.
+    pub max_dose: Grid3<f64>,
+    pub bands: Vec<DvhBand>,
+    pub metrics: Vec<MetricSpread>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RobustnessEvaluation<'a> {
+    pub dose: &'a Grid3<f64>,
+    pub isocenter: Vec3<f64>,
+    /// Needed by scenarios with a range error.
+    pub range: Option<RangeModel>,
+    /// Gy; needed by relative dose metrics.
+    pub prescription: Option<f64>,
+    /// DVH bin width (Gy).
+    pub bin_width: f64,
+}
+
+impl<'a> RobustnessEvaluation<'a> {
+    pub fn new(dose: &'a Grid3<f64>, isocenter: Vec3<f64>) -> Self {
+        RobustnessEvaluation {
+            dose,
+            isocenter,
+            range: None,
+            prescription: None,
+            bin_width: 0.01,
+        }
+    }
+
+    /// The dose delivered in `scenario`, on the nominal grid.
+    pub fn scenario_dose(&self, scenario: &Scenario) -> Result<Grid3<f64>> {
+        if scenario.is_nominal() {
+            return Ok(self.dose.clone());
+        }
+        if scenario.range_scale.is_nan() || scenario.range_scale <= 0.0 {
+            return Err(Error::InvalidArgument(format!(
+                "range scale must be positive, got {}",
+                scenario.range_scale
+            )));
+        }
+        let range = match self.range {
+            _ if scenario.range_scale == 1.0 => None,
+            Some(r) => Some((r.origin, r.direction.normalize())),
+            None => {
+                return Err(Error::InvalidArgument(
+                    "range scenarios need a range model".to_string(),
+                ))
+            }
+        };
+        let r = scenario.rotation;
+        let setup = Affine3::rigid(r.x, r.y, r.z, self.isocenter, scenario.shift);
+        let sampler = Interpolator::new(self.dose);
+        let geometry = self.dose.geometry();
+        let i2w = geometry.index_to_world();
+        let mut out = self.dose.clone();
+        for (n, v) in out.data_mut().iter_mut().enumerate() {
+            let [i, j, k] = geometry.ijk(n);
+            let p = i2w.transform_point(Vec3::from(i as f64, j as f64, k as f64));
+            let mut q = setup.transform_point(p);
+            if let Some((origin, direction)) = range {
+                let depth = (q - origin).dot(direction);
+                q -= direction.scale(depth - depth / scenario.range_scale);
+            }
+            *v = sampler.at(q).unwrap_or(0.0);
+        }
+        Ok(out)
+    }
+
+    /// Evaluates every scenario: voxel-wise dose extremes, DVH bands of `structures` (name,
+    /// voxel fractions on the dose grid) and the spread of `metrics` (structure name,
+    /// metric).
+    pub fn evaluate(
+        &self,
+        scenarios: &[Scenario],
+        structures: &[(&str, &Grid3<f64>)],
+        metrics: &[(&str, Metric)],
+    ) -> Result<RobustnessReport> {
+        if scenarios.is_empty() {
+            return Err(Error::InvalidArgument(
+                "robustness evaluation needs at least one scenario".to_string(),
+            ));
+        }
+        let dvhs = |dose: &Grid3<f64>| -> Result<Vec<Dvh>> {
+            structures
+                .iter()
+                .map(|(name, fraction)| Dvh::from_fraction(name, fraction, dose, self.bin_width))
+                .collect()
+        };
+        let index = |name: &str| {
+            structures
+                .iter()
+                .position(|(s, _)| *s == name)
+                .ok_or_else(|| Error::InvalidArgument(format!("no structure named {}", name)))
+        };
+        let nominal = dvhs(self.dose)?;
+        let mut spreads = Vec::with_capacity(metrics.len());
+        for (name, metric) in metrics {
+            spreads.push(MetricSpread {
+                structure: name.to_string(),
+                metric: *metric,
+                nominal: metric.evaluate(&nominal[index(name)?], self.prescription)?,
+                values: Vec::with_capacity(scenarios.len()),
+                min: f64::INFINITY,
+                max: f64::NEG_INFINITY,
+            });
+        }
+        let mut min_dose = self.dose.map(|_| f64::INFINITY);
+        let mut max_dose = self.dose.map(|_| f64::NEG_INFINITY);
+        let mut scenario_dvhs = Vec::with_capacity(scenarios.len());
+        for scenario in scenarios {
+            let dose = self.scenario_dose(scenario)?;
+            for ((lo, hi), d) in min_dose
+                .data_mut()
+                .iter_mut()
+                .zip(max_dose.data_mut())
+                .zip(dose.data())
+            {
+                *lo = lo.min(*d);
+                *hi = hi.max(*d);
+            }
+            let h = dvhs(&dose)?;
+            for (spread, (name, metric)) in spreads.iter_mut().zip(metrics) {
+                let v = metric
+                    .evaluate(&h[index(name)?], self.prescription)?
+                    .value();
+                spread.values.push(v);
+                spread.min = spread.min.min(v);
+                spread.max = spread.max.max(v);
+            }
+            scenario_dvhs.push(h);
+        }
+        let bands = nominal
+            .iter()
+            .enumerate()
+            .map(|(s, n)| {
+                let curves: Vec<&Dvh> = scenario_dvhs.iter().map(|h| &h[s]).collect();
+                band(n, &curves)
+            })
+            .collect();
+        Ok(RobustnessReport {
+            scenarios: scenarios.to_vec(),
+            min_dose,
+            max_dose,
+            bands,
+            metrics: spreads,
+        })
+    }
+}
+
+fn band(nominal: &Dvh, scenarios: &[&Dvh]) -> DvhBand {
+    let bins = scenarios
+        .iter()
+        .map(|h| h.bins.len())
+        .fold(nominal.bins.len(), usize::max);
+    let curve = |h: &Dvh| {
+        let mut c: Vec<f64> = h
+            .cumulative(VolumeUnit::Percent)
+            .iter()
+            .map(|(_, v)| *v)
+            .collect();
+        c.resize(bins + 1, 0.0);
+        c
+    };
+    let nominal_curve = curve(nominal);
+    let mut lower = nominal_curve.clone();
+    let mut upper = nominal_curve.clone();
+    for h in scenarios {
+        for ((lo, hi), v) in lower.iter_mut().zip(upper.iter_mut()).zip(curve(h)) {
+            *lo = lo.min(v);
+            *hi = hi.max(v);
+        }
+    }
+    DvhBand {
+        structure: nominal.name.clone(),
+        dose: (0..=bins).map(|n| n as f64 * nominal.bin_width).collect(),
+        nominal: nominal_curve,
+        lower,
+        upper,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::grid::{Grid3, GridGeometry};
+    use crate::metric::{DoseUnit, Metric};
+    use crate::robustness::{RangeModel, RobustnessEvaluation, Scenario};
+
+    #[test]
+    fn robustness_scenarios() {
+        assert_eq!(Scenario::generate(3.0, 0.0, 0.0).len(), 7);
+        assert_eq!(Scenario::generate(3.0, 0.02, 0.035).len(), 39);
+        assert!(Scenario::generate(0.0, 0.0, 0.0)[0].is_nominal());
+
+        // 1 mm voxels along x with a dose rising 1 Gy per mm.
+        let geometry = GridGeometry::new([11, 1, 1], Vec3::new(), Vec3::from(1.0, 1.0, 1.0));
+        let dose = Grid3::from_vec(geometry, (0..11).map(|i| i as f64).collect()).unwrap();
+        let mut eval = RobustnessEvaluation::new(&dose, Vec3::from(5.0, 0.0, 0.0));
+        let shifted = eval
+            .scenario_dose(&Scenario::shifted(Vec3::from(2.0, 0.0, 0.0)))
+            .unwrap();
+        assert_eq!(shifted.data()[3], 5.0);
+        assert_eq!(shifted.data()[10], 0.0);
+
+        let longer = Scenario {
+            range_scale: 2.0,
+            ..Scenario::nominal()
+        };
+        assert!(eval.scenario_dose(&longer).is_err());
+        eval.range = Some(RangeModel {
+            origin: Vec3::new(),
+            direction: Vec3::from(2.0, 0.0, 0.0),
+        });
+        assert_eq!(eval.scenario_dose(&longer).unwrap().data()[8], 4.0);
+
+        // The target covers voxels 4 to 6.
+        let target = dose.map(|d| if (4.0..=6.0).contains(d) { 1.0 } else { 0.0 });
+        let scenarios = [
+            Scenario::nominal(),
+            Scenario::shifted(Vec3::from(1.0, 0.0, 0.0)),
+            Scenario::shifted(Vec3::from(-1.0, 0.0, 0.0)),
+        ];
+        let report = eval
+            .evaluate(
+                &scenarios,
+                &[("CTV", &target)],
+                &[("CTV", Metric::Max(DoseUnit::Gy))],
+            )
+            .unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(report.min_dose.data()[5], 4.0);
+        assert_eq!(report.max_dose.data()[5], 6.0);
+        let spread = &report.metrics[0];
+        assert_eq!(spread.nominal.value(), 6.0);
+        assert_eq!(spread.values, vec![6.0, 7.0, 5.0]);
+        assert_eq!((spread.min, spread.max), (5.0, 7.0));
+        let band = &report.bands[0];
+        assert_eq!(band.structure, "CTV");
+        // At 5.5 Gy: 1 of 3 voxels nominally, 2 shifted by +1 mm and 0 by -1 mm.
+        let n = (5.5 / eval.bin_width).round() as usize;
+        assert!((band.nominal[n] - 100.0 / 3.0).abs() < 1e-9);
+        assert!((band.upper[n] - 200.0 / 3.0).abs() < 1e-9);
+        assert_eq!(band.lower[n], 0.0);
+        assert!(eval
+            .evaluate(&scenarios, &[], &[("PTV", Metric::Max(DoseUnit::Gy))])
+            .is_err());
+    }
+}
@@ -0,0 +1,401 @@
+//! Volume, surface area and shape descriptors of structures.
+//!
+//! Contour-based values treat every contour slice as a prism of one slice thickness (the
+//! median distance between contoured slices unless given), as treatment planning systems
+//! do. Nested contours on a slice alternate between solid and hole.
+
+use std::f64::consts::PI;
+
+use crate::coords::Vec3;
+use crate::grid::Grid3;
+use crate::raster::{crosses, group_slices, slice_thickness};
+use crate::structure::{Contour, Structure};
+
+/// Area (mm²) enclosed by the contours of one slice under the even-odd rule.
+pub fn slice_area(contours: &[&Contour]) -> f64 {
+    contours
+        .iter()
+        .enumerate()
+        .map(|(n, c)| {
+            let depth = contours
+                .iter()
+                .enumerate()
+                .filter(|(m, d)| *m != n && crosses(d, c.points[0]))
+                .count();
+            if depth % 2 == 0 {
+                c.area()
+            } else {
+                -c.area()
+            }
+        })
+        .sum()
+}
+
+/// Volume in mm³; `slice_thickness` defaults to the contour slice spacing, and a structure
+/// on a single slice without explicit thickness has no volume.
+pub fn contour_volume(structure: &Structure, thickness: Option<f64>) -> f64 {
+    let slices = group_slices(&structure.contours);
+    let thickness = match thickness.or_else(|| slice_thickness(&slices)) {
+        Some(t) => t,
+        None => return 0.0,
+    };
+    slices.iter().map(|(_, c)| slice_area(c)).sum::<f64>() * thickness
+}
+
+/// Surface area in mm². Neighbouring slices are joined by frusta whose slant follows the
+/// change in equivalent radius; slices without a neighbour within 1.5 thicknesses end in a
+/// half-thickness wall and a flat cap.
+pub fn contour_surface_area(structure: &Structure, thickness: Option<f64>) -> f64 {
+    let slices = group_slices(&structure.contours);
+    let thickness = match thickness.or_else(|| slice_thickness(&slices)) {
+        Some(t) => t,
+        None => return 0.0,
+    };
+    let areas: Vec<f64> = slices.iter().map(|(_, c)| slice_area(c)).collect();
+    let perimeters: Vec<f64> = slices
+        .iter()
+        .map(|(_, c)| c.iter().map(|c| c.perimeter()).sum())
+        .collect();
+    let end = |i: usize| 0.5 * thickness * perimeters[i] + areas[i];
+    let mut total = 0.0;
+    for i in 0..slices.len() {
+        let joined_below = i > 0 && slices[i].0 - slices[i - 1].0 <= 1.5 * thickness;
+        let joined_above = i + 1 < slices.len() && slices[i + 1].0 - slices[i].0 <= 1.5 * thickness;
+        if !joined_below {
+            total += end(i);
+        }
+        if !joined_above {
+            total += end(i);
+            continue;
+        }
+        let h = slices[i + 1].0 - slices[i].0;
+        let dr = (areas[i + 1].max(0.0) / PI).sqrt() - (areas[i].max(0.0) / PI).sqrt();
+        total += 0.5 * (perimeters[i] + perimeters[i + 1]) * (h * h + dr * dr).sqrt();
+    }
+    total
+}
+
+/// Volume in mm³ of a voxel-fraction (anti-aliased) mask.
+pub fn mask_volume(fraction: &Grid3<f64>) -> f64 {
+    fraction.data().iter().sum::<f64>() * fraction.geometry().voxel_volume()
+}
+
+/// Ratio of the surface of the sphere with the same volume to the actual surface (1 for a
+/// sphere, smaller for any other shape).
+pub fn sphericity(volume: f64, surface_area: f64) -> f64 {
+    if surface_area <= 0.0 {
+        return 0.0;
+    }
+    PI.powf(1.0 / 3.0) * (6.0 * volume).powf(2.0 / 3.0) / surface_area
+}
+
+/// Diameter (mm) of the sphere with the given volume.
+pub fn equivalent_diameter(volume: f64) -> f64 {
+    (6.0 * volume / PI).cbrt()
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ShapeMetrics {
+    /// mm³
+    pub volume: f64,
+    /// mm²
+    pub surface_area: f64,
+    pub sphericity: f64,
+    /// mm
+    pub equivalent_diameter: f64,
+}
+
+impl ShapeMetrics {
+    pub fn of(structure: &Structure) -> Self {
+        let volume = contour_volume(structure, None);
+        let surface_area = contour_surface_area(structure, None);
+        Self {
+            volume,
+            surface_area,
+            sphericity: sphericity(volume, surface_area),
+            equivalent_diameter: equivalent_diameter(volume),
+        }
+    }
+
+    pub fn volume_cc(&self) -> f64 {
+        self.volume / 1000.0
+    }
+}
+
+/// Axis-aligned box in patient coordinates (mm).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min: Vec3<f64>,
+    pub max: Vec3<f64>,
+}
+
+impl BoundingBox {
+    /// Box around `points`; `None` when there are none.
+    pub fn of<'a, I: IntoIterator<Item = &'a Vec3<f64>>>(points: I) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = *points.next()?;
+        Some(points.fold(
+            Self {
+                min: first,
+                max: first,
+            },
+            |b, p| Self {
+                min: Vec3::from(b.min.x.min(p.x), b.min.y.min(p.y), b.min.z.min(p.z)),
+                max: Vec3::from(b.max.x.max(p.x), b.max.y.max(p.y), b.max.z.max(p.z)),
+            },
+        ))
+    }
+
+    pub fn size(&self) -> Vec3<f64> {
+        self.max - self.min
+    }
+
+    pub fn center(&self) -> Vec3<f64> {
+        (self.min + self.max).scale(0.5)
+    }
+
+    pub fn contains(&self, p: Vec3<f64>) -> bool {
+        p.x >= self.min.x
+            && p.x <= self.max.x
+            && p.y >= self.min.y
+            && p.y <= self.max.y
+            && p.z >= self.min.z
+            && p.z <= self.max.z
+    }
+
+    /// Distance between the boxes; 0 when they overlap.
+    pub fn distance(&self, other: &BoundingBox) -> f64 {
+        let gap = |lo: f64, hi: f64, olo: f64, ohi: f64| (olo - hi).max(lo - ohi).max(0.0);
+        Vec3::from(
+            gap(self.min.x, self.max.x, other.min.x, other.max.x),
+            gap(self.min.y, self.max.y, other.min.y, other.max.y),
+            gap(self.min.z, self.max.z, other.min.z, other.max.z),
+        )
+        .norm()
+    }
+}
+
+/// Box around all contour points of `structure`.
+pub fn bounding_box(structure: &Structure) -> Option<BoundingBox> {
+    BoundingBox::of(structure.contours.iter().flat_map(|c| c.points.iter()))
+}
+
+/// Volume centroid in patient coordinates (mm), from the area-weighted slice centroids.
+pub fn centroid(structure: &Structure) -> Option<Vec3<f64>> {
+    let mut weight = 0.0;
+    let mut sum = Vec3::new();
+    for (_, contours) in group_slices(&structure.contours) {
+        for (n, c) in contours.iter().enumerate() {
+            let depth = contours
+                .iter()
+                .enumerate()
+                .filter(|(m, d)| *m != n && crosses(d, c.points[0]))
+                .count();
+            let a = if depth % 2 == 0 { c.area() } else { -c.area() };
+            sum += c.centroid().scale(a);
+            weight += a;
+        }
+    }
+    if weight.abs() < 1e-12 {
+        None
+    } else {
+        Some(sum.scale(1.0 / weight))
+    }
+}
+
+/// Cranio-caudal length (mm): from the lowest to the highest contoured slice plus one slice
+/// thickness, matching the prism model of [`contour_volume`].
+pub fn cranio_caudal_extent(structure: &Structure, thickness: Option<f64>) -> f64 {
+    let slices = group_slices(&structure.contours);
+    let (first, last) = match (slices.first(), slices.last()) {
+        (Some(f), Some(l)) => (f.0, l.0),
+        _ => return 0.0,
+    };
+    last - first
+        + thickness
+            .or_else(|| slice_thickness(&slices))
+            .unwrap_or(0.0)
+}
+
+/// Smallest distance (mm) between the contour outlines of two structures.
+pub fn surface_distance(a: &Structure, b: &Structure) -> Option<f64> {
+    let mut best: Option<f64> = None;
+    for ca in a.contours.iter().filter(|c| !c.is_empty()) {
+        let box_a = BoundingBox::of(&ca.points)?;
+        for cb in b.contours.iter().filter(|c| !c.is_empty()) {
+            let box_b = BoundingBox::of(&cb.points)?;
+            if best.is_some_and(|d| box_a.distance(&box_b) >= d) {
+                continue;
+            }
+            for (p0, p1) in edges(ca) {
+                for (q0, q1) in edges(cb) {
+                    let d = segment_distance(p0, p1, q0, q1);
+                    if best.is_none_or(|b| d < b) {
+                        best = Some(d);
+                    }
+                }
+            }
+        }
+    }
+    best
+}
+
+fn edges(contour: &Contour) -> impl Iterator<Item = (Vec3<f64>, Vec3<f64>)> + '_ {
+    let n = contour.points.len();
+    let count = if contour.closed || n < 2 { n } else { n - 1 };
+    (0..count).map(move |i| (contour.points[i], contour.points[(i + 1) % n]))
+}
+
+/// Distance between segments `p0 p1` and `q0 q1` (closest points as in Ericson,
+/// Real-Time Collision Detection, 5.1.9).
+fn segment_distance(p0: Vec3<f64>, p1: Vec3<f64>, q0: Vec3<f64>, q1: Vec3<f64>) -> f64 {
+    let d1 = p1 - p0;
+    let d2 = q1 - q0;
+    let r = p0 - q0;
+    let a = d1.dot(d1);
+    let e = d2.dot(d2);
+    let f = d2.dot(r);
+    let eps = 1e-12;
+    let (s, t) = if a <= eps && e <= eps {
+        (0.0, 0.0)
+    } else if a <= eps {
+        (0.0, (f / e).clamp(0.0, 1.0))
+    } else {
+        let c = d1.dot(r);
+        if e <= eps {
+            ((-c / a).clamp(0.0, 1.0), 0.0)
+        } else {
+            let b = d1.dot(d2);
+            let denom = a * e - b * b;
+            let mut s = if denom > eps {
+                ((b * f - c * e) / denom).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let mut t = (b * s + f) / e;
+            if t < 0.0 {
+                t = 0.0;
+                s = (-c / a).clamp(0.0, 1.0);
+            } else if t > 1.0 {
+                t = 1.0;
+                s = ((b - c) / a).clamp(0.0, 1.0);
+            }
+            (s, t)
+        }
+    };
+    (p0 + d1.scale(s)).distance(q0 + d2.scale(t))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use crate::coords::Vec3;
+    use crate::grid::GridGeometry;
+    use crate::raster::rasterize_fractional;
+    use crate::shape::{
+        bounding_box, centroid, contour_surface_area, contour_volume, cranio_caudal_extent,
+        mask_volume, surface_distance, ShapeMetrics,
+    };
+    use crate::structure::{Contour, Structure, StructureType};
+
+    fn circle(r: f64, z: f64) -> Contour {
+        Contour::new(
+            (0..360)
+                .map(|n| {
+                    let a = n as f64 * PI / 180.0;
+                    Vec3::from(r * a.cos(), r * a.sin(), z)
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn shape_cylinder_with_hole() {
+        let mut contours = Vec::new();
+        for n in 0..6 {
+            let z = 2.0 * n as f64;
+            contours.push(circle(10.0, z));
+            contours.push(circle(5.0, z));
+        }
+        let s = Structure::new("Rectum", StructureType::Oar).with_contours(contours);
+        let area = circle(10.0, 0.0).area() - circle(5.0, 0.0).area();
+        assert!((contour_volume(&s, None) - 12.0 * area).abs() < 1e-9);
+        assert!((contour_volume(&s, Some(3.0)) - 18.0 * area).abs() < 1e-9);
+        let perimeter = circle(10.0, 0.0).perimeter() + circle(5.0, 0.0).perimeter();
+        let expected = 12.0 * perimeter + 2.0 * area;
+        assert!((contour_surface_area(&s, None) - expected).abs() < 1e-9);
+        let single =
+            Structure::new("Dot", StructureType::Marker).with_contours(vec![circle(1.0, 0.0)]);
+        assert_eq!(contour_volume(&single, None), 0.0);
+    }
+
+    #[test]
+    fn shape_sphere() {
+        let r = 20.0;
+        let contours = (-10..10)
+            .map(|n| {
+                let z = 2.0 * n as f64 + 1.0;
+                circle((r * r - z * z).sqrt(), z)
+            })
+            .collect();
+        let s = Structure::new("Sphere", StructureType::Ptv).with_contours(contours);
+        let m = ShapeMetrics::of(&s);
+        let exact = 4.0 / 3.0 * PI * r.powi(3);
+        assert!(
+            (m.volume - exact).abs() / exact < 0.01,
+            "volume {}",
+            m.volume
+        );
+        assert!(
+            m.sphericity > 0.9 && m.sphericity <= 1.0,
+            "sphericity {}",
+            m.sphericity
+        );
+        assert!((m.equivalent_diameter - 2.0 * r).abs() < 0.2);
+        assert!((m.volume_cc() - m.volume / 1000.0).abs() < 1e-12);
+
+        let geometry = GridGeometry::new(
+            [23, 23, 23],
+            Vec3::from(-22.0, -22.0, -22.0),
+            Vec3::from(2.0, 2.0, 2.0),
+        );
+        let v = mask_volume(&rasterize_fractional(&s, &geometry, 4));
+        assert!((v - m.volume).abs() / m.volume < 0.01, "mask volume {}", v);
+    }
+
+    #[test]
+    fn shape_position_queries() {
+        let ring = |cx: f64, z: f64| {
+            let outer = circle(10.0, z);
+            let inner = circle(4.0, z);
+            let shift = |c: Contour| {
+                Contour::new(
+                    c.points
+                        .iter()
+                        .map(|p| *p + Vec3::from(cx, 5.0, 0.0))
+                        .collect(),
+                )
+            };
+            vec![shift(outer), shift(inner)]
+        };
+        let a = Structure::new("A", StructureType::Oar)
+            .with_contours((0..4).flat_map(|n| ring(0.0, 3.0 * n as f64)).collect());
+        let c = centroid(&a).unwrap();
+        assert!(c.distance(Vec3::from(0.0, 5.0, 4.5)) < 1e-9);
+        let bb = bounding_box(&a).unwrap();
+        assert!((bb.size().x - 20.0).abs() < 1e-9);
+        assert!((bb.center().y - 5.0).abs() < 1e-9);
+        assert!(bb.contains(Vec3::from(0.0, 5.0, 9.0)));
+        assert!((cranio_caudal_extent(&a, None) - 12.0).abs() < 1e-12);
+
+        let b = Structure::new("B", StructureType::Oar)
+            .with_contours((0..4).flat_map(|n| ring(25.0, 3.0 * n as f64)).collect());
+        let d = surface_distance(&a, &b).unwrap();
+        assert!((d - 5.0).abs() < 0.01, "distance {}", d);
+        let above = Structure::new("C", StructureType::Oar).with_contours(ring(0.0, 20.0));
+        let d = surface_distance(&a, &above).unwrap();
+        assert!((d - 11.0).abs() < 1e-9, "distance {}", d);
+        assert!(surface_distance(&a, &Structure::new("D", StructureType::Oar)).is_none());
+    }
+}
@@ -0,0 +1,536 @@
+//! Compressed sparse matrices of `f32` values, tailored to dose-influence matrices.
+//!
+//! [`CsrMatrix`] stores the nonzeros row by row and [`CscMatrix`] column by column, as offsets
+//! into `u32` indices and `f32` values; products take and return `f64` vectors and accumulate
+//! in `f64`. A product along the storage order gathers every output entry from one row or
+//! column and splits the outputs over threads; the transpose product scatters every stored row
+//! or column into the outputs, each thread into its own vector that are summed at the end.
+//! Products too small to pay for threads run on the calling thread.
+//!
+//! On disk a matrix is the magic `PLTSPMAT`, a byte `R` or `C` for the storage order, the
+//! stored and other dimension and the number of nonzeros as `u64`, then the offsets as `u64`,
+//! the indices as `u32` and the values as `f32`, all little-endian. Without a dependency for
+//! memory mapping, [`read`] loads a matrix with one bulk read and converts the arrays
+//! in place of parsing them entry by entry.
+
+use crate::error::{Error, Result};
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"PLTSPMAT";
+/// Nonzeros below which a thread is not worth spawning.
+const WORK_PER_THREAD: usize = 1 << 15;
+
+/// Offsets, indices and values of either storage order: `major` rows of CSR or columns of
+/// CSC, `minor` the other dimension.
+#[derive(Debug, Clone, PartialEq)]
+struct Compressed {
+    major: usize,
+    minor: usize,
+    offsets: Vec<usize>,
+    indices: Vec<u32>,
+    values: Vec<f32>,
+}
+
+impl Compressed {
+    fn new(
+        major: usize,
+        minor: usize,
+        offsets: Vec<usize>,
+        indices: Vec<u32>,
+        values: Vec<f32>,
+    ) -> Result<Self> {
+        if offsets.len() != major + 1
+            || offsets[0] != 0
+            || offsets.windows(2).any(|o| o[1] < o[0])
+            || offsets[major] != indices.len()
+            || indices.len() != values.len()
+        {
+            return Err(Error::InvalidArgument(format!(
+                "inconsistent sparse storage: {} offsets for {} lines, {} indices, {} values",
+                offsets.len(),
+                major,
+                indices.len(),
+                values.len()
+            )));
+        }
+        if indices.iter().any(|i| *i as usize >= minor) {
+            return Err(Error::InvalidArgument(format!(
+                "sparse index beyond dimension {}",
+                minor
+            )));
+        }
+        Ok(Compressed {
+            major,
+            minor,
+            offsets,
+            indices,
+            values,
+        })
+    }
+
+    /// Storage from `(major, minor, value)` entries in any order; duplicates are summed.
+    fn from_entries(major: usize, minor: usize, mut entries: Vec<(u32, u32, f32)>) -> Result<Self> {
+        if let Some(e) = entries
+            .iter()
+            .find(|e| e.0 as usize >= major || e.1 as usize >= minor)
+        {
+            return Err(Error::InvalidArgument(format!(
+                "sparse entry ({}, {}) outside {} x {}",
+                e.0, e.1, major, minor
+            )));
+        }
+        entries.sort_by_key(|e| (e.0, e.1));
+        let mut offsets = vec![0; major + 1];
+        let (mut indices, mut values): (Vec<u32>, Vec<f32>) = (Vec::new(), Vec::new());
+        let mut last = None;
+        for (m, n, v) in entries {
+            if last == Some((m, n)) {
+                *values.last_mut().unwrap() += v;
+                continue;
+            }
+            last = Some((m, n));
+            offsets[m as usize + 1] += 1;
+            indices.push(n);
+            values.push(v);
+        }
+        for m in 0..major {
+            offsets[m + 1] += offsets[m];
+        }
+        Compressed::new(major, minor, offsets, indices, values)
+    }
+
+    fn line(&self, m: usize) -> (&[u32], &[f32]) {
+        let range = self.offsets[m]..self.offsets[m + 1];
+        (&self.indices[range.clone()], &self.values[range])
+    }
+
+    fn threads(&self, threads: usize) -> usize {
+        let threads = match threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        threads.min(self.values.len() / WORK_PER_THREAD).max(1)
+    }
+
+    /// Per line, the sum of its values times `x` at their indices.
+    fn gather(&self, x: &[f64], threads: usize) -> Vec<f64> {
+        let line = |m: usize| {
+            let (indices, values) = self.line(m);
+            indices
+                .iter()
+                .zip(values)
+                .map(|(i, v)| x[*i as usize] * *v as f64)
+                .sum()
+        };
+        let threads = self.threads(threads);
+        if threads == 1 {
+            return (0..self.major).map(line).collect();
+        }
+        let mut out = vec![0.0; self.major];
+        let chunk = self.major.div_ceil(threads).max(1);
+        let line = &line;
+        std::thread::scope(|scope| {
+            for (c, part) in out.chunks_mut(chunk).enumerate() {
+                scope.spawn(move || {
+                    for (k, o) in part.iter_mut().enumerate() {
+                        *o = line(c * chunk + k);
+                    }
+                });
+            }
+        });
+        out
+    }
+
+    /// Per index, the sum over lines of their value there times `x` of the line.
+    fn scatter(&self, x: &[f64], threads: usize) -> Vec<f64> {
+        let lines = |range: std::ops::Range<usize>| {
+            let mut out = vec![0.0; self.minor];
+            for m in range {
+                if x[m] == 0.0 {
+                    continue;
+                }
+                let (indices, values) = self.line(m);
+                for (i, v) in indices.iter().zip(values) {
+                    out[*i as usize] += x[m] * *v as f64;
+                }
+            }
+            out
+        };
+        let threads = self.threads(threads);
+        if threads == 1 {
+            return lines(0..self.major);
+        }
+        let chunk = self.major.div_ceil(threads).max(1);
+        let lines = &lines;
+        let parts: Vec<Vec<f64>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..self.major)
+                .step_by(chunk)
+                .map(|start| scope.spawn(move || lines(start..(start + chunk).min(self.major))))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("sparse product thread"))
+                .collect()
+        });
+        let mut out = vec![0.0; self.minor];
+        for part in parts {
+            for (o, p) in out.iter_mut().zip(part) {
+                *o += p;
+            }
+        }
+        out
+    }
+
+    /// The same matrix stored in the other order.
+    fn transposed(&self) -> Compressed {
+        let mut offsets = vec![0; self.minor + 1];
+        for i in &self.indices {
+            offsets[*i as usize + 1] += 1;
+        }
+        for n in 0..self.minor {
+            offsets[n + 1] += offsets[n];
+        }
+        let mut next = offsets.clone();
+        let mut indices = vec![0; self.indices.len()];
+        let mut values = vec![0.0; self.values.len()];
+        for m in 0..self.major {
+            let (line_indices, line_values) = self.line(m);
+            for (i, v) in line_indices.iter().zip(line_values) {
+                let at = &mut next[*i as usize];
+                indices[*at] = m as u32;
+                values[*at] = *v;
+                *at += 1;
+            }
+        }
+        Compressed {
+            major: self.minor,
+            minor: self.major,
+            offsets,
+            indices,
+            values,
+        }
+    }
+
+    fn check_len(len: usize, expected: usize) -> Result<()> {
+        if len != expected {
+            return Err(Error::InvalidArgument(format!(
+                "vector of {} entries for a matrix dimension of {}",
+                len, expected
+            )));
+        }
+        Ok(())
+    }
+
+    fn to_bytes(&self, order: u8) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(
+            33 + 8 * self.offsets.len() + 4 * self.indices.len() + 4 * self.values.len(),
+        );
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(order);
+        for n in [self.major, self.minor, self.values.len()] {
+            bytes.extend_from_slice(&(n as u64).to_le_bytes());
+        }
+        for o in &self.offsets {
+            bytes.extend_from_slice(&(*o as u64).to_le_bytes());
+        }
+        for i in &self.indices {
+            bytes.extend_from_slice(&i.to_le_bytes());
+        }
+        for v in &self.values {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<(u8, Compressed)> {
+        let truncated = || Error::Format("truncated sparse matrix".to_string());
+        if bytes.len() < 33 || &bytes[..8] != MAGIC {
+            return Err(Error::Format("not a sparse matrix file".to_string()));
+        }
+        let order = bytes[8];
+        let word = |at: usize| u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap()) as usize;
+        let (major, minor, nnz) = (word(9), word(17), word(25));
+        let offsets_end = major
+            .checked_add(1)
+            .and_then(|n| n.checked_mul(8))
+            .and_then(|n| n.checked_add(33))
+            .ok_or_else(truncated)?;
+        let indices_end = nnz
+            .checked_mul(4)
+            .and_then(|n| n.checked_add(offsets_end))
+            .ok_or_else(truncated)?;
+        let end = indices_end.checked_add(4 * nnz).ok_or_else(truncated)?;
+        if bytes.len() != end {
+            return Err(truncated());
+        }
+        let offsets = bytes[33..offsets_end]
+            .chunks_exact(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()) as usize)
+            .collect();
+        let indices = bytes[offsets_end..indices_end]
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        let values = bytes[indices_end..end]
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        let compressed = Compressed::new(major, minor, offsets, indices, values)
+            .map_err(|e| Error::Format(e.to_string()))?;
+        Ok((order, compressed))
+    }
+}
+
+/// Compressed sparse rows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsrMatrix(Compressed);
+
+/// Compressed sparse columns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CscMatrix(Compressed);
+
+impl CsrMatrix {
+    /// A `rows` x `cols` matrix from its row `offsets` into the column `indices` and `values`.
+    pub fn new(
+        rows: usize,
+        cols: usize,
+        offsets: Vec<usize>,
+        indices: Vec<u32>,
+        values: Vec<f32>,
+    ) -> Result<Self> {
+        Compressed::new(rows, cols, offsets, indices, values).map(CsrMatrix)
+    }
+
+    /// A matrix from `(row, column, value)` entries in any order; duplicates are summed.
+    pub fn from_triplets(rows: usize, cols: usize, triplets: &[(u32, u32, f32)]) -> Result<Self> {
+        Compressed::from_entries(rows, cols, triplets.to_vec()).map(CsrMatrix)
+    }
+
+    pub fn rows(&self) -> usize {
+        self.0.major
+    }
+
+    pub fn cols(&self) -> usize {
+        self.0.minor
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.0.values.len()
+    }
+
+    /// Column indices and values of row `row`.
+    pub fn row(&self, row: usize) -> (&[u32], &[f32]) {
+        self.0.line(row)
+    }
+
+    /// The product with `x`, one entry per column, on `threads` threads (0 for the
+    /// available parallelism).
+    pub fn mul_vec(&self, x: &[f64], threads: usize) -> Result<Vec<f64>> {
+        Compressed::check_len(x.len(), self.cols())?;
+        Ok(self.0.gather(x, threads))
+    }
+
+    /// The product of the transpose with `y`, one entry per row.
+    pub fn transpose_mul_vec(&self, y: &[f64], threads: usize) -> Result<Vec<f64>> {
+        Compressed::check_len(y.len(), self.rows())?;
+        Ok(self.0.scatter(y, threads))
+    }
+
+    pub fn to_csc(&self) -> CscMatrix {
+        CscMatrix(self.0.transposed())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes(b'R')
+    }
+}
+
+impl CscMatrix {
+    /// A `rows` x `cols` matrix from its column `offsets` into the row `indices` and `values`.
+    pub fn new(
+        rows: usize,
+        cols: usize,
+        offsets: Vec<usize>,
+        indices: Vec<u32>,
+        values: Vec<f32>,
+    ) -> Result<Self> {
+        Compressed::new(cols, rows, offsets, indices, values).map(CscMatrix)
+    }
+
+    /// A matrix from `(row, column, value)` entries in any order; duplicates are summed.
+    pub fn from_triplets(rows: usize, cols: usize, triplets: &[(u32, u32, f32)]) -> Result<Self> {
+        let entries = triplets.iter().map(|(r, c, v)| (*c, *r, *v)).collect();
+        Compressed::from_entries(cols, rows, entries).map(CscMatrix)
+    }
+
+    pub fn rows(&self) -> usize {
+        self.0.minor
+    }
+
+    pub fn cols(&self) -> usize {
+        self.0.major
+    }
+
+    pub fn nnz(&self) -> usize {
+        self.0.values.len()
+    }
+
+    /// Row indices and values of column `col`.
+    pub fn col(&self, col: usize) -> (&[u32], &[f32]) {
+        self.0.line(col)
+    }
+
+    /// The product with `x`, one entry per column, on `threads` threads (0 for the
+    /// available parallelism).
+    pub fn mul_vec(&self, x: &[f64], threads: usize) -> Result<Vec<f64>> {
+        Compressed::check_len(x.len(), self.cols())?;
+        Ok(self.0.scatter(x, threads))
+    }
+
+    /// The product of the transpose with `y`, one entry per row.
+    pub fn transpose_mul_vec(&self, y: &[f64], threads: usize) -> Result<Vec<f64>> {
+        Compressed::check_len(y.len(), self.rows())?;
+        Ok(self.0.gather(y, threads))
+    }
+
+    pub fn to_csr(&self) -> CsrMatrix {
+        CsrMatrix(self.0.transposed())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_bytes(b'C')
+    }
+}
+
+/// A matrix in either storage order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SparseMatrix {
+    Csr(CsrMatrix),
+    Csc(CscMatrix),
+}
+
+impl SparseMatrix {
+    pub fn into_csr(self) -> CsrMatrix {
+        match self {
+            SparseMatrix::Csr(m) => m,
+            SparseMatrix::Csc(m) => m.to_csr(),
+        }
+    }
+
+    pub fn into_csc(self) -> CscMatrix {
+        match self {
+            SparseMatrix::Csr(m) => m.to_csc(),
+            SparseMatrix::Csc(m) => m,
+        }
+    }
+}
+
+pub fn from_bytes(bytes: &[u8]) -> Result<SparseMatrix> {
+    match Compressed::from_bytes(bytes)? {
+        (b'R', m) => Ok(SparseMatrix::Csr(CsrMatrix(m))),
+        (b'C', m) => Ok(SparseMatrix::Csc(CscMatrix(m))),
+        (order, _) => Err(Error::Format(format!(
+            "unknown sparse storage order {:?}",
+            order as char
+        ))),
+    }
+}
+
+pub fn read<P: AsRef<Path>>(path: P) -> Result<SparseMatrix> {
+    from_bytes(&fs::read(path)?)
+}
+
+pub fn write_csr<P: AsRef<Path>>(path: P, matrix: &CsrMatrix) -> Result<()> {
+    fs::write(path, matrix.to_bytes())?;
+    Ok(())
+}
+
+pub fn write_csc<P: AsRef<Path>>(path: P, matrix: &CscMatrix) -> Result<()> {
+    fs::write(path, matrix.to_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sparse::{from_bytes, CscMatrix, CsrMatrix, SparseMatrix};
+
+    /// A 3 x 4 matrix:
+    /// ```text
+    /// 1 0 2 0
+    /// 0 0 3 0
+    /// 4 5 0 6
+    /// ```
+    fn triplets() -> Vec<(u32, u32, f32)> {
+        vec![
+            (2, 3, 6.0),
+            (0, 0, 1.0),
+            (0, 2, 1.5),
+            (1, 2, 3.0),
+            (2, 0, 4.0),
+            (2, 1, 5.0),
+            (0, 2, 0.5),
+        ]
+    }
+
+    #[test]
+    fn sparse_products() {
+        let csr = CsrMatrix::from_triplets(3, 4, &triplets()).unwrap();
+        assert_eq!(csr.nnz(), 6);
+        assert_eq!(csr.row(0), (&[0, 2][..], &[1.0, 2.0][..]));
+        let csc = csr.to_csc();
+        assert_eq!(csc, CscMatrix::from_triplets(3, 4, &triplets()).unwrap());
+        assert_eq!(csc.col(2), (&[0, 1][..], &[2.0, 3.0][..]));
+        assert_eq!(csc.to_csr(), csr);
+
+        let x = [1.0, 2.0, 3.0, 4.0];
+        let y = [1.0, -1.0, 2.0];
+        let ax = vec![7.0, 9.0, 38.0];
+        let aty = vec![9.0, 10.0, -1.0, 12.0];
+        for threads in [1, 3] {
+            assert_eq!(csr.mul_vec(&x, threads).unwrap(), ax);
+            assert_eq!(csc.mul_vec(&x, threads).unwrap(), ax);
+            assert_eq!(csr.transpose_mul_vec(&y, threads).unwrap(), aty);
+            assert_eq!(csc.transpose_mul_vec(&y, threads).unwrap(), aty);
+        }
+        assert!(csr.mul_vec(&y, 1).is_err());
+        assert!(CsrMatrix::from_triplets(3, 4, &[(3, 0, 1.0)]).is_err());
+        assert!(CsrMatrix::new(2, 2, vec![0, 2, 1], vec![0, 1], vec![1.0, 1.0]).is_err());
+    }
+
+    #[test]
+    fn sparse_parallel_products() {
+        // Enough nonzeros for several threads.
+        let (rows, cols) = (1200, 300);
+        let triplets: Vec<(u32, u32, f32)> = (0..rows * cols)
+            .filter(|n| n % 2 == 0)
+            .map(|n| ((n / cols) as u32, (n % cols) as u32, (n % 7) as f32))
+            .collect();
+        let csr = CsrMatrix::from_triplets(rows, cols, &triplets).unwrap();
+        let csc = csr.to_csc();
+        let x: Vec<f64> = (0..cols).map(|n| (n % 5) as f64).collect();
+        let y: Vec<f64> = (0..rows).map(|n| (n % 4) as f64 - 1.0).collect();
+        let serial = csr.mul_vec(&x, 1).unwrap();
+        assert_eq!(csr.mul_vec(&x, 4).unwrap(), serial);
+        assert_eq!(csc.mul_vec(&x, 4).unwrap(), serial);
+        let serial = csc.transpose_mul_vec(&y, 1).unwrap();
+        assert_eq!(csc.transpose_mul_vec(&y, 4).unwrap(), serial);
+        assert_eq!(csr.transpose_mul_vec(&y, 4).unwrap(), serial);
+    }
+
+    #[test]
+    fn sparse_serialization() {
+        let csr = CsrMatrix::from_triplets(3, 4, &triplets()).unwrap();
+        let path = std::env::temp_dir().join(format!("planrt-sparse-{}.bin", std::process::id()));
+        crate::sparse::write_csr(&path, &csr).unwrap();
+        let read = crate::sparse::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, SparseMatrix::Csr(csr.clone()));
+        let csc = csr.to_csc();
+        assert_eq!(from_bytes(&csc.to_bytes()).unwrap().into_csr(), csr);
+        let bytes = csr.to_bytes();
+        assert!(from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(from_bytes(b"not a matrix at all, just some text").is_err());
+    }
+}
@@ -0,0 +1,463 @@
+use crate::coords::Vec3;
+use crate::error::{Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// Clinical role of a structure (after DICOM RT ROI Interpreted Type).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StructureType {
+    External,
+    Ptv,
+    Ctv,
+    Gtv,
+    Oar,
+    Avoidance,
+    Bolus,
+    Support,
+    Cavity,
+    Marker,
+    Isocenter,
+    Control,
+    Other,
+}
+
+impl StructureType {
+    pub fn is_target(self) -> bool {
+        matches!(
+            self,
+            StructureType::Ptv | StructureType::Ctv | StructureType::Gtv
+        )
+    }
+
+    /// The DICOM RT ROI Interpreted Type code string.
+    pub fn code(self) -> &'static str {
+        match self {
+            StructureType::External => "EXTERNAL",
+            StructureType::Ptv => "PTV",
+            StructureType::Ctv => "CTV",
+            StructureType::Gtv => "GTV",
+            StructureType::Oar => "ORGAN",
+            StructureType::Avoidance => "AVOIDANCE",
+            StructureType::Bolus => "BOLUS",
+            StructureType::Support => "SUPPORT",
+            StructureType::Cavity => "CAVITY",
+            StructureType::Marker => "MARKER",
+            StructureType::Isocenter => "ISOCENTER",
+            StructureType::Control => "CONTROL",
+            StructureType::Other => "",
+        }
+    }
+}
+
+impl fmt::Display for StructureType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+impl FromStr for StructureType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim().to_ascii_uppercase().as_str() {
+            "EXTERNAL" | "BODY" => StructureType::External,
+            "PTV" => StructureType::Ptv,
+            "CTV" => StructureType::Ctv,
+            "GTV" => StructureType::Gtv,
+            "ORGAN" | "OAR" => StructureType::Oar,
+            "AVOIDANCE" => StructureType::Avoidance,
+            "BOLUS" => StructureType::Bolus,
+            "SUPPORT" => StructureType::Support,
+            "CAVITY" => StructureType::Cavity,
+            "MARKER" => StructureType::Marker,
+            "ISOCENTER" => StructureType::Isocenter,
+            "CONTROL" => StructureType::Control,
+            "" => StructureType::Other,
+            other => {
+                return Err(Error::InvalidArgument(format!(
+                    "unknown structure type {}",
+                    other
+                )))
+            }
+        })
+    }
+}
+
+/// A planar polygon in patient coordinates (mm), normally on one axial slice.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contour {
+    pub points: Vec<Vec3<f64>>,
+    pub closed: bool,
+}
+
+impl Contour {
+    pub fn new(points: Vec<Vec3<f64>>) -> Self {
+        Self {
+            points,
+            closed: true,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Mean z of the points, i.e. the slice position of an axial contour.
+    pub fn z(&self) -> f64 {
+        if self.points.is_empty() {
+            return 0.0;
+        }
+        self.points.iter().map(|p| p.z).sum::<f64>() / self.points.len() as f64
+    }
+
+    /// Signed area in the axial plane (mm²); positive for counter-clockwise polygons.
+    pub fn signed_area(&self) -> f64 {
+        let n = self.points.len();
+        if n < 3 {
+            return 0.0;
+        }
+        let mut a = 0.0;
+        for i in 0..n {
+            let p = self.points[i];
+            let q = self.points[(i + 1) % n];
+            a += p.x * q.y - q.x * p.y;
+        }
+        0.5 * a
+    }
+
+    pub fn area(&self) -> f64 {
+        self.signed_area().abs()
+    }
+
+    /// Area centroid in the axial plane at the contour's z; the mean point for degenerate
+    /// polygons.
+    pub fn centroid(&self) -> Vec3<f64> {
+        let n = self.points.len();
+        if n == 0 {
+            return Vec3::new();
+        }
+        let a = self.signed_area();
+        if a.abs() < 1e-12 {
+            let sum = self.points.iter().fold(Vec3::new(), |s, p| s + *p);
+            return sum.scale(1.0 / n as f64);
+        }
+        let (mut cx, mut cy) = (0.0, 0.0);
+        for i in 0..n {
+            let p = self.points[i];
+            let q = self.points[(i + 1) % n];
+            let cross = p.x * q.y - q.x * p.y;
+            cx += (p.x + q.x) * cross;
+            cy += (p.y + q.y) * cross;
+        }
+        Vec3::from(cx / (6.0 * a), cy / (6.0 * a), self.z())
+    }
+
+    /// Perimeter length including the closing segment for closed contours.
+    pub fn perimeter(&self) -> f64 {
+        let n = self.points.len();
+        if n < 2 {
+            return 0.0;
+        }
+        let open: f64 = self.points.windows(2).map(|w| w[0].distance(w[1])).sum();
+        if self.closed {
+            open + self.points[n - 1].distance(self.points[0])
+        } else {
+            open
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Structure {
+    /// ROI number, unique within a structure set; 0 until added to one.
+    pub number: u32,
+    pub name: String,
+    pub structure_type: StructureType,
+    pub color: [u8; 3],
+    pub description: String,
+    pub contours: Vec<Contour>,
+}
+
+impl Structure {
+    pub fn new(name: &str, structure_type: StructureType) -> Self {
+        Self {
+            number: 0,
+            name: name.to_string(),
+            structure_type,
+            color: [255, 0, 0],
+            description: String::new(),
+            contours: Vec::new(),
+        }
+    }
+
+    pub fn with_color(mut self, color: [u8; 3]) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_contours(mut self, contours: Vec<Contour>) -> Self {
+        self.contours = contours;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contours.iter().all(|c| c.is_empty())
+    }
+
+    /// Sorted slice positions (z, mm) that carry at least one contour.
+    pub fn slices(&self, tolerance: f64) -> Vec<f64> {
+        let mut zs: Vec<f64> = self
+            .contours
+            .iter()
+            .filter(|c| !c.is_empty())
+            .map(|c| c.z())
+            .collect();
+        zs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        zs.dedup_by(|a, b| (*a - *b).abs() <= tolerance);
+        zs
+    }
+
+    pub fn contours_at(&self, z: f64, tolerance: f64) -> impl Iterator<Item = &Contour> {
+        self.contours
+            .iter()
+            .filter(move |c| !c.is_empty() && (c.z() - z).abs() <= tolerance)
+    }
+
+    pub fn point_count(&self) -> usize {
+        self.contours.iter().map(|c| c.len()).sum()
+    }
+}
+
+/// A named collection of structures sharing one frame of reference.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StructureSet {
+    pub label: String,
+    pub frame_of_reference_uid: String,
+    structures: Vec<Structure>,
+}
+
+impl StructureSet {
+    pub fn new(label: &str) -> Self {
+        Self {
+            label: label.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Adds a structure, assigning the next free ROI number if its number is 0.
+    pub fn add(&mut self, mut structure: Structure) -> Result<&mut Structure> {
+        if self.get(&structure.name).is_some() {
+            return Err(Error::InvalidArgument(format!(
+                "structure '{}' already exists",
+                structure.name
+            )));
+        }
+        if structure.number == 0 {
+            structure.number = self.structures.iter().map(|s| s.number).max().unwrap_or(0) + 1;
+        } else if self.by_number(structure.number).is_some() {
+            return Err(Error::InvalidArgument(format!(
+                "ROI number {} already exists",
+                structure.number
+            )));
+        }
+        self.structures.push(structure);
+        Ok(self.structures.last_mut().unwrap())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Structure> {
+        let i = self.structures.iter().position(|s| s.name == name)?;
+        Some(self.structures.remove(i))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Structure> {
+        self.structures.iter().find(|s| s.name == name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Structure> {
+        self.structures.iter_mut().find(|s| s.name == name)
+    }
+
+    /// Case-insensitive lookup, ignoring surrounding whitespace.
+    pub fn find(&self, name: &str) -> Option<&Structure> {
+        let name = name.trim();
+        self.structures
+            .iter()
+            .find(|s| s.name.trim().eq_ignore_ascii_case(name))
+    }
+
+    pub fn by_number(&self, number: u32) -> Option<&Structure> {
+        self.structures.iter().find(|s| s.number == number)
+    }
+
+    pub fn of_type(&self, structure_type: StructureType) -> impl Iterator<Item = &Structure> {
+        self.structures
+            .iter()
+            .filter(move |s| s.structure_type == structure_type)
+    }
+
+    pub fn targets(&self) -> impl Iterator<Item = &Structure> {
+        self.structures
+            .iter()
+            .filter(|s| s.structure_type.is_target())
+    }
+
+    /// The body contour, if the set has exactly one structure of type EXTERNAL.
+    pub fn external(&self) -> Option<&Structure> {
+        let mut it = self.of_type(StructureType::External);
+        let first = it.next()?;
+        if it.next().is_some() {
+            return None;
+        }
+        Some(first)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.structures.iter().map(|s| s.name.as_str())
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Structure> {
+        self.structures.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Structure> {
+        self.structures.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.structures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.structures.is_empty()
+    }
+}
+
+impl<'a> IntoIterator for &'a StructureSet {
+    type Item = &'a Structure;
+    type IntoIter = std::slice::Iter<'a, Structure>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.structures.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coords::Vec3;
+    use crate::structure::{Contour, Structure, StructureSet, StructureType};
+
+    fn square(z: f64, half: f64) -> Contour {
+        Contour::new(vec![
+            Vec3::from(-half, -half, z),
+            Vec3::from(half, -half, z),
+            Vec3::from(half, half, z),
+            Vec3::from(-half, half, z),
+        ])
+    }
+
+    #[test]
+    fn structure_type_from_str() {
+        assert_eq!(
+            "ORGAN".parse::<StructureType>().unwrap(),
+            StructureType::Oar
+        );
+        assert_eq!("ptv".parse::<StructureType>().unwrap(), StructureType::Ptv);
+        assert_eq!("".parse::<StructureType>().unwrap(), StructureType::Other);
+        assert!("TUMOUR".parse::<StructureType>().is_err());
+        assert_eq!(StructureType::External.to_string(), "EXTERNAL");
+        assert!(StructureType::Gtv.is_target());
+        assert!(!StructureType::Oar.is_target());
+    }
+
+    #[test]
+    fn contour_area() {
+        let c = square(2.0, 5.0);
+        assert_eq!(c.area(), 100.0);
+        assert!(c.signed_area() > 0.0);
+        assert_eq!(c.perimeter(), 40.0);
+        assert_eq!(c.z(), 2.0);
+        let mut open = c.clone();
+        open.closed = false;
+        assert_eq!(open.perimeter(), 30.0);
+        let shifted = Contour::new(
+            c.points
+                .iter()
+                .map(|p| *p + Vec3::from(1.0, -2.0, 0.0))
+                .collect(),
+        );
+        assert_eq!(shifted.centroid(), Vec3::from(1.0, -2.0, 2.0));
+    }
+
+    #[test]
+    fn structure_slices() {
+        let s = Structure::new("PTV", StructureType::Ptv).with_contours(vec![
+            square(3.0, 1.0),
+            square(0.0, 2.0),
+            square(0.0, 1.0),
+        ]);
+        assert_eq!(s.slices(1e-3), vec![0.0, 3.0]);
+        assert_eq!(s.contours_at(0.0, 1e-3).count(), 2);
+        assert_eq!(s.point_count(), 12);
+        assert!(!s.is_empty());
+    }
+
+    #[test]
+    fn structure_set_add() {
+        let mut set = StructureSet::new("RS1");
+        assert_eq!(
+            set.add(Structure::new("BODY", StructureType::External))
+                .unwrap()
+                .number,
+            1
+        );
+        assert_eq!(
+            set.add(Structure::new("PTV", StructureType::Ptv))
+                .unwrap()
+                .number,
+            2
+        );
+        assert!(set.add(Structure::new("PTV", StructureType::Ptv)).is_err());
+        let mut numbered = Structure::new("Cord", StructureType::Oar);
+        numbered.number = 2;
+        assert!(set.add(numbered.clone()).is_err());
+        numbered.number = 10;
+        set.add(numbered).unwrap();
+        assert_eq!(
+            set.add(Structure::new("Lung", StructureType::Oar))
+                .unwrap()
+                .number,
+            11
+        );
+        assert_eq!(set.len(), 4);
+    }
+
+    #[test]
+    fn structure_set_lookup() {
+        let mut set = StructureSet::new("RS1");
+        set.add(Structure::new("BODY", StructureType::External))
+            .unwrap();
+        set.add(Structure::new("PTV_60", StructureType::Ptv))
+            .unwrap();
+        set.add(Structure::new("Parotid_L", StructureType::Oar))
+            .unwrap();
+        assert!(set.get("ptv_60").is_none());
+        assert_eq!(set.find(" ptv_60 ").unwrap().name, "PTV_60");
+        assert_eq!(set.by_number(3).unwrap().name, "Parotid_L");
+        assert_eq!(set.external().unwrap().name, "BODY");
+        assert_eq!(set.targets().count(), 1);
+        assert_eq!(set.of_type(StructureType::Oar).count(), 1);
+        assert_eq!(
+            set.names().collect::<Vec<_>>(),
+            vec!["BODY", "PTV_60", "Parotid_L"]
+        );
+        set.get_mut("Parotid_L").unwrap().color = [0, 255, 0];
+        assert_eq!(set.get("Parotid_L").unwrap().color, [0, 255, 0]);
+        assert!(set.remove("BODY").is_some());
+        assert!(set.external().is_none());
+        assert_eq!((&set).into_iter().count(), 2);
+    }
+}
@@ -0,0 +1,150 @@
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum length of a DICOM UID (PS3.5 9.1).
+pub const MAX_UID_LEN: usize = 64;
+
+/// Maximum length of a root so generated UIDs always fit in `MAX_UID_LEN`.
+pub const MAX_ROOT_LEN: usize = 24;
+
+/// Returns true if `uid` is a syntactically valid DICOM UID.
+pub fn is_valid_uid(uid: &str) -> bool {
+    if uid.is_empty() || uid.len() > MAX_UID_LEN {
+        return false;
+    }
+    uid.split('.').all(|c| {
+        !c.is_empty() && c.bytes().all(|b| b.is_ascii_digit()) && (c == "0" || !c.starts_with('0'))
+    })
+}
+
+/// Generates UIDs under a root and remembers which UID was handed out for which key, so
+/// that objects written in the same export session reference each other consistently.
+#[derive(Debug, Clone)]
+pub struct UidRegistry {
+    root: String,
+    session: String,
+    counter: u64,
+    uids: HashMap<String, String>,
+}
+
+impl UidRegistry {
+    pub fn new(root: &str) -> Result<Self> {
+        if root.len() > MAX_ROOT_LEN || !is_valid_uid(root) {
+            return Err(Error::InvalidArgument(format!(
+                "invalid UID root: {}",
+                root
+            )));
+        }
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros())
+            .unwrap_or(0);
+        let session = format!("{}.{}", std::process::id().max(1), micros.max(1));
+        Ok(Self {
+            root: root.to_string(),
+            session,
+            counter: 0,
+            uids: HashMap::new(),
+        })
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// Generates a new UID that is not associated with any key.
+    pub fn generate(&mut self) -> String {
+        self.counter += 1;
+        format!("{}.{}.{}", self.root, self.session, self.counter)
+    }
+
+    /// Returns the UID registered for `key`, generating one on first use.
+    pub fn uid(&mut self, key: &str) -> String {
+        if let Some(uid) = self.uids.get(key) {
+            return uid.clone();
+        }
+        let uid = self.generate();
+        self.uids.insert(key.to_string(), uid.clone());
+        uid
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.uids.get(key).map(|s| s.as_str())
+    }
+
+    /// Registers an existing UID (e.g. one read from an input object) under `key`.
+    pub fn insert(&mut self, key: &str, uid: &str) -> Result<()> {
+        if !is_valid_uid(uid) {
+            return Err(Error::InvalidArgument(format!("invalid UID: {}", uid)));
+        }
+        self.uids.insert(key.to_string(), uid.to_string());
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.uids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.uids.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.uids.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::uid::{is_valid_uid, UidRegistry, MAX_UID_LEN};
+
+    #[test]
+    fn uid_is_valid() {
+        assert!(is_valid_uid("1.2.840.10008.5.1.4.1.1.481.2"));
+        assert!(is_valid_uid("1.0.3"));
+        assert!(!is_valid_uid(""));
+        assert!(!is_valid_uid("1..2"));
+        assert!(!is_valid_uid("1.02"));
+        assert!(!is_valid_uid("1.2a"));
+        assert!(!is_valid_uid("1.2."));
+    }
+
+    #[test]
+    fn uid_registry_new() {
+        assert!(UidRegistry::new("1.2.826.0.1.3680043").is_ok());
+        assert!(UidRegistry::new("1.2.x").is_err());
+        assert!(UidRegistry::new("1.2.3.4.5.6.7.8.9.10.11.12.13").is_err());
+    }
+
+    #[test]
+    fn uid_registry_generate() {
+        let mut reg = UidRegistry::new("1.2.826.0.1.3680043").unwrap();
+        let a = reg.generate();
+        let b = reg.generate();
+        assert_ne!(a, b);
+        assert!(a.starts_with("1.2.826.0.1.3680043."));
+        assert!(is_valid_uid(&a));
+        assert!(a.len() <= MAX_UID_LEN);
+        assert!(reg.is_empty());
+    }
+
+    #[test]
+    fn uid_registry_uid() {
+        let mut reg = UidRegistry::new("1.2.3").unwrap();
+        let study = reg.uid("study");
+        let frame = reg.uid("frame_of_reference");
+        assert_ne!(study, frame);
+        assert_eq!(reg.uid("study"), study);
+        assert_eq!(reg.get("frame_of_reference"), Some(frame.as_str()));
+        assert_eq!(reg.len(), 2);
+    }
+
+    #[test]
+    fn uid_registry_insert() {
+        let mut reg = UidRegistry::new("1.2.3").unwrap();
+        reg.insert("ct_series", "1.2.840.113619.2.1").unwrap();
+        assert_eq!(reg.uid("ct_series"), "1.2.840.113619.2.1");
+        assert!(reg.insert("bad", "1.2.a").is_err());
+    }
+}